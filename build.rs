@@ -1,9 +1,19 @@
 extern crate bindgen;
+extern crate cc;
 
 use std::env;
 use std::path::PathBuf;
 
 fn main() {
+    // The functions creating and freeing TASO's graphs, which the bindings
+    // can not (see src/taso_graph.cc)
+    println!("cargo:rerun-if-changed=src/taso_graph.cc");
+    cc::Build::new()
+        .cpp(true)
+        .flag("-std=c++11")
+        .file("src/taso_graph.cc")
+        .compile("tensat_taso_graph");
+
     // Tell cargo to tell rustc to link the libraries.
     println!("cargo:rustc-link-search=/opt/conda/lib");
     println!("cargo:rustc-link-lib=protobuf");
@@ -27,6 +27,8 @@ def get_args():
         help='Name of thread calling the extractor')
     parser.add_argument('--verbose', action='store_true', default=False,
         help='Allow print statements')
+    parser.add_argument('--max_memory', type=int, required=False, default=None, metavar='BYTES',
+        help='Maximum total size of the activations of the extracted graph (default: no limit)')
 
     return parser.parse_args()
 
@@ -43,6 +45,7 @@ def main():
     # - e: e[m] is the set of nodes within eclass m
     # - h: h[i] is the set of children eclasses for node i
     # - g: g(i) gives the eclass of node i
+    # - memory: memory[i] is the size of the activation of node i in bytes
     with open(os.path.join(args.output_dir, 'ilp_data_' + args.thread_name + '.json')) as f:
         data = json.load(f)
 
@@ -52,6 +55,7 @@ def main():
     g = data['g_i']
     root_m = data['root_m']
     blacklist_i = data['blacklist_i']
+    memory = data.get('memory_i')
     num_nodes = len(costs)
     num_classes = len(e)
 
@@ -125,6 +129,10 @@ def main():
     for j in blacklist_i:
         solver.Add(x[j] == 0)
 
+    # Memory constraint: the activations of the picked nodes fit in the budget
+    if args.max_memory is not None and memory is not None:
+        solver.Add(sum([memory[j] * x[j] for j in range(num_nodes)]) <= args.max_memory)
+
     # Define objective
     obj_expr = [costs[j] * x[j] for j in range(num_nodes)]
     solver.Minimize(sum(obj_expr))
@@ -187,6 +195,9 @@ def main():
     result_dict = {}
     result_dict["solved_x"] = solved_x
     result_dict["cost"] = solver.Objective().Value()
+    # Lower bound on the cost of any graph in the EGraph, the cost itself if the
+    # solution is optimal
+    result_dict["bound"] = solver.Objective().BestBound()
     result_dict["time"] = solve_time / 1000
     with open(os.path.join(args.output_dir, 'solved_' + args.thread_name + '.json'), 'w') as f:
         json.dump(result_dict, f)
@@ -0,0 +1,148 @@
+//! Expected shapes of named tensors, checked on the input and the optimized
+//! graph (--shape_assertions)
+//!
+//! A frontend that maps an op wrongly often still produces a valid graph,
+//! only with a tensor of the wrong shape somewhere, and the optimized graph
+//! then computes something else than the model without any error. A shape
+//! assertions file is a JSON object from tensor names to their expected
+//! dims, e.g. `{"input": [1, 3, 224, 224], "conv2d_12": [1, 64, 112, 112]}`.
+//!
+//! A tensor is named by the name of its input or weight, or by the name of
+//! the layer producing it: the name given by the importer (e.g. the node
+//! name in an ONNX model) or its default name (see default_layer_names). The
+//! second output of a split is named `<layer>:1`.
+
+use crate::model::*;
+use crate::provenance::{default_layer_names, expr_eclasses};
+use crate::shape::TensorShape;
+use egg::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+#[derive(Debug, Clone, Default)]
+pub struct ShapeAssertions {
+    /// Expected dims of each named tensor
+    pub shapes: BTreeMap<String, Vec<i32>>,
+}
+
+impl ShapeAssertions {
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let s = fs::read_to_string(filename).map_err(|e| format!("Could not read {}: {}", filename, e))?;
+        let shapes = serde_json::from_str(&s).map_err(|e| format!("Could not parse {}: {}", filename, e))?;
+        Ok(ShapeAssertions { shapes: shapes })
+    }
+
+    /// Checks the assertions on a graph
+    ///
+    /// # Parameters
+    ///
+    /// - `tensors`: index of the node producing each named tensor, see
+    ///   tensor_names
+    /// - `shapes`: the output shapes of each node (see shape::expr_shapes)
+    /// - `report_missing`: whether asserting on a tensor that is not in
+    ///   tensors is an error. Not for an optimized graph, where rewritten
+    ///   away tensors are expected
+    ///
+    /// # Returns
+    ///
+    /// What is wrong with each assertion that does not hold
+    pub fn check(
+        &self,
+        tensors: &HashMap<String, usize>,
+        shapes: &[Option<Vec<TensorShape>>],
+        report_missing: bool,
+    ) -> Vec<String> {
+        let mut errors = vec![];
+        for (name, expected) in self.shapes.iter() {
+            let (node, output) = match lookup(tensors, name) {
+                Some(found) => found,
+                None => {
+                    if report_missing {
+                        errors.push(format!("{}: no tensor has this name", name));
+                    }
+                    continue;
+                }
+            };
+            match shapes[node].as_ref().and_then(|s| s.get(output)) {
+                Some(shape) if shape.dims == *expected => (),
+                Some(shape) => errors.push(format!("{}: expected shape {:?}, got {:?}", name, expected, shape.dims)),
+                None => errors.push(format!("{}: the shape is unknown", name)),
+            }
+        }
+        errors
+    }
+}
+
+/// Finds a tensor by name, as the node producing it and the index of the
+/// output. Tensor names can contain `:`, so the full name is tried first
+fn lookup(tensors: &HashMap<String, usize>, name: &str) -> Option<(usize, usize)> {
+    if let Some(node) = tensors.get(name) {
+        return Some((*node, 0));
+    }
+    let colon = name.rfind(':')?;
+    let output = name[colon + 1..].parse::<usize>().ok()?;
+    tensors.get(&name[..colon]).map(|node| (*node, output))
+}
+
+/// Names the tensors of a graph
+///
+/// # Parameters
+///
+/// - `expr`: the graph
+/// - `layer_names`: names of the layers given by the importer, keyed by their
+///   index in expr. Every layer also keeps its default name
+///
+/// # Returns
+///
+/// Index of the node producing each named tensor
+pub fn tensor_names(expr: &RecExpr<Mdl>, layer_names: &HashMap<Id, String>) -> HashMap<String, usize> {
+    let nodes = expr.as_ref();
+    let mut tensors = HashMap::new();
+    for (i, node) in nodes.iter().enumerate() {
+        if let Mdl::Input([name]) | Mdl::Weight([name]) = node {
+            let name = nodes[usize::from(*name)].to_string();
+            let name = name.split('@').next().unwrap().to_string();
+            tensors.insert(name, i);
+        }
+    }
+    for (id, name) in default_layer_names(expr).into_iter().chain(layer_names.clone()) {
+        tensors.insert(name, usize::from(id));
+    }
+    tensors
+}
+
+/// Carries the names of the tensors of the original graph over to the
+/// optimized graph: a tensor keeps its name if a node of the optimized graph
+/// computes the same value (is in the same eclass)
+///
+/// # Parameters
+///
+/// - `egraph`: E-graph containing both graphs, e.g. the EGraph after saturation
+/// - `original`: the original graph
+/// - `tensors`: the named tensors of original, see tensor_names
+/// - `optimized`: the optimized graph extracted from egraph
+///
+/// # Returns
+///
+/// Index in optimized of the node producing each named tensor that is left
+pub fn carry_names(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    original: &RecExpr<Mdl>,
+    tensors: &HashMap<String, usize>,
+    optimized: &RecExpr<Mdl>,
+) -> HashMap<String, usize> {
+    let original_ids = expr_eclasses(egraph, original);
+    let mut optimized_nodes = HashMap::new();
+    for (i, id) in expr_eclasses(egraph, optimized).into_iter().enumerate() {
+        if let Some(id) = id {
+            optimized_nodes.entry(id).or_insert(i);
+        }
+    }
+    tensors
+        .iter()
+        .filter_map(|(name, node)| {
+            let id = original_ids[*node]?;
+            optimized_nodes.get(&id).map(|i| (name.clone(), *i))
+        })
+        .collect()
+}
@@ -0,0 +1,172 @@
+//! Attributing the speedup of the optimized graph to regions of the model
+//! (--speedup_report)
+//!
+//! A region is a set of layers of the original graph together with the
+//! nodes of the optimized graph replacing them (see provenance): the
+//! connected groups of the replaces relation. Its saving is the sum of the
+//! runtimes of its original layers minus that of its optimized nodes, in the
+//! runtime model of the run. The whole graph measures differently from the
+//! sum of its ops, so the savings are also scaled to add up to the measured
+//! end-to-end saving. Regions are grouped by their pattern, the ops before
+//! and after, e.g. `conv2d+relu -> conv2d`, which tells what kinds of layers
+//! of a model gain the most from the rewrites.
+
+use crate::model::*;
+use egg::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// The saving of a region, in milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RegionSpeedup {
+    /// Names of the original layers of the region
+    pub layers: Vec<String>,
+    /// Ops of the original layers and of the optimized nodes
+    pub pattern: String,
+    pub original_ms: f32,
+    pub optimized_ms: f32,
+    /// Share of the measured end-to-end saving
+    pub measured_saved_ms: f32,
+}
+
+impl RegionSpeedup {
+    /// Saving in the runtime model
+    pub fn estimated_saved_ms(&self) -> f32 {
+        self.original_ms - self.optimized_ms
+    }
+}
+
+/// The savings of the regions of a pattern, in milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PatternSpeedup {
+    pub pattern: String,
+    pub num_regions: usize,
+    pub estimated_saved_ms: f32,
+    pub measured_saved_ms: f32,
+}
+
+/// Splits the rewritten parts of a graph into regions and attributes the
+/// saving to them
+///
+/// # Parameters
+///
+/// - `original`, `original_runtimes`: the original graph, and the runtime of
+///   each of its nodes (see optimize::expr_op_runtimes)
+/// - `names`: names of the layers of the original graph, as given to
+///   provenance
+/// - `optimized`, `optimized_runtimes`: the same for the optimized graph
+/// - `replaced`: for each node of optimized, the original layers it replaces
+/// - `measured_saving`: measured runtime of the original minus that of the
+///   optimized graph, to scale the savings to. None to not scale them
+///
+/// # Returns
+///
+/// The regions whose ops changed, by decreasing saving
+pub fn speedup_regions(
+    original: &RecExpr<Mdl>,
+    original_runtimes: &[f32],
+    names: &HashMap<Id, String>,
+    optimized: &RecExpr<Mdl>,
+    optimized_runtimes: &[f32],
+    replaced: &[Vec<String>],
+    measured_saving: Option<f32>,
+) -> Vec<RegionSpeedup> {
+    let layer_index: HashMap<&str, usize> = names.iter().map(|(id, name)| (name.as_str(), usize::from(*id))).collect();
+
+    // Group the optimized nodes sharing an original layer, the region of a
+    // node is the smallest node of its group
+    let mut region_of: Vec<usize> = (0..replaced.len()).collect();
+    let mut first_user: HashMap<&str, usize> = HashMap::new();
+    for (j, layers) in replaced.iter().enumerate() {
+        for layer in layers.iter() {
+            match first_user.get(layer.as_str()) {
+                Some(k) => {
+                    let (a, b) = (find(&mut region_of, *k), find(&mut region_of, j));
+                    region_of[a.max(b)] = a.min(b);
+                }
+                None => {
+                    first_user.insert(layer.as_str(), j);
+                }
+            }
+        }
+    }
+    let mut groups: BTreeMap<usize, (Vec<usize>, Vec<String>)> = BTreeMap::new();
+    for (j, layers) in replaced.iter().enumerate() {
+        if layers.is_empty() {
+            continue;
+        }
+        let group = groups.entry(find(&mut region_of, j)).or_default();
+        group.0.push(j);
+        group.1.extend(layers.iter().cloned());
+    }
+
+    let op_names = |nodes: &mut Vec<String>| {
+        nodes.sort();
+        nodes.join("+")
+    };
+    let mut regions: Vec<RegionSpeedup> = groups
+        .into_values()
+        .filter_map(|(opt_nodes, mut layers)| {
+            layers.sort();
+            layers.dedup();
+            let orig_nodes: Vec<usize> = layers.iter().filter_map(|l| layer_index.get(l.as_str()).cloned()).collect();
+            let before = op_names(&mut orig_nodes.iter().map(|i| original[Id::from(*i)].to_string()).collect());
+            let after = op_names(&mut opt_nodes.iter().map(|j| optimized[Id::from(*j)].to_string()).collect());
+            if before == after {
+                return None;
+            }
+            Some(RegionSpeedup {
+                layers: layers,
+                pattern: format!("{} -> {}", before, after),
+                original_ms: orig_nodes.iter().map(|i| original_runtimes[*i]).sum(),
+                optimized_ms: opt_nodes.iter().map(|j| optimized_runtimes[*j]).sum(),
+                measured_saved_ms: 0.0,
+            })
+        })
+        .collect();
+
+    // Scale to the measured saving, unless the estimate disagrees on whether
+    // there is one
+    let estimated: f32 = regions.iter().map(|r| r.estimated_saved_ms()).sum();
+    let scale = match measured_saving {
+        Some(measured) if estimated != 0.0 && measured * estimated > 0.0 => measured / estimated,
+        _ => 1.0,
+    };
+    for region in regions.iter_mut() {
+        region.measured_saved_ms = region.estimated_saved_ms() * scale;
+    }
+    regions.sort_by(|a, b| b.estimated_saved_ms().partial_cmp(&a.estimated_saved_ms()).unwrap());
+    regions
+}
+
+/// Groups regions by their pattern
+///
+/// # Returns
+///
+/// The savings of each pattern, by decreasing saving
+pub fn pattern_speedups(regions: &[RegionSpeedup]) -> Vec<PatternSpeedup> {
+    let mut patterns: BTreeMap<&str, PatternSpeedup> = BTreeMap::new();
+    for region in regions.iter() {
+        let pattern = patterns.entry(region.pattern.as_str()).or_insert(PatternSpeedup {
+            pattern: region.pattern.clone(),
+            num_regions: 0,
+            estimated_saved_ms: 0.0,
+            measured_saved_ms: 0.0,
+        });
+        pattern.num_regions += 1;
+        pattern.estimated_saved_ms += region.estimated_saved_ms();
+        pattern.measured_saved_ms += region.measured_saved_ms;
+    }
+    let mut patterns: Vec<PatternSpeedup> = patterns.into_values().collect();
+    patterns.sort_by(|a, b| b.estimated_saved_ms.partial_cmp(&a.estimated_saved_ms).unwrap());
+    patterns
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    parent[i] = root;
+    root
+}
@@ -0,0 +1,302 @@
+//! Packed attribute encoding for ops with many scalar attributes
+//!
+//! conv2d takes 4 and the poolings 6 scalar attributes, each in its own
+//! child slot, so every match of a rule on these ops binds and compares them
+//! one by one. The packed ops (conv2d_p, sconv2d_p, poolmax_p, poolavg_p)
+//! take all attributes of an op as one scalar instead. The packed value is a
+//! Num, so hash-consing interns it: every distinct set of attributes gets a
+//! single eclass.
+//!
+//! Rules are migrated by packing the attributes on both sides. This works if
+//! the attributes of an op are all constants, or all variables that are only
+//! used together; other rules are kept unpacked (and can not match the
+//! packed ops).
+
+use crate::model::*;
+use egg::*;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Number of bits of each size attribute (kernel sizes and strides)
+const SIZE_BITS: u32 = 6;
+/// Number of bits of the padding and of the activation mode
+const MODE_BITS: u32 = 2;
+
+/// Packs the attributes of an op into one scalar
+///
+/// # Parameters
+///
+/// - `sizes`: the kernel sizes and strides, each between 1 and 63
+/// - `pad`: the padding mode
+/// - `act`: the activation mode
+pub fn pack_attrs(sizes: &[i32], pad: i32, act: i32) -> i32 {
+    assert!(sizes.len() <= 4);
+    assert!((0..1 << MODE_BITS).contains(&pad));
+    assert!((0..1 << MODE_BITS).contains(&act));
+    let mut packed = act | (pad << MODE_BITS);
+    for (i, s) in sizes.iter().enumerate() {
+        assert!((1..1 << SIZE_BITS).contains(s), "size {} can not be packed", s);
+        packed |= s << (2 * MODE_BITS + SIZE_BITS * i as u32);
+    }
+    packed
+}
+
+/// Unpacks the attributes packed by pack_attrs
+///
+/// # Returns
+///
+/// The num_sizes sizes, the padding mode and the activation mode
+pub fn unpack_attrs(packed: i32, num_sizes: usize) -> (Vec<i32>, i32, i32) {
+    let mask = |bits: u32| (1 << bits) - 1;
+    let act = packed & mask(MODE_BITS);
+    let pad = (packed >> MODE_BITS) & mask(MODE_BITS);
+    let sizes = (0..num_sizes)
+        .map(|i| (packed >> (2 * MODE_BITS + SIZE_BITS * i as u32)) & mask(SIZE_BITS))
+        .collect();
+    (sizes, pad, act)
+}
+
+/// Positions of the attribute children of an op, packed or not
+fn attr_positions(node: &Mdl) -> &'static [usize] {
+    match node {
+        Mdl::Conv2d(_) | Mdl::Sconv2d(_) | Mdl::Conv2dBlocked(_) => &[0, 1, 2, 3],
+        Mdl::Poolmax(_) | Mdl::Poolavg(_) => &[1, 2, 3, 4, 5, 6],
+        Mdl::Conv2dPacked(_) | Mdl::Sconv2dPacked(_) => &[0],
+        Mdl::PoolmaxPacked(_) | Mdl::PoolavgPacked(_) => &[1],
+        _ => &[],
+    }
+}
+
+/// Copies the nodes of expr reachable from its root (not counting attribute
+/// children) into a new graph, converting each with convert
+fn convert_expr<F>(expr: &RecExpr<Mdl>, convert: F) -> RecExpr<Mdl>
+where
+    F: Fn(&Mdl, &[Mdl], &mut RecExpr<Mdl>, &dyn Fn(&Id) -> Id) -> Mdl,
+{
+    let nodes = expr.as_ref();
+    let mut reachable = vec![false; nodes.len()];
+    reachable[nodes.len() - 1] = true;
+    for i in (0..nodes.len()).rev() {
+        if reachable[i] {
+            let attrs = attr_positions(&nodes[i]);
+            for (pos, child) in nodes[i].children().iter().enumerate() {
+                if !attrs.contains(&pos) {
+                    reachable[usize::from(*child)] = true;
+                }
+            }
+        }
+    }
+
+    let mut converted = RecExpr::default();
+    let mut ids: Vec<Option<Id>> = vec![None; nodes.len()];
+    for (i, node) in nodes.iter().enumerate() {
+        if reachable[i] {
+            let new_node = convert(node, nodes, &mut converted, &|id: &Id| ids[usize::from(*id)].unwrap());
+            ids[i] = Some(converted.add(new_node));
+        }
+    }
+    converted
+}
+
+fn attr_val(nodes: &[Mdl], id: &Id) -> i32 {
+    match &nodes[usize::from(*id)] {
+        Mdl::Num(n) => *n,
+        other => panic!("attribute {} is not a scalar", other),
+    }
+}
+
+/// Converts the conv2d and pooling ops of a graph to the packed ops
+pub fn pack_expr(expr: &RecExpr<Mdl>) -> RecExpr<Mdl> {
+    convert_expr(expr, |node, nodes, new, id| {
+        let val = |i: &Id| attr_val(nodes, i);
+        match node {
+            Mdl::Conv2d([stride_h, stride_w, pad, act, inpt, wght]) => {
+                let attrs = new.add(Mdl::Num(pack_attrs(&[val(stride_h), val(stride_w)], val(pad), val(act))));
+                Mdl::Conv2dPacked([attrs, id(inpt), id(wght)])
+            }
+            Mdl::Sconv2d([stride_h, stride_w, pad, act, inpt, wght]) => {
+                let attrs = new.add(Mdl::Num(pack_attrs(&[val(stride_h), val(stride_w)], val(pad), val(act))));
+                Mdl::Sconv2dPacked([attrs, id(inpt), id(wght)])
+            }
+            Mdl::Poolmax([inpt, kernel_h, kernel_w, stride_h, stride_w, pad, act])
+            | Mdl::Poolavg([inpt, kernel_h, kernel_w, stride_h, stride_w, pad, act]) => {
+                let sizes = [val(kernel_h), val(kernel_w), val(stride_h), val(stride_w)];
+                let attrs = new.add(Mdl::Num(pack_attrs(&sizes, val(pad), val(act))));
+                match node {
+                    Mdl::Poolmax(_) => Mdl::PoolmaxPacked([id(inpt), attrs]),
+                    _ => Mdl::PoolavgPacked([id(inpt), attrs]),
+                }
+            }
+            other => other.clone().map_children(|c| id(&c)),
+        }
+    })
+}
+
+/// Converts the packed ops of a graph back to the conv2d and pooling ops
+pub fn unpack_expr(expr: &RecExpr<Mdl>) -> RecExpr<Mdl> {
+    convert_expr(expr, |node, nodes, new, id| {
+        let mut unpack = |attrs: &Id, num_sizes: usize| -> Vec<Id> {
+            let (sizes, pad, act) = unpack_attrs(attr_val(nodes, attrs), num_sizes);
+            sizes
+                .iter()
+                .chain([pad, act].iter())
+                .map(|v| new.add(Mdl::Num(*v)))
+                .collect()
+        };
+        match node {
+            Mdl::Conv2dPacked([attrs, inpt, wght]) => {
+                let a = unpack(attrs, 2);
+                Mdl::Conv2d([a[0], a[1], a[2], a[3], id(inpt), id(wght)])
+            }
+            Mdl::Sconv2dPacked([attrs, inpt, wght]) => {
+                let a = unpack(attrs, 2);
+                Mdl::Sconv2d([a[0], a[1], a[2], a[3], id(inpt), id(wght)])
+            }
+            Mdl::PoolmaxPacked([inpt, attrs]) => {
+                let a = unpack(attrs, 4);
+                Mdl::Poolmax([id(inpt), a[0], a[1], a[2], a[3], a[4], a[5]])
+            }
+            Mdl::PoolavgPacked([inpt, attrs]) => {
+                let a = unpack(attrs, 4);
+                Mdl::Poolavg([id(inpt), a[0], a[1], a[2], a[3], a[4], a[5]])
+            }
+            other => other.clone().map_children(|c| id(&c)),
+        }
+    })
+}
+
+/// Packs the attributes of the ops in a pattern
+///
+/// The attribute variables of an op are replaced by one variable named after
+/// them, e.g. ?sx ?sy ?p ?c by ?attrs_sx_sy_p_c. groups records which group
+/// variable each attribute variable went into.
+fn pack_pattern(pat: &Pattern<Mdl>, groups: &mut HashMap<Var, Var>) -> Result<Pattern<Mdl>, String> {
+    let nodes = pat.ast.as_ref();
+    let mut packed: RecExpr<ENodeOrVar<Mdl>> = RecExpr::default();
+    let mut ids: Vec<Option<Id>> = vec![None; nodes.len()];
+    // Attribute children are only added through the op using them
+    let mut used = vec![false; nodes.len()];
+    used[nodes.len() - 1] = true;
+    for i in (0..nodes.len()).rev() {
+        if let (true, ENodeOrVar::ENode(node)) = (used[i], &nodes[i]) {
+            let attrs = attr_positions(node);
+            for (pos, child) in node.children().iter().enumerate() {
+                if !attrs.contains(&pos) {
+                    used[usize::from(*child)] = true;
+                }
+            }
+        }
+    }
+
+    for (i, node) in nodes.iter().enumerate() {
+        if !used[i] {
+            continue;
+        }
+        let new_node = match node {
+            ENodeOrVar::Var(v) => ENodeOrVar::Var(*v),
+            ENodeOrVar::ENode(e) => {
+                let attrs: Vec<Id> = attr_positions(e).iter().map(|pos| e.children()[*pos]).collect();
+                let attr_id = if attrs.is_empty() {
+                    None
+                } else {
+                    Some(packed.add(pack_attr_group(&attrs, nodes, e, groups)?))
+                };
+                let id = |c: &Id| ids[usize::from(*c)].unwrap();
+                let a = attr_id.unwrap_or(Id::from(0));
+                ENodeOrVar::ENode(match e {
+                    Mdl::Conv2d([_, _, _, _, inpt, wght]) => Mdl::Conv2dPacked([a, id(inpt), id(wght)]),
+                    Mdl::Sconv2d([_, _, _, _, inpt, wght]) => Mdl::Sconv2dPacked([a, id(inpt), id(wght)]),
+                    Mdl::Poolmax([inpt, ..]) => Mdl::PoolmaxPacked([id(inpt), a]),
+                    Mdl::Poolavg([inpt, ..]) => Mdl::PoolavgPacked([id(inpt), a]),
+                    other => other.clone().map_children(|c| id(&c)),
+                })
+            }
+        };
+        ids[i] = Some(packed.add(new_node));
+    }
+    Ok(Pattern::new(packed))
+}
+
+/// Packs the attributes of one op in a pattern, which have to be all
+/// constants or all variables
+fn pack_attr_group(
+    attrs: &[Id],
+    nodes: &[ENodeOrVar<Mdl>],
+    op: &Mdl,
+    groups: &mut HashMap<Var, Var>,
+) -> Result<ENodeOrVar<Mdl>, String> {
+    let num_sizes = attrs.len() - 2;
+    let consts: Vec<i32> = attrs
+        .iter()
+        .filter_map(|id| match &nodes[usize::from(*id)] {
+            ENodeOrVar::ENode(Mdl::Num(n)) => Some(*n),
+            _ => None,
+        })
+        .collect();
+    if consts.len() == attrs.len() {
+        let (sizes, modes) = consts.split_at(num_sizes);
+        if sizes.iter().any(|s| !(1..1 << SIZE_BITS).contains(s)) || modes.iter().any(|m| !(0..1 << MODE_BITS).contains(m)) {
+            return Err(format!("the attributes {:?} of {} can not be packed", consts, op));
+        }
+        let packed = pack_attrs(&consts[..num_sizes], consts[num_sizes], consts[num_sizes + 1]);
+        return Ok(ENodeOrVar::ENode(Mdl::Num(packed)));
+    }
+
+    let vars: Vec<Var> = attrs
+        .iter()
+        .filter_map(|id| match &nodes[usize::from(*id)] {
+            ENodeOrVar::Var(v) => Some(*v),
+            _ => None,
+        })
+        .collect();
+    if vars.len() != attrs.len() {
+        return Err(format!("the attributes of {} are neither all constants nor all variables", op));
+    }
+    if (1..vars.len()).any(|i| vars[..i].contains(&vars[i])) {
+        return Err(format!("the attributes of {} repeat a variable", op));
+    }
+    let name: String = vars.iter().map(|v| format!("_{}", v.to_string().trim_start_matches('?'))).collect();
+    let group = Var::from_str(&format!("?attrs{}", name)).unwrap();
+    for v in vars {
+        match groups.insert(v, group) {
+            Some(other) if other != group => {
+                return Err(format!("{} is used in different attribute groups", v));
+            }
+            _ => (),
+        }
+    }
+    Ok(ENodeOrVar::Var(group))
+}
+
+/// Migrates a set of rules in the format lhs=>rhs to the packed ops
+///
+/// The rules of a set share their variables, e.g. the two rules of a
+/// multi-pattern rule. Either all rules of the set are migrated or none.
+///
+/// # Returns
+///
+/// The migrated rules, or the reason the set can not be migrated
+pub fn pack_rule_set(rules: &[&str]) -> Result<Vec<String>, String> {
+    let mut groups = HashMap::new();
+    let mut packed = vec![];
+    for rule in rules {
+        let eqn: Vec<&str> = rule.split("=>").collect();
+        let lhs: Pattern<Mdl> = eqn[0].parse().unwrap();
+        let rhs: Pattern<Mdl> = eqn[1].parse().unwrap();
+        packed.push((pack_pattern(&lhs, &mut groups)?, pack_pattern(&rhs, &mut groups)?));
+    }
+    // An attribute variable used outside of its group would no longer be
+    // bound to the same value
+    for (lhs, rhs) in packed.iter() {
+        for v in lhs.vars().iter().chain(rhs.vars().iter()) {
+            if groups.contains_key(v) {
+                return Err(format!("{} is used outside of an attribute group", v));
+            }
+        }
+        let lhs_vars = lhs.vars();
+        if let Some(v) = rhs.vars().iter().find(|v| !lhs_vars.contains(v)) {
+            return Err(format!("{} is not bound by the left hand side", v));
+        }
+    }
+    Ok(packed.iter().map(|(lhs, rhs)| format!("{}=>{}", lhs, rhs)).collect())
+}
@@ -0,0 +1,101 @@
+//! TASO's own optimizer, as a baseline and as a seed of the EGraph
+//!
+//! TASO searches sequentially, applying one substitution at a time and
+//! keeping the graphs within alpha of the best one (see ffi::taso_optimize).
+//! Its best graph is imported back (through the format of export_to_file, see
+//! parse.rs) with the names of the inputs and weights of the input graph, so
+//! it can be unioned with the root of the EGraph: the extraction then picks
+//! from both the graphs of the search and those of equality saturation.
+
+use crate::ffi::taso_optimize;
+use crate::model::*;
+use crate::parse::parse_model_with_coverage;
+use egg::*;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+
+/// The graph TASO's optimizer found
+pub struct TasoBaseline {
+    /// The optimized graph, with the inputs and weights of the input graph
+    pub expr: RecExpr<Mdl>,
+    /// Runtime of the input graph, as measured by TASO
+    pub start_runtime: f32,
+    /// Runtime of the optimized graph, as measured by TASO
+    pub runtime: f32,
+}
+
+/// Runs TASO's optimizer on a graph and imports its best graph
+///
+/// # Parameters
+///
+/// - `expr`: the graph to optimize
+/// - `alpha`, `budget`: the settings of the search, see ffi::taso_optimize
+///
+/// # Returns
+///
+/// The optimized graph, or why it could not be imported. Only graphs with a
+/// single output can be imported, since TASO does not keep track of which
+/// output is which
+pub fn run_taso_baseline(expr: &RecExpr<Mdl>, alpha: f32, budget: i32) -> Result<TasoBaseline, String> {
+    let runner = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(expr);
+
+    // Names of the inputs and weights, by the guid of their TASO op
+    let mut names: HashMap<usize, Symbol> = HashMap::new();
+    for class in runner.egraph.classes() {
+        for node in class.nodes.iter() {
+            if let Mdl::Input([name]) | Mdl::Weight([name]) = node {
+                if !class.data.meta.is_null() {
+                    let guid = unsafe { (*class.data.meta.ptr()).op.guid } as usize;
+                    names.insert(guid, runner.egraph[*name].data.name);
+                }
+            }
+        }
+    }
+
+    let file_name = std::env::temp_dir().join(format!("tensat_taso_baseline_{}.txt", std::process::id()));
+    let (start_runtime, runtime) = {
+        let mut g = runner.egraph.analysis.graph.lock().unwrap();
+        let start_runtime = unsafe { g.run() };
+        let optimized = taso_optimize(&mut **g, alpha, budget).map_err(|e| e.to_string())?;
+        unsafe {
+            (*optimized).export_to_file_raw(CString::new(file_name.to_str().unwrap()).unwrap().into_raw());
+            (start_runtime, (*optimized).run())
+        }
+    };
+    let serialized = fs::read_to_string(&file_name).map_err(|e| format!("Could not read TASO's graph: {}", e))?;
+    let _ = fs::remove_file(&file_name);
+
+    let (converter, report) = parse_model_with_coverage(&serialized);
+    if report.num_failed() > 0 {
+        return Err(format!("{} ops of TASO's graph could not be imported", report.num_failed()));
+    }
+    let (imported, layer_names) = converter.rec_expr_with_names();
+    let mut nodes = imported.as_ref().to_vec();
+
+    // The importer names the inputs and weights anew, give them back their
+    // names. The layers are named op_<guid>, see parse_model_with_mode
+    for (id, layer) in layer_names.iter() {
+        if let Mdl::Input([name]) | Mdl::Weight([name]) = nodes[usize::from(*id)] {
+            let guid: usize = layer.trim_start_matches("op_").parse().unwrap();
+            let original = names
+                .get(&guid)
+                .ok_or_else(|| format!("op {} of TASO's graph is not an input or weight of the input graph", guid))?;
+            nodes[usize::from(name)] = Mdl::Var(*original);
+        }
+    }
+
+    let used: HashSet<Id> = nodes.iter().flat_map(|node| node.children().to_vec()).collect();
+    let outputs: Vec<usize> = (0..nodes.len())
+        .filter(|i| !used.contains(&Id::from(*i)) && !matches!(nodes[*i], Mdl::Num(_) | Mdl::Var(_)))
+        .collect();
+    if outputs != vec![nodes.len() - 1] {
+        return Err(format!("TASO's graph has {} outputs, only graphs with one output can be imported", outputs.len()));
+    }
+
+    Ok(TasoBaseline {
+        expr: RecExpr::from(nodes),
+        start_runtime: start_runtime,
+        runtime: runtime,
+    })
+}
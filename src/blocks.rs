@@ -0,0 +1,102 @@
+//! Library of optimized forms of common blocks, unioned into the EGraph at
+//! startup
+//!
+//! Saturation finds these forms too, but each takes several rule steps, and
+//! thus several iterations, which a small node budget may not allow. Each
+//! block is a rule in the syntax of the rules (see rules_from_str) from the
+//! block to its optimized form. Before the first iteration, every match of a
+//! block in the starting EGraph gets its optimized form as an alternative,
+//! checked on the TASO side like any rewrite, and extraction picks between
+//! them.
+//!
+//! A blocks file has one block per line, `name: lhs=>rhs`; empty lines and
+//! lines starting with `#` are skipped.
+
+use crate::model::*;
+use crate::rewrites::rules_from_str;
+use crate::rulefile::parse_rule_text;
+use egg::*;
+use std::fs;
+
+/// The built-in blocks, as (name, rule)
+#[rustfmt::skip]
+pub static BUILTIN_BLOCKS: &[(&str, &str)] = &[
+    // Conv with its relu fused
+    ("conv_relu", "(relu (conv2d ?stride_h ?stride_w ?pad 0 ?input_1 ?input_2))=>(conv2d ?stride_h ?stride_w ?pad 2 ?input_1 ?input_2)"),
+    // Fully connected layer with bias and relu, in one kernel
+    ("dense_relu", "(relu (ewadd (matmul 0 ?input_1 ?input_2) ?input_3))=>(dense 2 ?input_1 ?input_2 ?input_3)"),
+    // Separable conv (depthwise then pointwise), both relus fused
+    ("separable_conv_relu", "(relu (conv2d 1 1 0 0 (relu (conv2d ?stride_h ?stride_w ?pad 0 ?input_1 ?input_2)) ?input_3))=>(conv2d 1 1 0 2 (conv2d ?stride_h ?stride_w ?pad 2 ?input_1 ?input_2) ?input_3)"),
+    // Attention scores q k^T, with the query and key projections in one matmul
+    ("attention_scores", "(matmul 0 (matmul 0 ?input_1 ?input_2) (transpose (matmul 0 ?input_1 ?input_3) 1_0 ?shuffle))=>(matmul 0 (split_0 (split 1 (matmul 0 ?input_1 (concat 1 2 ?input_2 ?input_3)))) (transpose (split_1 (split 1 (matmul 0 ?input_1 (concat 1 2 ?input_2 ?input_3)))) 1_0 ?shuffle))"),
+];
+
+/// Gets the built-in blocks
+pub fn builtin_blocks() -> Vec<(String, String)> {
+    BUILTIN_BLOCKS
+        .iter()
+        .map(|(name, rule)| (name.to_string(), rule.to_string()))
+        .collect()
+}
+
+/// Loads the blocks of a blocks file
+///
+/// # Returns
+///
+/// The blocks as (name, rule), or what is wrong with each invalid line
+pub fn load_blocks(filename: &str) -> Result<Vec<(String, String)>, Vec<String>> {
+    let s = fs::read_to_string(filename).map_err(|e| vec![format!("Could not read {}: {}", filename, e)])?;
+    let mut blocks = vec![];
+    let mut errors = vec![];
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, rule) = match line.find(':') {
+            Some(colon) => (line[..colon].trim(), &line[colon + 1..]),
+            None => {
+                errors.push(format!("line {}: expected name: lhs=>rhs", i + 1));
+                continue;
+            }
+        };
+        match parse_rule_text(rule) {
+            Ok(mut parsed) if parsed.multi_rules.is_empty() => blocks.push((name.to_string(), parsed.rules.pop().unwrap())),
+            Ok(_) => errors.push(format!("line {}: a block has one pattern", i + 1)),
+            Err(e) => errors.push(format!("line {}: {}", i + 1, e.join(", "))),
+        }
+    }
+    if errors.is_empty() {
+        Ok(blocks)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Adds the optimized form of every match of the blocks in an EGraph
+///
+/// # Parameters
+///
+/// - `egraph`: the starting EGraph
+/// - `blocks`: the blocks, as (name, rule)
+/// - `filter_after`: as for rules_from_str
+///
+/// # Returns
+///
+/// For each block, its name and the number of its matches that got their
+/// optimized form
+pub fn union_blocks(
+    egraph: &mut EGraph<Mdl, TensorAnalysis>,
+    blocks: &[(String, String)],
+    filter_after: bool,
+) -> Vec<(String, usize)> {
+    let rewrites = rules_from_str(blocks.iter().map(|(_, rule)| rule.as_str()).collect(), filter_after);
+    let mut counts = vec![];
+    for ((name, _), rewrite) in blocks.iter().zip(rewrites.iter()) {
+        let matches = rewrite.search(egraph);
+        let num_added = rewrite.apply(egraph, &matches).len();
+        counts.push((name.clone(), num_added));
+    }
+    egraph.rebuild();
+    counts
+}
@@ -0,0 +1,86 @@
+//! Splitting a wall-clock budget across the phases of an optimization run
+//!
+//! The node and time limits that fit a budget vary a lot between models:
+//! how fast the EGraph grows depends on how many new ops TASO has to
+//! measure, and how long the ILP takes depends on the size of the EGraph.
+//! A short probe saturation measures the growth rate, the ILP rate comes from
+//! previous runs, and the saturation is sized so that the extraction of the
+//! EGraph it grows still fits in the budget.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Fraction of the budget spent on the probe saturation
+pub const PROBE_FRACTION: f32 = 0.05;
+
+/// ILP rate assumed before any run measured it, in EGraph nodes per second
+pub const DEFAULT_ILP_NODES_PER_SEC: f32 = 2000.0;
+
+/// Rates observed for a model
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ObservedRates {
+    /// Nodes added per second of saturation, including measuring new ops
+    pub nodes_per_sec: f32,
+    /// EGraph nodes the ILP solves per second
+    pub ilp_nodes_per_sec: f32,
+    /// Seconds to measure the runtime of the full graph once
+    pub measure_sec: f32,
+}
+
+/// Limits for one run
+#[derive(Debug, Clone, Copy)]
+pub struct BudgetPlan {
+    /// Time limit of saturation
+    pub saturation_sec: u64,
+    /// Node limit of saturation
+    pub node_limit: usize,
+    /// Time limit of the ILP solver, None if not extracting by ILP
+    pub ilp_sec: Option<u64>,
+}
+
+/// Splits the remaining budget between saturation and extraction
+///
+/// The measurement of the starting and the extracted graph is reserved
+/// first. Saturating for t seconds grows the EGraph by nodes_per_sec * t
+/// nodes, which the ILP solves in nodes_per_sec * t / ilp_nodes_per_sec
+/// seconds, so t is chosen for both to add up to the rest of the budget.
+///
+/// # Parameters
+///
+/// - `remaining_sec`: budget left, after the probe
+/// - `initial_nodes`: size of the starting EGraph
+/// - `use_ilp`: if the extraction is by ILP (greedy extraction takes no
+///   noticeable time)
+pub fn plan_budget(remaining_sec: f32, initial_nodes: usize, rates: &ObservedRates, use_ilp: bool) -> BudgetPlan {
+    let available = (remaining_sec - 2.0 * rates.measure_sec).max(1.0);
+    let saturation_sec = if use_ilp {
+        (available - initial_nodes as f32 / rates.ilp_nodes_per_sec) / (1.0 + rates.nodes_per_sec / rates.ilp_nodes_per_sec)
+    } else {
+        available
+    };
+    let saturation_sec = saturation_sec.max(1.0);
+    let node_limit = initial_nodes + (rates.nodes_per_sec * saturation_sec) as usize;
+    BudgetPlan {
+        saturation_sec: saturation_sec.ceil() as u64,
+        node_limit: node_limit,
+        ilp_sec: if use_ilp {
+            Some(((available - saturation_sec).max(1.0)).ceil() as u64)
+        } else {
+            None
+        },
+    }
+}
+
+/// Gets the ILP rate a previous run recorded in dir, if any
+pub fn load_ilp_rate(dir: &str) -> Option<f32> {
+    let s = fs::read_to_string(Path::new(dir).join("budget_rates.json")).ok()?;
+    let rates: ObservedRates = serde_json::from_str(&s).ok()?;
+    Some(rates.ilp_nodes_per_sec)
+}
+
+/// Records the rates observed in a run in dir, for the next runs
+pub fn save_rates(dir: &str, rates: &ObservedRates) {
+    fs::write(Path::new(dir).join("budget_rates.json"), serde_json::to_string(rates).unwrap())
+        .expect("Unable to write file");
+}
@@ -0,0 +1,200 @@
+//! Typed API for building input graphs in Rust
+//!
+//! GraphBuilder wraps GraphConverter with typed arguments: Tensor handles
+//! instead of Ids, and Stride, Padding and Activation instead of the i32
+//! codes of TASO, so a misplaced or missing argument does not compile. The
+//! graph is read with build, which ties several outputs together with noop
+//! nodes, as the pre-defined models do.
+
+use crate::input::*;
+use crate::model::*;
+use egg::*;
+
+/// A tensor of the graph being built
+#[derive(Copy, Clone)]
+pub struct Tensor {
+    info: TensorInfo,
+}
+
+impl Tensor {
+    /// Index of the node producing the tensor in the graph
+    pub fn id(&self) -> Id {
+        self.info.id
+    }
+
+    /// The dimensions, as inferred by GraphConverter
+    pub fn dims(&self) -> &[i32] {
+        &self.info.shape[..self.info.n_dim]
+    }
+}
+
+/// Strides of a conv or a pooling, along the height and the width
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stride(pub i32, pub i32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    Same,
+    Valid,
+}
+
+impl Padding {
+    fn code(self) -> i32 {
+        match self {
+            Padding::Same => PSAME,
+            Padding::Valid => PVALID,
+        }
+    }
+}
+
+/// Activation fused into an op
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    None,
+    Sigmoid,
+    Relu,
+    Tanh,
+}
+
+impl Activation {
+    fn code(self) -> i32 {
+        match self {
+            Activation::None => ACTNONE,
+            Activation::Sigmoid => ACTSIGMOID,
+            Activation::Relu => ACTRELU,
+            Activation::Tanh => ACTTANH,
+        }
+    }
+}
+
+/// Builds an input graph, see the module documentation
+#[derive(Default)]
+pub struct GraphBuilder {
+    graph: GraphConverter,
+}
+
+impl GraphBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn wrap(info: TensorInfo) -> Tensor {
+        Tensor { info: info }
+    }
+
+    pub fn input(&mut self, dims: &[i32]) -> Tensor {
+        Self::wrap(self.graph.new_input(dims))
+    }
+
+    pub fn named_input(&mut self, name: &str, dims: &[i32]) -> Tensor {
+        Self::wrap(self.graph.new_named_input(name, dims))
+    }
+
+    pub fn weight(&mut self, dims: &[i32]) -> Tensor {
+        Self::wrap(self.graph.new_weight(dims))
+    }
+
+    pub fn named_weight(&mut self, name: &str, dims: &[i32]) -> Tensor {
+        Self::wrap(self.graph.new_named_weight(name, dims))
+    }
+
+    pub fn conv2d(&mut self, input: Tensor, weight: Tensor, stride: Stride, padding: Padding, activation: Activation) -> Tensor {
+        Self::wrap(self.graph.conv2d(input.info, weight.info, stride.0, stride.1, padding.code(), activation.code()))
+    }
+
+    pub fn matmul(&mut self, input: Tensor, weight: Tensor) -> Tensor {
+        Self::wrap(self.graph.matmul(input.info, weight.info))
+    }
+
+    /// Matmul followed by adding the bias and the activation (see Mdl::Dense)
+    pub fn dense(&mut self, input: Tensor, weight: Tensor, bias: Tensor, activation: Activation) -> Tensor {
+        Self::wrap(self.graph.dense(input.info, weight.info, bias.info, activation.code()))
+    }
+
+    pub fn batchnorm(&mut self, input: Tensor, scale: Tensor, bias: Tensor, mean: Tensor, var: Tensor) -> Tensor {
+        Self::wrap(self.graph.batchnorm(input.info, scale.info, bias.info, mean.info, var.info))
+    }
+
+    pub fn relu(&mut self, input: Tensor) -> Tensor {
+        Self::wrap(self.graph.relu(input.info))
+    }
+
+    pub fn tanh(&mut self, input: Tensor) -> Tensor {
+        Self::wrap(self.graph.tanh(input.info))
+    }
+
+    pub fn sigmoid(&mut self, input: Tensor) -> Tensor {
+        Self::wrap(self.graph.sigmoid(input.info))
+    }
+
+    pub fn dropout(&mut self, input: Tensor) -> Tensor {
+        Self::wrap(self.graph.dropout(input.info))
+    }
+
+    pub fn add(&mut self, input_1: Tensor, input_2: Tensor) -> Tensor {
+        Self::wrap(self.graph.add(input_1.info, input_2.info))
+    }
+
+    pub fn mul(&mut self, input_1: Tensor, input_2: Tensor) -> Tensor {
+        Self::wrap(self.graph.mul(input_1.info, input_2.info))
+    }
+
+    pub fn softmax(&mut self, input: Tensor, axis: i32) -> Tensor {
+        Self::wrap(self.graph.softmax(input.info, axis))
+    }
+
+    pub fn reshape(&mut self, input: Tensor, dims: &[i32]) -> Tensor {
+        Self::wrap(self.graph.reshape(input.info, dims))
+    }
+
+    pub fn transpose(&mut self, input: Tensor, perm: &[i32], shuffle: bool) -> Tensor {
+        Self::wrap(self.graph.transpose(input.info, perm, shuffle))
+    }
+
+    /// Concatenates at least two tensors along an axis
+    pub fn concat(&mut self, axis: i32, inputs: &[Tensor]) -> Tensor {
+        assert!(inputs.len() >= 2, "A concat takes at least two inputs");
+        let infos: Vec<TensorInfo> = inputs.iter().map(|t| t.info).collect();
+        Self::wrap(self.graph.concat_multi(axis, &infos))
+    }
+
+    pub fn maxpool2d(&mut self, input: Tensor, kernel: (i32, i32), stride: Stride, padding: Padding) -> Tensor {
+        Self::wrap(self.graph.maxpool2d(input.info, kernel.0, kernel.1, stride.0, stride.1, padding.code()))
+    }
+
+    pub fn avgpool2d(&mut self, input: Tensor, kernel: (i32, i32), stride: Stride, padding: Padding) -> Tensor {
+        Self::wrap(self.graph.avgpool2d(input.info, kernel.0, kernel.1, stride.0, stride.1, padding.code()))
+    }
+
+    /// Gets the graph computing the outputs
+    ///
+    /// The outputs are tied together with noop nodes. Nodes the outputs do
+    /// not depend on are left out.
+    pub fn build(mut self, outputs: &[Tensor]) -> RecExpr<Mdl> {
+        assert!(!outputs.is_empty(), "A graph has at least one output");
+        let root = outputs[1..]
+            .iter()
+            .fold(outputs[0].info, |tied, output| self.graph.noop(tied, output.info));
+        let expr = self.graph.rec_expr();
+
+        // Copy the nodes the root depends on, in order
+        let nodes = expr.as_ref();
+        let mut used = vec![false; nodes.len()];
+        used[usize::from(root.id)] = true;
+        for i in (0..nodes.len()).rev() {
+            if used[i] {
+                for child in nodes[i].children() {
+                    used[usize::from(*child)] = true;
+                }
+            }
+        }
+        let mut new_ids: Vec<Id> = vec![Id::from(0); nodes.len()];
+        let mut built = RecExpr::default();
+        for (i, node) in nodes.iter().enumerate().take(usize::from(root.id) + 1) {
+            if used[i] {
+                new_ids[i] = built.add(node.clone().map_children(|child| new_ids[usize::from(child)]));
+            }
+        }
+        built
+    }
+}
@@ -0,0 +1,176 @@
+//! Bundles of what is needed to reproduce a failed run
+//!
+//! With a bundle directory set (see enable), the optimizer records its
+//! settings, rules, input graph and the EGraph as the run goes, and when the
+//! run panics or exits with an error, writes them to the directory along with
+//! the input files and the output written so far:
+//!
+//! - `failure.txt`: why the run failed, followed by the log of its stages
+//! - `settings.json`: the command line flags
+//! - `rules.txt`, `multi_rules.txt`: the rules of the run, one per line
+//! - `input.txt`: the input graph in the textual format (see text.rs)
+//! - `egraph_snapshot.json`: the EGraph before the last iteration, or after
+//!   saturation (see snapshot::EGraphSnapshot)
+//! - `files/`: the files passed on the command line (model, rules, ...)
+//! - `output/`: the files in the output directory of the run
+//!
+//! Nothing is written if the run succeeds.
+
+use crate::model::*;
+use crate::snapshot::EGraphSnapshot;
+use crate::text::to_text;
+use egg::*;
+use once_cell::sync::Lazy;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// What a run recorded so far
+struct FailureBundle {
+    dir: PathBuf,
+    output_dir: PathBuf,
+    settings: serde_json::Value,
+    files: Vec<PathBuf>,
+    rules: Vec<String>,
+    multi_rules: Vec<String>,
+    input: Option<String>,
+    snapshot: Option<EGraphSnapshot>,
+    /// Stages of the run, with the seconds since it started
+    log: Vec<String>,
+    start: Instant,
+}
+
+static BUNDLE: Lazy<Mutex<Option<FailureBundle>>> = Lazy::new(Default::default);
+
+/// Runs f on the bundle, if enabled. Does nothing if the bundle is in use,
+/// e.g. when the panic happened while recording
+fn with_bundle<F: FnOnce(&mut FailureBundle)>(f: F) {
+    if let Ok(mut bundle) = BUNDLE.try_lock() {
+        if let Some(bundle) = bundle.as_mut() {
+            f(bundle);
+        }
+    }
+}
+
+/// Enables writing a bundle to dir when the run fails, and installs a panic
+/// hook writing it (after the default hook has printed the panic)
+///
+/// # Parameters
+///
+/// - `dir`: the directory to write the bundle to
+/// - `output_dir`: the output directory of the run
+/// - `settings`: the command line flags, as written to settings.txt
+/// - `files`: the files passed on the command line, copied into the bundle
+pub fn enable(dir: &str, output_dir: &str, settings: serde_json::Value, files: Vec<String>) {
+    *BUNDLE.lock().unwrap() = Some(FailureBundle {
+        dir: PathBuf::from(dir),
+        output_dir: PathBuf::from(output_dir),
+        settings: settings,
+        files: files.iter().map(PathBuf::from).collect(),
+        rules: vec![],
+        multi_rules: vec![],
+        input: None,
+        snapshot: None,
+        log: vec![],
+        start: Instant::now(),
+    });
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        write_bundle(&format!("panic: {}", info));
+    }));
+}
+
+/// Records a stage of the run in the log of the bundle
+pub fn log_stage(message: &str) {
+    with_bundle(|b| {
+        let line = format!("[{:.3}s] {}", b.start.elapsed().as_secs_f32(), message);
+        b.log.push(line);
+    });
+}
+
+pub fn record_rules(rules: &[&str]) {
+    with_bundle(|b| b.rules = rules.iter().map(|r| r.to_string()).collect());
+}
+
+pub fn record_multi_rules(rules: &[(&str, bool)]) {
+    with_bundle(|b| b.multi_rules = rules.iter().map(|(r, _)| r.to_string()).collect());
+}
+
+pub fn record_input(expr: &RecExpr<Mdl>) {
+    with_bundle(|b| b.input = Some(to_text(expr, None)));
+}
+
+/// Records the EGraph, replacing the one recorded before
+pub fn record_egraph(egraph: &EGraph<Mdl, TensorAnalysis>) {
+    with_bundle(|b| b.snapshot = Some(EGraphSnapshot::take(egraph)));
+}
+
+/// If a bundle is written when the run fails
+pub fn is_enabled() -> bool {
+    BUNDLE.try_lock().map_or(false, |b| b.is_some())
+}
+
+/// Copies the files of dir (not its subdirectories) into target
+fn copy_dir(dir: &Path, target: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            fs::copy(&path, target.join(path.file_name().unwrap()))?;
+        }
+    }
+    Ok(())
+}
+
+impl FailureBundle {
+    fn write(&self, reason: &str) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut failure = format!("{}\n\nLog:\n", reason);
+        for line in self.log.iter() {
+            failure = format!("{}{}\n", failure, line);
+        }
+        fs::write(self.dir.join("failure.txt"), failure)?;
+        fs::write(self.dir.join("settings.json"), serde_json::to_string_pretty(&self.settings).unwrap())?;
+        fs::write(self.dir.join("rules.txt"), self.rules.join("\n"))?;
+        if !self.multi_rules.is_empty() {
+            fs::write(self.dir.join("multi_rules.txt"), self.multi_rules.join("\n"))?;
+        }
+        if let Some(input) = &self.input {
+            fs::write(self.dir.join("input.txt"), input)?;
+        }
+        if let Some(snapshot) = &self.snapshot {
+            fs::write(self.dir.join("egraph_snapshot.json"), serde_json::to_string(snapshot).unwrap())?;
+        }
+        let files_dir = self.dir.join("files");
+        for file in self.files.iter().filter(|f| f.is_file()) {
+            fs::create_dir_all(&files_dir)?;
+            fs::copy(file, files_dir.join(file.file_name().unwrap()))?;
+        }
+        // Only files are copied, so a bundle inside the output directory is
+        // not copied into itself
+        if self.output_dir.is_dir() {
+            copy_dir(&self.output_dir, &self.dir.join("output"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the bundle, if enabled
+///
+/// # Parameters
+///
+/// - `reason`: why the run failed, the first line of failure.txt
+pub fn write_bundle(reason: &str) {
+    with_bundle(|b| match b.write(reason) {
+        Ok(()) => eprintln!("Wrote a failure bundle to {}", b.dir.display()),
+        Err(e) => eprintln!("Could not write the failure bundle to {}: {}", b.dir.display(), e),
+    });
+}
+
+/// Writes the bundle, if enabled, and exits with an error
+pub fn exit_failure(reason: &str) -> ! {
+    write_bundle(reason);
+    std::process::exit(1);
+}
@@ -0,0 +1,116 @@
+//! Reporting the rules that did not apply all their matches
+//!
+//! A saturation converged when no rule adds anything to the EGraph. A run
+//! stopped by its node, time or iteration limit, or whose schedulers skipped
+//! rules with too many matches (egg's BackoffScheduler, the caps of
+//! schedule.rs), may have left matches unapplied, and raising the limits
+//! could still find a better graph. Such rules are recorded as the run goes:
+//!
+//! - CapRecorder, wrapping the scheduler of the single-pattern rules, counts
+//!   the iterations in which the scheduler skipped a rule that had matches
+//! - MultiPatterns counts the rounds in which a multi-pattern rule stopped
+//!   at its cap, or was cut short by the node or time limit of the round
+//! - record_stop marks the rules still applying in the last iteration of a
+//!   saturation stopped by a limit
+//!
+//! and are reported as warnings after the saturation (see capped_warnings).
+
+use crate::model::*;
+use egg::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// How a rule was kept from applying all its matches
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CappedRule {
+    /// Number of iterations in which the scheduler skipped the rule while it
+    /// had matches
+    pub skipped_iterations: usize,
+    /// Number of rounds of the multi-pattern rules in which the rule stopped
+    /// at its cap or at the limits of the round
+    pub capped_rounds: usize,
+    /// If the rule was applied in the last iteration of a saturation
+    /// stopped by a limit
+    pub applied_at_stop: bool,
+}
+
+/// Capped rules by name, shared between the schedulers and the caller
+pub type CappedRules = Arc<Mutex<BTreeMap<String, CappedRule>>>;
+
+/// Scheduler recording the rules that its inner scheduler skips while they
+/// have matches
+pub struct CapRecorder {
+    inner: Box<dyn RewriteScheduler<Mdl, TensorAnalysis>>,
+    capped: CappedRules,
+}
+
+impl CapRecorder {
+    pub fn new<S: RewriteScheduler<Mdl, TensorAnalysis> + 'static>(inner: S, capped: CappedRules) -> Self {
+        CapRecorder {
+            inner: Box::new(inner),
+            capped: capped,
+        }
+    }
+}
+
+impl RewriteScheduler<Mdl, TensorAnalysis> for CapRecorder {
+    fn can_stop(&mut self, iteration: usize) -> bool {
+        self.inner.can_stop(iteration)
+    }
+
+    fn search_rewrite<'a>(
+        &mut self,
+        iteration: usize,
+        egraph: &EGraph<Mdl, TensorAnalysis>,
+        rewrite: &'a Rewrite<Mdl, TensorAnalysis>,
+    ) -> Vec<SearchMatches<'a, Mdl>> {
+        let matches = self.inner.search_rewrite(iteration, egraph, rewrite);
+        // One match is enough to know the rule was skipped
+        if matches.is_empty() && !rewrite.search_with_limit(egraph, 1).is_empty() {
+            let mut capped = self.capped.lock().unwrap();
+            capped.entry(rewrite.name.to_string()).or_default().skipped_iterations += 1;
+        }
+        matches
+    }
+}
+
+/// Marks the rules applied in the last iteration of a runner, if a limit
+/// stopped it before the saturation converged
+pub fn record_stop(capped: &CappedRules, runner: &Runner<Mdl, TensorAnalysis, ()>) {
+    if matches!(runner.stop_reason, None | Some(StopReason::Saturated)) {
+        return;
+    }
+    let mut capped = capped.lock().unwrap();
+    if let Some(last) = runner.iterations.last() {
+        for (rule, n) in last.applied.iter() {
+            if *n > 0 {
+                capped.entry(rule.to_string()).or_default().applied_at_stop = true;
+            }
+        }
+    }
+}
+
+/// Describes the capped rules, one warning per rule
+pub fn capped_warnings(capped: &BTreeMap<String, CappedRule>) -> Vec<String> {
+    capped
+        .iter()
+        .filter_map(|(rule, c)| {
+            let mut reasons = vec![];
+            if c.skipped_iterations > 0 {
+                reasons.push(format!("skipped by the scheduler with matches in {} iterations", c.skipped_iterations));
+            }
+            if c.capped_rounds > 0 {
+                reasons.push(format!("stopped at its cap or the round limits in {} rounds", c.capped_rounds));
+            }
+            if c.applied_at_stop {
+                reasons.push(String::from("still applying when a limit stopped the saturation"));
+            }
+            if reasons.is_empty() {
+                None
+            } else {
+                Some(format!("{} did not apply all its matches: {}", rule, reasons.join(", ")))
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,152 @@
+//! Checkpoints of the saturated EGraph, to extract from it in a later run
+//!
+//! The analysis data of an eclass points to tensors on the TASO side, so it
+//! is not saved: only the enodes of each eclass (the op and the eclasses of
+//! the children) and which enodes are blacklisted (see remove_cycle_by_order).
+//! Restoring adds the enodes again, which makes the analysis create the
+//! tensors anew, and merges the enodes of each eclass. The analysis of the
+//! restored EGraph gets the settings of the saturation (AnalysisSettings).
+//! The settings that change the EGraph (e.g. --packed_attrs) have to be the
+//! same in the run restoring it.
+
+use crate::model::*;
+use egg::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+/// An enode of a checkpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedNode {
+    pub op: String,
+    /// Ids of the eclasses of the children, in the checkpoint
+    pub children: Vec<usize>,
+    pub blacklisted: bool,
+}
+
+/// A saturated EGraph, with what the saturation reported
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EGraphCheckpoint {
+    /// The enodes of each eclass, by eclass Id
+    pub classes: BTreeMap<usize, Vec<SavedNode>>,
+    pub roots: Vec<usize>,
+    pub num_iterations: usize,
+    pub saturation_secs: f32,
+    pub stop_reason: String,
+    /// Settings of the analysis of the saturation
+    #[serde(default)]
+    pub analysis: AnalysisSettings,
+}
+
+impl EGraphCheckpoint {
+    /// Takes a checkpoint of the EGraph of a runner after saturation
+    ///
+    /// # Parameters
+    ///
+    /// - `runner`: the runner, after saturation
+    /// - `saturation_secs`: how long the saturation took
+    pub fn take(runner: &Runner<Mdl, TensorAnalysis, ()>, saturation_secs: f32) -> Self {
+        let egraph = &runner.egraph;
+        let classes = egraph
+            .classes()
+            .map(|class| {
+                let nodes = class
+                    .iter()
+                    .map(|node| SavedNode {
+                        op: node.to_string(),
+                        children: node.children().iter().map(|id| usize::from(egraph.find(*id))).collect(),
+                        blacklisted: egraph.analysis.blacklist_nodes.contains(node),
+                    })
+                    .collect();
+                (usize::from(class.id), nodes)
+            })
+            .collect();
+        EGraphCheckpoint {
+            classes: classes,
+            roots: runner.roots.iter().map(|id| usize::from(egraph.find(*id))).collect(),
+            num_iterations: runner.iterations.len().saturating_sub(1),
+            saturation_secs: saturation_secs,
+            stop_reason: format!("{:?}", runner.stop_reason.as_ref().unwrap()),
+            analysis: egraph.analysis.settings(),
+        }
+    }
+
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let s = fs::read_to_string(filename).map_err(|e| format!("Could not read {}: {}", filename, e))?;
+        serde_json::from_str(&s).map_err(|e| format!("Could not parse {}: {}", filename, e))
+    }
+
+    pub fn save(&self, filename: &str) {
+        fs::write(filename, serde_json::to_string(self).unwrap()).expect("Unable to write file");
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.classes.values().map(|nodes| nodes.len()).sum()
+    }
+
+    /// Rebuilds the EGraph
+    ///
+    /// An enode is added once an enode of each of its child eclasses was,
+    /// so the enodes are added in rounds (enodes on cycles come after the
+    /// other enodes of their eclasses). The analysis of the new EGraph has
+    /// the settings of the saturation.
+    ///
+    /// # Returns
+    ///
+    /// The EGraph and its roots, or why the checkpoint is not a valid EGraph
+    pub fn restore(&self) -> Result<(EGraph<Mdl, TensorAnalysis>, Vec<Id>), String> {
+        let mut egraph = EGraph::new(TensorAnalysis::with_settings(&self.analysis)?);
+        let mut ids: HashMap<usize, Id> = HashMap::new();
+        let mut blacklisted: Vec<Mdl> = vec![];
+        let mut pending: Vec<(usize, &SavedNode)> = self
+            .classes
+            .iter()
+            .flat_map(|(class, nodes)| nodes.iter().map(move |node| (*class, node)))
+            .collect();
+
+        while !pending.is_empty() {
+            let mut rest = vec![];
+            for (class, saved) in pending.iter() {
+                if !saved.children.iter().all(|child| ids.contains_key(child)) {
+                    rest.push((*class, *saved));
+                    continue;
+                }
+                let children = saved.children.iter().map(|child| ids[child]).collect();
+                let node = Mdl::from_op(&saved.op, children)
+                    .map_err(|e| format!("Could not restore {} in eclass {}: {}", saved.op, class, e))?;
+                if saved.blacklisted {
+                    blacklisted.push(node.clone());
+                }
+                let id = egraph.add(node);
+                match ids.get(class) {
+                    Some(existing) => {
+                        egraph.union(*existing, id);
+                    }
+                    None => {
+                        ids.insert(*class, id);
+                    }
+                }
+            }
+            if rest.len() == pending.len() {
+                return Err(format!("{} enodes have children that are not in the checkpoint", rest.len()));
+            }
+            pending = rest;
+        }
+        egraph.rebuild();
+
+        egraph.analysis.blacklist_nodes = blacklisted
+            .into_iter()
+            .map(|node| node.map_children(|id| egraph.find(id)))
+            .collect();
+        let roots = self
+            .roots
+            .iter()
+            .map(|root| {
+                ids.get(root)
+                    .map(|id| egraph.find(*id))
+                    .ok_or_else(|| format!("Root {} is not in the checkpoint", root))
+            })
+            .collect::<Result<Vec<Id>, String>>()?;
+        Ok((egraph, roots))
+    }
+}
@@ -0,0 +1,169 @@
+//! Regression corpus for the extraction backends
+//!
+//! A case is a small EGraph serialized in the format of the ILP data (see
+//! prep_ilp_data), together with the cost of its optimal extraction. The
+//! costs are part of the case, so running it does not need TASO. Every
+//! backend has to return a valid graph for every case, and the exact
+//! backends (the ILP) have to find the optimal cost.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// A serialized EGraph with a known optimal extraction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtractionCase {
+    /// e_m[m] is the list of nodes i within eclass m
+    pub e_m: Vec<Vec<usize>>,
+    /// h_i[i] is the list of children eclasses of node i
+    pub h_i: Vec<Vec<usize>>,
+    /// Self cost of each node i
+    pub cost_i: Vec<f32>,
+    /// g_i[i] is the eclass of node i
+    pub g_i: Vec<usize>,
+    /// Eclass of the root
+    pub root_m: usize,
+    /// Nodes that can not be picked
+    #[serde(default)]
+    pub blacklist_i: Vec<usize>,
+    /// Cost of the optimal extraction
+    pub optimal_cost: f32,
+}
+
+/// Result of running one backend on one case
+#[derive(Debug, Clone, Serialize)]
+pub struct CaseResult {
+    pub case: String,
+    pub backend: String,
+    /// Cost of the extracted graph, if it is valid
+    pub cost: Option<f32>,
+    /// Why the backend failed the case
+    pub error: Option<String>,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Loads all cases (*.json) in a directory, sorted by file name
+pub fn load_corpus(dir: &str) -> Vec<(String, ExtractionCase)> {
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .expect("Unable to read the corpus directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |e| e == "json"))
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| {
+            let s = fs::read_to_string(path).expect("Unable to read the corpus case");
+            let case = serde_json::from_str(&s)
+                .unwrap_or_else(|e| panic!("Could not parse corpus case {}: {}", path.display(), e));
+            (path.file_stem().unwrap().to_string_lossy().to_string(), case)
+        })
+        .collect()
+}
+
+/// Gets the picked node of each eclass from the solved ILP variables
+pub fn solution_from_x(case: &ExtractionCase, solved_x: &[i32]) -> Vec<Option<usize>> {
+    let mut picked = vec![None; case.e_m.len()];
+    for (i, x_i) in solved_x.iter().enumerate() {
+        if *x_i == 1 && picked[case.g_i[i]].is_none() {
+            picked[case.g_i[i]] = Some(i);
+        }
+    }
+    picked
+}
+
+/// Extracts greedily, picking the node with the smallest tree cost in each
+/// eclass, like egg's Extractor
+pub fn greedy_solution(case: &ExtractionCase) -> Vec<Option<usize>> {
+    let mut class_cost = vec![f32::INFINITY; case.e_m.len()];
+    let mut picked = vec![None; case.e_m.len()];
+    loop {
+        let mut changed = false;
+        for (i, cost) in case.cost_i.iter().enumerate() {
+            if case.blacklist_i.contains(&i) {
+                continue;
+            }
+            let total = cost + case.h_i[i].iter().map(|m| class_cost[*m]).sum::<f32>();
+            if total < class_cost[case.g_i[i]] {
+                class_cost[case.g_i[i]] = total;
+                picked[case.g_i[i]] = Some(i);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    picked
+}
+
+/// Gets the cost of an extracted graph, counting each picked node reachable
+/// from the root once
+///
+/// # Returns
+///
+/// The cost, or why the picked nodes are not a valid graph
+pub fn solution_cost(case: &ExtractionCase, picked: &[Option<usize>]) -> Result<f32, String> {
+    // 0: not visited, 1: on the current path, 2: done
+    let mut state = vec![0u8; case.e_m.len()];
+    fn visit(case: &ExtractionCase, picked: &[Option<usize>], m: usize, state: &mut [u8]) -> Result<f32, String> {
+        match state[m] {
+            1 => return Err(format!("the picked nodes form a cycle through eclass {}", m)),
+            2 => return Ok(0.0),
+            _ => (),
+        }
+        let i = picked[m].ok_or_else(|| format!("no node picked in eclass {}", m))?;
+        if case.g_i[i] != m {
+            return Err(format!("node {} picked in eclass {} is in eclass {}", i, m, case.g_i[i]));
+        }
+        if case.blacklist_i.contains(&i) {
+            return Err(format!("node {} picked in eclass {} is blacklisted", i, m));
+        }
+        state[m] = 1;
+        let mut cost = case.cost_i[i];
+        for child in case.h_i[i].iter() {
+            cost += visit(case, picked, *child, state)?;
+        }
+        state[m] = 2;
+        Ok(cost)
+    }
+    visit(case, picked, case.root_m, &mut state)
+}
+
+/// Checks the solution of a backend on a case
+///
+/// # Parameters
+///
+/// - `exact`: if the backend has to find the optimal cost, otherwise it only
+///   can not be cheaper than the optimum
+pub fn check_solution(
+    name: &str,
+    case: &ExtractionCase,
+    backend: &str,
+    solution: Result<Vec<Option<usize>>, String>,
+    exact: bool,
+) -> CaseResult {
+    let cost = solution.and_then(|picked| solution_cost(case, &picked));
+    let tolerance = 1e-4 * case.optimal_cost.abs().max(1.0);
+    let error = match &cost {
+        Err(e) => Some(e.clone()),
+        Ok(c) if *c < case.optimal_cost - tolerance => Some(format!(
+            "cost {} is below the optimal cost {}, the case or the cost computation is wrong",
+            c, case.optimal_cost
+        )),
+        Ok(c) if exact && *c > case.optimal_cost + tolerance => {
+            Some(format!("cost {} is not the optimal cost {}", c, case.optimal_cost))
+        }
+        Ok(_) => None,
+    };
+    CaseResult {
+        case: name.to_string(),
+        backend: backend.to_string(),
+        cost: cost.ok(),
+        error: error,
+    }
+}
@@ -0,0 +1,576 @@
+//! Where the cost model gets the runtimes of ops from
+//!
+//! CostModel converts runtimes to the objective of the extraction, and gets
+//! the runtimes from a RuntimeModel: measured by TASO on the local GPU
+//! (MeasuredRuntime, the default), estimated from the shapes of the ops
+//! (AnalyticalRuntime), or looked up in a table of runtimes measured
+//! elsewhere (RuntimeTable). The analysis still creates every op on the TASO
+//! side to infer its shape, and TASO measures an op when it is first created,
+//! so the other runtime models only decide the extraction; saturating without
+//! the target GPU needs a TASO build that skips the measurements.
+//!
+//! The analysis and the costs are two phases: the analysis infers the shape
+//! and type of each eclass as enodes are added during saturation, which rule
+//! conditions and validation need right away. The runtimes are only needed by
+//! the extraction, CostModel::populate_runtimes computes them for all enodes
+//! in one pass after saturation (see PopulatedRuntime).
+//!
+//! The cost phase can also ask the runtime model for only some of the ops
+//! (see ShapeSampling): ops are put in buckets of similar shapes, with one
+//! representative asked for each bucket, and only a sample of the buckets of
+//! each op type is asked, the runtimes of the others being interpolated. The
+//! analytical runtimes tell how a runtime scales between shapes. With the
+//! stock TASO the analysis has measured every op anyway, so this does not
+//! make a run with measured runtimes faster; what it saves is the shapes
+//! that have to be measured on the target GPU for a --runtime_table (the
+//! keys of the representatives asked, see SamplingReport::measured_keys).
+
+use crate::model::*;
+use crate::optimize::*;
+use crate::shape::{shape_dims, with_shape, ElemType};
+use egg::*;
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+extern "C" {
+    fn flock(fd: i32, operation: i32) -> i32;
+}
+
+/// Exclusive lock of flock
+const LOCK_EX: i32 = 2;
+
+/// Gives the runtime of enodes
+pub trait RuntimeModel: Send + Sync {
+    /// Gets the runtime of an enode
+    ///
+    /// # Parameters
+    ///
+    /// - `cost_model`: the cost model asking, for its settings (e.g.
+    ///   discount_all_weights)
+    /// - `egraph`: E-graph of interest
+    /// - `enode`: enode to get the runtime for
+    ///
+    /// # Returns
+    ///
+    /// Runtime in milliseconds, or INFEASIBLE_COST if the enode can not be
+    /// created
+    fn runtime(&self, cost_model: &CostModel, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32;
+
+    /// Describes the model, for the logs
+    fn name(&self) -> String;
+}
+
+/// Runtimes measured by TASO, see CostModel::get_self_runtime
+pub struct MeasuredRuntime;
+
+impl RuntimeModel for MeasuredRuntime {
+    fn runtime(&self, cost_model: &CostModel, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32 {
+        scale_to_elem(egraph, enode, cost_model.get_self_runtime(egraph, enode))
+    }
+
+    fn name(&self) -> String {
+        "measured".to_string()
+    }
+}
+
+/// Scales the runtime of an op computed for fp32 elements (as TASO measures
+/// them) to the element type of its inputs, assuming memory traffic and
+/// arithmetic throughput both scale with the size of the elements
+fn scale_to_elem(egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl, runtime: f32) -> f32 {
+    if runtime >= INFEASIBLE_COST {
+        return runtime;
+    }
+    match enode {
+        // Has the runtime of its element types
+        Mdl::Cast(_) => runtime,
+        _ => match output_elem(egraph, enode) {
+            Ok(elem) => runtime * elem.bytes() as f32 / ElemType::Float32.bytes() as f32,
+            Err(_) => INFEASIBLE_COST,
+        },
+    }
+}
+
+/// If an enode has no runtime, in all runtime models
+fn is_free(enode: &Mdl) -> bool {
+    // The same ops are free as for the measured runtimes
+    matches!(
+        enode,
+        Mdl::Num(_)
+            | Mdl::Var(_)
+            | Mdl::Input(_)
+            | Mdl::Weight(_)
+            | Mdl::Merge(_)
+            | Mdl::Split0(_)
+            | Mdl::Split1(_)
+            | Mdl::Reshape(_)
+            | Mdl::Transpose(_)
+            | Mdl::Dropout(_)
+            | Mdl::Noop(_)
+            | Mdl::Opaque(_)
+    )
+}
+
+/// Runtimes estimated from the shapes of the ops (a roofline model): the
+/// larger of the time for their FLOPs at `peak_tflops` and the time for
+/// reading their inputs and writing their output at `gb_per_sec`
+pub struct AnalyticalRuntime {
+    pub peak_tflops: f32,
+    pub gb_per_sec: f32,
+}
+
+impl RuntimeModel for AnalyticalRuntime {
+    fn runtime(&self, cost_model: &CostModel, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32 {
+        let x = |i: &Id| &egraph[*i].data;
+        if enode.children().iter().any(|id| x(id).infeasible) {
+            return INFEASIBLE_COST;
+        }
+        if is_free(enode) {
+            return 0.0;
+        }
+        // Registered ops have their own cost function, casts are copies
+        if let Mdl::Custom(_) | Mdl::Cast(_) = enode {
+            return cost_model.get_self_runtime(egraph, enode);
+        }
+        let output = match egraph.lookup(enode.clone()) {
+            Some(id) => x(&id),
+            None => return 0.0,
+        };
+        if output.infeasible {
+            return INFEASIBLE_COST;
+        }
+
+        let inputs: Vec<&ValTnsr> = enode.children().iter().map(x).filter(|t| t.dtype == DataKind::Tnsr).collect();
+        let out_volume = match output.dtype {
+            DataKind::TnsrTuple => tensor_volume(output.meta.ptr()) + tensor_volume(output.meta_2.ptr()),
+            _ => tensor_volume(output.meta.ptr()),
+        };
+        let flops = match enode {
+            Mdl::Conv2d(_) | Mdl::Sconv2d(_) | Mdl::Conv2dBlocked(_) | Mdl::Conv2dPacked(_) | Mdl::Sconv2dPacked(_) => {
+                // Each output element multiplies and adds the weights of one
+                // output channel
+                let weight = inputs[1].shape.unwrap();
+                2.0 * out_volume * with_shape(weight, |dims| dims[1..].iter().map(|d| *d as f32).product::<f32>())
+            }
+            Mdl::Matmul(_) | Mdl::Smatmul(_) | Mdl::Dense(_) => {
+                let a = inputs[0].shape.unwrap();
+                2.0 * out_volume * with_shape(a, |dims| *dims.last().unwrap() as f32)
+            }
+            // Other ops do about one operation per element they read or write
+            _ => inputs.iter().map(|t| tensor_volume(t.meta.ptr())).fold(out_volume, f32::max),
+        };
+        let flops = match enode {
+            Mdl::Sconv2d(_) | Mdl::Sconv2dPacked(_) | Mdl::Smatmul(_) => flops * SPARSE_OVERHEAD * inputs[1].density,
+            Mdl::Conv2dBlocked(_) => flops / BLOCKED_SPEEDUP,
+            _ => flops,
+        };
+        let bytes = 4.0 * (inputs.iter().map(|t| tensor_volume(t.meta.ptr())).sum::<f32>() + out_volume);
+
+        // 1 TFLOP/s is 1e9 FLOPs per millisecond, 1 GB/s is 1e6 bytes per
+        // millisecond
+        let runtime = f32::max(flops / (self.peak_tflops * 1e9), bytes / (self.gb_per_sec * 1e6));
+        cost_model.discount_all_weights(output.all_weights, scale_to_elem(egraph, enode, runtime))
+    }
+
+    fn name(&self) -> String {
+        format!("analytical ({} TFLOP/s, {} GB/s)", self.peak_tflops, self.gb_per_sec)
+    }
+}
+
+/// Gets the key of an enode in a runtime table: its op followed by, for each
+/// child, its value (scalars), its name (names) or its dimensions joined by
+/// 'x' (tensors, followed by the element type if not fp32), separated by
+/// spaces, e.g. `conv2d 1 1 0 2 1x64x56x56 64x64x3x3` or
+/// `relu 1x64x56x56:f16`
+pub fn op_key(egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> String {
+    key_with_dims(egraph, enode, |d| d.to_string())
+}
+
+/// Gets the key of the bucket of an enode: its op_key with each dimension
+/// replaced by the bucket of dimensions it falls in, ~k for the dimensions
+/// up to granularity^k. A granularity of 1 or less only puts equal shapes
+/// in one bucket, the key is then the op_key
+pub fn bucket_key(egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl, granularity: f32) -> String {
+    if granularity <= 1.0 {
+        return op_key(egraph, enode);
+    }
+    // Less an epsilon, so that a power of the granularity is in its own bucket
+    key_with_dims(egraph, enode, |d| format!("~{}", ((d as f32).ln() / granularity.ln() - 1e-4).ceil() as i32))
+}
+
+/// Gets the key of an enode, with the dimensions of its tensors written by
+/// dim (see op_key)
+fn key_with_dims(egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl, dim: impl Fn(i32) -> String) -> String {
+    let mut key = enode.to_string();
+    for child in enode.children() {
+        let data = &egraph[*child].data;
+        let part = match data.dtype {
+            DataKind::Scalar => data.val.to_string(),
+            DataKind::Name => data.name.to_string(),
+            DataKind::Tnsr | DataKind::TnsrTuple if data.shape.is_some() => {
+                let dims = shape_dims(data.shape.unwrap()).into_iter().map(&dim).join("x");
+                match data.elem {
+                    ElemType::Float32 => dims,
+                    elem => format!("{}:{}", dims, elem.name()),
+                }
+            }
+            _ => "?".to_string(),
+        };
+        key = format!("{} {}", key, part);
+    }
+    key
+}
+
+/// Runtimes looked up in a table, e.g. measured on the target GPU by
+/// another tool, with the runtimes of ops not in the table from another
+/// model
+pub struct RuntimeTable {
+    /// Runtime in milliseconds of each op, by op_key
+    pub table: HashMap<String, f32>,
+    /// Model for the ops not in the table
+    pub fallback: Box<dyn RuntimeModel>,
+}
+
+/// Loads a table of runtimes from a JSON file, an object mapping op keys
+/// (see op_key) to runtimes in milliseconds
+pub fn load_table(filename: &str) -> Result<HashMap<String, f32>, String> {
+    let s = fs::read_to_string(filename).map_err(|e| format!("Could not read {}: {}", filename, e))?;
+    serde_json::from_str(&s).map_err(|e| format!("Could not parse {}: {}", filename, e))
+}
+
+impl RuntimeTable {
+    /// Loads a table, see load_table
+    pub fn load(filename: &str, fallback: Box<dyn RuntimeModel>) -> Result<Self, String> {
+        Ok(RuntimeTable {
+            table: load_table(filename)?,
+            fallback: fallback,
+        })
+    }
+}
+
+impl RuntimeModel for RuntimeTable {
+    fn runtime(&self, cost_model: &CostModel, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32 {
+        if is_free(enode) || enode.children().iter().any(|id| egraph[*id].data.infeasible) {
+            return self.fallback.runtime(cost_model, egraph, enode);
+        }
+        match self.table.get(&op_key(egraph, enode)) {
+            Some(runtime) => {
+                let all_weights = egraph.lookup(enode.clone()).map_or(false, |id| egraph[id].data.all_weights);
+                cost_model.discount_all_weights(all_weights, *runtime)
+            }
+            None => self.fallback.runtime(cost_model, egraph, enode),
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("table of {} ops, {} otherwise", self.table.len(), self.fallback.name())
+    }
+}
+
+/// Runtimes of another model computed ahead for the enodes of an EGraph, see
+/// CostModel::populate_runtimes. Enodes that were not populated get the
+/// runtime of the other model
+pub struct PopulatedRuntime {
+    pub inner: Box<dyn RuntimeModel>,
+    pub runtimes: HashMap<Mdl, f32>,
+}
+
+impl RuntimeModel for PopulatedRuntime {
+    fn runtime(&self, cost_model: &CostModel, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32 {
+        match self.runtimes.get(enode) {
+            Some(runtime) => *runtime,
+            None => self.inner.runtime(cost_model, egraph, enode),
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("{} ({} ops populated)", self.inner.name(), self.runtimes.len())
+    }
+}
+
+/// Runtimes kept across runs, in the format of RuntimeTable (so a cache can
+/// also be the table of a run on another machine)
+#[derive(Default)]
+pub struct RuntimeCache {
+    entries: Mutex<HashMap<String, f32>>,
+    /// Number of runtimes found in the cache, and computed
+    stats: Mutex<(usize, usize)>,
+}
+
+impl RuntimeCache {
+    /// Loads the cache in filename, empty if the file does not exist
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let entries = if Path::new(filename).exists() {
+            load_table(filename)?
+        } else {
+            HashMap::new()
+        };
+        Ok(RuntimeCache {
+            entries: Mutex::new(entries),
+            stats: Mutex::new((0, 0)),
+        })
+    }
+
+    /// Saves the cache to filename, so that concurrent runs sharing it keep
+    /// each other's runtimes: the file is locked (with flock on filename.lock),
+    /// the entries other runs saved since this one loaded it are merged in,
+    /// and the merged entries are written to a temporary file renamed into
+    /// place, so a reader never sees a partly written cache. The runtimes of
+    /// this run win for the keys both have
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        let lock_file = format!("{}.lock", filename);
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&lock_file)
+            .map_err(|e| format!("Could not open {}: {}", lock_file, e))?;
+        if unsafe { flock(lock.as_raw_fd(), LOCK_EX) } != 0 {
+            return Err(format!("Could not lock {}", lock_file));
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if Path::new(filename).exists() {
+            for (key, runtime) in load_table(filename)? {
+                entries.entry(key).or_insert(runtime);
+            }
+        }
+        let tmp_file = format!("{}.{}.tmp", filename, std::process::id());
+        fs::write(&tmp_file, serde_json::to_string(&*entries).unwrap())
+            .map_err(|e| format!("Could not write {}: {}", tmp_file, e))?;
+        fs::rename(&tmp_file, filename).map_err(|e| format!("Could not write {}: {}", filename, e))
+        // The lock is released when lock is closed
+    }
+
+    /// Gets a copy of the runtimes in the cache, by op_key
+    pub fn entries(&self) -> HashMap<String, f32> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Gets the number of runtimes found in the cache and computed since it
+    /// was loaded
+    pub fn stats(&self) -> (usize, usize) {
+        *self.stats.lock().unwrap()
+    }
+}
+
+/// Runtimes of another model, kept in a cache, so a run reuses the runtimes
+/// of the ops it has in common with earlier runs instead of asking the model
+/// again
+///
+/// Ops on weights only are not cached, their runtime depends on the discount
+/// of the cost model.
+pub struct CachedRuntime {
+    pub inner: Box<dyn RuntimeModel>,
+    pub cache: Arc<RuntimeCache>,
+}
+
+impl RuntimeModel for CachedRuntime {
+    fn runtime(&self, cost_model: &CostModel, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32 {
+        let all_weights = egraph.lookup(enode.clone()).map_or(false, |id| egraph[id].data.all_weights);
+        if is_free(enode) || all_weights || enode.children().iter().any(|id| egraph[*id].data.infeasible) {
+            return self.inner.runtime(cost_model, egraph, enode);
+        }
+        let key = op_key(egraph, enode);
+        if let Some(runtime) = self.cache.entries.lock().unwrap().get(&key) {
+            self.cache.stats.lock().unwrap().0 += 1;
+            return *runtime;
+        }
+        let runtime = self.inner.runtime(cost_model, egraph, enode);
+        self.cache.stats.lock().unwrap().1 += 1;
+        self.cache.entries.lock().unwrap().insert(key, runtime);
+        runtime
+    }
+
+    fn name(&self) -> String {
+        format!("cached {}", self.inner.name())
+    }
+}
+
+/// Asking the runtime model for only some of the ops in the cost phase
+/// (--shape_buckets, --sample_shapes), see the module documentation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShapeSampling {
+    /// Ratio between the bounds of a bucket of dimensions, see bucket_key
+    pub granularity: f32,
+    /// Fraction of the buckets of each op type whose representative is
+    /// asked. The smallest and largest buckets (by analytical runtime) are
+    /// always asked, so the others can be interpolated between them
+    pub fraction: f32,
+}
+
+/// Runtime of a bucket interpolated between the buckets of the same op type
+/// around it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InterpolatedRuntime {
+    /// Bucket key of the representative
+    pub key: String,
+    pub runtime: f32,
+    /// How much the ratios of the measured to the analytical runtime of the
+    /// two buckets interpolated between agree, from 0 to 1 (1 when they are
+    /// equal, so the analytical runtimes scale exactly as the measured ones)
+    pub confidence: f32,
+}
+
+/// What the sampled cost phase asked the runtime model for
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SamplingReport {
+    /// Enodes asked directly: free ops, infeasible ones and ops on weights
+    /// only
+    pub num_direct: usize,
+    /// Enodes put in buckets
+    pub num_bucketed: usize,
+    pub num_buckets: usize,
+    /// Op keys of the representatives asked, the shapes to measure
+    pub measured_keys: Vec<String>,
+    pub interpolated: Vec<InterpolatedRuntime>,
+}
+
+impl SamplingReport {
+    /// Mean confidence of the interpolated runtimes, None if there are none
+    pub fn mean_confidence(&self) -> Option<f32> {
+        if self.interpolated.is_empty() {
+            return None;
+        }
+        Some(self.interpolated.iter().map(|r| r.confidence).sum::<f32>() / self.interpolated.len() as f32)
+    }
+}
+
+/// Enodes of similar shapes, see bucket_key
+struct Bucket {
+    key: String,
+    members: Vec<Mdl>,
+    /// Analytical runtime of each member
+    estimates: Vec<f32>,
+}
+
+/// Gets the runtimes of all enodes of an EGraph, asking the runtime model of
+/// the cost model only for a sample of them
+///
+/// # Parameters
+///
+/// - `cost_model`: the cost model, whose runtime model is asked
+/// - `egraph`: E-graph of interest
+/// - `sampling`: the buckets and the fraction of them to ask for
+/// - `estimate`: how runtimes scale between shapes, usually an
+///   AnalyticalRuntime
+///
+/// # Returns
+///
+/// The runtime of each enode, and what was asked
+pub fn sampled_runtimes(
+    cost_model: &CostModel,
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    sampling: &ShapeSampling,
+    estimate: &dyn RuntimeModel,
+) -> (HashMap<Mdl, f32>, SamplingReport) {
+    let model = cost_model.runtime_model();
+    let mut runtimes = HashMap::new();
+    let mut report = SamplingReport::default();
+    let mut buckets: Vec<Bucket> = vec![];
+    let mut bucket_index: HashMap<String, usize> = HashMap::new();
+    for class in egraph.classes() {
+        for enode in class.iter() {
+            let direct = is_free(enode)
+                || class.data.infeasible
+                || class.data.all_weights
+                || enode.children().iter().any(|id| egraph[*id].data.infeasible)
+                || matches!(enode, Mdl::Custom(_) | Mdl::Cast(_));
+            if direct {
+                runtimes.insert(enode.clone(), model.runtime(cost_model, egraph, enode));
+                report.num_direct += 1;
+                continue;
+            }
+            let key = bucket_key(egraph, enode, sampling.granularity);
+            let i = *bucket_index.entry(key.clone()).or_insert_with(|| {
+                buckets.push(Bucket {
+                    key: key,
+                    members: vec![],
+                    estimates: vec![],
+                });
+                buckets.len() - 1
+            });
+            buckets[i].members.push(enode.clone());
+            buckets[i].estimates.push(estimate.runtime(cost_model, egraph, enode));
+            report.num_bucketed += 1;
+        }
+    }
+    report.num_buckets = buckets.len();
+
+    // The buckets of each op type, by the analytical runtime of their
+    // representative (the first member)
+    let mut op_types: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, bucket) in buckets.iter().enumerate() {
+        op_types.entry(bucket.members[0].to_string()).or_default().push(i);
+    }
+    let step = (1.0 / sampling.fraction.max(f32::EPSILON)).round().max(1.0) as usize;
+    for (_, mut indices) in op_types.into_iter().sorted_by(|a, b| a.0.cmp(&b.0)) {
+        indices.sort_by(|a, b| buckets[*a].estimates[0].partial_cmp(&buckets[*b].estimates[0]).unwrap());
+        let n = indices.len();
+        // Runtime of the representative of each bucket, and its ratio to the
+        // analytical runtime if both are usable
+        let mut rep_runtimes: Vec<Option<f32>> = vec![None; n];
+        let mut factors: Vec<Option<f32>> = vec![None; n];
+        for (k, i) in indices.iter().enumerate() {
+            if k % step == 0 || k + 1 == n {
+                let bucket = &buckets[*i];
+                let runtime = model.runtime(cost_model, egraph, &bucket.members[0]);
+                report.measured_keys.push(op_key(egraph, &bucket.members[0]));
+                rep_runtimes[k] = Some(runtime);
+                if runtime < INFEASIBLE_COST && bucket.estimates[0] > 0.0 && bucket.estimates[0] < INFEASIBLE_COST {
+                    factors[k] = Some(runtime / bucket.estimates[0]);
+                }
+            }
+        }
+        for k in 0..n {
+            let bucket = &buckets[indices[k]];
+            let est = bucket.estimates[0];
+            if rep_runtimes[k].is_none() {
+                let lo = (0..k).rev().find(|j| factors[*j].is_some());
+                let hi = (k + 1..n).find(|j| factors[*j].is_some());
+                let interpolated = match (lo, hi) {
+                    (Some(lo), Some(hi)) if est > 0.0 && est < INFEASIBLE_COST => {
+                        let (f_lo, f_hi) = (factors[lo].unwrap(), factors[hi].unwrap());
+                        let (e_lo, e_hi) = (buckets[indices[lo]].estimates[0], buckets[indices[hi]].estimates[0]);
+                        // Linear in the log of the analytical runtime
+                        let t = if e_hi > e_lo { (est.ln() - e_lo.ln()) / (e_hi.ln() - e_lo.ln()) } else { 0.5 };
+                        Some((est * (f_lo + t * (f_hi - f_lo)), f_lo.min(f_hi) / f_lo.max(f_hi)))
+                    }
+                    _ => None,
+                };
+                match interpolated {
+                    Some((runtime, confidence)) => {
+                        report.interpolated.push(InterpolatedRuntime {
+                            key: bucket.key.clone(),
+                            runtime: runtime,
+                            confidence: confidence,
+                        });
+                        rep_runtimes[k] = Some(runtime);
+                    }
+                    // No measured bucket on both sides to interpolate between
+                    None => {
+                        rep_runtimes[k] = Some(model.runtime(cost_model, egraph, &bucket.members[0]));
+                        report.measured_keys.push(op_key(egraph, &bucket.members[0]));
+                    }
+                }
+            }
+            // The other members scale with their analytical runtime
+            let rep_runtime = rep_runtimes[k].unwrap();
+            for (enode, member_est) in bucket.members.iter().zip(bucket.estimates.iter()) {
+                let runtime = if rep_runtime >= INFEASIBLE_COST || est <= 0.0 || est >= INFEASIBLE_COST {
+                    rep_runtime
+                } else {
+                    rep_runtime * member_est / est
+                };
+                runtimes.insert(enode.clone(), runtime);
+            }
+        }
+    }
+    (runtimes, report)
+}
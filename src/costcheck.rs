@@ -0,0 +1,145 @@
+//! Re-measuring the runtimes in a cost cache (mode validate_costs)
+//!
+//! A cost cache (see cost::RuntimeCache) keeps runtimes across runs, so when
+//! the driver, the clocks or the GPU change, an optimization run trusts
+//! runtimes that no longer hold. This re-measures a random sample of the
+//! cached ops and compares them to the cache.
+//!
+//! The ops are rebuilt from their keys (see cost::op_key), with an input for
+//! each tensor, cast to its element type if it is not fp32. A key with a
+//! tensor of one dimension reads as a scalar, so its op can usually not be
+//! created and is skipped, as are keys with unknown children.
+
+use crate::cost::RuntimeCache;
+use crate::microbench::{microbench, LatencySummary};
+use crate::model::*;
+use crate::optimize::{CostModel, INFEASIBLE_COST};
+use crate::shape::ElemType;
+use crate::text::read_graph;
+use egg::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
+
+/// A cached runtime compared to its re-measurement, in milliseconds
+#[derive(Debug, Clone, Serialize)]
+pub struct CostDrift {
+    pub key: String,
+    pub cached: f32,
+    pub measured: f32,
+}
+
+impl CostDrift {
+    /// Difference of the measured runtime to the cached one, relative to the
+    /// cached one
+    pub fn relative(&self) -> f32 {
+        (self.measured - self.cached) / self.cached
+    }
+}
+
+/// Statistics of the relative drifts of the re-measured ops
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DriftSummary {
+    pub num_checked: usize,
+    /// Mean of the relative drifts, positive if the ops got slower
+    pub mean_drift: f32,
+    /// Mean and largest of the absolute values of the relative drifts
+    pub mean_abs_drift: f32,
+    pub max_abs_drift: f32,
+    /// Number of ops whose absolute relative drift is past the threshold
+    pub num_over_threshold: usize,
+}
+
+impl DriftSummary {
+    /// Summarizes drifts, None if there are none
+    pub fn from_drifts(drifts: &[CostDrift], threshold: f32) -> Option<Self> {
+        if drifts.is_empty() {
+            return None;
+        }
+        let n = drifts.len() as f32;
+        let relative: Vec<f32> = drifts.iter().map(|d| d.relative()).collect();
+        Some(DriftSummary {
+            num_checked: drifts.len(),
+            mean_drift: relative.iter().sum::<f32>() / n,
+            mean_abs_drift: relative.iter().map(|r| r.abs()).sum::<f32>() / n,
+            max_abs_drift: relative.iter().map(|r| r.abs()).fold(0.0, f32::max),
+            num_over_threshold: relative.iter().filter(|r| r.abs() > threshold).count(),
+        })
+    }
+
+    /// If the cache should be invalidated: the ops drifted by more than the
+    /// threshold on average
+    pub fn is_stale(&self, threshold: f32) -> bool {
+        self.mean_abs_drift > threshold
+    }
+}
+
+/// Rebuilds the op of a key in a runtime table as a graph whose root is the
+/// op (see microbench)
+pub fn key_expr(key: &str) -> Result<RecExpr<Mdl>, String> {
+    let mut parts = key.split(' ');
+    let op = parts.next().filter(|op| !op.is_empty()).ok_or("the key is empty")?;
+    let mut children = vec![];
+    for (i, part) in parts.enumerate() {
+        if part.parse::<i32>().is_ok() {
+            children.push(part.to_string());
+            continue;
+        }
+        let mut tensor = part.splitn(2, ':');
+        let dims: Vec<&str> = tensor.next().unwrap().split('x').collect();
+        if dims.iter().all(|d| d.parse::<i32>().is_ok()) {
+            let input = format!("(input t{}@{})", i, dims.join("_"));
+            match tensor.next() {
+                None => children.push(input),
+                Some(elem) => {
+                    let elem = ElemType::from_name(elem).ok_or_else(|| format!("unknown element type in {}", part))?;
+                    children.push(format!("(cast {} {})", elem.code(), input));
+                }
+            }
+        } else if part == "?" {
+            return Err(format!("child {} of {} is not known", i, op));
+        } else {
+            // A name
+            children.push(part.to_string());
+        }
+    }
+    read_graph(&format!("({} {})", op, children.join(" ")))
+}
+
+/// Chooses a random sample of the keys of a cache, the same for a seed
+pub fn sample_keys(cache: &RuntimeCache, num_samples: usize, seed: u64) -> Vec<(String, f32)> {
+    let mut entries: Vec<(String, f32)> = cache.entries().into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.shuffle(&mut StdRng::seed_from_u64(seed));
+    entries.truncate(num_samples);
+    entries
+}
+
+/// Re-measures the op of a cached runtime
+///
+/// # Parameters
+///
+/// - `key`, `cached`: the cached runtime, see op_key
+/// - `cost_model`: gives the runtime model to measure with
+/// - `repeats`: number of measurements, the median is taken
+///
+/// # Returns
+///
+/// The cached and the measured runtime, or why the op could not be measured
+pub fn remeasure(key: &str, cached: f32, cost_model: &CostModel, repeats: usize) -> Result<CostDrift, String> {
+    if cached <= 0.0 || cached >= INFEASIBLE_COST {
+        return Err(format!("the cached runtime {} can not be compared", cached));
+    }
+    let expr = key_expr(key)?;
+    let samples = microbench(&expr, cost_model, repeats.max(1))?;
+    let measured = LatencySummary::from_samples(&samples).unwrap().p50;
+    if measured >= INFEASIBLE_COST {
+        return Err(String::from("the op could not be measured"));
+    }
+    Ok(CostDrift {
+        key: key.to_string(),
+        cached: cached,
+        measured: measured,
+    })
+}
@@ -0,0 +1,203 @@
+//! Operators registered by users at runtime
+//!
+//! An op the language does not have can be registered with its arity, a
+//! shape function, and optionally a cost function and rewrite rules, instead
+//! of adding it to `define_language!`. A registered op is a custom node
+//! (see Mdl::Custom) whose first child is its label, `op` (or
+//! `op:attr1:attr2...` with integer attributes), and whose other children are
+//! its inputs. The analysis gets its shape from the shape function and the
+//! cost model its cost from the cost function, so importers can map ops the
+//! language does not have without changing the crate. The textual format
+//! (see text.rs) writes registered ops like the built-in ones, and the rules
+//! registered with them are added to the rules of a run.
+//!
+//! An op the backend can not measure, e.g. a layernorm, does not need a cost
+//! function: it can be registered with a decomposition into ops of the
+//! language instead. A custom node of such an op stays as it is in the graph
+//! and in the exported model, and costs what its decomposition, built at the
+//! shapes of its inputs, costs (see decompose and decomposition_runtime).
+
+use crate::model::*;
+use crate::optimize::CostModel;
+use crate::provenance::expr_eclasses;
+use egg::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Gets the output shape of an op from the shapes of its inputs and its
+/// integer attributes
+pub type ShapeFn = fn(&[Vec<i32>], &[i32]) -> Result<Vec<i32>, String>;
+
+/// Gets the cost of an op, in milliseconds, from the shapes of its inputs
+/// and its integer attributes
+pub type CostFn = fn(&[Vec<i32>], &[i32]) -> f32;
+
+/// A user-defined op
+#[derive(Clone)]
+pub struct CustomOp {
+    pub name: String,
+    /// Number of tensor inputs, at least 1
+    pub arity: usize,
+    /// Names of the integer attributes, in the order they are passed to the
+    /// shape and cost functions
+    pub attrs: Vec<String>,
+    pub shape: ShapeFn,
+    /// Cost function, if None the op costs what its decomposition costs, or
+    /// nothing without one, like opaque ops
+    pub cost: Option<CostFn>,
+    /// Decomposition into ops of the language, as a pattern over the inputs
+    /// `?x0`, `?x1`... and the attributes `?a0`, `?a1`..., e.g.
+    /// `(ewmul (relu ?x0) ?x1)`. Only used for the cost
+    pub decomposition: Option<String>,
+    /// Rewrite rules in the format of the rule file, `lhs=>rhs`, with the op
+    /// written as a custom node, e.g. `(custom op:2 ?x)`
+    pub rules: Vec<String>,
+}
+
+static CUSTOM_OPS: Lazy<RwLock<HashMap<String, Arc<CustomOp>>>> = Lazy::new(Default::default);
+
+/// Runtimes of the decompositions, by custom node label and input shapes
+static DECOMPOSITION_RUNTIMES: Lazy<Mutex<HashMap<String, f32>>> = Lazy::new(Default::default);
+
+/// If the language has an op of this name, with any number of children
+fn is_builtin(name: &str) -> bool {
+    // Without children, any name parses as a Var (or a Num)
+    (0..8).any(|n| match Mdl::from_op(name, vec![Id::from(0); n]) {
+        Ok(Mdl::Var(_)) | Ok(Mdl::Num(_)) | Err(_) => false,
+        Ok(_) => true,
+    })
+}
+
+/// Registers an op, for all graphs and EGraphs of the process
+///
+/// # Returns
+///
+/// Ok, or why the op can not be registered
+pub fn register_op(op: CustomOp) -> Result<(), String> {
+    if op.name.is_empty() || op.name.contains(|c: char| c == '@' || c == ':' || c.is_whitespace()) {
+        return Err(format!("invalid op name {}", op.name));
+    }
+    if is_builtin(&op.name) {
+        return Err(format!("{} is an op of the language", op.name));
+    }
+    if op.arity == 0 {
+        return Err(format!("{} has no inputs", op.name));
+    }
+    if let Some(decomposition) = &op.decomposition {
+        decomposition
+            .parse::<Pattern<Mdl>>()
+            .map_err(|e| format!("invalid decomposition of {}: {}", op.name, e))?;
+    }
+    let mut ops = CUSTOM_OPS.write().unwrap();
+    if ops.contains_key(&op.name) {
+        return Err(format!("{} is already registered", op.name));
+    }
+    ops.insert(op.name.clone(), Arc::new(op));
+    Ok(())
+}
+
+/// Gets a registered op by name
+pub fn custom_op(name: &str) -> Option<Arc<CustomOp>> {
+    CUSTOM_OPS.read().unwrap().get(name).cloned()
+}
+
+/// Gets the rules of all registered ops
+pub fn custom_rules() -> Vec<String> {
+    let ops = CUSTOM_OPS.read().unwrap();
+    let mut names: Vec<&String> = ops.keys().collect();
+    names.sort();
+    names.iter().flat_map(|name| ops[*name].rules.iter().cloned()).collect()
+}
+
+/// Gets the label of a custom node
+pub fn custom_label(name: &str, attrs: &[i32]) -> String {
+    let mut label = name.to_string();
+    for attr in attrs.iter() {
+        label = format!("{}:{}", label, attr);
+    }
+    label
+}
+
+/// Gets the registered op and its attributes from the label of a custom node
+///
+/// # Returns
+///
+/// None if the label is not of a registered op
+pub fn parse_custom_label(label: &str) -> Option<(Arc<CustomOp>, Vec<i32>)> {
+    let mut parts = label.split(':');
+    let op = custom_op(parts.next()?)?;
+    let attrs: Result<Vec<i32>, _> = parts.map(|a| a.parse::<i32>()).collect();
+    Some((op, attrs.ok()?))
+}
+
+/// Builds the decomposition of a custom node at the shapes of its inputs
+///
+/// # Parameters
+///
+/// - `op`: the registered op, with a decomposition
+/// - `inputs`: shapes of the inputs of the node
+/// - `attrs`: attributes of the node
+///
+/// # Returns
+///
+/// The decomposition, on inputs x0, x1... of these shapes, or why it can
+/// not be built
+pub fn decompose(op: &CustomOp, inputs: &[Vec<i32>], attrs: &[i32]) -> Result<RecExpr<Mdl>, String> {
+    let decomposition = op.decomposition.as_ref().ok_or_else(|| format!("{} has no decomposition", op.name))?;
+    let pattern: Pattern<Mdl> = decomposition.parse().map_err(|e| format!("{}", e))?;
+    let mut expr = RecExpr::default();
+    let mut ids: Vec<Id> = Vec::with_capacity(pattern.ast.as_ref().len());
+    for node in pattern.ast.as_ref() {
+        let id = match node {
+            ENodeOrVar::ENode(node) => expr.add(node.clone().map_children(|c| ids[usize::from(c)])),
+            ENodeOrVar::Var(var) => {
+                let name = var.to_string();
+                let index = |prefix: &str| name.strip_prefix(prefix).and_then(|i| i.parse::<usize>().ok());
+                if let Some(dims) = index("?x").and_then(|i| inputs.get(i)) {
+                    let dims: Vec<String> = dims.iter().map(|d| d.to_string()).collect();
+                    let name = format!("x{}@{}", index("?x").unwrap(), dims.join("_"));
+                    let name = expr.add(Mdl::Var(Symbol::from(name)));
+                    expr.add(Mdl::Input([name]))
+                } else if let Some(attr) = index("?a").and_then(|i| attrs.get(i)) {
+                    expr.add(Mdl::Num(*attr))
+                } else {
+                    return Err(format!("the decomposition of {} uses {}, it has {} inputs and {} attributes", op.name, name, inputs.len(), attrs.len()));
+                }
+            }
+        };
+        ids.push(id);
+    }
+    Ok(expr)
+}
+
+/// Gets the runtime of a custom node as the sum of the runtimes of the ops of
+/// its decomposition, built in an EGraph of its own. Runtimes are cached by
+/// label and input shapes
+pub fn decomposition_runtime(cost_model: &CostModel, op: &CustomOp, label: &str, inputs: &[Vec<i32>], attrs: &[i32]) -> f32 {
+    let key = format!("{}{:?}", label, inputs);
+    if let Some(runtime) = DECOMPOSITION_RUNTIMES.lock().unwrap().get(&key) {
+        return *runtime;
+    }
+    let runtime = match decompose(op, inputs, attrs) {
+        Ok(expr) => {
+            let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+            egraph.add_expr(&expr);
+            let ids = expr_eclasses(&egraph, &expr);
+            let mut runtime = 0.0;
+            for node in expr.as_ref() {
+                let children: Option<Vec<Id>> = node.children().iter().map(|c| ids[usize::from(*c)]).collect();
+                let mut children = children.unwrap().into_iter();
+                let enode = node.clone().map_children(|_| children.next().unwrap());
+                runtime += cost_model.get_self_runtime(&egraph, &enode);
+            }
+            runtime
+        }
+        Err(e) => {
+            println!("Warning: {}, costing {} as free", e, label);
+            0.0
+        }
+    };
+    DECOMPOSITION_RUNTIMES.lock().unwrap().insert(key, runtime);
+    runtime
+}
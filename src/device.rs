@@ -0,0 +1,111 @@
+//! Device profiles of common GPUs for the analytical runtimes
+//!
+//! The analytical runtimes (see cost::AnalyticalRuntime) need the throughput
+//! and the memory bandwidth of the target GPU. The built-in profiles give
+//! the sustained fp32 throughput and bandwidth of common GPUs, what large
+//! matmuls and copies reach, about 85-90% of the datasheet peaks. The local
+//! GPU is detected through nvidia-smi and matched to a profile by its name.
+//!
+//! A profile also records how far the measured runtimes of optimized graphs
+//! are from the sums of the runtimes of their ops, for each source of the
+//! runtimes. A ratio far from 1 means the runtimes of the ops miss effects
+//! of the whole graph (fusion by the backend, overlapping kernels), and
+//! could correct the estimates of later runs.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::process::Command;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceProfile {
+    pub name: String,
+    /// Sustained fp32 throughput, in TFLOP/s
+    pub peak_tflops: f32,
+    /// Sustained memory bandwidth, in GB/s
+    pub gb_per_sec: f32,
+    /// Ratios of measured to estimated graph runtimes, by the source of the
+    /// runtimes of the ops (see --runtime_source)
+    #[serde(default)]
+    pub runtime_ratios: BTreeMap<String, RuntimeRatio>,
+}
+
+/// Mean ratio of measured to estimated graph runtimes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuntimeRatio {
+    pub mean: f32,
+    pub num_samples: usize,
+}
+
+impl DeviceProfile {
+    /// Records the ratio of the measured to the estimated runtime of a graph
+    ///
+    /// # Returns
+    ///
+    /// The ratio, None if the estimate is not positive
+    pub fn record_runtime(&mut self, source: &str, estimated: f32, measured: f32) -> Option<f32> {
+        if estimated <= 0.0 || !estimated.is_finite() {
+            return None;
+        }
+        let ratio = measured / estimated;
+        let entry = self.runtime_ratios.entry(source.to_string()).or_insert(RuntimeRatio {
+            mean: 0.0,
+            num_samples: 0,
+        });
+        entry.num_samples += 1;
+        entry.mean += (ratio - entry.mean) / entry.num_samples as f32;
+        Some(ratio)
+    }
+}
+
+/// The built-in profiles, as (model in the GPU name, TFLOP/s, GB/s)
+#[rustfmt::skip]
+pub static BUILTIN_PROFILES: &[(&str, f32, f32)] = &[
+    ("V100",  14.0,  830.0),
+    ("A100",  17.5, 1400.0),
+    ("T4",     7.0,  280.0),
+    ("4090",  72.0,  900.0),
+    ("4080",  43.0,  640.0),
+    ("4070",  26.0,  450.0),
+    ("4060",  13.5,  240.0),
+];
+
+/// Gets the built-in profile of a GPU by its name, as nvidia-smi reports it
+/// (e.g. `Tesla V100-SXM2-16GB`, `NVIDIA GeForce RTX 4090`) or just its
+/// model (e.g. `a100`)
+pub fn builtin_profile(gpu_name: &str) -> Option<DeviceProfile> {
+    let upper = gpu_name.to_uppercase();
+    let words: Vec<&str> = upper.split(|c: char| c.is_whitespace() || c == '-').collect();
+    BUILTIN_PROFILES
+        .iter()
+        .find(|(model, _, _)| words.contains(model))
+        .map(|(model, tflops, gbps)| DeviceProfile {
+            name: model.to_string(),
+            peak_tflops: *tflops,
+            gb_per_sec: *gbps,
+            runtime_ratios: BTreeMap::new(),
+        })
+}
+
+/// Gets the name of the first local GPU through nvidia-smi
+pub fn detect_gpu() -> Result<String, String> {
+    let output = Command::new("nvidia-smi")
+        .args(&["--query-gpu=name", "--format=csv,noheader", "--id=0"])
+        .output()
+        .map_err(|e| format!("could not run nvidia-smi: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Loads a profile: a built-in one by its GPU name, or a JSON file of a
+/// DeviceProfile
+pub fn load_profile(name_or_file: &str) -> Result<DeviceProfile, String> {
+    if let Some(profile) = builtin_profile(name_or_file) {
+        return Ok(profile);
+    }
+    let s = fs::read_to_string(name_or_file)
+        .map_err(|_| format!("{} is neither a built-in device profile nor a profile file", name_or_file))?;
+    serde_json::from_str(&s).map_err(|e| format!("Could not parse {}: {}", name_or_file, e))
+}
@@ -0,0 +1,117 @@
+//! DOT files of graphs and EGraphs, for Graphviz
+//!
+//! Unlike egg's dot output, which draws every scalar and name as a node, a
+//! graph is drawn with one node per op: its scalar and name children become
+//! parameters in its label (e.g. `conv2d 1 1 0 2`), its tensor children
+//! become edges labeled with the shape of the tensor. An EGraph is drawn
+//! with one cluster per eclass and the cost of each enode, so the
+//! alternatives the extraction chose between can be compared.
+
+use crate::model::*;
+use crate::optimize::{CostModel, INFEASIBLE_COST};
+use crate::shape::TensorShape;
+use egg::*;
+use std::fmt::Write;
+
+/// If a node is a parameter of its parent (a scalar or a name), as opposed
+/// to a tensor
+fn is_param(node: &Mdl) -> bool {
+    matches!(node, Mdl::Num(_) | Mdl::Var(_))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Formats the shapes of a tensor (of both for a TnsrTuple), e.g. 1x64x56x56
+fn shape_label(shapes: &[TensorShape]) -> String {
+    shapes
+        .iter()
+        .map(|s| s.dims.iter().map(|d| d.to_string()).collect::<Vec<_>>().join("x"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Gets the DOT of a graph
+///
+/// # Parameters
+///
+/// - `expr`: the graph
+/// - `shapes`: the output shapes of each node (see shape::expr_shapes), to
+///   label the edges with, if known
+pub fn graph_dot(expr: &RecExpr<Mdl>, shapes: Option<&[Option<Vec<TensorShape>>]>) -> String {
+    let nodes = expr.as_ref();
+    let mut dot = String::from("digraph {\n  rankdir=BT;\n  node [shape=box];\n");
+    for (i, node) in nodes.iter().enumerate() {
+        if is_param(node) {
+            continue;
+        }
+        let mut label = node.to_string();
+        for child in node.children() {
+            let child_node = &nodes[usize::from(*child)];
+            if is_param(child_node) {
+                label.push(' ');
+                label.push_str(&child_node.to_string());
+            }
+        }
+        writeln!(dot, "  n{} [label=\"{}\"];", i, escape(&label)).unwrap();
+        for child in node.children() {
+            let c = usize::from(*child);
+            if is_param(&nodes[c]) {
+                continue;
+            }
+            let edge_label = shapes
+                .and_then(|shapes| shapes[c].as_ref())
+                .map_or(String::new(), |s| shape_label(&s[..]));
+            writeln!(dot, "  n{} -> n{} [label=\"{}\"];", c, i, edge_label).unwrap();
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Gets the DOT of an EGraph, with the cost of each enode
+///
+/// Enodes that are blacklisted or infeasible are drawn dashed.
+///
+/// # Parameters
+///
+/// - `egraph`: the EGraph
+/// - `cost_model`: the cost model for the costs of the enodes
+pub fn egraph_dot(egraph: &EGraph<Mdl, TensorAnalysis>, cost_model: &CostModel) -> String {
+    let mut dot = String::from("digraph {\n  compound=true;\n  rankdir=BT;\n  node [shape=box];\n");
+    for class in egraph.classes() {
+        writeln!(dot, "  subgraph cluster_{} {{\n    label=\"eclass {}\";", class.id, class.id).unwrap();
+        for (j, node) in class.iter().enumerate() {
+            let label = if is_param(node) {
+                escape(&node.to_string())
+            } else {
+                let cost = cost_model.get_self_cost(egraph, node);
+                if cost >= INFEASIBLE_COST {
+                    format!("{}\\ninfeasible", escape(&node.to_string()))
+                } else {
+                    format!("{}\\ncost {:.4}", escape(&node.to_string()), cost)
+                }
+            };
+            let style = if egraph.analysis.blacklist_nodes.contains(node) || label.ends_with("infeasible") {
+                ", style=dashed"
+            } else {
+                ""
+            };
+            writeln!(dot, "    c{}_{} [label=\"{}\"{}];", class.id, j, label, style).unwrap();
+        }
+        dot.push_str("  }\n");
+    }
+    // Edges from an enode to the first enode of each child eclass, clipped
+    // at the cluster
+    for class in egraph.classes() {
+        for (j, node) in class.iter().enumerate() {
+            for child in node.children() {
+                let child = egraph.find(*child);
+                writeln!(dot, "  c{}_0 -> c{}_{} [ltail=cluster_{}];", child, class.id, j, child).unwrap();
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
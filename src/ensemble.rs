@@ -0,0 +1,121 @@
+//! Comparing the graphs extracted under two cost models
+//!
+//! Runtimes measured by TASO are noisy, and an analytical estimate (see
+//! cost::AnalyticalRuntime) is biased for ops whose kernels are far from
+//! the roofline. Extracting under both and comparing where their graphs
+//! differ shows which decisions depend on the cost model: each graph is
+//! costed under both models, and each eclass where the models pick different
+//! enodes is reported with the cost of both picks under both models. A
+//! disagreement that is small under both models is likely measurement noise,
+//! one where the models rank the picks in opposite orders by a wide margin
+//! points to a bias of one of them.
+
+use crate::model::*;
+use crate::optimize::*;
+use egg::*;
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// An eclass where the two cost models pick different enodes
+#[derive(Debug, Clone, Serialize)]
+pub struct Disagreement {
+    pub eclass: usize,
+    /// Enode picked under the first model
+    pub pick_a: String,
+    /// Enode picked under the second model
+    pub pick_b: String,
+    /// Cost of the subgraph rooted at pick_a, under the first and the second
+    /// model
+    pub costs_a: [f32; 2],
+    /// Cost of the subgraph rooted at pick_b, under the first and the second
+    /// model
+    pub costs_b: [f32; 2],
+}
+
+/// Result of extracting under two cost models
+#[derive(Debug, Clone, Serialize)]
+pub struct CostModelComparison {
+    /// Cost of the graph extracted under the first model, under the first and
+    /// the second model
+    pub graph_a: [f32; 2],
+    /// Cost of the graph extracted under the second model, under the first
+    /// and the second model
+    pub graph_b: [f32; 2],
+    /// Number of ops of the two graphs
+    pub num_ops: [usize; 2],
+    /// Where the picks differ, by eclass
+    pub disagreements: Vec<Disagreement>,
+}
+
+/// Gets the cost of a graph extracted from an EGraph, counting shared nodes
+/// once
+pub fn graph_cost(egraph: &EGraph<Mdl, TensorAnalysis>, expr: &RecExpr<Mdl>, cost_model: &CostModel) -> f32 {
+    expr_op_costs(egraph, expr, cost_model).iter().map(|c| c.unwrap_or(0.0)).sum()
+}
+
+/// Gets the eclasses of the graph an extractor picks from root
+fn picked_eclasses<CF: CostFunction<Mdl>>(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    extractor: &Extractor<CF, Mdl, TensorAnalysis>,
+    root: Id,
+) -> HashSet<Id> {
+    let mut seen = HashSet::new();
+    let mut todo = vec![egraph.find(root)];
+    while let Some(id) = todo.pop() {
+        if seen.insert(id) {
+            todo.extend(extractor.find_best_node(id).children().iter().map(|c| egraph.find(*c)));
+        }
+    }
+    seen
+}
+
+/// Extracts greedily under two cost models and compares the graphs
+///
+/// # Parameters
+///
+/// - `egraph`: the saturated EGraph
+/// - `root`: its root
+/// - `model_a`, `model_b`: the cost models
+pub fn compare_cost_models(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    model_a: &CostModel,
+    model_b: &CostModel,
+) -> CostModelComparison {
+    let extractor_a = Extractor::new(egraph, TensorCost::new(egraph, model_a, true));
+    let extractor_b = Extractor::new(egraph, TensorCost::new(egraph, model_b, true));
+    let costs = |expr: &RecExpr<Mdl>| [graph_cost(egraph, expr, model_a), graph_cost(egraph, expr, model_b)];
+    let num_ops = |expr: &RecExpr<Mdl>| {
+        expr.as_ref()
+            .iter()
+            .filter(|node| !matches!(node, Mdl::Num(_) | Mdl::Var(_)))
+            .count()
+    };
+
+    let (_, best_a) = extractor_a.find_best(root);
+    let (_, best_b) = extractor_b.find_best(root);
+
+    let mut eclasses: Vec<Id> = picked_eclasses(egraph, &extractor_a, root)
+        .union(&picked_eclasses(egraph, &extractor_b, root))
+        .cloned()
+        .collect();
+    eclasses.sort();
+    let disagreements = eclasses
+        .iter()
+        .filter(|id| extractor_a.find_best_node(**id) != extractor_b.find_best_node(**id))
+        .map(|id| Disagreement {
+            eclass: usize::from(*id),
+            pick_a: extractor_a.find_best_node(*id).to_string(),
+            pick_b: extractor_b.find_best_node(*id).to_string(),
+            costs_a: costs(&extractor_a.find_best(*id).1),
+            costs_b: costs(&extractor_b.find_best(*id).1),
+        })
+        .collect();
+
+    CostModelComparison {
+        graph_a: costs(&best_a),
+        graph_b: costs(&best_b),
+        num_ops: [num_ops(&best_a), num_ops(&best_b)],
+        disagreements: disagreements,
+    }
+}
@@ -0,0 +1,122 @@
+//! Running a whole graph on given inputs (GraphExecutor)
+//!
+//! TASO runs a graph only to measure it (Graph::run, on device memory it
+//! never initializes), and gives back no values. The executor builds a graph
+//! on the TASO side, which checks its shapes, runs it there for its runtime,
+//! and computes its outputs with the reference interpreter (see interp.rs)
+//! on the inputs and weights the caller feeds, the others getting
+//! pseudo-random values. Without the runtime, it only runs the interpreter,
+//! as the rule verifier does.
+
+use crate::interp::{evaluate_with_inputs, HostTensor};
+use crate::model::*;
+use crate::provenance::expr_eclasses;
+use crate::resources::record_measure_time;
+use egg::*;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Outcome of running a graph
+#[derive(Debug, Clone)]
+pub struct Execution {
+    /// The outputs of the graph: the tensors combined by the noop at the
+    /// root, or the root tensor itself
+    pub outputs: Vec<HostTensor>,
+    /// Runtime of the whole graph as TASO measures it, in milliseconds, if
+    /// measured
+    pub runtime_ms: Option<f32>,
+}
+
+/// Runs graphs on fed inputs
+#[derive(Debug, Clone, Default)]
+pub struct GraphExecutor {
+    /// Values of inputs and weights, by their names without the shape
+    inputs: HashMap<String, HostTensor>,
+    /// Seed of the values of the inputs and weights that are not fed
+    seed: u64,
+    /// Whether to measure the runtime with TASO
+    measure: bool,
+    /// Whether TASO precomputes the ops on weights only before measuring, as
+    /// for the optimized graph
+    preprocess_weights: bool,
+}
+
+impl GraphExecutor {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Feeds the value of an input or weight, by its name without the shape
+    /// (`x` for `(input x@1_64)`)
+    pub fn with_input(mut self, name: &str, value: HostTensor) -> Self {
+        self.inputs.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Also measures the runtime of the graph with TASO, precomputing its ops
+    /// on weights only if preprocess_weights is set
+    pub fn with_runtime(mut self, preprocess_weights: bool) -> Self {
+        self.measure = true;
+        self.preprocess_weights = preprocess_weights;
+        self
+    }
+
+    /// Runs a graph
+    ///
+    /// # Returns
+    ///
+    /// Its outputs and runtime, or why it could not be run: a fed name that
+    /// is not an input or weight of the graph, a fed value of other
+    /// dimensions, an op TASO or the interpreter does not support
+    pub fn run(&self, expr: &RecExpr<Mdl>) -> Result<Execution, String> {
+        let names: Vec<String> = expr
+            .as_ref()
+            .iter()
+            .filter_map(|node| match node {
+                Mdl::Input([n]) | Mdl::Weight([n]) => Some(expr[*n].to_string()),
+                _ => None,
+            })
+            .collect();
+        for name in self.inputs.keys() {
+            if !names.iter().any(|full| full.split('@').next() == Some(name.as_str())) {
+                return Err(format!("the graph has no input or weight {}", name));
+            }
+        }
+        let runtime_ms = if self.measure { Some(self.measure_runtime(expr)?) } else { None };
+        let outputs = evaluate_with_inputs(expr, &self.inputs, self.seed)?;
+        Ok(Execution {
+            outputs: outputs,
+            runtime_ms: runtime_ms,
+        })
+    }
+
+    /// Builds a graph on the TASO side and measures it
+    fn measure_runtime(&self, expr: &RecExpr<Mdl>) -> Result<f32, String> {
+        let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+        let root = egraph.add_expr(expr);
+        let ids = expr_eclasses(&egraph, expr);
+        if let Some(i) = ids.iter().position(|id| id.map_or(false, |id| egraph[id].data.infeasible)) {
+            return Err(format!("TASO can not create node {} ({})", i, expr.as_ref()[i]));
+        }
+        if egraph[root].data.infeasible {
+            return Err(String::from("TASO can not create the graph"));
+        }
+        let mut g = egraph.analysis.graph.lock().unwrap();
+        let start_time = Instant::now();
+        let runtime = unsafe {
+            if self.preprocess_weights {
+                let processed_g = g.preprocess_weights();
+                (*processed_g).run()
+            } else {
+                (*g).run()
+            }
+        };
+        record_measure_time(start_time.elapsed());
+        Ok(runtime)
+    }
+}
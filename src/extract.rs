@@ -0,0 +1,864 @@
+//! Extraction strategies, trading optimality for speed
+//!
+//! - Greedy: egg's Extractor with TensorCost, picking the cheapest enode of
+//!   each eclass bottom up. Fast, but it counts a subgraph once for each of
+//!   its uses, so it does not see the savings of sharing.
+//! - Beam: a local search from the greedy graph, changing the pick of one
+//!   eclass at a time and keeping the `width` cheapest graphs of each round,
+//!   with the cost of a graph counting shared nodes once.
+//! - Ilp: the formulation of extractor/extract.py, solved in the crate with
+//!   CBC (linked through coin_cbc, the same solver as egg's LpExtractor): a
+//!   binary variable x_i per enode, the root eclass picks one enode, a picked
+//!   enode picks an enode in each of its children eclasses, and (unless
+//!   disabled) a variable t_m per eclass orders the picked eclasses, so that
+//!   the extracted graph has no cycles. Optimal, given the time.
+//! - IlpScript: the same formulation, solved by extractor/extract.py in a
+//!   Python process, through files in an output directory.
+//! - EggIlp: egg's LpExtractor, which ignores the memory budget.
+//!
+//! A memory budget (see extract_within_memory) bounds the total size of the
+//! activations of the extracted graph, each eclass counted once. This bounds
+//! the peak memory of any schedule of the graph, as if no activation were
+//! freed. The ILP takes it as a constraint, the greedy and beam extraction
+//! add a cost per MB of activation to the enodes, raised until the graph fits.
+//!
+//! With the cost model weighing the objective, the op count and the memory
+//! (see ObjectiveWeights), every strategy extracts for the weighted sum.
+//! pareto_front sweeps the weights, and keeps the extracted graphs no other
+//! graph beats on all three.
+
+use crate::ensemble::graph_cost;
+use crate::model::*;
+use crate::optimize::*;
+use crate::provenance::expr_eclasses;
+use crate::strategies::custom_strategy;
+use coin_cbc::{Col, Model, Sense};
+use egg::*;
+use serde::Serialize;
+use crate::subprocess::run_solver;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_to_string, write};
+use std::path::Path;
+use std::process::Command;
+use std::time::Instant;
+
+/// How to extract a graph from the EGraph
+#[derive(Debug, Clone)]
+pub enum ExtractionStrategy {
+    Greedy,
+    Beam {
+        /// Number of graphs kept in each round
+        width: usize,
+        /// Maximum number of rounds, each changes one more eclass
+        max_rounds: usize,
+    },
+    Ilp(IlpSettings),
+    /// The ILP solved by extractor/extract.py, which reads and writes its
+    /// files in output_dir
+    IlpScript { settings: IlpSettings, output_dir: String },
+    /// egg's LpExtractor
+    EggIlp,
+    /// A strategy registered under this name (see strategies.rs)
+    Custom(String),
+}
+
+impl ExtractionStrategy {
+    pub fn name(&self) -> &str {
+        match self {
+            ExtractionStrategy::Greedy => "greedy",
+            ExtractionStrategy::Beam { .. } => "beam",
+            ExtractionStrategy::Ilp(_) => "ilp (cbc)",
+            ExtractionStrategy::IlpScript { .. } => "ilp (extract.py)",
+            ExtractionStrategy::EggIlp => "egg_ilp",
+            ExtractionStrategy::Custom(name) => name,
+        }
+    }
+}
+
+/// Extracts a graph from EGraph with a strategy
+///
+/// # Parameters
+///
+/// - `egraph`: the EGraph to extract from
+/// - `root`: its root
+/// - `cost_model`: the costs of the enodes
+/// - `strategy`: how to extract
+///
+/// # Returns
+///
+/// The extracted graph, its cost as the strategy counts it (greedy counts
+/// shared nodes once per use, the others once) and the seconds it took
+pub fn extract(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    cost_model: &CostModel,
+    strategy: &ExtractionStrategy,
+) -> (RecExpr<Mdl>, f32, f32) {
+    match strategy {
+        ExtractionStrategy::Greedy => {
+            let start_time = Instant::now();
+            let extractor = Extractor::new(egraph, TensorCost::new(egraph, cost_model, true));
+            let (best_cost, best) = extractor.find_best(root);
+            (best, best_cost, start_time.elapsed().as_secs_f32())
+        }
+        ExtractionStrategy::Beam { width, max_rounds } => {
+            extract_by_beam(egraph, root, cost_model, *width, *max_rounds, 0.0)
+        }
+        ExtractionStrategy::Ilp(settings) => extract_by_cbc(egraph, root, cost_model, settings),
+        ExtractionStrategy::IlpScript { settings, output_dir } => {
+            extract_by_ilp_script(egraph, root, cost_model, settings, output_dir)
+        }
+        ExtractionStrategy::EggIlp => {
+            let start_time = Instant::now();
+            let mut lp_extractor = LpExtractor::new(egraph, TensorCost::new(egraph, cost_model, true));
+            let (best_cost, best) = lp_extractor.solve(root);
+            (best, best_cost as f32, start_time.elapsed().as_secs_f32())
+        }
+        ExtractionStrategy::Custom(name) => {
+            let custom = custom_strategy(name).unwrap_or_else(|| panic!("No extraction strategy {} is registered", name));
+            let start_time = Instant::now();
+            let (best, best_cost) = custom.extract(egraph, root, cost_model);
+            (best, best_cost, start_time.elapsed().as_secs_f32())
+        }
+    }
+}
+
+/// Number of times the greedy and beam extraction raise the memory penalty
+const MAX_PENALTY_ROUNDS: usize = 10;
+
+/// Gets the total size of the activations of a graph, counting each eclass
+/// once (see enode_activation_bytes)
+pub fn graph_memory(egraph: &EGraph<Mdl, TensorAnalysis>, expr: &RecExpr<Mdl>) -> u64 {
+    let ids = expr_eclasses(egraph, expr);
+    let mut bytes: HashMap<Id, u64> = HashMap::new();
+    for (node, id) in expr.as_ref().iter().zip(ids.iter()) {
+        if let Some(id) = id {
+            // The node is in the EGraph, so are its children
+            let node = node.clone().map_children(|child| ids[usize::from(child)].unwrap());
+            bytes.entry(*id).or_insert_with(|| enode_activation_bytes(egraph, &node));
+        }
+    }
+    bytes.values().sum()
+}
+
+/// Gets the size of the activation of each enode of the ILP, indexed as
+/// in prep_ilp_data
+pub fn memory_i(egraph: &EGraph<Mdl, TensorAnalysis>, i_to_nodes: &[Mdl]) -> Vec<u64> {
+    i_to_nodes.iter().map(|node| enode_activation_bytes(egraph, node)).collect()
+}
+
+/// Extracts a graph from EGraph whose activations fit in a memory budget
+///
+/// The ILP extracts the cheapest graph within the budget. The greedy and
+/// beam extraction add a cost per MB of activation to each enode, starting
+/// from the cost per MB of the unconstrained graph and doubling it until the
+/// graph fits, for at most MAX_PENALTY_ROUNDS rounds. Registered strategies
+/// extract as they are, with a warning if the graph does not fit.
+///
+/// # Parameters
+///
+/// - `egraph`: the EGraph to extract from
+/// - `root`: its root
+/// - `cost_model`: the costs of the enodes
+/// - `strategy`: how to extract
+/// - `max_memory`: the budget, in bytes
+///
+/// # Returns
+///
+/// The extracted graph (the smallest one found if none fits), its cost
+/// counting shared nodes once, without the penalty (the ILP objective for the
+/// ILP) and the seconds it took
+pub fn extract_within_memory(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    cost_model: &CostModel,
+    strategy: &ExtractionStrategy,
+    max_memory: u64,
+) -> (RecExpr<Mdl>, f32, f32) {
+    if let ExtractionStrategy::Ilp(settings) = strategy {
+        let settings = IlpSettings {
+            max_memory: Some(max_memory),
+            ..settings.clone()
+        };
+        return extract_by_cbc(egraph, root, cost_model, &settings);
+    }
+    if let ExtractionStrategy::IlpScript { settings, output_dir } = strategy {
+        let settings = IlpSettings {
+            max_memory: Some(max_memory),
+            ..settings.clone()
+        };
+        return extract_by_ilp_script(egraph, root, cost_model, &settings, output_dir);
+    }
+    if let ExtractionStrategy::EggIlp = strategy {
+        println!("Warning: egg_ilp extraction ignores max_memory");
+        return extract(egraph, root, cost_model, strategy);
+    }
+    // Registered strategies do not take a penalty
+    if let ExtractionStrategy::Custom(name) = strategy {
+        let (best, _, secs) = extract(egraph, root, cost_model, strategy);
+        let memory = graph_memory(egraph, &best);
+        if memory > max_memory {
+            println!(
+                "Warning: the graph of {} has {} MB of activations, over the budget of {} MB",
+                name,
+                memory / (1024 * 1024),
+                max_memory / (1024 * 1024)
+            );
+        }
+        let cost = graph_cost(egraph, &best, cost_model);
+        return (best, cost, secs);
+    }
+    let extract_penalized = |memory_weight: f32| match strategy {
+        ExtractionStrategy::Beam { width, max_rounds } => {
+            let (expr, _, secs) = extract_by_beam(egraph, root, cost_model, *width, *max_rounds, memory_weight);
+            (expr, secs)
+        }
+        _ => {
+            let start_time = Instant::now();
+            let cost_function = TensorCost::new(egraph, cost_model, true).with_memory_weight(memory_weight);
+            let (_, expr) = Extractor::new(egraph, cost_function).find_best(root);
+            (expr, start_time.elapsed().as_secs_f32())
+        }
+    };
+
+    let (mut best, mut total_secs) = extract_penalized(0.0);
+    let mut best_memory = graph_memory(egraph, &best);
+    let mut memory_weight = graph_cost(egraph, &best, cost_model) / (best_memory as f32 / 1e6).max(1e-6);
+    for _ in 0..MAX_PENALTY_ROUNDS {
+        if best_memory <= max_memory {
+            break;
+        }
+        let (expr, secs) = extract_penalized(memory_weight);
+        total_secs += secs;
+        let memory = graph_memory(egraph, &expr);
+        if memory < best_memory {
+            best = expr;
+            best_memory = memory;
+        }
+        memory_weight *= 2.0;
+    }
+    let cost = graph_cost(egraph, &best, cost_model);
+    (best, cost, total_secs)
+}
+
+/// Metrics of an extracted graph
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GraphMetrics {
+    /// Cost in the objective, counting shared nodes once
+    pub cost: f32,
+    /// Number of ops, not counting names and scalars
+    pub num_ops: usize,
+    /// Total size of the activations in bytes (see graph_memory)
+    pub memory: u64,
+}
+
+impl GraphMetrics {
+    /// Gets the metrics of a graph, with the cost without weights
+    pub fn of(egraph: &EGraph<Mdl, TensorAnalysis>, expr: &RecExpr<Mdl>, cost_model: &mut CostModel) -> Self {
+        let weights = cost_model.weights();
+        cost_model.set_weights(ObjectiveWeights::default());
+        let cost = graph_cost(egraph, expr, cost_model);
+        cost_model.set_weights(weights);
+        GraphMetrics {
+            cost: cost,
+            num_ops: expr.as_ref().iter().filter(|n| !matches!(n, Mdl::Num(_) | Mdl::Var(_))).count(),
+            memory: graph_memory(egraph, expr),
+        }
+    }
+
+    /// If this graph is at least as good on all metrics and better on one
+    pub fn dominates(&self, other: &GraphMetrics) -> bool {
+        self.cost <= other.cost
+            && self.num_ops <= other.num_ops
+            && self.memory <= other.memory
+            && (self.cost < other.cost || self.num_ops < other.num_ops || self.memory < other.memory)
+    }
+}
+
+/// A graph of the Pareto front, with the weights it was extracted with
+#[derive(Debug, Clone)]
+pub struct ParetoPoint {
+    pub weights: ObjectiveWeights,
+    pub metrics: GraphMetrics,
+    pub expr: RecExpr<Mdl>,
+}
+
+/// Multiples of the scale of the op count and memory weights swept by
+/// pareto_front
+const PARETO_MULTIPLES: [f32; 4] = [0.0, 0.1, 1.0, 10.0];
+
+/// Extracts graphs for different weights of the objective, the op count and
+/// the memory, and keeps the non-dominated ones
+///
+/// The weights of the op count and the memory are multiples (see
+/// PARETO_MULTIPLES) of the cost per op and per MB of the graph extracted
+/// for the objective alone, so that both metrics are on the scale of the
+/// objective. The weights of the cost model are restored afterwards.
+///
+/// # Parameters
+///
+/// - `egraph`: the EGraph to extract from
+/// - `root`: its root
+/// - `cost_model`: the costs of the enodes, its weights are swept
+/// - `strategy`: how to extract each graph
+///
+/// # Returns
+///
+/// The distinct non-dominated graphs, by increasing cost
+pub fn pareto_front(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    cost_model: &mut CostModel,
+    strategy: &ExtractionStrategy,
+) -> Vec<ParetoPoint> {
+    let weights = cost_model.weights();
+    cost_model.set_weights(ObjectiveWeights::default());
+    let (base, _, _) = extract(egraph, root, cost_model, strategy);
+    let base_metrics = GraphMetrics::of(egraph, &base, cost_model);
+    let op_scale = base_metrics.cost / base_metrics.num_ops.max(1) as f32;
+    let memory_scale = base_metrics.cost / (base_metrics.memory as f32 / 1e6).max(1e-6);
+
+    let mut points: Vec<ParetoPoint> = vec![];
+    let mut seen: HashSet<String> = HashSet::new();
+    for op_multiple in PARETO_MULTIPLES.iter() {
+        for memory_multiple in PARETO_MULTIPLES.iter() {
+            let point_weights = ObjectiveWeights {
+                objective: 1.0,
+                op_count: op_multiple * op_scale,
+                memory: memory_multiple * memory_scale,
+            };
+            cost_model.set_weights(point_weights);
+            let (expr, _, _) = extract(egraph, root, cost_model, strategy);
+            if !seen.insert(expr.to_string()) {
+                continue;
+            }
+            points.push(ParetoPoint {
+                weights: point_weights,
+                metrics: GraphMetrics::of(egraph, &expr, cost_model),
+                expr: expr,
+            });
+        }
+    }
+    cost_model.set_weights(weights);
+
+    let mut front: Vec<ParetoPoint> = points
+        .iter()
+        .filter(|p| !points.iter().any(|q| q.metrics.dominates(&p.metrics)))
+        .cloned()
+        .collect();
+    front.sort_by(|a, b| a.metrics.cost.partial_cmp(&b.metrics.cost).unwrap());
+    front
+}
+
+/// Gets how much more a graph costs than the ILP optimum, both counting
+/// shared nodes once (see ensemble::graph_cost)
+///
+/// # Returns
+///
+/// (cost - optimum) / optimum
+pub fn cost_gap(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    expr: &RecExpr<Mdl>,
+    optimum: &RecExpr<Mdl>,
+    cost_model: &CostModel,
+) -> f32 {
+    let optimal_cost = graph_cost(egraph, optimum, cost_model);
+    (graph_cost(egraph, expr, cost_model) - optimal_cost) / optimal_cost
+}
+
+/// Picks of the eclasses changed from the greedy ones
+type Overrides = Vec<(Id, Mdl)>;
+
+/// State of the local search: the greedy picks, and the self costs of all
+/// enodes
+struct BeamSearch<'a> {
+    egraph: &'a EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    greedy: HashMap<Id, Mdl>,
+    costs: HashMap<Id, Vec<(Mdl, f32)>>,
+}
+
+impl BeamSearch<'_> {
+    fn pick<'b>(&'b self, overrides: &'b [(Id, Mdl)], id: Id) -> &'b Mdl {
+        overrides
+            .iter()
+            .find(|(class, _)| *class == id)
+            .map_or_else(|| &self.greedy[&id], |(_, node)| node)
+    }
+
+    fn self_cost(&self, id: Id, node: &Mdl) -> f32 {
+        self.costs[&id].iter().find(|(n, _)| n == node).map_or(INFEASIBLE_COST, |(_, c)| *c)
+    }
+
+    /// Gets the eclasses of the graph and its cost, counting each eclass
+    /// once, or None if the picks have a cycle or an infeasible node
+    fn graph(&self, overrides: &[(Id, Mdl)]) -> Option<(Vec<Id>, f32)> {
+        let mut done: HashSet<Id> = HashSet::new();
+        let mut on_path: HashSet<Id> = HashSet::new();
+        let mut order = vec![];
+        let mut cost = 0.0;
+        // Depth first, with a marker to leave an eclass after its children
+        let mut todo = vec![(self.root, false)];
+        while let Some((id, leaving)) = todo.pop() {
+            if leaving {
+                on_path.remove(&id);
+                done.insert(id);
+                order.push(id);
+                continue;
+            }
+            if done.contains(&id) {
+                continue;
+            }
+            if !on_path.insert(id) {
+                return None;
+            }
+            let node = self.pick(overrides, id);
+            let node_cost = self.self_cost(id, node);
+            if node_cost >= INFEASIBLE_COST {
+                return None;
+            }
+            cost += node_cost;
+            todo.push((id, true));
+            for child in node.children() {
+                let child = self.egraph.find(*child);
+                if on_path.contains(&child) {
+                    return None;
+                }
+                if !done.contains(&child) {
+                    todo.push((child, false));
+                }
+            }
+        }
+        Some((order, cost))
+    }
+}
+
+/// Extracts by a beam search from the greedy graph
+///
+/// Each round changes the pick of one eclass of each graph in the beam to
+/// another enode (its new children eclasses keep their greedy picks), and
+/// keeps the `width` cheapest distinct graphs. The search stops when a round
+/// finds nothing cheaper than the best graph so far.
+///
+/// # Parameters
+///
+/// - `memory_weight`: cost added per MB of the activation of an enode, 0.0
+///   for none (see extract_within_memory)
+///
+/// # Returns
+///
+/// The extracted graph, its cost counting shared nodes once (with the memory
+/// penalty) and the seconds it took
+pub fn extract_by_beam(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    cost_model: &CostModel,
+    width: usize,
+    max_rounds: usize,
+    memory_weight: f32,
+) -> (RecExpr<Mdl>, f32, f32) {
+    let start_time = Instant::now();
+    let root = egraph.find(root);
+    let extractor = Extractor::new(
+        egraph,
+        TensorCost::new(egraph, cost_model, true).with_memory_weight(memory_weight),
+    );
+    let mut search = BeamSearch {
+        egraph: egraph,
+        root: root,
+        greedy: HashMap::new(),
+        costs: HashMap::new(),
+    };
+    for class in egraph.classes() {
+        let id = egraph.find(class.id);
+        search.greedy.insert(id, extractor.find_best_node(id).clone());
+        let costs = class
+            .iter()
+            .map(|node| {
+                let cost = if egraph.analysis.blacklist_nodes.contains(node) {
+                    INFEASIBLE_COST
+                } else {
+                    let penalty = memory_weight * enode_activation_bytes(egraph, node) as f32 / 1e6;
+                    cost_model.get_self_cost(egraph, node) + penalty
+                };
+                (node.clone(), cost)
+            })
+            .collect();
+        search.costs.insert(id, costs);
+    }
+
+    // An infeasible greedy graph has no feasible neighbours to search from
+    let greedy_cost = match search.graph(&[]) {
+        Some((_, cost)) => cost,
+        None => {
+            let (cost, expr) = extractor.find_best(root);
+            return (expr, cost, start_time.elapsed().as_secs_f32());
+        }
+    };
+    let mut best: (Overrides, f32) = (vec![], greedy_cost);
+    let mut beam: Vec<(Overrides, f32)> = vec![best.clone()];
+    for _ in 0..max_rounds {
+        let mut candidates: Vec<(Overrides, f32)> = vec![];
+        let mut seen: HashSet<Overrides> = HashSet::new();
+        for (overrides, _) in beam.iter() {
+            let (classes, _) = search.graph(overrides).unwrap();
+            for id in classes {
+                let current = search.pick(overrides, id).clone();
+                for (node, cost) in search.costs[&id].iter() {
+                    if *node == current || *cost >= INFEASIBLE_COST {
+                        continue;
+                    }
+                    let mut changed: Overrides = overrides.iter().filter(|(c, _)| *c != id).cloned().collect();
+                    changed.push((id, node.clone()));
+                    changed.sort_by_key(|(c, _)| *c);
+                    if !seen.insert(changed.clone()) {
+                        continue;
+                    }
+                    if let Some((_, cost)) = search.graph(&changed) {
+                        candidates.push((changed, cost));
+                    }
+                }
+            }
+        }
+        candidates.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        candidates.truncate(width.max(1));
+        match candidates.first() {
+            Some(candidate) if candidate.1 < best.1 => best = candidate.clone(),
+            _ => break,
+        }
+        beam = candidates;
+    }
+
+    let mut node_picked: HashMap<Id, Mdl> = search.greedy.clone();
+    node_picked.extend(best.0.iter().cloned());
+    let mut expr = RecExpr::default();
+    let mut added_memo: HashMap<Id, Id> = Default::default();
+    let _ = construct_best_rec(&node_picked, root, &mut added_memo, egraph, &mut expr);
+    (expr, best.1, start_time.elapsed().as_secs_f32())
+}
+
+/// Settings of the ILP, the flags of extractor/extract.py
+#[derive(Debug, Clone, Default)]
+pub struct IlpSettings {
+    /// Integer order variables t_m in [0, number of eclasses), instead of
+    /// continuous ones in [0, 1]
+    pub order_var_int: bool,
+    /// Each eclass picks at most one enode
+    pub class_constraint: bool,
+    /// No order variables, for EGraphs without cycles
+    pub no_order: bool,
+    /// Start the solver from the greedy solution
+    pub initialize: bool,
+    pub time_limit_sec: Option<u64>,
+    pub num_threads: Option<usize>,
+    /// Maximum total size of the activations of the graph, in bytes (see
+    /// graph_memory)
+    pub max_memory: Option<u64>,
+}
+
+/// Extracts the optimal graph from EGraph by ILP, solved with CBC
+///
+/// # Parameters
+///
+/// - `egraph`: the EGraph to extract from
+/// - `root`: its root
+/// - `cost_model`: the costs of the enodes
+/// - `settings`: the settings of the ILP
+///
+/// # Returns
+///
+/// The extracted graph, its cost and the seconds the solver took
+pub fn extract_by_cbc(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    cost_model: &CostModel,
+    settings: &IlpSettings,
+) -> (RecExpr<Mdl>, f32, f32) {
+    let (m_id_map, e_m, h_i, cost_i, g_i, root_m, i_to_nodes, blacklist_i) =
+        prep_ilp_data(egraph, root, cost_model);
+    let num_nodes = cost_i.len();
+    let num_classes = e_m.len();
+
+    let epsilon = 1.0 / (10.0 * num_classes as f64);
+    let big_a = if settings.order_var_int { num_classes as f64 } else { 2.0 };
+
+    let mut model = Model::default();
+    model.set_parameter("log", "0");
+    if let Some(time_limit) = settings.time_limit_sec {
+        model.set_parameter("seconds", &time_limit.to_string());
+    }
+    if let Some(num_threads) = settings.num_threads {
+        model.set_parameter("threads", &num_threads.to_string());
+    }
+
+    // Variables
+    let x: Vec<Col> = (0..num_nodes).map(|_| model.add_binary()).collect();
+    let t: Vec<Col> = if settings.no_order {
+        vec![]
+    } else {
+        (0..num_classes)
+            .map(|_| {
+                let col = if settings.order_var_int { model.add_integer() } else { model.add_col() };
+                model.set_col_lower(col, 0.0);
+                model.set_col_upper(col, if settings.order_var_int { big_a - 1.0 } else { 1.0 });
+                col
+            })
+            .collect()
+    };
+
+    // Root
+    let row = model.add_row();
+    model.set_row_equal(row, 1.0);
+    for j in e_m[root_m].iter() {
+        model.set_weight(row, x[*j], 1.0);
+    }
+
+    if settings.class_constraint {
+        for nodes in e_m.iter() {
+            let row = model.add_row();
+            model.set_row_upper(row, 1.0);
+            for j in nodes.iter() {
+                model.set_weight(row, x[*j], 1.0);
+            }
+        }
+    }
+
+    for (i, children) in h_i.iter().enumerate() {
+        for m in children.iter() {
+            // A node that is its own child always has its child picked, and
+            // can not be ordered after it
+            if *m == g_i[i] {
+                if !settings.no_order {
+                    model.set_col_upper(x[i], 0.0);
+                }
+                continue;
+            }
+
+            // Children
+            let row = model.add_row();
+            model.set_row_lower(row, 0.0);
+            for j in e_m[*m].iter() {
+                model.set_weight(row, x[*j], 1.0);
+            }
+            model.set_weight(row, x[i], -1.0);
+
+            // Order: t[g_i] - t[m] >= 1 (or epsilon) if x_i is picked
+            if !settings.no_order {
+                let row = model.add_row();
+                let lower = if settings.order_var_int { 1.0 } else { epsilon };
+                model.set_row_lower(row, lower - big_a);
+                model.set_weight(row, t[g_i[i]], 1.0);
+                model.set_weight(row, t[*m], -1.0);
+                model.set_weight(row, x[i], -big_a);
+            }
+        }
+    }
+
+    // Blacklist
+    for j in blacklist_i.iter() {
+        model.set_col_upper(x[*j], 0.0);
+    }
+
+    // Memory: the activations of the picked nodes fit in the budget
+    if let Some(max_memory) = settings.max_memory {
+        let row = model.add_row();
+        model.set_row_upper(row, max_memory as f64);
+        for (j, bytes) in memory_i(egraph, &i_to_nodes).iter().enumerate() {
+            model.set_weight(row, x[j], *bytes as f64);
+        }
+    }
+
+    // Objective
+    model.set_obj_sense(Sense::Minimize);
+    for (j, cost) in cost_i.iter().enumerate() {
+        model.set_obj_coeff(x[j], *cost as f64);
+    }
+
+    // Initial solution, from greedy extraction
+    if settings.initialize {
+        let node_to_i: HashMap<Mdl, usize> = i_to_nodes.iter().enumerate().map(|(i, node)| (node.clone(), i)).collect();
+        let extractor = Extractor::new(egraph, TensorCost::new(egraph, cost_model, true));
+        let (i_list, m_list) = get_init_solution(egraph, root, &extractor, &g_i, &node_to_i);
+        for col in x.iter() {
+            model.set_col_initial_solution(*col, 0.0);
+        }
+        for i in i_list.iter() {
+            model.set_col_initial_solution(x[*i], 1.0);
+        }
+        if !settings.no_order {
+            let gap = 1.0 / m_list.len() as f64;
+            for (count, m) in m_list.iter().enumerate() {
+                let order = if settings.order_var_int { count as f64 } else { count as f64 * gap };
+                model.set_col_initial_solution(t[*m], order);
+            }
+        }
+    }
+
+    let start_time = Instant::now();
+    let solution = model.solve();
+    let solve_sec = start_time.elapsed().as_secs_f32();
+    if let (Some(max_memory), true) = (settings.max_memory, solution.raw().is_proven_infeasible()) {
+        panic!("No graph fits in the memory budget of {} bytes", max_memory);
+    }
+    if !solution.raw().is_proven_optimal() {
+        println!("Warning: the ILP solution is not proven optimal");
+    }
+
+    let mut node_picked: HashMap<Id, Mdl> = HashMap::new();
+    for (i, col) in x.iter().enumerate() {
+        if solution.col(*col) > 0.5 {
+            let eclass_id = m_id_map[g_i[i]];
+            if node_picked.contains_key(&eclass_id) {
+                println!("Duplicate node in eclass");
+                println!("{}", node_picked.get(&eclass_id).unwrap());
+                println!("{}", i_to_nodes[i]);
+                continue;
+            }
+            node_picked.insert(eclass_id, i_to_nodes[i].clone());
+        }
+    }
+
+    if let Err(e) = check_selection(&node_picked, &[root], egraph) {
+        panic!("The ILP solution is not a valid graph: {}", e);
+    }
+    let mut expr = RecExpr::default();
+    let mut added_memo: HashMap<Id, Id> = Default::default();
+    let _ = construct_best_rec(&node_picked, root, &mut added_memo, egraph, &mut expr);
+    (expr, solution.raw().obj_value() as f32, solve_sec)
+}
+
+/// Extracts the optimal graph from EGraph by ILP, solved by
+/// extractor/extract.py
+///
+/// This function prepares the data for the ILP formulation, save it as json, call the python
+/// script to read the data + solve ILP + save the solved results. After the python script
+/// finishes, it reads back the solved result and construct the RecExpr for the optimized graph.
+///
+/// # Parameters
+///
+/// - `egraph`: the EGraph to extract from
+/// - `root`: its root
+/// - `cost_model`: the costs of the enodes
+/// - `settings`: the settings of the ILP, passed to the script as its flags
+/// - `output_dir`: where the data, the initial and the solved solution are
+///   written, one file each per thread
+///
+/// # Returns
+///
+/// The extracted graph, its cost and the seconds the solver took
+pub fn extract_by_ilp_script(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    cost_model: &CostModel,
+    settings: &IlpSettings,
+    output_dir: &str,
+) -> (RecExpr<Mdl>, f32, f32) {
+    let binding = std::thread::current();
+    let thread_name = binding.name().unwrap();
+    // Prepare data for ILP formulation, save to json
+    let (m_id_map, e_m, h_i, cost_i, g_i, root_m, i_to_nodes, blacklist_i) =
+        prep_ilp_data(egraph, root, cost_model);
+
+    let data = json!({
+        "e_m": e_m,
+        "h_i": h_i,
+        "cost_i": cost_i,
+        "g_i": g_i,
+        "root_m": root_m,
+        "blacklist_i": blacklist_i,
+        "memory_i": memory_i(egraph, &i_to_nodes),
+    });
+    let data_str = serde_json::to_string(&data).expect("Fail to convert json to string");
+
+    let filename = Path::new(output_dir).join("ilp_data_".to_owned() + thread_name + ".json");
+    write(filename, data_str).expect("Unable to write file");
+
+    if settings.initialize {
+        // Get node_to_i map
+        let node_to_i: HashMap<Mdl, usize> = i_to_nodes.iter().enumerate().map(|(i, node)| (node.clone(), i)).collect();
+        let extractor = Extractor::new(egraph, TensorCost::new(egraph, cost_model, true));
+        let (i_list, m_list) = get_init_solution(egraph, root, &extractor, &g_i, &node_to_i);
+
+        // Store initial solution
+        let solution_data = json!({
+            "i_list": i_list,
+            "m_list": m_list,
+        });
+        let sol_data_str = serde_json::to_string(&solution_data).expect("Fail to convert json to string");
+        let filename = Path::new(output_dir).join("init_sol_".to_owned() + thread_name + ".json");
+        write(filename, sol_data_str).expect("Unable to write file");
+    }
+
+    // Call python script to run ILP
+    let mut arg_vec = vec![String::from("extractor/extract.py")];
+    if settings.order_var_int {
+        arg_vec.push(String::from("--order_var_int"));
+    }
+    if settings.class_constraint {
+        arg_vec.push(String::from("--eclass_constraint"));
+    }
+    if settings.no_order {
+        arg_vec.push(String::from("--no_order"));
+    }
+    if settings.initialize {
+        arg_vec.push(String::from("--initialize"));
+    }
+    if let Some(time_lim) = settings.time_limit_sec {
+        arg_vec.push(String::from("--time_lim_sec"));
+        arg_vec.push(time_lim.to_string());
+    }
+    if let Some(num_thread) = settings.num_threads {
+        arg_vec.push(String::from("--num_thread"));
+        arg_vec.push(num_thread.to_string());
+    }
+    arg_vec.push(String::from("--output_dir"));
+    arg_vec.push(output_dir.to_string());
+    arg_vec.push(String::from("--thread_name"));
+    arg_vec.push(thread_name.to_string());
+    if let Some(memory_lim) = settings.max_memory {
+        arg_vec.push(String::from("--max_memory"));
+        arg_vec.push(memory_lim.to_string());
+    }
+    let status = run_solver(Command::new("python").args(&arg_vec)).unwrap_or_else(|e| panic!("Python script failed: {}", e));
+    if !status.success() {
+        panic!("Python script failed");
+    }
+
+    // Read back solved results, construct optimized graph
+    let filename = Path::new(output_dir).join("solved_".to_owned() + thread_name + ".json");
+    let solved_str = read_to_string(filename).expect("Something went wrong reading the solved file");
+    let solved_data: SolvedResults = serde_json::from_str(&solved_str).expect("JSON was not well-formatted");
+
+    let mut node_picked: HashMap<Id, Mdl> = HashMap::new();
+    for (i, x_i) in solved_data.solved_x.iter().enumerate() {
+        if *x_i == 1 {
+            let eclass_id = m_id_map[g_i[i]];
+            if node_picked.contains_key(&eclass_id) {
+                println!("Duplicate node in eclass");
+                println!("{}", node_picked.get(&eclass_id).unwrap());
+                println!("{}", i_to_nodes[i]);
+                continue;
+            }
+            node_picked.insert(eclass_id, i_to_nodes[i].clone());
+        }
+    }
+
+    if let Err(e) = check_selection(&node_picked, &[root], egraph) {
+        panic!("The ILP solution is not a valid graph: {}", e);
+    }
+    let mut expr = RecExpr::default();
+    let mut added_memo: HashMap<Id, Id> = Default::default();
+    let _ = construct_best_rec(&node_picked, root, &mut added_memo, egraph, &mut expr);
+    (expr, solved_data.cost, solved_data.time)
+}
+
+/// Gets the lower bound on the cost of the last ILP solved by
+/// extractor/extract.py (in the thread)
+pub fn ilp_script_bound(output_dir: &str) -> Option<f32> {
+    let binding = std::thread::current();
+    let filename = Path::new(output_dir).join("solved_".to_owned() + binding.name().unwrap() + ".json");
+    let solved_str = read_to_string(filename).ok()?;
+    let solved_data: SolvedResults = serde_json::from_str(&solved_str).ok()?;
+    solved_data.bound
+}
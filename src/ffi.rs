@@ -0,0 +1,401 @@
+//! Helpers around the FFI boundary to the TASO runtime
+//!
+//! Ownership across the boundary: TASO copies the dims, data and input
+//! arrays passed to it (new_input, new_weight, get_or_create_concat, ...)
+//! before returning, so they stay Rust vectors freed as usual. Everything
+//! else belongs to a TasoGraph: the Graph and its Model, the ops the Model
+//! caches, the tensors TASO returns (allocated on the C++ side) and the
+//! tensors created on the Rust side (the outputs of a split, which TASO does
+//! not return as tensors). The analysis keeps them as TasoTensors and TasoOps,
+//! which keep their graph alive, and the graph is freed once the TasoGraph
+//! and the last of them are dropped.
+//!
+//! TASO's Graph and Model declare no destructors (their implicit ones are
+//! inline, so there are no symbols for bindgen to bind), so the C++ side is
+//! created and freed by src/taso_graph.cc, compiled by build.rs against the
+//! header of the bindings. The input and weight ops of a graph are not
+//! cached by its Model, and are still not freed.
+
+use crate::model::*;
+use crate::resources::*;
+use root::taso::*;
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+/// The fork of TASO the bindings in taso_bindings.rs are generated against
+pub const TASO_FORK: &str = "https://github.com/yycdavid/taso";
+
+/// Dimensions of the probe tensor used in the handshake. Chosen to be all
+/// different so that a shifted field shows up as a wrong value
+const PROBE_DIMS: [i32; 4] = [2, 3, 5, 7];
+
+/// Checks that the linked TASO library agrees with the bindings
+///
+/// TASO does not export a version symbol or the sizes of its structs, so the
+/// handshake creates a small probe graph on the TASO side and reads the
+/// returned tensor and op back through the bindings. If the library was built
+/// from different headers, the fields read back will not match what was
+/// passed in. (Comparing the sizes of the bindgen structs would not tell: they
+/// are compiled into this crate, from the headers of the bindings.)
+///
+/// # Returns
+///
+/// Ok if the library looks compatible, otherwise an error message explaining
+/// the mismatch and how to fix it
+pub fn check_taso_abi() -> Result<(), String> {
+    unsafe {
+        let mut graph = TasoGraph::new();
+        if graph.model.is_null() {
+            return Err(abi_error("Graph was constructed without a Model"));
+        }
+
+        let dims = PROBE_DIMS;
+        let ndim: i32 = dims.len().try_into().unwrap();
+        let t = graph.new_input(ndim, dims.as_ptr());
+        if t.is_null() {
+            return Err(abi_error("new_input returned a null tensor"));
+        }
+        if (*t).numDim != ndim || (*t).dim[..dims.len()] != dims[..] {
+            return Err(abi_error(&format!(
+                "new_input({:?}) returned a tensor with numDim {} and dims {:?}",
+                dims,
+                (*t).numDim,
+                &(*t).dim[..]
+            )));
+        }
+
+        let op = (*t).op;
+        if op.ptr.is_null() {
+            return Err(abi_error("input tensor has no op attached"));
+        }
+        let op_base = &*op.ptr;
+        if op_base.type_ != OpType_OP_INPUT || op_base.numOutputs != 1 {
+            return Err(abi_error(&format!(
+                "input op has type {} and {} outputs, expected type {} and 1 output",
+                op_base.type_, op_base.numOutputs, OpType_OP_INPUT
+            )));
+        }
+        if op_base.outputs[0].dim[..dims.len()] != dims[..] || op_base.model != graph.model {
+            return Err(abi_error("fields of the input op do not match the probe tensor"));
+        }
+    }
+    Ok(())
+}
+
+fn abi_error(detail: &str) -> String {
+    format!(
+        "The linked TASO runtime does not match taso_bindings.rs ({}). \
+         Rebuild and reinstall TASO from {} and regenerate the bindings with \
+         bindgen (see build.rs) so both come from the same headers.",
+        detail, TASO_FORK
+    )
+}
+
+extern "C" {
+    // In src/taso_graph.cc
+    fn tensat_new_graph() -> *mut Graph;
+    fn tensat_free_graph(graph: *mut Graph);
+    fn tensat_free_tensor(tensor: TensorHandle);
+}
+
+/// The C++ side of a TasoGraph, with the tensors of the graph, shared with
+/// its TasoTensors and TasoOps
+struct GraphMemory {
+    graph: *mut Graph,
+    /// Tensors TASO returned, allocated on the C++ side
+    taso_tensors: Mutex<Vec<TensorHandle>>,
+    /// Tensors created on the Rust side, boxed so that their handles stay
+    /// valid as more are added
+    #[allow(clippy::vec_box)]
+    rust_tensors: Mutex<Vec<Box<Tensor>>>,
+}
+
+// The graph is only changed through its TasoGraph, behind the lock of
+// TensorAnalysis::graph
+unsafe impl Send for GraphMemory {}
+unsafe impl Sync for GraphMemory {}
+
+impl Drop for GraphMemory {
+    fn drop(&mut self) {
+        let taso_tensors = self.taso_tensors.get_mut().unwrap();
+        count_taso_tensors_freed(taso_tensors.len());
+        count_rust_tensors_freed(self.rust_tensors.get_mut().unwrap().len());
+        unsafe {
+            for t in taso_tensors.drain(..) {
+                tensat_free_tensor(t);
+            }
+            tensat_free_graph(self.graph);
+        }
+        count_taso_graph_freed();
+    }
+}
+
+/// A graph on the TASO side, owning its Model, ops and tensors (see the
+/// module doc)
+pub struct TasoGraph {
+    memory: Arc<GraphMemory>,
+}
+
+impl TasoGraph {
+    pub fn new() -> Self {
+        let graph = unsafe { tensat_new_graph() };
+        count_taso_graph();
+        TasoGraph {
+            memory: Arc::new(GraphMemory {
+                graph: graph,
+                taso_tensors: Mutex::new(vec![]),
+                rust_tensors: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// Creates a tensor on the TASO side
+    ///
+    /// # Parameters
+    ///
+    /// - `func`: the TASO function f calls, for the error
+    /// - `f`: calls a function of the graph returning a new tensor
+    ///
+    /// # Returns
+    ///
+    /// The tensor, freed with the graph, or an error if f returned a null
+    /// tensor
+    pub fn create<F: FnOnce(&mut Graph) -> TensorHandle>(&mut self, func: &'static str, f: F) -> Result<TasoTensor, TasoError> {
+        let t = check_tensor(func, f(self))?;
+        self.memory.taso_tensors.lock().unwrap().push(t);
+        Ok(self.tensor(t))
+    }
+
+    /// Keeps a tensor created on the Rust side
+    ///
+    /// # Returns
+    ///
+    /// The tensor, freed with the graph
+    pub fn own_tensor(&mut self, tensor: Tensor) -> TasoTensor {
+        let mut tensor = Box::new(tensor);
+        let handle: TensorHandle = &mut *tensor;
+        self.memory.rust_tensors.lock().unwrap().push(tensor);
+        count_rust_tensor();
+        self.tensor(handle)
+    }
+
+    /// Takes an op of the Model of the graph, see check_op
+    pub fn op(&self, func: &'static str, op: Op) -> Result<TasoOp, TasoError> {
+        Ok(TasoOp {
+            op: check_op(func, op)?,
+            _graph: self.memory.clone(),
+        })
+    }
+
+    /// Number of tensors created on the Rust side
+    pub fn num_owned_tensors(&self) -> usize {
+        self.memory.rust_tensors.lock().unwrap().len()
+    }
+
+    fn tensor(&self, handle: TensorHandle) -> TasoTensor {
+        TasoTensor {
+            handle: handle,
+            _graph: Some(self.memory.clone()),
+        }
+    }
+}
+
+impl Default for TasoGraph {
+    fn default() -> Self {
+        TasoGraph::new()
+    }
+}
+
+impl Deref for TasoGraph {
+    type Target = Graph;
+
+    fn deref(&self) -> &Graph {
+        unsafe { &*self.memory.graph }
+    }
+}
+
+impl DerefMut for TasoGraph {
+    fn deref_mut(&mut self) -> &mut Graph {
+        unsafe { &mut *self.memory.graph }
+    }
+}
+
+/// A tensor of a TasoGraph, or no tensor (see TasoTensor::null). Its graph
+/// stays allocated as long as the tensor is kept, so the handle stays valid
+#[derive(Clone)]
+pub struct TasoTensor {
+    handle: TensorHandle,
+    _graph: Option<Arc<GraphMemory>>,
+}
+
+// The tensor is not changed once created
+unsafe impl Send for TasoTensor {}
+unsafe impl Sync for TasoTensor {}
+
+impl TasoTensor {
+    pub fn null() -> Self {
+        TasoTensor {
+            handle: std::ptr::null_mut(),
+            _graph: None,
+        }
+    }
+
+    /// Gets the handle to pass to TASO, null if there is no tensor
+    pub fn ptr(&self) -> TensorHandle {
+        self.handle
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.handle.is_null()
+    }
+}
+
+impl Default for TasoTensor {
+    fn default() -> Self {
+        TasoTensor::null()
+    }
+}
+
+impl fmt::Debug for TasoTensor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TasoTensor({:?})", self.handle)
+    }
+}
+
+/// An op cached by the Model of a TasoGraph, which stays allocated as long
+/// as the op is kept
+pub struct TasoOp {
+    op: Op,
+    _graph: Arc<GraphMemory>,
+}
+
+impl TasoOp {
+    pub fn op(&self) -> Op {
+        self.op
+    }
+
+    /// Gets the runtime TASO measured when it created the op
+    pub fn runtime(&self) -> f32 {
+        unsafe { (*self.op.ptr).runtime }
+    }
+}
+
+/// Errors from creating an op on the TASO side
+///
+/// C++ exceptions can not unwind through the bindings, but TASO reports most
+/// failures (e.g. unsupported shapes or parameters) by returning a null tensor
+/// or an invalid op, which are translated into these errors.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TasoError {
+    /// The TASO function returned a null tensor
+    NullTensor(&'static str),
+    /// The TASO function returned INVALID_OP
+    InvalidOp(&'static str),
+    /// The TASO function returned a null graph
+    NullGraph(&'static str),
+    /// The output tensor has a shape that does not validate
+    InvalidShape(String),
+    /// An input tensor of the op could not be created
+    MissingInput,
+    /// The op is not supported by the analysis
+    Unsupported(String),
+    /// A panic was caught while creating the op
+    Panic(String),
+}
+
+impl fmt::Display for TasoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TasoError::NullTensor(func) => write!(f, "TASO {} returned a null tensor", func),
+            TasoError::InvalidOp(func) => write!(f, "TASO {} returned an invalid op", func),
+            TasoError::NullGraph(func) => write!(f, "TASO {} returned a null graph", func),
+            TasoError::InvalidShape(shape) => write!(f, "invalid output shape {}", shape),
+            TasoError::MissingInput => write!(f, "an input tensor is missing"),
+            TasoError::Unsupported(op) => write!(f, "op not supported: {}", op),
+            TasoError::Panic(msg) => write!(f, "panicked: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TasoError {}
+
+/// Turns a null tensor returned by the TASO function `func` into an error
+pub fn check_tensor(func: &'static str, t: TensorHandle) -> Result<TensorHandle, TasoError> {
+    if t.is_null() {
+        Err(TasoError::NullTensor(func))
+    } else {
+        count_taso_tensor();
+        Ok(t)
+    }
+}
+
+/// Checks that the tensor t has at most 8 dimensions, all positive. A 0-d
+/// tensor (a scalar) is valid
+pub fn check_shape(t: TensorHandle) -> Result<(), TasoError> {
+    let n_dim = unsafe { (*t).numDim };
+    let valid = n_dim >= 0
+        && (n_dim as usize) <= 8
+        && unsafe { (*t).dim[..n_dim as usize].iter().all(|d| *d > 0) };
+    if valid {
+        Ok(())
+    } else {
+        let dims = unsafe { &(*t).dim[..(n_dim.max(0) as usize).min(8)] };
+        Err(TasoError::InvalidShape(format!("{:?}", dims)))
+    }
+}
+
+/// Turns an invalid op returned by the TASO function `func` into an error
+pub fn check_op(func: &'static str, op: Op) -> Result<Op, TasoError> {
+    if op.ptr.is_null() || unsafe { op == Op_INVALID_OP } {
+        Err(TasoError::InvalidOp(func))
+    } else {
+        Ok(op)
+    }
+}
+
+/// Runs TASO's own optimizer on graph: a backtracking search applying its
+/// substitutions one at a time, keeping the graphs within alpha of the best
+/// one found so far
+///
+/// # Parameters
+///
+/// - `graph`: the graph to optimize, unchanged
+/// - `alpha`: how much slower than the best graph a graph can be and still be
+///   explored further, TASO uses 1.0 by default
+/// - `budget`: the number of graphs to explore
+///
+/// # Returns
+///
+/// The best graph found, a new graph on the TASO side sharing the input and
+/// weight ops of graph
+pub fn taso_optimize(graph: &mut Graph, alpha: f32, budget: i32) -> Result<*mut Graph, TasoError> {
+    let optimized = unsafe { graph.optimize(alpha, budget, false) };
+    if optimized.is_null() {
+        Err(TasoError::NullGraph("optimize"))
+    } else {
+        Ok(optimized)
+    }
+}
+
+/// Runs f, turning a panic inside it into a TasoError::Panic
+///
+/// Used around callbacks that call into TASO (like the analysis), so that a
+/// single bad op does not abort the whole process.
+pub fn catch_panic<T, F: FnOnce() -> Result<T, TasoError>>(f: F) -> Result<T, TasoError> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => {
+            let msg = if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                String::from("unknown panic")
+            };
+            Err(TasoError::Panic(msg))
+        }
+    }
+}
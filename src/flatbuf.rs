@@ -0,0 +1,287 @@
+//! Exporting the optimized graph as a FlatBuffer (--output_format flatbuffer)
+//!
+//! Embedded runtimes often can not afford an ONNX (protobuf) parser, while a
+//! FlatBuffer is read in place, without unpacking or allocating. The graph is
+//! written with a small builder of the FlatBuffer binary format, as onnx.rs
+//! does for protobuf, following this schema (file identifier "TSAT"):
+//!
+//! ```text
+//! table Node {
+//!   op: string;       // op of the language, e.g. "conv2d"
+//!   inputs: [uint];   // indices of the input nodes in Graph.nodes
+//!   attrs: [int];     // the number arguments, in the order of the op
+//!   label: string;    // the name argument (e.g. the shape of a reshape)
+//!   name: string;     // input and weight nodes: name without the shape
+//!   dims: [int];      // input and weight nodes: dimensions
+//!   density: float = 1.0;  // weight nodes: density of a pruned weight
+//! }
+//! table Graph {
+//!   nodes: [Node];    // in topological order
+//!   weights: [uint];  // indices of the weight nodes
+//!   outputs: [uint];  // indices of the output nodes
+//! }
+//! root_type Graph;
+//! ```
+//!
+//! The noop nodes combining the outputs are not written, their inputs are
+//! the outputs of the graph. As in export_onnx, the values of the weights are
+//! not in the file: the runtime attaches them by name.
+
+use crate::model::*;
+use crate::names::parse_tensor_name;
+use egg::*;
+use std::convert::TryInto;
+
+/// File identifier of the exported graphs
+pub const FLATBUFFER_IDENTIFIER: &[u8; 4] = b"TSAT";
+
+/// A field of a table
+enum Slot {
+    /// Offset of an object written before
+    Offset(usize),
+    Float(f32),
+}
+
+/// Writes a FlatBuffer back to front
+///
+/// The bytes are kept reversed, so positions are counted from the end of
+/// the buffer: an object written earlier is at a higher address, which the
+/// unsigned offsets of FlatBuffers require.
+#[derive(Default)]
+struct Builder {
+    rev: Vec<u8>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Default::default()
+    }
+
+    /// Pads so the next 4-byte value, written after `additional` bytes, is
+    /// aligned
+    fn align(&mut self, additional: usize) {
+        while (self.rev.len() + additional) % 4 != 0 {
+            self.rev.push(0);
+        }
+    }
+
+    fn prepend(&mut self, bytes: &[u8]) {
+        self.rev.extend(bytes.iter().rev());
+    }
+
+    fn prepend_u32(&mut self, v: u32) {
+        self.align(0);
+        self.prepend(&v.to_le_bytes());
+    }
+
+    /// Writes an offset to an object, at the next position
+    fn prepend_offset(&mut self, target: usize) {
+        self.align(0);
+        let at = self.rev.len() + 4;
+        self.prepend(&((at - target) as u32).to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) -> usize {
+        self.align(s.len() + 1);
+        self.prepend(&[0]);
+        self.prepend(s.as_bytes());
+        self.prepend_u32(s.len() as u32);
+        self.rev.len()
+    }
+
+    fn ints(&mut self, vals: &[i32]) -> usize {
+        self.align(0);
+        for v in vals.iter().rev() {
+            self.prepend(&v.to_le_bytes());
+        }
+        self.prepend_u32(vals.len() as u32);
+        self.rev.len()
+    }
+
+    fn uints(&mut self, vals: &[u32]) -> usize {
+        self.align(0);
+        for v in vals.iter().rev() {
+            self.prepend(&v.to_le_bytes());
+        }
+        self.prepend_u32(vals.len() as u32);
+        self.rev.len()
+    }
+
+    /// Writes a vector of tables written before
+    fn tables(&mut self, offsets: &[usize]) -> usize {
+        for off in offsets.iter().rev() {
+            self.prepend_offset(*off);
+        }
+        self.prepend_u32(offsets.len() as u32);
+        self.rev.len()
+    }
+
+    /// Writes a table and its vtable
+    ///
+    /// # Parameters
+    ///
+    /// - `slots`: the fields, by their index in the schema, None for the
+    ///   absent ones
+    fn table(&mut self, slots: &[Option<Slot>]) -> usize {
+        self.align(0);
+        let start = self.rev.len();
+        let mut positions = vec![0; slots.len()];
+        for (i, slot) in slots.iter().enumerate().rev() {
+            match slot {
+                Some(Slot::Offset(target)) => self.prepend_offset(*target),
+                Some(Slot::Float(v)) => {
+                    self.align(0);
+                    self.prepend(&v.to_le_bytes())
+                }
+                None => continue,
+            }
+            positions[i] = self.rev.len();
+        }
+        // Offset to the vtable, set once the vtable is written
+        self.prepend_u32(0);
+        let table = self.rev.len();
+        for pos in positions.iter().rev() {
+            let field = if *pos == 0 { 0 } else { (table - pos) as u16 };
+            self.prepend(&field.to_le_bytes());
+        }
+        self.prepend(&((table - start) as u16).to_le_bytes());
+        self.prepend(&((4 + 2 * slots.len()) as u16).to_le_bytes());
+        let vtable = self.rev.len();
+        let soffset = ((vtable - table) as i32).to_le_bytes();
+        for (k, b) in soffset.iter().enumerate() {
+            self.rev[table - 1 - k] = *b;
+        }
+        table
+    }
+
+    /// Writes the header, with the offset to the root table
+    fn finish(mut self, root: usize) -> Vec<u8> {
+        self.align(8);
+        self.prepend(FLATBUFFER_IDENTIFIER);
+        self.prepend_offset(root);
+        self.rev.reverse();
+        self.rev
+    }
+}
+
+/// Serializes a graph as a FlatBuffer (see the schema above)
+///
+/// # Parameters
+///
+/// - `expr`: the graph, with the attributes not packed (see attrs::unpack_expr)
+///
+/// # Returns
+///
+/// The serialized graph, or the input or weight whose name is invalid
+pub fn export_flatbuffer(expr: &RecExpr<Mdl>) -> Result<Vec<u8>, String> {
+    let nodes = expr.as_ref();
+    let mut b = Builder::new();
+    // Index of each tensor node in Graph.nodes
+    let mut index: Vec<Option<u32>> = vec![None; nodes.len()];
+    let mut tables = Vec::new();
+    let mut weights = Vec::new();
+    for (i, node) in nodes.iter().enumerate() {
+        if matches!(node, Mdl::Num(_) | Mdl::Var(_) | Mdl::Noop(_)) {
+            continue;
+        }
+        let mut inputs = Vec::new();
+        let mut attrs = Vec::new();
+        let mut label = None;
+        for child in node.children() {
+            match &nodes[usize::from(*child)] {
+                Mdl::Num(v) => attrs.push(*v),
+                Mdl::Var(s) => label = Some(s.as_str()),
+                _ => inputs.push(index[usize::from(*child)].ok_or_else(|| format!("node {} uses a noop", i))?),
+            }
+        }
+        let mut slots: Vec<Option<Slot>> = Vec::with_capacity(7);
+        slots.push(Some(Slot::Offset(b.string(&node.to_string()))));
+        slots.push(Some(Slot::Offset(b.uints(&inputs))));
+        slots.push(Some(Slot::Offset(b.ints(&attrs))));
+        match node {
+            Mdl::Input(_) | Mdl::Weight(_) => {
+                let name = parse_tensor_name(label.unwrap())?;
+                slots.push(None);
+                slots.push(Some(Slot::Offset(b.string(&name.label))));
+                slots.push(Some(Slot::Offset(b.ints(&name.dims))));
+                slots.push(name.density.map(Slot::Float));
+            }
+            _ => slots.push(label.map(|s| Slot::Offset(b.string(s)))),
+        }
+        let n = tables.len() as u32;
+        if matches!(node, Mdl::Weight(_)) {
+            weights.push(n);
+        }
+        tables.push(b.table(&slots));
+        index[i] = Some(n);
+    }
+
+    // Flatten the noops at the root into the outputs
+    let mut outputs = Vec::new();
+    let mut stack = vec![nodes.len() - 1];
+    while let Some(i) = stack.pop() {
+        match &nodes[i] {
+            Mdl::Noop([x, y]) => {
+                stack.push(usize::from(*y));
+                stack.push(usize::from(*x));
+            }
+            _ => outputs.extend(index[i]),
+        }
+    }
+
+    let nodes_off = b.tables(&tables);
+    let weights_off = b.uints(&weights);
+    let outputs_off = b.uints(&outputs);
+    let root = b.table(&[
+        Some(Slot::Offset(nodes_off)),
+        Some(Slot::Offset(weights_off)),
+        Some(Slot::Offset(outputs_off)),
+    ]);
+    Ok(b.finish(root))
+}
+
+fn read_u32(buf: &[u8], at: usize) -> Result<u32, String> {
+    let bytes = buf.get(at..at + 4).ok_or("truncated FlatBuffer")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u16(buf: &[u8], at: usize) -> Result<u16, String> {
+    let bytes = buf.get(at..at + 2).ok_or("truncated FlatBuffer")?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Gets the position of the object a field of a table points to
+fn field_target(buf: &[u8], table: usize, field: usize) -> Result<Option<usize>, String> {
+    let vtable = table as i64 - read_u32(buf, table)? as i32 as i64;
+    if vtable < 0 {
+        return Err("invalid vtable offset".to_string());
+    }
+    let vtable = vtable as usize;
+    if 4 + 2 * field >= read_u16(buf, vtable)? as usize {
+        return Ok(None);
+    }
+    match read_u16(buf, vtable + 4 + 2 * field)? as usize {
+        0 => Ok(None),
+        off => Ok(Some(table + off + read_u32(buf, table + off)? as usize)),
+    }
+}
+
+/// Reads the ops of the nodes of a graph exported by export_flatbuffer, to
+/// check a file
+pub fn flatbuffer_ops(buf: &[u8]) -> Result<Vec<String>, String> {
+    if buf.get(4..8) != Some(&FLATBUFFER_IDENTIFIER[..]) {
+        return Err("not a graph exported by tensat".to_string());
+    }
+    let root = read_u32(buf, 0)? as usize;
+    let nodes = field_target(buf, root, 0)?.ok_or("the graph has no nodes")?;
+    (0..read_u32(buf, nodes)? as usize)
+        .map(|k| {
+            let elem = nodes + 4 + 4 * k;
+            let node = elem + read_u32(buf, elem)? as usize;
+            let op = field_target(buf, node, 0)?.ok_or("a node has no op")?;
+            let len = read_u32(buf, op)? as usize;
+            let bytes = buf.get(op + 4..op + 4 + len).ok_or("truncated FlatBuffer")?;
+            String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+        })
+        .collect()
+}
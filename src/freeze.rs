@@ -0,0 +1,149 @@
+//! Freezing a prefix or suffix of the graph (--freeze_prefix, --freeze_suffix)
+//!
+//! Part of a model may have to stay as it is, e.g. a calibrated quantized
+//! head whose accuracy was validated op by op. Its layers are frozen: rules
+//! do not apply to matches on their enodes, so no rewrite replaces them or
+//! fuses them into their neighbours, and in the extraction their eclasses
+//! only have their original enode (see FrozenGraph), so the optimized graph
+//! has them verbatim. The rest of the graph is optimized normally and uses
+//! the frozen tensors as they are.
+//!
+//! A prefix is frozen up to a layer: the layer and every layer it depends
+//! on. A suffix from a layer: the layer and every layer depending on it.
+//! Layers are named as in the profiles (see provenance::default_layer_names),
+//! e.g. conv2d_12. Inputs, weights, names and scalars are never frozen, the
+//! rest of the graph shares them.
+
+use crate::model::*;
+use crate::provenance::{default_layer_names, expr_eclasses};
+use egg::*;
+use std::collections::HashSet;
+
+/// If a node can be frozen, as opposed to a leaf or the noops combining the
+/// outputs
+fn is_frozen_op(node: &Mdl) -> bool {
+    !matches!(node, Mdl::Num(_) | Mdl::Var(_) | Mdl::Input(_) | Mdl::Weight(_) | Mdl::Noop(_))
+}
+
+/// Gets the index of a layer of a graph by its name, e.g. conv2d_12
+pub fn layer_index(expr: &RecExpr<Mdl>, name: &str) -> Result<usize, String> {
+    default_layer_names(expr)
+        .into_iter()
+        .find(|(_, n)| n == name)
+        .map(|(id, _)| usize::from(id))
+        .ok_or_else(|| format!("the graph has no layer {}", name))
+}
+
+/// Gets the frozen layers of a graph
+///
+/// # Parameters
+///
+/// - `expr`: the graph
+/// - `prefix`: indices of the layers up to which the graph is frozen
+/// - `suffix`: indices of the layers from which the graph is frozen
+///
+/// # Returns
+///
+/// For each node of expr, if it is frozen
+pub fn frozen_layers(expr: &RecExpr<Mdl>, prefix: &[usize], suffix: &[usize]) -> Vec<bool> {
+    let nodes = expr.as_ref();
+    // The layers the prefix layers depend on, from the root down
+    let mut before = vec![false; nodes.len()];
+    for (i, node) in nodes.iter().enumerate().rev() {
+        before[i] |= prefix.contains(&i);
+        if before[i] {
+            for child in node.children() {
+                before[usize::from(*child)] = true;
+            }
+        }
+    }
+    // The layers depending on the suffix layers, from the leaves up
+    let mut after = vec![false; nodes.len()];
+    for (i, node) in nodes.iter().enumerate() {
+        after[i] = suffix.contains(&i) || node.children().iter().any(|c| after[usize::from(*c)]);
+    }
+    (0..nodes.len())
+        .map(|i| (before[i] || after[i]) && is_frozen_op(&nodes[i]))
+        .collect()
+}
+
+/// Gets the enodes of the frozen layers in an EGraph constructed from the
+/// graph, to set TensorAnalysis::frozen_nodes
+pub fn frozen_enodes(egraph: &EGraph<Mdl, TensorAnalysis>, expr: &RecExpr<Mdl>, frozen: &[bool]) -> HashSet<Mdl> {
+    let ids = expr_eclasses(egraph, expr);
+    expr.as_ref()
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| frozen[*i])
+        .filter_map(|(_, node)| {
+            let children: Option<Vec<Id>> = node.children().iter().map(|c| ids[usize::from(*c)]).collect();
+            let mut children = children?.into_iter();
+            Some(node.clone().map_children(|_| children.next().unwrap()))
+        })
+        .collect()
+}
+
+/// Updates the frozen enodes with the canonical Ids of their children, after
+/// the EGraph was rebuilt
+pub fn canonicalize_frozen(egraph: &mut EGraph<Mdl, TensorAnalysis>) {
+    let frozen = std::mem::take(&mut egraph.analysis.frozen_nodes);
+    egraph.analysis.frozen_nodes = frozen
+        .into_iter()
+        .map(|enode| enode.map_children(|id| egraph.find(id)))
+        .collect();
+}
+
+/// If an eclass has a frozen enode. Needs the frozen enodes to be canonical
+pub fn is_frozen_class(egraph: &EGraph<Mdl, TensorAnalysis>, eclass: Id) -> bool {
+    let frozen = &egraph.analysis.frozen_nodes;
+    !frozen.is_empty() && egraph[eclass].iter().any(|enode| frozen.contains(enode))
+}
+
+/// The eclasses of the frozen layers, for the extraction
+pub struct FrozenGraph {
+    /// Eclasses with a frozen enode
+    classes: HashSet<Id>,
+    /// The frozen enodes, with canonical children
+    original: HashSet<Mdl>,
+}
+
+impl FrozenGraph {
+    /// Finds the frozen layers in an EGraph, e.g. after saturation
+    pub fn new(egraph: &EGraph<Mdl, TensorAnalysis>) -> Self {
+        let original: HashSet<Mdl> = egraph
+            .analysis
+            .frozen_nodes
+            .iter()
+            .map(|enode| enode.clone().map_children(|id| egraph.find(id)))
+            .collect();
+        let classes = original
+            .iter()
+            .filter_map(|enode| egraph.lookup(&mut enode.clone()))
+            .map(|id| egraph.find(id))
+            .collect();
+        FrozenGraph {
+            classes: classes,
+            original: original,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.original.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.original.is_empty()
+    }
+
+    /// If an enode replaces a frozen layer: it is in the eclass of one, but
+    /// not the frozen enode
+    pub fn replaces(&self, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> bool {
+        if self.original.contains(enode) || matches!(enode, Mdl::Num(_) | Mdl::Var(_)) {
+            return false;
+        }
+        match egraph.lookup(&mut enode.clone()) {
+            Some(id) => self.classes.contains(&egraph.find(id)),
+            None => false,
+        }
+    }
+}
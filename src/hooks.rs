@@ -0,0 +1,76 @@
+//! Per-iteration callbacks for the runner of a saturation
+//!
+//! Library users can register callbacks to inspect the EGraph between
+//! iterations, add unions of their own, or stop the saturation, without
+//! writing a runner hook (and rebuilding the EGraph after their unions)
+//! themselves. They are called at the beginning of each iteration, in the
+//! order they were added, like the hook applying the multi-pattern rules.
+
+use crate::model::*;
+use egg::*;
+
+/// Runner used for saturation
+pub type TensatRunner = Runner<Mdl, TensorAnalysis, ()>;
+
+/// What the saturation does after a callback
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    Continue,
+    /// Stop before the iteration, with the reason (reported as
+    /// StopReason::Other)
+    Stop(String),
+}
+
+/// A callback called at the beginning of each iteration
+pub trait IterationCallback {
+    /// # Parameters
+    ///
+    /// - `iteration`: number of iterations done so far
+    /// - `egraph`: the EGraph, which can be modified, e.g. by unions
+    fn on_iteration(&mut self, iteration: usize, egraph: &mut EGraph<Mdl, TensorAnalysis>) -> Control;
+}
+
+impl<F> IterationCallback for F
+where
+    F: FnMut(usize, &mut EGraph<Mdl, TensorAnalysis>) -> Control,
+{
+    fn on_iteration(&mut self, iteration: usize, egraph: &mut EGraph<Mdl, TensorAnalysis>) -> Control {
+        self(iteration, egraph)
+    }
+}
+
+/// Callbacks to install on a runner
+#[derive(Default)]
+pub struct Callbacks {
+    callbacks: Vec<Box<dyn IterationCallback>>,
+}
+
+impl Callbacks {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a callback, called after the ones added before
+    pub fn with_callback<C: IterationCallback + 'static>(mut self, callback: C) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Installs the callbacks as a hook of the runner
+    ///
+    /// The EGraph is rebuilt after the callbacks, so that the unions they
+    /// made are seen by the searches of the iteration.
+    pub fn install(self, runner: TensatRunner) -> TensatRunner {
+        let mut callbacks = self.callbacks;
+        runner.with_hook(move |runner| {
+            let iteration = runner.iterations.len();
+            for callback in callbacks.iter_mut() {
+                if let Control::Stop(reason) = callback.on_iteration(iteration, &mut runner.egraph) {
+                    return Err(reason);
+                }
+            }
+            runner.egraph.rebuild();
+            Ok(())
+        })
+    }
+}
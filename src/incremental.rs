@@ -0,0 +1,300 @@
+//! Incremental re-optimization of a model after small edits
+//!
+//! Optimizing a model from scratch saturates the whole EGraph, which takes
+//! too long to run for every candidate in a neural architecture search. After
+//! a small edit (e.g. changing the width of one layer) most of the model is
+//! unchanged, and hash-consing maps the unchanged parts to the eclasses
+//! already saturated in the previous run. So only rules matching around the
+//! changed nodes can find anything new, and only the costs of new enodes
+//! need to be looked up.
+
+use crate::model::*;
+use crate::optimize::*;
+use crate::provenance::expr_eclasses;
+use egg::*;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// How many levels of users (downstream nodes) of a changed node rules are
+/// also matched on. A rule with a deep left hand side can match with its root
+/// a few levels above the changed node
+const AFFECTED_DEPTH: usize = 3;
+
+/// Limits for a re-optimization
+#[derive(Debug, Clone, Copy)]
+pub struct ReoptLimits {
+    /// Maximum number of iterations of rule applications
+    pub iter_limit: usize,
+    /// Stops when more than this many enodes were added to the EGraph
+    pub node_limit: usize,
+    /// Stops after this time
+    pub time_limit: Duration,
+}
+
+impl Default for ReoptLimits {
+    fn default() -> Self {
+        ReoptLimits {
+            iter_limit: 5,
+            node_limit: 50000,
+            time_limit: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Statistics of a re-optimization
+#[derive(Debug, Clone, Default)]
+pub struct ReoptStats {
+    /// Number of nodes in the model that were not in the cached EGraph
+    pub num_changed: usize,
+    /// Number of eclasses rules were matched on in the first iteration
+    pub num_affected: usize,
+    /// Number of iterations of rule applications
+    pub num_iterations: usize,
+    /// Number of enodes whose cost was not in the cost table
+    pub num_new_costs: usize,
+}
+
+/// Saturated EGraph and cost table kept between optimizations of similar models
+pub struct OptimizationCache {
+    /// EGraph containing all previously optimized models
+    pub egraph: EGraph<Mdl, TensorAnalysis>,
+    /// Cost of each (canonical) enode, as computed by the cost model
+    pub costs: HashMap<Mdl, f32>,
+    /// The last optimized model
+    pub optimized: Option<RecExpr<Mdl>>,
+}
+
+impl OptimizationCache {
+    /// Creates a cache from the EGraph of a previous optimization, e.g. the
+    /// EGraph of the runner after saturation
+    pub fn new(egraph: EGraph<Mdl, TensorAnalysis>) -> Self {
+        OptimizationCache {
+            egraph: egraph,
+            costs: HashMap::new(),
+            optimized: None,
+        }
+    }
+
+    /// Re-optimizes a model that differs slightly from the previously optimized ones
+    ///
+    /// The model is added to the cached EGraph, then the rules are only
+    /// matched on the eclasses of changed nodes and the eclasses created by
+    /// applying rules, instead of on the whole EGraph. The optimized model is
+    /// extracted greedily, looking up costs in the cost table first.
+    ///
+    /// # Parameters
+    ///
+    /// - `model`: the edited model
+    /// - `rules`: single pattern rewrite rules, e.g. from rules_from_str
+    /// - `cost_model`: cost model for the enodes not in the cost table
+    /// - `limits`: limits for the rule applications
+    ///
+    /// # Returns
+    ///
+    /// The optimized model, its cost, and statistics of the re-optimization
+    pub fn reoptimize(
+        &mut self,
+        model: &RecExpr<Mdl>,
+        rules: &[Rewrite<Mdl, TensorAnalysis>],
+        cost_model: &CostModel,
+        limits: ReoptLimits,
+    ) -> (RecExpr<Mdl>, f32, ReoptStats) {
+        let start_time = Instant::now();
+        let start_size = self.egraph.total_size();
+        let mut stats = ReoptStats::default();
+
+        // Nodes already in the EGraph are unchanged, the others and the nodes
+        // using them (up to AFFECTED_DEPTH) are where new matches can be found
+        let known = expr_eclasses(&self.egraph, model);
+        stats.num_changed = known.iter().filter(|id| id.is_none()).count();
+        let nodes = model.as_ref();
+        let mut depth: Vec<Option<usize>> = vec![None; nodes.len()];
+        for (i, node) in nodes.iter().enumerate() {
+            if known[i].is_none() {
+                depth[i] = Some(0);
+            } else {
+                let from_children = node.fold(None, |d: Option<usize>, child| {
+                    match (d, depth[usize::from(child)]) {
+                        (Some(a), Some(b)) => Some(a.min(b + 1)),
+                        (None, Some(b)) => Some(b + 1),
+                        (d, None) => d,
+                    }
+                });
+                depth[i] = from_children.filter(|d| *d <= AFFECTED_DEPTH);
+            }
+        }
+
+        let root = self.egraph.add_expr(model);
+        self.egraph.rebuild();
+        let ids = expr_eclasses(&self.egraph, model);
+        let mut affected: HashSet<Id> = ids
+            .iter()
+            .zip(depth.iter())
+            .filter(|(_, d)| d.is_some())
+            .filter_map(|(id, _)| *id)
+            .collect();
+        stats.num_affected = affected.len();
+
+        while !affected.is_empty()
+            && stats.num_iterations < limits.iter_limit
+            && self.egraph.total_size() < start_size + limits.node_limit
+            && start_time.elapsed() < limits.time_limit
+        {
+            stats.num_iterations += 1;
+            let num_ids = new_id_bound(&self.egraph);
+
+            let mut all_matches = vec![];
+            for rule in rules {
+                let matches: Vec<SearchMatches<Mdl>> = affected
+                    .iter()
+                    .filter_map(|id| rule.searcher.search_eclass(&self.egraph, self.egraph.find(*id)))
+                    .collect();
+                all_matches.push(matches);
+            }
+            let mut changed = vec![];
+            for (rule, matches) in rules.iter().zip(all_matches.iter()) {
+                changed.extend(rule.apply(&mut self.egraph, matches));
+            }
+            self.egraph.rebuild();
+
+            // Rules are matched again on the eclasses created in this iteration,
+            // and on the eclasses that got new enodes
+            affected = self
+                .egraph
+                .classes()
+                .filter(|class| usize::from(class.id) >= num_ids)
+                .map(|class| class.id)
+                .chain(changed.iter().map(|id| self.egraph.find(*id)))
+                .collect();
+        }
+
+        // Extract, with the costs of known enodes from the cost table
+        let num_costs_before = self.costs.len();
+        let cached_cost = CachedCost {
+            egraph: &self.egraph,
+            cost_model: cost_model,
+            costs: &mut self.costs,
+        };
+        let (best_cost, best) = Extractor::new(&self.egraph, cached_cost).find_best(root);
+        stats.num_new_costs = self.costs.len() - num_costs_before;
+        self.optimized = Some(best.clone());
+        (best, best_cost, stats)
+    }
+}
+
+/// Result of optimizing one candidate of a population
+#[derive(Debug, Clone)]
+pub struct CandidateResult {
+    /// The optimized graph
+    pub optimized: RecExpr<Mdl>,
+    /// Cost of the candidate as given
+    pub original_cost: f32,
+    /// Cost of the optimized graph
+    pub optimized_cost: f32,
+    /// Statistics of the optimization
+    pub stats: ReoptStats,
+}
+
+impl OptimizationCache {
+    /// Gets the cost of a graph in the EGraph, looking up the costs of its
+    /// nodes in the cost table first
+    ///
+    /// # Returns
+    ///
+    /// The cost, or None if a node of expr is not in the EGraph
+    pub fn expr_cost(&mut self, expr: &RecExpr<Mdl>, cost_model: &CostModel) -> Option<f32> {
+        let ids = expr_eclasses(&self.egraph, expr);
+        let mut total = 0.0;
+        for (node, id) in expr.as_ref().iter().zip(ids.iter()) {
+            if id.is_none() {
+                return None;
+            }
+            let key = node.clone().map_children(|child| ids[usize::from(child)].unwrap());
+            let egraph = &self.egraph;
+            let cost = *self
+                .costs
+                .entry(key.clone())
+                .or_insert_with(|| cost_model.get_self_cost(egraph, &key));
+            total += cost;
+        }
+        Some(total)
+    }
+}
+
+/// Optimizes a population of structurally similar graphs, e.g. the candidates
+/// of a neural architecture search
+///
+/// All candidates share one EGraph and cost table. The first candidate is
+/// saturated within the limits, the later ones only where they differ from
+/// the candidates before them (see OptimizationCache::reoptimize), so both
+/// rule matching and cost measurements of shared parts are done once.
+///
+/// # Parameters
+///
+/// - `candidates`: the graphs to optimize
+/// - `rules`: single pattern rewrite rules, e.g. from rules_from_str
+/// - `cost_model`: cost model for the enodes
+/// - `limits`: limits for optimizing each candidate
+///
+/// # Returns
+///
+/// The result of each candidate, in the same order
+pub fn optimize_population(
+    candidates: &[RecExpr<Mdl>],
+    rules: &[Rewrite<Mdl, TensorAnalysis>],
+    cost_model: &CostModel,
+    limits: ReoptLimits,
+) -> Vec<CandidateResult> {
+    let mut cache = OptimizationCache::new(EGraph::new(TensorAnalysis::default()));
+    candidates
+        .iter()
+        .map(|candidate| {
+            let (optimized, optimized_cost, stats) = cache.reoptimize(candidate, rules, cost_model, limits);
+            let original_cost = cache.expr_cost(candidate, cost_model).unwrap();
+            CandidateResult {
+                optimized: optimized,
+                original_cost: original_cost,
+                optimized_cost: optimized_cost,
+                stats: stats,
+            }
+        })
+        .collect()
+}
+
+/// Smallest Id an eclass created from now on can have
+///
+/// egg hands out Ids in increasing order, one for each added enode, so the
+/// largest Id currently in use bounds the new ones.
+fn new_id_bound(egraph: &EGraph<Mdl, TensorAnalysis>) -> usize {
+    egraph
+        .classes()
+        .map(|class| usize::from(class.id) + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Greedy cost function reusing the costs of enodes from a cost table
+struct CachedCost<'a> {
+    egraph: &'a EGraph<Mdl, TensorAnalysis>,
+    cost_model: &'a CostModel,
+    costs: &'a mut HashMap<Mdl, f32>,
+}
+
+impl CostFunction<Mdl> for CachedCost<'_> {
+    type Cost = f32;
+    fn cost<C: FnMut(Id) -> Self::Cost>(&mut self, enode: &Mdl, mut costs: C, eclass_id: Option<Id>) -> Self::Cost {
+        let key = enode.clone().map_children(|id| self.egraph.find(id));
+        let self_cost = match self.costs.get(&key) {
+            Some(cost) => *cost,
+            None => {
+                let cost = self.cost_model.get_self_cost(self.egraph, enode);
+                self.costs.insert(key, cost);
+                cost
+            }
+        };
+        if self_cost >= INFEASIBLE_COST {
+            return std::f32::INFINITY;
+        }
+        enode.fold(self_cost, |sum, id| sum + costs(id))
+    }
+}
@@ -1,3 +1,4 @@
+use crate::custom::{custom_label, custom_op};
 use crate::model::*;
 use egg::*;
 use itertools::Itertools;
@@ -15,6 +16,8 @@ pub struct GraphConverter {
     rec_expr: RecExpr<Mdl>,
     scalar_map: HashMap<i32, Id>,
     name_gen: NameGen,
+    /// Names of the layers (ops) in the model, keyed by their Id into the RecExpr
+    layer_names: HashMap<Id, String>,
 }
 
 /// Struct for storing information of a tensor. This is passed between functions
@@ -37,6 +40,18 @@ impl GraphConverter {
         self.rec_expr
     }
 
+    /// Gets the RecExpr after graph is constructed, together with the layer names
+    pub fn rec_expr_with_names(self) -> (RecExpr<Mdl>, HashMap<Id, String>) {
+        (self.rec_expr, self.layer_names)
+    }
+
+    /// Gives the op producing tensor t a name, used to report provenance of the
+    /// optimized graph. Ops not named here get a default name, see
+    /// provenance::default_layer_names
+    pub fn name_layer(&mut self, t: &TensorInfo, name: &str) {
+        self.layer_names.insert(t.id, name.to_string());
+    }
+
     /// Takes in the parameters for the new input, construct the node in RexExpr,
     /// return the Id (index) of this input node in the RecExpr. This is the
     /// pattern for all these op functions.
@@ -68,6 +83,52 @@ impl GraphConverter {
         }
     }
 
+    /// Adds an input keeping the name it has in an imported model. Characters
+    /// that can not be in the name of a tensor are replaced by '_'
+    pub fn new_named_input(&mut self, name: &str, dims: &[i32]) -> TensorInfo {
+        let name = sanitize_name(name) + "@" + &dims.iter().join("_");
+        let name_id = self.rec_expr.add(Mdl::Var(Symbol::from(name)));
+
+        let new_node = Mdl::Input([name_id]);
+        let (shape, n_dim) = self.shape_from_dim(dims);
+        TensorInfo {
+            id: self.rec_expr.add(new_node),
+            shape,
+            n_dim,
+        }
+    }
+
+    /// Adds a weight keeping the name it has in an imported model, see
+    /// new_named_input
+    pub fn new_named_weight(&mut self, name: &str, dims: &[i32]) -> TensorInfo {
+        let name = sanitize_name(name) + "@" + &dims.iter().join("_");
+        let name_id = self.rec_expr.add(Mdl::Var(Symbol::from(name)));
+
+        let new_node = Mdl::Weight([name_id]);
+        let (shape, n_dim) = self.shape_from_dim(dims);
+        TensorInfo {
+            id: self.rec_expr.add(new_node),
+            shape,
+            n_dim,
+        }
+    }
+
+    /// Adds a pruned weight, with the given fraction of non-zero entries
+    pub fn new_pruned_weight(&mut self, dims: &[i32], density: f32) -> TensorInfo {
+        assert!(density > 0.0 && density <= 1.0);
+        let name = self.name_gen.new_weight_name() + "@" + &dims.iter().join("_") + "@" + &density.to_string();
+        let node = Mdl::Var(Symbol::from(name));
+        let name_id = self.rec_expr.add(node);
+
+        let new_node = Mdl::Weight([name_id]);
+        let (shape, n_dim) = self.shape_from_dim(dims);
+        TensorInfo {
+            id: self.rec_expr.add(new_node),
+            shape,
+            n_dim,
+        }
+    }
+
     pub fn conv2d(
         &mut self,
         inpt: TensorInfo,
@@ -219,9 +280,8 @@ impl GraphConverter {
 
     pub fn concat_multi(&mut self, axis: i32, inputs: &[TensorInfo]) -> TensorInfo {
         let n_inputs = inputs.len();
-        // We can add supports for other number of inputs later when needed.
-        // We need to add a new Concat op for each number of inputs
-        assert!(n_inputs <= 5);
+        // Concats of more than 5 inputs use the variadic concat_n, the fixed
+        // ones are kept for the rules from TASO
 
         let n_dim = inputs[0].n_dim;
         let axis_id = self.add_or_get_val(axis);
@@ -266,6 +326,11 @@ impl GraphConverter {
                    inputs[4].id,
                 ])
             }
+            n if n > 5 => {
+                let mut children = vec![axis_id, ndim_id];
+                children.extend(inputs.iter().map(|t| t.id));
+                Mdl::ConcatN(children)
+            }
             _ => panic!("Number of input for concat not supported"),
         };
 
@@ -414,6 +479,34 @@ impl GraphConverter {
         (out_0, out_1)
     }
 
+    /// Adds a matmul followed by adding bias and the activation (see
+    /// Mdl::Dense)
+    pub fn dense(&mut self, inpt_1: TensorInfo, inpt_2: TensorInfo, bias: TensorInfo, activation: i32) -> TensorInfo {
+        let act_id = self.add_or_get_val(activation);
+
+        let new_node = Mdl::Dense([act_id, inpt_1.id, inpt_2.id, bias.id]);
+
+        let mut shape = inpt_1.shape;
+        let n_dim = inpt_1.n_dim;
+        shape[n_dim - 1] = inpt_2.shape[n_dim - 1];
+
+        TensorInfo {
+            id: self.rec_expr.add(new_node),
+            shape,
+            n_dim,
+        }
+    }
+
+    pub fn softmax(&mut self, inpt: TensorInfo, axis: i32) -> TensorInfo {
+        let axis_id = self.add_or_get_val(axis);
+
+        let new_node = Mdl::Softmax([inpt.id, axis_id]);
+        TensorInfo {
+            id: self.rec_expr.add(new_node),
+            ..inpt
+        }
+    }
+
     pub fn reshape(&mut self, inpt: TensorInfo, shape: &[i32]) -> TensorInfo {
         let shape_name = &shape.iter().join("_");
         let node = Mdl::Var(Symbol::from(shape_name));
@@ -459,6 +552,50 @@ impl GraphConverter {
         }
     }
 
+    /// Adds an op that is not supported, kept as it is (see Mdl::Opaque)
+    ///
+    /// # Parameters
+    ///
+    /// - `label`: identifies the op, e.g. its type and id in the imported model
+    /// - `inputs`: one or two inputs of the op
+    /// - `dims`: the output shape
+    pub fn opaque(&mut self, label: &str, inputs: &[TensorInfo], dims: &[i32]) -> TensorInfo {
+        assert!(inputs.len() == 1 || inputs.len() == 2);
+        let name = format!("{}@{}", label, dims.iter().join("_"));
+        let name_id = self.rec_expr.add(Mdl::Var(Symbol::from(name)));
+        let new_node = Mdl::Opaque([name_id, inputs[0].id, inputs[inputs.len() - 1].id]);
+        let (shape, n_dim) = self.shape_from_dim(dims);
+        TensorInfo {
+            id: self.rec_expr.add(new_node),
+            shape,
+            n_dim,
+        }
+    }
+
+    /// Adds an op registered at runtime (see custom.rs), whose output shape
+    /// comes from its shape function
+    ///
+    /// # Parameters
+    ///
+    /// - `op`: name of the registered op
+    /// - `attrs`: its integer attributes
+    /// - `inputs`: its inputs, as many as its arity
+    pub fn custom(&mut self, op: &str, attrs: &[i32], inputs: &[TensorInfo]) -> TensorInfo {
+        let custom = custom_op(op).unwrap_or_else(|| panic!("custom op {} is not registered", op));
+        assert!(inputs.len() == custom.arity);
+        let input_dims: Vec<Vec<i32>> = inputs.iter().map(|t| t.shape[..t.n_dim].to_vec()).collect();
+        let dims = (custom.shape)(&input_dims, attrs).unwrap_or_else(|e| panic!("custom op {}: {}", op, e));
+        let label_id = self.rec_expr.add(Mdl::Var(Symbol::from(custom_label(op, attrs))));
+        let mut children = vec![label_id];
+        children.extend(inputs.iter().map(|t| t.id));
+        let (shape, n_dim) = self.shape_from_dim(&dims);
+        TensorInfo {
+            id: self.rec_expr.add(Mdl::Custom(children)),
+            shape,
+            n_dim,
+        }
+    }
+
     /// If a scalar value is in the RecExpr, gets the Id. Otherwise creates one.
     fn add_or_get_val(&mut self, val: i32) -> Id {
         match self.scalar_map.get(&val) {
@@ -502,6 +639,20 @@ impl GraphConverter {
     }
 }
 
+/// Replaces the characters that can not be in the label of a tensor name
+/// (see names.rs), or in an s-expression symbol, by '_'
+pub fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '/' { c } else { '_' })
+        .collect();
+    if name.is_empty() {
+        "_".to_string()
+    } else {
+        name
+    }
+}
+
 /// Struct for generating new names for weight tensors in the model
 ///
 /// Generates names like w1, w2...
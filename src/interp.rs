@@ -0,0 +1,789 @@
+//! Reference interpreter evaluating graphs on the CPU
+//!
+//! TASO only measures the runtime of a graph, it does not give back the values
+//! it computes. This interpreter computes the values of a graph on the host, so
+//! that the outputs of the original and the optimized graph can be compared.
+//! It favours simplicity over speed, and follows the TASO semantics of the ops
+//! (NCHW layout, weights as [out_channels, in_channels/groups, h, w]).
+
+use crate::attrs::unpack_attrs;
+use crate::model::*;
+use crate::names::parse_dims;
+use crate::shape::{broadcast_shape, ElemType};
+use crate::weights::{weights_only, TRANSFORM_CACHE};
+use egg::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+
+/// Epsilon used for batchnorm, as in TASO
+const BN_EPSILON: f32 = 1e-5;
+
+/// Records where a dimension was concatenated, so that split can undo it. This
+/// mirrors TASO's SplitInfo
+#[derive(Debug, Clone, PartialEq)]
+pub enum SplitTree {
+    /// Not the result of a concat
+    Leaf,
+    /// Result of a concat, with the size of the first part and the trees of
+    /// the two parts
+    Node(usize, Box<SplitTree>, Box<SplitTree>),
+}
+
+/// A tensor with its values on the host
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostTensor {
+    /// Size of each dimension
+    pub dims: Vec<usize>,
+    /// Values in row-major order
+    pub data: Vec<f32>,
+    /// Concat history of each dimension
+    pub split: Vec<SplitTree>,
+}
+
+impl HostTensor {
+    pub fn new(dims: Vec<usize>, data: Vec<f32>) -> Self {
+        assert!(dims.iter().product::<usize>() == data.len());
+        let split = vec![SplitTree::Leaf; dims.len()];
+        HostTensor { dims, data, split }
+    }
+
+    pub fn zeros(dims: Vec<usize>) -> Self {
+        let n = dims.iter().product();
+        HostTensor::new(dims, vec![0.0; n])
+    }
+
+    /// Row-major strides of the dimensions
+    fn strides(&self) -> Vec<usize> {
+        let mut strides = vec![1; self.dims.len()];
+        for i in (0..self.dims.len().saturating_sub(1)).rev() {
+            strides[i] = strides[i + 1] * self.dims[i + 1];
+        }
+        strides
+    }
+}
+
+/// Value of a node during evaluation
+#[derive(Debug, Clone)]
+enum Value {
+    Scalar(i32),
+    Name(String),
+    Tnsr(HostTensor),
+    Tuple(HostTensor, HostTensor),
+    /// Outputs of the graph, combined by noop
+    Outputs(Vec<HostTensor>),
+}
+
+/// Evaluates a graph on the host
+///
+/// Inputs and weights get pseudo-random values determined by their names and
+/// the seed, so that two graphs sharing inputs and weights get the same values
+/// for them.
+///
+/// # Returns
+///
+/// The outputs of the graph: the tensors combined by the noop at the root, or
+/// the root tensor itself. An error if the graph uses an op the interpreter
+/// does not support, or the shapes do not fit
+pub fn evaluate(expr: &RecExpr<Mdl>, seed: u64) -> Result<Vec<HostTensor>, String> {
+    evaluate_with_inputs(expr, &HashMap::new(), seed)
+}
+
+/// Evaluates a graph on the host, with the values of some inputs and weights
+/// given
+///
+/// # Parameters
+///
+/// - `expr`: the graph
+/// - `inputs`: values of inputs and weights, by their names without the
+///   shape (`x` for `x@1_64`). Their dimensions have to be those of the name
+/// - `seed`: the other inputs and weights get pseudo-random values, as in
+///   evaluate
+///
+/// # Returns
+///
+/// The outputs of the graph, as in evaluate
+pub fn evaluate_with_inputs(expr: &RecExpr<Mdl>, inputs: &HashMap<String, HostTensor>, seed: u64) -> Result<Vec<HostTensor>, String> {
+    let nodes = expr.as_ref();
+    let weight_only = weights_only(nodes);
+    let mut values: Vec<Value> = Vec::with_capacity(nodes.len());
+    // The subexpression computing each tensor from weights that are not
+    // fed, as the key of the cache of transformed weights
+    let mut keys: Vec<Option<String>> = Vec::with_capacity(nodes.len());
+    for (i, node) in nodes.iter().enumerate() {
+        let key = match node {
+            Mdl::Num(_) | Mdl::Var(_) => Some(node.to_string()),
+            Mdl::Weight([n]) => {
+                let full_name = nodes[usize::from(*n)].to_string();
+                if inputs.contains_key(full_name.split('@').next().unwrap()) {
+                    None
+                } else {
+                    Some(format!("(weight {})", full_name))
+                }
+            }
+            _ if weight_only[i] => node
+                .children()
+                .iter()
+                .map(|c| keys[usize::from(*c)].clone())
+                .collect::<Option<Vec<String>>>()
+                .map(|args| format!("({} {})", node, args.join(" "))),
+            _ => None,
+        };
+        let transformed = match &key {
+            Some(k) if weight_only[i] && !matches!(node, Mdl::Weight(_)) => Some(k),
+            _ => None,
+        };
+        let cached = transformed.and_then(|k| TRANSFORM_CACHE.lock().unwrap().get(k, seed));
+        let value = match cached {
+            Some(t) => Value::Tnsr(t),
+            None => {
+                let value = eval_node(node, &values, inputs, seed)?;
+                if let (Some(k), Value::Tnsr(t)) = (transformed, &value) {
+                    TRANSFORM_CACHE.lock().unwrap().insert(k, seed, t);
+                }
+                value
+            }
+        };
+        values.push(value);
+        keys.push(key);
+    }
+    match values.pop() {
+        Some(Value::Tnsr(t)) => Ok(vec![t]),
+        Some(Value::Outputs(ts)) => Ok(ts),
+        Some(Value::Tuple(t_1, t_2)) => Ok(vec![t_1, t_2]),
+        _ => Err(String::from("root of the graph is not a tensor")),
+    }
+}
+
+/// Gets the largest error of the outputs b compared to a, relative to the
+/// largest absolute value of each output
+pub fn max_relative_error(a: &[HostTensor], b: &[HostTensor]) -> Result<f32, String> {
+    if a.len() != b.len() {
+        return Err(format!("{} outputs compared to {}", b.len(), a.len()));
+    }
+    let mut max_err: f32 = 0.0;
+    for (t_a, t_b) in a.iter().zip(b.iter()) {
+        if t_a.dims != t_b.dims {
+            return Err(format!("output of shape {:?} compared to {:?}", t_b.dims, t_a.dims));
+        }
+        let scale = t_a.data.iter().fold(0.0f32, |m, x| m.max(x.abs())).max(1e-12);
+        for (x, y) in t_a.data.iter().zip(t_b.data.iter()) {
+            let err = (x - y).abs() / scale;
+            if err.is_nan() {
+                return Ok(std::f32::INFINITY);
+            }
+            max_err = max_err.max(err);
+        }
+    }
+    Ok(max_err)
+}
+
+/// Gets the largest absolute difference between the outputs a and b
+pub fn max_absolute_error(a: &[HostTensor], b: &[HostTensor]) -> Result<f32, String> {
+    if a.len() != b.len() {
+        return Err(format!("{} outputs compared to {}", b.len(), a.len()));
+    }
+    let mut max_err: f32 = 0.0;
+    for (t_a, t_b) in a.iter().zip(b.iter()) {
+        if t_a.dims != t_b.dims {
+            return Err(format!("output of shape {:?} compared to {:?}", t_b.dims, t_a.dims));
+        }
+        for (x, y) in t_a.data.iter().zip(t_b.data.iter()) {
+            let err = (x - y).abs();
+            if err.is_nan() {
+                return Ok(f32::INFINITY);
+            }
+            max_err = max_err.max(err);
+        }
+    }
+    Ok(max_err)
+}
+
+/// Evaluates two graphs on the same inputs and weights and compares their
+/// outputs
+///
+/// # Returns
+///
+/// The largest absolute and relative error of the outputs of b compared to
+/// those of a, or why they could not be compared
+pub fn equivalence_errors(a: &RecExpr<Mdl>, b: &RecExpr<Mdl>, seed: u64) -> Result<(f32, f32), String> {
+    let out_a = evaluate(a, seed)?;
+    let out_b = evaluate(b, seed)?;
+    Ok((max_absolute_error(&out_a, &out_b)?, max_relative_error(&out_a, &out_b)?))
+}
+
+/// Deterministic random values in [-1, 1) for the tensor with the given name
+fn random_tensor(name: &str, dims: Vec<usize>, density: f32, seed: u64) -> HostTensor {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    let mut rng = StdRng::seed_from_u64(seed ^ hasher.finish());
+    let n = dims.iter().product();
+    let data = (0..n)
+        .map(|_| {
+            let value = rng.gen::<f32>() * 2.0 - 1.0;
+            if density < 1.0 && rng.gen::<f32>() >= density {
+                0.0
+            } else {
+                value
+            }
+        })
+        .collect();
+    HostTensor::new(dims, data)
+}
+
+fn dims_from_str(s: &str) -> Result<Vec<usize>, String> {
+    // A 0-d tensor has no dimensions
+    if s.is_empty() {
+        return Ok(vec![]);
+    }
+    // The symbolic batch dimension is the batch value
+    parse_dims(s)
+        .map_err(|_| format!("bad dimension in {}", s))?
+        .iter()
+        .map(|d| (*d).try_into().map_err(|_| format!("bad dimension in {}", s)))
+        .collect()
+}
+
+fn eval_node(node: &Mdl, values: &[Value], inputs: &HashMap<String, HostTensor>, seed: u64) -> Result<Value, String> {
+    let v = |id: &Id| &values[usize::from(*id)];
+    let tnsr = |id: &Id| match v(id) {
+        Value::Tnsr(t) => Ok(t),
+        other => Err(format!("expected a tensor, got {:?}", other)),
+    };
+    let scalar = |id: &Id| match v(id) {
+        Value::Scalar(n) => Ok(*n),
+        other => Err(format!("expected a scalar, got {:?}", other)),
+    };
+    let name = |id: &Id| match v(id) {
+        Value::Name(s) => Ok(s.clone()),
+        other => Err(format!("expected a name, got {:?}", other)),
+    };
+
+    let res = match node {
+        Mdl::Num(n) => Value::Scalar(*n),
+        Mdl::Var(s) => Value::Name(s.as_str().to_string()),
+        Mdl::Input([n]) | Mdl::Weight([n]) => {
+            let full_name = name(n)?;
+            let name_vec: Vec<&str> = full_name.split("@").collect();
+            if name_vec.len() != 2 && name_vec.len() != 3 {
+                return Err(format!("tensor name {} has no shape", full_name));
+            }
+            // Pruned weights have their density as a third part
+            let density = match name_vec.get(2) {
+                Some(d) => d.parse::<f32>().map_err(|_| format!("bad density in {}", full_name))?,
+                None => 1.0,
+            };
+            let dims = dims_from_str(name_vec[1])?;
+            match inputs.get(name_vec[0]) {
+                Some(t) if t.dims == dims => Value::Tnsr(HostTensor::new(dims, t.data.clone())),
+                Some(t) => return Err(format!("the value of {} has dimensions {:?}", full_name, t.dims)),
+                None => Value::Tnsr(random_tensor(&full_name, dims, density, seed)),
+            }
+        }
+        Mdl::Ewadd([a, b]) => Value::Tnsr(elementwise(tnsr(a)?, tnsr(b)?, |x, y| x + y)?),
+        Mdl::Ewmul([a, b]) => Value::Tnsr(elementwise(tnsr(a)?, tnsr(b)?, |x, y| x * y)?),
+        Mdl::Relu(a) => Value::Tnsr(activation(tnsr(a)?.clone(), ACTRELU)),
+        Mdl::Tanh(a) => Value::Tnsr(activation(tnsr(a)?.clone(), ACTTANH)),
+        Mdl::Sigmoid(a) => Value::Tnsr(activation(tnsr(a)?.clone(), ACTSIGMOID)),
+        // Inference only, dropout does nothing
+        Mdl::Dropout(a) => Value::Tnsr(tnsr(a)?.clone()),
+        // Only the storage layout changes, not the values
+        Mdl::Repack([_, a]) => Value::Tnsr(tnsr(a)?.clone()),
+        Mdl::Cast([elem, a]) => {
+            let code = scalar(elem)?;
+            let elem = ElemType::from_code(code).ok_or_else(|| format!("unknown element type {}", code))?;
+            Value::Tnsr(cast(tnsr(a)?.clone(), elem))
+        }
+        // The sparse variants compute the same values as the dense ops
+        Mdl::Matmul([act, a, b]) | Mdl::Smatmul([act, a, b]) => Value::Tnsr(activation(matmul(tnsr(a)?, tnsr(b)?)?, scalar(act)?)),
+        Mdl::Dense([act, a, b, bias]) => {
+            let res = elementwise(&matmul(tnsr(a)?, tnsr(b)?)?, tnsr(bias)?, |x, y| x + y)?;
+            Value::Tnsr(activation(res, scalar(act)?))
+        }
+        Mdl::Softmax([a, axis]) => Value::Tnsr(softmax(tnsr(a)?, scalar(axis)? as usize)?),
+        Mdl::Conv2d([stride_h, stride_w, pad, act, inpt, wght])
+        | Mdl::Sconv2d([stride_h, stride_w, pad, act, inpt, wght])
+        | Mdl::Conv2dBlocked([stride_h, stride_w, pad, act, inpt, wght]) => {
+            let res = conv2d(
+                tnsr(inpt)?,
+                tnsr(wght)?,
+                scalar(stride_h)? as usize,
+                scalar(stride_w)? as usize,
+                scalar(pad)?,
+            )?;
+            Value::Tnsr(activation(res, scalar(act)?))
+        }
+        Mdl::Poolmax([inpt, kernel_h, kernel_w, stride_h, stride_w, pad, act])
+        | Mdl::Poolavg([inpt, kernel_h, kernel_w, stride_h, stride_w, pad, act]) => {
+            let is_max = matches!(node, Mdl::Poolmax(_));
+            let res = pool2d(
+                tnsr(inpt)?,
+                [scalar(kernel_h)? as usize, scalar(kernel_w)? as usize],
+                [scalar(stride_h)? as usize, scalar(stride_w)? as usize],
+                scalar(pad)?,
+                is_max,
+            )?;
+            Value::Tnsr(activation(res, scalar(act)?))
+        }
+        Mdl::Conv2dPacked([attrs, inpt, wght]) | Mdl::Sconv2dPacked([attrs, inpt, wght]) => {
+            let (strides, pad, act) = unpack_attrs(scalar(attrs)?, 2);
+            let res = conv2d(tnsr(inpt)?, tnsr(wght)?, strides[0] as usize, strides[1] as usize, pad)?;
+            Value::Tnsr(activation(res, act))
+        }
+        Mdl::PoolmaxPacked([inpt, attrs]) | Mdl::PoolavgPacked([inpt, attrs]) => {
+            let (sizes, pad, act) = unpack_attrs(scalar(attrs)?, 4);
+            let sizes: Vec<usize> = sizes.iter().map(|s| *s as usize).collect();
+            let is_max = matches!(node, Mdl::PoolmaxPacked(_));
+            let res = pool2d(tnsr(inpt)?, [sizes[0], sizes[1]], [sizes[2], sizes[3]], pad, is_max)?;
+            Value::Tnsr(activation(res, act))
+        }
+        Mdl::BatchNorm([inpt, scale, bias, mean, var]) => Value::Tnsr(batchnorm(
+            tnsr(inpt)?,
+            [tnsr(scale)?, tnsr(bias)?, tnsr(mean)?, tnsr(var)?],
+        )?),
+        Mdl::Concat([axis, _ndim, a, b]) => Value::Tnsr(concat(scalar(axis)? as usize, &[tnsr(a)?, tnsr(b)?])?),
+        Mdl::Concat3([axis, _ndim, a, b, c]) => {
+            Value::Tnsr(concat(scalar(axis)? as usize, &[tnsr(a)?, tnsr(b)?, tnsr(c)?])?)
+        }
+        Mdl::Concat4([axis, _ndim, a, b, c, d]) => Value::Tnsr(concat(
+            scalar(axis)? as usize,
+            &[tnsr(a)?, tnsr(b)?, tnsr(c)?, tnsr(d)?],
+        )?),
+        Mdl::Concat5([axis, _ndim, a, b, c, d, e]) => Value::Tnsr(concat(
+            scalar(axis)? as usize,
+            &[tnsr(a)?, tnsr(b)?, tnsr(c)?, tnsr(d)?, tnsr(e)?],
+        )?),
+        Mdl::ConcatN(children) => {
+            let inputs = children[2..].iter().map(tnsr).collect::<Result<Vec<_>, _>>()?;
+            Value::Tnsr(concat(scalar(&children[0])? as usize, &inputs)?)
+        }
+        Mdl::SplitI([axis, count, index, inpt]) => {
+            let (count, index) = (scalar(count)?, scalar(index)?);
+            if count < 2 || index < 0 || index >= count {
+                return Err(format!("output {} of a split into {}", index, count));
+            }
+            // The first count - 1 parts are split off the front, the rest is
+            // the last part, as concats of more than two inputs nest to the
+            // right
+            let axis = scalar(axis)? as usize;
+            let mut rest = tnsr(inpt)?.clone();
+            for _ in 0..index {
+                rest = split(&rest, axis)?.1;
+            }
+            Value::Tnsr(if index < count - 1 { split(&rest, axis)?.0 } else { rest })
+        }
+        Mdl::Split([axis, inpt]) => {
+            let (t_1, t_2) = split(tnsr(inpt)?, scalar(axis)? as usize)?;
+            Value::Tuple(t_1, t_2)
+        }
+        Mdl::Split0(inpt) | Mdl::Split1(inpt) => match v(inpt) {
+            Value::Tuple(t_1, t_2) => {
+                Value::Tnsr(if matches!(node, Mdl::Split0(_)) { t_1.clone() } else { t_2.clone() })
+            }
+            other => return Err(format!("split output taken from {:?}", other)),
+        },
+        Mdl::Enlarge([a, b]) => Value::Tnsr(enlarge(tnsr(a)?, tnsr(b)?)?),
+        Mdl::Merge([weight, count]) => Value::Tnsr(merge_gconv(tnsr(weight)?, scalar(count)? as usize)?),
+        Mdl::Reshape([inpt, shape_name]) => {
+            let t = tnsr(inpt)?;
+            let dims = dims_from_str(&name(shape_name)?)?;
+            if dims.iter().product::<usize>() != t.data.len() {
+                return Err(format!("can not reshape {:?} to {:?}", t.dims, dims));
+            }
+            Value::Tnsr(HostTensor::new(dims, t.data.clone()))
+        }
+        Mdl::Transpose([inpt, perm_name, _shuffle]) => {
+            let perm = dims_from_str(&name(perm_name)?)?;
+            Value::Tnsr(transpose(tnsr(inpt)?, &perm)?)
+        }
+        Mdl::Noop([a, b]) => {
+            let mut outputs = vec![];
+            for id in &[a, b] {
+                match v(id) {
+                    Value::Tnsr(t) => outputs.push(t.clone()),
+                    Value::Outputs(ts) => outputs.extend(ts.iter().cloned()),
+                    other => return Err(format!("noop of {:?}", other)),
+                }
+            }
+            Value::Outputs(outputs)
+        }
+        other => return Err(format!("interpreter does not support {}", other)),
+    };
+    Ok(res)
+}
+
+/// Rounds the values to an element type, and keeps them as f32: to the
+/// nearest fp16 (ignoring its smaller range), or to the nearest integer in
+/// the range of int8
+fn cast(mut t: HostTensor, elem: ElemType) -> HostTensor {
+    let f: fn(f32) -> f32 = match elem {
+        ElemType::Float32 => return t,
+        // Rounds the 23 bit mantissa to the 10 bits of fp16
+        ElemType::Float16 => |x| f32::from_bits((x.to_bits() + 0x1000) & 0xffff_e000),
+        ElemType::Int8 => |x| x.round().max(-128.0).min(127.0),
+    };
+    for x in t.data.iter_mut() {
+        *x = f(*x);
+    }
+    t
+}
+
+fn activation(mut t: HostTensor, act: i32) -> HostTensor {
+    let f: fn(f32) -> f32 = match act {
+        ACTRELU => |x| x.max(0.0),
+        ACTTANH => |x| x.tanh(),
+        ACTSIGMOID => |x| 1.0 / (1.0 + (-x).exp()),
+        _ => return t,
+    };
+    for x in t.data.iter_mut() {
+        *x = f(*x);
+    }
+    t
+}
+
+/// Index into t of each element of the tensor of shape dims t is broadcast to
+fn broadcast_indices(t: &HostTensor, dims: &[usize]) -> Vec<usize> {
+    let offset = dims.len() - t.dims.len();
+    let strides = t.strides();
+    let n: usize = dims.iter().product();
+    (0..n)
+        .map(|flat| {
+            let (mut rest, mut index) = (flat, 0);
+            for d in (0..dims.len()).rev() {
+                let i = rest % dims[d];
+                rest /= dims[d];
+                if d >= offset && t.dims[d - offset] != 1 {
+                    index += i * strides[d - offset];
+                }
+            }
+            index
+        })
+        .collect()
+}
+
+/// Elementwise op, broadcasting the inputs as in NumPy
+fn elementwise(a: &HostTensor, b: &HostTensor, f: fn(f32, f32) -> f32) -> Result<HostTensor, String> {
+    let to_i32 = |t: &HostTensor| t.dims.iter().map(|d| *d as i32).collect::<Vec<i32>>();
+    let dims: Vec<usize> = broadcast_shape(&to_i32(a), &to_i32(b))
+        .ok_or_else(|| format!("elementwise op on shapes {:?} and {:?}", a.dims, b.dims))?
+        .iter()
+        .map(|d| *d as usize)
+        .collect();
+    let data = broadcast_indices(a, &dims)
+        .iter()
+        .zip(broadcast_indices(b, &dims).iter())
+        .map(|(i, j)| f(a.data[*i], b.data[*j]))
+        .collect();
+    let mut res = HostTensor::new(dims.clone(), data);
+    // A broadcast dimension was not concatenated
+    if a.dims == dims {
+        res.split = a.split.clone();
+    } else if b.dims == dims {
+        res.split = b.split.clone();
+    }
+    Ok(res)
+}
+
+/// Batched matrix multiplication over the last two dimensions
+fn matmul(a: &HostTensor, b: &HostTensor) -> Result<HostTensor, String> {
+    let n_dim = a.dims.len();
+    if n_dim < 2 || b.dims.len() != n_dim || a.dims[..n_dim - 2] != b.dims[..n_dim - 2] || a.dims[n_dim - 1] != b.dims[n_dim - 2] {
+        return Err(format!("matmul of shapes {:?} and {:?}", a.dims, b.dims));
+    }
+    let (m, k, n) = (a.dims[n_dim - 2], a.dims[n_dim - 1], b.dims[n_dim - 1]);
+    let batch: usize = a.dims[..n_dim - 2].iter().product();
+    let mut dims = a.dims.clone();
+    dims[n_dim - 1] = n;
+    let mut res = HostTensor::zeros(dims);
+    for bt in 0..batch {
+        let (a_off, b_off, r_off) = (bt * m * k, bt * k * n, bt * m * n);
+        for i in 0..m {
+            for p in 0..k {
+                let x = a.data[a_off + i * k + p];
+                for j in 0..n {
+                    res.data[r_off + i * n + j] += x * b.data[b_off + p * n + j];
+                }
+            }
+        }
+    }
+    res.split[n_dim - 2] = a.split[n_dim - 2].clone();
+    res.split[n_dim - 1] = b.split[n_dim - 1].clone();
+    Ok(res)
+}
+
+/// Output size and padding at the start of a convolution/pooling dimension
+fn conv_dim(input: usize, kernel: usize, stride: usize, padding: i32) -> Result<(usize, usize), String> {
+    if stride == 0 {
+        return Err(String::from("stride of 0"));
+    }
+    if padding == PSAME {
+        let output = (input + stride - 1) / stride;
+        let total_pad = ((output - 1) * stride + kernel).saturating_sub(input);
+        Ok((output, total_pad / 2))
+    } else if input >= kernel {
+        Ok(((input - kernel) / stride + 1, 0))
+    } else {
+        Err(format!("kernel {} larger than input {}", kernel, input))
+    }
+}
+
+fn conv2d(inpt: &HostTensor, wght: &HostTensor, stride_h: usize, stride_w: usize, padding: i32) -> Result<HostTensor, String> {
+    if inpt.dims.len() != 4 || wght.dims.len() != 4 || wght.dims[1] == 0 || inpt.dims[1] % wght.dims[1] != 0 {
+        return Err(format!("conv2d of shapes {:?} and {:?}", inpt.dims, wght.dims));
+    }
+    let (n, c, h, w) = (inpt.dims[0], inpt.dims[1], inpt.dims[2], inpt.dims[3]);
+    let (o, c_g, kh, kw) = (wght.dims[0], wght.dims[1], wght.dims[2], wght.dims[3]);
+    let groups = c / c_g;
+    if o % groups != 0 {
+        return Err(format!("conv2d of shapes {:?} and {:?}", inpt.dims, wght.dims));
+    }
+    let o_g = o / groups;
+    let (out_h, pad_h) = conv_dim(h, kh, stride_h, padding)?;
+    let (out_w, pad_w) = conv_dim(w, kw, stride_w, padding)?;
+    let mut res = HostTensor::zeros(vec![n, o, out_h, out_w]);
+    for b in 0..n {
+        for oc in 0..o {
+            let g = oc / o_g;
+            for oh in 0..out_h {
+                for ow in 0..out_w {
+                    let mut sum = 0.0;
+                    for ic in 0..c_g {
+                        let in_c = g * c_g + ic;
+                        for i in 0..kh {
+                            let ih = (oh * stride_h + i) as isize - pad_h as isize;
+                            if ih < 0 || ih >= h as isize {
+                                continue;
+                            }
+                            for j in 0..kw {
+                                let iw = (ow * stride_w + j) as isize - pad_w as isize;
+                                if iw < 0 || iw >= w as isize {
+                                    continue;
+                                }
+                                let x = inpt.data[((b * c + in_c) * h + ih as usize) * w + iw as usize];
+                                let y = wght.data[((oc * c_g + ic) * kh + i) * kw + j];
+                                sum += x * y;
+                            }
+                        }
+                    }
+                    res.data[((b * o + oc) * out_h + oh) * out_w + ow] = sum;
+                }
+            }
+        }
+    }
+    res.split[0] = inpt.split[0].clone();
+    res.split[1] = wght.split[0].clone();
+    Ok(res)
+}
+
+fn pool2d(inpt: &HostTensor, kernel: [usize; 2], stride: [usize; 2], padding: i32, is_max: bool) -> Result<HostTensor, String> {
+    if inpt.dims.len() != 4 {
+        return Err(format!("pool2d of shape {:?}", inpt.dims));
+    }
+    let (n, c, h, w) = (inpt.dims[0], inpt.dims[1], inpt.dims[2], inpt.dims[3]);
+    let (out_h, pad_h) = conv_dim(h, kernel[0], stride[0], padding)?;
+    let (out_w, pad_w) = conv_dim(w, kernel[1], stride[1], padding)?;
+    let mut res = HostTensor::zeros(vec![n, c, out_h, out_w]);
+    for bc in 0..n * c {
+        for oh in 0..out_h {
+            for ow in 0..out_w {
+                let mut acc = if is_max { std::f32::MIN } else { 0.0 };
+                for i in 0..kernel[0] {
+                    let ih = (oh * stride[0] + i) as isize - pad_h as isize;
+                    for j in 0..kernel[1] {
+                        let iw = (ow * stride[1] + j) as isize - pad_w as isize;
+                        // Padding counts as zeros for the average
+                        let x = if ih < 0 || ih >= h as isize || iw < 0 || iw >= w as isize {
+                            if is_max {
+                                continue;
+                            }
+                            0.0
+                        } else {
+                            inpt.data[(bc * h + ih as usize) * w + iw as usize]
+                        };
+                        acc = if is_max { acc.max(x) } else { acc + x };
+                    }
+                }
+                if !is_max {
+                    acc /= (kernel[0] * kernel[1]) as f32;
+                }
+                res.data[(bc * out_h + oh) * out_w + ow] = acc;
+            }
+        }
+    }
+    res.split[1] = inpt.split[1].clone();
+    Ok(res)
+}
+
+fn batchnorm(inpt: &HostTensor, params: [&HostTensor; 4]) -> Result<HostTensor, String> {
+    if inpt.dims.len() < 2 || params.iter().any(|p| p.data.len() != inpt.dims[1]) {
+        return Err(format!("batchnorm of shape {:?}", inpt.dims));
+    }
+    let [scale, bias, mean, var] = params;
+    let c = inpt.dims[1];
+    let inner: usize = inpt.dims[2..].iter().product();
+    let mut res = inpt.clone();
+    for (i, x) in res.data.iter_mut().enumerate() {
+        let ch = (i / inner) % c;
+        // Random weights can be negative, take the absolute value of the variance
+        let std = (var.data[ch].abs() + BN_EPSILON).sqrt();
+        *x = (*x - mean.data[ch]) / std * scale.data[ch] + bias.data[ch];
+    }
+    Ok(res)
+}
+
+fn softmax(t: &HostTensor, axis: usize) -> Result<HostTensor, String> {
+    if axis >= t.dims.len() {
+        return Err(format!("softmax along axis {} of shape {:?}", axis, t.dims));
+    }
+    let n = t.dims[axis];
+    let inner: usize = t.dims[axis + 1..].iter().product();
+    let outer: usize = t.dims[..axis].iter().product();
+    let mut res = t.clone();
+    for o in 0..outer {
+        for i in 0..inner {
+            let index = |k: usize| (o * n + k) * inner + i;
+            // Subtract the max for stability
+            let max = (0..n).map(|k| t.data[index(k)]).fold(f32::NEG_INFINITY, f32::max);
+            let sum: f32 = (0..n).map(|k| (t.data[index(k)] - max).exp()).sum();
+            for k in 0..n {
+                res.data[index(k)] = (t.data[index(k)] - max).exp() / sum;
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn concat(axis: usize, inputs: &[&HostTensor]) -> Result<HostTensor, String> {
+    let first = inputs[0];
+    for t in inputs {
+        let same_rest = t.dims.len() == first.dims.len()
+            && axis < t.dims.len()
+            && (0..t.dims.len()).all(|i| i == axis || t.dims[i] == first.dims[i]);
+        if !same_rest {
+            return Err(format!("concat along axis {} of shapes {:?} and {:?}", axis, first.dims, t.dims));
+        }
+    }
+    let outer: usize = first.dims[..axis].iter().product();
+    let inner: usize = first.dims[axis + 1..].iter().product();
+    let mut dims = first.dims.clone();
+    dims[axis] = inputs.iter().map(|t| t.dims[axis]).sum();
+    let mut data = Vec::with_capacity(dims.iter().product());
+    for o in 0..outer {
+        for t in inputs {
+            let chunk = t.dims[axis] * inner;
+            data.extend_from_slice(&t.data[o * chunk..(o + 1) * chunk]);
+        }
+    }
+    let mut res = HostTensor::new(dims, data);
+    // Concats of more than two inputs nest to the right, as in TASO
+    let mut tree = inputs[inputs.len() - 1].split[axis].clone();
+    for t in inputs[..inputs.len() - 1].iter().rev() {
+        tree = SplitTree::Node(t.dims[axis], Box::new(t.split[axis].clone()), Box::new(tree));
+    }
+    res.split[axis] = tree;
+    for i in 0..res.dims.len() {
+        if i != axis {
+            res.split[i] = first.split[i].clone();
+        }
+    }
+    Ok(res)
+}
+
+/// Splits t along axis where it was last concatenated
+fn split(t: &HostTensor, axis: usize) -> Result<(HostTensor, HostTensor), String> {
+    if axis >= t.dims.len() {
+        return Err(format!("split along axis {} of shape {:?}", axis, t.dims));
+    }
+    let (pos, left, right) = match &t.split[axis] {
+        SplitTree::Node(pos, left, right) => (*pos, left.as_ref().clone(), right.as_ref().clone()),
+        SplitTree::Leaf => return Err(format!("split of a tensor not concatenated along axis {}", axis)),
+    };
+    let outer: usize = t.dims[..axis].iter().product();
+    let inner: usize = t.dims[axis + 1..].iter().product();
+    let chunk = t.dims[axis] * inner;
+    let mut data_1 = vec![];
+    let mut data_2 = vec![];
+    for o in 0..outer {
+        data_1.extend_from_slice(&t.data[o * chunk..o * chunk + pos * inner]);
+        data_2.extend_from_slice(&t.data[o * chunk + pos * inner..(o + 1) * chunk]);
+    }
+    let mut dims_1 = t.dims.clone();
+    dims_1[axis] = pos;
+    let mut dims_2 = t.dims.clone();
+    dims_2[axis] = t.dims[axis] - pos;
+    let mut t_1 = HostTensor::new(dims_1, data_1);
+    let mut t_2 = HostTensor::new(dims_2, data_2);
+    t_1.split = t.split.clone();
+    t_2.split = t.split.clone();
+    t_1.split[axis] = left;
+    t_2.split[axis] = right;
+    Ok((t_1, t_2))
+}
+
+/// Pads the kernel of the weight a with zeros (centered) to the kernel size of b
+fn enlarge(a: &HostTensor, b: &HostTensor) -> Result<HostTensor, String> {
+    if a.dims.len() != 4 || b.dims.len() != 4 || a.dims[2] > b.dims[2] || a.dims[3] > b.dims[3] {
+        return Err(format!("enlarge of shapes {:?} and {:?}", a.dims, b.dims));
+    }
+    let (o, c, kh, kw) = (a.dims[0], a.dims[1], a.dims[2], a.dims[3]);
+    let (nh, nw) = (b.dims[2], b.dims[3]);
+    let (off_h, off_w) = ((nh - kh) / 2, (nw - kw) / 2);
+    let mut res = HostTensor::zeros(vec![o, c, nh, nw]);
+    for oc in 0..o * c {
+        for i in 0..kh {
+            for j in 0..kw {
+                res.data[(oc * nh + i + off_h) * nw + j + off_w] = a.data[(oc * kh + i) * kw + j];
+            }
+        }
+    }
+    res.split[0] = a.split[0].clone();
+    Ok(res)
+}
+
+/// Merges the weight of a grouped convolution so that count groups become one,
+/// filling in zeros for the input channels of the other groups. Same layout as
+/// the merge_gconv kernel in TASO
+fn merge_gconv(w: &HostTensor, count: usize) -> Result<HostTensor, String> {
+    if w.dims.len() != 4 || count == 0 || w.dims[0] % count != 0 {
+        return Err(format!("merge_gconv of shape {:?} with count {}", w.dims, count));
+    }
+    let (o, c, kh, kw) = (w.dims[0], w.dims[1], w.dims[2], w.dims[3]);
+    let c_hw = c * kh * kw;
+    let mut res = HostTensor::zeros(vec![o, c * count, kh, kw]);
+    for i in 0..w.data.len() {
+        let (oc, rest) = (i / c_hw, i % c_hw);
+        let dst = oc * c_hw * count + oc / (o / count) * c_hw + rest;
+        res.data[dst] = w.data[i];
+    }
+    Ok(res)
+}
+
+fn transpose(t: &HostTensor, perm: &[usize]) -> Result<HostTensor, String> {
+    let n_dim = t.dims.len();
+    let mut sorted = perm.to_vec();
+    sorted.sort();
+    if sorted != (0..n_dim).collect::<Vec<usize>>() {
+        return Err(format!("transpose of shape {:?} by {:?}", t.dims, perm));
+    }
+    let dims: Vec<usize> = perm.iter().map(|p| t.dims[*p]).collect();
+    let strides = t.strides();
+    let mut res = HostTensor::zeros(dims.clone());
+    let mut index = vec![0; n_dim];
+    for i in 0..res.data.len() {
+        let src: usize = (0..n_dim).map(|d| index[d] * strides[perm[d]]).sum();
+        res.data[i] = t.data[src];
+        // Advance the index into the output
+        for d in (0..n_dim).rev() {
+            index[d] += 1;
+            if index[d] < dims[d] {
+                break;
+            }
+            index[d] = 0;
+        }
+    }
+    res.split = perm.iter().map(|p| t.split[*p].clone()).collect();
+    Ok(res)
+}
@@ -0,0 +1,84 @@
+//! Optimizing several models that share subgraphs together
+//!
+//! Models served together often share a backbone. Putting them into one
+//! graph, with their outputs combined by noops, unifies the nodes they have
+//! in common (the same ops on the same tensors, so the shared weights have to
+//! have the same names), and the shared part is then optimized once, and the
+//! same way for every model.
+
+use crate::model::*;
+use crate::partition::region_outputs;
+use egg::*;
+use std::collections::HashMap;
+
+/// Combines models into one graph, sharing their common nodes
+///
+/// # Returns
+///
+/// The graph, with the outputs of the models combined by noops (the first two
+/// in an inner noop), and the number of nodes shared with an earlier model
+pub fn combine_models(models: &[RecExpr<Mdl>]) -> (RecExpr<Mdl>, usize) {
+    assert!(!models.is_empty());
+    let mut combined = RecExpr::default();
+    let mut hashcons: HashMap<Mdl, Id> = HashMap::new();
+    let mut num_shared = 0;
+    let mut roots = vec![];
+    for model in models.iter() {
+        let mut ids: Vec<Id> = Vec::with_capacity(model.as_ref().len());
+        for node in model.as_ref().iter() {
+            let node = node.clone().map_children(|child| ids[usize::from(child)]);
+            let id = match hashcons.get(&node) {
+                Some(id) => {
+                    num_shared += 1;
+                    *id
+                }
+                None => {
+                    let id = combined.add(node.clone());
+                    hashcons.insert(node, id);
+                    id
+                }
+            };
+            ids.push(id);
+        }
+        roots.push(*ids.last().unwrap());
+    }
+    let mut out = roots[0];
+    for root in roots[1..].iter() {
+        out = combined.add(Mdl::Noop([out, *root]));
+    }
+    (combined, num_shared)
+}
+
+/// Copies the nodes of a graph reachable from root
+fn subgraph(expr: &RecExpr<Mdl>, root: usize) -> RecExpr<Mdl> {
+    let nodes = expr.as_ref();
+    let mut reachable = vec![false; nodes.len()];
+    reachable[root] = true;
+    for i in (0..=root).rev() {
+        if reachable[i] {
+            for child in nodes[i].children() {
+                reachable[usize::from(*child)] = true;
+            }
+        }
+    }
+    let mut sub = RecExpr::default();
+    let mut ids: HashMap<usize, Id> = HashMap::new();
+    for i in 0..=root {
+        if reachable[i] {
+            let node = nodes[i].clone().map_children(|child| ids[&usize::from(child)]);
+            ids.insert(i, sub.add(node));
+        }
+    }
+    sub
+}
+
+/// Splits an optimized combined graph back into the models
+///
+/// # Returns
+///
+/// The optimized graph of each model, or None if the noops combining the
+/// outputs were rewritten
+pub fn split_models(expr: &RecExpr<Mdl>, num_models: usize) -> Option<Vec<RecExpr<Mdl>>> {
+    let outputs = region_outputs(expr, num_models)?;
+    Some(outputs.iter().map(|root| subgraph(expr, *root)).collect())
+}
@@ -13,35 +13,72 @@ pub mod mobilenetv2;
 pub mod vgg;
 pub mod squeezenet;
 pub mod utils;
-
-pub mod verify {
-    use crate::model::*;
-    use crate::rewrites::*;
-    use egg::*;
-
-    type ExprPair = (RecExpr<Mdl>, RecExpr<Mdl>);
-    // returns failed pairs
-    pub fn verify(pairs: &[ExprPair]) -> Vec<ExprPair> {
-        let mut runner = Runner::<Mdl, (), ()>::default();
-        for (l, r) in pairs {
-            runner = runner.with_expr(l).with_expr(r);
-        }
-
-        println!("Running...");
-        let runner = runner.run(&rules());
-        println!("Runner complete!");
-        println!("  Nodes: {}", runner.egraph.total_size());
-        println!("  Classes: {}", runner.egraph.number_of_classes());
-        println!("  Stopped: {:?}", runner.stop_reason.unwrap());
-
-        let mut failed = vec![];
-        for (i, roots) in runner.roots.chunks(2).enumerate() {
-            let eg = &runner.egraph;
-            if eg.find(roots[0]) != eg.find(roots[1]) {
-                failed.push(pairs[i].clone());
-            }
-        }
-
-        failed
-    }
-}
+pub mod ffi;
+pub mod shape;
+pub mod provenance;
+pub mod interp;
+pub mod text;
+pub mod validate;
+pub mod incremental;
+pub mod region;
+pub mod power;
+pub mod attrs;
+pub mod library;
+pub mod partition;
+pub mod corpus;
+pub mod budget;
+pub mod snapshot;
+pub mod names;
+pub mod weights;
+pub mod replay;
+pub mod joint;
+pub mod hooks;
+pub mod custom;
+pub mod onnx;
+pub mod ensemble;
+pub mod specialize;
+pub mod cost;
+pub mod bundle;
+pub mod baseline;
+pub mod extract;
+pub mod priors;
+pub mod checkpoint;
+pub mod live;
+pub mod whatif;
+pub mod verify;
+pub mod subprocess;
+pub mod rulefile;
+pub mod stdio;
+pub mod blocks;
+pub mod stats;
+pub mod builder;
+pub mod dot;
+pub mod assertions;
+pub mod optimizer;
+pub mod schedule;
+pub mod device;
+pub mod values;
+pub mod microbench;
+pub mod progress;
+pub mod ort;
+pub mod resources;
+pub mod reduce;
+pub mod costcheck;
+pub mod targets;
+pub mod stages;
+pub mod attribution;
+pub mod rulecache;
+pub mod tied;
+pub mod strategies;
+pub mod prune;
+pub mod watchdog;
+pub mod execute;
+pub mod flatbuf;
+pub mod freeze;
+pub mod capped;
+pub mod selfcheck;
+pub mod suggest;
+pub mod rulesets;
+pub mod reports;
+#[cfg(feature = "python")]
+pub mod python;
@@ -0,0 +1,113 @@
+//! Library of the best graphs found for each model across runs
+//!
+//! A run only finds what its node and time limits allow, and different runs
+//! (with other rules, limits or seeds) find different graphs. The library
+//! keeps the best graph found so far for each model and target on disk, as
+//! `<dir>/<model hash>/<target>.json`. Unioning it into the starting EGraph
+//! of a new run means the extracted graph can only get better.
+
+use crate::model::*;
+use egg::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// Best graph found for one model and target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    /// The graph, as an s-expression
+    pub graph: String,
+    /// Cost of the graph when it was found
+    pub cost: f32,
+    /// Number of runs that recorded a result for this model and target
+    pub num_runs: usize,
+}
+
+/// Hashes a model, stable across runs and builds
+///
+/// # Returns
+///
+/// The FNV-1a hash of the s-expression of the model, as 16 hex digits
+pub fn model_hash(expr: &RecExpr<Mdl>) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in expr.to_string().bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Best graphs found across runs, stored in a directory
+pub struct GraphLibrary {
+    dir: PathBuf,
+}
+
+impl GraphLibrary {
+    /// Opens the library in dir, creating the directory if needed
+    pub fn open(dir: &str) -> Self {
+        fs::create_dir_all(dir).expect("Unable to create the library directory");
+        GraphLibrary {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    fn path(&self, hash: &str, target: &str) -> PathBuf {
+        let target: String = target
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        self.dir.join(hash).join(format!("{}.json", target))
+    }
+
+    /// Gets the entry of a model and target, if any run recorded one
+    pub fn get(&self, hash: &str, target: &str) -> Option<LibraryEntry> {
+        let s = fs::read_to_string(self.path(hash, target)).ok()?;
+        match serde_json::from_str(&s) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                println!("Ignoring unreadable library entry for {}: {}", hash, e);
+                None
+            }
+        }
+    }
+
+    /// Gets the best graph of a model and target, if any run recorded one
+    pub fn best_graph(&self, hash: &str, target: &str) -> Option<RecExpr<Mdl>> {
+        self.get(hash, target).and_then(|entry| entry.graph.parse().ok())
+    }
+
+    /// Records the result of a run
+    ///
+    /// The graph replaces the entry only if it is cheaper. The entry is
+    /// written to a temporary file first, so an interrupted run does not
+    /// corrupt it.
+    ///
+    /// # Returns
+    ///
+    /// If the graph is the best one recorded so far
+    pub fn record(&self, hash: &str, target: &str, graph: &RecExpr<Mdl>, cost: f32) -> bool {
+        let path = self.path(hash, target);
+        let (entry, improved) = match self.get(hash, target) {
+            Some(old) if old.cost <= cost => (
+                LibraryEntry {
+                    num_runs: old.num_runs + 1,
+                    ..old
+                },
+                false,
+            ),
+            old => (
+                LibraryEntry {
+                    graph: graph.to_string(),
+                    cost: cost,
+                    num_runs: old.map_or(0, |e| e.num_runs) + 1,
+                },
+                true,
+            ),
+        };
+        fs::create_dir_all(path.parent().unwrap()).expect("Unable to create the library directory");
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string(&entry).unwrap()).expect("Unable to write the library entry");
+        fs::rename(&tmp, &path).expect("Unable to write the library entry");
+        improved
+    }
+}
@@ -0,0 +1,175 @@
+//! A small web page showing the EGraph while it saturates
+//!
+//! With a port set, a server thread answers on 127.0.0.1: `/` is a page
+//! polling `/state` every second, `/state` is the latest LiveState as JSON.
+//! The state is updated by a runner hook before each iteration (see
+//! LiveServer::update), so the page lags by at most one iteration. The
+//! server answers one request at a time, which is enough for a page or two.
+
+use crate::model::*;
+use egg::*;
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Number of eclasses with the most enodes to show
+const NUM_HOT_CLASSES: usize = 10;
+
+/// Number of iterations to show the rule applications of
+const NUM_RECENT_ITERATIONS: usize = 5;
+
+/// What the page shows
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LiveState {
+    pub iteration: usize,
+    pub seconds: f32,
+    pub num_nodes: usize,
+    pub num_classes: usize,
+    /// Number of enodes after each iteration
+    pub nodes_by_iteration: Vec<usize>,
+    /// The eclasses with the most enodes, as (eclass Id, number of enodes,
+    /// ops of the enodes)
+    pub hot_classes: Vec<(usize, usize, Vec<String>)>,
+    /// The rules applied in the last iterations, as (iteration, rule, number
+    /// of applications), most applied first
+    pub recent_applications: Vec<(usize, String, usize)>,
+    pub done: bool,
+}
+
+/// The server and the state it shows
+pub struct LiveServer {
+    state: Arc<Mutex<LiveState>>,
+    start: Instant,
+}
+
+impl LiveServer {
+    /// Starts the server thread
+    ///
+    /// # Parameters
+    ///
+    /// - `port`: the port to listen on, on 127.0.0.1
+    pub fn start(port: u16) -> Result<Self, String> {
+        let listener =
+            TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("Could not listen on port {}: {}", port, e))?;
+        let state = Arc::new(Mutex::new(LiveState::default()));
+        let server_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = answer(stream, &server_state) {
+                    println!("Warning: live server: {}", e);
+                }
+            }
+        });
+        Ok(LiveServer {
+            state: state,
+            start: Instant::now(),
+        })
+    }
+
+    /// Updates the state from a runner. Call it before each iteration (it is
+    /// a runner hook) and after the last one
+    pub fn update(&self, runner: &Runner<Mdl, TensorAnalysis, ()>, done: bool) {
+        let egraph = &runner.egraph;
+        let mut hot_classes: Vec<(usize, usize, Vec<String>)> = egraph
+            .classes()
+            .map(|class| {
+                let mut ops: Vec<String> = class.iter().map(|node| node.to_string()).collect();
+                ops.sort();
+                ops.dedup();
+                (usize::from(class.id), class.len(), ops)
+            })
+            .collect();
+        hot_classes.sort_by(|a, b| b.1.cmp(&a.1));
+        hot_classes.truncate(NUM_HOT_CLASSES);
+
+        let first_recent = runner.iterations.len().saturating_sub(NUM_RECENT_ITERATIONS);
+        let mut recent_applications = vec![];
+        for (i, iteration) in runner.iterations.iter().enumerate().skip(first_recent) {
+            let mut applied: Vec<(usize, String, usize)> =
+                iteration.applied.iter().map(|(rule, n)| (i, rule.to_string(), *n)).collect();
+            applied.sort_by(|a, b| b.2.cmp(&a.2));
+            recent_applications.extend(applied);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.iteration = runner.iterations.len();
+        state.seconds = self.start.elapsed().as_secs_f32();
+        state.num_nodes = egraph.total_size();
+        state.num_classes = egraph.number_of_classes();
+        state.nodes_by_iteration = runner.iterations.iter().map(|it| it.egraph_nodes).collect();
+        state.hot_classes = hot_classes;
+        state.recent_applications = recent_applications;
+        state.done = done;
+    }
+}
+
+/// Answers one request
+fn answer(mut stream: TcpStream, state: &Mutex<LiveState>) -> std::io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, content_type, body) = match path {
+        "/" => ("200 OK", "text/html", PAGE.to_string()),
+        "/state" => (
+            "200 OK",
+            "application/json",
+            serde_json::to_string(&*state.lock().unwrap()).unwrap(),
+        ),
+        _ => ("404 Not Found", "text/plain", "Not found".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>tensat</title>
+<style>
+body { font-family: monospace; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+td, th { padding: 0 1em; text-align: left; }
+</style>
+</head>
+<body>
+<h2 id="summary">Waiting for the first iteration</h2>
+<p id="growth"></p>
+<h3>Eclasses with the most enodes</h3>
+<table id="hot"></table>
+<h3>Rules applied in the last iterations</h3>
+<table id="applied"></table>
+<script>
+function rows(id, header, data) {
+  let html = "<tr>" + header.map(h => "<th>" + h + "</th>").join("") + "</tr>";
+  for (const row of data) {
+    html += "<tr>" + row.map(c => "<td>" + c + "</td>").join("") + "</tr>";
+  }
+  document.getElementById(id).innerHTML = html;
+}
+async function poll() {
+  try {
+    const s = await (await fetch("/state")).json();
+    document.getElementById("summary").textContent =
+      (s.done ? "Done" : "Iteration " + s.iteration) + ": " + s.num_nodes + " enodes, " +
+      s.num_classes + " eclasses, " + s.seconds.toFixed(1) + "s";
+    document.getElementById("growth").textContent = "Enodes by iteration: " + s.nodes_by_iteration.join(", ");
+    rows("hot", ["eclass", "enodes", "ops"], s.hot_classes.map(c => [c[0], c[1], c[2].join(" ")]));
+    rows("applied", ["iteration", "rule", "applications"], s.recent_applications);
+    if (s.done) return;
+  } catch (e) {}
+  setTimeout(poll, 1000);
+}
+poll();
+</script>
+</body>
+</html>
+"#;
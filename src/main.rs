@@ -3,7 +3,7 @@
 
 use clap::{App, Arg};
 use egg::*;
-use std::collections::{HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::*;
 use std::time::{Duration, Instant};
 use tensat::bert;
@@ -18,15 +18,73 @@ use tensat::inceptionv3;
 use tensat::mobilenetv2;
 use tensat::vgg;
 use tensat::squeezenet;
+use tensat::ffi::*;
+use tensat::provenance::*;
+use tensat::interp::*;
+use tensat::shape::{expr_shapes, TensorShape};
+use tensat::text::*;
+use tensat::validate::*;
+use tensat::region::*;
+use tensat::attrs::*;
+use tensat::library::*;
+use tensat::partition::*;
+use tensat::corpus::*;
+use tensat::budget::*;
+use tensat::snapshot::*;
+use tensat::names::*;
+use tensat::weights::*;
+use tensat::replay::*;
+use tensat::joint::*;
+use tensat::custom::custom_rules;
+use tensat::onnx::*;
+use tensat::ensemble::*;
+use tensat::specialize::*;
+use tensat::cost::*;
+use tensat::bundle;
+use tensat::baseline::*;
+use tensat::extract::*;
+use tensat::priors::*;
+use tensat::checkpoint::*;
+use tensat::live::*;
+use tensat::whatif::*;
+use tensat::subprocess::*;
+use tensat::rulefile::*;
+use tensat::stdio::*;
+use tensat::blocks::*;
+use tensat::stats::*;
+use tensat::dot::*;
+use tensat::assertions::*;
+use tensat::schedule::*;
+use tensat::device::*;
+use tensat::values::*;
+use tensat::microbench::*;
+use tensat::progress::*;
+use tensat::ort::*;
+use tensat::resources::*;
+use tensat::reduce::*;
+use tensat::costcheck::*;
+use tensat::targets::*;
+use tensat::stages::*;
+use tensat::attribution::*;
+use tensat::tied::*;
+use tensat::strategies::*;
+use tensat::prune::*;
+use tensat::watchdog::*;
+use tensat::flatbuf::*;
+use tensat::freeze::*;
+use tensat::capped::*;
+use tensat::selfcheck::*;
+use tensat::suggest::*;
+use tensat::rulesets::*;
+use tensat::reports::*;
+use tensat::utils::get_full_graph_runtime;
+use tensat::optimizer::*;
+use std::sync::{Arc, Mutex};
 use tensat::{parse::*, verify::*};
 
 use serde_json::{json, Map, Value};
-use std::fs::OpenOptions;
-use std::io::prelude::*;
 use std::process::{Command};
-use std::path::Path;
-
-use std::ffi::CString;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // Parse arguments
@@ -37,7 +95,14 @@ fn main() {
                 .long("mode")
                 .takes_value(true)
                 .default_value("optimize")
-                .help("Mode to run, can be verify, optimize, test, convert"),
+                .help("Mode to run, can be verify, optimize, test, convert, estimate, extraction_test, compare_rules, what_if, microbench, reduce, validate_costs, saturate, extract, suggest"),
+        )
+        .arg(
+            Arg::with_name("corpus")
+                .long("corpus")
+                .takes_value(true)
+                .default_value("extractor/corpus")
+                .help("Directory of serialized EGraphs with known optimal costs, for mode extraction_test"),
         )
         .arg(
             Arg::with_name("model")
@@ -53,16 +118,91 @@ fn main() {
                 .takes_value(true)
                 .help("Provide a file with rewrite rules"),
         )
+        .arg(
+            Arg::with_name("rules_b")
+                .long("rules_b")
+                .takes_value(true)
+                .help("Second file with rewrite rules, compared with rules in mode compare_rules"),
+        )
+        .arg(
+            Arg::with_name("op")
+                .long("op")
+                .takes_value(true)
+                .help("Op to measure in mode microbench, as a graph whose root is the op, e.g. \"(relu (input x@1_64_56_56))\""),
+        )
+        .arg(
+            Arg::with_name("repeats")
+                .long("repeats")
+                .takes_value(true)
+                .default_value("20")
+                .help("Number of times mode microbench creates and measures the op, and mode validate_costs each sampled op"),
+        )
+        .arg(
+            Arg::with_name("sample")
+                .long("sample")
+                .takes_value(true)
+                .default_value("50")
+                .help("Number of cached runtimes of cost_cache mode validate_costs re-measures, chosen at random with seed"),
+        )
+        .arg(
+            Arg::with_name("what_if_node")
+                .long("what_if_node")
+                .takes_value(true)
+                .help("Index of the node of the input graph to apply a rule at, in mode what_if"),
+        )
+        .arg(
+            Arg::with_name("what_if_rule")
+                .long("what_if_rule")
+                .takes_value(true)
+                .help("Name of the rule to apply in mode what_if (e.g. rule12, rules are numbered from 0 in the rules file, then the pre-defined rules). If not given, every rule matching at the node is tried"),
+        )
+        .arg(
+            Arg::with_name("suggest_top")
+                .long("suggest_top")
+                .takes_value(true)
+                .default_value("20")
+                .help("Number of substitutions mode suggest prints, by decreasing gain"),
+        )
+        .arg(
+            Arg::with_name("suggest_min_gain")
+                .long("suggest_min_gain")
+                .takes_value(true)
+                .default_value("0")
+                .help("Smallest estimated gain of a substitution printed by mode suggest, in the unit of the objective"),
+        )
         .arg(
             Arg::with_name("gj")
                 .long("gj"),
         )
+        .arg(
+            Arg::with_name("reduce_command")
+                .long("reduce_command")
+                .takes_value(true)
+                .help("Command reproducing the failure in mode reduce, run with sh with {} replaced by the graph file, e.g. \"target/release/tensat -m optimize --model_file {} --check_equivalence 1e-3\". A graph fails if the command exits with an error"),
+        )
+        .arg(
+            Arg::with_name("reduce_match")
+                .long("reduce_match")
+                .takes_value(true)
+                .help("In mode reduce, a graph only fails if the output of the command also contains this text, so that the reduced graph fails the same way"),
+        )
         .arg(
             Arg::with_name("out_file")
                 // .short("o")
                 .long("out_file")
                 .takes_value(true)
-                .help("Provide a output file name. For mode convert, it's for converted rules; for mode optimize, it's for measured runtime"),
+                .help("Provide a output file name. For mode convert, it's for converted rules; for mode optimize, it's for measured runtime; for mode estimate, it's for the cost of each op; for mode microbench, it's for the latencies; for mode validate_costs, it's for the drifts"),
+        )
+        .arg(
+            Arg::with_name("stats_out")
+                .long("stats_out")
+                .takes_value(true)
+                .help("File to write the statistics of the run to as JSON, in the output directory: iterations, rule applications, time per phase, costs"),
+        )
+        .arg(
+            Arg::with_name("resource_summary")
+                .long("resource_summary")
+                .help("Whether to report the resources the run used: peak resident and GPU memory, tensors created and freed, time spent measuring on the GPU. Recorded in stats_out too"),
         )
         .arg(
             Arg::with_name("export_models")
@@ -70,12 +210,340 @@ fn main() {
                 .long("export_models")
                 .help("Whether or not to store input and optimized model"),
         )
+        .arg(
+            Arg::with_name("onnx_script")
+                .long("onnx_script")
+                .takes_value(true)
+                .requires("export_models")
+                .help("Script converting a TASO model file to ONNX (e.g. TASO/examples/load_model.py in our fork), called as `python <script> <model file> <onnx file>` on the measured optimized model"),
+        )
+        .arg(
+            Arg::with_name("onnx_provider")
+                .long("onnx_provider")
+                .takes_value(true)
+                .possible_values(&["standard", "ort"])
+                .default_value("standard")
+                .help("Execution provider the optimized ONNX model is written for (without onnx_script). With ort, ops with fused activations are written as ONNX Runtime's fused ops"),
+        )
+        .arg(
+            Arg::with_name("onnx_opset")
+                .long("onnx_opset")
+                .takes_value(true)
+                .default_value("13")
+                .help("Operator set the optimized ONNX model is written for (without onnx_script), from 9 to 17. Ops whose definition changed (split, pad, slice, softmax) are written as that version expects them"),
+        )
+        .arg(
+            Arg::with_name("profile_markers")
+                .long("profile_markers")
+                .requires("export_models")
+                .help("Whether to write profiling markers (op names and the original layers they replace) next to the exported models"),
+        )
+        .arg(
+            Arg::with_name("output_file")
+                .long("output_file")
+                .takes_value(true)
+                .help("File to write the optimized graph to, in the output directory, or - for stdout (the progress is then printed to stderr)"),
+        )
+        .arg(
+            Arg::with_name("output_format")
+                .long("output_format")
+                .takes_value(true)
+                .possible_values(&["sexpr", "annotated", "onnx", "flatbuffer"])
+                .default_value("sexpr")
+                .help("Format of the output file: s-expression, one op per line with named attributes and shapes, ONNX, or a FlatBuffer for embedded runtimes"),
+        )
+        .arg(
+            Arg::with_name("partition")
+                .long("partition")
+                .help("Whether to optimize the regions between opaque ops separately, each with the given limits. Only single-pattern rules and greedy extraction are used"),
+        )
+        .arg(
+            Arg::with_name("partition_parts")
+                .long("partition_parts")
+                .takes_value(true)
+                .help("Number of parts to cut the graph into at minimum cuts over the tensor sizes. The cuts are printed, and with --partition a graph without opaque ops is optimized by these parts"),
+        )
+        .arg(
+            Arg::with_name("taso_model")
+                .long("taso_model")
+                .takes_value(true)
+                .help("Model serialized by TASO's export_to_file, imported instead of model_file, or - for stdin"),
+        )
+        .arg(
+            Arg::with_name("onnx_model")
+                .long("onnx_model")
+                .takes_value(true)
+                .conflicts_with_all(&["model", "model_file", "taso_model"])
+                .help("Model serialized as ONNX, imported instead of model_file, or - for stdin. Its inputs and initializers keep their names"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .requires("taso_model")
+                .conflicts_with("permissive")
+                .help("Whether importing the taso_model fails on any unsupported op or shape mismatch"),
+        )
+        .arg(
+            Arg::with_name("permissive")
+                .long("permissive")
+                .requires("taso_model")
+                .help("Whether to keep every unsupported op and op with mismatched shapes of the taso_model as an opaque op, and continue with a warning if ops still could not be imported"),
+        )
+        .arg(
+            Arg::with_name("coverage_report")
+                .long("coverage_report")
+                .takes_value(true)
+                .requires("taso_model")
+                .help("File to write the op coverage report of the imported model to (json)"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .help("Runtime profile of the input model (json, layer name to runtime, or the output of mode estimate). Rules are then only applied around the layers dominating the runtime"),
+        )
+        .arg(
+            Arg::with_name("packed_attrs")
+                .long("packed_attrs")
+                .conflicts_with("profile")
+                .help("Whether to pack the scalar attributes of conv2d and pooling ops into one scalar during saturation. Layer names in the reports refer to the packed graph"),
+        )
+        .arg(
+            Arg::with_name("profile_coverage")
+                .long("profile_coverage")
+                .takes_value(true)
+                .default_value("0.8")
+                .help("Fraction of the profiled runtime the optimized layers should account for"),
+        )
+        .arg(
+            Arg::with_name("rule_priors")
+                .long("rule_priors")
+                .takes_value(true)
+                .help("File of the priors of the rules (json, see priors.rs), e.g. written by mode convert. Rules unlikely to apply are searched from later iterations, and how often each rule was applied is recorded in the file"),
+        )
+        .arg(
+            Arg::with_name("library")
+                .long("library")
+                .takes_value(true)
+                .help("Directory of the library of the best graphs found for each model across runs. The result of this run is recorded in it"),
+        )
+        .arg(
+            Arg::with_name("library_target")
+                .long("library_target")
+                .takes_value(true)
+                .default_value("default")
+                .help("Name of the hardware target the library entries are recorded for, e.g. the GPU"),
+        )
+        .arg(
+            Arg::with_name("library_union")
+                .long("library_union")
+                .requires("library")
+                .help("Whether to union the best graph in the library into the starting EGraph, so results only ever improve"),
+        )
+        .arg(
+            Arg::with_name("blocks")
+                .long("blocks")
+                .help("Whether to add the optimized forms of common blocks (conv-relu, dense-relu, separable conv, attention scores) to the starting EGraph, for good graphs with small node budgets"),
+        )
+        .arg(
+            Arg::with_name("blocks_file")
+                .long("blocks_file")
+                .takes_value(true)
+                .help("File with more blocks and their optimized forms for --blocks, one `name: lhs=>rhs` per line"),
+        )
+        .arg(
+            Arg::with_name("taso_seed")
+                .long("taso_seed")
+                .help("Whether to run TASO's own optimizer on the input graph first and union its best graph into the starting EGraph"),
+        )
+        .arg(
+            Arg::with_name("taso_alpha")
+                .long("taso_alpha")
+                .takes_value(true)
+                .default_value("1.0")
+                .help("Alpha of TASO's optimizer (with taso_seed): how much slower than the best graph a graph can be and still be explored"),
+        )
+        .arg(
+            Arg::with_name("taso_budget")
+                .long("taso_budget")
+                .takes_value(true)
+                .default_value("1000")
+                .help("Number of graphs TASO's optimizer explores (with taso_seed)"),
+        )
+        .arg(
+            Arg::with_name("record_replay")
+                .long("record_replay")
+                .takes_value(true)
+                .help("File (in output_dir) to record the starting and optimized graph in, to replay the optimization onto other checkpoints of the model with --replay"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .takes_value(true)
+                .conflicts_with("record_replay")
+                .help("File recorded by --record_replay. Instead of optimizing, the recorded optimization is applied to the input graph, which has to be the same architecture (with other tensor names)"),
+        )
+        .arg(
+            Arg::with_name("objective")
+                .long("objective")
+                .takes_value(true)
+                .possible_values(&["latency", "energy", "cost"])
+                .default_value("latency")
+                .help("What to optimize for: latency, energy (see --power_watts), or cost per inference (see --price_per_hour)"),
+        )
+        .arg(
+            Arg::with_name("tie_break")
+                .long("tie_break")
+                .takes_value(true)
+                .possible_values(&["none", "op_count", "original"])
+                .default_value("none")
+                .help("Secondary objective of extraction, deciding between graphs of equal cost: none, the number of ops, or the number of ops not in the original graph"),
+        )
+        .arg(
+            Arg::with_name("power_watts")
+                .long("power_watts")
+                .takes_value(true)
+                .help("Board power of the GPU under load, for --objective energy. Measured through nvidia-smi if not given"),
+        )
+        .arg(
+            Arg::with_name("measure_power")
+                .long("measure_power")
+                .help("Whether to sample the GPU power (through NVML) while TASO measures ops, for the energy of each op"),
+        )
+        .arg(
+            Arg::with_name("pareto")
+                .long("pareto")
+                .help("Whether to write an energy vs. latency Pareto report of graphs extracted with different weights"),
+        )
+        .arg(
+            Arg::with_name("op_weight")
+                .long("op_weight")
+                .takes_value(true)
+                .help("Cost of each op in the extraction objective, in the unit of the objective (e.g. ms), to prefer graphs with fewer ops"),
+        )
+        .arg(
+            Arg::with_name("memory_weight")
+                .long("memory_weight")
+                .takes_value(true)
+                .help("Cost per MB of activation in the extraction objective, in the unit of the objective (e.g. ms), to prefer graphs using less memory"),
+        )
+        .arg(
+            Arg::with_name("pareto_front")
+                .long("pareto_front")
+                .help("Whether to sweep the weights of the objective, the op count and the memory, and write the non-dominated extracted graphs to pareto_front.json"),
+        )
+        .arg(
+            Arg::with_name("speedup_report")
+                .long("speedup_report")
+                .help("Whether to attribute the measured speedup to the rewritten regions of the model (see attribution.rs), print the patterns that gain the most and write speedup_report.json"),
+        )
+        .arg(
+            Arg::with_name("targets")
+                .long("targets")
+                .takes_value(true)
+                .help("Comma-separated targets to extract a graph for each from the same saturated EGraph, e.g. V100,T4,cpu.json: device profiles (as for --device_profile) with analytical runtimes, or local for the runtimes measured on the local GPU. Writes optimized_<target>.txt and targets.json"),
+        )
+        .arg(
+            Arg::with_name("batch_size")
+                .long("batch_size")
+                .takes_value(true)
+                .default_value("1")
+                .help("Batch size the symbolic batch dimension N of input shapes (e.g. input@N_3_224_224) stands for when computing shapes and measuring ops. An optimized graph with N is checked at 2x that batch size"),
+        )
+        .arg(
+            Arg::with_name("shape_report")
+                .long("shape_report")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .help("Batch sizes to measure the starting and optimized graph at after the optimization, written to shape_report.json, with a warning for each batch size where the optimized graph is slower"),
+        )
+        .arg(
+            Arg::with_name("runtime_source")
+                .long("runtime_source")
+                .takes_value(true)
+                .possible_values(&["measured", "analytical", "table"])
+                .default_value("measured")
+                .help("Where extraction gets the runtimes of ops from: measured by TASO, estimated from their FLOPs and bytes (see --peak_tflops and --bandwidth_gbps), or looked up in --runtime_table"),
+        )
+        .arg(
+            Arg::with_name("runtime_table")
+                .long("runtime_table")
+                .takes_value(true)
+                .help("Runtimes of ops for --runtime_source table (json, op key like `conv2d 1 1 0 2 1x64x56x56 64x64x3x3` to milliseconds). Ops not in the table use the analytical runtimes"),
+        )
+        .arg(
+            Arg::with_name("cost_cache")
+                .long("cost_cache")
+                .takes_value(true)
+                .help("File keeping the runtimes of ops across runs (json, in the format of --runtime_table). Extraction reuses the runtimes in it and adds the new ones"),
+        )
+        .arg(
+            Arg::with_name("shape_buckets")
+                .long("shape_buckets")
+                .takes_value(true)
+                .help("Ratio between the bounds of the buckets of dimensions (e.g. 2 puts 33 to 64 in one bucket). Ops of the same type whose shapes fall in the same buckets get the runtime of one of them, scaled by their analytical runtimes. 1 only puts equal shapes together"),
+        )
+        .arg(
+            Arg::with_name("sample_shapes")
+                .long("sample_shapes")
+                .takes_value(true)
+                .help("Fraction of the buckets of shapes of each op type whose runtime is taken from the runtime source, the others are interpolated between them (with a confidence in shape_sampling.json)"),
+        )
+        .arg(
+            Arg::with_name("peak_tflops")
+                .long("peak_tflops")
+                .takes_value(true)
+                .default_value("14")
+                .help("Peak throughput of the GPU, for the analytical runtimes"),
+        )
+        .arg(
+            Arg::with_name("bandwidth_gbps")
+                .long("bandwidth_gbps")
+                .takes_value(true)
+                .default_value("900")
+                .help("Memory bandwidth of the GPU in GB/s, for the analytical runtimes"),
+        )
+        .arg(
+            Arg::with_name("drift_threshold")
+                .long("drift_threshold")
+                .takes_value(true)
+                .default_value("0.25")
+                .help("Relative difference between the measured runtime of the optimized graph and the sum of the runtimes of its ops above which the cost model is flagged as off. The ratio is recorded in device_profile.json in the output directory. In mode validate_costs, the mean relative difference between the cached and the re-measured runtimes above which the cache is stale"),
+        )
+        .arg(
+            Arg::with_name("device_profile")
+                .long("device_profile")
+                .takes_value(true)
+                .default_value("auto")
+                .help("Throughput and bandwidth of the GPU for the analytical runtimes: a built-in profile (V100, A100, T4, 4090, 4080, 4070, 4060), a JSON profile file, or auto to detect the local GPU. --peak_tflops and --bandwidth_gbps override it"),
+        )
+        .arg(
+            Arg::with_name("compare_runtime_sources")
+                .long("compare_runtime_sources")
+                .help("Whether to also extract with the other runtime source, and report where the two graphs differ and by how much to cost_model_comparison.json"),
+        )
+        .arg(
+            Arg::with_name("price_per_hour")
+                .long("price_per_hour")
+                .takes_value(true)
+                .help("Price of the instance in dollars per hour, for --objective cost"),
+        )
         .arg(
             Arg::with_name("model_file")
                 .short("f")
                 .long("model_file")
                 .takes_value(true)
-                .help("Provide a file with the input model"),
+                .help("Provide a file with the input model (an s-expression or the annotated format), or - for stdin"),
+        )
+        .arg(
+            Arg::with_name("joint_models")
+                .long("joint_models")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .conflicts_with_all(&["model", "model_file", "taso_model", "onnx_model"])
+                .help("Files with models to optimize together in one EGraph, instead of model_file. Nodes the models share (same ops on tensors with the same names) are optimized once. The optimized models are written to joint_model_<k>.txt"),
         )
         .arg(
             Arg::with_name("multi_rules")
@@ -84,6 +552,18 @@ fn main() {
                 .takes_value(true)
                 .help("File with multi-pattern rules. Every two lines belong to one multi-pattern rule"),
         )
+        .arg(
+            Arg::with_name("rules_file")
+                .long("rules_file")
+                .takes_value(true)
+                .help("File with more single- and multi-pattern rules (text, or JSON ending in .json), checked against the language and added to the built-in rules"),
+        )
+        .arg(
+            Arg::with_name("replace_builtin_rules")
+                .long("replace_builtin_rules")
+                .requires("rules_file")
+                .help("Whether the rules of --rules_file replace the built-in rules instead of adding to them"),
+        )
         .arg(
             Arg::with_name("save_graph")
                 .short("s")
@@ -92,6 +572,35 @@ fn main() {
                 .default_value("io")
                 .help("Whether to save graphs as dot files. Can be: all, io, none"),
         )
+        .arg(
+            Arg::with_name("export_dot")
+                .long("export_dot")
+                .help("Whether to write DOT files of the input and the optimized graph (start.dot, optimized.dot), with the parameters of the ops and the shapes of the tensors"),
+        )
+        .arg(
+            Arg::with_name("export_egraph_dot")
+                .long("export_egraph_dot")
+                .help("Whether to write a DOT file of the final EGraph (egraph.dot), with the cost of each enode"),
+        )
+        .arg(
+            Arg::with_name("shape_assertions")
+                .long("shape_assertions")
+                .takes_value(true)
+                .help("JSON file of the expected shapes of named tensors, checked on the input and the optimized graph"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .default_value("0")
+                .help("Seed of the random values of the weights TASO measures the ops on, and of the sample of mode validate_costs"),
+        )
+        .arg(
+            Arg::with_name("weights_file")
+                .long("weights_file")
+                .takes_value(true)
+                .help("File of the values of the weights, instead of random ones: an .npz archive of arrays named as the weights, or an ONNX model with the weights as initializers"),
+        )
         .arg(
             Arg::with_name("use_multi")
                 .short("u")
@@ -112,6 +621,12 @@ fn main() {
                 .default_value("10")
                 .help("Max number of seconds for egg to run"),
         )
+        .arg(
+            Arg::with_name("time_budget")
+                .long("time_budget")
+                .takes_value(true)
+                .help("Total seconds for saturation, measurement and extraction. Overrides n_sec, n_nodes and ilp_time_sec, sizing them from a short probe saturation and the ILP rate of previous runs (in output_dir)"),
+        )
         .arg(
             Arg::with_name("n_nodes")
                 .long("n_nodes")
@@ -125,7 +640,38 @@ fn main() {
                 .long("extract")
                 .takes_value(true)
                 .default_value("greedy")
-                .help("Extraction method, can be greedy, ilp"),
+                .help("Extraction method, can be greedy, beam (a local search from the greedy graph), ilp (extractor/extract.py), cbc (the same ILP, solved in process) egg_ilp or the name of a strategy registered with strategies::register_strategy"),
+        )
+        .arg(
+            Arg::with_name("beam_width")
+                .long("beam_width")
+                .takes_value(true)
+                .default_value("4")
+                .help("Number of graphs the beam extraction keeps in each round"),
+        )
+        .arg(
+            Arg::with_name("beam_rounds")
+                .long("beam_rounds")
+                .takes_value(true)
+                .default_value("20")
+                .help("Max number of rounds of the beam extraction, each changes the pick of one more eclass"),
+        )
+        .arg(
+            Arg::with_name("max_memory")
+                .long("max_memory")
+                .takes_value(true)
+                .help("Max total size of the activations of the extracted graph, in MB. A constraint for the ILP, a penalty raised until the graph fits for greedy and beam"),
+        )
+        .arg(
+            Arg::with_name("recompute_ops")
+                .long("recompute_ops")
+                .takes_value(true)
+                .help("Comma-separated ops the backend recomputes in their consumers instead of materializing, not counted in the activation memory. default for ewadd,ewmul,relu,tanh,sigmoid,cast"),
+        )
+        .arg(
+            Arg::with_name("ilp_gap")
+                .long("ilp_gap")
+                .help("Whether to also extract by ILP (solved with cbc) after greedy or beam extraction, and report the cost gap to it"),
         )
         .arg(
             Arg::with_name("order_var_int")
@@ -137,6 +683,64 @@ fn main() {
                 .long("class_constraint")
                 .help("Add constraint in ILP that each eclass sum to 1"),
         )
+        .arg(
+            Arg::with_name("measure_timeout")
+                .long("measure_timeout")
+                .takes_value(true)
+                .help("Longest a single TASO measurement can take, in seconds. A measurement going over marks its op infeasible and restarts the run (see watchdog.rs)"),
+        )
+        .arg(
+            Arg::with_name("hung_ops")
+                .long("hung_ops")
+                .takes_value(true)
+                .help("File of the ops whose measurement timed out, hung_ops.txt in the output directory by default"),
+        )
+        .arg(
+            Arg::with_name("prune_enodes")
+                .long("prune_enodes")
+                .takes_value(true)
+                .help("Before each iteration, prune the eclasses with more than this many enodes to their cheapest enodes and those of the original graph (see prune.rs)"),
+        )
+        .arg(
+            Arg::with_name("prune_keep")
+                .long("prune_keep")
+                .takes_value(true)
+                .default_value("4")
+                .help("Number of the cheapest enodes kept in an eclass pruned with --prune_enodes"),
+        )
+        .arg(
+            Arg::with_name("tied_weights")
+                .long("tied_weights")
+                .help("Keep the weights shared by several ops (e.g. a tied embedding and projection) the same tensor in the optimized graph: the ops on weights only using them are not extracted, unless the original graph has them (see tied.rs)"),
+        )
+        .arg(
+            Arg::with_name("freeze_prefix")
+                .long("freeze_prefix")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .help("Layers (named as in the profiles, e.g. conv2d_12) up to which the graph is frozen: they and the layers they depend on are kept as they are in the optimized graph (see freeze.rs)"),
+        )
+        .arg(
+            Arg::with_name("freeze_suffix")
+                .long("freeze_suffix")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .help("Layers from which the graph is frozen: they and the layers depending on them are kept as they are in the optimized graph, e.g. a calibrated quantized head"),
+        )
+        .arg(
+            Arg::with_name("no_condition_cache")
+                .long("no_condition_cache")
+                .help("Check the shapes of every match of a rule, instead of caching the checks per rule and shapes of the matched eclasses within an iteration (see rulecache.rs)"),
+        )
+        .arg(
+            Arg::with_name("weight_cache_mb")
+                .long("weight_cache_mb")
+                .takes_value(true)
+                .default_value("256")
+                .help("Size in MB of the cache of the weights transformed by the reference interpreter, by transformation and source weights, 0 to disable it (see weights.rs)"),
+        )
         .arg(
             Arg::with_name("no_order")
                 .long("no_order")
@@ -153,6 +757,24 @@ fn main() {
                 .takes_value(true)
                 .help("Time limit for ILP solver (seconds)"),
         )
+        .arg(
+            Arg::with_name("solver_cpu_sec")
+                .long("solver_cpu_sec")
+                .takes_value(true)
+                .help("CPU time limit of the solver subprocesses (seconds), after which the kernel kills them"),
+        )
+        .arg(
+            Arg::with_name("solver_memory_mb")
+                .long("solver_memory_mb")
+                .takes_value(true)
+                .help("Address space limit of the solver subprocesses (MB)"),
+        )
+        .arg(
+            Arg::with_name("solver_wall_sec")
+                .long("solver_wall_sec")
+                .takes_value(true)
+                .help("Wall-clock time limit of the solver subprocesses (seconds), after which tensat kills them"),
+        )
         .arg(
             Arg::with_name("ilp_num_threads")
                 .long("ilp_num_threads")
@@ -174,14 +796,104 @@ fn main() {
                 .help("Max number of nodes added by multi-pattern rules"),
         )
         .arg(
-            Arg::with_name("no_cycle")
-                .long("no_cycle")
-                .help("Not allowing cycles in EGraph"),
+            Arg::with_name("node_multi_round")
+                .long("node_multi_round")
+                .takes_value(true)
+                .help("Max number of nodes added by one round of multi-pattern rules, within node_multi"),
         )
         .arg(
-            Arg::with_name("filter_before")
-                .long("filter_before")
-                .help("Filter cycles before applying rules"),
+            Arg::with_name("bidirectional_rules")
+                .long("bidirectional_rules")
+                .help("Whether to run each pair of single-pattern rules that are the same rule in opposite directions as one bidirectional rule"),
+        )
+        .arg(
+            Arg::with_name("egraph_snapshots")
+                .long("egraph_snapshots")
+                .help("Whether to write what each iteration added to the EGraph (new eclasses, new enodes by op, applications by rule) to egraph_diffs.jsonl in output_dir"),
+        )
+        .arg(
+            Arg::with_name("full_snapshots")
+                .long("full_snapshots")
+                .requires("egraph_snapshots")
+                .help("Whether to also write the whole EGraph before each iteration to egraph_snapshot_<iteration>.json"),
+        )
+        .arg(
+            Arg::with_name("progress_every")
+                .long("progress_every")
+                .takes_value(true)
+                .help("Extract greedily every this many iterations and after the last one, writing the best graph so far to best_<iteration>.txt and its cost to progress.jsonl in output_dir"),
+        )
+        .arg(
+            Arg::with_name("live_port")
+                .long("live_port")
+                .takes_value(true)
+                .help("Port to serve a page showing the EGraph during saturation on (http://127.0.0.1:<port>/, see live.rs)"),
+        )
+        .arg(
+            Arg::with_name("save_egraph")
+                .long("save_egraph")
+                .takes_value(true)
+                .help("File to save the saturated EGraph to (json, see checkpoint.rs), to extract from it with --load_egraph"),
+        )
+        .arg(
+            Arg::with_name("artifact")
+                .long("artifact")
+                .takes_value(true)
+                .help("Directory mode saturate writes the saturated EGraph, the input graph and the settings to, and mode extract extracts from (see stages.rs)"),
+        )
+        .arg(
+            Arg::with_name("load_egraph")
+                .long("load_egraph")
+                .takes_value(true)
+                .conflicts_with("save_egraph")
+                .help("File of an EGraph saved with --save_egraph, to extract from instead of saturating. Give the model and the settings of the run that saved it"),
+        )
+        .arg(
+            Arg::with_name("multi_prepass")
+                .long("multi_prepass")
+                .help("Whether to run all iter_multi rounds of multi-pattern rules back to back before the first iteration of single-pattern rules, instead of one round at the start of each of the first iter_multi iterations"),
+        )
+        .arg(
+            Arg::with_name("multi_start_iter")
+                .long("multi_start_iter")
+                .takes_value(true)
+                .default_value("0")
+                .help("First iteration of the multi-pattern rules, the single-pattern rules run alone before"),
+        )
+        .arg(
+            Arg::with_name("k_multi")
+                .long("k_multi")
+                .takes_value(true)
+                .help("Max applications of each multi-pattern rule in a round, a rule over it is banned for the next rounds"),
+        )
+        .arg(
+            Arg::with_name("rule_match_limit")
+                .long("rule_match_limit")
+                .takes_value(true)
+                .help("Max matches of each single-pattern rule in an iteration, a rule over it is banned for the next iterations"),
+        )
+        .arg(
+            Arg::with_name("rule_caps")
+                .long("rule_caps")
+                .takes_value(true)
+                .help("JSON file of the caps of some rules (rule<i> or multi<i>), instead of rule_match_limit or k_multi"),
+        )
+        .arg(
+            Arg::with_name("rule_ban_length")
+                .long("rule_ban_length")
+                .takes_value(true)
+                .default_value("2")
+                .help("Iterations (rounds) of the first ban of a rule over its cap, doubled at each ban of the rule"),
+        )
+        .arg(
+            Arg::with_name("no_cycle")
+                .long("no_cycle")
+                .help("Not allowing cycles in EGraph"),
+        )
+        .arg(
+            Arg::with_name("filter_before")
+                .long("filter_before")
+                .help("Filter cycles before applying rules"),
         )
         .arg(
             Arg::with_name("all_weight_only")
@@ -193,6 +905,74 @@ fn main() {
                 .long("saturation_only")
                 .help("Run saturation only"),
         )
+        .arg(
+            Arg::with_name("quantized")
+                .long("quantized")
+                .help("The model is quantized: disable rewrite rules that change numerics"),
+        )
+        .arg(
+            Arg::with_name("safety")
+                .long("safety")
+                .takes_value(true)
+                .possible_values(&["strict", "normal", "aggressive"])
+                .default_value("normal")
+                .help("Which rewrite rules to enable: strict for the rules proven with the axioms, normal for those and the numerically exact rules, aggressive for all rules, including approximate ones and approx_rules. Multi-pattern rules are never proven"),
+        )
+        .arg(
+            Arg::with_name("check_equivalence")
+                .long("check_equivalence")
+                .takes_value(true)
+                .help("Largest relative error allowed between the outputs of the original and the optimized graph, evaluated on the same random inputs and weights after extraction. The graphs are evaluated by the equivalence_backend, not by TASO, which does not give back tensor values: the TASO kernels themselves are not checked"),
+        )
+        .arg(
+            Arg::with_name("equivalence_backend")
+                .long("equivalence_backend")
+                .takes_value(true)
+                .possible_values(&["interp", "ort"])
+                .default_value("interp")
+                .help("What evaluates the graphs for check_equivalence: the host interpreter, or ONNX Runtime on the graphs exported to ONNX (verify/ort_equivalence.py)"),
+        )
+        .arg(
+            Arg::with_name("verify_rules")
+                .long("verify_rules")
+                .takes_value(true)
+                .help("Number of random instantiations to check each rule with numerically before using it. Rules whose sides diverge are reported and not used. Both sides are evaluated by the host interpreter, not by TASO, which does not give back tensor values: a rule only wrong under TASO's kernels is not caught"),
+        )
+        .arg(
+            Arg::with_name("weight_layouts")
+                .long("weight_layouts")
+                .help("Add rules between the default and the blocked layout of conv2d weights, with the cost of repacking the weights"),
+        )
+        .arg(
+            Arg::with_name("approx_rules")
+                .long("approx_rules")
+                .takes_value(true)
+                .help("File with rewrite rules that change numerics within a bound. The optimized graph is only kept if its error is within error_budget"),
+        )
+        .arg(
+            Arg::with_name("error_budget")
+                .long("error_budget")
+                .takes_value(true)
+                .default_value("0.001")
+                .help("Max relative error of the outputs of the optimized graph, when using approx_rules"),
+        )
+        .arg(
+            Arg::with_name("failure_bundle")
+                .long("failure_bundle")
+                .takes_value(true)
+                .help("Directory to write what is needed to reproduce the run to (settings, rules, input graph, EGraph, input files, outputs) if it fails. Snapshots the EGraph before each iteration"),
+        )
+        .arg(
+            Arg::with_name("deterministic")
+                .long("deterministic")
+                .help("Make the result depend on the settings only: runtimes estimated from FLOPs and bytes, no time limits (n_sec, time_budget, ilp_time_sec), one ILP thread"),
+        )
+        .arg(
+            Arg::with_name("self_check")
+                .long("self_check")
+                .takes_value(true)
+                .help("Run the whole pipeline this many times (in output_dir/self_check). With deterministic, fails unless the optimized graphs are byte-identical, else reports the spread of the final cost"),
+        )
         .arg(
             Arg::with_name("output_dir")
                 .long("output_dir")
@@ -200,16 +980,41 @@ fn main() {
                 .takes_value(true)
                 .help("Output directory to save all experimental results to"),
         )
-        .get_matches();
+        .get_matches_from(deterministic_args(&std::env::args().collect::<Vec<_>>()));
 
+    // The optimized graph goes to stdout, everything else to stderr
+    if matches.value_of("output_file") == Some(STDIO) {
+        redirect_stdout();
+    }
     let run_mode = matches.value_of("mode").unwrap();
     println!("Running mode is: {}", run_mode);
+    set_batch_value(matches.value_of("batch_size").unwrap().parse::<i32>().unwrap());
+    let limit = |arg| matches.value_of(arg).map(|v| v.parse::<u64>().unwrap());
+    set_solver_limits(SolverLimits {
+        cpu_secs: limit("solver_cpu_sec"),
+        memory_mb: limit("solver_memory_mb"),
+        wall_secs: limit("solver_wall_sec"),
+    });
+    if matches.is_present("self_check") {
+        self_check(&matches);
+        return;
+    }
 
     match run_mode {
-        "optimize" => optimize(matches),
+        "optimize" => optimize(matches, Stage::All),
+        "saturate" => optimize(matches, Stage::Saturate),
+        "extract" => optimize(matches, Stage::Extract),
         "verify" => prove_taso_rules(matches),
         "test" => test(matches),
         "convert" => convert_learned_rules(matches),
+        "estimate" => estimate(matches),
+        "extraction_test" => extraction_test(matches),
+        "compare_rules" => compare_rules(matches),
+        "what_if" => what_if_rewrite(matches),
+        "suggest" => suggest(matches),
+        "microbench" => microbench_op(matches),
+        "reduce" => reduce_failure(matches),
+        "validate_costs" => validate_costs(matches),
         _ => panic!("Running mode not supported"),
     }
 }
@@ -224,501 +1029,2023 @@ fn convert_learned_rules(matches: clap::ArgMatches) {
     let taso_rules = read_to_string(file).expect("Something went wrong reading the file");
 
     let converted = parse_and_convert(&taso_rules);
+    // The priors of the rules, to start scheduling them from (see --rule_priors)
+    let rule_priors = RulePriors::from_rules(&converted.lines().collect::<Vec<_>>());
+    rule_priors.save(&format!("{}.priors.json", outf));
 
     write(outf, converted).expect("Unable to write file");
 }
 
 fn test(matches: clap::ArgMatches) {}
 
-/// Main procedure to run optimization
-///
-/// Gets input graph and rewrite rules; runs saturation with TensorAnalysis dealing with metadata; runs
-/// greedy extraction with TensorCost getting the cost per node/op; evaluates
-/// full graph runtime of the starting graph and extracted graph.
-fn optimize(matches: clap::ArgMatches) {
-    env_logger::init();
+/// Runs the pipeline self_check times with the same settings and compares
+/// the results (see selfcheck.rs). Exits with an error if a run fails, or if
+/// the optimized graphs of a deterministic check differ
+fn self_check(matches: &clap::ArgMatches) {
+    let num_runs = matches.value_of("self_check").unwrap().parse::<usize>().unwrap();
+    let output_directory = matches.value_of("output_dir").expect("Pls supply --output_dir for --self_check");
+    let exe = std::env::current_exe().unwrap();
+    let args: Vec<String> = deterministic_args(&std::env::args().collect::<Vec<_>>()).into_iter().skip(1).collect();
+    let runs = run_self_check(&exe, &args, output_directory, num_runs).unwrap_or_else(|e| {
+        eprintln!("Self check failed: {}", e);
+        std::process::exit(1);
+    });
 
-    // Read settings from args
-    let rule_file = matches
-        .value_of("rules")
-        .expect("Pls supply rewrite rules file.");
-    let save_graph = matches.value_of("save_graph").unwrap();
-    let use_multi = matches.is_present("use_multi");
-    let no_cycle = matches.is_present("no_cycle");
-    let filter_after = !matches.is_present("filter_before");
+    let costs: Vec<f32> = runs.iter().filter_map(|run| run.cost).collect();
+    if let Some(spread) = cost_spread(&costs) {
+        println!(
+            "Final cost over {} runs: mean {}, std dev {} ({:.2}%), min {}, max {}",
+            costs.len(),
+            spread.mean,
+            spread.std_dev,
+            100.0 * spread.std_dev / spread.mean,
+            spread.min,
+            spread.max
+        );
+    }
+    match first_mismatch(&runs) {
+        None => println!("The {} runs gave byte-identical optimized graphs", runs.len()),
+        Some(i) if matches.is_present("deterministic") => {
+            eprintln!("Self check failed: the optimized graph of run {} differs from the one of run 0", i);
+            std::process::exit(1);
+        }
+        Some(i) => println!("The optimized graphs differ (first at run {}), rerun with --deterministic for reproducible results", i),
+    }
+}
+
+/// Runs every extraction backend on the regression corpus
+///
+/// Greedy extraction has to give a valid graph, the ILP has to give the
+/// optimal one (with the ILP flags given). Exits with an error if a backend
+/// fails a case.
+fn extraction_test(matches: clap::ArgMatches) {
+    let corpus_dir = matches.value_of("corpus").unwrap();
     let output_directory = matches.value_of("output_dir").unwrap();
+    let cases = load_corpus(corpus_dir);
 
-    // Warn if output directory already exists, otherwise create it
-    if Path::new(output_directory).exists() {
-        println!("You are overwriting existing data!");
-    } else {
-        create_dir_all(output_directory);
+    let mut results = vec![];
+    for (name, case) in cases.iter() {
+        results.push(check_solution(name, case, "greedy", Ok(greedy_solution(case)), false));
+        let ilp = solve_case_by_ilp(case, &matches, output_directory);
+        results.push(check_solution(name, case, "ilp", ilp, true));
     }
 
-    // Save settings
-    let mut settings = Map::new();
-    for arg in &matches.args {
-        let key = arg.0.to_string();
-        let value = if !arg.1.vals.is_empty() {
-            Value::String(arg.1.vals[0].clone().into_string().unwrap())
-        } else {
-            Value::String(String::from("true"))
-        };
-        settings.insert(key, value);
+    println!("{:<24} {:<8} {:>10} {:>10}  {}", "case", "backend", "cost", "optimal", "error");
+    for result in results.iter() {
+        let optimal = cases.iter().find(|(name, _)| *name == result.case).unwrap().1.optimal_cost;
+        println!(
+            "{:<24} {:<8} {:>10} {:>10}  {}",
+            result.case,
+            result.backend,
+            result.cost.map_or(String::from("-"), |c| c.to_string()),
+            optimal,
+            result.error.as_deref().unwrap_or("")
+        );
+    }
+    let num_failed = results.iter().filter(|r| !r.passed()).count();
+    if num_failed > 0 {
+        eprintln!("{} of {} runs failed", num_failed, results.len());
+        std::process::exit(1);
     }
+}
 
-    let filename = Path::new(output_directory).join("settings.txt");
-    let mut file = OpenOptions::new().append(true).create(true).open(filename).unwrap();
-    let settings_data = serde_json::to_string(&settings).expect("Failed to convert json to string");
+/// Solves a corpus case with the ILP script, the same way as
+/// extract_by_ilp_script
+fn solve_case_by_ilp(
+    case: &ExtractionCase,
+    matches: &clap::ArgMatches,
+    output_directory: &str,
+) -> Result<Vec<Option<usize>>, String> {
+    let filename = Path::new(output_directory).join("ilp_data_corpus.json");
+    write(filename, serde_json::to_string(case).unwrap()).expect("Unable to write file");
 
-    if let Err(e) = writeln!(file, "{}", settings_data) {
-        eprintln!("Couldn't write to file: {}", e);
+    let mut arg_vec = vec!["extractor/extract.py", "--output_dir", output_directory, "--thread_name", "corpus"];
+    if matches.is_present("order_var_int") {
+        arg_vec.push("--order_var_int");
     }
+    if matches.is_present("class_constraint") {
+        arg_vec.push("--eclass_constraint");
+    }
+    if matches.is_present("no_order") {
+        arg_vec.push("--no_order");
+    }
+    if let Some(time_lim) = matches.value_of("ilp_time_sec") {
+        arg_vec.push("--time_lim_sec");
+        arg_vec.push(time_lim);
+    }
+    let status = run_solver(Command::new("python").args(&arg_vec))
+        .map_err(|e| format!("failed to execute the ILP script: {}", e))?;
+    if !status.success() {
+        return Err(format!("the ILP script failed: {}", status));
+    }
+    let filename = Path::new(output_directory).join("solved_corpus.json");
+    let solved_str = read_to_string(filename).map_err(|e| format!("no solution: {}", e))?;
+    let solved_data: SolvedResults = serde_json::from_str(&solved_str).map_err(|e| format!("bad solution: {}", e))?;
+    Ok(solution_from_x(case, &solved_data.solved_x))
+}
 
-    // Get input graph and rules
-    // learned_rules are the learned rules from TASO, pre_defined_rules are the hand-specified rules from TASO
-    let learned_rules =
-        read_to_string(rule_file).expect("Something went wrong reading the rule file");
-    let pre_defined_rules = PRE_DEFINED_RULES.iter().map(|&x| x);
-    let split_rules: Vec<&str> = learned_rules.split("\n").chain(pre_defined_rules).collect();
-    let do_filter_after = no_cycle && filter_after;
-    let rules = rules_from_str(split_rules, do_filter_after);
-
-    let start = match matches.value_of("model") {
-        Some("resnet50") => resnet50::get_resnet50(),
-        Some("nasrnn") => nasrnn::get_nasrnn(),
-        Some("resnext50") => resnext50::get_resnext50(),
-        Some("bert") => bert::get_bert(),
-        Some("nasneta") => nasneta::get_nasneta(),
-        Some("inceptionv3") => inceptionv3::get_inceptionv3(),
-        Some("mobilenetv2") => mobilenetv2::get_mobilenetv2(),
-        Some("vgg") => vgg::get_vgg(),
-        Some("squeezenet") => squeezenet::get_squeezenet(),
-        Some(_) => panic!("The model name is not supported"),
-        None => {
-            let model_file = matches
-                .value_of("model_file")
-                .expect("Pls supply input graph file.");
-            let input_graph =
-                read_to_string(model_file).expect("Something went wrong reading the model file");
-            input_graph.parse().unwrap()
-        }
-    };
+/// Estimates the runtime of a graph without optimizing it
+///
+/// Only runs the analysis on the input graph (no saturation or extraction),
+/// then prints the cost of each op from the cost model and the measured
+/// runtime of the full graph.
+fn estimate(matches: clap::ArgMatches) {
+    env_logger::init();
 
-    // Get multi-pattern rules. learned_rules are the learned rules from TASO,
-    // pre_defined_multi are the hand-specified rules from TASO
-    let n_sec = matches.value_of("n_sec").unwrap().parse::<u64>().unwrap();
-    let iter_multi = matches
-        .value_of("iter_multi")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    let node_multi = matches
-        .value_of("node_multi")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    let mut multi_patterns = if let Some(rule_file) = matches.value_of("multi_rules") {
-        let learned_rules =
-            read_to_string(rule_file).expect("Something went wrong reading the rule file");
-        let pre_defined_multi = PRE_DEFINED_MULTI.iter().map(|&x| (x, /*symmetric=*/ false));
-        // The learned rules we have are symmetric. Predefined ones are not
-        let multi_rules: Vec<(&str, bool)> = learned_rules
-            .split("\n")
-            .map(|x| (x, /*symmetric=*/ true))
-            .chain(pre_defined_multi)
-            .collect();
-        MultiPatterns::with_rules(multi_rules, no_cycle, iter_multi, filter_after, node_multi, n_sec, String::from(output_directory))
-    } else {
-        let multi_rules: Vec<(&str, bool)> = PRE_DEFINED_MULTI
-            .iter()
-            .map(|&x| (x, /*symmetric=*/ false))
-            .collect();
-        MultiPatterns::with_rules(multi_rules, no_cycle, iter_multi, filter_after, node_multi, n_sec, String::from(output_directory))
-    };
+    if let Err(e) = check_taso_abi() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    // Run saturation
-    let time_limit_sec = Duration::new(n_sec, 0);
-    let iter_limit = matches
-        .value_of("n_iter")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    let node_limit = matches
-        .value_of("n_nodes")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
+    let start = load_model(&matches);
+    let runner = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&start);
+    let cost_model = CostModel::with_setting(false).with_objective(get_objective(&matches));
+    let costs = expr_op_costs(&runner.egraph, &start, &cost_model);
 
-    let runner = if use_multi {
-        // This hook function (which applies the multi-pattern rules) will be called at the
-        // beginning of each iteration in equality saturation
-        Runner::<Mdl, TensorAnalysis, ()>::default()
-            .with_node_limit(node_limit)
-            .with_time_limit(time_limit_sec)
-            .with_iter_limit(iter_limit)
-            .with_expr(&start)
-            .with_hook(move |runner| multi_patterns.run_one(runner, None))
-    } else {
-        Runner::<Mdl, TensorAnalysis, ()>::default()
-            .with_node_limit(node_limit)
-            .with_time_limit(time_limit_sec)
-            .with_iter_limit(iter_limit)
-            .with_expr(&start)
-    };
+    let layer_names = default_layer_names(&start);
+    let mut ops = vec![];
+    for (i, cost) in costs.iter().enumerate() {
+        if let (Some(cost), Some(name)) = (cost, layer_names.get(&Id::from(i))) {
+            println!("{:<24} {:>12.4}", name, cost);
+            ops.push(json!({"node": i, "name": name, "cost": cost}));
+        }
+    }
+    let total: f32 = costs.iter().filter_map(|c| *c).sum();
+    let measured = get_full_graph_runtime(&runner, false);
+    println!("Sum of op costs: {} {}", total, cost_model.objective().unit());
+    println!("Measured graph runtime: {}", measured);
 
-    // if matches.is_present("gj") {
-    //     runner.egraph.strategy = egg::Strategy::GenericJoin;
-    // } else {
-    //     runner.egraph.strategy = egg::Strategy::EMatch;
-    // }
+    if let Some(outf) = matches.value_of("out_file") {
+        let data = json!({
+            "ops": ops,
+            "total_cost": total,
+            "measured_runtime": measured,
+        });
+        write(outf, serde_json::to_string(&data).unwrap()).expect("Unable to write file");
+    }
+}
 
-    let start_time = Instant::now();
-    let mut runner = runner.run(&rules[..]);
+/// Measures an op standalone, through the runtime model of optimization
+fn microbench_op(matches: clap::ArgMatches) {
+    env_logger::init();
 
-    if do_filter_after {
-        // Do cycle removal after the final iteration
-        remove_cycle_by_order(&mut runner);
+    if let Err(e) = check_taso_abi() {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
-    let sat_duration = start_time.elapsed();
-    let num_iter_sat = runner.iterations.len() - 1;
 
-    println!("Runner complete!");
-    println!("  Nodes: {}", runner.egraph.total_size());
-    println!("  Classes: {}", runner.egraph.number_of_classes());
-    println!("  Stopped: {:?}", runner.stop_reason.as_ref().unwrap());
-    println!("  Time taken: {:?}", sat_duration);
-    println!("  Number of iterations: {:?}", num_iter_sat);
+    let op = matches.value_of("op").expect("Pls supply the op to measure with --op.");
+    let expr = read_graph(op).unwrap_or_else(|e| panic!("Could not read the op: {}", e));
+    let repeats = matches.value_of("repeats").unwrap().parse::<usize>().unwrap();
+    let runtime_source = matches.value_of("runtime_source").unwrap();
+    let cost_model = CostModel::with_setting(false).with_runtime_model(get_runtime_model(&matches, runtime_source));
+    let samples = microbench(&expr, &cost_model, repeats).unwrap_or_else(|e| panic!("{}", e));
+    let summary = LatencySummary::from_samples(&samples).expect("Pls supply at least one repeat.");
 
-    let (num_enodes, num_classes, avg_nodes_per_class, num_edges, num_programs) =
-        get_stats(&runner.egraph);
-    println!("  Average nodes per class: {}", avg_nodes_per_class);
-    println!("  Number of edges: {}", num_edges);
-    println!("  Number of programs: {}", num_programs);
+    println!("{} ({}, {} repeats)", expr.as_ref().last().unwrap(), cost_model.runtime_model().name(), summary.count);
+    println!(
+        "{:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "min", "p50", "p90", "p99", "max", "mean", "stddev"
+    );
+    println!(
+        "{:>10.4} {:>10.4} {:>10.4} {:>10.4} {:>10.4} {:>10.4} {:>10.4}",
+        summary.min, summary.p50, summary.p90, summary.p99, summary.max, summary.mean, summary.stddev
+    );
 
-    // Save iteration data
-    let filename = Path::new(output_directory).join("iteration_data.txt");
-    let mut file = OpenOptions::new().append(true).create(true).open(filename).unwrap();
-    let iteration_data = serde_json::to_string(&runner.iterations).expect("Failed to convert IterationData json to string");
-    if let Err(e) = writeln!(file, "{}", iteration_data) {
-        eprintln!("Couldn't write to file: {}", e);
+    if let Some(outf) = matches.value_of("out_file") {
+        let data = json!({
+            "op": op,
+            "runtime_model": cost_model.runtime_model().name(),
+            "samples": samples,
+            "summary": summary,
+        });
+        write(outf, serde_json::to_string(&data).unwrap()).expect("Unable to write file");
     }
+}
 
-    // Save egraph
-    let (egraph, root) = (runner.egraph, runner.roots[0]);
-    if save_graph == "all" {
-        let filename = Path::new(output_directory).join("tensat.svg");
-        egraph.dot().to_svg(filename).unwrap();
-    }
+/// Re-measures a random sample of the runtimes in a cost cache and reports
+/// how far they drifted, see costcheck.rs
+fn validate_costs(matches: clap::ArgMatches) {
+    env_logger::init();
 
-    if matches.is_present("saturation_only") {
-        if let Some(outf) = matches.value_of("out_file") {
-            let filename = Path::new(output_directory).join(outf);
-            let mut file = OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(filename)
-                .unwrap();
+    if let Err(e) = check_taso_abi() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-            // Stats to write: original runtime, optimized runtime, saturation time, extraction time,
-            // number of nodes, number of eclasses, number of possible programs
-            let data = json!({
-                "runner_stop_reason": runner.stop_reason.as_ref().unwrap(),
-                "runner_time": sat_duration.as_secs_f32(),
-                "num_iterations": num_iter_sat,
-                "num_enodes": num_enodes,
-                "num_classes": num_classes,
-                "avg_nodes_per_class": avg_nodes_per_class,
-                "num_edges": num_edges,
-                "num_programs": num_programs,
-                "extraction_time": 0.0,
-                "original_runtime": 0.0,
-                "optimized_runtime": 0.0,
-            });
-            let sol_data_str = serde_json::to_string(&data).expect("Fail to convert json to string");
+    let cache_file = matches
+        .value_of("cost_cache")
+        .expect("Pls supply the cost cache to validate with --cost_cache.");
+    let cache = RuntimeCache::load(cache_file).unwrap_or_else(|e| panic!("{}", e));
+    let num_samples = matches.value_of("sample").unwrap().parse::<usize>().unwrap();
+    let seed = matches.value_of("seed").unwrap().parse::<u64>().unwrap();
+    let repeats = matches.value_of("repeats").unwrap().parse::<usize>().unwrap();
+    let threshold = matches.value_of("drift_threshold").unwrap().parse::<f32>().unwrap();
+    let runtime_source = matches.value_of("runtime_source").unwrap();
+    let cost_model = CostModel::with_setting(false).with_runtime_model(get_runtime_model(&matches, runtime_source));
 
-            if let Err(e) = writeln!(file, "{}", sol_data_str) {
-                eprintln!("Couldn't write to file: {}", e);
+    let sample = sample_keys(&cache, num_samples, seed);
+    println!(
+        "Re-measuring {} of {} cached runtimes ({})",
+        sample.len(),
+        cache.num_entries(),
+        cost_model.runtime_model().name()
+    );
+    let mut drifts = vec![];
+    let mut num_skipped = 0;
+    for (key, cached) in sample.iter() {
+        match remeasure(key, *cached, &cost_model, repeats) {
+            Ok(drift) => drifts.push(drift),
+            Err(e) => {
+                println!("  Skipped {}: {}", key, e);
+                num_skipped += 1;
             }
         }
-    } else {
-        // Run extraction
-        let extract_mode = matches.value_of("extract").unwrap();
-        let cost_model = CostModel::with_setting(
-            /*ignore_all_weight_only=*/ matches.is_present("all_weight_only"),
-        );
-        let (best, best_cost, ext_secs) = match extract_mode {
-            "ilp" => extract_by_ilp(&egraph, root, &matches, &cost_model),
-            "egg_ilp" => {
-                let tnsr_cost = TensorCost::new(
-                    &egraph,
-                    &cost_model,
-                    true,
-                );
-
-                let start_time = Instant::now();
-                let mut lp_extractor = LpExtractor::new(&egraph, tnsr_cost);
-                let (best_cost, best) = lp_extractor.solve(root);
-                let duration = start_time.elapsed();
+    }
+    drifts.sort_by(|a, b| b.relative().abs().partial_cmp(&a.relative().abs()).unwrap());
 
-                println!("Egg's ILP Extractor complete!");
-                println!("  Time taken: {:?}", duration);
-                println!("  Best cost: {:?}", best_cost);
-                (best, best_cost as f32, duration.as_secs_f32())
-            }
-            "greedy" => {
-                let tnsr_cost = TensorCost::new(
-                    &egraph,
-                    &cost_model,
-                    true,
+    println!("{:>10} {:>10} {:>8}  op", "cached", "measured", "drift");
+    for drift in drifts.iter().take(10) {
+        println!("{:>10.4} {:>10.4} {:>+7.1}%  {}", drift.cached, drift.measured, drift.relative() * 100.0, drift.key);
+    }
+    let summary = DriftSummary::from_drifts(&drifts, threshold);
+    match &summary {
+        Some(summary) => {
+            println!(
+                "Drift of {} ops ({} skipped): mean {:+.1}%, mean absolute {:.1}%, max absolute {:.1}%, {} past {:.0}%",
+                summary.num_checked,
+                num_skipped,
+                summary.mean_drift * 100.0,
+                summary.mean_abs_drift * 100.0,
+                summary.max_abs_drift * 100.0,
+                summary.num_over_threshold,
+                threshold * 100.0
+            );
+            if summary.is_stale(threshold) {
+                println!(
+                    "Warning: the cached runtimes are stale (e.g. from another driver or clock state), invalidate {} before trusting a new optimization run",
+                    cache_file
                 );
-                let start_time = Instant::now();
-                let extractor = Extractor::new(&egraph, tnsr_cost);
-                let (best_cost, best) = extractor.find_best(root);
-                let duration = start_time.elapsed();
-
-                println!("Extractor complete!");
-                println!("  Time taken: {:?}", duration);
-                println!("  Best cost: {:?}", best_cost);
-                (best, best_cost, duration.as_secs_f32())
             }
-            _ => panic!("Extracting mode not supported"),
-        };
+        }
+        None => println!("Warning: none of the {} sampled ops could be re-measured", sample.len()),
+    }
 
-        // Evaluation starting and extracted graph runtime, save graphs
-        let runner_start = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&start);
-        let runner_ext = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&best);
+    if let Some(outf) = matches.value_of("out_file") {
+        let data = json!({
+            "cost_cache": cache_file,
+            "runtime_model": cost_model.runtime_model().name(),
+            "num_skipped": num_skipped,
+            "drifts": drifts,
+            "summary": summary,
+        });
+        write(outf, serde_json::to_string(&data).unwrap()).expect("Unable to write file");
+    }
+}
 
-        if save_graph != "none" {
-            let start_filename = Path::new(output_directory).join("start.svg");
-            runner_start.egraph.dot().to_svg(start_filename).unwrap();
+/// Reduces a failing input graph to a small reproducer
+///
+/// Runs the command of reduce_command on smaller and smaller graphs (see
+/// reduce.rs), and writes the smallest graph that still fails to reduced.txt
+/// in the output directory.
+fn reduce_failure(matches: clap::ArgMatches) {
+    let start = load_model(&matches);
+    let command = matches
+        .value_of("reduce_command")
+        .expect("Pls supply the command reproducing the failure with --reduce_command.");
+    let pattern = matches.value_of("reduce_match");
+    let output_directory = Path::new(matches.value_of("output_dir").unwrap());
+    let graph_file = output_directory.join("reduce_candidate.txt");
+    let log_file = output_directory.join("reduce_candidate.log");
 
-            let ext_filename = Path::new(output_directory).join("ext.svg");
-            runner_ext.egraph.dot().to_svg(ext_filename).unwrap();
+    let fails = |graph: &RecExpr<Mdl>| -> bool {
+        write(&graph_file, to_text(graph, None)).expect("Unable to write file");
+        let log = File::create(&log_file).expect("Unable to write file");
+        let status = run_solver(
+            Command::new("sh")
+                .arg("-c")
+                .arg(command.replace("{}", graph_file.to_str().unwrap()))
+                .stdout(log.try_clone().unwrap())
+                .stderr(log),
+        );
+        match status {
+            Ok(status) if status.success() => false,
+            Ok(_) => pattern.map_or(true, |p| read_to_string(&log_file).map_or(false, |out| out.contains(p))),
+            Err(e) => {
+                println!("Warning: the command did not run: {}", e);
+                false
+            }
         }
+    };
 
-        let time_start = get_full_graph_runtime(&runner_start, false);
-        println!("Start graph runtime: {}", time_start);
+    if !fails(&start) {
+        eprintln!("The input graph does not fail with the command, nothing to reduce");
+        std::process::exit(1);
+    }
+    let start_time = Instant::now();
+    let reduction = reduce(&start, fails);
+    let reduced_file = output_directory.join("reduced.txt");
+    write(&reduced_file, to_text(&reduction.graph, None)).expect("Unable to write file");
+    let _ = remove_file(&graph_file);
+    println!(
+        "Reduced the graph from {} to {} nodes in {} tries ({:.1}s), written to {}",
+        start.as_ref().len(),
+        reduction.graph.as_ref().len(),
+        reduction.num_tries,
+        start_time.elapsed().as_secs_f32(),
+        reduced_file.display()
+    );
+}
 
-        let time_ext = get_full_graph_runtime(&runner_ext, true);
-        println!("Extracted graph runtime: {}", time_ext);
+/// Compares two rule files on the same model and limits
+///
+/// Saturates the model with the rules of each file (and the pre-defined
+/// rules, without multi-pattern rules), extracts greedily, and reports the
+/// size of the EGraph, the cost of the extracted graph and the time taken for
+/// both, and the differences between the two.
+fn compare_rules(matches: clap::ArgMatches) {
+    env_logger::init();
 
-        if matches.is_present("export_models") {
-            let filename_start = Path::new(output_directory).join("start.model");
-            save_model(&runner_start, filename_start.to_str().unwrap());
+    if let Err(e) = check_taso_abi() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-            let filename_optimized = Path::new(output_directory).join("optimized.model");
-            save_model(&runner_ext, filename_optimized.to_str().unwrap());
-        }
+    let rule_files = [
+        matches.value_of("rules").expect("Pls supply rewrite rules file."),
+        matches.value_of("rules_b").expect("Pls supply the second rewrite rules file."),
+    ];
+    let n_sec = matches.value_of("n_sec").unwrap().parse::<u64>().unwrap();
+    let iter_limit = matches.value_of("n_iter").unwrap().parse::<usize>().unwrap();
+    let node_limit = matches.value_of("n_nodes").unwrap().parse::<usize>().unwrap();
+    let filter_after = !matches.is_present("filter_before");
+    let cost_model = CostModel::with_setting(matches.is_present("all_weight_only"))
+        .with_objective(get_objective(&matches));
 
-        if let Some(outf) = matches.value_of("out_file") {
-            let filename = Path::new(output_directory).join(outf);
-            let mut file = OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(filename)
-                .unwrap();
+    let start = load_model(&matches);
+    let mut reports = vec![];
+    for rule_file in rule_files.iter() {
+        let learned_rules = read_to_string(rule_file).expect("Something went wrong reading the rule file");
+        let split_rules: Vec<&str> = learned_rules.split("\n").chain(PRE_DEFINED_RULES.iter().map(|&x| x)).collect();
+        let num_rules = split_rules.len();
+        let rules = rules_from_str(split_rules, filter_after);
 
-            // Stats to write: original runtime, optimized runtime, saturation time, extraction time,
-            // number of nodes, number of eclasses, number of possible programs
-            let data = json!({
-                "runner_stop_reason": runner.stop_reason.as_ref().unwrap(),
-                "runner_time": sat_duration.as_secs_f32(),
-                "num_iterations": num_iter_sat,
-                "num_enodes": num_enodes,
-                "num_classes": num_classes,
-                "avg_nodes_per_class": avg_nodes_per_class,
-                "num_edges": num_edges,
-                "num_programs": num_programs,
-                "extraction_time": ext_secs,
-                "original_runtime": time_start,
-                "optimized_runtime": time_ext,
-            });
-            let sol_data_str = serde_json::to_string(&data).expect("Fail to convert json to string");
+        let start_time = Instant::now();
+        let runner = Runner::<Mdl, TensorAnalysis, ()>::default()
+            .with_node_limit(node_limit)
+            .with_time_limit(Duration::new(n_sec, 0))
+            .with_iter_limit(iter_limit)
+            .with_expr(&start)
+            .run(&rules[..]);
+        let sat_duration = start_time.elapsed();
 
-            if let Err(e) = writeln!(file, "{}", sol_data_str) {
-                eprintln!("Couldn't write to file: {}", e);
-            }
-        }
+        let start_time = Instant::now();
+        let tnsr_cost = TensorCost::new(&runner.egraph, &cost_model, true);
+        let (best_cost, _) = Extractor::new(&runner.egraph, tnsr_cost).find_best(runner.roots[0]);
+        let ext_duration = start_time.elapsed();
+
+        reports.push(json!({
+            "rules": rule_file,
+            "num_rules": num_rules,
+            "num_iterations": runner.iterations.len(),
+            "stop_reason": format!("{:?}", runner.stop_reason.as_ref().unwrap()),
+            "num_enodes": runner.egraph.total_number_of_nodes(),
+            "num_eclasses": runner.egraph.number_of_classes(),
+            "cost": best_cost,
+            "saturation_sec": sat_duration.as_secs_f32(),
+            "extraction_sec": ext_duration.as_secs_f32(),
+        }));
+    }
+
+    let keys = ["num_rules", "num_iterations", "num_enodes", "num_eclasses", "cost", "saturation_sec", "extraction_sec"];
+    println!("{:<16} {:>14} {:>14} {:>14}", "", "rules", "rules_b", "difference");
+    for key in keys.iter() {
+        let (a, b) = (reports[0][key].as_f64().unwrap(), reports[1][key].as_f64().unwrap());
+        println!("{:<16} {:>14.4} {:>14.4} {:>14.4}", key, a, b, b - a);
+    }
+    println!(
+        "{:<16} {:>14} {:>14}",
+        "stop_reason",
+        reports[0]["stop_reason"].as_str().unwrap(),
+        reports[1]["stop_reason"].as_str().unwrap()
+    );
+
+    if let Some(outf) = matches.value_of("out_file") {
+        write(outf, serde_json::to_string(&reports).unwrap()).expect("Unable to write file");
     }
 }
 
-/// Extract the optimal graph from EGraph by ILP
+/// Applies a rule at a node of the input graph and reports the change in cost
 ///
-/// This function prepares the data for the ILP formulation, save it as json, call the python
-/// script to read the data + solve ILP + save the solved results. After the python script
-/// finishes, it reads back the solved result and construct the RecExpr for the optimized graph.
-fn extract_by_ilp(
-    egraph: &EGraph<Mdl, TensorAnalysis>,
-    root: Id,
-    matches: &clap::ArgMatches,
-    cost_model: &CostModel,
-) -> (RecExpr<Mdl>, f32, f32) {
-    let binding = std::thread::current();
-    let thread_name = binding.name().unwrap();
-    // Prepare data for ILP formulation, save to json
-    let (m_id_map, e_m, h_i, cost_i, g_i, root_m, i_to_nodes, blacklist_i) =
-        prep_ilp_data(egraph, root, cost_model);
-
-    let data = json!({
-        "e_m": e_m,
-        "h_i": h_i,
-        "cost_i": cost_i,
-        "g_i": g_i,
-        "root_m": root_m,
-        "blacklist_i": blacklist_i,
-    });
-    let data_str = serde_json::to_string(&data).expect("Fail to convert json to string");
+/// Without a rule, tries every rule matching at the node, cheapest result
+/// first. Writes the results, with the rewritten graphs in the textual
+/// format, to out_file.
+fn what_if_rewrite(matches: clap::ArgMatches) {
+    env_logger::init();
 
-    let output_directory = matches.value_of("output_dir").unwrap();
-    let filename = Path::new(output_directory).join("ilp_data_".to_owned() + thread_name + ".json");
-    write(filename, data_str).expect("Unable to write file");
+    if let Err(e) = check_taso_abi() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let rule_file = matches.value_of("rules").expect("Pls supply rewrite rules file.");
+    let learned_rules = read_to_string(rule_file).expect("Something went wrong reading the rule file");
+    let split_rules: Vec<&str> = learned_rules.split("\n").chain(PRE_DEFINED_RULES.iter().map(|&x| x)).collect();
+    let rules = rules_from_str(split_rules, !matches.is_present("filter_before"));
+    let node = matches
+        .value_of("what_if_node")
+        .expect("Pls supply the node to apply the rule at.")
+        .parse::<usize>()
+        .unwrap();
+    let cost_model = CostModel::with_setting(matches.is_present("all_weight_only"))
+        .with_objective(get_objective(&matches));
+
+    let start = load_model(&matches);
+    let rule_names = match matches.value_of("what_if_rule") {
+        Some(rule) => vec![rule.to_string()],
+        None => matching_rules(&start, &rules, node),
+    };
+    println!("Trying {} rules at node {}: {}", rule_names.len(), node, start.as_ref()[node]);
 
-    let initialize = matches.is_present("initial_with_greedy");
-    if initialize {
-        // Get node_to_i map
-        let node_to_i: HashMap<Mdl, usize> = (&i_to_nodes)
+    let mut results = vec![];
+    for rule in rule_names.iter() {
+        match what_if(&start, &rules, rule, node, &cost_model) {
+            Ok(result) => results.push(result),
+            Err(e) => println!("{}: {}", rule, e),
+        }
+    }
+    results.sort_by(|a, b| a.delta().partial_cmp(&b.delta()).unwrap());
+    println!("{:<12} {:>8} {:>12} {:>12} {:>12}", "rule", "matches", "before", "after", "delta");
+    for result in results.iter() {
+        println!(
+            "{:<12} {:>8} {:>12.4} {:>12.4} {:>12.4}",
+            result.rule,
+            result.num_matches,
+            result.cost_before,
+            result.cost_after,
+            result.delta()
+        );
+    }
+
+    if let Some(outf) = matches.value_of("out_file") {
+        let data: Vec<Value> = results
             .iter()
-            .enumerate()
-            .map(|(i, node)| (node.clone(), i))
+            .map(|result| {
+                json!({
+                    "rule": result.rule,
+                    "node": result.node,
+                    "num_matches": result.num_matches,
+                    "cost_before": result.cost_before,
+                    "cost_after": result.cost_after,
+                    "delta": result.delta(),
+                    "graph": to_text(&result.expr, None),
+                })
+            })
             .collect();
+        write(outf, serde_json::to_string(&data).unwrap()).expect("Unable to write file");
+    }
+}
+
+/// Prints the substitutions lowering the cost of the input graph, for
+/// engineers to apply by hand, without writing a new graph (see suggest.rs)
+fn suggest(matches: clap::ArgMatches) {
+    env_logger::init();
+
+    if let Err(e) = check_taso_abi() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let rule_file = matches.value_of("rules").expect("Pls supply rewrite rules file.");
+    let learned_rules = read_to_string(rule_file).expect("Something went wrong reading the rule file");
+    let split_rules: Vec<&str> = learned_rules.split("\n").chain(PRE_DEFINED_RULES.iter().map(|&x| x)).collect();
+    let rules = rules_from_str(split_rules, !matches.is_present("filter_before"));
+    let objective = get_objective(&matches);
+    let cost_model = CostModel::with_setting(matches.is_present("all_weight_only")).with_objective(objective);
+    let top = matches.value_of("suggest_top").unwrap().parse::<usize>().unwrap();
+    let min_gain = matches.value_of("suggest_min_gain").unwrap().parse::<f32>().unwrap();
+
+    let start = load_model(&matches);
+    let suggestions = suggest_substitutions(&start, &rules, &cost_model, min_gain);
+    if suggestions.is_empty() {
+        println!("No single rewrite lowers the cost of the graph");
+    }
+    for (i, suggestion) in suggestions.iter().take(top).enumerate() {
+        println!("{:>3}. {}", i + 1, suggestion.describe(objective.unit()));
+    }
+
+    if let Some(outf) = matches.value_of("out_file") {
+        let data: Vec<Value> = suggestions
+            .iter()
+            .map(|s| {
+                json!({
+                    "rule": s.rule,
+                    "node": s.node,
+                    "replaced": s.replaced,
+                    "added": s.added,
+                    "delta": s.delta,
+                })
+            })
+            .collect();
+        write(outf, serde_json::to_string(&data).unwrap()).expect("Unable to write file");
+    }
+}
+
+/// Main procedure to run optimization
+///
+/// Gets input graph and rewrite rules; runs saturation with TensorAnalysis dealing with metadata; runs
+/// greedy extraction with TensorCost getting the cost per node/op; evaluates
+/// full graph runtime of the starting graph and extracted graph.
+fn optimize(matches: clap::ArgMatches, stage: Stage) {
+    env_logger::init();
+
+    // Fail early if the linked TASO library does not match the bindings
+    if let Err(e) = check_taso_abi() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // A measurement hanging past the timeout restarts the run without it
+    if let Some(secs) = matches.value_of("measure_timeout") {
+        let timeout = Duration::from_secs_f64(secs.parse::<f64>().unwrap());
+        let hung_file = match matches.value_of("hung_ops") {
+            Some(file) => PathBuf::from(file),
+            None => Path::new(matches.value_of("output_dir").unwrap()).join("hung_ops.txt"),
+        };
+        match start_watchdog(timeout, hung_file) {
+            Ok(0) => (),
+            Ok(num_hung) => println!("{} ops hung in previous runs, they are infeasible", num_hung),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // Sample the GPU memory for the whole run
+    let gpu_memory = if matches.is_present("resource_summary") {
+        GpuMemorySampler::start(0, 100)
+            .map_err(|e| println!("Warning: the peak GPU memory is not recorded: {}", e))
+            .ok()
+    } else {
+        None
+    };
+
+    let save_graph = matches.value_of("save_graph").unwrap();
+    let packed_attrs = matches.is_present("packed_attrs");
+    let output_directory = matches.value_of("output_dir").unwrap();
+
+    // Warn if output directory already exists, otherwise create it
+    if Path::new(output_directory).exists() {
+        println!("You are overwriting existing data!");
+    } else {
+        create_dir_all(output_directory);
+    }
+
+    // The extraction has to restore the EGraph as it was saturated
+    let artifact = match stage {
+        Stage::All => None,
+        _ => Some(matches.value_of("artifact").expect("Pls supply the artifact directory with --artifact.")),
+    };
+    let settings = save_settings(&matches, stage, output_directory);
+    let optimizer = Optimizer::new(optimizer_settings(&matches, stage));
+    // The rules the selection enabled, to match them to their priors
+    let enabled_rules: Vec<String> = optimizer.rule_strs().to_vec();
+    let rule_strs: Vec<&str> = enabled_rules.iter().map(|r| r.as_str()).collect();
+
+    let (start, layer_names) = load_model_with_names(&matches);
+    bundle::record_input(&start);
+    bundle::log_stage(&format!("loaded the input graph, {} nodes", start.as_ref().len()));
+    init_weights(&matches, &start);
+
+    // Check the expected shapes, before the graph is packed
+    let shape_assertions = matches.value_of("shape_assertions").map(|file| {
+        ShapeAssertions::load(file).unwrap_or_else(|e| bundle::exit_failure(&e))
+    });
+    let start_tensors = tensor_names(&start, &layer_names);
+    if let Some(assertions) = &shape_assertions {
+        let runner_start = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&start);
+        check_shape_assertions(assertions, &start_tensors, &expr_shapes(&runner_start.egraph, &start), true, "input graph");
+        println!("{} shape assertions hold on the input graph", assertions.shapes.len());
+    }
+
+    if let Some(replay_file) = matches.value_of("replay") {
+        replay_graph(&matches, replay_file, &start);
+        return;
+    }
+
+    // Look up the best graph previous runs found for this model
+    let library = matches.value_of("library").map(GraphLibrary::open);
+    let start_hash = model_hash(&start);
+    let library_key = format!(
+        "{}_{}",
+        matches.value_of("library_target").unwrap(),
+        matches.value_of("objective").unwrap()
+    );
+    let previous_best = match &library {
+        Some(library) if matches.is_present("library_union") => library.best_graph(&start_hash, &library_key),
+        _ => None,
+    };
+    let taso_seed = if matches.is_present("taso_seed") { taso_seed(&matches, &start) } else { None };
+
+    let start = if packed_attrs { pack_expr(&start) } else { start };
+
+    // Size the limits to fit the time budget, from a short probe saturation
+    let budget_plan = matches
+        .value_of("time_budget")
+        .map(|budget| plan_time_budget(&matches, budget.parse::<f32>().unwrap(), &optimizer, &start, output_directory));
+    let n_sec = match &budget_plan {
+        Some((plan, _)) => plan.saturation_sec,
+        None => matches.value_of("n_sec").unwrap().parse::<u64>().unwrap(),
+    };
+    let iter_limit = matches
+        .value_of("n_iter")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let node_limit = match &budget_plan {
+        Some((plan, _)) => plan.node_limit,
+        None => matches.value_of("n_nodes").unwrap().parse::<usize>().unwrap(),
+    };
+    let ilp_time_sec = match &budget_plan {
+        Some((plan, _)) => plan.ilp_sec,
+        None => matches.value_of("ilp_time_sec").map(|t| t.parse::<u64>().unwrap()),
+    };
+    let optimizer = optimizer.with_limits(iter_limit, n_sec, node_limit).add_graph(&start);
+
+    // Optimize the regions of supported ops between opaque ops (or the
+    // suggested parts) separately
+    let parts = matches
+        .value_of("partition_parts")
+        .map(|num_parts| suggest_parts(&start, num_parts.parse::<usize>().unwrap()));
+    if matches.is_present("partition") && (has_opaque(&start) || parts.is_some()) {
+        let start_time = Instant::now();
+        let (best, num_regions) =
+            optimizer.optimize_partitioned(parts.as_ref().map(|(part_of, shapes)| (&part_of[..], &shapes[..])));
+        println!("Optimized {} regions in {:?}", num_regions, start_time.elapsed());
+
+        let runner_start = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&start);
+        let runner_ext = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&best);
+        println!("Start graph runtime: {}", get_full_graph_runtime(&runner_start, false));
+        println!("Extracted graph runtime: {}", get_full_graph_runtime(&runner_ext, true));
+        write_graph_output(&matches, &best, packed_attrs);
+        return;
+    }
+
+    // Restrict the rules to the hot region of the profile
+    let hot_region = matches.value_of("profile").map(|profile_file| hot_region(&matches, profile_file, &start));
+    let mut multi_patterns = optimizer.multi_patterns(output_directory);
+    multi_patterns.region = hot_region.clone();
+    let mut multi_patterns = multi_patterns.with_interleave(!matches.is_present("multi_prepass"));
+    if let Some(round_limit) = matches.value_of("node_multi_round") {
+        multi_patterns = multi_patterns.with_round_node_limit(round_limit.parse::<usize>().unwrap());
+    }
+    let multi_start_iter = matches.value_of("multi_start_iter").unwrap().parse::<usize>().unwrap();
+    let mut multi_patterns = multi_patterns.with_start_iteration(multi_start_iter);
+    let growth_limits = get_growth_limits(&matches);
+    let rule_growth = RuleGrowth::default();
+    if let Some(limits) = &growth_limits {
+        multi_patterns = multi_patterns.with_growth_limits(limits.clone(), rule_growth.clone());
+    }
+    let capped_rules = CappedRules::default();
+    let multi_patterns = multi_patterns.with_capped_rules(capped_rules.clone());
+
+    // Record the power while TASO measures ops
+    let analysis_settings = AnalysisSettings {
+        condition_cache: !matches.is_present("no_condition_cache"),
+        measure_power: matches.is_present("measure_power"),
+        recomputed_ops: matches.value_of("recompute_ops").map(recomputed_ops).unwrap_or_default(),
+    };
+    let analysis = TensorAnalysis::with_settings(&analysis_settings).unwrap_or_else(|e| panic!("{}", e));
+    let weight_cache_mb = matches.value_of("weight_cache_mb").unwrap().parse::<usize>().unwrap();
+    set_transform_cache_size(weight_cache_mb << 20);
+
+    // Union the best graph of previous runs into the starting EGraph
+    let mut optimizer = optimizer;
+    if let Some(previous) = &previous_best {
+        let previous = if packed_attrs { pack_expr(previous) } else { previous.clone() };
+        optimizer = optimizer.add_graph(&previous);
+        println!("Added the best graph of previous runs to the starting EGraph");
+    }
+    if let Some(seed) = &taso_seed {
+        let seed = if packed_attrs { pack_expr(seed) } else { seed.clone() };
+        optimizer = optimizer.add_graph(&seed);
+        println!("Added TASO's optimized graph to the starting EGraph");
+    }
+    let runner = optimizer.runner(analysis, Some(multi_patterns));
+
+    let rule_priors = matches.value_of("rule_priors").map(|file| {
+        let priors = RulePriors::load(file).unwrap_or_else(|e| panic!("{}", e));
+        println!("Rule priors of {} rules", priors.rules.len());
+        priors
+    });
+    let growth = growth_limits.map(|limits| (limits, rule_growth.clone()));
+    let runner = with_rule_scheduler(runner, hot_region, rule_priors.as_ref(), &rule_strs, growth, &capped_rules);
+    let do_filter_after = optimizer.settings().no_cycle && optimizer.settings().filter_after;
+    let (runner, hooks) = with_saturation_hooks(&matches, runner, &start, do_filter_after);
+
+    let load_egraph = match (stage, artifact) {
+        (Stage::Extract, Some(dir)) => Some(artifact_egraph(dir).to_str().unwrap().to_string()),
+        _ => matches.value_of("load_egraph").map(String::from),
+    };
+    let checkpoint = load_egraph.map(|file| {
+        let checkpoint = EGraphCheckpoint::load(&file).unwrap_or_else(|e| panic!("{}", e));
+        println!("Loaded an EGraph of {} nodes, skipping saturation", checkpoint.num_nodes());
+        checkpoint
+    });
+    // A loaded EGraph reports the saturation of the run that saved it
+    let mut optimizer = match &checkpoint {
+        Some(checkpoint) => {
+            if checkpoint.analysis != analysis_settings {
+                println!("Warning: the EGraph is analyzed with the settings of its saturation, not those of this run");
+            }
+            let mut optimizer = optimizer
+                .restore(runner, checkpoint)
+                .unwrap_or_else(|e| panic!("Could not restore the EGraph: {}", e));
+            if hooks.frozen.iter().any(|f| *f) {
+                let egraph = &mut optimizer.saturated_mut().egraph;
+                egraph.analysis.frozen_nodes = frozen_enodes(egraph, &start, &hooks.frozen);
+            }
+            optimizer
+        }
+        None => optimizer.run(runner),
+    };
+    set_graceful_interrupt(false);
+    let cancelled = interrupted();
+    if cancelled {
+        println!("Interrupted: extracting the EGraph so far greedily");
+    }
+    optimizer.stats_mut().interrupted = cancelled;
+    let sat_duration = Duration::from_secs_f32(optimizer.stats().phase_secs["saturation"]);
+    let num_iter_sat = match &checkpoint {
+        Some(checkpoint) => checkpoint.num_iterations,
+        None => optimizer.saturated().iterations.len() - 1,
+    };
+    if let Some(pruner) = &hooks.pruner {
+        canonicalize_pruned(&mut optimizer.saturated_mut().egraph);
+        let num_pruned = pruner.lock().unwrap().num_pruned;
+        optimizer.stats_mut().num_pruned = num_pruned;
+        println!("Pruned {} enodes from eclasses of more than {} enodes", num_pruned, pruner.lock().unwrap().max_enodes);
+    }
+    let mut stats = optimizer.stats().clone();
+    let runner = optimizer.saturated();
+    if let Some(server) = &hooks.live {
+        server.update(runner, true);
+    }
+    if let Some(file) = matches.value_of("save_egraph") {
+        EGraphCheckpoint::take(runner, sat_duration.as_secs_f32()).save(file);
+        println!("Saved the EGraph to {}", file);
+    }
+    if let (Stage::Saturate, Some(dir)) = (stage, artifact) {
+        let input = if packed_attrs { unpack_expr(&start) } else { start.clone() };
+        save_artifact(dir, &to_text(&input, None), &settings).unwrap_or_else(|e| panic!("{}", e));
+        EGraphCheckpoint::take(runner, sat_duration.as_secs_f32()).save(artifact_egraph(dir).to_str().unwrap());
+        println!(
+            "Saturated in {:?} ({} iterations, {} nodes), saved to {}",
+            sat_duration,
+            num_iter_sat,
+            runner.egraph.total_size(),
+            dir
+        );
+        return;
+    }
+
+    saturation_report(runner, sat_duration.as_secs_f32(), num_iter_sat, checkpoint.is_some(), output_directory);
+    if let (Some(mut priors), None) = (rule_priors, &checkpoint) {
+        priors.record_run(&rule_strs, runner);
+        priors.save(matches.value_of("rule_priors").unwrap());
+    }
+    bundle::record_egraph(&runner.egraph);
+    bundle::log_stage(&format!(
+        "saturated in {} iterations, {} nodes, stopped: {:?}",
+        num_iter_sat,
+        runner.egraph.total_size(),
+        runner.stop_reason.as_ref().unwrap()
+    ));
+    rule_growth_report(&rule_growth, output_directory);
+    if checkpoint.is_none() {
+        capped_rules_report(&capped_rules, runner, output_directory);
+    }
 
-        let tnsr_cost = TensorCost::new(
+    if let Some(recorder) = &hooks.snapshots {
+        recorder.lock().unwrap().record(runner).unwrap();
+        println!("EGraph diffs of each iteration written to egraph_diffs.jsonl");
+    }
+    if let Some(recorder) = &hooks.progress {
+        let mut recorder = recorder.lock().unwrap();
+        recorder.record(runner, true).unwrap();
+        println!("Wrote {} best graphs so far, their costs to progress.jsonl", recorder.points.len());
+    }
+
+    // Save egraph
+    let root = optimizer.root();
+    if save_graph == "all" {
+        let filename = Path::new(output_directory).join("tensat.svg");
+        optimizer.egraph().dot().to_svg(filename).unwrap();
+    }
+    let mut data = run_data(runner, sat_duration.as_secs_f32(), num_iter_sat);
+
+    if matches.is_present("saturation_only") {
+        if let Some(outf) = matches.value_of("out_file") {
+            append_line(output_directory, outf, &serde_json::to_string(&data).expect("Fail to convert json to string"));
+        }
+    } else {
+        // Run extraction
+        let extract_mode = if cancelled { "greedy" } else { matches.value_of("extract").unwrap() };
+        let cost_cache = matches
+            .value_of("cost_cache")
+            .map(|filename| Arc::new(RuntimeCache::load(filename).unwrap_or_else(|e| panic!("{}", e))));
+        let mut cost_model = extraction_cost_model(&matches, &mut optimizer, &cost_cache, output_directory);
+        let egraph = optimizer.egraph();
+        let strategy = extraction_strategy(&matches, extract_mode, ilp_time_sec);
+        let (best, best_cost, ext_secs) = optimizer.extract_with(&cost_model, &strategy, max_memory(&matches));
+        println!("Extractor complete!");
+        println!("  Strategy: {}", strategy.name());
+        println!("  Time taken: {}s", ext_secs);
+        println!("  Best cost: {:?}", best_cost);
+        bundle::log_stage(&format!("extracted with {} in {}s, cost {}", extract_mode, ext_secs, best_cost));
+        let result = optimizer.record_extraction(best, extract_mode, ext_secs, &cost_model);
+        stats = result.stats;
+        if matches!(extract_mode, "ilp" | "cbc" | "egg_ilp") {
+            stats.record_phase("ilp_solve", ext_secs);
+        }
+        let mut best = result.graph;
+        let memory_mb = graph_memory(egraph, &best) as f64 / (1024.0 * 1024.0);
+        println!("Activation memory of the extracted graph: {:.2} MB", memory_mb);
+        if let Some(budget) = matches.value_of("max_memory") {
+            if memory_mb > budget.parse::<f64>().unwrap() {
+                println!("Warning: the extracted graph does not fit in max_memory ({} MB)", budget);
+            }
+        }
+
+        // How far the faster strategies are from the optimum
+        if matches.is_present("ilp_gap") && !matches!(extract_mode, "ilp" | "cbc") && !cancelled {
+            let ilp = extraction_strategy(&matches, "cbc", ilp_time_sec);
+            ilp_gap_report(egraph, root, &best, &cost_model, extract_mode, ext_secs, &ilp);
+        }
+        // The other extractions are in process, with the strategy of the run
+        // if it is one
+        let in_process_strategy = match extract_mode {
+            mode if is_in_process(mode) => strategy.clone(),
+            _ => ExtractionStrategy::Greedy,
+        };
+        if matches.is_present("pareto_front") && !cancelled {
+            pareto_front_report(egraph, root, &mut cost_model, &in_process_strategy, packed_attrs, output_directory);
+        }
+        // The saturation is shared by all targets, only the extraction is
+        // done for each
+        if let Some(targets) = matches.value_of("targets") {
+            let targets: Vec<Target> = targets
+                .split(',')
+                .map(|t| match t.trim() {
+                    "local" => Target::Local,
+                    name_or_file => Target::Profile(load_profile(name_or_file).unwrap_or_else(|e| panic!("{}", e))),
+                })
+                .collect();
+            let target_cost_model = |runtime_model: Box<dyn RuntimeModel>| {
+                let target_model = optimizer
+                    .cost_model()
+                    .with_runtime_model(runtime_model)
+                    .with_weights(get_weights(&matches));
+                let target_model = if matches.is_present("tied_weights") {
+                    target_model.with_tied_weights(TiedWeights::new(egraph, &start))
+                } else {
+                    target_model
+                };
+                if egraph.analysis.frozen_nodes.is_empty() {
+                    target_model
+                } else {
+                    target_model.with_frozen(FrozenGraph::new(egraph))
+                }
+            };
+            targets_report(egraph, root, &targets, target_cost_model, &in_process_strategy, packed_attrs, output_directory);
+        }
+        if let Some(cache) = &cost_cache {
+            let (hits, computed) = cache.stats();
+            println!("Runtime cache: {} runtimes reused, {} computed, {} cached", hits, computed, cache.num_entries());
+            cache
+                .save(matches.value_of("cost_cache").unwrap())
+                .unwrap_or_else(|e| panic!("{}", e));
+        }
+
+        // Record the ILP rate for the budgets of later runs
+        if let (Some((_, rates)), "ilp" | "cbc") = (&budget_plan, extract_mode) {
+            let observed = ObservedRates {
+                ilp_nodes_per_sec: egraph.total_size() as f32 / ext_secs.max(1e-3),
+                ..*rates
+            };
+            save_rates(output_directory, &observed);
+        }
+
+        if best_cost >= INFEASIBLE_COST {
+            panic!("No feasible graph to extract: every alternative of some eclass is infeasible on TASO");
+        }
+
+        // Approximate rules are only acceptable if the end-to-end error is within budget
+        if !optimizer.settings().selection.approx_rules.is_empty() {
+            let budget = matches.value_of("error_budget").unwrap().parse::<f32>().unwrap();
+            if !approximation_report(&start, &best, budget) {
+                best = start.clone();
+            }
+        }
+
+        // The whole pipeline (rules, cycle filtering, extraction) has to keep
+        // the outputs of the graph
+        if let Some(tolerance) = matches.value_of("check_equivalence") {
+            let tolerance = tolerance.parse::<f32>().unwrap();
+            let backend = matches.value_of("equivalence_backend").unwrap();
+            if let Some(rel_err) = equivalence_report(&start, &best, packed_attrs, backend, output_directory) {
+                assert!(
+                    rel_err <= tolerance,
+                    "The optimized graph differs from the original graph: relative error {} exceeds {}",
+                    rel_err,
+                    tolerance
+                );
+            }
+        }
+
+        if let Some(library) = &library {
+            let graph = if packed_attrs { unpack_expr(&best) } else { best.clone() };
+            if library.record(&start_hash, &library_key, &graph, best_cost) {
+                println!("New best graph for model {} recorded in the library", start_hash);
+            }
+        }
+
+        // Evaluation starting and extracted graph runtime, save graphs
+        let runner_start = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&start);
+        let runner_ext = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&best);
+        graph_files(
             egraph,
-            cost_model,
-            true,
+            &cost_model,
+            &start,
+            &best,
+            &runner_start,
+            &runner_ext,
+            save_graph != "none",
+            matches.is_present("export_dot"),
+            matches.is_present("export_egraph_dot"),
+            output_directory,
         );
-        let extractor = Extractor::new(egraph, tnsr_cost);
-        let (i_list, m_list) = get_init_solution(egraph, root, &extractor, &g_i, &node_to_i);
 
-        // Store initial solution
-        let solution_data = json!({
-            "i_list": i_list,
-            "m_list": m_list,
-        });
-        let sol_data_str = serde_json::to_string(&solution_data).expect("Fail to convert json to string");
-        let filename = Path::new(output_directory).join("init_sol_".to_owned() + thread_name + ".json");
-        write(filename, sol_data_str).expect("Unable to write file");
+        // An assertion that held on the input graph but not here means a
+        // rewrite changed a shape
+        match &shape_assertions {
+            Some(_) if packed_attrs => println!("Warning: the shape assertions are not checked on the optimized graph with packed attributes"),
+            Some(assertions) => {
+                let tensors = carry_names(egraph, &start, &start_tensors, &best);
+                check_shape_assertions(assertions, &tensors, &expr_shapes(&runner_ext.egraph, &best), false, "optimized graph");
+                println!("The shape assertions hold on the optimized graph");
+            }
+            None => (),
+        }
+
+        let time_start = get_full_graph_runtime(&runner_start, false);
+        println!("Start graph runtime: {}", time_start);
+
+        let time_ext = get_full_graph_runtime(&runner_ext, true);
+        println!("Extracted graph runtime: {}", time_ext);
+        stats.original_runtime = Some(time_start);
+        stats.optimized_runtime = Some(time_ext);
+        if extract_mode == "ilp" {
+            stats.lower_bound = ilp_script_bound(output_directory);
+        }
+
+        // The runtimes of the ops miss what the backend does to the whole
+        // graph, e.g. fusing or overlapping kernels
+        let estimate = expr_runtime_estimate(egraph, &best, &cost_model);
+        stats.optimized_runtime_estimate = Some(estimate);
+        runtime_drift_report(
+            &mut get_device_profile(&matches),
+            matches.value_of("runtime_source").unwrap(),
+            estimate,
+            time_ext,
+            matches.value_of("drift_threshold").unwrap().parse::<f32>().unwrap(),
+            output_directory,
+        );
+        println!("Cost of doing nothing:\n{}", stats.runtime_summary());
+
+        if matches.is_present("speedup_report") {
+            speedup_report(egraph, &start, &best, &cost_model, time_start - time_ext, output_directory);
+        }
+        if matches.is_present("pareto") {
+            pareto_report(egraph, root, matches.is_present("all_weight_only"), get_power_watts(&matches), output_directory);
+        }
+
+        // The rewrites only saw the shapes at the batch value
+        let report_batches: Vec<i32> = matches
+            .values_of("shape_report")
+            .map_or(vec![], |batches| batches.map(|b| b.parse::<i32>().unwrap()).collect());
+        if is_batch_symbolic(&start) {
+            let mut batches = vec![2 * batch_value()];
+            batches.extend(report_batches.iter().cloned());
+            batch_validity_report(&best, &batches);
+        }
+        if matches.is_present("shape_report") {
+            shape_report(&start, &best, &report_batches, time_start > time_ext, output_directory);
+        }
+
+        if matches.is_present("compare_runtime_sources") {
+            let other = match matches.value_of("runtime_source").unwrap() {
+                "measured" => "analytical",
+                _ => "measured",
+            };
+            let other_model = optimizer.cost_model().with_runtime_model(get_runtime_model(&matches, other));
+            cost_model_comparison_report(egraph, root, &cost_model, &other_model, other, output_directory);
+        }
+
+        if let Some(models) = matches.values_of("joint_models") {
+            let combined = if packed_attrs { unpack_expr(&best) } else { best.clone() };
+            match split_models(&combined, models.count()) {
+                Some(optimized) => {
+                    for (k, model) in optimized.iter().enumerate() {
+                        let filename = Path::new(output_directory).join(format!("joint_model_{}.txt", k));
+                        write(filename, model.to_string()).expect("Unable to write file");
+                    }
+                }
+                None => println!("Warning: the outputs of the joint models were rewritten, not splitting the optimized graph"),
+            }
+        }
+
+        if let Some(replay_file) = matches.value_of("record_replay") {
+            let record = if packed_attrs {
+                ReplayRecord::new(&unpack_expr(&start), &unpack_expr(&best))
+            } else {
+                ReplayRecord::new(&start, &best)
+            };
+            record.save(Path::new(output_directory).join(replay_file).to_str().unwrap());
+        }
+
+        write_graph_output(&matches, &best, packed_attrs);
+
+        if matches.is_present("export_models") {
+            let onnx = OnnxExport {
+                script: matches.value_of("onnx_script"),
+                provider: matches.value_of("onnx_provider").unwrap().parse().unwrap(),
+                opset: matches.value_of("onnx_opset").unwrap().parse::<i64>().unwrap(),
+            };
+            export_models(
+                egraph,
+                &start,
+                &best,
+                &runner_start,
+                &runner_ext,
+                packed_attrs,
+                &onnx,
+                matches.is_present("profile_markers"),
+                output_directory,
+            );
+        }
+
+        if let Some(outf) = matches.value_of("out_file") {
+            data.insert(String::from("extraction_time"), json!(ext_secs));
+            data.insert(String::from("original_runtime"), json!(time_start));
+            data.insert(String::from("optimized_runtime"), json!(time_ext));
+            data.insert(String::from("objective"), json!(format!("{:?}", cost_model.objective())));
+            data.insert(String::from("extracted_cost"), json!(best_cost));
+            append_line(output_directory, outf, &serde_json::to_string(&data).expect("Fail to convert json to string"));
+        }
     }
 
-    // Call python script to run ILP
-    let order_var_int = matches.is_present("order_var_int");
-    let class_constraint = matches.is_present("class_constraint");
-    let no_order = matches.is_present("no_order");
-    let mut arg_vec = vec!["extractor/extract.py"];
-    if order_var_int {
-        arg_vec.push("--order_var_int");
+    if matches.is_present("resource_summary") {
+        let usage = ResourceUsage::collect(gpu_memory.as_ref());
+        println!("Resource usage:");
+        match usage.peak_rss_mb {
+            Some(mb) => println!("  Peak resident memory: {:.1} MB", mb),
+            None => println!("  Peak resident memory: unknown"),
+        }
+        match usage.peak_gpu_memory_mb {
+            Some(mb) => println!("  Peak GPU memory: {:.1} MB", mb),
+            None => println!("  Peak GPU memory: unknown"),
+        }
+        println!("  TASO graphs created: {}, freed: {}", usage.taso_graphs, usage.taso_graphs_freed);
+        println!("  TASO tensors created: {}, freed: {}", usage.taso_tensors, usage.taso_tensors_freed);
+        println!("  Rust-side tensors created: {}, freed: {}", usage.rust_tensors, usage.rust_tensors_freed);
+        println!("  Time measuring on the GPU: {:.2}s", usage.measure_secs);
+        stats.resources = Some(usage);
     }
-    if class_constraint {
-        arg_vec.push("--eclass_constraint");
+
+    if let Some(stats_file) = matches.value_of("stats_out") {
+        stats.save(Path::new(output_directory).join(stats_file).to_str().unwrap());
     }
-    if no_order {
-        arg_vec.push("--no_order");
+}
+
+/// Saves the settings of the run to settings.txt, one JSON object of the
+/// args per run, and enables the failure bundle. Mode extract exits if they
+/// differ from the settings of the saturation
+///
+/// # Returns
+///
+/// The settings
+fn save_settings(matches: &clap::ArgMatches, stage: Stage, output_directory: &str) -> Map<String, Value> {
+    let mut settings = Map::new();
+    for arg in &matches.args {
+        let key = arg.0.to_string();
+        let value = if !arg.1.vals.is_empty() {
+            Value::String(arg.1.vals[0].clone().into_string().unwrap())
+        } else {
+            Value::String(String::from("true"))
+        };
+        settings.insert(key, value);
     }
-    if initialize {
-        arg_vec.push("--initialize")
+
+    if let (Stage::Extract, Some(dir)) = (stage, matches.value_of("artifact")) {
+        let saturated = load_artifact_settings(dir).unwrap_or_else(|e| bundle::exit_failure(&e));
+        let mismatches = setting_mismatches(&saturated, &settings);
+        if !mismatches.is_empty() {
+            for m in mismatches.iter() {
+                eprintln!("The settings differ from the saturation: {}", m);
+            }
+            bundle::exit_failure(&format!("{} settings differ from the saturation", mismatches.len()));
+        }
     }
-    if let Some(time_lim) = matches.value_of("ilp_time_sec") {
-        arg_vec.push("--time_lim_sec");
-        arg_vec.push(time_lim);
+
+    let settings_data = serde_json::to_string(&settings).expect("Failed to convert json to string");
+    append_line(output_directory, "settings.txt", &settings_data);
+
+    if let Some(dir) = matches.value_of("failure_bundle") {
+        let files = ["model_file", "onnx_model", "taso_model", "rules", "multi_rules", "rules_file", "blocks_file", "rule_caps", "approx_rules", "profile", "replay"]
+            .iter()
+            .filter_map(|arg| matches.value_of(arg).map(String::from))
+            .filter(|file| file != STDIO)
+            .collect();
+        bundle::enable(dir, output_directory, Value::Object(settings.clone()), files);
     }
-    if let Some(num_thread) = matches.value_of("ilp_num_threads") {
-        arg_vec.push("--num_thread");
-        arg_vec.push(num_thread);
-    }
-    if let Some(output_dir) = matches.value_of("output_dir") {
-        arg_vec.push("--output_dir");
-        arg_vec.push(output_dir);
-    }
-    arg_vec.push("--thread_name");
-    arg_vec.push(thread_name);
-    let child = Command::new("python")
-        .args(&arg_vec)
-        .spawn()
-        .expect("failed to execute child");
-    let output = child.wait_with_output().expect("failed to get output");
-
-    if output.status.success() {
-        // Read back solved results, construct optimized graph
-        let filename = Path::new(output_directory).join("solved_".to_owned() + thread_name + ".json");
-        let solved_str = read_to_string(filename).expect("Something went wrong reading the solved file");
-        let solved_data: SolvedResults = serde_json::from_str(&solved_str).expect("JSON was not well-formatted");
-
-        let mut node_picked: HashMap<Id, Mdl> = HashMap::new();
-        for (i, x_i) in solved_data.solved_x.iter().enumerate() {
-            if *x_i == 1 {
-                let eclass_id = m_id_map[g_i[i]];
-                if node_picked.contains_key(&eclass_id) {
-                    println!("Duplicate node in eclass");
-                    println!("{}", node_picked.get(&eclass_id).unwrap());
-                    println!("{}", i_to_nodes[i]);
-                    continue;
-                }
-                //assert!(!node_picked.contains_key(&eclass_id));
-                node_picked.insert(eclass_id, i_to_nodes[i].clone());
+    settings
+}
+
+/// Gets the settings of the Optimizer of the optimize command from the args:
+/// the rules of the rule files, with the builtin rules unless replaced, and
+/// which of them to enable. The limits are set once the time budget is
+/// planned
+fn optimizer_settings(matches: &clap::ArgMatches, stage: Stage) -> OptimizerSettings {
+    // Mode extract defaults to the rules of the saturation
+    let artifact_rules = match stage {
+        Stage::Extract => matches
+            .value_of("artifact")
+            .and_then(|dir| load_artifact_settings(dir).ok())
+            .and_then(|saturated| saturated.get("rules").and_then(|r| r.as_str().map(String::from))),
+        _ => None,
+    };
+    let rule_file = matches
+        .value_of("rules")
+        .or_else(|| artifact_rules.as_deref())
+        .expect("Pls supply rewrite rules file.");
+
+    // Rules given at runtime, with or instead of the built-in rules
+    let user_rules = matches.value_of("rules_file").map(|file| {
+        let rule_file = load_rule_file(file).unwrap_or_else(|errors| panic!("Invalid rules in {}:\n{}", file, errors.join("\n")));
+        println!(
+            "Loaded {} rules and {} multi-pattern rules from {}",
+            rule_file.rules.len(),
+            rule_file.multi_rules.len(),
+            file
+        );
+        rule_file
+    });
+    let (builtin_rules, builtin_multi): (&[&str], &[&str]) = if matches.is_present("replace_builtin_rules") {
+        (&[], &[])
+    } else {
+        (PRE_DEFINED_RULES, PRE_DEFINED_MULTI)
+    };
+
+    // learned_rules are the learned rules from TASO, pre_defined_rules are the hand-specified rules from TASO
+    let learned_rules =
+        read_to_string(rule_file).expect("Something went wrong reading the rule file");
+    let mut rules: Vec<String> = learned_rules.split("\n").chain(builtin_rules.iter().cloned()).map(String::from).collect();
+    if let Some(rule_file) = &user_rules {
+        rules.extend(rule_file.rules.iter().cloned());
+    }
+    if matches.is_present("weight_layouts") {
+        rules.extend(WEIGHT_LAYOUT_RULES.iter().map(|r| r.to_string()));
+    }
+    // Rules of the ops registered at runtime
+    rules.extend(custom_rules());
+
+    // The learned multi-pattern rules we have are symmetric. Predefined ones
+    // are not
+    let mut multi_rules: Vec<(String, bool)> = match matches.value_of("multi_rules") {
+        Some(file) => read_to_string(file)
+            .expect("Something went wrong reading the rule file")
+            .split("\n")
+            .map(|r| (r.to_string(), /*symmetric=*/ true))
+            .collect(),
+        None => vec![],
+    };
+    multi_rules.extend(builtin_multi.iter().map(|r| (r.to_string(), /*symmetric=*/ false)));
+    if let Some(rule_file) = &user_rules {
+        multi_rules.extend(rule_file.multi_rule_pairs().into_iter().map(|(r, s)| (r.to_string(), s)));
+    }
+
+    let selection = RuleSelection {
+        quantized: matches.is_present("quantized"),
+        approx_rules: matches.value_of("approx_rules").map_or(vec![], |f| {
+            let approx_rules = read_to_string(f).expect("Something went wrong reading the approx rule file");
+            approx_rules.lines().map(String::from).collect()
+        }),
+        verify_trials: matches.value_of("verify_rules").map(|n| n.parse::<usize>().unwrap()),
+        safety: get_safety(matches),
+        packed_attrs: matches.is_present("packed_attrs"),
+    };
+    OptimizerSettings {
+        rules: rules,
+        multi_rules: multi_rules,
+        // The builtin rules are in the rules already, unless replaced
+        builtin_rules: false,
+        selection: selection,
+        bidirectional_rules: matches.is_present("bidirectional_rules"),
+        use_multi: matches.is_present("use_multi"),
+        iter_multi: matches.value_of("iter_multi").unwrap().parse::<usize>().unwrap(),
+        node_multi: matches.value_of("node_multi").unwrap().parse::<usize>().unwrap(),
+        no_cycle: matches.is_present("no_cycle"),
+        filter_after: !matches.is_present("filter_before"),
+        ignore_all_weight_only: matches.is_present("all_weight_only"),
+        objective: get_objective(matches),
+        // The runtimes are measured by the command, with the graphs saved
+        measure_runtime: false,
+        ..OptimizerSettings::default()
+    }
+}
+
+/// Sets the values of the weights before TASO creates any: their seed, and
+/// the values of --weights_file, which have to fit the graph
+fn init_weights(matches: &clap::ArgMatches, start: &RecExpr<Mdl>) {
+    let mut weights = WeightValues::default();
+    weights.seed = matches.value_of("seed").unwrap().parse::<u64>().unwrap();
+    if let Some(file) = matches.value_of("weights_file") {
+        weights.loaded = load_weight_file(file).unwrap_or_else(|e| bundle::exit_failure(&e));
+        let (errors, num_loaded) = weights.check(start);
+        if !errors.is_empty() {
+            for e in errors.iter() {
+                eprintln!("  {}", e);
             }
+            bundle::exit_failure(&format!("{} weights do not fit their loaded values", errors.len()));
+        }
+        println!("Loaded the values of {} weights", num_loaded);
+    }
+    set_weight_values(weights);
+}
+
+/// Exits if the shape assertions do not hold on a graph
+///
+/// # Parameters
+///
+/// - `tensors`: the names of the tensors of the graph
+/// - `shapes`: the shapes of its nodes
+/// - `report_missing`: whether an asserted tensor missing from the graph is
+///   an error
+/// - `graph`: which graph it is, for the messages
+fn check_shape_assertions(
+    assertions: &ShapeAssertions,
+    tensors: &HashMap<String, usize>,
+    shapes: &[Option<Vec<TensorShape>>],
+    report_missing: bool,
+    graph: &str,
+) {
+    let errors = assertions.check(tensors, shapes, report_missing);
+    if !errors.is_empty() {
+        eprintln!("Shape assertions do not hold on the {}:", graph);
+        for e in errors.iter() {
+            eprintln!("  {}", e);
         }
+        bundle::exit_failure(&format!("{} shape assertions do not hold on the {}", errors.len(), graph));
+    }
+}
+
+/// Renames the tensors of a recorded optimized graph to those of the input
+/// graph and writes it, skipping saturation
+fn replay_graph(matches: &clap::ArgMatches, replay_file: &str, start: &RecExpr<Mdl>) {
+    let record = ReplayRecord::load(replay_file).unwrap_or_else(|e| bundle::exit_failure(&e));
+    let best = replay(&record, start).unwrap_or_else(|e| {
+        eprintln!("The recorded optimization does not apply to the input graph: {}", e);
+        bundle::exit_failure(&format!("the recorded optimization does not apply to the input graph: {}", e));
+    });
+    let runner_start = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(start);
+    let runner_ext = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&best);
+    println!("Start graph runtime: {}", get_full_graph_runtime(&runner_start, false));
+    println!("Replayed graph runtime: {}", get_full_graph_runtime(&runner_ext, true));
+    write_graph_output(matches, &best, false);
+}
 
-        let mut expr = RecExpr::default();
-        let mut added_memo: HashMap<Id, Id> = Default::default();
-        let _ = construct_best_rec(&node_picked, root, &mut added_memo, egraph, &mut expr);
-        (expr, solved_data.time, solved_data.time)
+/// Runs TASO's own optimizer, its best graph is a seed of the EGraph
+///
+/// # Returns
+///
+/// TASO's optimized graph, or None if it could not be used
+fn taso_seed(matches: &clap::ArgMatches, start: &RecExpr<Mdl>) -> Option<RecExpr<Mdl>> {
+    let alpha = matches.value_of("taso_alpha").unwrap().parse::<f32>().unwrap();
+    let budget = matches.value_of("taso_budget").unwrap().parse::<i32>().unwrap();
+    match run_taso_baseline(start, alpha, budget) {
+        Ok(baseline) => {
+            println!(
+                "TASO's optimizer: {} ms -> {} ms (alpha {}, budget {})",
+                baseline.start_runtime, baseline.runtime, alpha, budget
+            );
+            bundle::log_stage("ran TASO's optimizer");
+            Some(baseline.expr)
+        }
+        Err(e) => {
+            println!("Warning: TASO's optimized graph is not used: {}", e);
+            None
+        }
+    }
+}
+
+/// Sizes the limits of the run to fit a time budget, from a short probe
+/// saturation and a measurement of the input graph
+///
+/// # Parameters
+///
+/// - `budget`: the time budget of the run, in seconds
+/// - `optimizer`: the optimizer, for its rules
+///
+/// # Returns
+///
+/// The plan, and the rates it is planned from
+fn plan_time_budget(
+    matches: &clap::ArgMatches,
+    budget: f32,
+    optimizer: &Optimizer,
+    start: &RecExpr<Mdl>,
+    output_directory: &str,
+) -> (BudgetPlan, ObservedRates) {
+    let budget_start = Instant::now();
+    let probe = Runner::<Mdl, TensorAnalysis, ()>::default()
+        .with_iter_limit(1)
+        .with_time_limit(Duration::from_secs_f32(PROBE_FRACTION * budget))
+        .with_expr(start);
+    let initial_nodes = probe.egraph.total_size();
+    let probe = probe.run(optimizer.rules());
+    let probe_sec = budget_start.elapsed().as_secs_f32();
+
+    let measure_start = Instant::now();
+    let _ = get_full_graph_runtime(&Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(start), false);
+    let rates = ObservedRates {
+        nodes_per_sec: (probe.egraph.total_size() - initial_nodes) as f32 / probe_sec.max(1e-3),
+        ilp_nodes_per_sec: load_ilp_rate(output_directory).unwrap_or(DEFAULT_ILP_NODES_PER_SEC),
+        measure_sec: measure_start.elapsed().as_secs_f32(),
+    };
+    let use_ilp = matches!(matches.value_of("extract").unwrap(), "ilp" | "cbc");
+    let plan = plan_budget(budget - budget_start.elapsed().as_secs_f32(), initial_nodes, &rates, use_ilp);
+    println!("Time budget of {}s: {:?} from {:?}", budget, plan, rates);
+    (plan, rates)
+}
+
+/// Suggests points to partition the graph at, see suggest_cuts
+///
+/// # Returns
+///
+/// The part of each node, and the shapes of the graph
+fn suggest_parts(start: &RecExpr<Mdl>, num_parts: usize) -> (Vec<Option<usize>>, Vec<Option<Vec<TensorShape>>>) {
+    let runner_start = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(start);
+    let shapes = expr_shapes(&runner_start.egraph, start);
+    let (cuts, part_of) = suggest_cuts(start, &shapes, num_parts);
+    println!("Suggested cuts into {} parts:", cuts.len() + 1);
+    for (j, cut) in cuts.iter().enumerate() {
+        let tensors: Vec<String> = cut.tensors.iter().map(|i| i.to_string()).collect();
+        println!(
+            "  Cut {}: after {} ops, {} tensors of {:.3} MB (nodes {})",
+            j + 1,
+            cut.num_before,
+            cut.tensors.len(),
+            cut.bytes as f64 / 1e6,
+            tensors.join(", ")
+        );
+    }
+    (part_of, shapes)
+}
+
+/// Gets the hot region of a profile, the eclasses the rules are restricted
+/// to. The EGraph is constructed the same way as the runner's, so the eclass
+/// Ids agree
+fn hot_region(matches: &clap::ArgMatches, profile_file: &str, start: &RecExpr<Mdl>) -> HotRegion {
+    let profile_str = read_to_string(profile_file).expect("Something went wrong reading the profile");
+    let profile = parse_profile(&profile_str).unwrap_or_else(|e| panic!("Could not parse the profile: {}", e));
+    let coverage = matches.value_of("profile_coverage").unwrap().parse::<f32>().unwrap();
+    let mut egraph = EGraph::new(TensorAnalysis::default());
+    egraph.add_expr(start);
+    let region = HotRegion::from_profile(&egraph, start, &default_layer_names(start), &profile, coverage);
+    println!("Hot region: {} of {} eclasses", region.len(), egraph.number_of_classes());
+    region
+}
+
+/// Sets the scheduler of the rules: restricted to the hot region if any,
+/// delaying rules by their priors if any, see with_growth_scheduler
+///
+/// # Parameters
+///
+/// - `rule_strs`: the rules of the runner, matched to their priors
+fn with_rule_scheduler(
+    runner: Runner<Mdl, TensorAnalysis, ()>,
+    hot_region: Option<HotRegion>,
+    rule_priors: Option<&RulePriors>,
+    rule_strs: &[&str],
+    growth: Option<(GrowthLimits, RuleGrowth)>,
+    capped: &CappedRules,
+) -> Runner<Mdl, TensorAnalysis, ()> {
+    match (hot_region, rule_priors) {
+        (Some(region), Some(priors)) => {
+            let scheduler = PriorScheduler::new(RegionScheduler::new(region), priors.start_iterations(rule_strs));
+            println!("Rule priors: {} rules delayed", scheduler.num_delayed());
+            with_growth_scheduler(runner, scheduler, growth, capped)
+        }
+        (None, Some(priors)) => {
+            let scheduler = PriorScheduler::new(BackoffScheduler::default(), priors.start_iterations(rule_strs));
+            println!("Rule priors: {} rules delayed", scheduler.num_delayed());
+            with_growth_scheduler(runner, scheduler, growth, capped)
+        }
+        (Some(region), None) => with_growth_scheduler(runner, RegionScheduler::new(region), growth, capped),
+        // The default scheduler of the runner
+        (None, None) => with_growth_scheduler(runner, BackoffScheduler::default(), growth, capped),
+    }
+}
+
+/// What the hooks of the saturation record, for after it
+struct SaturationHooks {
+    snapshots: Option<Arc<Mutex<SnapshotRecorder>>>,
+    progress: Option<Arc<Mutex<ProgressRecorder>>>,
+    live: Option<Arc<LiveServer>>,
+    pruner: Option<Arc<Mutex<Pruner>>>,
+    /// Whether each layer of the input graph is frozen
+    frozen: Vec<bool>,
+}
+
+/// Adds the hooks of the args to the runner of the saturation (snapshots,
+/// progress, the live view, the failure bundle, the cache of the shape
+/// checks, pruning, frozen layers and Ctrl-C), and unions the optimized forms
+/// of the blocks into its EGraph
+///
+/// # Parameters
+///
+/// - `start`: the input graph
+/// - `do_filter_after`: whether cycles are filtered after each iteration
+fn with_saturation_hooks(
+    matches: &clap::ArgMatches,
+    mut runner: Runner<Mdl, TensorAnalysis, ()>,
+    start: &RecExpr<Mdl>,
+    do_filter_after: bool,
+) -> (Runner<Mdl, TensorAnalysis, ()>, SaturationHooks) {
+    let output_directory = matches.value_of("output_dir").unwrap();
+    let packed_attrs = matches.is_present("packed_attrs");
+
+    // Snapshot the EGraph before each iteration, after the multi-pattern rules
+    let snapshots = if matches.is_present("egraph_snapshots") {
+        let recorder = Arc::new(Mutex::new(SnapshotRecorder::new(
+            output_directory,
+            matches.is_present("full_snapshots"),
+        )));
+        let hook_recorder = recorder.clone();
+        runner = runner.with_hook(move |runner| hook_recorder.lock().unwrap().record(runner));
+        Some(recorder)
     } else {
-        panic!("Python script failed");
-    }
-}
-
-/// This function gets the following stats:
-///     Total number of enodes
-///     Total number of eclasses
-///     Average number of enodes per class
-///     Total number of edges (children relationships)
-///     Total number of equivalent programs represented (power of 2)
-fn get_stats(egraph: &EGraph<Mdl, TensorAnalysis>) -> (usize, usize, f32, usize, f32) {
-    let num_enodes = egraph.total_size();
-    let num_classes = egraph.number_of_classes();
-    let avg_nodes_per_class = num_enodes as f32 / (num_classes as f32);
-    let num_edges = egraph
-        .classes()
-        .fold(0, |acc, c| c.iter().fold(0, |sum, n| n.len() + sum) + acc);
-    let num_programs = egraph
-        .classes()
-        .fold(0.0, |acc, c| acc + (c.len() as f32).log2());
-    (
-        num_enodes,
-        num_classes,
-        avg_nodes_per_class,
-        num_edges,
-        num_programs,
-    )
-}
-
-fn get_full_graph_runtime(runner: &Runner<Mdl, TensorAnalysis, ()>, process: bool) -> f32 {
-    // let mut g = runner.egraph.analysis.graph.borrow_mut();
-    let mut g = runner.egraph.analysis.graph.lock().unwrap();
-    unsafe {
-        // This is calling TASO's preprocess_weights function before evaluating full graph
-        // run time. It removes op that has only weights as its inputs. Since TASO only cares
-        // about inference time, such ops can be pre-computed
-        if process {
-            let processed_g = g.preprocess_weights();
-            // (*processed_g).export_to_file_raw(CString::new("/usr/tensat/optimized.onnx").unwrap().into_raw());
-            (*processed_g).run()
+        None
+    };
+
+    // The best graph so far, every k iterations
+    let progress = matches.value_of("progress_every").map(|every| {
+        let recorder = Arc::new(Mutex::new(ProgressRecorder::new(
+            output_directory,
+            every.parse::<usize>().unwrap(),
+            packed_attrs,
+            matches.is_present("all_weight_only"),
+            get_objective(matches),
+        )));
+        let hook_recorder = recorder.clone();
+        runner = runner.with_hook(move |runner| hook_recorder.lock().unwrap().record(runner, false));
+        recorder
+    });
+
+    let live = matches.value_of("live_port").map(|port| {
+        let port = port.parse::<u16>().unwrap();
+        let server = LiveServer::start(port).unwrap_or_else(|e| panic!("{}", e));
+        println!("Showing the EGraph on http://127.0.0.1:{}/", port);
+        Arc::new(server)
+    });
+    if let Some(server) = &live {
+        let hook_server = server.clone();
+        runner = runner.with_hook(move |runner| {
+            hook_server.update(runner, false);
+            Ok(())
+        });
+    }
+
+    if bundle::is_enabled() {
+        runner = runner.with_hook(|runner| {
+            bundle::record_egraph(&runner.egraph);
+            Ok(())
+        });
+    }
+
+    if matches.is_present("blocks") || matches.is_present("blocks_file") {
+        let mut blocks = builtin_blocks();
+        if let Some(file) = matches.value_of("blocks_file") {
+            blocks.extend(load_blocks(file).unwrap_or_else(|errors| panic!("Invalid blocks in {}:\n{}", file, errors.join("\n"))));
+        }
+        if packed_attrs {
+            println!("Warning: the blocks match unpacked ops, no blocks added with --packed_attrs");
         } else {
-            //(*g).export_to_file_raw(CString::new("/usr/tensat/orig.onnx").unwrap().into_raw());
-            (*g).run()
+            let counts = union_blocks(&mut runner.egraph, &blocks, do_filter_after);
+            let added: Vec<String> = counts
+                .iter()
+                .filter(|(_, n)| *n > 0)
+                .map(|(name, n)| format!("{} x{}", name, n))
+                .collect();
+            println!("Added the optimized forms of {} blocks: {}", added.len(), added.join(", "));
         }
     }
+
+    // if matches.is_present("gj") {
+    //     runner.egraph.strategy = egg::Strategy::GenericJoin;
+    // } else {
+    //     runner.egraph.strategy = egg::Strategy::EMatch;
+    // }
+
+    // The shape checks are cached within an iteration
+    runner = runner.with_hook(|runner| {
+        runner.egraph.analysis.condition_cache.clear();
+        Ok(())
+    });
+
+    let pruner = matches.value_of("prune_enodes").map(|max_enodes| {
+        let keep = matches.value_of("prune_keep").unwrap().parse::<usize>().unwrap();
+        Arc::new(Mutex::new(Pruner::new(max_enodes.parse::<usize>().unwrap(), keep, start)))
+    });
+    if let Some(pruner) = &pruner {
+        let hook_pruner = pruner.clone();
+        runner = runner.with_hook(move |runner| {
+            hook_pruner.lock().unwrap().prune(&mut runner.egraph);
+            Ok(())
+        });
+    }
+
+    // Rules do not rewrite the frozen layers
+    let layers_of = |arg: &str| -> Vec<usize> {
+        matches.values_of(arg).map_or(vec![], |names| {
+            names
+                .map(|name| layer_index(start, name).unwrap_or_else(|e| panic!("Invalid --{}: {}", arg, e)))
+                .collect()
+        })
+    };
+    let frozen = frozen_layers(start, &layers_of("freeze_prefix"), &layers_of("freeze_suffix"));
+    if frozen.iter().any(|f| *f) {
+        runner.egraph.analysis.frozen_nodes = frozen_enodes(&runner.egraph, start, &frozen);
+        println!("Frozen layers: {} of {}", frozen.iter().filter(|f| **f).count(), frozen.len());
+        runner = runner.with_hook(|runner| {
+            canonicalize_frozen(&mut runner.egraph);
+            Ok(())
+        });
+    }
+
+    // Ctrl-C stops saturating at the next iteration, the EGraph so far is
+    // extracted greedily
+    runner = runner.with_hook(|_| {
+        if interrupted() {
+            Err(String::from("interrupted"))
+        } else {
+            Ok(())
+        }
+    });
+    set_graceful_interrupt(true);
+
+    let hooks = SaturationHooks {
+        snapshots: snapshots,
+        progress: progress,
+        live: live,
+        pruner: pruner,
+        frozen: frozen,
+    };
+    (runner, hooks)
+}
+
+/// Builds the cost model of the extraction from the args and computes its
+/// runtimes: the runtime model (behind the runtime cache if any), the
+/// weights, the tie break, the tied weights and the frozen layers
+fn extraction_cost_model(
+    matches: &clap::ArgMatches,
+    optimizer: &mut Optimizer,
+    cost_cache: &Option<Arc<RuntimeCache>>,
+    output_directory: &str,
+) -> CostModel {
+    let runtime_model = get_runtime_model(matches, matches.value_of("runtime_source").unwrap());
+    let runtime_model: Box<dyn RuntimeModel> = match cost_cache {
+        Some(cache) => Box::new(CachedRuntime {
+            inner: runtime_model,
+            cache: cache.clone(),
+        }),
+        None => runtime_model,
+    };
+    let mut cost_model = optimizer
+        .cost_model()
+        .with_runtime_model(runtime_model)
+        .with_weights(get_weights(matches));
+    // The analysis ran during saturation, the runtimes are computed here
+    // in one pass
+    let populate_start = Instant::now();
+    match get_shape_sampling(matches) {
+        Some(sampling) => {
+            let profile = get_device_profile(matches);
+            let estimate = AnalyticalRuntime {
+                peak_tflops: profile.peak_tflops,
+                gb_per_sec: profile.gb_per_sec,
+            };
+            let report = optimizer.populate_sampled_runtimes(&mut cost_model, &sampling, &estimate);
+            println!(
+                "Computed the runtimes of {} enodes in {:?}: {} shapes asked for {} buckets, {} interpolated (mean confidence {:.2})",
+                report.num_direct + report.num_bucketed,
+                populate_start.elapsed(),
+                report.measured_keys.len(),
+                report.num_buckets,
+                report.interpolated.len(),
+                report.mean_confidence().unwrap_or(1.0)
+            );
+            let filename = Path::new(output_directory).join("shape_sampling.json");
+            write(filename, serde_json::to_string_pretty(&report).unwrap()).expect("Unable to write file");
+        }
+        None => {
+            let num_populated = optimizer.populate_runtimes(&mut cost_model);
+            println!("Computed the runtimes of {} enodes in {:?}", num_populated, populate_start.elapsed());
+        }
+    }
+    let egraph = optimizer.egraph();
+    let start = optimizer.start();
+    let mut cost_model = match matches.value_of("tie_break").unwrap() {
+        "op_count" => {
+            let epsilon = tie_epsilon(egraph, &cost_model);
+            cost_model.with_tie_break(TieBreak::OpCount, epsilon)
+        }
+        "original" => {
+            let epsilon = tie_epsilon(egraph, &cost_model);
+            cost_model
+                .with_tie_break(TieBreak::PreferOriginal, epsilon)
+                .with_original_enodes(original_enodes(egraph, start))
+        }
+        _ => cost_model,
+    };
+    if matches.is_present("tied_weights") {
+        let tied_weights = TiedWeights::new(egraph, start);
+        if tied_weights.is_empty() {
+            println!("Warning: no weight of the graph is used by more than one op, no weights tied");
+        } else {
+            println!("Tied weights: {}", tied_weights.names.join(", "));
+            cost_model = cost_model.with_tied_weights(tied_weights);
+        }
+    }
+    if !egraph.analysis.frozen_nodes.is_empty() {
+        cost_model = cost_model.with_frozen(FrozenGraph::new(egraph));
+    }
+    println!(
+        "Optimizing for {:?}, costs in {}, with {} runtimes",
+        cost_model.objective(),
+        cost_model.objective().unit(),
+        cost_model.runtime_model().name()
+    );
+    cost_model
+}
+
+/// Gets the input graph, either a pre-defined model or read from the model
+/// file. Exits if the graph does not validate
+/// Writes a graph to the output file (or stdout for -) in the output format.
+/// The packed ops are internal, the output uses the ops of the input
+///
+/// # Parameters
+///
+/// - `graph`: the graph to write
+/// - `packed_attrs`: whether the graph has packed attributes
+fn write_graph_output(matches: &clap::ArgMatches, graph: &RecExpr<Mdl>, packed_attrs: bool) {
+    let outf = match matches.value_of("output_file") {
+        Some(outf) => outf,
+        None => return,
+    };
+    let out = if packed_attrs { unpack_expr(graph) } else { graph.clone() };
+    let shapes = || {
+        let runner_out = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&out);
+        expr_shapes(&runner_out.egraph, &out)
+    };
+    let contents = match matches.value_of("output_format").unwrap() {
+        "annotated" => to_text(&out, Some(&shapes())).into_bytes(),
+        "onnx" => {
+            let provider: ExecutionProvider = matches.value_of("onnx_provider").unwrap().parse().unwrap();
+            let opset = matches.value_of("onnx_opset").unwrap().parse::<i64>().unwrap();
+            export_onnx(&out, &shapes(), provider, opset)
+                .unwrap_or_else(|e| panic!("Could not write the optimized graph as ONNX: {}", e))
+        }
+        "flatbuffer" => export_flatbuffer(&out)
+            .unwrap_or_else(|e| panic!("Could not write the optimized graph as a FlatBuffer: {}", e)),
+        _ => out.to_string().into_bytes(),
+    };
+    write_output(outf, matches.value_of("output_dir").unwrap(), &contents).expect("Unable to write file");
+}
+
+fn load_model(matches: &clap::ArgMatches) -> RecExpr<Mdl> {
+    load_model_with_names(matches).0
+}
+
+/// Gets the input graph as load_model, together with the names the importer
+/// gave its layers (none for the other inputs)
+fn load_model_with_names(matches: &clap::ArgMatches) -> (RecExpr<Mdl>, HashMap<Id, String>) {
+    let mut layer_names = HashMap::new();
+    let start = match matches.value_of("model") {
+        Some("resnet50") => resnet50::get_resnet50(),
+        Some("nasrnn") => nasrnn::get_nasrnn(),
+        Some("resnext50") => resnext50::get_resnext50(),
+        Some("bert") => bert::get_bert(),
+        Some("nasneta") => nasneta::get_nasneta(),
+        Some("inceptionv3") => inceptionv3::get_inceptionv3(),
+        Some("mobilenetv2") => mobilenetv2::get_mobilenetv2(),
+        Some("vgg") => vgg::get_vgg(),
+        Some("squeezenet") => squeezenet::get_squeezenet(),
+        Some(_) => panic!("The model name is not supported"),
+        None if matches.is_present("taso_model") => {
+            let model_file = matches.value_of("taso_model").unwrap();
+            let serialized =
+                read_input_string(model_file).expect("Something went wrong reading the model file");
+            let mode = if matches.is_present("strict") {
+                ImportMode::Strict
+            } else if matches.is_present("permissive") {
+                ImportMode::Permissive
+            } else {
+                ImportMode::Default
+            };
+            let (converter, report) = parse_model_with_mode(&serialized, mode);
+            println!("Op coverage of {}:\n{}", model_file, report.to_table());
+            if let Some(report_file) = matches.value_of("coverage_report") {
+                write(report_file, serde_json::to_string(&report).unwrap()).expect("Unable to write file");
+            }
+            if report.num_failed() > 0 && mode == ImportMode::Permissive {
+                println!("Warning: {} ops could not be imported, continuing with the rest of the model", report.num_failed());
+            } else if report.num_failed() > 0 {
+                eprintln!("{} ops could not be imported", report.num_failed());
+                bundle::exit_failure(&format!("{} ops could not be imported", report.num_failed()));
+            }
+            let (expr, names) = converter.rec_expr_with_names();
+            layer_names = names;
+            expr
+        }
+        None if matches.is_present("onnx_model") => {
+            let model_file = matches.value_of("onnx_model").unwrap();
+            let serialized = read_input(model_file).expect("Something went wrong reading the model file");
+            let (expr, names) = import_onnx_with_names(&serialized).unwrap_or_else(|e| {
+                eprintln!("Could not import {}: {}", model_file, e);
+                bundle::exit_failure(&format!("could not import {}: {}", model_file, e));
+            });
+            layer_names = names;
+            expr
+        }
+        None if matches.is_present("joint_models") => {
+            let models: Vec<RecExpr<Mdl>> = matches
+                .values_of("joint_models")
+                .unwrap()
+                .map(|model_file| {
+                    let input_graph =
+                        read_to_string(model_file).expect("Something went wrong reading the model file");
+                    read_graph(&input_graph)
+                        .unwrap_or_else(|e| panic!("Could not parse the model file {}: {}", model_file, e))
+                })
+                .collect();
+            let num_nodes: usize = models.iter().map(|m| m.as_ref().len()).sum();
+            let (combined, num_shared) = combine_models(&models);
+            println!("Combined {} models, {} of {} nodes are shared", models.len(), num_shared, num_nodes);
+            combined
+        }
+        None => {
+            // Mode extract gets the input graph of the saturation
+            let artifact_model = matches.value_of("artifact").map(|dir| artifact_input(dir).to_str().unwrap().to_string());
+            let model_file = matches
+                .value_of("model_file")
+                .or_else(|| artifact_model.as_deref())
+                .expect("Pls supply input graph file.");
+            let input_graph =
+                read_input_string(model_file).expect("Something went wrong reading the model file");
+            read_graph(&input_graph).unwrap_or_else(|e| panic!("Could not parse the model file: {}", e))
+        }
+    };
+
+    // Parse the names of the tensors once, the analysis looks them up
+    if let Err(errors) = validate_names(&start) {
+        for e in errors.iter() {
+            eprintln!("The input graph is invalid: node {} ({}): {}", e.node, e.name, e.error);
+        }
+        bundle::exit_failure(&format!("the input graph has {} invalid names", errors.len()));
+    }
+    if let Err(errors) = validate_graph(&start) {
+        eprintln!("The input graph is invalid:");
+        for e in errors.iter() {
+            eprintln!("  {}", e);
+        }
+        bundle::exit_failure(&format!("the input graph is invalid: {}", errors.join("; ")));
+    }
+    (start, layer_names)
+}
+
+/// Gets the board power of the GPU from the args, or measures it
+fn get_power_watts(matches: &clap::ArgMatches) -> f32 {
+    match matches.value_of("power_watts") {
+        Some(w) => w.parse::<f32>().unwrap(),
+        None => measure_gpu_power()
+            .unwrap_or_else(|e| panic!("Could not measure the GPU power, pass --power_watts: {}", e)),
+    }
 }
 
-fn save_model(runner: &Runner<Mdl, TensorAnalysis, ()>, file_name: &str) {
-    // let mut g = runner.egraph.analysis.graph.borrow_mut();
-    let mut g = runner.egraph.analysis.graph.lock().unwrap();
-    unsafe {
-        (*g).export_to_file_raw(CString::new(file_name).unwrap().into_raw());
+/// Gets the objective from the args
+fn get_objective(matches: &clap::ArgMatches) -> Objective {
+    match matches.value_of("objective").unwrap() {
+        "energy" => Objective::Energy {
+            watts: get_power_watts(matches),
+        },
+        "cost" => {
+            let price = matches
+                .value_of("price_per_hour")
+                .expect("Pls supply --price_per_hour for --objective cost")
+                .parse::<f32>()
+                .unwrap();
+            Objective::Price { dollars_per_hour: price }
+        }
+        _ => Objective::Latency,
     }
 }
 
+/// Gets the weights of the objective, the op count and the memory from the
+/// args
+fn get_weights(matches: &clap::ArgMatches) -> ObjectiveWeights {
+    let weight = |arg| matches.value_of(arg).map_or(0.0, |w| w.parse::<f32>().unwrap());
+    ObjectiveWeights {
+        objective: 1.0,
+        op_count: weight("op_weight"),
+        memory: weight("memory_weight"),
+    }
+}
+
+/// Gets the device profile of --device_profile, with the values of
+/// --peak_tflops and --bandwidth_gbps if given. Falls back to their defaults
+/// if the local GPU has no profile
+fn get_device_profile(matches: &clap::ArgMatches) -> DeviceProfile {
+    let fallback = || DeviceProfile {
+        name: String::from("default"),
+        peak_tflops: matches.value_of("peak_tflops").unwrap().parse::<f32>().unwrap(),
+        gb_per_sec: matches.value_of("bandwidth_gbps").unwrap().parse::<f32>().unwrap(),
+        runtime_ratios: BTreeMap::new(),
+    };
+    let mut profile = match matches.value_of("device_profile").unwrap() {
+        "auto" => match detect_gpu() {
+            Ok(gpu) => builtin_profile(&gpu).unwrap_or_else(|| {
+                println!("Warning: no device profile for {}, using --peak_tflops and --bandwidth_gbps", gpu);
+                fallback()
+            }),
+            Err(e) => {
+                println!("Warning: could not detect the GPU ({}), using --peak_tflops and --bandwidth_gbps", e);
+                fallback()
+            }
+        },
+        name_or_file => load_profile(name_or_file).unwrap_or_else(|e| panic!("{}", e)),
+    };
+    if matches.occurrences_of("peak_tflops") > 0 {
+        profile.peak_tflops = matches.value_of("peak_tflops").unwrap().parse::<f32>().unwrap();
+    }
+    if matches.occurrences_of("bandwidth_gbps") > 0 {
+        profile.gb_per_sec = matches.value_of("bandwidth_gbps").unwrap().parse::<f32>().unwrap();
+    }
+    profile
+}
+
+/// Gets the sampling of the shapes in the cost phase, if --shape_buckets or
+/// --sample_shapes is given
+fn get_shape_sampling(matches: &clap::ArgMatches) -> Option<ShapeSampling> {
+    if !(matches.is_present("shape_buckets") || matches.is_present("sample_shapes")) {
+        return None;
+    }
+    let value = |arg| matches.value_of(arg).map_or(1.0, |v| v.parse::<f32>().unwrap());
+    Some(ShapeSampling {
+        granularity: value("shape_buckets"),
+        fraction: value("sample_shapes"),
+    })
+}
+
+/// Gets the runtime model of the cost model, for a runtime_source
+fn get_runtime_model(matches: &clap::ArgMatches, source: &str) -> Box<dyn RuntimeModel> {
+    let analytical = || {
+        let profile = get_device_profile(matches);
+        AnalyticalRuntime {
+            peak_tflops: profile.peak_tflops,
+            gb_per_sec: profile.gb_per_sec,
+        }
+    };
+    match source {
+        "analytical" => Box::new(analytical()),
+        "table" => {
+            let filename = matches
+                .value_of("runtime_table")
+                .expect("Pls supply --runtime_table for --runtime_source table");
+            let table = RuntimeTable::load(filename, Box::new(analytical())).unwrap_or_else(|e| panic!("{}", e));
+            Box::new(table)
+        }
+        _ => Box::new(MeasuredRuntime),
+    }
+}
+
+/// Gets the growth limits of the rules, if any of --k_multi,
+/// --rule_match_limit and --rule_caps is given
+fn get_growth_limits(matches: &clap::ArgMatches) -> Option<GrowthLimits> {
+    if !(matches.is_present("k_multi") || matches.is_present("rule_match_limit") || matches.is_present("rule_caps")) {
+        return None;
+    }
+    let mut limits = GrowthLimits::default();
+    if let Some(k_multi) = matches.value_of("k_multi") {
+        limits.k_multi = k_multi.parse::<usize>().unwrap();
+    }
+    if let Some(match_limit) = matches.value_of("rule_match_limit") {
+        limits.match_limit = match_limit.parse::<usize>().unwrap();
+    }
+    limits.ban_length = matches.value_of("rule_ban_length").unwrap().parse::<usize>().unwrap();
+    if let Some(file) = matches.value_of("rule_caps") {
+        limits.load_caps(file).unwrap_or_else(|e| panic!("{}", e));
+    }
+    Some(limits)
+}
+
+/// Sets the scheduler of a runner, wrapped in a GrowthScheduler if the rules
+/// have growth limits, and in a CapRecorder recording the rules it skips in
+/// capped
+fn with_growth_scheduler<S: RewriteScheduler<Mdl, TensorAnalysis> + 'static>(
+    runner: Runner<Mdl, TensorAnalysis, ()>,
+    scheduler: S,
+    growth: Option<(GrowthLimits, RuleGrowth)>,
+    capped: &CappedRules,
+) -> Runner<Mdl, TensorAnalysis, ()> {
+    match growth {
+        Some((limits, rule_growth)) => runner.with_scheduler(CapRecorder::new(
+            GrowthScheduler::new(scheduler, limits, rule_growth),
+            capped.clone(),
+        )),
+        None => runner.with_scheduler(CapRecorder::new(scheduler, capped.clone())),
+    }
+}
+
+/// Gets the ops of --recompute_ops, warning about the ones that are not
+/// cheap elementwise ops
+fn recomputed_ops(ops: &str) -> BTreeSet<String> {
+    if ops == "default" {
+        return RECOMPUTABLE_OPS.iter().map(|op| op.to_string()).collect();
+    }
+    let ops: BTreeSet<String> = ops.split(',').map(|op| op.trim().to_string()).filter(|op| !op.is_empty()).collect();
+    for op in ops.iter() {
+        if !RECOMPUTABLE_OPS.contains(&op.as_str()) {
+            println!("Warning: {} is not an elementwise op, its recomputation may not be cheap", op);
+        }
+    }
+    ops
+}
+
+/// Gets the safety level of the safety flag
+fn get_safety(matches: &clap::ArgMatches) -> Safety {
+    match matches.value_of("safety").unwrap() {
+        "strict" => Safety::Strict,
+        "aggressive" => Safety::Aggressive,
+        _ => Safety::Normal,
+    }
+}
+
+/// If an extraction mode runs in process (see extraction_strategy)
+fn is_in_process(mode: &str) -> bool {
+    matches!(mode, "greedy" | "beam" | "cbc") || custom_strategy(mode).is_some()
+}
+
+/// Gets the extraction strategy of the extract flag (greedy, beam, cbc, ilp,
+/// egg_ilp or a registered strategy)
+fn extraction_strategy(matches: &clap::ArgMatches, mode: &str, ilp_time_sec: Option<u64>) -> ExtractionStrategy {
+    let ilp_settings = IlpSettings {
+        order_var_int: matches.is_present("order_var_int"),
+        class_constraint: matches.is_present("class_constraint"),
+        no_order: matches.is_present("no_order"),
+        initialize: matches.is_present("initial_with_greedy"),
+        time_limit_sec: ilp_time_sec,
+        num_threads: matches.value_of("ilp_num_threads").map(|n| n.parse::<usize>().unwrap()),
+        max_memory: max_memory(matches),
+    };
+    match mode {
+        "greedy" => ExtractionStrategy::Greedy,
+        "beam" => ExtractionStrategy::Beam {
+            width: matches.value_of("beam_width").unwrap().parse::<usize>().unwrap(),
+            max_rounds: matches.value_of("beam_rounds").unwrap().parse::<usize>().unwrap(),
+        },
+        "cbc" => ExtractionStrategy::Ilp(ilp_settings),
+        "ilp" => ExtractionStrategy::IlpScript {
+            settings: ilp_settings,
+            output_dir: matches.value_of("output_dir").unwrap().to_string(),
+        },
+        "egg_ilp" => ExtractionStrategy::EggIlp,
+        name if custom_strategy(name).is_some() => ExtractionStrategy::Custom(name.to_string()),
+        _ => panic!(
+            "Extracting mode not supported: {} is not one of {} or a registered strategy ({})",
+            mode,
+            BUILTIN_STRATEGIES.join(", "),
+            custom_strategy_names().join(", ")
+        ),
+    }
+}
+
+/// Gets the memory budget of the max_memory flag, in bytes
+fn max_memory(matches: &clap::ArgMatches) -> Option<u64> {
+    matches
+        .value_of("max_memory")
+        .map(|mb| (mb.parse::<f64>().unwrap() * 1024.0 * 1024.0) as u64)
+}
+
 fn prove_taso_rules(matches: clap::ArgMatches) {
     env_logger::init();
 
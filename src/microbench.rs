@@ -0,0 +1,85 @@
+//! Measuring a single op standalone (mode microbench)
+//!
+//! The op is given as a graph whose root is the op, on inputs and weights
+//! of the shapes to measure, e.g.
+//! `(conv2d 1 1 0 2 (input x@1_64_56_56) (weight w@64_64_3_3))`. Each
+//! repetition builds the graph in a fresh EGraph, so TASO creates and
+//! measures the op anew, and reads its runtime through the runtime model as
+//! the cost model does during optimization. The spread of the repetitions
+//! shows how much a measured cost can be trusted.
+
+use crate::cost::RuntimeModel;
+use crate::model::*;
+use crate::optimize::CostModel;
+use egg::*;
+use serde::Serialize;
+
+/// Distribution of the latencies of the repetitions, in milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub min: f32,
+    pub mean: f32,
+    pub stddev: f32,
+    pub p50: f32,
+    pub p90: f32,
+    pub p99: f32,
+    pub max: f32,
+}
+
+impl LatencySummary {
+    /// Summarizes latencies, None if there are none
+    pub fn from_samples(samples: &[f32]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = sorted.len();
+        let mean = sorted.iter().sum::<f32>() / n as f32;
+        let variance = sorted.iter().map(|s| (s - mean) * (s - mean)).sum::<f32>() / n as f32;
+        // Nearest-rank percentiles
+        let percentile = |p: f32| sorted[((p * n as f32).ceil() as usize).clamp(1, n) - 1];
+        Some(LatencySummary {
+            count: n,
+            min: sorted[0],
+            mean: mean,
+            stddev: variance.sqrt(),
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: sorted[n - 1],
+        })
+    }
+}
+
+/// Measures the op at the root of a graph
+///
+/// # Parameters
+///
+/// - `expr`: the op on its inputs, see the module documentation
+/// - `cost_model`: gives the runtime model, as in optimization
+/// - `repeats`: number of times to create and measure the op
+///
+/// # Returns
+///
+/// The runtime of each repetition, in milliseconds. An error if TASO can
+/// not create the op
+pub fn microbench(expr: &RecExpr<Mdl>, cost_model: &CostModel, repeats: usize) -> Result<Vec<f32>, String> {
+    let root_node = expr.as_ref().last().ok_or("the graph is empty")?.clone();
+    if root_node.children().is_empty() {
+        return Err(format!("the root {} is not an op", root_node));
+    }
+    let mut samples = vec![];
+    for _ in 0..repeats {
+        let mut egraph = EGraph::new(TensorAnalysis::default());
+        let root = egraph.add_expr(expr);
+        if egraph[root].data.infeasible {
+            return Err(format!("TASO can not create {}", root_node));
+        }
+        // Without rules the eclass of the root has only the op
+        let enode = egraph[root].nodes[0].clone();
+        samples.push(cost_model.runtime_model().runtime(cost_model, &egraph, &enode));
+    }
+    Ok(samples)
+}
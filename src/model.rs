@@ -9,9 +9,20 @@
 include!(concat!("/usr/tensat/taso_bindings.rs"));
 
 //use rand::prelude::*;
-use rand;
+use crate::attrs::unpack_attrs;
+use crate::cost::op_key;
+use crate::custom::parse_custom_label;
+use crate::ffi::*;
+use crate::names::{parse_dims, tensor_name};
+use crate::power::{PowerLog, PowerSampler, SharedPowerLog};
+use crate::rulecache::ConditionCache;
+use crate::shape::{broadcast_shape, handle_dims, handle_shape, shape_dims, with_shape, ElemType, ShapeId};
+use crate::resources::record_measure_time;
+use crate::values::weight_values;
+use crate::watchdog;
 use root::taso::*;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
 use std::convert::TryInto;
 use std::sync::{Arc, Mutex};
 
@@ -29,16 +40,30 @@ pub const ACTTANH: i32 = 3;
 pub const NOSHUFFLE: i32 = 0;
 pub const SHUFFLE: i32 = 1;
 
+/// Element types of tensors, see Mdl::Cast
+pub const ELEM_F32: i32 = 0;
+pub const ELEM_F16: i32 = 1;
+pub const ELEM_I8: i32 = 2;
+
+/// Storage layouts of weights, see Mdl::Repack
+pub const LAYOUT_DEFAULT: i32 = 0;
+pub const LAYOUT_BLOCKED: i32 = 1;
+
 define_language! {
     pub enum Mdl {
         "input"     = Input([Id; 1]), // takes a Var, format: name@dim1_dim2...
-        "weight"    = Weight([Id; 1]), // takes a Var, format : name@dim1_dim2...
+        "weight"    = Weight([Id; 1]), // takes a Var, format : name@dim1_dim2... or name@dim1_dim2...@density for pruned weights
         "ewadd"     = Ewadd([Id; 2]),
         "ewmul"     = Ewmul([Id; 2]),
         "smul"      = Smul([Id; 2]),
         "transpose" = Transpose([Id; 3]), // input, perm_name (format: dim1_dim2...), shuffle
         "matmul"    = Matmul([Id; 3]), // activation, input1, input2
         "conv2d"    = Conv2d([Id; 6]), // conv2d's weight tensor kernel size can not be even, it seems that TASO's output shape computation is incorrect for even kernal size (like 4x4)
+        "smatmul"   = Smatmul([Id; 3]), // matmul with a sparse kernel for input2, same arguments as matmul
+        "sconv2d"   = Sconv2d([Id; 6]), // conv2d with a sparse kernel for the weight, same arguments as conv2d
+        "conv2d_b"  = Conv2dBlocked([Id; 6]), // conv2d with a kernel taking the weight in the blocked layout, same arguments as conv2d
+        "repack"    = Repack([Id; 2]), // layout (LAYOUT_DEFAULT or LAYOUT_BLOCKED), input. Copies a weight into another storage layout, same values
+        "cast"      = Cast([Id; 2]), // element type (ELEM_F32, ELEM_F16 or ELEM_I8), input. Converts the elements of a tensor, inputs and weights are fp32
         "enlarge"   = Enlarge([Id; 2]), // input_to_enlarge, ref_input
         "dropout"   = Dropout(Id),
         "relu"      = Relu(Id),
@@ -46,6 +71,11 @@ define_language! {
         "sigmoid"   = Sigmoid(Id),
         "poolmax"   = Poolmax([Id; 7]), // input, kernel_h, kernel_w, stride_h, stride_w, padding, activation
         "poolavg"   = Poolavg([Id; 7]), // input, kernel_h, kernel_w, stride_h, stride_w, padding, activation
+        // Packed variants, taking all scalar attributes as one scalar (see attrs::pack_attrs)
+        "conv2d_p"  = Conv2dPacked([Id; 3]), // attrs (stride_h, stride_w, padding, activation), input, weight
+        "sconv2d_p" = Sconv2dPacked([Id; 3]), // attrs (stride_h, stride_w, padding, activation), input, weight
+        "poolmax_p" = PoolmaxPacked([Id; 2]), // input, attrs (kernel_h, kernel_w, stride_h, stride_w, padding, activation)
+        "poolavg_p" = PoolavgPacked([Id; 2]), // input, attrs (kernel_h, kernel_w, stride_h, stride_w, padding, activation)
         "concat"    = Concat([Id; 4]), // axis, ndim, input1, input2. ndim is for using in CheckApply only
         "concat3"    = Concat3([Id; 5]), // axis, ndim, input1, input2. input3, ndim is for using in CheckApply only
         "concat4"    = Concat4([Id; 6]), // axis, ndim, input1, input2. input3, input4, ndim is for using in CheckApply only
@@ -54,6 +84,7 @@ define_language! {
         "split_0"   = Split0(Id), // must take a split node as input
         "split_1"   = Split1(Id), // must take a split node as input
         "split"     = Split([Id; 2]), // axis, input
+        "split_i"   = SplitI([Id; 4]), // axis, count, index, input. Output index of splitting input into count parts where it was concatenated
         "Cpool"     = Cpool([Id; 2]),
         "Iconv"     = Iconv([Id; 2]),
         "Imatmul"   = Imatmul,
@@ -62,12 +93,17 @@ define_language! {
         "reshape"   = Reshape([Id; 2]), // input, shape_name (format: dim1_dim2...)
         "noop"      = Noop([Id; 2]), // No op, use to combine the outputs of a graph in case there are multiple, since egg works with single root graph
         "batchnorm" = BatchNorm([Id; 5]), // input, scale, bias, mean, var
+        "softmax"   = Softmax([Id; 2]), // input, axis
+        "dense"     = Dense([Id; 4]), // activation, input, weight, bias. matmul followed by adding the (broadcast) bias and the activation, in one kernel
+        "opaque"    = Opaque([Id; 3]), // name (format: label@dim1_dim2...), input1, input2. An op not supported here, kept as is: no rule matches it, and its output is a new tensor of the given shape
+        "concat_n"  = ConcatN(Vec<Id>), // axis, ndim, inputs. Concat of any number of inputs
+        "custom"    = Custom(Vec<Id>), // label (format: op or op:attr1:attr2...), inputs. An op registered at runtime (see custom.rs), whose shape and cost come from its callbacks
         Num(i32),
         Var(Symbol),
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum DataKind {
     Name,
     Scalar,
@@ -82,134 +118,304 @@ impl Default for DataKind {
 }
 
 /// Metadata struct for TensorAnalysis
+///
+/// There is one for every eclass, so it is kept small: names (including the
+/// shapes encoded in them) are interned Symbols shared with the Var enodes,
+/// tensors are handles keeping their TASO graph alive (see ffi::TasoTensor),
+/// and the shapes of the tensors are ids in the shape arena (see
+/// shape::ShapeId), read without going to the TASO side.
 #[derive(Debug, Clone)]
 pub struct ValTnsr {
     /// The data type of this eclass, can be a name/scalar/tensor
     pub dtype: DataKind,
     /// The value of this eclass if it is a Scalar type
     pub val: i32,
-    /// The name string of this eclass if it is a Name type
-    pub name: String,
-    /// The pointer to the tensor if it is a Tensor type
-    pub meta: TensorHandle,
-    /// The pointer to the second tensor if it is a TnsrTuple type (for split node)
-    pub meta_2: TensorHandle,
+    /// The name of this eclass if it is a Name type
+    pub name: Symbol,
+    /// The tensor if it is a Tensor type
+    pub meta: TasoTensor,
+    /// The second tensor if it is a TnsrTuple type (for split node)
+    pub meta_2: TasoTensor,
     /// If the tensor results from all weights computations
     pub all_weights: bool,
+    /// If the tensor could not be created on the TASO side, e.g. because the
+    /// op or its shapes are not supported
+    pub infeasible: bool,
+    /// Fraction of non-zero entries if the tensor results from all weights
+    /// computations, 1.0 otherwise
+    pub density: f32,
+    /// The element type if it is a Tensor type. TASO's tensors are all fp32,
+    /// so this only tracks what the tensor would be (see Mdl::Cast)
+    pub elem: ElemType,
+    /// Size of the output tensors in bytes (of both for a TnsrTuple), 0 for
+    /// names and scalars
+    pub bytes: u64,
+    /// Shape of the tensor in the shape arena, if it is a Tensor type
+    pub shape: Option<ShapeId>,
+    /// Shape of the second tensor if it is a TnsrTuple type
+    pub shape_2: Option<ShapeId>,
+}
+
+impl ValTnsr {
+    /// Size of the output tensor in bytes if it is an activation (computed
+    /// from the inputs at inference), 0 for weights, names and scalars. 0 for
+    /// a TnsrTuple too, its tensors are counted at the split_0 and split_1
+    /// taking them
+    pub fn activation_bytes(&self) -> u64 {
+        if self.all_weights || self.dtype == DataKind::TnsrTuple {
+            0
+        } else {
+            self.bytes
+        }
+    }
 }
 
 impl Default for ValTnsr {
     fn default() -> Self {
         ValTnsr {
-            meta: std::ptr::null_mut(),
-            meta_2: std::ptr::null_mut(),
-            ..Default::default()
+            dtype: DataKind::default(),
+            val: 0,
+            name: Symbol::from(""),
+            meta: TasoTensor::null(),
+            meta_2: TasoTensor::null(),
+            all_weights: false,
+            infeasible: false,
+            density: 1.0,
+            elem: ElemType::Float32,
+            bytes: 0,
+            shape: None,
+            shape_2: None,
         }
     }
 }
 
-unsafe impl Send for ValTnsr {}
-unsafe impl Sync for ValTnsr {}
-
 /// Struct for metadata analysis
 ///
 /// In this analysis, it calls functions on the TASO side (e.g. graph.matmul())
 /// to create (or get) new ops/nodes and stores pointers to the output tensors.
 /// TASO will measure and store the runtime cost when creating a new op/node.
+/// The analysis only keeps the shape and type of each eclass, the costs are
+/// computed in a separate pass for the extraction (see cost.rs).
 #[derive(Clone)]
 pub struct TensorAnalysis {
-    /// Points to the graph object on the TASO side
+    /// Owns the graph object on the TASO side, freed once the analysis and
+    /// the tensors taken out of it are dropped
     // pub graph: std::cell::RefCell<Box<Graph>>,
-    pub graph: Arc<Mutex<Box<Graph>>>,
+    pub graph: Arc<Mutex<TasoGraph>>,
     /// Record blacklisted nodes for filtering cycles
     pub blacklist_nodes: HashSet<Mdl>,
     /// Newly added nodes by order
     pub newly_added: Vec<Mdl>,
+    /// Number of merges between two feasible eclasses whose metadata disagree
+    pub num_merge_mismatches: usize,
+    /// If set, the GPU power is recorded while TASO measures new ops
+    pub power_log: Option<SharedPowerLog>,
+    /// Ops whose output the backend may recompute in each consumer instead
+    /// of materializing it (see enode_activation_bytes)
+    pub recomputed_ops: HashSet<String>,
+    /// Results of the shape checks of the rules in the current iteration
+    pub condition_cache: ConditionCache,
+    /// Enodes pruned from explosive eclasses (see prune.rs)
+    pub pruned_nodes: HashSet<Mdl>,
+    /// Enodes of the frozen layers, which rules do not rewrite (see freeze.rs)
+    pub frozen_nodes: HashSet<Mdl>,
+    /// Enodes made while one of their input eclasses was infeasible, made
+    /// again once it is feasible (see revive_waiting)
+    pub waiting_nodes: Arc<Mutex<HashSet<Mdl>>>,
+    /// If an infeasible eclass became feasible since the last modify
+    pub revived: bool,
 }
 
 impl Default for TensorAnalysis {
     fn default() -> Self {
-        unsafe {
-            // NOTE Box heap-allocates, otherwise any pointer from
-            // C++ may be dangling
-            let mut graph = Box::new(Graph::new());
-            Graph_Graph(&mut *graph);
-            TensorAnalysis {
-                // graph: std::cell::RefCell::new(graph),
-                graph: Arc::new(Mutex::new(graph)),
-                blacklist_nodes: HashSet::<Mdl>::new(),
-                newly_added: Vec::<Mdl>::new(),
-            }
+        TensorAnalysis {
+            // graph: std::cell::RefCell::new(graph),
+            graph: Arc::new(Mutex::new(TasoGraph::new())),
+            blacklist_nodes: HashSet::<Mdl>::new(),
+            newly_added: Vec::<Mdl>::new(),
+            num_merge_mismatches: 0,
+            power_log: None,
+            recomputed_ops: HashSet::new(),
+            condition_cache: ConditionCache::default(),
+            pruned_nodes: HashSet::new(),
+            frozen_nodes: HashSet::new(),
+            waiting_nodes: Default::default(),
+            revived: false,
         }
     }
 }
 
-impl Analysis<Mdl> for TensorAnalysis {
-    type Data = ValTnsr;
-
-    /// Merges two metadata when two eclasses are merged.
-    fn merge(&mut self, to: &mut Self::Data, from: Self::Data) -> DidMerge {
-        let a0 = to.all_weights.clone();
-        let b = from.all_weights.clone();
+/// The settings a run chooses for its TensorAnalysis. A checkpoint keeps
+/// them, so that the EGraph it restores is analyzed as in the saturation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AnalysisSettings {
+    /// Whether the shape checks of the rules are cached (see rulecache.rs)
+    pub condition_cache: bool,
+    /// Whether the GPU power is recorded while TASO measures new ops
+    pub measure_power: bool,
+    /// Ops the backend may recompute in each consumer
+    pub recomputed_ops: BTreeSet<String>,
+}
 
-        if from.all_weights && (!to.all_weights) {
-            to.all_weights = from.all_weights;
+impl TensorAnalysis {
+    /// Constructs an analysis with the settings of a run
+    ///
+    /// # Returns
+    ///
+    /// The analysis, or why the GPU power can not be sampled
+    pub fn with_settings(settings: &AnalysisSettings) -> Result<Self, String> {
+        let mut analysis = TensorAnalysis::default();
+        if settings.measure_power {
+            let sampler = PowerSampler::start(0).map_err(|e| format!("Could not sample the GPU power: {}", e))?;
+            analysis.power_log = Some(Arc::new(Mutex::new(PowerLog::new(sampler))));
         }
-        
-        let a1 = to.all_weights.clone();
-
-        let a_merged = if a0 != a1 {
-            true
-        } else {
-            false
-        };
+        analysis.condition_cache = ConditionCache::new(settings.condition_cache);
+        analysis.recomputed_ops = settings.recomputed_ops.iter().cloned().collect();
+        Ok(analysis)
+    }
 
-        let b_merged = if b != a1 {
-            true
-        } else {
-            false
-        };
-        
-        DidMerge(a_merged, b_merged)
+    /// Gets the settings the analysis was constructed with
+    pub fn settings(&self) -> AnalysisSettings {
+        AnalysisSettings {
+            condition_cache: self.condition_cache.enabled,
+            measure_power: self.power_log.is_some(),
+            recomputed_ops: self.recomputed_ops.iter().cloned().collect(),
+        }
     }
+}
 
-    // Constructs metadata for a new enode, using TASO side functions for tensors.
-    fn make(egraph: &EGraph<Mdl, Self>, enode: &Mdl) -> Self::Data {
+impl TensorAnalysis {
+    /// Constructs metadata for a new enode, calling TASO side functions for tensors
+    ///
+    /// # Returns
+    ///
+    /// The metadata, or the error reported by TASO if the op could not be created
+    fn make_checked(
+        egraph: &EGraph<Mdl, Self>,
+        g: &mut TasoGraph,
+        enode: &Mdl,
+    ) -> Result<ValTnsr, TasoError> {
         let x = |i: &Id| &egraph[*i].data;
-        let dim_from_name = |name: &Id| {
-            let name_vec: Vec<&str> = x(name).name.split("@").collect();
-            assert!(name_vec.len() == 2);
-            let dims: Vec<i32> = name_vec[1]
-                .split("_")
-                .map(|x| x.parse::<i32>().unwrap())
-                .collect();
-            dims
-        };
+        let parsed_name = |name: &Id| tensor_name(x(name).name).unwrap_or_else(|e| panic!("{}", e));
+        let dim_from_name = |name: &Id| parsed_name(name).dims.clone();
+
+        // Ops can not be created from an infeasible input. Noop is the only op
+        // whose (feasible) output has no tensor
+        for child in enode.children() {
+            let data = x(child);
+            let is_tensor = data.dtype == DataKind::Tnsr || data.dtype == DataKind::TnsrTuple;
+            if data.infeasible || (is_tensor && data.meta.is_null() && !matches!(enode, Mdl::Noop(_))) {
+                return Err(TasoError::MissingInput);
+            }
+        }
 
-        // let mut g = egraph.analysis.graph.borrow_mut();
-        let mut g = egraph.analysis.graph.lock().unwrap();
-        match enode {
-            Mdl::Matmul([act, a, b]) => {
+        let mut data = match enode {
+            Mdl::Matmul([act, a, b]) | Mdl::Smatmul([act, a, b]) => {
                 // Check types
                 assert!(x(act).dtype == DataKind::Scalar);
                 assert!(x(a).dtype == DataKind::Tnsr);
                 assert!(x(b).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_a = x(a).meta;
-                let t_b = x(b).meta;
+                let t_a = x(a).meta.ptr();
+                let t_b = x(b).meta.ptr();
                 let activation: ActiMode = x(act).val.try_into().unwrap();
                 let all_weights = x(a).all_weights && x(b).all_weights;
 
                 // Create tensorhandle and get metadata
-                let res = unsafe { g.matmul(t_a, t_b, activation) };
-                Self::Data {
+                let res = g.create("matmul", |g| unsafe { g.matmul(t_a, t_b, activation) })?;
+                ValTnsr {
+                    dtype: DataKind::Tnsr,
+                    val: 0,
+                    name: Symbol::from(""),
+                    meta: res,
+                    meta_2: TasoTensor::null(),
+                    all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
+                }
+            }
+
+            Mdl::Dense([act, a, b, bias]) => {
+                // Check types
+                assert!(x(act).dtype == DataKind::Scalar);
+                assert!(x(a).dtype == DataKind::Tnsr);
+                assert!(x(b).dtype == DataKind::Tnsr);
+                assert!(x(bias).dtype == DataKind::Tnsr);
+
+                // Get arguments
+                let t_a = x(a).meta.ptr();
+                let t_b = x(b).meta.ptr();
+                let t_bias = x(bias).meta.ptr();
+                let all_weights = x(a).all_weights && x(b).all_weights && x(bias).all_weights;
+
+                // Create tensorhandle and get metadata, as the matmul, the
+                // bias add and the activation
+                let mm = g.create("matmul", |g| unsafe { g.matmul(t_a, t_b, ACTNONE.try_into().unwrap()) })?;
+                broadcast_shape(&handle_dims(mm.ptr()), &handle_dims(t_bias)).ok_or_else(|| {
+                    TasoError::InvalidShape(format!(
+                        "bias {:?} does not broadcast to {:?}",
+                        handle_dims(t_bias),
+                        handle_dims(mm.ptr())
+                    ))
+                })?;
+                let res = g.create("element", |g| unsafe { g.element(OpType_OP_EW_ADD, mm.ptr(), t_bias) })?;
+                let res = match x(act).val {
+                    ACTNONE => res,
+                    ACTRELU => g.create("relu", |g| unsafe { g.relu(res.ptr(), true) })?,
+                    ACTTANH => g.create("tanh", |g| unsafe { g.tanh(res.ptr(), true) })?,
+                    ACTSIGMOID => g.create("sigmoid", |g| unsafe { g.sigmoid(res.ptr(), true) })?,
+                    other => return Err(TasoError::Unsupported(format!("activation {}", other))),
+                };
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
+                }
+            }
+
+            Mdl::Softmax([a, axis]) => {
+                // Check types
+                assert!(x(a).dtype == DataKind::Tnsr);
+                assert!(x(axis).dtype == DataKind::Scalar);
+
+                // Get arguments
+                let mut dims = x(a).shape.map(shape_dims).unwrap_or_default();
+                let ndim = dims.len();
+                if x(axis).val < 0 || x(axis).val as usize >= ndim {
+                    return Err(TasoError::InvalidShape(format!("softmax along axis {} of {:?}", x(axis).val, dims)));
+                }
+                let ptr = dims.as_mut_ptr();
+
+                // TASO has no softmax, the output is created like an input
+                // (see Mdl::Opaque), of the shape of the input
+                let res = g.create("new_input", |g| unsafe { g.new_input(ndim.try_into().unwrap(), ptr) })?;
+                ValTnsr {
+                    dtype: DataKind::Tnsr,
+                    val: 0,
+                    name: Symbol::from(""),
+                    meta: res,
+                    meta_2: TasoTensor::null(),
+                    all_weights: x(a).all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -222,26 +428,34 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(var).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_inpt = x(input).meta;
-                let t_scale = x(scale).meta;
-                let t_bias = x(bias).meta;
-                let t_mean = x(mean).meta;
-                let t_var = x(var).meta;
+                let t_inpt = x(input).meta.ptr();
+                let t_scale = x(scale).meta.ptr();
+                let t_bias = x(bias).meta.ptr();
+                let t_mean = x(mean).meta.ptr();
+                let t_var = x(var).meta.ptr();
                 let all_weights = x(input).all_weights && x(scale).all_weights && x(bias).all_weights && x(mean).all_weights && x(var).all_weights;
 
                 // Create tensorhandle and get metadata
                 let res =
-                    unsafe { g.batchnorm(t_inpt, t_scale, t_bias, t_mean, t_var) };
-                Self::Data {
+                    g.create("batchnorm", |g| unsafe { g.batchnorm(t_inpt, t_scale, t_bias, t_mean, t_var) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             },
-            Mdl::Conv2d([stride_h, stride_w, pad, act, inpt, wght]) => {
+            Mdl::Conv2d([stride_h, stride_w, pad, act, inpt, wght])
+            | Mdl::Sconv2d([stride_h, stride_w, pad, act, inpt, wght])
+            | Mdl::Conv2dBlocked([stride_h, stride_w, pad, act, inpt, wght]) => {
                 // Check types
                 assert!(x(stride_h).dtype == DataKind::Scalar);
                 assert!(x(stride_w).dtype == DataKind::Scalar);
@@ -251,8 +465,8 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(wght).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_inpt = x(inpt).meta;
-                let t_wght = x(wght).meta;
+                let t_inpt = x(inpt).meta.ptr();
+                let t_wght = x(wght).meta.ptr();
                 let strideH = x(stride_h).val;
                 let strideW = x(stride_w).val;
                 let padding: PaddingMode = x(pad).val.try_into().unwrap();
@@ -261,14 +475,20 @@ impl Analysis<Mdl> for TensorAnalysis {
 
                 // Create tensorhandle and get metadata
                 let res =
-                    unsafe { g.conv2d1(t_inpt, t_wght, strideH, strideW, padding, activation) };
-                Self::Data {
+                    g.create("conv2d1", |g| unsafe { g.conv2d1(t_inpt, t_wght, strideH, strideW, padding, activation) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -278,19 +498,35 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(b).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_a = x(a).meta;
-                let t_b = x(b).meta;
+                let t_a = x(a).meta.ptr();
+                let t_b = x(b).meta.ptr();
                 let all_weights = x(a).all_weights && x(b).all_weights;
 
+                // TASO asserts that the inputs broadcast together, and
+                // measures the op on the broadcast shape
+                broadcast_shape(&handle_dims(t_a), &handle_dims(t_b)).ok_or_else(|| {
+                    TasoError::InvalidShape(format!(
+                        "{:?} and {:?} do not broadcast",
+                        handle_dims(t_a),
+                        handle_dims(t_b)
+                    ))
+                })?;
+
                 // Create tensorhandle and get metadata
-                let res = unsafe { g.element(OpType_OP_EW_ADD, t_a, t_b) };
-                Self::Data {
+                let res = g.create("element", |g| unsafe { g.element(OpType_OP_EW_ADD, t_a, t_b) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -300,83 +536,147 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(b).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_a = x(a).meta;
-                let t_b = x(b).meta;
+                let t_a = x(a).meta.ptr();
+                let t_b = x(b).meta.ptr();
                 let all_weights = x(a).all_weights && x(b).all_weights;
 
+                // TASO asserts that the inputs broadcast together, and
+                // measures the op on the broadcast shape
+                broadcast_shape(&handle_dims(t_a), &handle_dims(t_b)).ok_or_else(|| {
+                    TasoError::InvalidShape(format!(
+                        "{:?} and {:?} do not broadcast",
+                        handle_dims(t_a),
+                        handle_dims(t_b)
+                    ))
+                })?;
+
                 // Create tensorhandle and get metadata
-                let res = unsafe { g.element(OpType_OP_EW_MUL, t_a, t_b) };
-                Self::Data {
+                let res = g.create("element", |g| unsafe { g.element(OpType_OP_EW_MUL, t_a, t_b) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
+                }
+            }
+
+            Mdl::Repack([layout, a]) => {
+                assert!(x(layout).dtype == DataKind::Scalar);
+                assert!(x(a).dtype == DataKind::Tnsr);
+
+                // The layout is not visible to TASO, the repacked weight has
+                // the same tensor
+                ValTnsr {
+                    name: Symbol::from(""),
+                    ..x(a).clone()
+                }
+            }
+
+            Mdl::Cast([elem, a]) => {
+                assert!(x(elem).dtype == DataKind::Scalar);
+                assert!(x(a).dtype == DataKind::Tnsr);
+
+                // TASO's tensors are all fp32, the cast has the same tensor
+                // (its element type is set below)
+                ValTnsr {
+                    name: Symbol::from(""),
+                    ..x(a).clone()
                 }
             }
 
             Mdl::Dropout(a) => {
                 assert!(x(a).dtype == DataKind::Tnsr);
-                let t_a = x(a).meta;
+                let t_a = x(a).meta.ptr();
                 let all_weights = x(a).all_weights;
 
-                let res = unsafe { g.dropout(t_a) };
-                Self::Data {
+                let res = g.create("dropout", |g| unsafe { g.dropout(t_a) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
  
             Mdl::Relu(a) => {
                 assert!(x(a).dtype == DataKind::Tnsr);
-                let t_a = x(a).meta;
+                let t_a = x(a).meta.ptr();
                 let all_weights = x(a).all_weights;
 
-                let res = unsafe { g.relu(t_a, true) };
-                Self::Data {
+                let res = g.create("relu", |g| unsafe { g.relu(t_a, true) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
             Mdl::Tanh(a) => {
                 assert!(x(a).dtype == DataKind::Tnsr);
-                let t_a = x(a).meta;
+                let t_a = x(a).meta.ptr();
                 let all_weights = x(a).all_weights;
 
-                let res = unsafe { g.tanh(t_a, true) };
-                Self::Data {
+                let res = g.create("tanh", |g| unsafe { g.tanh(t_a, true) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
             Mdl::Sigmoid(a) => {
                 assert!(x(a).dtype == DataKind::Tnsr);
-                let t_a = x(a).meta;
+                let t_a = x(a).meta.ptr();
                 let all_weights = x(a).all_weights;
 
-                let res = unsafe { g.sigmoid(t_a, true) };
-                Self::Data {
+                let res = g.create("sigmoid", |g| unsafe { g.sigmoid(t_a, true) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -387,20 +687,93 @@ impl Analysis<Mdl> for TensorAnalysis {
                 // Get arguments
                 let mut dims = dim_from_name(name);
                 let ndim = dims.len();
-                dims.shrink_to_fit();
-                assert!(dims.len() == dims.capacity());
                 let ptr = dims.as_mut_ptr();
-                std::mem::forget(dims);
 
                 // Create tensorhandle and get metadata
-                let res = unsafe { g.new_input(ndim.try_into().unwrap(), ptr) };
-                Self::Data {
+                let res = g.create("new_input", |g| unsafe { g.new_input(ndim.try_into().unwrap(), ptr) })?;
+                ValTnsr {
+                    dtype: DataKind::Tnsr,
+                    val: 0,
+                    name: Symbol::from(""),
+                    meta: res,
+                    meta_2: TasoTensor::null(),
+                    all_weights: false,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
+                }
+            }
+
+            Mdl::Opaque([name, a, b]) => {
+                // Check types
+                assert!(x(name).dtype == DataKind::Name);
+                assert!(x(a).dtype == DataKind::Tnsr);
+                assert!(x(b).dtype == DataKind::Tnsr);
+
+                // Get arguments
+                let mut dims = dim_from_name(name);
+                let ndim = dims.len();
+                let ptr = dims.as_mut_ptr();
+
+                // The output is not computed from the inputs on the TASO side,
+                // it is created like an input
+                let res = g.create("new_input", |g| unsafe { g.new_input(ndim.try_into().unwrap(), ptr) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: false,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
+                }
+            }
+
+            Mdl::Custom(children) => {
+                // Check types
+                assert!(x(&children[0]).dtype == DataKind::Name);
+                assert!(children[1..].iter().all(|t| x(t).dtype == DataKind::Tnsr));
+
+                // Get arguments
+                let label = x(&children[0]).name;
+                let (custom, attrs) = parse_custom_label(label.as_str())
+                    .ok_or_else(|| TasoError::Unsupported(format!("custom op {} is not registered", label)))?;
+                let inputs: Vec<Vec<i32>> = children[1..].iter().filter_map(|t| x(t).shape).map(shape_dims).collect();
+                if inputs.len() != custom.arity {
+                    return Err(TasoError::Unsupported(format!(
+                        "custom op {} takes {} inputs, got {}",
+                        custom.name,
+                        custom.arity,
+                        inputs.len()
+                    )));
+                }
+                let mut dims = (custom.shape)(&inputs, &attrs).map_err(TasoError::InvalidShape)?;
+                let ndim = dims.len();
+                let ptr = dims.as_mut_ptr();
+
+                // Like opaque ops, the output is created like an input
+                let res = g.create("new_input", |g| unsafe { g.new_input(ndim.try_into().unwrap(), ptr) })?;
+                ValTnsr {
+                    dtype: DataKind::Tnsr,
+                    val: 0,
+                    name: Symbol::from(""),
+                    meta: res,
+                    meta_2: TasoTensor::null(),
+                    all_weights: children[1..].iter().all(|t| x(t).all_weights),
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -411,28 +784,38 @@ impl Analysis<Mdl> for TensorAnalysis {
                 // Get arguments
                 let mut dims = dim_from_name(name);
                 let ndim = dims.len();
-                dims.shrink_to_fit();
-                assert!(dims.len() == dims.capacity());
 
-                let num_entries = dims.iter().product();
-                let mut weight_data: Vec<f32> = (0..num_entries).map(|_| rand::random()).collect();
-                weight_data.shrink_to_fit();
-                assert!(weight_data.len() == weight_data.capacity());
+                // Pruned weights have a target density in their name, the
+                // pruned entries are set to zero
+                let target_density = parsed_name(name).density.unwrap_or(1.0);
+                assert!(target_density > 0.0 && target_density <= 1.0);
+
+                // Seeded random values, or the loaded values of the weight
+                let num_entries = dims.iter().product::<i32>() as usize;
+                let mut weight_data = weight_values()
+                    .values(&parsed_name(name).label, num_entries, target_density)
+                    .unwrap_or_else(|e| panic!("{}", e));
+                let nnz = weight_data.iter().filter(|v| **v != 0.0).count();
+                let density = nnz as f32 / weight_data.len() as f32;
 
                 let ptr = dims.as_mut_ptr();
-                std::mem::forget(dims);
                 let data_ptr = weight_data.as_mut_ptr();
-                std::mem::forget(weight_data);
 
                 // Create tensorhandle and get metadata
-                let res = unsafe { g.new_weight(ndim.try_into().unwrap(), ptr, data_ptr) };
-                Self::Data {
+                let res = g.create("new_weight", |g| unsafe { g.new_weight(ndim.try_into().unwrap(), ptr, data_ptr) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: true,
+                    infeasible: false,
+                    density: density,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -444,21 +827,56 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(b).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_a = x(a).meta;
-                let t_b = x(b).meta;
+                let t_a = x(a).meta.ptr();
+                let t_b = x(b).meta.ptr();
                 let axis_val = x(axis).val;
                 let all_weights = x(a).all_weights && x(b).all_weights;
 
                 // Create tensorhandle and get metadata
                 let t = [t_a, t_b];
-                let res = unsafe { g.concat(axis_val, 2, t.as_ptr()) };
-                Self::Data {
+                let res = g.create("concat", |g| unsafe { g.concat(axis_val, 2, t.as_ptr()) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
+                }
+            }
+
+            Mdl::ConcatN(children) => {
+                // Check types
+                assert!(x(&children[0]).dtype == DataKind::Scalar);
+                assert!(x(&children[1]).dtype == DataKind::Scalar);
+                assert!(children[2..].iter().all(|t| x(t).dtype == DataKind::Tnsr));
+
+                // Get arguments
+                let t: Vec<TensorHandle> = children[2..].iter().map(|t| x(t).meta.ptr()).collect();
+                let axis_val = x(&children[0]).val;
+                let all_weights = children[2..].iter().all(|t| x(t).all_weights);
+
+                // Create tensorhandle and get metadata
+                let res = g.create("concat", |g| unsafe { g.concat(axis_val, t.len() as i32, t.as_ptr()) })?;
+                ValTnsr {
+                    dtype: DataKind::Tnsr,
+                    val: 0,
+                    name: Symbol::from(""),
+                    meta: res,
+                    meta_2: TasoTensor::null(),
+                    all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -471,9 +889,9 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(input3).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_1 = x(input1).meta;
-                let t_2 = x(input2).meta;
-                let t_3 = x(input3).meta;
+                let t_1 = x(input1).meta.ptr();
+                let t_2 = x(input2).meta.ptr();
+                let t_3 = x(input3).meta.ptr();
                 let axis_val = x(axis).val;
                 let all_weights = x(input1).all_weights
                     && x(input2).all_weights
@@ -481,14 +899,20 @@ impl Analysis<Mdl> for TensorAnalysis {
 
                 // Create tensorhandle and get metadata
                 let t = [t_1, t_2, t_3];
-                let res = unsafe { g.concat(axis_val, 3, t.as_ptr()) };
-                Self::Data {
+                let res = g.create("concat", |g| unsafe { g.concat(axis_val, 3, t.as_ptr()) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -502,10 +926,10 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(input4).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_1 = x(input1).meta;
-                let t_2 = x(input2).meta;
-                let t_3 = x(input3).meta;
-                let t_4 = x(input4).meta;
+                let t_1 = x(input1).meta.ptr();
+                let t_2 = x(input2).meta.ptr();
+                let t_3 = x(input3).meta.ptr();
+                let t_4 = x(input4).meta.ptr();
                 let axis_val = x(axis).val;
                 let all_weights = x(input1).all_weights
                     && x(input2).all_weights
@@ -514,14 +938,20 @@ impl Analysis<Mdl> for TensorAnalysis {
 
                 // Create tensorhandle and get metadata
                 let t = [t_1, t_2, t_3, t_4];
-                let res = unsafe { g.concat(axis_val, 4, t.as_ptr()) };
-                Self::Data {
+                let res = g.create("concat", |g| unsafe { g.concat(axis_val, 4, t.as_ptr()) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -536,11 +966,11 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(input5).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_1 = x(input1).meta;
-                let t_2 = x(input2).meta;
-                let t_3 = x(input3).meta;
-                let t_4 = x(input4).meta;
-                let t_5 = x(input5).meta;
+                let t_1 = x(input1).meta.ptr();
+                let t_2 = x(input2).meta.ptr();
+                let t_3 = x(input3).meta.ptr();
+                let t_4 = x(input4).meta.ptr();
+                let t_5 = x(input5).meta.ptr();
                 let axis_val = x(axis).val;
                 let all_weights = x(input1).all_weights
                     && x(input2).all_weights
@@ -550,14 +980,20 @@ impl Analysis<Mdl> for TensorAnalysis {
 
                 // Create tensorhandle and get metadata
                 let t = [t_1, t_2, t_3, t_4, t_5];
-                let res = unsafe { g.concat(axis_val, 5, t.as_ptr()) };
-                Self::Data {
+                let res = g.create("concat", |g| unsafe { g.concat(axis_val, 5, t.as_ptr()) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -567,19 +1003,25 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(weight).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_weight = x(weight).meta;
+                let t_weight = x(weight).meta.ptr();
                 let count_val = x(count).val;
                 let all_weights = x(weight).all_weights;
 
                 // Create tensorhandle and get metadata
-                let res = unsafe { g.merge_gconv(t_weight, count_val) };
-                Self::Data {
+                let res = g.create("merge_gconv", |g| unsafe { g.merge_gconv(t_weight, count_val) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -594,7 +1036,7 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(inpt).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_inpt = x(inpt).meta;
+                let t_inpt = x(inpt).meta.ptr();
                 let kernelH = x(kernel_h).val;
                 let kernelW = x(kernel_w).val;
                 let strideH = x(stride_h).val;
@@ -604,18 +1046,24 @@ impl Analysis<Mdl> for TensorAnalysis {
                 let all_weights = x(inpt).all_weights;
 
                 // Create tensorhandle and get metadata
-                let res = unsafe {
+                let res = g.create("pool2d_max", |g| unsafe {
                     g.pool2d_max(
                         t_inpt, kernelH, kernelW, strideH, strideW, padding, activation,
                     )
-                };
-                Self::Data {
+                })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -630,7 +1078,7 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(inpt).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_inpt = x(inpt).meta;
+                let t_inpt = x(inpt).meta.ptr();
                 let kernelH = x(kernel_h).val;
                 let kernelW = x(kernel_w).val;
                 let strideH = x(stride_h).val;
@@ -640,18 +1088,95 @@ impl Analysis<Mdl> for TensorAnalysis {
                 let all_weights = x(inpt).all_weights;
 
                 // Create tensorhandle and get metadata
-                let res = unsafe {
+                let res = g.create("pool2d_avg", |g| unsafe {
                     g.pool2d_avg(
                         t_inpt, kernelH, kernelW, strideH, strideW, padding, activation,
                     )
+                })?;
+                ValTnsr {
+                    dtype: DataKind::Tnsr,
+                    val: 0,
+                    name: Symbol::from(""),
+                    meta: res,
+                    meta_2: TasoTensor::null(),
+                    all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
+                }
+            }
+
+            Mdl::Conv2dPacked([attrs, inpt, wght]) | Mdl::Sconv2dPacked([attrs, inpt, wght]) => {
+                // Check types
+                assert!(x(attrs).dtype == DataKind::Scalar);
+                assert!(x(inpt).dtype == DataKind::Tnsr);
+                assert!(x(wght).dtype == DataKind::Tnsr);
+
+                // Get arguments
+                let t_inpt = x(inpt).meta.ptr();
+                let t_wght = x(wght).meta.ptr();
+                let (strides, pad, act) = unpack_attrs(x(attrs).val, 2);
+                let padding: PaddingMode = pad.try_into().unwrap();
+                let activation: ActiMode = act.try_into().unwrap();
+                let all_weights = x(inpt).all_weights && x(wght).all_weights;
+
+                // Create tensorhandle and get metadata
+                let res = g.create("conv2d1", |g| unsafe {
+                    g.conv2d1(t_inpt, t_wght, strides[0], strides[1], padding, activation)
+                })?;
+                ValTnsr {
+                    dtype: DataKind::Tnsr,
+                    val: 0,
+                    name: Symbol::from(""),
+                    meta: res,
+                    meta_2: TasoTensor::null(),
+                    all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
+                }
+            }
+
+            Mdl::PoolmaxPacked([inpt, attrs]) | Mdl::PoolavgPacked([inpt, attrs]) => {
+                // Check types
+                assert!(x(attrs).dtype == DataKind::Scalar);
+                assert!(x(inpt).dtype == DataKind::Tnsr);
+
+                // Get arguments
+                let t_inpt = x(inpt).meta.ptr();
+                let (sizes, pad, act) = unpack_attrs(x(attrs).val, 4);
+                let padding: PaddingMode = pad.try_into().unwrap();
+                let activation: ActiMode = act.try_into().unwrap();
+                let all_weights = x(inpt).all_weights;
+
+                // Create tensorhandle and get metadata
+                let res = match enode {
+                    Mdl::PoolmaxPacked(_) => g.create("pool2d_max", |g| unsafe {
+                        g.pool2d_max(t_inpt, sizes[0], sizes[1], sizes[2], sizes[3], padding, activation)
+                    })?,
+                    _ => g.create("pool2d_avg", |g| unsafe {
+                        g.pool2d_avg(t_inpt, sizes[0], sizes[1], sizes[2], sizes[3], padding, activation)
+                    })?,
                 };
-                Self::Data {
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -661,7 +1186,7 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(inpt).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_inpt = x(inpt).meta;
+                let t_inpt = x(inpt).meta.ptr();
                 let axis_val = x(axis).val;
                 let all_weights = x(inpt).all_weights;
 
@@ -669,22 +1194,72 @@ impl Analysis<Mdl> for TensorAnalysis {
                 unsafe {
                     // Has to do it this way since TASO side does not provide a
                     // Graph.split() function that infers split position from input
-                    let op = (*g.model).get_or_create_split1(t_inpt, axis_val, 2);
-                    assert!(op != Op_INVALID_OP);
+                    let op = check_op("split", (*g.model).get_or_create_split1(t_inpt, axis_val, 2))?;
                     g.add_edge((*t_inpt).op, op, (*t_inpt).idx, 0);
-                    let x1 = Box::new((*op.ptr).outputs[0].clone());
-                    let res_1 = Box::into_raw(x1);
-                    (*res_1).op = op;
-                    let x2 = Box::new((*op.ptr).outputs[1].clone());
-                    let res_2 = Box::into_raw(x2);
-                    (*res_2).op = op;
-                    Self::Data {
+                    let mut t1 = (*op.ptr).outputs[0].clone();
+                    t1.op = op;
+                    let res_1 = g.own_tensor(t1);
+                    let mut t2 = (*op.ptr).outputs[1].clone();
+                    t2.op = op;
+                    let res_2 = g.own_tensor(t2);
+                    ValTnsr {
                         dtype: DataKind::TnsrTuple,
                         val: 0,
-                        name: String::new(),
+                        name: Symbol::from(""),
                         meta: res_1,
                         meta_2: res_2,
                         all_weights: all_weights,
+                        infeasible: false,
+                        density: 1.0,
+                        elem: ElemType::Float32,
+                        bytes: 0,
+                        shape: None,
+                        shape_2: None,
+                    }
+                }
+            }
+
+            Mdl::SplitI([axis, count, index, inpt]) => {
+                // Check types
+                assert!(x(axis).dtype == DataKind::Scalar);
+                assert!(x(count).dtype == DataKind::Scalar);
+                assert!(x(index).dtype == DataKind::Scalar);
+                assert!(x(inpt).dtype == DataKind::Tnsr);
+
+                // Get arguments
+                let t_inpt = x(inpt).meta.ptr();
+                let axis_val = x(axis).val;
+                let count_val = x(count).val;
+                let index_val = x(index).val;
+                let all_weights = x(inpt).all_weights;
+                if count_val < 2 || index_val < 0 || index_val >= count_val {
+                    return Err(TasoError::Unsupported(format!(
+                        "split_i output {} of {} outputs",
+                        index_val, count_val
+                    )));
+                }
+
+                // Create tensorhandle and get metadata. The split op is the
+                // same for all outputs, TASO creates it once
+                unsafe {
+                    let op = check_op("split", (*g.model).get_or_create_split1(t_inpt, axis_val, count_val))?;
+                    g.add_edge((*t_inpt).op, op, (*t_inpt).idx, 0);
+                    let mut t = (*op.ptr).outputs[index_val as usize].clone();
+                    t.op = op;
+                    let res = g.own_tensor(t);
+                    ValTnsr {
+                        dtype: DataKind::Tnsr,
+                        val: 0,
+                        name: Symbol::from(""),
+                        meta: res,
+                        meta_2: TasoTensor::null(),
+                        all_weights: all_weights,
+                        infeasible: false,
+                        density: 1.0,
+                        elem: ElemType::Float32,
+                        bytes: 0,
+                        shape: None,
+                        shape_2: None,
                     }
                 }
             }
@@ -694,14 +1269,20 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(inpt).dtype == DataKind::TnsrTuple);
                 let all_weights = x(inpt).all_weights;
 
-                let res = x(inpt).meta;
-                Self::Data {
+                let res = x(inpt).meta.clone();
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -710,14 +1291,20 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(inpt).dtype == DataKind::TnsrTuple);
                 let all_weights = x(inpt).all_weights;
 
-                let res = x(inpt).meta_2;
-                Self::Data {
+                let res = x(inpt).meta_2.clone();
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -727,19 +1314,25 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(b).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_a = x(a).meta;
-                let t_b = x(b).meta;
+                let t_a = x(a).meta.ptr();
+                let t_b = x(b).meta.ptr();
                 let all_weights = x(a).all_weights && x(b).all_weights;
 
                 // Create tensorhandle and get metadata
-                let res = unsafe { g.enlarge(t_a, t_b) };
-                Self::Data {
+                let res = g.create("enlarge", |g| unsafe { g.enlarge(t_a, t_b) })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -749,27 +1342,29 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(inpt).dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let dims: Vec<i32> = x(shape_name)
-                    .name
-                    .split("_")
-                    .map(|x| x.parse::<i32>().unwrap())
-                    .collect();
-                let t_inpt = x(inpt).meta;
+                let dims: Vec<i32> = parse_dims(x(shape_name).name.as_str()).unwrap();
+                let t_inpt = x(inpt).meta.ptr();
                 let all_weights = x(inpt).all_weights;
 
                 // Create tensorhandle and get metadata
-                let res = unsafe {
+                let res = g.create("reshape", |g| unsafe {
                     let cpp_dims = convert_to_cpp_vec(&dims);
                     let ptr = cpp_dims.as_ptr() as *const [u64; 3];
                     g.reshape(t_inpt, ptr)
-                };
-                Self::Data {
+                })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -780,29 +1375,31 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(shuffle).dtype == DataKind::Scalar);
 
                 // Get arguments
-                let perms: Vec<i32> = x(perm_name)
-                    .name
-                    .split("_")
-                    .map(|x| x.parse::<i32>().unwrap())
-                    .collect();
-                let t_inpt = x(inpt).meta;
+                let perms: Vec<i32> = parse_dims(x(perm_name).name.as_str()).unwrap();
+                let t_inpt = x(inpt).meta.ptr();
                 let shuffle_val = x(shuffle).val;
                 let shuffle_bool = (shuffle_val == SHUFFLE);
                 let all_weights = x(inpt).all_weights;
 
                 // Create tensorhandle and get metadata
-                let res = unsafe {
+                let res = g.create("transpose", |g| unsafe {
                     let cpp_perms = convert_to_cpp_vec(&perms);
                     let ptr = cpp_perms.as_ptr() as *const [u64; 3];
                     g.transpose(t_inpt, ptr, shuffle_bool)
-                };
-                Self::Data {
+                })?;
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
+                    name: Symbol::from(""),
                     meta: res,
-                    meta_2: std::ptr::null_mut(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
@@ -812,43 +1409,337 @@ impl Analysis<Mdl> for TensorAnalysis {
                 assert!(x(b).dtype == DataKind::Tnsr);
                 let all_weights = x(a).all_weights && x(b).all_weights;
 
-                Self::Data {
+                ValTnsr {
                     dtype: DataKind::Tnsr,
                     val: 0,
-                    name: String::new(),
-                    meta: std::ptr::null_mut(),
-                    meta_2: std::ptr::null_mut(),
+                    name: Symbol::from(""),
+                    meta: TasoTensor::null(),
+                    meta_2: TasoTensor::null(),
                     all_weights: all_weights,
+                    infeasible: false,
+                    density: 1.0,
+                    elem: ElemType::Float32,
+                    bytes: 0,
+                    shape: None,
+                    shape_2: None,
                 }
             }
 
-            Mdl::Num(_n) => Self::Data {
+            Mdl::Num(_n) => ValTnsr {
                 dtype: DataKind::Scalar,
                 val: *_n,
-                name: String::new(),
-                meta: std::ptr::null_mut(),
-                meta_2: std::ptr::null_mut(),
+                name: Symbol::from(""),
+                meta: TasoTensor::null(),
+                meta_2: TasoTensor::null(),
                 all_weights: false,
+                infeasible: false,
+                density: 1.0,
+                elem: ElemType::Float32,
+                bytes: 0,
+                shape: None,
+                shape_2: None,
             },
 
-            Mdl::Var(_s) => Self::Data {
+            Mdl::Var(_s) => ValTnsr {
                 dtype: DataKind::Name,
                 val: 0,
-                name: _s.as_str().to_string(),
-                meta: std::ptr::null_mut(),
-                meta_2: std::ptr::null_mut(),
+                name: *_s,
+                meta: TasoTensor::null(),
+                meta_2: TasoTensor::null(),
                 all_weights: false,
+                infeasible: false,
+                density: 1.0,
+                elem: ElemType::Float32,
+                bytes: 0,
+                shape: None,
+                shape_2: None,
             },
 
-            other => {
-                println!("{:?}", other);
-                todo!()
+            other => return Err(TasoError::Unsupported(format!("{:?}", other))),
+        };
+
+        // TASO does not validate all output shapes, e.g. for convolutions
+        // with kernels larger than the input
+        for t in &[data.meta.ptr(), data.meta_2.ptr()] {
+            if !t.is_null() {
+                check_shape(*t)?;
             }
         }
+        if data.all_weights {
+            data.density = weight_density(egraph, enode, &data);
+        }
+        data.elem = output_elem(egraph, enode)?;
+        data.shape = handle_shape(data.meta.ptr());
+        data.shape_2 = handle_shape(data.meta_2.ptr());
+        data.bytes = [data.shape, data.shape_2]
+            .iter()
+            .flatten()
+            .map(|id| with_shape(*id, |dims| dims.iter().map(|d| *d as u64).product::<u64>()) * data.elem.bytes() as u64)
+            .sum();
+        Ok(data)
     }
 
-    // Not needed to modify anything
-    fn modify(egraph: &mut EGraph<Mdl, Self>, id: Id) {}
+}
+
+/// Ops cheap enough to recompute in their consumers, e.g. a broadcast add
+/// fused into the op reading it
+pub static RECOMPUTABLE_OPS: &[&str] = &["ewadd", "ewmul", "relu", "tanh", "sigmoid", "cast"];
+
+/// Gets the size of the activation an enode materializes: the one of its
+/// eclass (see ValTnsr::activation_bytes), or 0 if its op is in the
+/// recomputed ops of the analysis
+pub fn enode_activation_bytes(egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> u64 {
+    if !egraph.analysis.recomputed_ops.is_empty() && egraph.analysis.recomputed_ops.contains(&enode.to_string()) {
+        return 0;
+    }
+    egraph.lookup(enode.clone()).map_or(0, |id| egraph[id].data.activation_bytes())
+}
+
+/// Gets the element type of the output of an enode: the one it casts to for
+/// a cast, otherwise the one of its tensor inputs (F32 for inputs, weights
+/// and ops without tensor inputs)
+///
+/// # Returns
+///
+/// The element type, or an error if the tensor inputs have different ones,
+/// which have to be cast to the same type first
+pub fn output_elem(egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> Result<ElemType, TasoError> {
+    let x = |i: &Id| &egraph[*i].data;
+    match enode {
+        Mdl::Cast([elem, _]) => ElemType::from_code(x(elem).val)
+            .ok_or_else(|| TasoError::Unsupported(format!("element type {}", x(elem).val))),
+        // Combines the outputs of a graph, which can have any types
+        Mdl::Noop(_) => Ok(ElemType::Float32),
+        _ => {
+            let mut elems = enode
+                .children()
+                .iter()
+                .map(|id| x(id))
+                .filter(|d| d.dtype == DataKind::Tnsr || d.dtype == DataKind::TnsrTuple)
+                .map(|d| d.elem);
+            let first = elems.next().unwrap_or_default();
+            match elems.find(|e| *e != first) {
+                Some(other) => Err(TasoError::Unsupported(format!(
+                    "inputs of element types {} and {}",
+                    first.name(),
+                    other.name()
+                ))),
+                None => Ok(first),
+            }
+        }
+    }
+}
+
+/// Number of entries in the tensor t, 0 for a null tensor
+pub fn tensor_volume(t: TensorHandle) -> f32 {
+    if t.is_null() {
+        return 0.0;
+    }
+    unsafe { (*t).dim[..(*t).numDim as usize].iter().map(|d| *d as f32).product() }
+}
+
+/// Computes the density of the output of an all weights enode from the
+/// densities of its inputs
+///
+/// Ops that only move data around keep the non-zero entries of their inputs,
+/// ops padding with zeros (enlarge, merge) lower the density. Arithmetic ops
+/// generally fill in the zeros, their outputs are considered dense.
+///
+/// # Parameters
+///
+/// - `egraph`: E-graph containing the inputs of enode
+/// - `enode`: the all weights enode
+/// - `data`: the metadata of enode, with the output tensor already created
+fn weight_density(egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl, data: &ValTnsr) -> f32 {
+    let x = |i: &Id| &egraph[*i].data;
+    match enode {
+        Mdl::Weight(_) => data.density,
+        Mdl::Concat(_) | Mdl::Concat3(_) | Mdl::Concat4(_) | Mdl::Concat5(_) | Mdl::ConcatN(_) => {
+            let inputs: Vec<&ValTnsr> = enode
+                .children()
+                .iter()
+                .map(|id| x(id))
+                .filter(|d| d.dtype == DataKind::Tnsr)
+                .collect();
+            let nnz: f32 = inputs.iter().map(|d| d.density * tensor_volume(d.meta.ptr())).sum();
+            let volume: f32 = inputs.iter().map(|d| tensor_volume(d.meta.ptr())).sum();
+            nnz / volume
+        }
+        // Assumes the non-zero entries are spread evenly over both outputs
+        Mdl::Split([_, inpt]) => x(inpt).density,
+        Mdl::Split0(inpt) | Mdl::Split1(inpt) | Mdl::Dropout(inpt) => x(inpt).density,
+        Mdl::SplitI([_, _, _, inpt]) => x(inpt).density,
+        Mdl::Transpose([inpt, _, _]) | Mdl::Reshape([inpt, _]) | Mdl::Repack([_, inpt]) => x(inpt).density,
+        Mdl::Cast([_, inpt]) => x(inpt).density,
+        Mdl::Enlarge([inpt, _]) | Mdl::Merge([inpt, _]) => {
+            x(inpt).density * tensor_volume(x(inpt).meta.ptr()) / tensor_volume(data.meta.ptr())
+        }
+        _ => 1.0,
+    }
+}
+
+/// Compares the metadata of two feasible eclasses
+///
+/// # Returns
+///
+/// A description of the first difference found, or None if they agree
+fn metadata_mismatch(a: &ValTnsr, b: &ValTnsr) -> Option<String> {
+    if a.dtype != b.dtype {
+        return Some(format!("data kinds {:?} and {:?}", a.dtype, b.dtype));
+    }
+    if a.elem != b.elem {
+        return Some(format!("element types {} and {}", a.elem.name(), b.elem.name()));
+    }
+    match a.dtype {
+        DataKind::Scalar if a.val != b.val => Some(format!("values {} and {}", a.val, b.val)),
+        DataKind::Name if a.name != b.name => Some(format!("names {} and {}", a.name, b.name)),
+        DataKind::Tnsr | DataKind::TnsrTuple => {
+            for (s_a, s_b) in &[(a.shape, b.shape), (a.shape_2, b.shape_2)] {
+                if let (Some(s_a), Some(s_b)) = (s_a, s_b) {
+                    if s_a != s_b {
+                        return Some(format!("shapes {:?} and {:?}", shape_dims(*s_a), shape_dims(*s_b)));
+                    }
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// The kind of data an enode produces
+fn output_kind(enode: &Mdl) -> DataKind {
+    match enode {
+        Mdl::Num(_) => DataKind::Scalar,
+        Mdl::Var(_) => DataKind::Name,
+        Mdl::Split(_) => DataKind::TnsrTuple,
+        _ => DataKind::Tnsr,
+    }
+}
+
+impl Analysis<Mdl> for TensorAnalysis {
+    type Data = ValTnsr;
+
+    /// Merges two metadata when two eclasses are merged.
+    ///
+    /// Feasible metadata is preferred over infeasible metadata, so that an
+    /// eclass only stays infeasible if none of its enodes could be created.
+    /// If both sides are feasible they should describe the same tensor; a
+    /// disagreement points to an unsound rewrite rule and is reported.
+    fn merge(&mut self, to: &mut Self::Data, from: Self::Data) -> DidMerge {
+        let take_from = to.infeasible && !from.infeasible;
+        if !to.infeasible && !from.infeasible {
+            if let Some(mismatch) = metadata_mismatch(to, &from) {
+                self.num_merge_mismatches += 1;
+                println!("Warning: merging eclasses with different {}", mismatch);
+            }
+        }
+        let all_weights = to.all_weights || from.all_weights;
+
+        // Whether the merged result differs from each side
+        let a_merged = take_from || all_weights != to.all_weights;
+        let b_merged = (!take_from && to.infeasible != from.infeasible)
+            || all_weights != from.all_weights;
+
+        if take_from {
+            *to = from;
+            self.revived = true;
+        }
+        to.all_weights = all_weights;
+
+        DidMerge(a_merged, b_merged)
+    }
+
+    // Constructs metadata for a new enode, using TASO side functions for tensors.
+    //
+    // A failure while creating the op only affects this enode: the error is
+    // reported and the enode gets metadata without a tensor, instead of
+    // taking the whole process down.
+    fn make(egraph: &EGraph<Mdl, Self>, enode: &Mdl) -> Self::Data {
+        // Ops whose measurement hung in a previous run are not measured again
+        let key = if watchdog::is_enabled() { Some(op_key(egraph, enode)) } else { None };
+        if let Some(key) = &key {
+            if watchdog::is_hung(key) {
+                println!("Marking {:?} as infeasible: its measurement timed out", enode);
+                return ValTnsr {
+                    dtype: output_kind(enode),
+                    infeasible: true,
+                    ..Default::default()
+                };
+            }
+            watchdog::begin_measurement(key.clone());
+        }
+        // let mut g = egraph.analysis.graph.borrow_mut();
+        // The lock is taken outside of catch_panic, so that a panic does not poison it
+        let mut g = egraph.analysis.graph.lock().unwrap();
+        let start_time = std::time::Instant::now();
+        let res = catch_panic(|| Self::make_checked(egraph, &mut g, enode));
+        record_measure_time(start_time.elapsed());
+        if key.is_some() {
+            watchdog::end_measurement();
+        }
+        if let Some(power_log) = &egraph.analysis.power_log {
+            power_log.lock().unwrap().record(enode, start_time, std::time::Instant::now());
+        }
+        match res {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Marking {:?} as infeasible: {}", enode, e);
+                if e == TasoError::MissingInput {
+                    egraph.analysis.waiting_nodes.lock().unwrap().insert(enode.clone());
+                }
+                ValTnsr {
+                    dtype: output_kind(enode),
+                    infeasible: true,
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    // egg does not make an enode again when its inputs change, so the enodes
+    // made from an infeasible eclass are made again once it is feasible
+    fn modify(egraph: &mut EGraph<Mdl, Self>, _id: Id) {
+        if egraph.analysis.revived {
+            egraph.analysis.revived = false;
+            revive_waiting(egraph);
+        }
+    }
+}
+
+/// Makes again the waiting enodes whose inputs are all feasible now, giving
+/// their eclasses the feasible metadata, until no eclass becomes feasible
+///
+/// An enode made while an input eclass was infeasible has no tensor, and its
+/// eclass stays infeasible for the cost model and the extraction even after
+/// the input eclass gets a feasible enode, unless the enode is made again.
+pub fn revive_waiting(egraph: &mut EGraph<Mdl, TensorAnalysis>) {
+    loop {
+        let waiting: Vec<Mdl> = egraph.analysis.waiting_nodes.lock().unwrap().iter().cloned().collect();
+        let mut revived = false;
+        for enode in waiting {
+            let canonical = enode.clone().map_children(|c| egraph.find(c));
+            if canonical.children().iter().any(|c| egraph[*c].data.infeasible) {
+                continue;
+            }
+            egraph.analysis.waiting_nodes.lock().unwrap().remove(&enode);
+            let id = match egraph.lookup(canonical.clone()) {
+                Some(id) => egraph.find(id),
+                None => continue,
+            };
+            if !egraph[id].data.infeasible {
+                continue;
+            }
+            let data = TensorAnalysis::make(egraph, &canonical);
+            if !data.infeasible {
+                egraph[id].data = data;
+                revived = true;
+            }
+        }
+        if !revived {
+            break;
+        }
+    }
 }
 
 /// Convert rust vector to C++ vector, for ffi
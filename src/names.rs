@@ -0,0 +1,163 @@
+//! Parsed names of inputs, weights and opaque ops
+//!
+//! These nodes carry the shape of their tensor in their name, as
+//! `name@dim1_dim2...`, with a third `@density` part for pruned weights. A
+//! 0-d tensor has no dimensions, as in `name@`.
+//! Instead of splitting the name every time the analysis creates a tensor,
+//! the names are parsed once, when a model is read or built (see
+//! validate_names), and looked up by their interned Symbol. A malformed name
+//! is rejected there, instead of panicking when the analysis first gets to it.
+//!
+//! The leading dimension can be the symbolic batch dimension N, as in
+//! `input@N_3_224_224`. TASO only handles fixed shapes, so N stands for the
+//! batch size set with set_batch_value (1 by default) wherever shapes are
+//! computed, and the names keep the N: the optimized graph is written with
+//! the symbolic dimension, to be checked at other batch sizes (see
+//! specialize::check_batch_sizes).
+
+use crate::model::*;
+use egg::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// The symbolic batch dimension
+pub const BATCH_DIM: &str = "N";
+
+/// Batch size N stands for
+static BATCH_VALUE: AtomicI32 = AtomicI32::new(1);
+
+/// Sets the batch size the symbolic batch dimension stands for. Call it
+/// before any name is parsed, parsed names are not updated
+pub fn set_batch_value(batch: i32) {
+    assert!(batch > 0, "the batch size should be positive");
+    BATCH_VALUE.store(batch, Ordering::Relaxed);
+}
+
+pub fn batch_value() -> i32 {
+    BATCH_VALUE.load(Ordering::Relaxed)
+}
+
+/// A name with its parts parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorName {
+    /// The part before the dimensions
+    pub label: String,
+    /// The dimensions, with the batch value for a symbolic batch dimension
+    pub dims: Vec<i32>,
+    /// If the leading dimension is the symbolic batch dimension
+    pub symbolic_batch: bool,
+    /// Target density of a pruned weight
+    pub density: Option<f32>,
+}
+
+/// Parses dimensions written as `dim1_dim2...`. The empty string is the
+/// shape of a 0-d tensor (a scalar). A leading N is the batch value
+pub fn parse_dims(s: &str) -> Result<Vec<i32>, String> {
+    if s.is_empty() {
+        return Ok(vec![]);
+    }
+    s.split('_')
+        .enumerate()
+        .map(|(i, d)| match d {
+            BATCH_DIM if i == 0 => Ok(batch_value()),
+            BATCH_DIM => Err(String::from("only the leading dimension can be symbolic")),
+            _ => d.parse::<i32>().map_err(|_| format!("invalid dimension {}", d)),
+        })
+        .collect()
+}
+
+/// If dimensions written as `dim1_dim2...` start with the symbolic batch
+/// dimension
+pub fn has_symbolic_batch(s: &str) -> bool {
+    s.split('_').next() == Some(BATCH_DIM)
+}
+
+/// Parses a name of the form `name@dim1_dim2...[@density]`
+pub fn parse_tensor_name(name: &str) -> Result<TensorName, String> {
+    let parts: Vec<&str> = name.split('@').collect();
+    if parts.len() != 2 && parts.len() != 3 {
+        return Err(format!("name {} should be name@dim1_dim2...", name));
+    }
+    let dims = parse_dims(parts[1]).map_err(|_| format!("invalid dimensions in {}", name))?;
+    let density = match parts.get(2) {
+        Some(d) => Some(d.parse::<f32>().map_err(|_| format!("invalid density in {}", name))?),
+        None => None,
+    };
+    Ok(TensorName {
+        label: parts[0].to_string(),
+        dims: dims,
+        symbolic_batch: has_symbolic_batch(parts[1]),
+        density: density,
+    })
+}
+
+/// Names parsed so far, shared by all EGraphs of the process
+static TENSOR_NAMES: Lazy<RwLock<HashMap<Symbol, Arc<TensorName>>>> = Lazy::new(Default::default);
+
+/// Gets a parsed name, parsing it now if it was not registered
+pub fn tensor_name(name: Symbol) -> Result<Arc<TensorName>, String> {
+    if let Some(parsed) = TENSOR_NAMES.read().unwrap().get(&name) {
+        return Ok(parsed.clone());
+    }
+    let parsed = Arc::new(parse_tensor_name(name.as_str())?);
+    TENSOR_NAMES.write().unwrap().insert(name, parsed.clone());
+    Ok(parsed)
+}
+
+/// A name that could not be parsed
+#[derive(Debug, Clone)]
+pub struct NameError {
+    /// Index of the Var node of the name in the RecExpr
+    pub node: usize,
+    pub name: String,
+    pub error: String,
+}
+
+/// Parses and registers the names of all inputs, weights and opaque ops of
+/// a graph
+///
+/// # Returns
+///
+/// Ok, or all the names that could not be parsed
+pub fn validate_names(expr: &RecExpr<Mdl>) -> Result<(), Vec<NameError>> {
+    let nodes = expr.as_ref();
+    let mut errors = vec![];
+    for node in nodes.iter() {
+        if let Mdl::Input([name]) | Mdl::Weight([name]) | Mdl::Opaque([name, _, _]) = node {
+            let i = usize::from(*name);
+            match &nodes[i] {
+                Mdl::Var(s) => {
+                    if let Err(e) = tensor_name(*s) {
+                        errors.push(NameError {
+                            node: i,
+                            name: s.as_str().to_string(),
+                            error: e,
+                        });
+                    }
+                }
+                other => errors.push(NameError {
+                    node: i,
+                    name: other.to_string(),
+                    error: format!("expected a name, got {}", other),
+                }),
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parses and registers the names of all inputs, weights and opaque ops of
+/// a graph
+///
+/// # Returns
+///
+/// Ok, or the error of the first name that could not be parsed
+pub fn register_names(expr: &RecExpr<Mdl>) -> Result<(), String> {
+    validate_names(expr).map_err(|errors| format!("node {}: {}", errors[0].node, errors[0].error))
+}
@@ -0,0 +1,1178 @@
+//! Importing and exporting models serialized as ONNX
+//!
+//! The graph of an ONNX model (a ModelProto protobuf) is read with a small
+//! reader of the protobuf wire format, so only the fields used here are
+//! decoded. Its ops are then built with a GraphConverter, as in parse.rs for
+//! models exported by TASO. Graph inputs are inputs and initializers are
+//! weights of the same name (see GraphConverter::new_named_input), with their
+//! shapes in the name. An op that has no counterpart in the language, or
+//! attributes TASO can not express (e.g. dilations or asymmetric padding),
+//! fails the import with the name of the node.
+//!
+//! An optimized graph is written back the same way (see export_onnx), so it
+//! can be run by ONNX runtimes without going through TASO. Ops with fused
+//! activations are written as two standard ops, which a runtime may not fuse
+//! again, or for a chosen execution provider as its fused ops (see
+//! ExecutionProvider). The operator set written can be chosen too, for
+//! runtimes that only load older ones.
+
+use crate::custom::parse_custom_label;
+use crate::input::*;
+use crate::model::*;
+use crate::names::{batch_value, parse_dims, parse_tensor_name, BATCH_DIM};
+use crate::specialize::is_batch_symbolic;
+use crate::shape::{broadcast_shape, ElemType, TensorShape};
+use egg::*;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+/// A field of a protobuf message
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// Fixed-size fields (floats) are skipped
+    Fixed,
+}
+
+/// Reads the fields of a protobuf message one by one
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf: buf, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut val: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *self.buf.get(self.pos).ok_or("truncated varint")?;
+            self.pos += 1;
+            val |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+            shift += 7;
+            if shift >= 64 {
+                return Err("varint too long".to_string());
+            }
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.buf.len() {
+            return Err("truncated message".to_string());
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// Gets the number and value of the next field, None at the end of the
+    /// message
+    fn field(&mut self) -> Result<Option<(u64, Field<'a>)>, String> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let field = match key & 7 {
+            0 => Field::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Field::Fixed
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Field::Fixed
+            }
+            wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+        };
+        Ok(Some((key >> 3, field)))
+    }
+}
+
+fn string(bytes: &[u8]) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| "invalid string".to_string())
+}
+
+/// Appends a repeated int64 field, packed or not
+fn push_ints(field: Field, ints: &mut Vec<i64>) -> Result<(), String> {
+    match field {
+        Field::Varint(v) => ints.push(v as i64),
+        Field::Bytes(bytes) => {
+            let mut reader = Reader::new(bytes);
+            while reader.pos < bytes.len() {
+                ints.push(reader.varint()? as i64);
+            }
+        }
+        _ => return Err("invalid repeated int64 field".to_string()),
+    }
+    Ok(())
+}
+
+/// Attribute of an ONNX node, only integer and string ones are used
+#[derive(Debug, Clone, PartialEq)]
+enum Attr {
+    Int(i64),
+    Ints(Vec<i64>),
+    Str(String),
+    Other,
+}
+
+struct Node {
+    name: String,
+    op_type: String,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    attrs: HashMap<String, Attr>,
+}
+
+struct Initializer {
+    dims: Vec<i32>,
+    /// Values of an int64 tensor, e.g. the shape of a Reshape
+    ints: Vec<i64>,
+    /// Values of a float tensor, e.g. a trained weight
+    floats: Vec<f32>,
+}
+
+/// The parts of a GraphProto used for importing
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    initializers: HashMap<String, Initializer>,
+    /// Inputs in the order of the graph, with their dims
+    inputs: Vec<(String, Vec<i32>)>,
+    outputs: Vec<String>,
+}
+
+fn parse_attr(bytes: &[u8]) -> Result<(String, Attr), String> {
+    let mut reader = Reader::new(bytes);
+    let mut name = String::new();
+    let mut attr = Attr::Other;
+    let mut ints = vec![];
+    while let Some((num, field)) = reader.field()? {
+        match (num, field) {
+            (1, Field::Bytes(b)) => name = string(b)?,
+            (3, Field::Varint(v)) => attr = Attr::Int(v as i64),
+            (4, Field::Bytes(b)) => attr = Attr::Str(string(b)?),
+            (8, field) => push_ints(field, &mut ints)?,
+            _ => (),
+        }
+    }
+    if !ints.is_empty() {
+        attr = Attr::Ints(ints);
+    }
+    Ok((name, attr))
+}
+
+fn parse_node(bytes: &[u8]) -> Result<Node, String> {
+    let mut reader = Reader::new(bytes);
+    let mut node = Node {
+        name: String::new(),
+        op_type: String::new(),
+        inputs: vec![],
+        outputs: vec![],
+        attrs: HashMap::new(),
+    };
+    while let Some((num, field)) = reader.field()? {
+        if let Field::Bytes(b) = field {
+            match num {
+                1 => node.inputs.push(string(b)?),
+                2 => node.outputs.push(string(b)?),
+                3 => node.name = string(b)?,
+                4 => node.op_type = string(b)?,
+                5 => {
+                    let (name, attr) = parse_attr(b)?;
+                    node.attrs.insert(name, attr);
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(node)
+}
+
+fn parse_initializer(bytes: &[u8]) -> Result<(String, Initializer), String> {
+    let mut reader = Reader::new(bytes);
+    let mut name = String::new();
+    let mut dims = vec![];
+    let mut ints = vec![];
+    let mut float_data: &[u8] = &[];
+    let mut data_type = 0;
+    let mut raw: &[u8] = &[];
+    while let Some((num, field)) = reader.field()? {
+        match (num, field) {
+            (1, field) => push_ints(field, &mut dims)?,
+            (2, Field::Varint(v)) => data_type = v,
+            (4, Field::Bytes(b)) => float_data = b,
+            (7, field) => push_ints(field, &mut ints)?,
+            (8, Field::Bytes(b)) => name = string(b)?,
+            (9, Field::Bytes(b)) => raw = b,
+            _ => (),
+        }
+    }
+    // INT64 tensors can have their values as raw little-endian bytes
+    if data_type == 7 && ints.is_empty() {
+        ints = raw
+            .chunks_exact(8)
+            .map(|c| {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(c);
+                i64::from_le_bytes(b)
+            })
+            .collect();
+    }
+    // FLOAT tensors have their values packed in float_data or as raw
+    // little-endian bytes
+    let floats = if data_type == 1 {
+        let bytes = if raw.is_empty() { float_data } else { raw };
+        bytes.chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap())).collect()
+    } else {
+        vec![]
+    };
+    let initializer = Initializer {
+        dims: dims.iter().map(|d| *d as i32).collect(),
+        ints: ints,
+        floats: floats,
+    };
+    Ok((name, initializer))
+}
+
+/// Gets the sub-messages of a message with a field number
+fn messages(bytes: &[u8], number: u64) -> Result<Vec<&[u8]>, String> {
+    let mut res = vec![];
+    let mut reader = Reader::new(bytes);
+    while let Some((num, field)) = reader.field()? {
+        if let (true, Field::Bytes(b)) = (num == number, field) {
+            res.push(b);
+        }
+    }
+    Ok(res)
+}
+
+/// Gets the name and dims of a ValueInfoProto. Symbolic dims (e.g. the batch
+/// size) are taken as 1
+fn parse_value_info(bytes: &[u8]) -> Result<(String, Vec<i32>), String> {
+    let name = match messages(bytes, 1)?.first() {
+        Some(b) => string(b)?,
+        None => String::new(),
+    };
+    let mut dims = vec![];
+    // ValueInfoProto.type.tensor_type.shape.dim
+    for type_proto in messages(bytes, 2)? {
+        for tensor_type in messages(type_proto, 1)? {
+            for shape in messages(tensor_type, 2)? {
+                for dim in messages(shape, 1)? {
+                    let mut value = 1;
+                    let mut reader = Reader::new(dim);
+                    while let Some((num, field)) = reader.field()? {
+                        if let (1, Field::Varint(v)) = (num, field) {
+                            value = v as i32;
+                        }
+                    }
+                    dims.push(value);
+                }
+            }
+        }
+    }
+    Ok((name, dims))
+}
+
+fn parse_graph(bytes: &[u8]) -> Result<Graph, String> {
+    let mut graph = Graph::default();
+    let mut reader = Reader::new(bytes);
+    while let Some((num, field)) = reader.field()? {
+        if let Field::Bytes(b) = field {
+            match num {
+                1 => graph.nodes.push(parse_node(b)?),
+                5 => {
+                    let (name, initializer) = parse_initializer(b)?;
+                    graph.initializers.insert(name, initializer);
+                }
+                11 => graph.inputs.push(parse_value_info(b)?),
+                12 => graph.outputs.push(parse_value_info(b)?.0),
+                _ => (),
+            }
+        }
+    }
+    Ok(graph)
+}
+
+/// Gets an integer attribute of a node, or its default
+fn int_attr(node: &Node, name: &str, default: i64) -> Result<i64, String> {
+    match node.attrs.get(name) {
+        None => Ok(default),
+        Some(Attr::Int(v)) => Ok(*v),
+        Some(_) => Err(format!("node {}: attribute {} should be an int", node.name, name)),
+    }
+}
+
+/// Gets an integer list attribute of a node, or its default
+fn ints_attr(node: &Node, name: &str, default: &[i64]) -> Result<Vec<i64>, String> {
+    match node.attrs.get(name) {
+        None => Ok(default.to_vec()),
+        Some(Attr::Ints(v)) => Ok(v.clone()),
+        Some(_) => Err(format!("node {}: attribute {} should be a list of ints", node.name, name)),
+    }
+}
+
+fn dims(t: &TensorInfo) -> Vec<i32> {
+    t.shape[..t.n_dim].to_vec()
+}
+
+/// Gets the TASO padding of a convolution or pooling
+///
+/// # Returns
+///
+/// PVALID for no padding, PSAME for the padding keeping ceil(input / stride)
+/// elements, or an error for any other padding
+fn padding(node: &Node, input: &TensorInfo, kernel: &[i32], strides: &[i32]) -> Result<i32, String> {
+    match node.attrs.get("auto_pad") {
+        Some(Attr::Str(s)) if s == "SAME_UPPER" || s == "SAME_LOWER" => return Ok(PSAME),
+        Some(Attr::Str(s)) if s == "VALID" => return Ok(PVALID),
+        _ => (),
+    }
+    let pads = ints_attr(node, "pads", &[0, 0, 0, 0])?;
+    if pads.iter().all(|p| *p == 0) {
+        return Ok(PVALID);
+    }
+    let same = (0..2).all(|i| {
+        let size = input.shape[2 + i];
+        let out = (size + strides[i] - 1) / strides[i];
+        let total = ((out - 1) * strides[i] + kernel[i] - size).max(0) as i64;
+        pads[i] + pads[i + 2] == total && (pads[i] - pads[i + 2]).abs() <= 1
+    });
+    if same {
+        Ok(PSAME)
+    } else {
+        Err(format!("node {}: padding {:?} is neither VALID nor SAME", node.name, pads))
+    }
+}
+
+/// Gets the kernel, strides and padding of a 2D convolution or pooling
+fn window(node: &Node, input: &TensorInfo, kernel: &[i32]) -> Result<(Vec<i32>, i32), String> {
+    if input.n_dim != 4 {
+        return Err(format!("node {}: only 2D (NCHW) inputs are supported", node.name));
+    }
+    if ints_attr(node, "dilations", &[1, 1])?.iter().any(|d| *d != 1) {
+        return Err(format!("node {}: dilations are not supported", node.name));
+    }
+    let strides: Vec<i32> = ints_attr(node, "strides", &[1, 1])?.iter().map(|s| *s as i32).collect();
+    let padding = padding(node, input, kernel, &strides)?;
+    Ok((strides, padding))
+}
+
+/// Makes the shape of a Reshape, resolving 0 (copy the input dim) and -1
+/// (infer the dim)
+fn reshape_dims(node: &Node, input: &[i32], shape: &[i64]) -> Result<Vec<i32>, String> {
+    let mut dims: Vec<i32> = shape
+        .iter()
+        .enumerate()
+        .map(|(i, d)| if *d == 0 { input[i] } else { *d as i32 })
+        .collect();
+    let total: i32 = input.iter().product();
+    if let Some(i) = dims.iter().position(|d| *d == -1) {
+        let known: i32 = dims.iter().filter(|d| **d != -1).product();
+        dims[i] = total / known.max(1);
+    }
+    if dims.iter().product::<i32>() != total {
+        return Err(format!("node {}: can not reshape {:?} to {:?}", node.name, input, shape));
+    }
+    Ok(dims)
+}
+
+/// Builds the graph of an ONNX model
+///
+/// # Returns
+///
+/// The graph, with the outputs of the model combined by noops (the first two
+/// in an inner noop), or the node that could not be imported and why
+pub fn import_onnx(bytes: &[u8]) -> Result<RecExpr<Mdl>, String> {
+    import_onnx_with_names(bytes).map(|(expr, _)| expr)
+}
+
+/// Gets the values of the float initializers of an ONNX model, by their
+/// names as on import (see sanitize_name)
+pub fn onnx_weight_values(bytes: &[u8]) -> Result<HashMap<String, Vec<f32>>, String> {
+    let mut values = HashMap::new();
+    let mut reader = Reader::new(bytes);
+    while let Some((num, field)) = reader.field()? {
+        if let (7, Field::Bytes(b)) = (num, field) {
+            for (name, initializer) in parse_graph(b)?.initializers {
+                if !initializer.floats.is_empty() {
+                    values.insert(sanitize_name(&name), initializer.floats);
+                }
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Builds the graph of an ONNX model, together with the layer names (the
+/// names of the ONNX nodes)
+pub fn import_onnx_with_names(bytes: &[u8]) -> Result<(RecExpr<Mdl>, HashMap<Id, String>), String> {
+    let mut graph = None;
+    let mut reader = Reader::new(bytes);
+    while let Some((num, field)) = reader.field()? {
+        if let (7, Field::Bytes(b)) = (num, field) {
+            graph = Some(parse_graph(b)?);
+        }
+    }
+    let graph = graph.ok_or("the model has no graph")?;
+
+    let mut converter: GraphConverter = Default::default();
+    let mut tensors: HashMap<String, TensorInfo> = HashMap::new();
+    for (name, dims) in graph.inputs.iter() {
+        // Older exporters list the initializers among the inputs
+        if !graph.initializers.contains_key(name) {
+            let t = converter.new_named_input(name, dims);
+            tensors.insert(name.clone(), t);
+        }
+    }
+    let mut sorted: Vec<(&String, &Initializer)> = graph.initializers.iter().collect();
+    sorted.sort_by_key(|(name, _)| *name);
+    for (name, initializer) in sorted {
+        let t = converter.new_named_weight(name, &initializer.dims);
+        tensors.insert(name.clone(), t);
+    }
+
+    for node in graph.nodes.iter() {
+        let input = |i: usize| -> Result<TensorInfo, String> {
+            let name = node
+                .inputs
+                .get(i)
+                .ok_or_else(|| format!("node {} ({}) has no input {}", node.name, node.op_type, i))?;
+            tensors
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("node {}: unknown tensor {}", node.name, name))
+        };
+        let has_input = |i: usize| node.inputs.get(i).map_or(false, |name| !name.is_empty());
+        let out = match node.op_type.as_str() {
+            "Conv" => {
+                let (x, w) = (input(0)?, input(1)?);
+                let kernel = [w.shape[2], w.shape[3]];
+                let (strides, padding) = window(node, &x, &kernel)?;
+                let mut out = converter.conv2d(x, w, strides[0], strides[1], padding, ACTNONE);
+                if has_input(2) {
+                    // The bias, of shape [C], broadcasts over [N, C, H, W]
+                    // once it is [C, 1, 1]
+                    let b = input(2)?;
+                    let b = converter.reshape(b, &[b.shape[0], 1, 1]);
+                    out = converter.add(out, b);
+                }
+                out
+            }
+            "MaxPool" | "AveragePool" => {
+                let x = input(0)?;
+                let kernel: Vec<i32> = ints_attr(node, "kernel_shape", &[])?.iter().map(|k| *k as i32).collect();
+                if kernel.len() != 2 {
+                    return Err(format!("node {}: only 2D pooling is supported", node.name));
+                }
+                let (strides, padding) = window(node, &x, &kernel)?;
+                if node.op_type == "MaxPool" {
+                    converter.maxpool2d(x, kernel[0], kernel[1], strides[0], strides[1], padding)
+                } else {
+                    converter.avgpool2d(x, kernel[0], kernel[1], strides[0], strides[1], padding)
+                }
+            }
+            "GlobalAveragePool" => {
+                let x = input(0)?;
+                converter.avgpool2d(x, x.shape[2], x.shape[3], 1, 1, PVALID)
+            }
+            "Relu" => converter.relu(input(0)?),
+            "Tanh" => converter.tanh(input(0)?),
+            "Sigmoid" => converter.sigmoid(input(0)?),
+            "Dropout" => converter.dropout(input(0)?),
+            "Identity" => input(0)?,
+            "Add" | "Mul" => {
+                let (a, b) = (input(0)?, input(1)?);
+                let shape = broadcast_shape(&dims(&a), &dims(&b))
+                    .ok_or_else(|| format!("node {}: {:?} and {:?} do not broadcast", node.name, dims(&a), dims(&b)))?;
+                let mut out = if node.op_type == "Add" {
+                    converter.add(a, b)
+                } else {
+                    converter.mul(a, b)
+                };
+                out.n_dim = shape.len();
+                out.shape[..shape.len()].copy_from_slice(&shape);
+                out
+            }
+            "MatMul" => {
+                let (a, b) = (input(0)?, input(1)?);
+                if a.n_dim != b.n_dim {
+                    return Err(format!("node {}: MatMul of tensors of different ranks is not supported", node.name));
+                }
+                converter.matmul(a, b)
+            }
+            "Gemm" => {
+                if int_attr(node, "transA", 0)? != 0 {
+                    return Err(format!("node {}: transA is not supported", node.name));
+                }
+                let (a, mut b) = (input(0)?, input(1)?);
+                if int_attr(node, "transB", 0)? != 0 {
+                    b = converter.transpose(b, &[1, 0], false);
+                }
+                if has_input(2) {
+                    converter.dense(a, b, input(2)?, ACTNONE)
+                } else {
+                    converter.matmul(a, b)
+                }
+            }
+            "Softmax" => {
+                let x = input(0)?;
+                let mut axis = int_attr(node, "axis", -1)?;
+                if axis < 0 {
+                    axis += x.n_dim as i64;
+                }
+                converter.softmax(x, axis as i32)
+            }
+            "BatchNormalization" => {
+                converter.batchnorm(input(0)?, input(1)?, input(2)?, input(3)?, input(4)?)
+            }
+            "Concat" => {
+                let inputs: Result<Vec<TensorInfo>, String> = (0..node.inputs.len()).map(input).collect();
+                let inputs = inputs?;
+                if inputs.len() < 2 {
+                    return Err(format!("node {}: Concat of {} inputs is not supported", node.name, inputs.len()));
+                }
+                let mut axis = int_attr(node, "axis", 0)?;
+                if axis < 0 {
+                    axis += inputs[0].n_dim as i64;
+                }
+                converter.concat_multi(axis as i32, &inputs)
+            }
+            "Reshape" => {
+                let x = input(0)?;
+                let shape = graph
+                    .initializers
+                    .get(&node.inputs[1])
+                    .filter(|init| !init.ints.is_empty())
+                    .ok_or_else(|| format!("node {}: only constant shapes are supported", node.name))?;
+                let new_dims = reshape_dims(node, &dims(&x), &shape.ints)?;
+                converter.reshape(x, &new_dims)
+            }
+            "Flatten" => {
+                let x = input(0)?;
+                let axis = int_attr(node, "axis", 1)? as usize;
+                let outer: i32 = x.shape[..axis].iter().product();
+                let inner: i32 = x.shape[axis..x.n_dim].iter().product();
+                converter.reshape(x, &[outer, inner])
+            }
+            "Transpose" => {
+                let x = input(0)?;
+                let default: Vec<i64> = (0..x.n_dim as i64).rev().collect();
+                let perm: Vec<i32> = ints_attr(node, "perm", &default)?.iter().map(|p| *p as i32).collect();
+                converter.transpose(x, &perm, false)
+            }
+            op => return Err(format!("node {}: unsupported op {}", node.name, op)),
+        };
+        if node.outputs.len() != 1 && node.op_type != "Dropout" {
+            return Err(format!("node {}: ops with {} outputs are not supported", node.name, node.outputs.len()));
+        }
+        if !node.name.is_empty() && node.op_type != "Identity" {
+            converter.name_layer(&out, &node.name);
+        }
+        tensors.insert(node.outputs[0].clone(), out);
+    }
+
+    let outputs: Result<Vec<TensorInfo>, String> = graph
+        .outputs
+        .iter()
+        .map(|name| tensors.get(name).copied().ok_or_else(|| format!("unknown output {}", name)))
+        .collect();
+    let outputs = outputs?;
+    if outputs.is_empty() {
+        return Err("the graph has no outputs".to_string());
+    }
+    let mut out = outputs[0];
+    for o in outputs[1..].iter() {
+        out = converter.noop(out, *o);
+    }
+    Ok(converter.rec_expr_with_names())
+}
+
+/// Writes the fields of a protobuf message
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Default::default()
+    }
+
+    fn varint(mut self, mut v: u64) -> Self {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+        self
+    }
+
+    fn int(self, num: u64, v: i64) -> Self {
+        self.varint(num << 3).varint(v as u64)
+    }
+
+    fn bytes(mut self, num: u64, bytes: &[u8]) -> Self {
+        self = self.varint(num << 3 | 2).varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    fn string(self, num: u64, s: &str) -> Self {
+        self.bytes(num, s.as_bytes())
+    }
+
+    /// Writes a packed repeated int64 field
+    fn ints(self, num: u64, vals: &[i64]) -> Self {
+        let packed = vals.iter().fold(Writer::new(), |w, v| w.varint(*v as u64));
+        self.bytes(num, &packed.buf)
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Default version of the default operator set written, the first where
+/// Split takes the sizes of its outputs as an input
+pub const DEFAULT_ONNX_OPSET: i64 = 13;
+/// Oldest operator set that can be written, the first whose version of the
+/// format allows initializers that are not graph inputs
+pub const MIN_ONNX_OPSET: i64 = 9;
+/// Newest operator set that can be written. From 18, Split takes the number
+/// of its outputs and Pad its axes, which are not written yet
+pub const MAX_ONNX_OPSET: i64 = 17;
+
+/// Gets the oldest version of the ONNX format supporting an operator set, so
+/// that a model for an older runtime is not rejected for its format
+fn ir_version(opset: i64) -> i64 {
+    match opset {
+        9 => 4,
+        10 => 5,
+        11 => 6,
+        12..=14 => 7,
+        _ => 8,
+    }
+}
+
+// AttributeProto.type and TensorProto.data_type of the fields written
+const ATTR_INT: i64 = 2;
+const ATTR_STRING: i64 = 3;
+const ATTR_INTS: i64 = 7;
+const TENSOR_FLOAT: i64 = 1;
+const TENSOR_INT64: i64 = 7;
+
+fn attr_int(name: &str, v: i64) -> Vec<u8> {
+    Writer::new().string(1, name).int(20, ATTR_INT).int(3, v).finish()
+}
+
+fn attr_ints(name: &str, vals: &[i64]) -> Vec<u8> {
+    Writer::new().string(1, name).int(20, ATTR_INTS).ints(8, vals).finish()
+}
+
+fn attr_string(name: &str, s: &str) -> Vec<u8> {
+    Writer::new().string(1, name).int(20, ATTR_STRING).string(4, s).finish()
+}
+
+/// Writes a ValueInfoProto. With symbolic_batch, the leading dimension is
+/// written as the dim_param N instead of its value
+fn value_info(name: &str, dims: Option<&Vec<i32>>, symbolic_batch: bool) -> Vec<u8> {
+    let mut tensor_type = Writer::new().int(1, TENSOR_FLOAT);
+    if let Some(dims) = dims {
+        let shape = dims.iter().enumerate().fold(Writer::new(), |w, (i, d)| {
+            let dim = if symbolic_batch && i == 0 {
+                Writer::new().string(2, BATCH_DIM)
+            } else {
+                Writer::new().int(1, *d as i64)
+            };
+            w.bytes(1, &dim.finish())
+        });
+        tensor_type = tensor_type.bytes(2, &shape.finish());
+    }
+    let type_proto = Writer::new().bytes(1, &tensor_type.finish());
+    Writer::new().string(1, name).bytes(2, &type_proto.finish()).finish()
+}
+
+/// Domain of the contrib ops of ONNX Runtime
+const ORT_DOMAIN: &str = "com.microsoft";
+
+/// Runtime an exported model is written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionProvider {
+    /// Standard ONNX ops only, an op with a fused activation is written as
+    /// the op followed by the activation
+    Standard,
+    /// ONNX Runtime: convolutions and 2-d matmuls with a fused activation are
+    /// written as its FusedConv and FusedGemm ops (domain com.microsoft)
+    OnnxRuntime,
+}
+
+impl std::str::FromStr for ExecutionProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(ExecutionProvider::Standard),
+            "ort" => Ok(ExecutionProvider::OnnxRuntime),
+            _ => Err(format!("unknown execution provider {}", s)),
+        }
+    }
+}
+
+/// Gets the ONNX op of an activation
+fn activation_op(act: i32) -> Result<&'static str, String> {
+    match act {
+        ACTRELU => Ok("Relu"),
+        ACTSIGMOID => Ok("Sigmoid"),
+        ACTTANH => Ok("Tanh"),
+        _ => Err(format!("unknown activation {}", act)),
+    }
+}
+
+/// The ONNX graph being written
+#[derive(Default)]
+struct OnnxWriter {
+    nodes: Vec<Vec<u8>>,
+    initializers: Vec<Vec<u8>>,
+    inputs: Vec<Vec<u8>>,
+    /// Names of the inputs and weights written so far
+    tensors: HashSet<String>,
+    /// If a registered op was written, in the domain "tensat"
+    uses_custom: bool,
+    /// If a fused op of ONNX Runtime was written
+    uses_ort: bool,
+}
+
+impl OnnxWriter {
+    fn node(&mut self, op_type: &str, inputs: &[String], outputs: &[String], attrs: &[Vec<u8>]) {
+        self.node_in("", op_type, inputs, outputs, attrs);
+    }
+
+    /// Adds a node of an op of another domain than the default one
+    fn node_in(&mut self, domain: &str, op_type: &str, inputs: &[String], outputs: &[String], attrs: &[Vec<u8>]) {
+        let mut w = Writer::new();
+        for i in inputs.iter() {
+            w = w.string(1, i);
+        }
+        for o in outputs.iter() {
+            w = w.string(2, o);
+        }
+        w = w.string(3, &outputs[0]).string(4, op_type);
+        for a in attrs.iter() {
+            w = w.bytes(5, a);
+        }
+        if !domain.is_empty() {
+            w = w.string(7, domain);
+        }
+        self.nodes.push(w.finish());
+    }
+
+    /// Adds a constant int64 tensor, e.g. the shape of a Reshape
+    fn constant(&mut self, name: &str, vals: &[i64]) -> String {
+        let init = Writer::new()
+            .ints(1, &[vals.len() as i64])
+            .int(2, TENSOR_INT64)
+            .ints(7, vals)
+            .string(8, name);
+        self.initializers.push(init.finish());
+        name.to_string()
+    }
+
+    /// Adds the activation fused into an op, the op writing its output to
+    /// name_pre
+    fn activation(&mut self, act: i32, name: &str) -> Result<(), String> {
+        let op_type = activation_op(act)?;
+        self.node(op_type, &[format!("{}_pre", name)], &[name.to_string()], &[]);
+        Ok(())
+    }
+}
+
+/// Serializes a graph as an ONNX model
+///
+/// The inputs of the graph are graph inputs, and its weights are
+/// initializers, both with their names without the shape. The data of a
+/// weight is external, in the file `<name>.bin` next to the model, where the
+/// original weights can be written to reattach them. Registered ops (see
+/// custom.rs) are written as ops of the domain "tensat" with their
+/// attributes.
+///
+/// # Parameters
+///
+/// - `expr`: the graph, with the attributes not packed (see attrs::unpack_expr)
+/// - `shapes`: the output shapes of its nodes, see shape::expr_shapes
+/// - `provider`: the runtime to write fused ops for
+/// - `opset`: the version of the default operator set, from MIN_ONNX_OPSET
+///   to MAX_ONNX_OPSET. The ops whose definition changed in between are
+///   written as the version expects them: Split takes the sizes of its
+///   outputs as an attribute before 13 and as an input from 13, Pad and
+///   Slice their pads and bounds as attributes before 11 and 10, and
+///   Softmax only normalizes along one axis from 13 (before, it flattens the
+///   dimensions from the axis, so only a softmax along the last axis can be
+///   written)
+///
+/// # Returns
+///
+/// The serialized model, or the node that has no ONNX counterpart in the
+/// operator set
+pub fn export_onnx(
+    expr: &RecExpr<Mdl>,
+    shapes: &[Option<Vec<TensorShape>>],
+    provider: ExecutionProvider,
+    opset: i64,
+) -> Result<Vec<u8>, String> {
+    if opset < MIN_ONNX_OPSET || opset > MAX_ONNX_OPSET {
+        return Err(format!(
+            "opset {} is not supported, only {} to {}",
+            opset, MIN_ONNX_OPSET, MAX_ONNX_OPSET
+        ));
+    }
+    let ort = provider == ExecutionProvider::OnnxRuntime;
+    let nodes = expr.as_ref();
+    let mut out = OnnxWriter::default();
+    let mut names: Vec<Vec<String>> = Vec::with_capacity(nodes.len());
+    let mut output_dims: HashMap<String, Vec<i32>> = HashMap::new();
+    let num = |id: &Id| match nodes[usize::from(*id)] {
+        Mdl::Num(v) => Ok(v),
+        ref other => Err(format!("{} is not a number", other)),
+    };
+    let var = |id: &Id| match nodes[usize::from(*id)] {
+        Mdl::Var(s) => Ok(s),
+        ref other => Err(format!("{} is not a name", other)),
+    };
+    let shape = |id: &Id, k: usize| -> Result<Vec<i32>, String> {
+        shapes[usize::from(*id)]
+            .as_ref()
+            .and_then(|s| s.get(k))
+            .map(|s| s.dims.clone())
+            .ok_or_else(|| format!("node {} has no shape", usize::from(*id)))
+    };
+
+    for (i, node) in nodes.iter().enumerate() {
+        let name = format!("t{}", i);
+        let t = |id: &Id| -> Result<String, String> {
+            names[usize::from(*id)]
+                .first()
+                .cloned()
+                .ok_or_else(|| format!("node {}: input {} is not a tensor", i, usize::from(*id)))
+        };
+        let single = vec![name.clone()];
+        let outputs = match node {
+            Mdl::Num(_) | Mdl::Var(_) => vec![],
+            Mdl::Input([n]) | Mdl::Weight([n]) => {
+                let tensor = parse_tensor_name(var(n)?.as_str())?;
+                let label = tensor.label.clone();
+                if out.tensors.insert(label.clone()) {
+                    if let Mdl::Input(_) = node {
+                        out.inputs.push(value_info(&label, Some(&tensor.dims), tensor.symbolic_batch));
+                    } else {
+                        // StringStringEntryProto of TensorProto.external_data,
+                        // with TensorProto.data_location EXTERNAL
+                        let location = Writer::new()
+                            .string(1, "location")
+                            .string(2, &format!("{}.bin", label.replace('/', "_")));
+                        let dims: Vec<i64> = tensor.dims.iter().map(|d| *d as i64).collect();
+                        let init = Writer::new()
+                            .ints(1, &dims)
+                            .int(2, TENSOR_FLOAT)
+                            .string(8, &label)
+                            .bytes(13, &location.finish())
+                            .int(14, 1);
+                        out.initializers.push(init.finish());
+                    }
+                }
+                output_dims.insert(label.clone(), tensor.dims.clone());
+                vec![label]
+            }
+            Mdl::Conv2d([sh, sw, pad, act, inpt, wght])
+            | Mdl::Sconv2d([sh, sw, pad, act, inpt, wght])
+            | Mdl::Conv2dBlocked([sh, sw, pad, act, inpt, wght]) => {
+                let (in_dims, w_dims) = (shape(inpt, 0)?, shape(wght, 0)?);
+                let auto_pad = if num(pad)? == PSAME { "SAME_UPPER" } else { "VALID" };
+                let act = num(act)?;
+                let mut attrs = vec![
+                    attr_ints("strides", &[num(sh)? as i64, num(sw)? as i64]),
+                    attr_ints("kernel_shape", &[w_dims[2] as i64, w_dims[3] as i64]),
+                    attr_string("auto_pad", auto_pad),
+                    attr_int("group", (in_dims[1] / w_dims[1]) as i64),
+                ];
+                if ort && act != ACTNONE {
+                    attrs.push(attr_string("activation", activation_op(act)?));
+                    out.node_in(ORT_DOMAIN, "FusedConv", &[t(inpt)?, t(wght)?], &single, &attrs);
+                    out.uses_ort = true;
+                } else {
+                    let conv_out = if act == ACTNONE { name.clone() } else { format!("{}_pre", name) };
+                    out.node("Conv", &[t(inpt)?, t(wght)?], &[conv_out], &attrs);
+                    if act != ACTNONE {
+                        out.activation(act, &name)?;
+                    }
+                }
+                single
+            }
+            Mdl::Matmul([act, a, b]) | Mdl::Smatmul([act, a, b]) => {
+                let act = num(act)?;
+                // Gemm only takes matrices
+                let is_2d = shape(a, 0)?.len() == 2 && shape(b, 0)?.len() == 2;
+                if ort && act != ACTNONE && is_2d {
+                    let attrs = [attr_string("activation", activation_op(act)?)];
+                    out.node_in(ORT_DOMAIN, "FusedGemm", &[t(a)?, t(b)?], &single, &attrs);
+                    out.uses_ort = true;
+                } else {
+                    let mm_out = if act == ACTNONE { name.clone() } else { format!("{}_pre", name) };
+                    out.node("MatMul", &[t(a)?, t(b)?], &[mm_out], &[]);
+                    if act != ACTNONE {
+                        out.activation(act, &name)?;
+                    }
+                }
+                single
+            }
+            Mdl::Dense([act, a, b, bias]) => {
+                let act = num(act)?;
+                let is_2d = shape(a, 0)?.len() == 2 && shape(b, 0)?.len() == 2;
+                if ort && act != ACTNONE && is_2d {
+                    let attrs = [attr_string("activation", activation_op(act)?)];
+                    out.node_in(ORT_DOMAIN, "FusedGemm", &[t(a)?, t(b)?, t(bias)?], &single, &attrs);
+                    out.uses_ort = true;
+                } else {
+                    let dense_out = if act == ACTNONE { name.clone() } else { format!("{}_pre", name) };
+                    if is_2d {
+                        out.node("Gemm", &[t(a)?, t(b)?, t(bias)?], &[dense_out], &[]);
+                    } else {
+                        let mm_out = format!("{}_mm", name);
+                        out.node("MatMul", &[t(a)?, t(b)?], &[mm_out.clone()], &[]);
+                        out.node("Add", &[mm_out, t(bias)?], &[dense_out], &[]);
+                    }
+                    if act != ACTNONE {
+                        out.activation(act, &name)?;
+                    }
+                }
+                single
+            }
+            Mdl::Softmax([inpt, axis]) => {
+                let axis = num(axis)? as i64;
+                if opset < 13 && axis + 1 != shape(inpt, 0)?.len() as i64 {
+                    return Err(format!("node {}: softmax along axis {} needs opset 13, only the last axis before", i, axis));
+                }
+                out.node("Softmax", &[t(inpt)?], &single, &[attr_int("axis", axis)]);
+                single
+            }
+            Mdl::Poolmax([inpt, kh, kw, sh, sw, pad, act]) | Mdl::Poolavg([inpt, kh, kw, sh, sw, pad, act]) => {
+                let op_type = if let Mdl::Poolmax(_) = node { "MaxPool" } else { "AveragePool" };
+                let auto_pad = if num(pad)? == PSAME { "SAME_UPPER" } else { "VALID" };
+                let act = num(act)?;
+                let pool_out = if act == ACTNONE { name.clone() } else { format!("{}_pre", name) };
+                out.node(
+                    op_type,
+                    &[t(inpt)?],
+                    &[pool_out],
+                    &[
+                        attr_ints("kernel_shape", &[num(kh)? as i64, num(kw)? as i64]),
+                        attr_ints("strides", &[num(sh)? as i64, num(sw)? as i64]),
+                        attr_string("auto_pad", auto_pad),
+                    ],
+                );
+                if act != ACTNONE {
+                    out.activation(act, &name)?;
+                }
+                single
+            }
+            Mdl::Ewadd([a, b]) => {
+                out.node("Add", &[t(a)?, t(b)?], &single, &[]);
+                single
+            }
+            Mdl::Ewmul([a, b]) => {
+                out.node("Mul", &[t(a)?, t(b)?], &single, &[]);
+                single
+            }
+            Mdl::Cast([elem, a]) => {
+                // TensorProto.DataType
+                let to = match ElemType::from_code(num(elem)?) {
+                    Some(ElemType::Float32) => TENSOR_FLOAT,
+                    Some(ElemType::Float16) => 10,
+                    Some(ElemType::Int8) => 3,
+                    None => return Err(format!("node {}: unknown element type {}", i, num(elem)?)),
+                };
+                out.node("Cast", &[t(a)?], &single, &[attr_int("to", to)]);
+                single
+            }
+            // ONNX runtimes choose the layouts of weights themselves
+            Mdl::Repack([_, a]) => {
+                out.node("Identity", &[t(a)?], &single, &[]);
+                single
+            }
+            Mdl::Relu(a) | Mdl::Tanh(a) | Mdl::Sigmoid(a) | Mdl::Dropout(a) => {
+                let op_type = match node {
+                    Mdl::Relu(_) => "Relu",
+                    Mdl::Tanh(_) => "Tanh",
+                    Mdl::Sigmoid(_) => "Sigmoid",
+                    _ => "Dropout",
+                };
+                out.node(op_type, &[t(a)?], &single, &[]);
+                single
+            }
+            Mdl::BatchNorm(inputs) => {
+                let inputs: Result<Vec<String>, String> = inputs.iter().map(t).collect();
+                out.node("BatchNormalization", &inputs?, &single, &[]);
+                single
+            }
+            Mdl::Concat(_) | Mdl::Concat3(_) | Mdl::Concat4(_) | Mdl::Concat5(_) | Mdl::ConcatN(_) => {
+                // axis, ndim, inputs...
+                let children = node.children();
+                let inputs: Result<Vec<String>, String> = children[2..].iter().map(t).collect();
+                out.node("Concat", &inputs?, &single, &[attr_int("axis", num(&children[0])? as i64)]);
+                single
+            }
+            Mdl::Split([axis, inpt]) => {
+                let axis = num(axis)? as usize;
+                let (s0, s1) = (shape(&Id::from(i), 0)?, shape(&Id::from(i), 1)?);
+                let sizes = [s0[axis] as i64, s1[axis] as i64];
+                let outputs = vec![format!("{}_0", name), format!("{}_1", name)];
+                if opset < 13 {
+                    let attrs = [attr_int("axis", axis as i64), attr_ints("split", &sizes)];
+                    out.node("Split", &[t(inpt)?], &outputs, &attrs);
+                } else {
+                    let split = out.constant(&format!("{}_split", name), &sizes);
+                    out.node("Split", &[t(inpt)?, split], &outputs, &[attr_int("axis", axis as i64)]);
+                }
+                output_dims.insert(outputs[0].clone(), s0);
+                output_dims.insert(outputs[1].clone(), s1);
+                outputs
+            }
+            Mdl::SplitI([axis, count, index, inpt]) => {
+                // Only the size of this part is known, so only the first and
+                // the last part have a known offset
+                let (axis, count) = (num(axis)? as usize, num(count)?);
+                let (in_dims, out_dims) = (shape(inpt, 0)?, shape(&Id::from(i), 0)?);
+                let start = match num(index)? {
+                    0 => 0,
+                    index if index == count - 1 => in_dims[axis] - out_dims[axis],
+                    index => return Err(format!("node {}: split_i of inner part {} has no known offset", i, index)),
+                };
+                let (start, end) = (start as i64, (start + out_dims[axis]) as i64);
+                if opset < 10 {
+                    let attrs = [attr_ints("starts", &[start]), attr_ints("ends", &[end]), attr_ints("axes", &[axis as i64])];
+                    out.node("Slice", &[t(inpt)?], &single, &attrs);
+                } else {
+                    let starts = out.constant(&format!("{}_starts", name), &[start]);
+                    let ends = out.constant(&format!("{}_ends", name), &[end]);
+                    let axes = out.constant(&format!("{}_axes", name), &[axis as i64]);
+                    out.node("Slice", &[t(inpt)?, starts, ends, axes], &single, &[]);
+                }
+                single
+            }
+            Mdl::Split0(s) => vec![names[usize::from(*s)][0].clone()],
+            Mdl::Split1(s) => vec![names[usize::from(*s)][1].clone()],
+            Mdl::Reshape([inpt, shape_name]) => {
+                let dims: Vec<i64> = parse_dims(var(shape_name)?.as_str())?.iter().map(|d| *d as i64).collect();
+                let target = out.constant(&format!("{}_shape", name), &dims);
+                out.node("Reshape", &[t(inpt)?, target], &single, &[]);
+                single
+            }
+            Mdl::Transpose([inpt, perm_name, _]) => {
+                let perm: Vec<i64> = parse_dims(var(perm_name)?.as_str())?.iter().map(|p| *p as i64).collect();
+                out.node("Transpose", &[t(inpt)?], &single, &[attr_ints("perm", &perm)]);
+                single
+            }
+            Mdl::Enlarge([a, b]) => {
+                // Pads the kernel a to the size of the kernel b, centered as
+                // in TASO's enlarge kernel
+                let (a_dims, b_dims) = (shape(a, 0)?, shape(b, 0)?);
+                let (off_h, off_w) = ((b_dims[2] - a_dims[2]) / 2, (b_dims[3] - a_dims[3]) / 2);
+                let pads = [
+                    0,
+                    0,
+                    off_h as i64,
+                    off_w as i64,
+                    0,
+                    0,
+                    (b_dims[2] - a_dims[2] - off_h) as i64,
+                    (b_dims[3] - a_dims[3] - off_w) as i64,
+                ];
+                if opset < 11 {
+                    out.node("Pad", &[t(a)?], &single, &[attr_ints("pads", &pads)]);
+                } else {
+                    let pads = out.constant(&format!("{}_pads", name), &pads);
+                    out.node("Pad", &[t(a)?, pads], &single, &[]);
+                }
+                single
+            }
+            Mdl::Noop([a, b]) => {
+                // Combines the outputs of the graph
+                let mut outputs = names[usize::from(*a)].clone();
+                outputs.extend(names[usize::from(*b)].iter().cloned());
+                outputs
+            }
+            Mdl::Custom(children) => {
+                let (custom, attrs) = parse_custom_label(var(&children[0])?.as_str())
+                    .ok_or_else(|| format!("node {}: custom op {} is not registered", i, var(&children[0]).unwrap()))?;
+                let inputs: Result<Vec<String>, String> = children[1..].iter().map(t).collect();
+                let attrs: Vec<Vec<u8>> =
+                    custom.attrs.iter().zip(attrs.iter()).map(|(a, v)| attr_int(a, *v as i64)).collect();
+                let mut w = Writer::new();
+                for input in inputs?.iter() {
+                    w = w.string(1, input);
+                }
+                w = w.string(2, &name).string(3, &name).string(4, &custom.name);
+                for a in attrs.iter() {
+                    w = w.bytes(5, a);
+                }
+                out.nodes.push(w.string(7, "tensat").finish());
+                out.uses_custom = true;
+                single
+            }
+            other => return Err(format!("node {}: {} has no ONNX counterpart", i, other)),
+        };
+        if !matches!(node, Mdl::Split(_) | Mdl::Noop(_) | Mdl::Input(_) | Mdl::Weight(_)) {
+            for (k, o) in outputs.iter().enumerate() {
+                if let Ok(dims) = shape(&Id::from(i), k) {
+                    output_dims.insert(o.clone(), dims);
+                }
+            }
+        }
+        names.push(outputs);
+    }
+
+    let mut graph = Writer::new();
+    for node in out.nodes.iter() {
+        graph = graph.bytes(1, node);
+    }
+    graph = graph.string(2, "tensat");
+    for init in out.initializers.iter() {
+        graph = graph.bytes(5, init);
+    }
+    for input in out.inputs.iter() {
+        graph = graph.bytes(11, input);
+    }
+    // The shapes are computed at the batch value, so in a graph with a
+    // symbolic batch dimension, an output whose leading dimension is the
+    // batch value is taken to have the symbolic one
+    let symbolic = is_batch_symbolic(expr);
+    for o in names.last().ok_or("the graph is empty")?.iter() {
+        let dims = output_dims.get(o);
+        let symbolic_batch = symbolic && dims.and_then(|d| d.first()) == Some(&batch_value());
+        graph = graph.bytes(12, &value_info(o, dims, symbolic_batch));
+    }
+
+    let mut model = Writer::new()
+        .int(1, ir_version(opset))
+        .string(2, "tensat")
+        .bytes(7, &graph.finish())
+        .bytes(8, &Writer::new().int(2, opset).finish());
+    if out.uses_custom {
+        model = model.bytes(8, &Writer::new().string(1, "tensat").int(2, 1).finish());
+    }
+    if out.uses_ort {
+        model = model.bytes(8, &Writer::new().string(1, ORT_DOMAIN).int(2, 1).finish());
+    }
+    Ok(model.finish())
+}
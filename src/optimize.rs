@@ -1,6 +1,11 @@
 #![allow(unused_variables)]
 
-use crate::{model::*};
+use crate::cost::{sampled_runtimes, MeasuredRuntime, PopulatedRuntime, RuntimeModel, SamplingReport, ShapeSampling};
+use crate::custom::{decomposition_runtime, parse_custom_label};
+use crate::shape::shape_dims;
+use crate::freeze::FrozenGraph;
+use crate::tied::TiedWeights;
+use crate::{attrs::unpack_attrs, ffi::*, model::*};
 use egg::*;
 use root::taso::*;
 use serde::{Deserialize, Serialize};
@@ -19,6 +24,9 @@ pub struct TensorCost<'a> {
     pub curr_eclass: Option<Id>,
     pub curr_eclass_best_cost: f32,
     pub curr_eclass_best_enode_history: HashMap<Id, f32>,
+    /// Cost added per MB of the activation of an enode, to trade cost for
+    /// memory (see extract::extract_within_memory)
+    pub memory_weight: f32,
 }
 
 impl<'a> TensorCost<'a> {
@@ -32,8 +40,24 @@ impl<'a> TensorCost<'a> {
             curr_eclass: None,
             curr_eclass_best_cost: std::f32::MAX,
             curr_eclass_best_enode_history: HashMap::new(),
+            memory_weight: 0.0,
         }
     }
+
+    pub fn with_memory_weight(mut self, memory_weight: f32) -> Self {
+        self.memory_weight = memory_weight;
+        self
+    }
+
+    /// Gets the self cost of an enode, with the memory penalty
+    fn penalized_self_cost(&self, enode: &Mdl) -> f32 {
+        let self_cost = self.cost_model.get_self_cost(self.egraph, enode);
+        if self.memory_weight == 0.0 || self_cost >= INFEASIBLE_COST {
+            return self_cost;
+        }
+        let bytes = enode_activation_bytes(self.egraph, enode);
+        self_cost + self.memory_weight * bytes as f32 / 1e6
+    }
 }
 
 impl CostFunction<Mdl> for TensorCost<'_> {
@@ -41,8 +65,13 @@ impl CostFunction<Mdl> for TensorCost<'_> {
     /// Getting total cost for the subtree rooted at enode. See egg::CostFunction
     /// trait for more information on interface.
     fn cost<C: FnMut(Id) -> Self::Cost>(&mut self, enode: &Mdl, mut costs: C, eclass_id: Option<Id>) -> Self::Cost {
-        let self_cost = self.cost_model.get_self_cost(self.egraph, enode);
+        let self_cost = self.penalized_self_cost(enode);
+        // Infeasible enodes are never picked if their eclass has an alternative
+        let infeasible = self_cost >= INFEASIBLE_COST;
         if self.use_default_greedy {
+            if infeasible {
+                return std::f32::INFINITY;
+            }
             enode.fold(self_cost, |sum, id| sum + costs(id))
         } else {
             if self.curr_eclass != eclass_id {
@@ -52,6 +81,9 @@ impl CostFunction<Mdl> for TensorCost<'_> {
                 self.curr_eclass = eclass_id;
                 self.curr_eclass_best_cost = std::f32::MAX;
             }
+            if infeasible {
+                return std::f32::INFINITY;
+            }
 
             let mut enode_history = HashMap::new();
 
@@ -111,12 +143,279 @@ impl LpCostFunction<Mdl, TensorAnalysis> for TensorCost<'_> {
     }
 }
 
+/// Cost of an enode that can not be created on the TASO side
+pub const INFEASIBLE_COST: f32 = 1e10;
+
+/// Gets the runtime TASO measured for an op of g, or INFEASIBLE_COST if TASO
+/// could not create it
+fn op_runtime(g: &TasoGraph, op: Op) -> f32 {
+    match g.op("get_or_create", op) {
+        Ok(op) => op.runtime(),
+        Err(_) => INFEASIBLE_COST,
+    }
+}
+
+/// Slowdown of a sparse kernel per non-zero entry, relative to the dense
+/// kernel per entry. A sparse kernel only pays off below a density of
+/// 1 / SPARSE_OVERHEAD
+pub const SPARSE_OVERHEAD: f32 = 3.0;
+
+/// Speedup of a conv2d kernel taking its weight in the blocked layout (see
+/// Mdl::Conv2dBlocked) over the default kernel. TASO has no such kernels, so
+/// the runtime is derived from the measured runtime of the default kernel
+pub const BLOCKED_SPEEDUP: f32 = 1.2;
+
+/// Bandwidth of repacking a weight into another layout, in GB/s. A repack
+/// reads and writes the weight once
+pub const REPACK_GB_PER_SEC: f32 = 300.0;
+
+/// Number of passes over its input a softmax does (the max, the exponentials
+/// and their sum, the normalization). TASO has no softmax, so it is costed as
+/// that many elementwise exponentials of the input
+pub const SOFTMAX_PASSES: f32 = 3.0;
+
+/// Estimates the runtime of the sparse variant of an op
+///
+/// TASO only has dense kernels, so the runtime is derived from the measured
+/// runtime of the dense op and the density of its weight, counted on the
+/// actual weight data.
+///
+/// # Parameters
+///
+/// - `dense_runtime`: runtime TASO measured for the dense op
+/// - `density`: fraction of non-zero entries in the weight
+fn sparse_runtime(dense_runtime: f32, density: f32) -> f32 {
+    if dense_runtime >= INFEASIBLE_COST {
+        dense_runtime
+    } else {
+        dense_runtime * SPARSE_OVERHEAD * density
+    }
+}
+
+/// Gets the cost of each op of a graph in an EGraph
+///
+/// # Parameters
+///
+/// - `egraph`: E-graph containing the nodes of expr, e.g. constructed from expr
+/// - `expr`: the graph to get costs for
+/// - `cost_model`: the cost model
+///
+/// # Returns
+///
+/// For each node in expr (in the same order), its cost, or None if the node
+/// is not in the EGraph
+pub fn expr_op_costs(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    expr: &RecExpr<Mdl>,
+    cost_model: &CostModel,
+) -> Vec<Option<f32>> {
+    let ids = crate::provenance::expr_eclasses(egraph, expr);
+    expr.as_ref()
+        .iter()
+        .map(|node| {
+            if node.all(|child| ids[usize::from(child)].is_some()) {
+                let node = node.clone().map_children(|child| ids[usize::from(child)].unwrap());
+                Some(cost_model.get_self_cost(egraph, &node))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Estimates the runtime of a graph as the sum of the runtimes of its ops in
+/// the runtime model of cost_model. Ops on weights only are left out, as
+/// when TASO measures an optimized graph (see get_full_graph_runtime)
+///
+/// # Parameters
+///
+/// - `egraph`: E-graph containing the nodes of expr
+/// - `expr`: the graph
+/// - `cost_model`: gives the runtime model
+///
+/// # Returns
+///
+/// The estimate, in milliseconds
+pub fn expr_runtime_estimate(egraph: &EGraph<Mdl, TensorAnalysis>, expr: &RecExpr<Mdl>, cost_model: &CostModel) -> f32 {
+    expr_op_runtimes(egraph, expr, cost_model).iter().sum()
+}
+
+/// Gets the runtime of each op of a graph, as expr_runtime_estimate sums
+/// them
+///
+/// # Returns
+///
+/// For each node in expr (in the same order), its runtime in milliseconds, 0
+/// for ops on weights only and nodes not in the EGraph
+pub fn expr_op_runtimes(egraph: &EGraph<Mdl, TensorAnalysis>, expr: &RecExpr<Mdl>, cost_model: &CostModel) -> Vec<f32> {
+    let ids = crate::provenance::expr_eclasses(egraph, expr);
+    expr.as_ref()
+        .iter()
+        .zip(ids.iter())
+        .map(|(node, id)| {
+            let weights_only = id.map_or(true, |id| egraph[id].data.all_weights);
+            if weights_only || !node.all(|child| ids[usize::from(child)].is_some()) {
+                return 0.0;
+            }
+            let node = node.clone().map_children(|child| ids[usize::from(child)].unwrap());
+            cost_model.runtime_model().runtime(cost_model, egraph, &node)
+        })
+        .collect()
+}
+
+/// Fraction of the board power drawn by memory bound ops (everything except
+/// convolutions and matmuls), which leave most of the compute units idle
+pub const MEMORY_BOUND_POWER_FRACTION: f32 = 0.6;
+
+/// What the cost model optimizes for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Objective {
+    /// Runtime, in milliseconds
+    Latency,
+    /// Energy, in millijoules. Ops use the power measured while TASO
+    /// measured them if available (see power::PowerLog). Otherwise compute
+    /// bound ops draw the board power `watts`, memory bound ops a fraction
+    /// MEMORY_BOUND_POWER_FRACTION of it
+    Energy { watts: f32 },
+    /// Price of the inference, in micro-dollars, for an instance costing
+    /// `dollars_per_hour`
+    Price { dollars_per_hour: f32 },
+    /// Weighted sum of latency and energy, in milliseconds: the energy is
+    /// divided by the board power `watts` so both have the same scale. Used
+    /// to trade off latency and energy
+    Blend { watts: f32, energy_weight: f32 },
+}
+
+impl Objective {
+    /// Converts the runtime of an enode (in milliseconds) to the objective
+    ///
+    /// # Parameters
+    ///
+    /// - `enode`: the enode
+    /// - `runtime`: its runtime
+    /// - `measured_watts`: the power measured while its op ran, if any
+    pub fn from_runtime(&self, enode: &Mdl, runtime: f32, measured_watts: Option<f32>) -> f32 {
+        if runtime >= INFEASIBLE_COST {
+            return runtime;
+        }
+        let power = |watts: f32| {
+            let compute_bound = matches!(
+                enode,
+                Mdl::Conv2d(_)
+                    | Mdl::Sconv2d(_)
+                    | Mdl::Conv2dBlocked(_)
+                    | Mdl::Conv2dPacked(_)
+                    | Mdl::Sconv2dPacked(_)
+                    | Mdl::Matmul(_)
+                    | Mdl::Smatmul(_)
+                    | Mdl::Dense(_)
+            );
+            match measured_watts {
+                Some(w) => w,
+                None if compute_bound => watts,
+                None => watts * MEMORY_BOUND_POWER_FRACTION,
+            }
+        };
+        match self {
+            Objective::Latency => runtime,
+            Objective::Energy { watts } => runtime * power(*watts),
+            // ms * $/h / (3.6e6 ms/h), in micro-dollars
+            Objective::Price { dollars_per_hour } => runtime * dollars_per_hour / 3.6,
+            Objective::Blend { watts, energy_weight } => {
+                runtime * ((1.0 - energy_weight) + energy_weight * power(*watts) / watts)
+            }
+        }
+    }
+
+    /// Unit of the objective
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Objective::Latency => "ms",
+            Objective::Energy { .. } => "mJ",
+            Objective::Price { .. } => "micro-$",
+            Objective::Blend { .. } => "ms",
+        }
+    }
+}
+
+/// Reads the current board power draw of the first GPU through NVML, using
+/// nvidia-smi
+///
+/// # Returns
+///
+/// The power in watts, or an error if nvidia-smi is not available
+pub fn measure_gpu_power() -> Result<f32, String> {
+    let output = std::process::Command::new("nvidia-smi")
+        .args(&["--query-gpu=power.draw", "--format=csv,noheader,nounits", "--id=0"])
+        .output()
+        .map_err(|e| format!("could not run nvidia-smi: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    let out = String::from_utf8_lossy(&output.stdout);
+    out.trim()
+        .parse::<f32>()
+        .map_err(|_| format!("unexpected nvidia-smi output {}", out.trim()))
+}
+
+/// Secondary objective, deciding between graphs of equal cost
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TieBreak {
+    /// Ties are broken by the order of the enodes in the EGraph
+    None,
+    /// Prefer the graph with fewer ops
+    OpCount,
+    /// Prefer the graph with fewer ops not in the original graph, so the
+    /// extracted graph only differs from it where that is cheaper
+    PreferOriginal,
+}
+
+/// Weights of the metrics in the cost of an enode, for a weighted objective
+/// (see CostModel::with_weights)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ObjectiveWeights {
+    /// Weight of the objective (e.g. the runtime)
+    pub objective: f32,
+    /// Cost of each op, in the unit of the objective
+    pub op_count: f32,
+    /// Cost per MB of the activation of the enode (see
+    /// ValTnsr::activation_bytes), in the unit of the objective
+    pub memory: f32,
+}
+
+impl Default for ObjectiveWeights {
+    fn default() -> Self {
+        ObjectiveWeights {
+            objective: 1.0,
+            op_count: 0.0,
+            memory: 0.0,
+        }
+    }
+}
+
 /// Class for our cost model
 pub struct CostModel {
     /// To have zero cost for all weight op only
     ignore_all_weight_only: bool,
     /// Discount factor for all weight ops
     all_weight_discount: f32,
+    /// What the costs measure
+    objective: Objective,
+    /// Secondary objective
+    tie_break: TieBreak,
+    /// Cost added for each op counted by the secondary objective, small
+    /// enough to never change the order of graphs of different cost
+    tie_epsilon: f32,
+    /// Enodes of the original graph, for TieBreak::PreferOriginal
+    original_enodes: HashSet<Mdl>,
+    /// Where the runtimes come from
+    runtime_model: Box<dyn RuntimeModel>,
+    /// Weights of the objective, the op count and the memory
+    weights: ObjectiveWeights,
+    /// Shared weights that the extracted graph may not transform
+    tied_weights: Option<TiedWeights>,
+    /// Frozen layers that the extracted graph keeps as they are
+    frozen: Option<FrozenGraph>,
 }
 
 impl CostModel {
@@ -124,10 +423,206 @@ impl CostModel {
         CostModel {
             ignore_all_weight_only: ignore_all_weight_only,
             all_weight_discount: 1.0,
+            objective: Objective::Latency,
+            tie_break: TieBreak::None,
+            tie_epsilon: 0.0,
+            original_enodes: HashSet::new(),
+            runtime_model: Box::new(MeasuredRuntime),
+            weights: ObjectiveWeights::default(),
+            tied_weights: None,
+            frozen: None,
+        }
+    }
+
+    /// Sets where the runtimes come from, measured by TASO by default (see
+    /// cost.rs)
+    pub fn with_runtime_model(mut self, runtime_model: Box<dyn RuntimeModel>) -> Self {
+        self.runtime_model = runtime_model;
+        self
+    }
+
+    pub fn runtime_model(&self) -> &dyn RuntimeModel {
+        self.runtime_model.as_ref()
+    }
+
+    /// Computes the runtimes of all enodes of an EGraph in one pass, the cost
+    /// phase (see cost.rs). The runtime model is wrapped in a
+    /// PopulatedRuntime that looks them up
+    ///
+    /// # Returns
+    ///
+    /// The number of enodes populated
+    pub fn populate_runtimes(&mut self, egraph: &EGraph<Mdl, TensorAnalysis>) -> usize {
+        let runtimes: HashMap<Mdl, f32> = egraph
+            .classes()
+            .flat_map(|class| class.iter())
+            .map(|enode| (enode.clone(), self.runtime_model.runtime(self, egraph, enode)))
+            .collect();
+        let num_populated = runtimes.len();
+        self.set_populated(runtimes);
+        num_populated
+    }
+
+    /// Computes the runtimes of all enodes of an EGraph in one pass, as
+    /// populate_runtimes, but asks the runtime model for a sample of them
+    /// only (see cost::sampled_runtimes)
+    ///
+    /// # Parameters
+    ///
+    /// - `egraph`: E-graph of interest
+    /// - `sampling`: the buckets and the fraction of them to ask for
+    /// - `estimate`: how runtimes scale between shapes
+    ///
+    /// # Returns
+    ///
+    /// What was asked, and what was interpolated
+    pub fn populate_sampled_runtimes(
+        &mut self,
+        egraph: &EGraph<Mdl, TensorAnalysis>,
+        sampling: &ShapeSampling,
+        estimate: &dyn RuntimeModel,
+    ) -> SamplingReport {
+        let (runtimes, report) = sampled_runtimes(self, egraph, sampling, estimate);
+        self.set_populated(runtimes);
+        report
+    }
+
+    /// Wraps the runtime model in a PopulatedRuntime looking up runtimes
+    fn set_populated(&mut self, runtimes: HashMap<Mdl, f32>) {
+        let inner = std::mem::replace(&mut self.runtime_model, Box::new(MeasuredRuntime));
+        self.runtime_model = Box::new(PopulatedRuntime {
+            inner: inner,
+            runtimes: runtimes,
+        });
+    }
+
+    /// Applies the discount for ops on weights only, if they are ignored, to
+    /// the runtime of an enode
+    ///
+    /// # Parameters
+    ///
+    /// - `all_weights`: if the output of the enode is computed from weights
+    ///   only
+    /// - `runtime`: its runtime
+    pub fn discount_all_weights(&self, all_weights: bool, runtime: f32) -> f32 {
+        if self.ignore_all_weight_only && all_weights {
+            self.all_weight_discount * runtime
+        } else {
+            runtime
+        }
+    }
+
+    /// Sets the secondary objective, with the cost epsilon added for each op
+    /// it counts (see tie_epsilon)
+    pub fn with_tie_break(mut self, tie_break: TieBreak, epsilon: f32) -> Self {
+        self.tie_break = tie_break;
+        self.tie_epsilon = epsilon;
+        self
+    }
+
+    /// Sets the enodes of the original graph (see original_enodes)
+    pub fn with_original_enodes(mut self, enodes: HashSet<Mdl>) -> Self {
+        self.original_enodes = enodes;
+        self
+    }
+
+    /// Makes the enodes transforming shared weights infeasible (see tied.rs)
+    pub fn with_tied_weights(mut self, tied_weights: TiedWeights) -> Self {
+        self.tied_weights = Some(tied_weights);
+        self
+    }
+
+    /// Makes the enodes replacing frozen layers infeasible (see freeze.rs)
+    pub fn with_frozen(mut self, frozen: FrozenGraph) -> Self {
+        self.frozen = Some(frozen);
+        self
+    }
+
+    /// Gets the cost the secondary objective adds for the enode
+    pub fn get_tie_cost(&self, enode: &Mdl) -> f32 {
+        match (self.tie_break, enode) {
+            (TieBreak::None, _) | (_, Mdl::Num(_)) | (_, Mdl::Var(_)) => 0.0,
+            (TieBreak::OpCount, _) => self.tie_epsilon,
+            (TieBreak::PreferOriginal, _) if self.original_enodes.contains(enode) => 0.0,
+            (TieBreak::PreferOriginal, _) => self.tie_epsilon,
+        }
+    }
+
+    /// Sets what the costs measure, latency by default
+    pub fn with_objective(mut self, objective: Objective) -> Self {
+        self.objective = objective;
+        self
+    }
+
+    pub fn objective(&self) -> Objective {
+        self.objective
+    }
+
+    /// Sets the weights of the metrics, only the objective by default
+    pub fn with_weights(mut self, weights: ObjectiveWeights) -> Self {
+        self.weights = weights;
+        self
+    }
+
+    /// Changes the weights of the metrics, e.g. to sweep them (see
+    /// extract::pareto_front)
+    pub fn set_weights(&mut self, weights: ObjectiveWeights) {
+        self.weights = weights;
+    }
+
+    pub fn weights(&self) -> ObjectiveWeights {
+        self.weights
+    }
+
+    /// Weighs the cost of an enode in the objective with its other metrics
+    fn weigh(&self, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl, cost: f32) -> f32 {
+        if self.weights == ObjectiveWeights::default() || cost >= INFEASIBLE_COST {
+            return cost;
         }
+        let is_op = !matches!(enode, Mdl::Num(_) | Mdl::Var(_));
+        let bytes = enode_activation_bytes(egraph, enode);
+        self.weights.objective * cost
+            + if is_op { self.weights.op_count } else { 0.0 }
+            + self.weights.memory * bytes as f32 / 1e6
     }
 
-    /// Gets cost for the enode itself.
+    /// Gets cost for the enode itself, in the unit of the objective
+    ///
+    /// # Parameters
+    ///
+    /// - `egraph`: E-graph of interest
+    /// - `enode`: enode to get cost for
+    ///
+    /// # Returns
+    ///
+    /// Cost for this enode, with its op count and memory if they are weighted
+    /// (see with_weights). INFEASIBLE_COST if it was pruned, transforms a
+    /// tied weight or replaces a frozen layer.
+    pub fn get_self_cost(&self, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32 {
+        if egraph.analysis.pruned_nodes.contains(enode) {
+            return INFEASIBLE_COST;
+        }
+        if let Some(tied_weights) = &self.tied_weights {
+            if tied_weights.unties(egraph, enode) {
+                return INFEASIBLE_COST;
+            }
+        }
+        if let Some(frozen) = &self.frozen {
+            if frozen.replaces(egraph, enode) {
+                return INFEASIBLE_COST;
+            }
+        }
+        let runtime = self.runtime_model.runtime(self, egraph, enode);
+        let measured_watts = egraph
+            .analysis
+            .power_log
+            .as_ref()
+            .and_then(|log| log.lock().unwrap().per_enode.get(enode).cloned());
+        let cost = self.objective.from_runtime(enode, runtime, measured_watts);
+        self.weigh(egraph, enode, cost) + self.get_tie_cost(enode)
+    }
+
+    /// Gets the runtime for the enode itself.
     ///
     /// This function gets the cost by calling TASO's get_or_create_{some_op}()
     /// functions with the tensor information stored in metadata. TASO side stores
@@ -141,11 +636,18 @@ impl CostModel {
     ///
     /// # Returns
     ///
-    /// Cost for this enode.
-    pub fn get_self_cost(&self, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32 {
+    /// Runtime for this enode, in milliseconds.
+    pub fn get_self_runtime(&self, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32 {
         let x = |i: &Id| &egraph[*i].data;
         // let mut g = egraph.analysis.graph.borrow_mut();
         let mut g = egraph.analysis.graph.lock().unwrap();
+
+        // An enode with an infeasible input can not be measured, and its
+        // inputs may not have tensors to pass to TASO
+        if enode.children().iter().any(|id| x(id).infeasible) {
+            return INFEASIBLE_COST;
+        }
+
         match enode {
             Mdl::Num(_)
             | Mdl::Var(_)
@@ -159,6 +661,57 @@ impl CostModel {
             | Mdl::Dropout(_)
             | Mdl::Noop(_) => 0.0,
 
+            // The runtime of opaque ops is not known, it is the same for all
+            // graphs since no rule rewrites them
+            Mdl::Opaque(_) => 0.0,
+
+            // A copy of the weight, precomputed if the weight is known ahead
+            // of time
+            Mdl::Repack([_layout, _inpt]) => {
+                let _inpt_data = x(_inpt);
+                assert!(_inpt_data.dtype == DataKind::Tnsr);
+                // 1 GB/s is 1e6 bytes per millisecond
+                let runtime = 2.0 * 4.0 * tensor_volume(_inpt_data.meta.ptr()) / (REPACK_GB_PER_SEC * 1e6);
+
+                if self.ignore_all_weight_only && _inpt_data.all_weights {
+                    self.all_weight_discount * runtime
+                } else {
+                    runtime
+                }
+            }
+
+            // Reads the input and writes it in the other element type, like
+            // a repack
+            Mdl::Cast([_elem, _inpt]) => {
+                let _inpt_data = x(_inpt);
+                assert!(_inpt_data.dtype == DataKind::Tnsr);
+                let bytes = match output_elem(egraph, enode) {
+                    Ok(elem) => (_inpt_data.elem.bytes() + elem.bytes()) as f32,
+                    Err(_) => return INFEASIBLE_COST,
+                };
+                let runtime = bytes * tensor_volume(_inpt_data.meta.ptr()) / (REPACK_GB_PER_SEC * 1e6);
+
+                if self.ignore_all_weight_only && _inpt_data.all_weights {
+                    self.all_weight_discount * runtime
+                } else {
+                    runtime
+                }
+            }
+
+            // Registered ops can have a cost function
+            Mdl::Custom(children) => match parse_custom_label(x(&children[0]).name.as_str()) {
+                Some((custom, attrs)) if custom.cost.is_some() => {
+                    let inputs: Vec<Vec<i32>> = children[1..].iter().filter_map(|t| x(t).shape).map(shape_dims).collect();
+                    (custom.cost.unwrap())(&inputs, &attrs)
+                }
+                // Or are costed as their decomposition
+                Some((custom, attrs)) if custom.decomposition.is_some() => {
+                    let inputs: Vec<Vec<i32>> = children[1..].iter().filter_map(|t| x(t).shape).map(shape_dims).collect();
+                    decomposition_runtime(self, &custom, x(&children[0]).name.as_str(), &inputs, &attrs)
+                }
+                _ => 0.0,
+            },
+
             Mdl::Relu(_a) => {
                 // Check types
                 let a_t_data = x(_a);
@@ -167,9 +720,8 @@ impl CostModel {
                 let runtime = unsafe {
                     // Get op
                     let op =
-                        (*g.model).get_or_create_activation(*a_t_data.meta, OpType_OP_RELU, true);
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                        (*g.model).get_or_create_activation(*a_t_data.meta.ptr(), OpType_OP_RELU, true);
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_a).all_weights {
@@ -187,9 +739,8 @@ impl CostModel {
                 let runtime = unsafe {
                     // Get op
                     let op =
-                        (*g.model).get_or_create_activation(*a_t_data.meta, OpType_OP_TANH, true);
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                        (*g.model).get_or_create_activation(*a_t_data.meta.ptr(), OpType_OP_TANH, true);
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_a).all_weights {
@@ -207,12 +758,11 @@ impl CostModel {
                 let runtime = unsafe {
                     // Get op
                     let op = (*g.model).get_or_create_activation(
-                        *a_t_data.meta,
+                        *a_t_data.meta.ptr(),
                         OpType_OP_SIGMOID,
                         true,
                     );
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_a).all_weights {
@@ -222,7 +772,9 @@ impl CostModel {
                 }
             }
 
-            Mdl::Conv2d([_stride_h, _stride_w, _pad, _act, _inpt, _wght]) => {
+            Mdl::Conv2d([_stride_h, _stride_w, _pad, _act, _inpt, _wght])
+            | Mdl::Sconv2d([_stride_h, _stride_w, _pad, _act, _inpt, _wght])
+            | Mdl::Conv2dBlocked([_stride_h, _stride_w, _pad, _act, _inpt, _wght]) => {
                 // Check types
                 let _stride_h_data = x(_stride_h);
                 let _stride_w_data = x(_stride_w);
@@ -243,14 +795,18 @@ impl CostModel {
                 let padding: PaddingMode = _pad_data.val.try_into().unwrap();
                 let activation: ActiMode = _act_data.val.try_into().unwrap();
                 let runtime = unsafe {
-                    let t_inpt = *_inpt_data.meta;
-                    let t_wght = *_wght_data.meta;
+                    let t_inpt = *_inpt_data.meta.ptr();
+                    let t_wght = *_wght_data.meta.ptr();
                     // Get op
                     let op = (*g.model).get_or_create_conv2d(
                         t_inpt, t_wght, stride_h, stride_w, padding, activation,
                     );
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
+                };
+                let runtime = match enode {
+                    Mdl::Sconv2d(_) => sparse_runtime(runtime, _wght_data.density),
+                    Mdl::Conv2dBlocked(_) if runtime < INFEASIBLE_COST => runtime / BLOCKED_SPEEDUP,
+                    _ => runtime,
                 };
 
                 if self.ignore_all_weight_only && x(_inpt).all_weights && x(_wght).all_weights {
@@ -268,13 +824,12 @@ impl CostModel {
                 assert!(_b_data.dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_a = _a_data.meta;
-                let t_b = _b_data.meta;
+                let t_a = _a_data.meta.ptr();
+                let t_b = _b_data.meta.ptr();
                 let runtime = unsafe {
                     // Get op
                     let op = (*g.model).get_or_create_element(OpType_OP_EW_ADD, t_a, t_b);
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_a).all_weights && x(_b).all_weights {
@@ -292,13 +847,12 @@ impl CostModel {
                 assert!(_b_data.dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_a = _a_data.meta;
-                let t_b = _b_data.meta;
+                let t_a = _a_data.meta.ptr();
+                let t_b = _b_data.meta.ptr();
                 let runtime = unsafe {
                     // Get op
                     let op = (*g.model).get_or_create_element(OpType_OP_EW_MUL, t_a, t_b);
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_a).all_weights && x(_b).all_weights {
@@ -308,7 +862,7 @@ impl CostModel {
                 }
             }
 
-            Mdl::Matmul([_act, _a, _b]) => {
+            Mdl::Matmul([_act, _a, _b]) | Mdl::Smatmul([_act, _a, _b]) => {
                 // Check types
                 let _act_data = x(_act);
                 let _a_data = x(_a);
@@ -320,12 +874,15 @@ impl CostModel {
                 // Get arguments
                 let activation: ActiMode = _act_data.val.try_into().unwrap();
                 let runtime = unsafe {
-                    let t_a = *_a_data.meta;
-                    let t_b = *_b_data.meta;
+                    let t_a = *_a_data.meta.ptr();
+                    let t_b = *_b_data.meta.ptr();
                     // Get op
                     let op = (*g.model).get_or_create_matmul(t_a, t_b, activation);
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
+                };
+                let runtime = match enode {
+                    Mdl::Smatmul(_) => sparse_runtime(runtime, _b_data.density),
+                    _ => runtime,
                 };
 
                 if self.ignore_all_weight_only && x(_a).all_weights && x(_b).all_weights {
@@ -335,6 +892,55 @@ impl CostModel {
                 }
             }
 
+            Mdl::Dense([_act, _a, _b, _bias]) => {
+                // Check types
+                let _act_data = x(_act);
+                let _a_data = x(_a);
+                let _b_data = x(_b);
+                let _bias_data = x(_bias);
+                assert!(_act_data.dtype == DataKind::Scalar);
+                assert!(_a_data.dtype == DataKind::Tnsr);
+                assert!(_b_data.dtype == DataKind::Tnsr);
+                assert!(_bias_data.dtype == DataKind::Tnsr);
+
+                // The matmul and the bias add, the activation is applied in
+                // the epilogue of the bias add
+                let runtime = unsafe {
+                    let op = (*g.model).get_or_create_matmul(*_a_data.meta.ptr(), *_b_data.meta.ptr(), ACTNONE.try_into().unwrap());
+                    if op == Op_INVALID_OP {
+                        INFEASIBLE_COST
+                    } else {
+                        let add = (*g.model).get_or_create_element(OpType_OP_EW_ADD, &(*op.ptr).outputs[0], _bias_data.meta.ptr());
+                        op_runtime(&g, op) + op_runtime(&g, add)
+                    }
+                };
+
+                if self.ignore_all_weight_only && x(_a).all_weights && x(_b).all_weights && x(_bias).all_weights {
+                    self.all_weight_discount * runtime
+                } else {
+                    runtime
+                }
+            }
+
+            Mdl::Softmax([_inpt, _axis]) => {
+                // Check types
+                let _inpt_data = x(_inpt);
+                assert!(_inpt_data.dtype == DataKind::Tnsr);
+
+                let runtime = unsafe {
+                    // Get op
+                    let op = (*g.model).get_or_create_elementwise_unary(_inpt_data.meta.ptr(), OpType_OP_EXP);
+                    op_runtime(&g, op)
+                };
+                let runtime = if runtime < INFEASIBLE_COST { SOFTMAX_PASSES * runtime } else { runtime };
+
+                if self.ignore_all_weight_only && x(_inpt).all_weights {
+                    self.all_weight_discount * runtime
+                } else {
+                    runtime
+                }
+            }
+
             Mdl::Concat([_axis, _ndim, _a, _b]) => {
                 // Check types
                 let _axis_data = x(_axis);
@@ -350,15 +956,11 @@ impl CostModel {
                 let axis = _axis_data.val;
                 let ndim = _ndim_data.val;
                 unsafe {
-                    let t_a = *_a_data.meta;
-                    let t_b = *_b_data.meta;
+                    let t_a = *_a_data.meta.ptr();
+                    let t_b = *_b_data.meta.ptr();
 
-                    // Pass ownership to C++
                     let mut inputs = vec![t_a, t_b];
-                    inputs.shrink_to_fit();
-                    assert!(inputs.len() == inputs.capacity());
                     let ptr = inputs.as_mut_ptr();
-                    std::mem::forget(inputs);
 
                     // Get op
                     let mut need_copy = if self.ignore_all_weight_only
@@ -369,8 +971,28 @@ impl CostModel {
                         [false, false]
                     };
                     let op = (*g.model).get_or_create_concat(axis, 2, ptr, need_copy.as_mut_ptr());
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
+                }
+            }
+
+            Mdl::ConcatN(children) => {
+                // Check types
+                assert!(x(&children[0]).dtype == DataKind::Scalar);
+                assert!(x(&children[1]).dtype == DataKind::Scalar);
+                assert!(children[2..].iter().all(|t| x(t).dtype == DataKind::Tnsr));
+
+                // Get arguments
+                let axis = x(&children[0]).val;
+                let n = children.len() - 2;
+                unsafe {
+                    let mut inputs: Vec<_> = children[2..].iter().map(|t| *x(t).meta.ptr()).collect();
+                    let ptr = inputs.as_mut_ptr();
+
+                    // Get op
+                    let all_weights = children[2..].iter().all(|t| x(t).all_weights);
+                    let mut need_copy = vec![self.ignore_all_weight_only && !all_weights; n];
+                    let op = (*g.model).get_or_create_concat(axis, n as i32, ptr, need_copy.as_mut_ptr());
+                    op_runtime(&g, op)
                 }
             }
 
@@ -391,16 +1013,12 @@ impl CostModel {
                 let axis = _axis_data.val;
                 let ndim = _ndim_data.val;
                 unsafe {
-                    let t_1 = *_input1_data.meta;
-                    let t_2 = *_input2_data.meta;
-                    let t_3 = *_input3_data.meta;
+                    let t_1 = *_input1_data.meta.ptr();
+                    let t_2 = *_input2_data.meta.ptr();
+                    let t_3 = *_input3_data.meta.ptr();
 
-                    // Pass ownership to C++
                     let mut inputs = vec![t_1, t_2, t_3];
-                    inputs.shrink_to_fit();
-                    assert!(inputs.len() == inputs.capacity());
                     let ptr = inputs.as_mut_ptr();
-                    std::mem::forget(inputs);
 
                     // Get op
                     let mut need_copy = if self.ignore_all_weight_only
@@ -413,8 +1031,7 @@ impl CostModel {
                         [false, false, false]
                     };
                     let op = (*g.model).get_or_create_concat(axis, 3, ptr, need_copy.as_mut_ptr());
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 }
             }
 
@@ -437,17 +1054,13 @@ impl CostModel {
                 let axis = _axis_data.val;
                 let ndim = _ndim_data.val;
                 unsafe {
-                    let t_1 = *_input1_data.meta;
-                    let t_2 = *_input2_data.meta;
-                    let t_3 = *_input3_data.meta;
-                    let t_4 = *_input4_data.meta;
+                    let t_1 = *_input1_data.meta.ptr();
+                    let t_2 = *_input2_data.meta.ptr();
+                    let t_3 = *_input3_data.meta.ptr();
+                    let t_4 = *_input4_data.meta.ptr();
 
-                    // Pass ownership to C++
                     let mut inputs = vec![t_1, t_2, t_3, t_4];
-                    inputs.shrink_to_fit();
-                    assert!(inputs.len() == inputs.capacity());
                     let ptr = inputs.as_mut_ptr();
-                    std::mem::forget(inputs);
 
                     // Get op
                     let mut need_copy = if self.ignore_all_weight_only
@@ -461,8 +1074,7 @@ impl CostModel {
                         [false, false, false, false]
                     };
                     let op = (*g.model).get_or_create_concat(axis, 4, ptr, need_copy.as_mut_ptr());
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 }
             }
 
@@ -487,18 +1099,14 @@ impl CostModel {
                 let axis = _axis_data.val;
                 let ndim = _ndim_data.val;
                 unsafe {
-                    let t_1 = *_input1_data.meta;
-                    let t_2 = *_input2_data.meta;
-                    let t_3 = *_input3_data.meta;
-                    let t_4 = *_input4_data.meta;
-                    let t_5 = *_input5_data.meta;
+                    let t_1 = *_input1_data.meta.ptr();
+                    let t_2 = *_input2_data.meta.ptr();
+                    let t_3 = *_input3_data.meta.ptr();
+                    let t_4 = *_input4_data.meta.ptr();
+                    let t_5 = *_input5_data.meta.ptr();
 
-                    // Pass ownership to C++
                     let mut inputs = vec![t_1, t_2, t_3, t_4, t_5];
-                    inputs.shrink_to_fit();
-                    assert!(inputs.len() == inputs.capacity());
                     let ptr = inputs.as_mut_ptr();
-                    std::mem::forget(inputs);
 
                     // Get op
                     let mut need_copy = if self.ignore_all_weight_only
@@ -513,8 +1121,7 @@ impl CostModel {
                         [false, false, false, false, false]
                     };
                     let op = (*g.model).get_or_create_concat(axis, 5, ptr, need_copy.as_mut_ptr());
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 }
             }
 
@@ -543,7 +1150,7 @@ impl CostModel {
                 let padding: PaddingMode = _pad_data.val.try_into().unwrap();
                 let activation: ActiMode = _act_data.val.try_into().unwrap();
                 let runtime = unsafe {
-                    let t_inpt = *_inpt_data.meta;
+                    let t_inpt = *_inpt_data.meta.ptr();
                     let t_wght = t_inpt.clone(); // Just a placeholder, t_wght won't be used in get_or_create_pool2d here
 
                     // Get op
@@ -558,8 +1165,7 @@ impl CostModel {
                         padding,
                         activation,
                     );
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_inpt).all_weights {
@@ -594,7 +1200,7 @@ impl CostModel {
                 let padding: PaddingMode = _pad_data.val.try_into().unwrap();
                 let activation: ActiMode = _act_data.val.try_into().unwrap();
                 let runtime = unsafe {
-                    let t_inpt = *_inpt_data.meta;
+                    let t_inpt = *_inpt_data.meta.ptr();
                     let t_wght = t_inpt.clone(); // Just a placeholder, t_wght won't be used in get_or_create_pool2d here
 
                     // Get op
@@ -609,8 +1215,74 @@ impl CostModel {
                         padding,
                         activation,
                     );
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
+                };
+
+                if self.ignore_all_weight_only && x(_inpt).all_weights {
+                    self.all_weight_discount * runtime
+                } else {
+                    runtime
+                }
+            }
+
+            Mdl::Conv2dPacked([_attrs, _inpt, _wght]) | Mdl::Sconv2dPacked([_attrs, _inpt, _wght]) => {
+                // Check types
+                let _attrs_data = x(_attrs);
+                let _inpt_data = x(_inpt);
+                let _wght_data = x(_wght);
+                assert!(_attrs_data.dtype == DataKind::Scalar);
+                assert!(_inpt_data.dtype == DataKind::Tnsr);
+                assert!(_wght_data.dtype == DataKind::Tnsr);
+
+                // Get arguments
+                let (strides, pad, act) = unpack_attrs(_attrs_data.val, 2);
+                let padding: PaddingMode = pad.try_into().unwrap();
+                let activation: ActiMode = act.try_into().unwrap();
+                let runtime = unsafe {
+                    let t_inpt = *_inpt_data.meta.ptr();
+                    let t_wght = *_wght_data.meta.ptr();
+                    // Get op
+                    let op = (*g.model).get_or_create_conv2d(
+                        t_inpt, t_wght, strides[0], strides[1], padding, activation,
+                    );
+                    op_runtime(&g, op)
+                };
+                let runtime = match enode {
+                    Mdl::Sconv2dPacked(_) => sparse_runtime(runtime, _wght_data.density),
+                    _ => runtime,
+                };
+
+                if self.ignore_all_weight_only && x(_inpt).all_weights && x(_wght).all_weights {
+                    self.all_weight_discount * runtime
+                } else {
+                    runtime
+                }
+            }
+
+            Mdl::PoolmaxPacked([_inpt, _attrs]) | Mdl::PoolavgPacked([_inpt, _attrs]) => {
+                // Check types
+                let _attrs_data = x(_attrs);
+                let _inpt_data = x(_inpt);
+                assert!(_attrs_data.dtype == DataKind::Scalar);
+                assert!(_inpt_data.dtype == DataKind::Tnsr);
+
+                // Get arguments
+                let (sizes, pad, act) = unpack_attrs(_attrs_data.val, 4);
+                let padding: PaddingMode = pad.try_into().unwrap();
+                let activation: ActiMode = act.try_into().unwrap();
+                let op_type = match enode {
+                    Mdl::PoolmaxPacked(_) => OpType_OP_POOL2D_MAX,
+                    _ => OpType_OP_POOL2D_AVG,
+                };
+                let runtime = unsafe {
+                    let t_inpt = *_inpt_data.meta.ptr();
+                    let t_wght = t_inpt.clone(); // Just a placeholder, t_wght won't be used in get_or_create_pool2d here
+
+                    // Get op
+                    let op = (*g.model).get_or_create_pool2d(
+                        t_inpt, t_wght, op_type, sizes[0], sizes[1], sizes[2], sizes[3], padding, activation,
+                    );
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_inpt).all_weights {
@@ -628,13 +1300,12 @@ impl CostModel {
                 assert!(_inpt_data.dtype == DataKind::Tnsr);
 
                 // Get arguments
-                let t_inpt = _inpt_data.meta;
+                let t_inpt = _inpt_data.meta.ptr();
                 let axis = _axis_data.val;
                 let runtime = unsafe {
                     // Get op
                     let op = (*g.model).get_or_create_split1(t_inpt, axis, 2);
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_inpt).all_weights {
@@ -644,6 +1315,34 @@ impl CostModel {
                 }
             }
 
+            Mdl::SplitI([_axis, _count, _index, _inpt]) => {
+                // Check types
+                let _axis_data = x(_axis);
+                let _count_data = x(_count);
+                let _inpt_data = x(_inpt);
+                assert!(_axis_data.dtype == DataKind::Scalar);
+                assert!(_count_data.dtype == DataKind::Scalar);
+                assert!(_inpt_data.dtype == DataKind::Tnsr);
+
+                // Get arguments
+                let t_inpt = _inpt_data.meta.ptr();
+                let axis = _axis_data.val;
+                let count = _count_data.val;
+                // All outputs share one split op, each pays its part of it
+                let runtime = unsafe {
+                    // Get op
+                    let op = (*g.model).get_or_create_split1(t_inpt, axis, count);
+                    op_runtime(&g, op)
+                };
+                let runtime = if runtime < INFEASIBLE_COST { runtime / count as f32 } else { runtime };
+
+                if self.ignore_all_weight_only && x(_inpt).all_weights {
+                    self.all_weight_discount * runtime
+                } else {
+                    runtime
+                }
+            }
+
             Mdl::Enlarge([_a, _b]) => {
                 // Check types
                 let _a_data = x(_a);
@@ -653,12 +1352,11 @@ impl CostModel {
 
                 // Get arguments
                 let runtime = unsafe {
-                    let t_a = *_a_data.meta;
-                    let t_b = *_b_data.meta;
+                    let t_a = *_a_data.meta.ptr();
+                    let t_b = *_b_data.meta.ptr();
                     // Get op
                     let op = (*g.model).get_or_create_enlarge(t_a, t_b);
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_a).all_weights && x(_b).all_weights {
@@ -683,15 +1381,14 @@ impl CostModel {
 
                 // Get arguments
                 let runtime = unsafe {
-                    let t_inpt = *_inpt_data.meta;
-                    let t_scale = *_scale_data.meta;
-                    let t_bias = *_bias_data.meta;
-                    let t_mean = *_mean_data.meta;
-                    let t_var = *_var_data.meta;
+                    let t_inpt = *_inpt_data.meta.ptr();
+                    let t_scale = *_scale_data.meta.ptr();
+                    let t_bias = *_bias_data.meta.ptr();
+                    let t_mean = *_mean_data.meta.ptr();
+                    let t_var = *_var_data.meta.ptr();
                     // Get op
                     let op = (*g.model).get_or_create_batchnorm(t_inpt, t_scale, t_bias, t_mean, t_var);
-                    assert!(op != Op_INVALID_OP);
-                    (*op.ptr).runtime.clone()
+                    op_runtime(&g, op)
                 };
 
                 if self.ignore_all_weight_only && x(_inpt).all_weights && x(_scale).all_weights && x(_bias).all_weights && x(_mean).all_weights && x(_var).all_weights {
@@ -709,6 +1406,44 @@ impl CostModel {
     }
 }
 
+/// Gets the enodes of the original graph as they are in the EGraph now,
+/// with canonical children
+pub fn original_enodes(egraph: &EGraph<Mdl, TensorAnalysis>, expr: &RecExpr<Mdl>) -> HashSet<Mdl> {
+    let mut ids: Vec<Option<Id>> = Vec::with_capacity(expr.as_ref().len());
+    let mut enodes = HashSet::new();
+    for node in expr.as_ref() {
+        // Nodes removed from the EGraph (e.g. by cycle filtering) are skipped,
+        // along with the nodes using them
+        if node.children().iter().any(|c| ids[usize::from(*c)].is_none()) {
+            ids.push(None);
+            continue;
+        }
+        let mut enode = node.clone().map_children(|c| ids[usize::from(c)].unwrap());
+        let id = egraph.lookup(&mut enode);
+        if id.is_some() {
+            enodes.insert(enode);
+        }
+        ids.push(id);
+    }
+    enodes
+}
+
+/// Gets a cost epsilon for the secondary objective
+///
+/// Even if every enode of the EGraph is counted once, the epsilons add up
+/// to less than 0.1% of the smallest positive cost of an enode, so cheaper
+/// graphs stay cheaper.
+pub fn tie_epsilon(egraph: &EGraph<Mdl, TensorAnalysis>, cost_model: &CostModel) -> f32 {
+    let min_cost = egraph
+        .classes()
+        .flat_map(|c| c.iter())
+        .map(|node| cost_model.get_self_cost(egraph, node))
+        .filter(|cost| *cost > 0.0 && *cost < INFEASIBLE_COST)
+        .fold(std::f32::MAX, f32::min);
+    let min_cost = if min_cost == std::f32::MAX { 1.0 } else { min_cost };
+    1e-3 * min_cost / egraph.total_size().max(1) as f32
+}
+
 /// Prepare the data for formulation ILP
 ///
 /// # Returns
@@ -757,7 +1492,9 @@ pub fn prep_ilp_data(
         let m = *id_m_map.get(&egraph.find(class.id)).unwrap();
         for node in class.iter() {
             i_to_nodes.push(node.clone());
-            if egraph.analysis.blacklist_nodes.contains(node) {
+            let cost = cost_model.get_self_cost(egraph, node);
+            // Infeasible nodes are excluded from the solution the same way as blacklisted ones
+            if egraph.analysis.blacklist_nodes.contains(node) || cost >= INFEASIBLE_COST {
                 blacklist_i.push(i);
             }
             e_m[m].push(i);
@@ -767,7 +1504,7 @@ pub fn prep_ilp_data(
                     .map(|id| *id_m_map.get(&egraph.find(*id)).unwrap())
                     .collect(),
             );
-            cost_i.push(cost_model.get_self_cost(egraph, node));
+            cost_i.push(cost);
             g_i.push(m);
             i += 1;
         }
@@ -796,6 +1533,133 @@ pub struct SolvedResults {
     pub cost: f32,
     /// Time for solver
     pub time: f32,
+    /// Lower bound the solver proved on the cost
+    #[serde(default)]
+    pub bound: Option<f32>,
+}
+
+/// Checks that the enodes picked by an extraction form a valid graph
+///
+/// Independently of how the extraction was encoded, checks that every root
+/// and every child of a picked enode has an enode picked, that each picked
+/// enode is in its eclass, and that the picked enodes form a DAG. Only the
+/// eclasses reachable from the roots are checked.
+///
+/// # Parameters
+///
+/// - `node_picked`: hashmap storing which node is picked for each EClass ID
+/// - `roots`: the roots of the graph
+/// - `egraph`: E-graph the nodes are picked from
+///
+/// # Returns
+///
+/// Ok, or a minimal counterexample: the shortest path from a root to an
+/// eclass without a valid picked enode, or the shortest cycle
+pub fn check_selection(
+    node_picked: &HashMap<Id, Mdl>,
+    roots: &[Id],
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+) -> Result<(), String> {
+    let picked = |id: Id| node_picked.get(&egraph.find(id));
+    let children = |id: Id| -> Vec<Id> {
+        picked(id).map_or(vec![], |node| node.children().iter().map(|c| egraph.find(*c)).collect())
+    };
+    let show = |path: &[Id]| path.iter().map(|id| format!("{}", id)).collect::<Vec<_>>().join(" -> ");
+
+    // Breadth first from the roots, so the first problem found has the
+    // shortest path
+    let mut parent: HashMap<Id, Option<Id>> = HashMap::new();
+    let mut queue = std::collections::VecDeque::new();
+    for root in roots.iter() {
+        let root = egraph.find(*root);
+        if !parent.contains_key(&root) {
+            parent.insert(root, None);
+            queue.push_back(root);
+        }
+    }
+    while let Some(id) = queue.pop_front() {
+        let problem = match picked(id) {
+            None => Some("no enode picked"),
+            Some(node) if !egraph[id].nodes.contains(node) => Some("the picked enode is not in the eclass"),
+            Some(_) => None,
+        };
+        if let Some(problem) = problem {
+            let mut path = vec![id];
+            while let Some(Some(p)) = parent.get(path.last().unwrap()) {
+                path.push(*p);
+            }
+            path.reverse();
+            return Err(format!("eclass {}: {}, reached by {}", id, problem, show(&path)));
+        }
+        for child in children(id) {
+            if !parent.contains_key(&child) {
+                parent.insert(child, Some(id));
+                queue.push_back(child);
+            }
+        }
+    }
+
+    // Remove the eclasses not on or behind a cycle in topological order, so
+    // the search for the shortest cycle only considers the remaining ones
+    let mut num_parents: HashMap<Id, usize> = parent.keys().map(|id| (*id, 0)).collect();
+    for id in parent.keys() {
+        for child in children(*id) {
+            *num_parents.get_mut(&child).unwrap() += 1;
+        }
+    }
+    let mut free: Vec<Id> = num_parents.iter().filter(|(_, n)| **n == 0).map(|(id, _)| *id).collect();
+    while let Some(id) = free.pop() {
+        num_parents.remove(&id);
+        for child in children(id) {
+            let n = num_parents.get_mut(&child).unwrap();
+            *n -= 1;
+            if *n == 0 {
+                free.push(child);
+            }
+        }
+    }
+
+    // Shortest cycle through each remaining eclass, by breadth first search
+    // back to it
+    let mut shortest: Option<Vec<Id>> = None;
+    for start in num_parents.keys() {
+        let mut pred: HashMap<Id, Id> = HashMap::new();
+        let mut queue: std::collections::VecDeque<Id> = vec![*start].into();
+        let mut found = false;
+        while let Some(id) = queue.pop_front() {
+            for child in children(id) {
+                if child == *start {
+                    pred.insert(*start, id);
+                    found = true;
+                    break;
+                }
+                if !pred.contains_key(&child) {
+                    pred.insert(child, id);
+                    queue.push_back(child);
+                }
+            }
+            if found {
+                break;
+            }
+        }
+        if found {
+            let mut cycle = vec![*start];
+            let mut current = pred[start];
+            while current != *start {
+                cycle.push(current);
+                current = pred[&current];
+            }
+            cycle.push(*start);
+            cycle.reverse();
+            if shortest.as_ref().map_or(true, |s| cycle.len() < s.len()) {
+                shortest = Some(cycle);
+            }
+        }
+    }
+    match shortest {
+        Some(cycle) => Err(format!("the picked enodes form a cycle: {}", show(&cycle))),
+        None => Ok(()),
+    }
 }
 
 /// Construct the RecExpr of the optimized graph extracted
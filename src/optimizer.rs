@@ -0,0 +1,476 @@
+//! Library API to the optimizer, for embedding tensat in another tool
+//!
+//! Optimizer runs the core of the optimize command on a graph given in Rust:
+//!
+//! ```ignore
+//! let result = Optimizer::new(OptimizerSettings::default())
+//!     .add_graph(&expr)
+//!     .saturate()
+//!     .extract();
+//! println!("{} -> {}", result.original_cost, result.optimized_cost);
+//! ```
+//!
+//! That is the rules the selection of the settings enables (see
+//! rulesets.rs), saturation with the single- and multi-pattern rules, cycle
+//! filtering and extraction with one of the strategies of extract. An
+//! Optimizer can also restore a saturated EGraph from a checkpoint (restore)
+//! instead of saturating, or optimize a graph in pieces between its opaque
+//! ops (optimize_partitioned). The reports on the saturation and the
+//! optimized graph are in reports.rs, and take its egraph and start. The
+//! linked TASO library should be checked with check_taso_abi first, as the
+//! command does.
+//!
+//! The optimize command runs on an Optimizer too, one step at a time: it
+//! configures the multi-pattern rules, adds its hooks and scheduler to the
+//! runner before run, populates its own cost model and extracts, then records
+//! the graph with record_extraction:
+//!
+//! ```ignore
+//! let optimizer = Optimizer::new(settings).add_graph(&expr);
+//! let multi_patterns = optimizer.multi_patterns(output_dir).with_interleave(true);
+//! let runner = optimizer.runner(analysis, Some(multi_patterns)).with_hook(hook);
+//! let mut optimizer = optimizer.run(runner);
+//! let mut cost_model = optimizer.cost_model().with_runtime_model(runtime_model);
+//! optimizer.populate_runtimes(&mut cost_model);
+//! let (graph, _, secs) = optimizer.extract_with(&cost_model, &strategy, None);
+//! let result = optimizer.record_extraction(graph, strategy.name(), secs, &cost_model);
+//! ```
+//!
+//! What remains in the command is reading its flags and files into these
+//! calls, and the hooks of its flags (snapshots, progress, the live view).
+
+use crate::bundle;
+use crate::checkpoint::EGraphCheckpoint;
+use crate::ensemble::graph_cost;
+use crate::extract::*;
+use crate::cost::{RuntimeModel, SamplingReport, ShapeSampling};
+use crate::model::*;
+use crate::optimize::*;
+use crate::partition::{has_opaque, optimize_parts, optimize_regions};
+use crate::rewrites::*;
+use crate::rulesets::RuleSelection;
+use crate::shape::TensorShape;
+use crate::stats::RunStats;
+use crate::utils::get_full_graph_runtime;
+use egg::*;
+use std::time::{Duration, Instant};
+
+/// Settings of an Optimizer, the defaults are those of the optimize command
+#[derive(Debug, Clone)]
+pub struct OptimizerSettings {
+    /// Single-pattern rules, e.g. the lines of converted.txt
+    pub rules: Vec<String>,
+    /// Multi-pattern rules, two adjacent entries per rule, with whether the
+    /// rule is symmetric
+    pub multi_rules: Vec<(String, bool)>,
+    /// Whether to add PRE_DEFINED_RULES and PRE_DEFINED_MULTI to the rules
+    pub builtin_rules: bool,
+    /// Which of the rules to enable, see rulesets.rs
+    pub selection: RuleSelection,
+    /// Whether to apply each pair of mirrored rules as one rule, see
+    /// bidirectional_rules_from_str
+    pub bidirectional_rules: bool,
+    /// Whether to apply the multi-pattern rules
+    pub use_multi: bool,
+    pub iter_limit: usize,
+    pub time_limit_sec: u64,
+    pub node_limit: usize,
+    /// Number of iterations to apply the multi-pattern rules
+    pub iter_multi: usize,
+    /// Maximum number of nodes the multi-pattern rules add
+    pub node_multi: usize,
+    /// Whether to keep cycles out of the EGraph
+    pub no_cycle: bool,
+    /// Whether to filter cycles after each iteration, instead of checking
+    /// before each application
+    pub filter_after: bool,
+    pub ignore_all_weight_only: bool,
+    pub objective: Objective,
+    pub strategy: ExtractionStrategy,
+    /// Whether to measure the runtimes of the original and the optimized
+    /// graph with TASO
+    pub measure_runtime: bool,
+}
+
+impl Default for OptimizerSettings {
+    fn default() -> Self {
+        OptimizerSettings {
+            rules: vec![],
+            multi_rules: vec![],
+            builtin_rules: true,
+            selection: RuleSelection::default(),
+            bidirectional_rules: false,
+            use_multi: false,
+            iter_limit: 3,
+            time_limit_sec: 10,
+            node_limit: 100000,
+            iter_multi: 1,
+            node_multi: 3000000,
+            no_cycle: false,
+            filter_after: true,
+            ignore_all_weight_only: false,
+            objective: Objective::Latency,
+            strategy: ExtractionStrategy::Greedy,
+            measure_runtime: true,
+        }
+    }
+}
+
+/// Result of an Optimizer
+#[derive(Debug)]
+pub struct OptimizeResult {
+    pub graph: RecExpr<Mdl>,
+    /// Cost of the original and the optimized graph in the cost model,
+    /// counting shared nodes once
+    pub original_cost: f32,
+    pub optimized_cost: f32,
+    /// Statistics of the saturation and extraction, as for --stats_out
+    pub stats: RunStats,
+}
+
+/// Optimizes a graph, see the module documentation
+pub struct Optimizer {
+    settings: OptimizerSettings,
+    rules: Vec<Rewrite<Mdl, TensorAnalysis>>,
+    rule_strs: Vec<String>,
+    graphs: Vec<RecExpr<Mdl>>,
+    runner: Option<Runner<Mdl, TensorAnalysis, ()>>,
+    stats: RunStats,
+}
+
+impl Optimizer {
+    /// Parses the rules of the settings the selection enables, in their
+    /// order: rule i is named "rule{i}"
+    pub fn new(settings: OptimizerSettings) -> Self {
+        let mut split_rules: Vec<&str> = settings.rules.iter().map(|r| r.as_str()).collect();
+        if settings.builtin_rules {
+            split_rules.extend(PRE_DEFINED_RULES.iter());
+        }
+        let rule_strs = settings.selection.select_rules(split_rules);
+        bundle::record_rules(&rule_strs.iter().map(|r| r.as_str()).collect::<Vec<&str>>());
+        let split_rules: Vec<&str> = rule_strs.iter().map(|r| r.as_str()).collect();
+        let do_filter_after = settings.no_cycle && settings.filter_after;
+        let rules = if settings.bidirectional_rules {
+            bidirectional_rules_from_str(split_rules, do_filter_after)
+        } else {
+            rules_from_str(split_rules, do_filter_after)
+        };
+        Optimizer {
+            settings: settings,
+            rules: rules,
+            rule_strs: rule_strs,
+            graphs: vec![],
+            runner: None,
+            stats: RunStats::default(),
+        }
+    }
+
+    /// Adds a graph to optimize. Graphs added after the first one compute the
+    /// same as it, e.g. graphs optimized before, and start in its eclass
+    pub fn add_graph(mut self, expr: &RecExpr<Mdl>) -> Self {
+        assert!(self.runner.is_none(), "Graphs are added before saturating");
+        self.graphs.push(expr.clone());
+        self
+    }
+
+    /// Sets the limits of the saturation, e.g. once a time budget is split
+    pub fn with_limits(mut self, iter_limit: usize, time_limit_sec: u64, node_limit: usize) -> Self {
+        self.settings.iter_limit = iter_limit;
+        self.settings.time_limit_sec = time_limit_sec;
+        self.settings.node_limit = node_limit;
+        self
+    }
+
+    pub fn settings(&self) -> &OptimizerSettings {
+        &self.settings
+    }
+
+    /// The single-pattern rules the saturation applies
+    pub fn rules(&self) -> &[Rewrite<Mdl, TensorAnalysis>] {
+        &self.rules
+    }
+
+    /// The strings of the rules, rule i of rules is the i-th
+    pub fn rule_strs(&self) -> &[String] {
+        &self.rule_strs
+    }
+
+    /// The first added graph
+    pub fn start(&self) -> &RecExpr<Mdl> {
+        &self.graphs[0]
+    }
+
+    /// Builds the multi-pattern rules of the settings the selection enables,
+    /// to configure before passing them to runner
+    ///
+    /// # Parameters
+    ///
+    /// - `output_dir`: where the rules write their statistics, none if empty
+    pub fn multi_patterns(&self, output_dir: &str) -> MultiPatterns {
+        let settings = &self.settings;
+        let mut multi_rules: Vec<(&str, bool)> = settings.multi_rules.iter().map(|(r, s)| (r.as_str(), *s)).collect();
+        if settings.builtin_rules {
+            multi_rules.extend(PRE_DEFINED_MULTI.iter().map(|&r| (r, /*symmetric=*/ false)));
+        }
+        let multi_rules = settings.selection.select_multi_rules(multi_rules);
+        let multi_rules: Vec<(&str, bool)> = multi_rules.iter().map(|(r, s)| (r.as_str(), *s)).collect();
+        bundle::record_multi_rules(&multi_rules);
+        MultiPatterns::with_rules(
+            multi_rules,
+            settings.no_cycle,
+            settings.iter_multi,
+            settings.filter_after,
+            settings.node_multi,
+            settings.time_limit_sec,
+            output_dir.to_string(),
+        )
+    }
+
+    /// Builds the runner of the saturation, on the added graphs, to add hooks
+    /// or a scheduler to before run
+    ///
+    /// # Parameters
+    ///
+    /// - `analysis`: the analysis of the EGraph
+    /// - `multi_patterns`: the multi-pattern rules to apply with use_multi,
+    ///   if not the ones of the settings
+    pub fn runner(&self, analysis: TensorAnalysis, multi_patterns: Option<MultiPatterns>) -> Runner<Mdl, TensorAnalysis, ()> {
+        assert!(!self.graphs.is_empty(), "Add a graph before saturating");
+        let settings = &self.settings;
+        let mut runner = Runner::<Mdl, TensorAnalysis, ()>::new(analysis)
+            .with_node_limit(settings.node_limit)
+            .with_time_limit(Duration::from_secs(settings.time_limit_sec))
+            .with_iter_limit(settings.iter_limit)
+            .with_expr(&self.graphs[0]);
+        if settings.use_multi {
+            // This hook function (which applies the multi-pattern rules) will
+            // be called at the beginning of each iteration
+            let mut multi_patterns = multi_patterns.unwrap_or_else(|| self.multi_patterns(""));
+            runner = runner.with_hook(move |runner| multi_patterns.run_one(runner, None));
+        }
+        for graph in self.graphs[1..].iter() {
+            let root = runner.egraph.add_expr(graph);
+            runner.egraph.union(runner.roots[0], root);
+        }
+        runner.egraph.rebuild();
+        runner
+    }
+
+    /// Runs the saturation with a runner built by runner, then filters the
+    /// cycles
+    pub fn run(mut self, runner: Runner<Mdl, TensorAnalysis, ()>) -> Self {
+        let start_time = Instant::now();
+        let mut runner = runner.run(&self.rules[..]);
+        let saturation_secs = start_time.elapsed().as_secs_f32();
+        if self.settings.no_cycle && self.settings.filter_after {
+            // Do cycle removal after the final iteration
+            let filter_start = Instant::now();
+            remove_cycle_by_order(&mut runner);
+            self.stats.record_phase("cycle_filter", filter_start.elapsed().as_secs_f32());
+        }
+        self.with_saturated(runner, saturation_secs)
+    }
+
+    /// Uses the EGraph of a checkpoint instead of saturating, in a runner
+    /// built by runner, whose hooks are kept. The EGraph is analyzed with
+    /// the settings of the saturation that saved it
+    pub fn restore(self, mut runner: Runner<Mdl, TensorAnalysis, ()>, checkpoint: &EGraphCheckpoint) -> Result<Self, String> {
+        let (egraph, roots) = checkpoint.restore()?;
+        runner.egraph = egraph;
+        runner.roots = roots;
+        runner.stop_reason = Some(StopReason::Other(format!("loaded, saturation stopped: {}", checkpoint.stop_reason)));
+        Ok(self.with_saturated(runner, checkpoint.saturation_secs))
+    }
+
+    /// Uses a runner saturated before instead, e.g. restored from a checkpoint
+    ///
+    /// # Parameters
+    ///
+    /// - `runner`: the runner, after saturation
+    /// - `saturation_secs`: how long the saturation took
+    pub fn with_saturated(mut self, runner: Runner<Mdl, TensorAnalysis, ()>, saturation_secs: f32) -> Self {
+        self.stats.record_saturation(&runner, saturation_secs);
+        self.stats.num_cycles_filtered = runner.egraph.analysis.blacklist_nodes.len();
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Runs the saturation
+    pub fn saturate(self) -> Self {
+        let runner = self.runner(TensorAnalysis::default(), None);
+        self.run(runner)
+    }
+
+    /// The saturated runner
+    pub fn saturated(&self) -> &Runner<Mdl, TensorAnalysis, ()> {
+        self.runner.as_ref().expect("Saturate before")
+    }
+
+    pub fn saturated_mut(&mut self) -> &mut Runner<Mdl, TensorAnalysis, ()> {
+        self.runner.as_mut().expect("Saturate before")
+    }
+
+    /// The saturated EGraph
+    pub fn egraph(&self) -> &EGraph<Mdl, TensorAnalysis> {
+        &self.saturated().egraph
+    }
+
+    /// The eclass of the added graphs
+    pub fn root(&self) -> Id {
+        self.saturated().roots[0]
+    }
+
+    /// Statistics of the run so far
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut RunStats {
+        &mut self.stats
+    }
+
+    /// The cost model of the settings, before its runtimes are populated
+    pub fn cost_model(&self) -> CostModel {
+        CostModel::with_setting(self.settings.ignore_all_weight_only).with_objective(self.settings.objective)
+    }
+
+    /// Computes the runtimes of the enodes of the saturated EGraph in one
+    /// pass, see CostModel::populate_runtimes
+    ///
+    /// # Returns
+    ///
+    /// The number of enodes with a runtime
+    pub fn populate_runtimes(&mut self, cost_model: &mut CostModel) -> usize {
+        let populate_start = Instant::now();
+        let num_populated = cost_model.populate_runtimes(&self.saturated().egraph);
+        self.stats.record_phase("cost_population", populate_start.elapsed().as_secs_f32());
+        num_populated
+    }
+
+    /// Computes the runtimes of the enodes of the saturated EGraph for a
+    /// sample of their shapes, see CostModel::populate_sampled_runtimes
+    pub fn populate_sampled_runtimes(
+        &mut self,
+        cost_model: &mut CostModel,
+        sampling: &ShapeSampling,
+        estimate: &dyn RuntimeModel,
+    ) -> SamplingReport {
+        let populate_start = Instant::now();
+        let report = cost_model.populate_sampled_runtimes(&self.saturated().egraph, sampling, estimate);
+        self.stats.record_phase("cost_population", populate_start.elapsed().as_secs_f32());
+        report
+    }
+
+    /// Extracts a graph from the saturated EGraph
+    ///
+    /// # Parameters
+    ///
+    /// - `cost_model`: the cost model, with its runtimes populated
+    /// - `strategy`: how to extract
+    /// - `max_memory`: budget of activation memory of the graph, in bytes,
+    ///   see extract_within_memory
+    ///
+    /// # Returns
+    ///
+    /// The graph, its cost as returned by the strategy, and the seconds it
+    /// took
+    pub fn extract_with(
+        &self,
+        cost_model: &CostModel,
+        strategy: &ExtractionStrategy,
+        max_memory: Option<u64>,
+    ) -> (RecExpr<Mdl>, f32, f32) {
+        let runner = self.saturated();
+        match max_memory {
+            Some(budget) => extract_within_memory(&runner.egraph, runner.roots[0], cost_model, strategy, budget),
+            None => extract(&runner.egraph, runner.roots[0], cost_model, strategy),
+        }
+    }
+
+    /// Records an extracted graph: its cost and that of the first added
+    /// graph, and their runtimes with measure_runtime
+    ///
+    /// # Parameters
+    ///
+    /// - `graph`: the graph, extracted from the saturated EGraph
+    /// - `extract_mode`: how it was extracted, for the statistics
+    /// - `extraction_secs`: how long the extraction took
+    /// - `cost_model`: the cost model it was extracted with
+    pub fn record_extraction(
+        &self,
+        graph: RecExpr<Mdl>,
+        extract_mode: &str,
+        extraction_secs: f32,
+        cost_model: &CostModel,
+    ) -> OptimizeResult {
+        let egraph = self.egraph();
+        let mut stats = self.stats.clone();
+        stats.record_phase("extraction", extraction_secs);
+        stats.extract_mode = extract_mode.to_string();
+        let original_cost = graph_cost(egraph, &self.graphs[0], cost_model);
+        let optimized_cost = graph_cost(egraph, &graph, cost_model);
+        stats.original_cost = Some(original_cost);
+        stats.optimized_cost = Some(optimized_cost);
+
+        if self.settings.measure_runtime {
+            let runner_start = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&self.graphs[0]);
+            let runner_ext = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&graph);
+            stats.original_runtime = Some(get_full_graph_runtime(&runner_start, false));
+            stats.optimized_runtime = Some(get_full_graph_runtime(&runner_ext, true));
+        }
+
+        OptimizeResult {
+            graph: graph,
+            original_cost: original_cost,
+            optimized_cost: optimized_cost,
+            stats: stats,
+        }
+    }
+
+    /// Optimizes the first added graph in pieces instead, each saturated on
+    /// its own and extracted greedily: the regions of supported ops between
+    /// its opaque ops, or (if it has none) the parts of suggest_cuts
+    ///
+    /// # Parameters
+    ///
+    /// - `parts`: the part of each node and the shapes of the graph, as
+    ///   suggest_cuts and expr_shapes return them
+    ///
+    /// # Returns
+    ///
+    /// The optimized graph and the number of pieces
+    pub fn optimize_partitioned(&self, parts: Option<(&[Option<usize>], &[Option<Vec<TensorShape>>])>) -> (RecExpr<Mdl>, usize) {
+        let start = &self.graphs[0];
+        let settings = &self.settings;
+        let cost_model = self.cost_model();
+        let mut num_regions = 0;
+        let optimize = |region: &RecExpr<Mdl>| {
+            num_regions += 1;
+            let runner = Runner::<Mdl, TensorAnalysis, ()>::default()
+                .with_node_limit(settings.node_limit)
+                .with_time_limit(Duration::from_secs(settings.time_limit_sec))
+                .with_iter_limit(settings.iter_limit)
+                .with_expr(region)
+                .run(&self.rules[..]);
+            let tnsr_cost = TensorCost::new(&runner.egraph, &cost_model, true);
+            let (_, best) = Extractor::new(&runner.egraph, tnsr_cost).find_best(runner.roots[0]);
+            best
+        };
+        let best = match parts {
+            Some((part_of, shapes)) if !has_opaque(start) => optimize_parts(start, part_of, shapes, optimize),
+            _ => optimize_regions(start, optimize),
+        };
+        (best, num_regions)
+    }
+
+    /// Extracts the optimized graph with the strategy of the settings,
+    /// saturating first if saturate was not called
+    pub fn extract(self) -> OptimizeResult {
+        let mut optimizer = if self.runner.is_none() { self.saturate() } else { self };
+        let mut cost_model = optimizer.cost_model();
+        optimizer.populate_runtimes(&mut cost_model);
+        let strategy = optimizer.settings.strategy.clone();
+        let (graph, _, extraction_secs) = optimizer.extract_with(&cost_model, &strategy, None);
+        optimizer.record_extraction(graph, strategy.name(), extraction_secs, &cost_model)
+    }
+}
@@ -0,0 +1,79 @@
+//! Checking equivalence with ONNX Runtime (--equivalence_backend ort)
+//!
+//! The host interpreter (see interp.rs) implements the ops the same way as
+//! the rules assume them, so it can miss a rule that is wrong for the real
+//! kernels. This backend writes the original and the optimized graph as ONNX
+//! models and runs both with ONNX Runtime (verify/ort_equivalence.py), on
+//! the same pseudo-random inputs and weights, independently of TASO and of
+//! the interpreter.
+
+use crate::model::*;
+use crate::onnx::{export_onnx, ExecutionProvider, DEFAULT_ONNX_OPSET};
+use crate::shape::TensorShape;
+use crate::subprocess::run_solver;
+use egg::*;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Script comparing the outputs of two ONNX models
+pub const ORT_SCRIPT: &str = "verify/ort_equivalence.py";
+
+#[derive(Debug, Deserialize)]
+struct OrtErrors {
+    /// None if an output has NaNs
+    abs_err: Option<f32>,
+    rel_err: Option<f32>,
+}
+
+/// Runs two graphs with ONNX Runtime and compares their outputs
+///
+/// # Parameters
+///
+/// - `a`, `b`: the graphs, with the attributes not packed, each with the
+///   output shapes of its nodes (see shape::expr_shapes)
+/// - `seed`: seed of the inputs and weights
+/// - `dir`: directory to write the models (equivalence_start.onnx and
+///   equivalence_optimized.onnx) and the errors to
+///
+/// # Returns
+///
+/// The largest absolute and relative error of the outputs of b compared to
+/// those of a, or why they could not be compared
+pub fn ort_equivalence_errors(
+    a: (&RecExpr<Mdl>, &[Option<Vec<TensorShape>>]),
+    b: (&RecExpr<Mdl>, &[Option<Vec<TensorShape>>]),
+    seed: u64,
+    dir: &Path,
+) -> Result<(f32, f32), String> {
+    let model_a = dir.join("equivalence_start.onnx");
+    let model_b = dir.join("equivalence_optimized.onnx");
+    let errors_file = dir.join("equivalence_errors.json");
+    let write_model = |(expr, shapes): (&RecExpr<Mdl>, &[Option<Vec<TensorShape>>]), file: &Path| {
+        let bytes = export_onnx(expr, shapes, ExecutionProvider::Standard, DEFAULT_ONNX_OPSET)?;
+        fs::write(file, bytes).map_err(|e| format!("Could not write {}: {}", file.display(), e))
+    };
+    write_model(a, &model_a)?;
+    write_model(b, &model_b)?;
+    let _ = fs::remove_file(&errors_file);
+
+    let status = run_solver(
+        Command::new("python")
+            .arg(ORT_SCRIPT)
+            .arg(&model_a)
+            .arg(&model_b)
+            .arg(&errors_file)
+            .arg("--seed")
+            .arg(seed.to_string()),
+    )?;
+    if !status.success() {
+        return Err(format!("{} failed: {}", ORT_SCRIPT, status));
+    }
+    let s = fs::read_to_string(&errors_file).map_err(|e| format!("Could not read {}: {}", errors_file.display(), e))?;
+    let errors: OrtErrors = serde_json::from_str(&s).map_err(|e| format!("Could not parse {}: {}", errors_file.display(), e))?;
+    Ok((
+        errors.abs_err.unwrap_or(f32::INFINITY),
+        errors.rel_err.unwrap_or(f32::INFINITY),
+    ))
+}
@@ -2,10 +2,12 @@
 
 use crate::input::*;
 use crate::model::*;
+use crate::shape::broadcast_shape;
 use egg::*;
 use pest::{iterators::Pair, Parser};
 use root::taso::*;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(pest_derive::Parser)]
 #[grammar = "equation.pest"]
@@ -80,11 +82,222 @@ pub fn parse_and_convert(rs_s: &str) -> String {
     }
 }
 
+/// How an op of an imported model was handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ImportStatus {
+    /// Converted to the corresponding Mdl op
+    Mapped,
+    /// Not supported, kept as an opaque op (see Mdl::Opaque)
+    Opaque,
+    /// Could not be imported, the model is incomplete
+    Failed,
+}
+
+/// Counts of how the ops of one op type were handled
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OpCoverage {
+    pub mapped: usize,
+    pub opaque: usize,
+    pub failed: usize,
+    /// Why the first failed op of this type failed
+    pub error: Option<String>,
+}
+
+/// Coverage of the op types of an imported model
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CoverageReport {
+    /// Coverage by op type name
+    pub ops: BTreeMap<String, OpCoverage>,
+}
+
+impl CoverageReport {
+    /// Records how an op was handled
+    pub fn add(&mut self, op: &str, status: ImportStatus, error: Option<String>) {
+        let entry = self.ops.entry(op.to_string()).or_default();
+        match status {
+            ImportStatus::Mapped => entry.mapped += 1,
+            ImportStatus::Opaque => entry.opaque += 1,
+            ImportStatus::Failed => entry.failed += 1,
+        }
+        if entry.error.is_none() {
+            entry.error = error;
+        }
+    }
+
+    /// Number of ops that could not be imported
+    pub fn num_failed(&self) -> usize {
+        self.ops.values().map(|c| c.failed).sum()
+    }
+
+    /// Number of ops kept as opaque ops
+    pub fn num_opaque(&self) -> usize {
+        self.ops.values().map(|c| c.opaque).sum()
+    }
+
+    /// Formats the report as a table, one line per op type
+    pub fn to_table(&self) -> String {
+        let mut lines = vec![format!("{:<24} {:>8} {:>8} {:>8}  {}", "op", "mapped", "opaque", "failed", "error")];
+        for (op, c) in self.ops.iter() {
+            lines.push(format!(
+                "{:<24} {:>8} {:>8} {:>8}  {}",
+                op,
+                c.mapped,
+                c.opaque,
+                c.failed,
+                c.error.as_deref().unwrap_or("")
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// How strictly a model is imported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Any unsupported op or shape mismatch fails the import
+    Strict,
+    /// Unsupported ops that keep the shape of their input are kept as opaque
+    /// ops, other unsupported ops and shape mismatches fail
+    Default,
+    /// Every unsupported op and op with mismatched input shapes is kept as an
+    /// opaque op, with a warning. The opaque op is assumed to keep the shape
+    /// of its first input
+    Permissive,
+}
+
+/// TASO op types that do not change the shape of their first input. When not
+/// supported they are kept as opaque ops
+const SHAPE_PRESERVING_OPS: &[u32] = &[
+    OpType_OP_EW_SUB,
+    OpType_OP_EW_DIV,
+    OpType_OP_EW_MAX,
+    OpType_OP_EW_MIN,
+    OpType_OP_LEAKYRELU,
+    OpType_OP_PRELU,
+    OpType_OP_SQRT,
+    OpType_OP_EXP,
+    OpType_OP_LOG,
+    OpType_OP_CEIL,
+    OpType_OP_ROUND,
+    OpType_OP_CAST,
+];
+
+/// Gets the name of a TASO op type, for the coverage report
+fn op_type_name(op: u32) -> String {
+    let name = match op {
+        OpType_OP_INPUT => "OP_INPUT",
+        OpType_OP_WEIGHT => "OP_WEIGHT",
+        OpType_OP_MATMUL => "OP_MATMUL",
+        OpType_OP_EW_ADD => "OP_EW_ADD",
+        OpType_OP_EW_MUL => "OP_EW_MUL",
+        OpType_OP_RELU => "OP_RELU",
+        OpType_OP_TANH => "OP_TANH",
+        OpType_OP_SIGMOID => "OP_SIGMOID",
+        OpType_OP_DROPOUT => "OP_DROPOUT",
+        OpType_OP_RESHAPE => "OP_RESHAPE",
+        OpType_OP_TRANSPOSE => "OP_TRANSPOSE",
+        OpType_OP_CONV2D => "OP_CONV2D",
+        OpType_OP_POOL2D_AVG => "OP_POOL2D_AVG",
+        OpType_OP_POOL2D_MAX => "OP_POOL2D_MAX",
+        OpType_OP_CONCAT => "OP_CONCAT",
+        OpType_OP_SPLIT => "OP_SPLIT",
+        OpType_OP_BATCHNORM => "OP_BATCHNORM",
+        OpType_OP_EW_SUB => "OP_EW_SUB",
+        OpType_OP_EW_DIV => "OP_EW_DIV",
+        OpType_OP_EW_MAX => "OP_EW_MAX",
+        OpType_OP_EW_MIN => "OP_EW_MIN",
+        OpType_OP_LEAKYRELU => "OP_LEAKYRELU",
+        OpType_OP_PRELU => "OP_PRELU",
+        OpType_OP_SQRT => "OP_SQRT",
+        OpType_OP_EXP => "OP_EXP",
+        OpType_OP_LOG => "OP_LOG",
+        OpType_OP_CEIL => "OP_CEIL",
+        OpType_OP_ROUND => "OP_ROUND",
+        OpType_OP_CAST => "OP_CAST",
+        o => return format!("op type {}", o),
+    };
+    name.to_string()
+}
+
+/// Checks that the shapes of the inputs of an op agree, as TASO requires
+///
+/// # Returns
+///
+/// Ok, or a description of the mismatch
+fn check_shapes(op: u32, inputs: &[TensorInfo], params: &[i32]) -> Result<(), String> {
+    let dims = |t: &TensorInfo| t.shape[..t.n_dim].to_vec();
+    match op {
+        OpType_OP_EW_ADD | OpType_OP_EW_MUL => {
+            if broadcast_shape(&dims(&inputs[0]), &dims(&inputs[1])).is_none() {
+                return Err(format!("input shapes {:?} and {:?} do not broadcast", dims(&inputs[0]), dims(&inputs[1])));
+            }
+        }
+        OpType_OP_MATMUL => {
+            let (a, b) = (dims(&inputs[0]), dims(&inputs[1]));
+            if a.len() != b.len() || a.len() < 2 || a[a.len() - 1] != b[b.len() - 2] {
+                return Err(format!("input shapes {:?} and {:?} can not be multiplied", a, b));
+            }
+        }
+        OpType_OP_CONV2D => {
+            let (x, w) = (dims(&inputs[0]), dims(&inputs[1]));
+            if x.len() != 4 || w.len() != 4 || w[1] == 0 || x[1] % w[1] != 0 {
+                return Err(format!("input shape {:?} does not fit weight shape {:?}", x, w));
+            }
+        }
+        OpType_OP_CONCAT => {
+            let axis = params[0] as usize;
+            let first = dims(&inputs[0]);
+            for t in inputs[1..].iter() {
+                let other = dims(t);
+                let agree = other.len() == first.len()
+                    && axis < first.len()
+                    && (0..first.len()).all(|i| i == axis || other[i] == first[i]);
+                if !agree {
+                    return Err(format!("input shapes {:?} and {:?} can not be concatenated on axis {}", first, other, axis));
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
 // parses a serialized model from taso
 // see tests/parse.rs for an example
 pub fn parse_model(rs_s: &str) -> GraphConverter {
+    let (g, report) = parse_model_with_coverage(rs_s);
+    if report.num_failed() > 0 {
+        panic!("The model could not be fully imported:\n{}", report.to_table());
+    }
+    g
+}
+
+/// Parses a serialized model from taso, reporting how each op was handled
+///
+/// Ops of unsupported types that keep the shape of their input are kept as
+/// opaque ops. Other unsupported ops, and the ops using their outputs, fail.
+///
+/// # Returns
+///
+/// The converted graph, which is incomplete if an op failed, and the coverage
+/// report
+pub fn parse_model_with_coverage(rs_s: &str) -> (GraphConverter, CoverageReport) {
+    parse_model_with_mode(rs_s, ImportMode::Default)
+}
+
+/// Parses a serialized model from taso in the given import mode (see
+/// ImportMode), reporting how each op was handled
+///
+/// Ops using the outputs of failed ops fail in every mode.
+///
+/// # Returns
+///
+/// The converted graph, which is incomplete if an op failed, and the coverage
+/// report
+pub fn parse_model_with_mode(rs_s: &str, mode: ImportMode) -> (GraphConverter, CoverageReport) {
     let mut ls = rs_s.lines();
     let mut g = GraphConverter::default();
+    let mut report = CoverageReport::default();
     let mut nodes: HashMap<usize, Vec<TensorInfo>> = HashMap::new();
     loop {
         if let Some(l) = ls.next() {
@@ -108,8 +321,41 @@ pub fn parse_model(rs_s: &str) -> GraphConverter {
                 .split(",")
                 .map(|p_s| p_s.parse().unwrap())
                 .collect();
+            let op_name = op_type_name(op);
+            let is_source = op == OpType_OP_INPUT || op == OpType_OP_WEIGHT;
+            let missing_input = deps
+                .iter()
+                .any(|d| nodes.get(&d[0]).map_or(true, |outputs| d[1] >= outputs.len()));
+            if !is_source && missing_input {
+                report.add(&op_name, ImportStatus::Failed, Some(String::from("an input could not be imported")));
+                continue;
+            }
+            let inputs: Vec<TensorInfo> = if is_source {
+                vec![]
+            } else {
+                deps.iter().map(|child| nodes[&child[0]][child[1]]).collect()
+            };
+            let shape_error = check_shapes(op, &inputs, &params).err();
+            // An opaque op keeps the first and the last input
+            let opaque_inputs = if inputs.is_empty() {
+                vec![]
+            } else {
+                vec![inputs[0], inputs[inputs.len() - 1]]
+            };
+            let mut status = ImportStatus::Mapped;
             // node is really a vec, because split may return two outputs
             let node: Vec<TensorInfo> = match op {
+                _ if shape_error.is_some() && mode == ImportMode::Permissive && !opaque_inputs.is_empty() => {
+                    let e = shape_error.unwrap();
+                    println!("Warning: keeping {} (op {}) as an opaque op: {}", op_name, guid, e);
+                    status = ImportStatus::Opaque;
+                    let dims = inputs[0].shape[..inputs[0].n_dim].to_vec();
+                    vec![g.opaque(&format!("{}_{}", op_name, guid), &opaque_inputs, &dims)]
+                }
+                _ if shape_error.is_some() => {
+                    report.add(&op_name, ImportStatus::Failed, shape_error);
+                    continue;
+                }
                 OpType_OP_INPUT => vec![g.new_input(&params)],
                 OpType_OP_WEIGHT => vec![g.new_weight(&params)],
                 OpType_OP_MATMUL => vec![g.matmul(
@@ -121,7 +367,13 @@ vec![g.add(
                     nodes[&deps[0][0]][deps[0][1]],
                     nodes[&deps[1][0]][deps[1][1]],
                 )]},
+                OpType_OP_EW_MUL => vec![g.mul(
+                    nodes[&deps[0][0]][deps[0][1]],
+                    nodes[&deps[1][0]][deps[1][1]],
+                )],
                 OpType_OP_RELU => vec![g.relu(nodes[&deps[0][0]][deps[0][1]])],
+                OpType_OP_TANH => vec![g.tanh(nodes[&deps[0][0]][deps[0][1]])],
+                OpType_OP_SIGMOID => vec![g.sigmoid(nodes[&deps[0][0]][deps[0][1]])],
                 OpType_OP_DROPOUT => vec![g.dropout(nodes[&deps[0][0]][deps[0][1]])],
                 OpType_OP_RESHAPE => vec![g.reshape(nodes[&deps[0][0]][deps[0][1]], &params)],
                 OpType_OP_TRANSPOSE => {
@@ -134,16 +386,37 @@ vec![g.add(
                     let inputs: Vec<TensorInfo> = deps.iter().map(|child| nodes[&child[0]][child[1]]).collect();
                     vec![g.concat_multi(params[0], &inputs)]
                 },
-                OpType_OP_SPLIT => todo!(), // reference 'Split' case in taso/examples/load_model.py
                 OpType_OP_BATCHNORM => {
  vec![g.batchnorm(nodes[&deps[0][0]][deps[0][1]], nodes[&deps[1][0]][deps[1][1]], nodes[&deps[2][0]][deps[2][1]], nodes[&deps[3][0]][deps[3][1]], nodes[&deps[4][0]][deps[4][1]])]
                 },
-                o => panic!("{} not yet implemented", o),
+                o if mode == ImportMode::Default && SHAPE_PRESERVING_OPS.contains(&o) && deps.len() <= 2 => {
+                    status = ImportStatus::Opaque;
+                    let dims = inputs[0].shape[..inputs[0].n_dim].to_vec();
+                    vec![g.opaque(&format!("{}_{}", op_name, guid), &inputs, &dims)]
+                }
+                _ if mode == ImportMode::Permissive && !opaque_inputs.is_empty() => {
+                    if !SHAPE_PRESERVING_OPS.contains(&op) {
+                        println!("Warning: keeping {} (op {}) as an opaque op, assuming it keeps the shape of its input", op_name, guid);
+                    }
+                    status = ImportStatus::Opaque;
+                    let dims = inputs[0].shape[..inputs[0].n_dim].to_vec();
+                    vec![g.opaque(&format!("{}_{}", op_name, guid), &opaque_inputs, &dims)]
+                }
+                // e.g. split, see the 'Split' case in taso/examples/load_model.py
+                _ => {
+                    report.add(&op_name, ImportStatus::Failed, Some(format!("{} not yet implemented", op_name)));
+                    continue;
+                }
             };
+            report.add(&op_name, status, None);
+            // The guid is stable across exports of the same model
+            for t in node.iter() {
+                g.name_layer(t, &format!("op_{}", guid));
+            }
             nodes.insert(guid, node);
         } else {
             break;
         }
     }
-    g
+    (g, report)
 }
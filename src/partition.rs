@@ -0,0 +1,540 @@
+//! Optimizing the supported regions of a graph with opaque ops separately
+//!
+//! Opaque ops (see Mdl::Opaque) split a graph into regions of supported ops.
+//! No rule can rewrite across an opaque op, so each region can be optimized
+//! on its own, with its own EGraph and limits, and the optimized regions are
+//! stitched back together with the opaque ops left as they are. The outputs
+//! of opaque ops used by a region become inputs of the region, and the
+//! tensors of a region used by opaque ops (or returned) become its outputs.
+//!
+//! Graphs without opaque ops can be cut into parts the same way (see
+//! suggest_cuts): a part uses the tensors of earlier parts as inputs.
+
+use crate::model::*;
+use crate::shape::TensorShape;
+use egg::*;
+use itertools::Itertools;
+use std::collections::{HashMap, VecDeque};
+
+/// A region of supported ops, as a graph of its own
+#[derive(Debug, Clone)]
+pub struct Region {
+    /// The region, with an input for each opaque output it uses, and its
+    /// outputs combined with noops
+    pub expr: RecExpr<Mdl>,
+    /// Indices into the original graph of the outputs of the region
+    pub outputs: Vec<usize>,
+    /// Index in expr of each input standing in for an opaque output, and the
+    /// index of the opaque op in the original graph
+    pub boundary: HashMap<usize, usize>,
+}
+
+/// If a graph has opaque ops
+pub fn has_opaque(expr: &RecExpr<Mdl>) -> bool {
+    expr.as_ref().iter().any(|node| matches!(node, Mdl::Opaque(_)))
+}
+
+/// If the node is part of a region, as opposed to an opaque op or a
+/// name or scalar (which are copied into the regions using them)
+fn in_region(node: &Mdl) -> bool {
+    !matches!(node, Mdl::Opaque(_) | Mdl::Num(_) | Mdl::Var(_))
+}
+
+fn find(parent: &mut Vec<usize>, i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    parent[i] = root;
+    root
+}
+
+/// Splits a graph into the maximal connected regions of supported ops
+///
+/// # Returns
+///
+/// The regions, and for each node of expr the region it is in (None for
+/// opaque ops, names and scalars)
+pub fn supported_regions(expr: &RecExpr<Mdl>) -> (Vec<Region>, Vec<Option<usize>>) {
+    let nodes = expr.as_ref();
+
+    // Connected components over the edges between supported ops
+    let mut parent: Vec<usize> = (0..nodes.len()).collect();
+    for (i, node) in nodes.iter().enumerate() {
+        if !in_region(node) {
+            continue;
+        }
+        for child in node.children() {
+            let c = usize::from(*child);
+            if in_region(&nodes[c]) {
+                let (a, b) = (find(&mut parent, i), find(&mut parent, c));
+                parent[a] = b;
+            }
+        }
+    }
+    let mut region_of: Vec<Option<usize>> = vec![None; nodes.len()];
+    let mut region_ids: HashMap<usize, usize> = HashMap::new();
+    for (i, node) in nodes.iter().enumerate() {
+        if in_region(node) {
+            let r = find(&mut parent, i);
+            let num_regions = region_ids.len();
+            region_of[i] = Some(*region_ids.entry(r).or_insert(num_regions));
+        }
+    }
+
+    let regions = build_regions(expr, &region_of, region_ids.len(), |c| match &nodes[c] {
+        Mdl::Opaque([name, _, _]) => match &nodes[usize::from(*name)] {
+            Mdl::Var(s) => s.as_str().split("@").nth(1).unwrap().to_string(),
+            other => panic!("opaque op with name {}", other),
+        },
+        other => panic!("{} is used outside of its region", other),
+    });
+    (regions, region_of)
+}
+
+/// Adds a node and its children to a region, for the nodes copied into each
+/// region using them
+fn copy_node(nodes: &[Mdl], i: usize, sub: &mut RecExpr<Mdl>, ids: &mut HashMap<usize, Id>) -> Id {
+    if let Some(id) = ids.get(&i) {
+        return *id;
+    }
+    let node = nodes[i].clone().map_children(|child| copy_node(nodes, usize::from(child), sub, ids));
+    let id = sub.add(node);
+    ids.insert(i, id);
+    id
+}
+
+/// Builds the regions of a graph
+///
+/// # Parameters
+///
+/// - `expr`: the graph
+/// - `region_of`: the region of each node of expr. Opaque ops and the nodes
+///     of other regions become inputs of the regions using them, the other
+///     nodes not in a region are copied into them
+/// - `num_regions`: the number of regions
+/// - `boundary_dims`: the dims (format: dim1_dim2...) of the input standing
+///     in for a node
+fn build_regions<F>(expr: &RecExpr<Mdl>, region_of: &[Option<usize>], num_regions: usize, boundary_dims: F) -> Vec<Region>
+where
+    F: Fn(usize) -> String,
+{
+    let nodes = expr.as_ref();
+    let root = nodes.len() - 1;
+    let is_boundary = |c: usize| matches!(nodes[c], Mdl::Opaque(_)) || region_of[c].is_some();
+
+    // Tensors used outside of their region
+    let mut is_output = vec![false; nodes.len()];
+    is_output[root] = region_of[root].is_some();
+    for (i, node) in nodes.iter().enumerate() {
+        if !is_boundary(i) {
+            continue;
+        }
+        for child in node.children() {
+            let c = usize::from(*child);
+            is_output[c] |= region_of[c].is_some() && region_of[c] != region_of[i];
+        }
+    }
+
+    let mut regions = vec![];
+    for r in 0..num_regions {
+        let mut sub = RecExpr::default();
+        let mut ids: HashMap<usize, Id> = HashMap::new();
+        let mut boundary = HashMap::new();
+        let mut outputs = vec![];
+        for (i, node) in nodes.iter().enumerate() {
+            if region_of[i] != Some(r) {
+                continue;
+            }
+            let new_node = node.clone().map_children(|child| {
+                let c = usize::from(child);
+                if let Some(id) = ids.get(&c) {
+                    return *id;
+                }
+                if !is_boundary(c) {
+                    return copy_node(nodes, c, &mut sub, &mut ids);
+                }
+                let name_id = sub.add(Mdl::Var(Symbol::from(format!("boundary_{}@{}", c, boundary_dims(c)))));
+                let id = sub.add(Mdl::Input([name_id]));
+                boundary.insert(usize::from(id), c);
+                ids.insert(c, id);
+                id
+            });
+            ids.insert(i, sub.add(new_node));
+            if is_output[i] {
+                outputs.push(i);
+            }
+        }
+        // Combine the outputs, the first two in an inner noop
+        let mut out = ids[&outputs[0]];
+        for o in outputs[1..].iter() {
+            out = sub.add(Mdl::Noop([out, ids[o]]));
+        }
+        regions.push(Region {
+            expr: sub,
+            outputs: outputs,
+            boundary: boundary,
+        });
+    }
+    regions
+}
+
+/// Gets the indices of the outputs of an optimized region (or of any graph
+/// whose outputs are combined by noops like a region's), undoing the noops
+/// combining them
+///
+/// # Returns
+///
+/// The indices, or None if the noops were rewritten
+pub fn region_outputs(expr: &RecExpr<Mdl>, num_outputs: usize) -> Option<Vec<usize>> {
+    let nodes = expr.as_ref();
+    let mut outputs = vec![];
+    let mut current = nodes.len() - 1;
+    for _ in 1..num_outputs {
+        match &nodes[current] {
+            Mdl::Noop([rest, last]) => {
+                outputs.push(usize::from(*last));
+                current = usize::from(*rest);
+            }
+            _ => return None,
+        }
+    }
+    outputs.push(current);
+    outputs.reverse();
+    Some(outputs)
+}
+
+/// A node of the stitched graph, in the original graph or in an optimized region
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum NodeRef {
+    Original(usize),
+    Optimized(usize, usize),
+}
+
+/// Optimizes each supported region of a graph separately
+///
+/// # Parameters
+///
+/// - `expr`: the graph, with opaque ops
+/// - `optimize`: optimizes a region, e.g. by saturating and extracting it
+///
+/// # Returns
+///
+/// The graph with every region optimized, and the opaque ops as in expr
+pub fn optimize_regions<F>(expr: &RecExpr<Mdl>, optimize: F) -> RecExpr<Mdl>
+where
+    F: FnMut(&RecExpr<Mdl>) -> RecExpr<Mdl>,
+{
+    let (regions, region_of) = supported_regions(expr);
+    stitch_regions(expr, &regions, &region_of, optimize)
+}
+
+/// Optimizes each part of a graph separately, see suggest_cuts
+///
+/// # Parameters
+///
+/// - `expr`: the graph, without opaque ops
+/// - `part_of`: the part of each node of expr, from suggest_cuts
+/// - `shapes`: the output shapes of each node of expr (see expr_shapes)
+/// - `optimize`: optimizes a part, e.g. by saturating and extracting it
+///
+/// # Returns
+///
+/// The graph with every part optimized
+pub fn optimize_parts<F>(
+    expr: &RecExpr<Mdl>,
+    part_of: &[Option<usize>],
+    shapes: &[Option<Vec<TensorShape>>],
+    optimize: F,
+) -> RecExpr<Mdl>
+where
+    F: FnMut(&RecExpr<Mdl>) -> RecExpr<Mdl>,
+{
+    let num_parts = part_of.iter().flatten().max().map_or(0, |p| p + 1);
+    let regions = build_regions(expr, part_of, num_parts, |c| match &shapes[c] {
+        Some(shapes) if shapes.len() == 1 => shapes[0].dims.iter().join("_"),
+        _ => panic!("no shape for the tensor {} crossing a cut", c),
+    });
+    stitch_regions(expr, &regions, part_of, optimize)
+}
+
+/// Optimizes each region of a graph and puts the optimized regions together
+fn stitch_regions<F>(expr: &RecExpr<Mdl>, regions: &[Region], region_of: &[Option<usize>], mut optimize: F) -> RecExpr<Mdl>
+where
+    F: FnMut(&RecExpr<Mdl>) -> RecExpr<Mdl>,
+{
+    let nodes = expr.as_ref();
+
+    // Optimized graph and the index of each original output in it
+    let optimized: Vec<(RecExpr<Mdl>, HashMap<usize, usize>)> = regions
+        .iter()
+        .enumerate()
+        .map(|(r, region)| {
+            let opt = optimize(&region.expr);
+            match region_outputs(&opt, region.outputs.len()) {
+                Some(outs) => (opt, region.outputs.iter().cloned().zip(outs).collect()),
+                None => {
+                    println!("Region {}: the outputs were rewritten, keeping it as it is", r);
+                    let outs = region_outputs(&region.expr, region.outputs.len()).unwrap();
+                    (region.expr.clone(), region.outputs.iter().cloned().zip(outs).collect())
+                }
+            }
+        })
+        .collect();
+    // An optimized region still contains its boundary inputs, they stand for
+    // the opaque ops
+    let boundary: Vec<&HashMap<usize, usize>> = regions.iter().map(|region| &region.boundary).collect();
+    let resolve = |node: NodeRef| -> NodeRef {
+        match node {
+            NodeRef::Original(i) => match region_of[i] {
+                Some(r) => NodeRef::Optimized(r, optimized[r].1[&i]),
+                None => node,
+            },
+            NodeRef::Optimized(r, k) => match boundary[r].get(&k) {
+                Some(i) => NodeRef::Original(*i),
+                None => node,
+            },
+        }
+    };
+    let children = |node: NodeRef| -> Vec<NodeRef> {
+        match node {
+            NodeRef::Original(i) => nodes[i].children().iter().map(|c| resolve(NodeRef::Original(usize::from(*c)))).collect(),
+            NodeRef::Optimized(r, k) => optimized[r].0.as_ref()[k]
+                .children()
+                .iter()
+                .map(|c| resolve(NodeRef::Optimized(r, usize::from(*c))))
+                .collect(),
+        }
+    };
+
+    // Add the nodes in post order, from the root
+    let mut stitched = RecExpr::default();
+    let mut ids: HashMap<NodeRef, Id> = HashMap::new();
+    let root = resolve(NodeRef::Original(nodes.len() - 1));
+    let mut stack = vec![(root, false)];
+    while let Some((node, children_added)) = stack.pop() {
+        if ids.contains_key(&node) {
+            continue;
+        }
+        let node_children = children(node);
+        if !children_added {
+            stack.push((node, true));
+            for child in node_children.iter().rev() {
+                if !ids.contains_key(child) {
+                    stack.push((*child, false));
+                }
+            }
+            continue;
+        }
+        let mut child_ids = node_children.iter().map(|c| ids[c]);
+        let new_node = match node {
+            NodeRef::Original(i) => nodes[i].clone(),
+            NodeRef::Optimized(r, k) => optimized[r].0.as_ref()[k].clone(),
+        }
+        .map_children(|_| child_ids.next().unwrap());
+        ids.insert(node, stitched.add(new_node));
+    }
+    stitched
+}
+
+/// A suggested partition point, from suggest_cuts
+#[derive(Debug, Clone)]
+pub struct CutPoint {
+    /// Number of ops before the cut (in this and earlier parts)
+    pub num_before: usize,
+    /// Indices into the graph of the tensors crossing the cut
+    pub tensors: Vec<usize>,
+    /// Total size of the tensors crossing the cut, in bytes
+    pub bytes: u64,
+}
+
+/// Capacity standing for an edge that must not be cut
+const UNCUTTABLE: u64 = u64::MAX / 4;
+
+/// A flow network, with the edges and their residual edges stored in pairs
+struct FlowGraph {
+    adj: Vec<Vec<usize>>,
+    to: Vec<usize>,
+    cap: Vec<u64>,
+}
+
+impl FlowGraph {
+    fn new(num_vertices: usize) -> Self {
+        FlowGraph {
+            adj: vec![vec![]; num_vertices],
+            to: vec![],
+            cap: vec![],
+        }
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, cap: u64) {
+        self.adj[from].push(self.to.len());
+        self.to.push(to);
+        self.cap.push(cap);
+        self.adj[to].push(self.to.len());
+        self.to.push(from);
+        self.cap.push(0);
+    }
+
+    /// Finds a path with residual capacity from s to t, as the edge taken to
+    /// reach each vertex
+    fn find_path(&self, s: usize, t: usize) -> Option<Vec<Option<usize>>> {
+        let mut via: Vec<Option<usize>> = vec![None; self.adj.len()];
+        let mut seen = vec![false; self.adj.len()];
+        seen[s] = true;
+        let mut queue = VecDeque::from(vec![s]);
+        while let Some(u) = queue.pop_front() {
+            for e in self.adj[u].iter() {
+                let v = self.to[*e];
+                if self.cap[*e] > 0 && !seen[v] {
+                    seen[v] = true;
+                    via[v] = Some(*e);
+                    if v == t {
+                        return Some(via);
+                    }
+                    queue.push_back(v);
+                }
+            }
+        }
+        None
+    }
+
+    /// Saturates the network from s to t (Edmonds-Karp)
+    ///
+    /// # Returns
+    ///
+    /// For each vertex, if it is on the side of s of a minimum cut
+    fn min_cut(&mut self, s: usize, t: usize) -> Vec<bool> {
+        while let Some(via) = self.find_path(s, t) {
+            let mut path = vec![];
+            let mut v = t;
+            while let Some(e) = via[v] {
+                path.push(e);
+                v = self.to[e ^ 1];
+            }
+            let flow = path.iter().map(|e| self.cap[*e]).min().unwrap();
+            for e in path {
+                self.cap[e] -= flow;
+                self.cap[e ^ 1] += flow;
+            }
+        }
+        let mut source_side = vec![false; self.adj.len()];
+        source_side[s] = true;
+        let mut stack = vec![s];
+        while let Some(u) = stack.pop() {
+            for e in self.adj[u].iter() {
+                let v = self.to[*e];
+                if self.cap[*e] > 0 && !source_side[v] {
+                    source_side[v] = true;
+                    stack.push(v);
+                }
+            }
+        }
+        source_side
+    }
+}
+
+/// If the node is an op that can be placed in a part, as opposed to an input,
+/// a weight, a name or a scalar (which are copied into the parts using them)
+fn is_cut_op(node: &Mdl) -> bool {
+    in_region(node) && !matches!(node, Mdl::Input(_) | Mdl::Weight(_))
+}
+
+/// Suggests points to partition a graph at, cutting as few bytes of tensors
+/// as possible
+///
+/// The ops are leveled by their longest path from the inputs. The cut
+/// between parts j and j + 1 is a minimum cut (over the sizes of the tensors
+/// crossing it, each counted once however many ops use it) within the levels
+/// around j / num_parts of the depth of the graph. Ops using a tensor never
+/// come before the op producing it, so the parts can run one after the
+/// other. Tuples of tensors and tensors of unknown size are not cut.
+///
+/// # Parameters
+///
+/// - `expr`: the graph
+/// - `shapes`: the output shapes of each node of expr (see expr_shapes)
+/// - `num_parts`: the number of parts to cut the graph into
+///
+/// # Returns
+///
+/// The cuts, and for each node of expr the part it is in (None for inputs,
+/// weights, names and scalars). Graphs with too few levels, or with tuples
+/// of tensors in the way, get fewer cuts
+pub fn suggest_cuts(
+    expr: &RecExpr<Mdl>,
+    shapes: &[Option<Vec<TensorShape>>],
+    num_parts: usize,
+) -> (Vec<CutPoint>, Vec<Option<usize>>) {
+    let nodes = expr.as_ref();
+    let n = nodes.len();
+    let mut level = vec![0; n];
+    let mut users: Vec<Vec<usize>> = vec![vec![]; n];
+    for (i, node) in nodes.iter().enumerate() {
+        if !is_cut_op(node) {
+            continue;
+        }
+        for child in node.children() {
+            let c = usize::from(*child);
+            if is_cut_op(&nodes[c]) {
+                level[i] = level[i].max(level[c] + 1);
+                users[c].push(i);
+            }
+        }
+    }
+    let max_level = (0..n).filter(|i| is_cut_op(&nodes[*i])).map(|i| level[i]).max().unwrap_or(0);
+    let bytes = |i: usize| -> u64 {
+        match &shapes[i] {
+            Some(shapes) if shapes.len() == 1 => shapes[0].volume() as u64 * shapes[0].dtype.bytes() as u64,
+            _ => UNCUTTABLE,
+        }
+    };
+
+    let mut cuts = vec![];
+    let mut part_of: Vec<Option<usize>> = nodes.iter().map(|node| if is_cut_op(node) { Some(0) } else { None }).collect();
+    let step = (max_level + 1) as f64 / num_parts.max(1) as f64;
+    for j in 1..num_parts {
+        let low = ((j as f64 - 0.5) * step).floor() as usize;
+        let high = (((j as f64 + 0.5) * step).ceil() as usize).max(low + 1);
+        if high > max_level {
+            break;
+        }
+        // Vertex i is op i, n + i the tensor it produces
+        let (s, t) = (2 * n, 2 * n + 1);
+        let mut flow = FlowGraph::new(2 * n + 2);
+        for i in (0..n).filter(|i| is_cut_op(&nodes[*i])) {
+            flow.add_edge(i, n + i, bytes(i));
+            for user in users[i].iter() {
+                flow.add_edge(n + i, *user, UNCUTTABLE);
+                // A user before the cut needs the op producing it before too
+                flow.add_edge(*user, i, UNCUTTABLE);
+            }
+            if level[i] <= low {
+                flow.add_edge(s, i, UNCUTTABLE);
+            } else if level[i] >= high {
+                flow.add_edge(i, t, UNCUTTABLE);
+            }
+        }
+        let before = flow.min_cut(s, t);
+        let tensors: Vec<usize> = (0..n)
+            .filter(|i| before[*i] && users[*i].iter().any(|user| !before[*user]))
+            .collect();
+        let cut_bytes = tensors.iter().fold(0, |sum: u64, i| sum.saturating_add(bytes(*i)));
+        let num_before = (0..n).filter(|i| before[*i]).count();
+        let num_prev = cuts.last().map_or(0, |cut: &CutPoint| cut.num_before);
+        if cut_bytes >= UNCUTTABLE || num_before == num_prev {
+            continue;
+        }
+        for (i, part) in part_of.iter_mut().enumerate() {
+            if part.is_some() && !before[i] {
+                *part = Some(cuts.len() + 1);
+            }
+        }
+        cuts.push(CutPoint {
+            num_before: num_before,
+            tensors: tensors,
+            bytes: cut_bytes,
+        });
+    }
+    (cuts, part_of)
+}
@@ -0,0 +1,126 @@
+//! GPU power measurement during op measurement
+//!
+//! TASO measures the runtime of an op when it is first created, on the C++
+//! side. While the analysis creates ops, a sampler records the board power
+//! through NVML (via nvidia-smi, which polls NVML), and the average power
+//! over the time an op was created in is recorded for its enode. Together
+//! with the runtime this gives the energy of the op.
+
+use crate::model::*;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sampling period of the power in milliseconds. NVML updates the power
+/// reading about every 20ms on most boards
+pub const SAMPLE_PERIOD_MS: u64 = 20;
+
+/// Creating an op faster than this did not measure it (TASO found it in its
+/// cache), so no power is recorded
+const MIN_MEASURE_TIME: Duration = Duration::from_millis(1);
+
+/// Samples the power of a GPU in the background
+pub struct PowerSampler {
+    child: Child,
+    samples: Arc<Mutex<Vec<(Instant, f32)>>>,
+}
+
+impl PowerSampler {
+    /// Starts sampling the power of GPU `gpu_id`
+    ///
+    /// # Returns
+    ///
+    /// The sampler, or an error if nvidia-smi is not available
+    pub fn start(gpu_id: usize) -> Result<Self, String> {
+        let mut child = Command::new("nvidia-smi")
+            .arg("--query-gpu=power.draw")
+            .arg("--format=csv,noheader,nounits")
+            .arg(format!("--id={}", gpu_id))
+            .arg(format!("--loop-ms={}", SAMPLE_PERIOD_MS))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("could not run nvidia-smi: {}", e))?;
+        let stdout = child.stdout.take().unwrap();
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let samples_writer = samples.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line.map(|l| l.trim().parse::<f32>()) {
+                    Ok(Ok(watts)) => samples_writer.lock().unwrap().push((Instant::now(), watts)),
+                    Ok(Err(_)) => continue,
+                    Err(_) => break,
+                }
+            }
+        });
+        Ok(PowerSampler {
+            child: child,
+            samples: samples,
+        })
+    }
+
+    /// Gets the average power between from and to
+    ///
+    /// Windows shorter than the sampling period use the sample closest to
+    /// their middle.
+    ///
+    /// # Returns
+    ///
+    /// The power in watts, or None if there are no samples yet
+    pub fn average(&self, from: Instant, to: Instant) -> Option<f32> {
+        let samples = self.samples.lock().unwrap();
+        let inside: Vec<f32> = samples
+            .iter()
+            .filter(|(t, _)| *t >= from && *t <= to)
+            .map(|(_, w)| *w)
+            .collect();
+        if !inside.is_empty() {
+            return Some(inside.iter().sum::<f32>() / inside.len() as f32);
+        }
+        let middle = from + (to - from) / 2;
+        let distance = |t: &Instant| if *t > middle { *t - middle } else { middle - *t };
+        samples
+            .iter()
+            .min_by_key(|(t, _)| distance(t))
+            .map(|(_, w)| *w)
+    }
+}
+
+impl Drop for PowerSampler {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Power recorded for the enodes measured by TASO
+pub struct PowerLog {
+    sampler: PowerSampler,
+    /// Average power while the op of each enode was measured, in watts
+    pub per_enode: HashMap<Mdl, f32>,
+}
+
+impl PowerLog {
+    pub fn new(sampler: PowerSampler) -> Self {
+        PowerLog {
+            sampler: sampler,
+            per_enode: HashMap::new(),
+        }
+    }
+
+    /// Records the power for enode, if its op was measured between from and to
+    pub fn record(&mut self, enode: &Mdl, from: Instant, to: Instant) {
+        if to - from < MIN_MEASURE_TIME || self.per_enode.contains_key(enode) {
+            return;
+        }
+        if let Some(watts) = self.sampler.average(from, to) {
+            self.per_enode.insert(enode.clone(), watts);
+        }
+    }
+}
+
+/// Shared power log, stored in the analysis
+pub type SharedPowerLog = Arc<Mutex<PowerLog>>;
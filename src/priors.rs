@@ -0,0 +1,179 @@
+//! Priors of the rules, to schedule them
+//!
+//! TASO's rules do not come with any record of how useful they are, so the
+//! priors are built from what is known about each rule: the size of its
+//! source pattern (written with the rules when converting them, see
+//! convert_learned_rules) and how often it was applied in earlier runs
+//! (recorded after each run given the priors file). Rules are kept by their text, so the
+//! priors of a rules file remain valid when rules are added or reordered.
+//!
+//! PriorScheduler uses them to delay the rules unlikely to apply: a rule
+//! applied in earlier runs is searched from the first iteration, one never
+//! seen before from an iteration growing with the size of its source pattern
+//! (large patterns rarely match), and one never applied in earlier runs only
+//! from MAX_DELAY on.
+
+use crate::model::*;
+use egg::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+/// Latest iteration a rule is delayed to
+pub const MAX_DELAY: usize = 3;
+
+/// What is known about a rule
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuleMeta {
+    /// Number of ops in the source pattern
+    pub src_size: usize,
+    /// Number of runs with the rule
+    pub num_runs: usize,
+    /// Number of times the rule was applied, over all runs
+    pub num_applied: usize,
+}
+
+/// Gets the number of ops in the source pattern of a rule
+pub fn src_size(rule: &str) -> usize {
+    let lhs = rule.split("=>").next().unwrap();
+    lhs.matches('(').count()
+}
+
+/// Gets the positions of the rules a rewrite was made from, by its name (see
+/// rules_from_str and bidirectional_rules_from_str)
+pub fn rule_positions(name: &str) -> Vec<usize> {
+    name.trim_start_matches("rule").split('-').filter_map(|pos| pos.parse().ok()).collect()
+}
+
+/// Priors of rules, by the text of the rule
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RulePriors {
+    pub rules: HashMap<String, RuleMeta>,
+}
+
+impl RulePriors {
+    /// Priors with only the sizes of the source patterns of the rules
+    pub fn from_rules(rules: &[&str]) -> Self {
+        let mut priors = RulePriors::default();
+        for rule in rules.iter() {
+            priors.meta(rule);
+        }
+        priors
+    }
+
+    /// Loads the priors in filename, empty if the file does not exist
+    pub fn load(filename: &str) -> Result<Self, String> {
+        if !std::path::Path::new(filename).exists() {
+            return Ok(RulePriors::default());
+        }
+        let s = fs::read_to_string(filename).map_err(|e| format!("Could not read {}: {}", filename, e))?;
+        serde_json::from_str(&s).map_err(|e| format!("Could not parse {}: {}", filename, e))
+    }
+
+    pub fn save(&self, filename: &str) {
+        fs::write(filename, serde_json::to_string_pretty(self).unwrap()).expect("Unable to write file");
+    }
+
+    fn meta(&mut self, rule: &str) -> &mut RuleMeta {
+        self.rules.entry(rule.to_string()).or_insert_with(|| RuleMeta {
+            src_size: src_size(rule),
+            num_runs: 0,
+            num_applied: 0,
+        })
+    }
+
+    /// Records how often each rule was applied in a run
+    ///
+    /// # Parameters
+    ///
+    /// - `rules`: the rules of the run, in the order given to rules_from_str
+    /// - `runner`: the runner after saturation
+    pub fn record_run(&mut self, rules: &[&str], runner: &Runner<Mdl, TensorAnalysis, ()>) {
+        let mut applied = vec![0; rules.len()];
+        for iteration in runner.iterations.iter() {
+            for (name, n) in iteration.applied.iter() {
+                // Both directions of a bidirectional rule are credited
+                for pos in rule_positions(&name.to_string()) {
+                    if pos < applied.len() {
+                        applied[pos] += n;
+                    }
+                }
+            }
+        }
+        for (rule, num_applied) in rules.iter().zip(applied) {
+            let meta = self.meta(rule);
+            meta.num_runs += 1;
+            meta.num_applied += num_applied;
+        }
+    }
+
+    /// Gets the first iteration each rule is searched in
+    ///
+    /// # Parameters
+    ///
+    /// - `rules`: the rules of the run, in the order given to rules_from_str
+    ///
+    /// # Returns
+    ///
+    /// The first iteration of each rule, in the order of rules
+    pub fn start_iterations(&self, rules: &[&str]) -> Vec<usize> {
+        rules
+            .iter()
+            .map(|rule| match self.rules.get(*rule) {
+                Some(meta) if meta.num_applied > 0 => 0,
+                Some(meta) if meta.num_runs > 0 => MAX_DELAY,
+                Some(meta) => meta.src_size.saturating_sub(1).min(MAX_DELAY),
+                None => src_size(rule).saturating_sub(1).min(MAX_DELAY),
+            })
+            .collect()
+    }
+}
+
+/// Scheduler searching each rule only from its first iteration (see
+/// RulePriors::start_iterations) on, and as the inner scheduler after
+pub struct PriorScheduler {
+    inner: Box<dyn RewriteScheduler<Mdl, TensorAnalysis>>,
+    /// First iteration of each rule, by its position
+    start: Vec<usize>,
+}
+
+impl PriorScheduler {
+    pub fn new<S: RewriteScheduler<Mdl, TensorAnalysis> + 'static>(inner: S, start: Vec<usize>) -> Self {
+        PriorScheduler {
+            inner: Box::new(inner),
+            start: start,
+        }
+    }
+
+    /// Number of rules delayed past the first iteration
+    pub fn num_delayed(&self) -> usize {
+        self.start.iter().filter(|i| **i > 0).count()
+    }
+}
+
+impl RewriteScheduler<Mdl, TensorAnalysis> for PriorScheduler {
+    fn can_stop(&mut self, iteration: usize) -> bool {
+        // The EGraph is only saturated once every rule was searched
+        let last_start = self.start.iter().copied().max().unwrap_or(0);
+        iteration >= last_start && self.inner.can_stop(iteration)
+    }
+
+    fn search_rewrite<'a>(
+        &mut self,
+        iteration: usize,
+        egraph: &EGraph<Mdl, TensorAnalysis>,
+        rewrite: &'a Rewrite<Mdl, TensorAnalysis>,
+    ) -> Vec<SearchMatches<'a, Mdl>> {
+        // A bidirectional rule is searched from the first iteration of
+        // either direction
+        let start = rule_positions(rewrite.name.as_str())
+            .iter()
+            .filter_map(|pos| self.start.get(*pos).copied())
+            .min()
+            .unwrap_or(0);
+        if iteration < start {
+            return vec![];
+        }
+        self.inner.search_rewrite(iteration, egraph, rewrite)
+    }
+}
@@ -0,0 +1,91 @@
+//! Best graphs so far during saturation (--progress_every)
+//!
+//! Every k iterations (as a runner hook) and after the last one, the EGraph
+//! is extracted greedily and the graph written to best_<iteration>.txt, with
+//! its cost appended to progress.jsonl. The costs make a convergence curve of
+//! the run, and a long run on a large model has usable graphs before it
+//! ends. The greedy extraction is fast, but a final extraction with another
+//! strategy can still find a better graph than the last one written.
+
+use crate::attrs::unpack_expr;
+use crate::extract::{extract, ExtractionStrategy};
+use crate::model::*;
+use crate::optimize::{CostModel, Objective};
+use egg::*;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// The best graph after an iteration
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressPoint {
+    /// Number of iterations done
+    pub iteration: usize,
+    /// Seconds since the recorder started
+    pub secs: f32,
+    pub num_enodes: usize,
+    pub num_classes: usize,
+    /// Cost of the greedily extracted graph
+    pub cost: f32,
+}
+
+pub struct ProgressRecorder {
+    dir: PathBuf,
+    every: usize,
+    /// Whether the EGraph has packed attributes, the graphs are written
+    /// unpacked
+    packed_attrs: bool,
+    cost_model: CostModel,
+    start_time: Instant,
+    pub points: Vec<ProgressPoint>,
+}
+
+impl ProgressRecorder {
+    pub fn new(dir: &str, every: usize, packed_attrs: bool, ignore_all_weight_only: bool, objective: Objective) -> Self {
+        assert!(every > 0, "Record the progress every 1 or more iterations");
+        let dir = PathBuf::from(dir);
+        // Start a new curve for this run
+        let _ = fs::remove_file(dir.join("progress.jsonl"));
+        ProgressRecorder {
+            dir: dir,
+            every: every,
+            packed_attrs: packed_attrs,
+            cost_model: CostModel::with_setting(ignore_all_weight_only).with_objective(objective),
+            start_time: Instant::now(),
+            points: vec![],
+        }
+    }
+
+    /// Extracts and writes the best graph every k iterations. Call it before
+    /// each iteration (it is a runner hook) and with last set after the last
+    /// one
+    pub fn record(&mut self, runner: &Runner<Mdl, TensorAnalysis, ()>, last: bool) -> Result<(), String> {
+        let iteration = runner.iterations.len();
+        let done = self.points.last().map_or(false, |p| p.iteration == iteration);
+        if iteration == 0 || done || (!last && iteration % self.every != 0) {
+            return Ok(());
+        }
+        let (best, cost, _) = extract(&runner.egraph, runner.roots[0], &self.cost_model, &ExtractionStrategy::Greedy);
+        let best = if self.packed_attrs { unpack_expr(&best) } else { best };
+        let filename = self.dir.join(format!("best_{}.txt", iteration));
+        fs::write(filename, best.to_string()).map_err(|e| e.to_string())?;
+
+        let point = ProgressPoint {
+            iteration: iteration,
+            secs: self.start_time.elapsed().as_secs_f32(),
+            num_enodes: runner.egraph.total_number_of_nodes(),
+            num_classes: runner.egraph.number_of_classes(),
+            cost: cost,
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("progress.jsonl"))
+            .map_err(|e| e.to_string())?;
+        writeln!(file, "{}", serde_json::to_string(&point).unwrap()).map_err(|e| e.to_string())?;
+        self.points.push(point);
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+//! Tracks which layers of the original graph each node of the optimized graph replaces
+
+use crate::model::*;
+use egg::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Gets the eclass of every node in a graph
+///
+/// # Returns
+///
+/// For each node in expr (in the same order), the Id of its eclass, or None if
+/// the node is not in the EGraph
+pub fn expr_eclasses(egraph: &EGraph<Mdl, TensorAnalysis>, expr: &RecExpr<Mdl>) -> Vec<Option<Id>> {
+    let mut ids: Vec<Option<Id>> = Vec::with_capacity(expr.as_ref().len());
+    for node in expr.as_ref() {
+        let id = if node.all(|child| ids[usize::from(child)].is_some()) {
+            let node = node.clone().map_children(|child| ids[usize::from(child)].unwrap());
+            egraph.lookup(node).map(|id| egraph.find(id))
+        } else {
+            None
+        };
+        ids.push(id);
+    }
+    ids
+}
+
+/// If the node is a layer (an op on tensors), as opposed to a name or a scalar parameter
+fn is_layer(node: &Mdl) -> bool {
+    match node {
+        Mdl::Num(_) | Mdl::Var(_) | Mdl::Noop(_) => false,
+        _ => true,
+    }
+}
+
+/// Names every layer of a graph by its op and its index in the RecExpr, e.g. conv2d_12
+///
+/// The index is stable as long as the original graph is constructed the same way.
+pub fn default_layer_names(expr: &RecExpr<Mdl>) -> HashMap<Id, String> {
+    expr.as_ref()
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| is_layer(node))
+        .map(|(i, node)| (Id::from(i), format!("{}_{}", node, i)))
+        .collect()
+}
+
+/// Computes the provenance of each node in the optimized graph
+///
+/// A layer of the original graph is attributed to the node of the optimized
+/// graph computing the same value (i.e. in the same eclass). A layer with no
+/// such node was rewritten away, it is attributed to the nodes the closest
+/// downstream layers are attributed to. For example a conv followed by a
+/// batchnorm that get fused into one conv are both attributed to that conv.
+///
+/// # Parameters
+///
+/// - `egraph`: E-graph containing both graphs, e.g. the EGraph after saturation
+/// - `original`: the original graph
+/// - `names`: names of the layers in the original graph, layers not in here
+///     get the names from default_layer_names
+/// - `optimized`: the optimized graph extracted from egraph
+///
+/// # Returns
+///
+/// For each node in optimized (in the same order), the sorted names of the
+/// original layers it replaces. Empty for nodes that are not layers.
+pub fn provenance(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    original: &RecExpr<Mdl>,
+    names: &HashMap<Id, String>,
+    optimized: &RecExpr<Mdl>,
+) -> Vec<Vec<String>> {
+    let orig_nodes = original.as_ref();
+    let opt_nodes = optimized.as_ref();
+    let mut default_names = default_layer_names(original);
+    for (id, name) in names.iter() {
+        default_names.insert(*id, name.clone());
+    }
+
+    // Layers of the optimized graph, by eclass
+    let mut opt_by_class: HashMap<Id, usize> = HashMap::new();
+    for (i, class) in expr_eclasses(egraph, optimized).iter().enumerate() {
+        if let Some(class) = class {
+            if is_layer(&opt_nodes[i]) {
+                opt_by_class.insert(*class, i);
+            }
+        }
+    }
+
+    // Users of each node in the original graph
+    let mut users: Vec<Vec<usize>> = vec![vec![]; orig_nodes.len()];
+    for (i, node) in orig_nodes.iter().enumerate() {
+        node.for_each(|child| users[usize::from(child)].push(i));
+    }
+
+    // Users come after their inputs in a RecExpr, so going backwards the
+    // targets of all users are known when a node is processed
+    let orig_classes = expr_eclasses(egraph, original);
+    let mut targets: Vec<Vec<usize>> = vec![vec![]; orig_nodes.len()];
+    for i in (0..orig_nodes.len()).rev() {
+        if !is_layer(&orig_nodes[i]) {
+            continue;
+        }
+        targets[i] = match orig_classes[i].and_then(|c| opt_by_class.get(&c)) {
+            Some(j) => vec![*j],
+            None => {
+                let mut t: Vec<usize> = users[i].iter().flat_map(|u| targets[*u].clone()).collect();
+                t.sort();
+                t.dedup();
+                t
+            }
+        };
+    }
+
+    let mut result: Vec<Vec<String>> = vec![vec![]; opt_nodes.len()];
+    for (i, t) in targets.iter().enumerate() {
+        // Inputs and weights are not layers that get replaced
+        match orig_nodes[i] {
+            Mdl::Input(_) | Mdl::Weight(_) => continue,
+            _ => (),
+        }
+        if let Some(name) = default_names.get(&Id::from(i)) {
+            for j in t {
+                result[*j].push(name.clone());
+            }
+        }
+    }
+    for names in result.iter_mut() {
+        names.sort();
+        names.dedup();
+    }
+    result
+}
+
+/// Marker attributing an op of an exported model back to tensat
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfilingMarker {
+    /// Guid of the op on the TASO side, as it appears in the exported model
+    pub guid: usize,
+    /// Index of the node in the graph the model was exported from
+    pub node: usize,
+    /// Name of the op, e.g. conv2d_12. Profilers can use it as a label
+    pub name: String,
+    /// Names of the original layers the op replaces, see provenance
+    pub replaces: Vec<String>,
+}
+
+/// Computes the profiling markers for a graph exported from an EGraph
+///
+/// The ops in a model exported by TASO are identified by their guid, so a
+/// marker maps a guid to the node that created it and to the original layers
+/// it replaces. Nodes without an op of their own on the TASO side (names,
+/// scalars, and the outputs of a split) get no marker.
+///
+/// # Parameters
+///
+/// - `egraph`: E-graph the model is exported from, e.g. the one of the runner
+///     constructed from expr
+/// - `expr`: the exported graph
+/// - `replaced`: for each node in expr, the original layers it replaces
+///
+/// # Returns
+///
+/// The markers, in the order of the nodes in expr
+pub fn profiling_markers(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    expr: &RecExpr<Mdl>,
+    replaced: &[Vec<String>],
+) -> Vec<ProfilingMarker> {
+    let nodes = expr.as_ref();
+    let mut markers = Vec::new();
+    for (i, class) in expr_eclasses(egraph, expr).iter().enumerate() {
+        let node = &nodes[i];
+        if !is_layer(node) || matches!(node, Mdl::Split0(_) | Mdl::Split1(_)) {
+            continue;
+        }
+        let data = match class {
+            Some(class) => &egraph[*class].data,
+            None => continue,
+        };
+        if data.infeasible || data.meta.is_null() {
+            continue;
+        }
+        let guid = unsafe { (*data.meta.ptr()).op.guid } as usize;
+        markers.push(ProfilingMarker {
+            guid: guid,
+            node: i,
+            name: format!("{}_{}", node, i),
+            replaces: replaced[i].clone(),
+        });
+    }
+    markers
+}
@@ -0,0 +1,114 @@
+//! Pruning the enodes of explosive eclasses (--prune_enodes)
+//!
+//! Some rules, like the associativity and commutativity of elementwise ops,
+//! grow a few eclasses to hundreds of equivalent enodes, most of which are
+//! never cheap. At the beginning of each iteration, the eclasses with more
+//! than a number of enodes are pruned: their enodes are measured, each with
+//! the cheapest cost of its children (as the greedy extraction counts it),
+//! and only the k cheapest and the enodes of the original graph are kept.
+//!
+//! egg can not remove enodes, so the pruned ones stay in the EGraph like
+//! blacklisted ones: they are infeasible in the extraction (see
+//! CostModel::get_self_cost), and rules no longer apply to matches using
+//! them, so their eclasses stop growing from them.
+
+use crate::model::*;
+use crate::optimize::{original_enodes, CostModel, TensorCost};
+use egg::*;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+/// Prunes the eclasses of an EGraph with too many enodes
+pub struct Pruner {
+    /// Number of enodes an eclass can have without being pruned
+    pub max_enodes: usize,
+    /// Number of the cheapest enodes kept in a pruned eclass
+    pub keep: usize,
+    /// The original graph, whose enodes are always kept
+    original: RecExpr<Mdl>,
+    /// Number of enodes pruned so far
+    pub num_pruned: usize,
+}
+
+/// Chooses the enodes to prune in an eclass
+///
+/// # Parameters
+///
+/// - `costs`: the enodes of the eclass that are not pruned yet, with their
+///   costs
+/// - `keep`: number of the cheapest enodes to keep
+/// - `original`: enodes that are always kept
+///
+/// # Returns
+///
+/// The enodes to prune
+pub fn enodes_to_prune(costs: &[(Mdl, f32)], keep: usize, original: &HashSet<Mdl>) -> Vec<Mdl> {
+    let mut sorted: Vec<&(Mdl, f32)> = costs.iter().collect();
+    sorted.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+    sorted
+        .iter()
+        .skip(keep)
+        .filter(|(enode, _)| !original.contains(enode))
+        .map(|(enode, _)| enode.clone())
+        .collect()
+}
+
+/// Updates the pruned enodes with the canonical Ids of their children, after
+/// the EGraph was rebuilt
+pub fn canonicalize_pruned(egraph: &mut EGraph<Mdl, TensorAnalysis>) {
+    let pruned = std::mem::take(&mut egraph.analysis.pruned_nodes);
+    egraph.analysis.pruned_nodes = pruned
+        .into_iter()
+        .map(|enode| enode.map_children(|id| egraph.find(id)))
+        .collect();
+}
+
+impl Pruner {
+    pub fn new(max_enodes: usize, keep: usize, original: &RecExpr<Mdl>) -> Self {
+        Pruner {
+            max_enodes: max_enodes,
+            keep: keep,
+            original: original.clone(),
+            num_pruned: 0,
+        }
+    }
+
+    /// Prunes the eclasses of an EGraph with more than max_enodes enodes
+    ///
+    /// # Returns
+    ///
+    /// The number of enodes pruned
+    pub fn prune(&mut self, egraph: &mut EGraph<Mdl, TensorAnalysis>) -> usize {
+        canonicalize_pruned(egraph);
+        let original = original_enodes(egraph, &self.original);
+        let cost_model = CostModel::with_setting(false);
+        let to_prune: Vec<Mdl> = {
+            let extractor = Extractor::new(egraph, TensorCost::new(egraph, &cost_model, true));
+            let pruned = &egraph.analysis.pruned_nodes;
+            egraph
+                .classes()
+                .flat_map(|class| {
+                    let costs: Vec<(Mdl, f32)> = class
+                        .iter()
+                        .filter(|enode| !pruned.contains(*enode))
+                        .map(|enode| {
+                            let cost = enode.fold(cost_model.get_self_cost(egraph, enode), |sum, id| {
+                                sum + extractor.find_best_cost(id)
+                            });
+                            (enode.clone(), cost)
+                        })
+                        .collect();
+                    if costs.len() > self.max_enodes {
+                        enodes_to_prune(&costs, self.keep, &original)
+                    } else {
+                        vec![]
+                    }
+                })
+                .collect()
+        };
+        let num_pruned = to_prune.len();
+        egraph.analysis.pruned_nodes.extend(to_prune);
+        self.num_pruned += num_pruned;
+        num_pruned
+    }
+}
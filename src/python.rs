@@ -0,0 +1,84 @@
+//! Python bindings of the Optimizer, built with the `python` feature
+//!
+//! Build the module with `maturin develop --features python`, then:
+//!
+//! ```python
+//! import json, tensat
+//! graph, stats = tensat.optimize(open("model.txt").read(), rules_file="converted.txt")
+//! print(json.loads(stats)["optimized_cost"])
+//! ```
+//!
+//! Graphs are passed as text, in any format read_graph reads; the optimized
+//! graph comes back in the textual format (see text::to_text) and the stats
+//! as the JSON of RunStats.
+
+use crate::extract::ExtractionStrategy;
+use crate::ffi::check_taso_abi;
+use crate::optimizer::*;
+use crate::text::{read_graph, to_text};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use std::fs;
+
+/// Optimizes a graph, returns the optimized graph and the stats as JSON
+#[pyfunction]
+#[pyo3(text_signature = "(graph, rules_file=None, multi_rules_file=None, n_iter=3, n_sec=10, n_nodes=100000, extract=\"greedy\", no_cycle=True, measure_runtime=True)")]
+#[args(
+    rules_file = "None",
+    multi_rules_file = "None",
+    n_iter = "3",
+    n_sec = "10",
+    n_nodes = "100000",
+    extract = "\"greedy\"",
+    no_cycle = "true",
+    measure_runtime = "true"
+)]
+#[allow(clippy::too_many_arguments)]
+fn optimize(
+    py: Python,
+    graph: &str,
+    rules_file: Option<&str>,
+    multi_rules_file: Option<&str>,
+    n_iter: usize,
+    n_sec: u64,
+    n_nodes: usize,
+    extract: &str,
+    no_cycle: bool,
+    measure_runtime: bool,
+) -> PyResult<(String, String)> {
+    check_taso_abi().map_err(PyRuntimeError::new_err)?;
+    let expr = read_graph(graph).map_err(PyValueError::new_err)?;
+    let mut settings = OptimizerSettings::default();
+    if let Some(file) = rules_file {
+        let rules = fs::read_to_string(file).map_err(|e| PyValueError::new_err(format!("Could not read {}: {}", file, e)))?;
+        settings.rules = rules.lines().map(String::from).collect();
+    }
+    if let Some(file) = multi_rules_file {
+        let rules = fs::read_to_string(file).map_err(|e| PyValueError::new_err(format!("Could not read {}: {}", file, e)))?;
+        // The learned multi-pattern rules are symmetric
+        settings.multi_rules = rules.lines().filter(|r| !r.trim().is_empty()).map(|r| (r.to_string(), true)).collect();
+        settings.use_multi = true;
+    }
+    settings.iter_limit = n_iter;
+    settings.time_limit_sec = n_sec;
+    settings.node_limit = n_nodes;
+    settings.no_cycle = no_cycle;
+    settings.measure_runtime = measure_runtime;
+    settings.strategy = match extract {
+        "greedy" => ExtractionStrategy::Greedy,
+        "beam" => ExtractionStrategy::Beam { width: 4, max_rounds: 20 },
+        "ilp" => ExtractionStrategy::Ilp(Default::default()),
+        _ => return Err(PyValueError::new_err(format!("Unknown extraction strategy {}", extract))),
+    };
+
+    // Saturation takes a while, let other Python threads run meanwhile
+    let result = py.allow_threads(|| Optimizer::new(settings).add_graph(&expr).saturate().extract());
+    let stats = serde_json::to_string(&result.stats).unwrap();
+    Ok((to_text(&result.graph, None), stats))
+}
+
+#[pymodule]
+fn tensat(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(optimize, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,189 @@
+//! Reducing a failing input graph to a small reproducer (mode reduce)
+//!
+//! When a run crashes or the optimized graph does not match the original
+//! one, the failing model is often too large to triage. The reducer shrinks
+//! it while the failure still happens, by delta debugging over its layers:
+//!
+//! - the last layers are dropped, an input of the root becoming the root
+//! - layers in the middle are removed, their uses taking their input of the
+//!   same shape instead, so that the rest of the graph stays valid
+//!
+//! Layers are removed in chunks, halving the chunks when no chunk can be
+//! removed, down to single layers, until no layer can be removed (the
+//! reproducer is 1-minimal). Only valid graphs (see validate_graph) are
+//! tried. Whether a graph fails is up to the caller, e.g. running tensat on
+//! it in a subprocess, as mode reduce does.
+
+use crate::model::*;
+use crate::validate::{inferred_shapes, validate_graph};
+use egg::*;
+use std::collections::HashMap;
+
+/// Outcome of a reduction
+#[derive(Debug, Clone)]
+pub struct Reduction {
+    /// The smallest failing graph found
+    pub graph: RecExpr<Mdl>,
+    /// Number of graphs tried
+    pub num_tries: usize,
+}
+
+/// Gets the subgraph of the nodes that root depends on, with root as its root
+pub fn subgraph(expr: &RecExpr<Mdl>, root: Id) -> RecExpr<Mdl> {
+    rebuild(expr, root, &HashMap::new())
+}
+
+/// Gets the layers of a graph that can be removed: the ops (other than the
+/// root) with an input of the same shape as their output
+pub fn removable_layers(expr: &RecExpr<Mdl>) -> Vec<Id> {
+    let shapes = inferred_shapes(expr);
+    let nodes = expr.as_ref();
+    (0..nodes.len().saturating_sub(1))
+        .filter(|i| bypass_input(nodes, &shapes, *i).is_some())
+        .map(Id::from)
+        .collect()
+}
+
+/// Removes layers from a graph, their uses taking their input of the same
+/// shape instead
+///
+/// # Returns
+///
+/// The graph without the layers, or None if one of them is not removable
+/// (see removable_layers)
+pub fn remove_layers(expr: &RecExpr<Mdl>, layers: &[Id]) -> Option<RecExpr<Mdl>> {
+    let shapes = inferred_shapes(expr);
+    let nodes = expr.as_ref();
+    let root = nodes.len() - 1;
+    let mut replace = HashMap::new();
+    for layer in layers {
+        let i = usize::from(*layer);
+        if i == root {
+            return None;
+        }
+        replace.insert(*layer, bypass_input(nodes, &shapes, i)?);
+    }
+    Some(rebuild(expr, Id::from(root), &replace))
+}
+
+/// Reduces a failing graph to a smaller graph failing the same way
+///
+/// # Parameters
+///
+/// - `expr`: the graph, which fails
+/// - `fails`: whether a graph fails
+///
+/// # Returns
+///
+/// The smallest failing graph found, expr itself if no layer can be removed
+pub fn reduce<F: FnMut(&RecExpr<Mdl>) -> bool>(expr: &RecExpr<Mdl>, mut fails: F) -> Reduction {
+    let mut current = expr.clone();
+    let mut num_tries = 0;
+    let mut try_graph = |graph: &RecExpr<Mdl>| {
+        if validate_graph(graph).is_err() {
+            return false;
+        }
+        num_tries += 1;
+        fails(graph)
+    };
+
+    loop {
+        let mut changed = false;
+
+        // Drop the last layers
+        'truncate: loop {
+            let nodes = current.as_ref();
+            let root = &nodes[nodes.len() - 1];
+            for input in root.children() {
+                if is_op(&nodes[usize::from(*input)]) {
+                    let candidate = subgraph(&current, *input);
+                    if try_graph(&candidate) {
+                        current = candidate;
+                        changed = true;
+                        continue 'truncate;
+                    }
+                }
+            }
+            break;
+        }
+
+        // Remove the layers in the middle, in chunks
+        let mut chunk_size = (removable_layers(&current).len() / 2).max(1);
+        loop {
+            let layers = removable_layers(&current);
+            if layers.is_empty() {
+                break;
+            }
+            chunk_size = chunk_size.min(layers.len());
+            let removed = layers.chunks(chunk_size).find_map(|chunk| {
+                remove_layers(&current, chunk).filter(|candidate| try_graph(candidate))
+            });
+            match removed {
+                Some(candidate) => {
+                    current = candidate;
+                    changed = true;
+                }
+                None if chunk_size == 1 => break,
+                None => chunk_size /= 2,
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+    Reduction {
+        graph: current,
+        num_tries: num_tries,
+    }
+}
+
+/// If a node is an op, not a scalar, a name or an input or weight
+fn is_op(node: &Mdl) -> bool {
+    !matches!(node, Mdl::Num(_) | Mdl::Var(_) | Mdl::Input(_) | Mdl::Weight(_))
+}
+
+/// The input of node i of the same shape as its output, if it is an op
+fn bypass_input(nodes: &[Mdl], shapes: &[Option<Vec<i32>>], i: usize) -> Option<Id> {
+    if !is_op(&nodes[i]) {
+        return None;
+    }
+    let shape = shapes[i].as_ref()?;
+    nodes[i]
+        .children()
+        .iter()
+        .find(|c| shapes[usize::from(**c)].as_ref() == Some(shape))
+        .cloned()
+}
+
+/// Rebuilds the nodes root depends on, with the uses of a replaced node
+/// taking its replacement instead
+fn rebuild(expr: &RecExpr<Mdl>, root: Id, replace: &HashMap<Id, Id>) -> RecExpr<Mdl> {
+    let nodes = expr.as_ref();
+    let resolve = |mut id: Id| {
+        while let Some(r) = replace.get(&id) {
+            id = *r;
+        }
+        id
+    };
+    // Children come before their parents, so one pass from the root finds
+    // all nodes it depends on
+    let mut reachable = vec![false; nodes.len()];
+    reachable[usize::from(resolve(root))] = true;
+    for i in (0..nodes.len()).rev() {
+        if reachable[i] {
+            for c in nodes[i].children() {
+                reachable[usize::from(resolve(*c))] = true;
+            }
+        }
+    }
+    let mut new_ids = HashMap::new();
+    let mut rebuilt = RecExpr::default();
+    for (i, node) in nodes.iter().enumerate() {
+        if reachable[i] {
+            let new_node = node.clone().map_children(|c| new_ids[&resolve(c)]);
+            new_ids.insert(Id::from(i), rebuilt.add(new_node));
+        }
+    }
+    rebuilt
+}
@@ -0,0 +1,172 @@
+//! Restricting saturation to the regions of a model that dominate its runtime
+//!
+//! Given a runtime profile of the original model, the hot region is the set
+//! of layers accounting for most of the runtime, plus their neighbours. Rules
+//! are only applied to matches rooted in the hot region (and in eclasses
+//! created by rewriting it), so the node and time budgets are spent where the
+//! runtime is, and cheap regions keep their original ops.
+
+use crate::model::*;
+use egg::*;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// Number of hops around a hot layer that are also part of the hot region, so
+/// rules spanning several layers can match on the boundary
+pub const REGION_RADIUS: usize = 2;
+
+/// Reads a runtime profile from json
+///
+/// Accepts either an object mapping layer names to runtimes, or the output of
+/// the estimate mode (an object with a list "ops" of entries with "name" and
+/// "cost").
+///
+/// # Returns
+///
+/// Runtime of each layer by name, or an error if the format is not recognized
+pub fn parse_profile(s: &str) -> Result<HashMap<String, f32>, String> {
+    let value: Value = serde_json::from_str(s).map_err(|e| e.to_string())?;
+    let entries: Vec<(String, &Value)> = match value.get("ops").and_then(|ops| ops.as_array()) {
+        Some(ops) => ops
+            .iter()
+            .map(|op| match (op.get("name").and_then(|n| n.as_str()), op.get("cost")) {
+                (Some(name), Some(cost)) => Ok((name.to_string(), cost)),
+                _ => Err(format!("op entry {} has no name or cost", op)),
+            })
+            .collect::<Result<_, _>>()?,
+        None => match value.as_object() {
+            Some(map) => map.iter().map(|(name, cost)| (name.clone(), cost)).collect(),
+            None => return Err(String::from("profile is not a json object")),
+        },
+    };
+    entries
+        .into_iter()
+        .map(|(name, cost)| match cost.as_f64() {
+            Some(c) => Ok((name, c as f32)),
+            None => Err(format!("runtime of {} is not a number", name)),
+        })
+        .collect()
+}
+
+/// Eclasses where rules may be applied
+#[derive(Debug, Clone)]
+pub struct HotRegion {
+    /// Eclasses in the hot region, canonical as of the last refresh
+    classes: HashSet<Id>,
+    /// Eclasses with an Id at least this were created during saturation
+    id_bound: usize,
+}
+
+impl HotRegion {
+    /// Computes the hot region of a graph
+    ///
+    /// # Parameters
+    ///
+    /// - `egraph`: E-graph constructed from expr, before saturation
+    /// - `expr`: the original graph
+    /// - `names`: names of the layers in expr, as used in the profile
+    /// - `profile`: runtime of each layer by name. Layers not in the profile
+    ///     are considered to take no time
+    /// - `coverage`: fraction of the total runtime the hot layers account for
+    pub fn from_profile(
+        egraph: &EGraph<Mdl, TensorAnalysis>,
+        expr: &RecExpr<Mdl>,
+        names: &HashMap<Id, String>,
+        profile: &HashMap<String, f32>,
+        coverage: f32,
+    ) -> Self {
+        let nodes = expr.as_ref();
+        let mut layers: Vec<(usize, f32)> = (0..nodes.len())
+            .filter_map(|i| names.get(&Id::from(i)).and_then(|n| profile.get(n)).map(|t| (i, *t)))
+            .collect();
+        layers.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let total: f32 = layers.iter().map(|(_, t)| t).sum();
+
+        let mut hot: HashSet<usize> = HashSet::new();
+        let mut covered = 0.0;
+        for (i, t) in layers {
+            if covered >= coverage * total {
+                break;
+            }
+            covered += t;
+            hot.insert(i);
+        }
+
+        // Grow the region by REGION_RADIUS hops in both directions
+        let mut neighbours: Vec<Vec<usize>> = vec![vec![]; nodes.len()];
+        for (i, node) in nodes.iter().enumerate() {
+            node.for_each(|child| {
+                neighbours[i].push(usize::from(child));
+                neighbours[usize::from(child)].push(i);
+            });
+        }
+        let mut frontier: Vec<usize> = hot.iter().cloned().collect();
+        for _ in 0..REGION_RADIUS {
+            let mut next = vec![];
+            for i in frontier {
+                for j in neighbours[i].iter() {
+                    if hot.insert(*j) {
+                        next.push(*j);
+                    }
+                }
+            }
+            frontier = next;
+        }
+
+        let ids = crate::provenance::expr_eclasses(egraph, expr);
+        HotRegion {
+            classes: hot.iter().filter_map(|i| ids[*i]).collect(),
+            id_bound: egraph.classes().map(|c| usize::from(c.id) + 1).max().unwrap_or(0),
+        }
+    }
+
+    /// Number of eclasses in the hot region of the original graph
+    pub fn len(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// Canonicalizes the eclasses after they were merged during saturation
+    pub fn refresh(&mut self, egraph: &EGraph<Mdl, TensorAnalysis>) {
+        self.classes = self.classes.iter().map(|id| egraph.find(*id)).collect();
+    }
+
+    /// If rules may be applied to eclass. Needs an up to date refresh
+    pub fn contains(&self, egraph: &EGraph<Mdl, TensorAnalysis>, eclass: Id) -> bool {
+        usize::from(eclass) >= self.id_bound || self.classes.contains(&egraph.find(eclass))
+    }
+}
+
+/// Scheduler only applying rules to matches rooted in a hot region
+pub struct RegionScheduler {
+    region: HotRegion,
+    /// Iteration in which the region was last refreshed
+    last_refresh: Option<usize>,
+}
+
+impl RegionScheduler {
+    pub fn new(region: HotRegion) -> Self {
+        RegionScheduler {
+            region: region,
+            last_refresh: None,
+        }
+    }
+}
+
+impl RewriteScheduler<Mdl, TensorAnalysis> for RegionScheduler {
+    fn search_rewrite<'a>(
+        &mut self,
+        iteration: usize,
+        egraph: &EGraph<Mdl, TensorAnalysis>,
+        rewrite: &'a Rewrite<Mdl, TensorAnalysis>,
+    ) -> Vec<SearchMatches<'a, Mdl>> {
+        if self.last_refresh != Some(iteration) {
+            self.region.refresh(egraph);
+            self.last_refresh = Some(iteration);
+        }
+        rewrite
+            .search(egraph)
+            .into_iter()
+            .filter(|m| self.region.contains(egraph, m.eclass))
+            .collect()
+    }
+}
@@ -0,0 +1,94 @@
+//! Replaying the result of an optimization onto a new checkpoint
+//!
+//! The graphs only refer to weights by name and shape, so a newly trained
+//! checkpoint of the same architecture has the same graph, up to the names
+//! of its tensors. A record of a run keeps its starting and optimized graph,
+//! and replaying it onto the graph of the new checkpoint renames the tensors
+//! of the optimized graph, without saturating again.
+
+use crate::model::*;
+use crate::names::parse_tensor_name;
+use egg::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+/// The structural transformation found by a run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayRecord {
+    /// Starting graph, as an s-expression
+    pub start: String,
+    /// Optimized graph, as an s-expression
+    pub optimized: String,
+}
+
+impl ReplayRecord {
+    pub fn new(start: &RecExpr<Mdl>, optimized: &RecExpr<Mdl>) -> Self {
+        ReplayRecord {
+            start: start.to_string(),
+            optimized: optimized.to_string(),
+        }
+    }
+
+    pub fn save(&self, filename: &str) {
+        fs::write(filename, serde_json::to_string(self).unwrap()).expect("Unable to write file");
+    }
+
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let s = fs::read_to_string(filename).map_err(|e| format!("Could not read {}: {}", filename, e))?;
+        serde_json::from_str(&s).map_err(|e| format!("Could not parse {}: {}", filename, e))
+    }
+}
+
+/// Matches the names of two graphs of the same architecture, node by node
+///
+/// # Returns
+///
+/// The name in start of each tensor name of recorded, or where the graphs
+/// differ
+fn match_names(recorded: &RecExpr<Mdl>, start: &RecExpr<Mdl>) -> Result<HashMap<Symbol, Symbol>, String> {
+    let (old, new) = (recorded.as_ref(), start.as_ref());
+    if old.len() != new.len() {
+        return Err(format!("the graphs have {} and {} nodes", old.len(), new.len()));
+    }
+    let mut names = HashMap::new();
+    for (i, (a, b)) in old.iter().zip(new.iter()).enumerate() {
+        match (a, b) {
+            (Mdl::Var(s_a), Mdl::Var(s_b)) if s_a != s_b => {
+                // Tensors can be renamed, as long as their shape stays the same
+                let same_shape = match (parse_tensor_name(s_a.as_str()), parse_tensor_name(s_b.as_str())) {
+                    (Ok(t_a), Ok(t_b)) => t_a.dims == t_b.dims && t_a.density == t_b.density,
+                    _ => false,
+                };
+                if !same_shape {
+                    return Err(format!("node {}: {} is now {}", i, s_a, s_b));
+                }
+                names.insert(*s_a, *s_b);
+            }
+            _ if a != b => return Err(format!("node {}: {} is now {}", i, a, b)),
+            _ => (),
+        }
+    }
+    Ok(names)
+}
+
+/// Applies a recorded optimization to a graph of the same architecture
+///
+/// # Returns
+///
+/// The optimized graph with the names of start, or why the record does not
+/// apply to start
+pub fn replay(record: &ReplayRecord, start: &RecExpr<Mdl>) -> Result<RecExpr<Mdl>, String> {
+    let recorded: RecExpr<Mdl> = record.start.parse().map_err(|e| format!("{:?}", e))?;
+    let optimized: RecExpr<Mdl> = record.optimized.parse().map_err(|e| format!("{:?}", e))?;
+    let names = match_names(&recorded, start)?;
+    let nodes: Vec<Mdl> = optimized
+        .as_ref()
+        .iter()
+        .map(|node| match node {
+            Mdl::Var(s) => Mdl::Var(*names.get(s).unwrap_or(s)),
+            other => other.clone(),
+        })
+        .collect();
+    Ok(RecExpr::from(nodes))
+}
@@ -0,0 +1,651 @@
+//! Reports on a saturation and on an optimized graph
+//!
+//! Each report prints a summary and, where it has details, writes them to a
+//! file of the output directory, as the optimize command does for its flags
+//! (e.g. speedup_report for --speedup_report writes speedup_report.json).
+//! They take the saturated EGraph and the graphs, so that they can be
+//! called on an Optimizer too, with its egraph and start.
+
+use crate::attrs::unpack_expr;
+use crate::attribution::{pattern_speedups, speedup_regions};
+use crate::capped::{capped_warnings, record_stop, CappedRules};
+use crate::cost::RuntimeModel;
+use crate::device::DeviceProfile;
+use crate::dot::{egraph_dot, graph_dot};
+use crate::ensemble::compare_cost_models;
+use crate::extract::*;
+use crate::interp::{equivalence_errors, evaluate, max_relative_error};
+use crate::model::*;
+use crate::onnx::{export_onnx, ExecutionProvider};
+use crate::optimize::*;
+use crate::ort::ort_equivalence_errors;
+use crate::provenance::{default_layer_names, profiling_markers, provenance};
+use crate::schedule::RuleGrowth;
+use crate::shape::expr_shapes;
+use crate::specialize::{batch_size, check_batch_sizes, measure_at_batch};
+use crate::subprocess::run_solver;
+use crate::targets::{extract_for_targets, Target};
+use crate::text::to_text;
+use crate::utils::{save_measured_model, save_model};
+use crate::weights::{transformed_weights, weight_transforms};
+use egg::*;
+use serde_json::{json, Map, Value};
+use std::fs::{write, OpenOptions};
+use std::io::prelude::*;
+use std::path::Path;
+use std::process::Command;
+
+/// Appends a line to a file of the output directory, e.g. one JSON object
+/// per run. Failing to write is only reported
+pub fn append_line(output_dir: &str, file_name: &str, line: &str) {
+    let filename = Path::new(output_dir).join(file_name);
+    let mut file = OpenOptions::new().append(true).create(true).open(filename).unwrap();
+    if let Err(e) = writeln!(file, "{}", line) {
+        eprintln!("Couldn't write to file: {}", e);
+    }
+}
+
+/// This function gets the following stats:
+///     Total number of enodes
+///     Total number of eclasses
+///     Average number of enodes per class
+///     Total number of edges (children relationships)
+///     Total number of equivalent programs represented (power of 2)
+pub fn get_stats(egraph: &EGraph<Mdl, TensorAnalysis>) -> (usize, usize, f32, usize, f32) {
+    let num_enodes = egraph.total_size();
+    let num_classes = egraph.number_of_classes();
+    let avg_nodes_per_class = num_enodes as f32 / (num_classes as f32);
+    let num_edges = egraph
+        .classes()
+        .fold(0, |acc, c| c.iter().fold(0, |sum, n| n.len() + sum) + acc);
+    let num_programs = egraph
+        .classes()
+        .fold(0.0, |acc, c| acc + (c.len() as f32).log2());
+    (
+        num_enodes,
+        num_classes,
+        avg_nodes_per_class,
+        num_edges,
+        num_programs,
+    )
+}
+
+/// Prints the summary of a saturation, and appends its iterations to
+/// iteration_data.txt
+///
+/// # Parameters
+///
+/// - `runner`: the runner, after saturation
+/// - `saturation_secs`: how long the saturation took
+/// - `num_iterations`: the number of iterations it ran
+/// - `restored`: whether the EGraph was restored from a checkpoint, whose
+///   shape checks were not cached in this run
+/// - `output_dir`: where iteration_data.txt is
+pub fn saturation_report(
+    runner: &Runner<Mdl, TensorAnalysis, ()>,
+    saturation_secs: f32,
+    num_iterations: usize,
+    restored: bool,
+    output_dir: &str,
+) {
+    println!("Runner complete!");
+    println!("  Nodes: {}", runner.egraph.total_size());
+    println!("  Classes: {}", runner.egraph.number_of_classes());
+    println!("  Stopped: {:?}", runner.stop_reason.as_ref().unwrap());
+    println!("  Time taken: {:?}", std::time::Duration::from_secs_f32(saturation_secs));
+    println!("  Number of iterations: {:?}", num_iterations);
+    let condition_cache = &runner.egraph.analysis.condition_cache;
+    if condition_cache.enabled && !restored {
+        println!(
+            "  Shape checks cached: {} of {} ({:.1}%)",
+            condition_cache.num_hits,
+            condition_cache.num_hits + condition_cache.num_misses,
+            condition_cache.hit_rate() * 100.0
+        );
+    }
+    let (_, _, avg_nodes_per_class, num_edges, num_programs) = get_stats(&runner.egraph);
+    println!("  Average nodes per class: {}", avg_nodes_per_class);
+    println!("  Number of edges: {}", num_edges);
+    println!("  Number of programs: {}", num_programs);
+
+    let iteration_data = serde_json::to_string(&runner.iterations).expect("Failed to convert IterationData json to string");
+    append_line(output_dir, "iteration_data.txt", &iteration_data);
+}
+
+/// Reports the rules banned for growing the EGraph, to rule_growth.json
+pub fn rule_growth_report(rule_growth: &RuleGrowth, output_dir: &str) {
+    let growth = rule_growth.lock().unwrap();
+    if growth.is_empty() {
+        return;
+    }
+    let num_banned = growth.values().filter(|entry| entry.num_bans > 0).count();
+    println!("  Rules banned for growing the EGraph: {}", num_banned);
+    let filename = Path::new(output_dir).join("rule_growth.json");
+    write(filename, serde_json::to_string_pretty(&*growth).unwrap()).expect("Unable to write file");
+}
+
+/// Reports whether raising the limits could still find more: the rules that
+/// did not apply all their matches, to capped_rules.json
+pub fn capped_rules_report(capped_rules: &CappedRules, runner: &Runner<Mdl, TensorAnalysis, ()>, output_dir: &str) {
+    record_stop(capped_rules, runner);
+    let capped = capped_rules.lock().unwrap();
+    for warning in capped_warnings(&capped).iter() {
+        println!("Warning: {}", warning);
+    }
+    if capped.is_empty() {
+        println!("  Every rule applied all its matches");
+    } else {
+        println!("  {} rules did not apply all their matches, raising the limits could find a better graph", capped.len());
+        let filename = Path::new(output_dir).join("capped_rules.json");
+        write(filename, serde_json::to_string_pretty(&*capped).unwrap()).expect("Unable to write file");
+    }
+}
+
+/// Gets the statistics of a saturation for the out file, with the fields of
+/// the extraction zero
+pub fn run_data(runner: &Runner<Mdl, TensorAnalysis, ()>, saturation_secs: f32, num_iterations: usize) -> Map<String, Value> {
+    let (num_enodes, num_classes, avg_nodes_per_class, num_edges, num_programs) = get_stats(&runner.egraph);
+    // Stats to write: original runtime, optimized runtime, saturation time, extraction time,
+    // number of nodes, number of eclasses, number of possible programs
+    json!({
+        "runner_stop_reason": runner.stop_reason.as_ref().unwrap(),
+        "runner_time": saturation_secs,
+        "num_iterations": num_iterations,
+        "num_enodes": num_enodes,
+        "num_classes": num_classes,
+        "avg_nodes_per_class": avg_nodes_per_class,
+        "num_edges": num_edges,
+        "num_programs": num_programs,
+        "extraction_time": 0.0,
+        "original_runtime": 0.0,
+        "optimized_runtime": 0.0,
+    })
+    .as_object()
+    .unwrap()
+    .clone()
+}
+
+/// Reports how far an extracted graph is from the optimum of the ILP
+///
+/// # Parameters
+///
+/// - `best`: the extracted graph
+/// - `extract_mode`: how it was extracted
+/// - `secs`: how long its extraction took
+/// - `ilp`: the strategy of the ILP
+pub fn ilp_gap_report(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    best: &RecExpr<Mdl>,
+    cost_model: &CostModel,
+    extract_mode: &str,
+    secs: f32,
+    ilp: &ExtractionStrategy,
+) {
+    let (optimum, _, ilp_secs) = extract(egraph, root, cost_model, ilp);
+    let gap = cost_gap(egraph, best, &optimum, cost_model);
+    println!(
+        "Cost gap of {} extraction to ILP: {:.2}% ({}s vs {}s)",
+        extract_mode,
+        100.0 * gap,
+        secs,
+        ilp_secs
+    );
+}
+
+/// Reports the Pareto front of the objective, the op count and the memory
+/// (see pareto_front), to pareto_front.json
+pub fn pareto_front_report(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    cost_model: &mut CostModel,
+    strategy: &ExtractionStrategy,
+    packed_attrs: bool,
+    output_dir: &str,
+) {
+    let front = pareto_front(egraph, root, cost_model, strategy);
+    println!("Pareto front of {} graphs ({} extraction)", front.len(), strategy.name());
+    let entries: Vec<Value> = front
+        .iter()
+        .map(|p| {
+            let graph = if packed_attrs { unpack_expr(&p.expr) } else { p.expr.clone() };
+            json!({"weights": p.weights, "metrics": p.metrics, "graph": graph.to_string()})
+        })
+        .collect();
+    let filename = Path::new(output_dir).join("pareto_front.json");
+    write(filename, serde_json::to_string(&entries).unwrap()).expect("Unable to write file");
+}
+
+/// Extracts a graph for each target from the same EGraph (see
+/// extract_for_targets), and reports them to targets.json
+pub fn targets_report<F: Fn(Box<dyn RuntimeModel>) -> CostModel>(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    targets: &[Target],
+    cost_model: F,
+    strategy: &ExtractionStrategy,
+    packed_attrs: bool,
+    output_dir: &str,
+) {
+    let results = extract_for_targets(egraph, root, targets, cost_model, strategy, packed_attrs, Path::new(output_dir));
+    println!("Extracted for {} targets ({} extraction):", results.len(), strategy.name());
+    for result in results.iter() {
+        println!(
+            "  {}: cost {}, {} nodes, {:.2}s, written to {}",
+            result.target, result.cost, result.num_nodes, result.secs, result.file
+        );
+    }
+    let filename = Path::new(output_dir).join("targets.json");
+    write(filename, serde_json::to_string(&results).unwrap()).expect("Unable to write file");
+}
+
+/// Checks that approximate rules kept the outputs of the graph within the
+/// error budget
+///
+/// # Returns
+///
+/// Whether the optimized graph is within the budget, false if its error
+/// could not be evaluated
+pub fn approximation_report(start: &RecExpr<Mdl>, best: &RecExpr<Mdl>, budget: f32) -> bool {
+    let error = evaluate(start, 0).and_then(|ref_out| max_relative_error(&ref_out, &evaluate(best, 0)?));
+    match error {
+        Ok(err) if err <= budget => {
+            println!("Approximation error {} within budget {}", err, budget);
+            true
+        }
+        Ok(err) => {
+            println!("Approximation error {} exceeds budget {}, keeping the original graph", err, budget);
+            false
+        }
+        Err(e) => {
+            println!("Could not evaluate approximation error ({}), keeping the original graph", e);
+            false
+        }
+    }
+}
+
+/// Compares the outputs of the original and the optimized graph, on the host
+/// interpreter or (backend "ort") on ONNX Runtime
+///
+/// # Returns
+///
+/// The largest relative error, None if the graphs could not be compared
+pub fn equivalence_report(
+    start: &RecExpr<Mdl>,
+    best: &RecExpr<Mdl>,
+    packed_attrs: bool,
+    backend: &str,
+    output_dir: &str,
+) -> Option<f32> {
+    let errors = match backend {
+        "ort" => {
+            let start_out = if packed_attrs { unpack_expr(start) } else { start.clone() };
+            let best_out = if packed_attrs { unpack_expr(best) } else { best.clone() };
+            let runner_a = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&start_out);
+            let runner_b = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&best_out);
+            ort_equivalence_errors(
+                (&start_out, &expr_shapes(&runner_a.egraph, &start_out)),
+                (&best_out, &expr_shapes(&runner_b.egraph, &best_out)),
+                0,
+                Path::new(output_dir),
+            )
+        }
+        _ => equivalence_errors(start, best, 0),
+    };
+    match errors {
+        Ok((abs_err, rel_err)) => {
+            let evaluator = if backend == "ort" { "ONNX Runtime" } else { "the host interpreter" };
+            println!(
+                "Equivalence check on {}: max absolute error {}, max relative error {}",
+                evaluator, abs_err, rel_err
+            );
+            Some(rel_err)
+        }
+        Err(e) => {
+            println!("Warning: could not check equivalence: {}", e);
+            None
+        }
+    }
+}
+
+/// Writes the original and the optimized graph as pictures (start.svg,
+/// ext.svg) and DOT files (start.dot, optimized.dot), and the EGraph as a
+/// DOT file (egraph.dot)
+///
+/// # Parameters
+///
+/// - `runner_start`, `runner_ext`: runners on the original and the
+///   optimized graph, as for their runtimes
+/// - `svg`: whether to write the pictures
+/// - `dot`: whether to write the DOT files of the graphs
+/// - `egraph_dot_file`: whether to write the DOT file of the EGraph
+#[allow(clippy::too_many_arguments)]
+pub fn graph_files(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    cost_model: &CostModel,
+    start: &RecExpr<Mdl>,
+    best: &RecExpr<Mdl>,
+    runner_start: &Runner<Mdl, TensorAnalysis, ()>,
+    runner_ext: &Runner<Mdl, TensorAnalysis, ()>,
+    svg: bool,
+    dot: bool,
+    egraph_dot_file: bool,
+    output_dir: &str,
+) {
+    if svg {
+        let start_filename = Path::new(output_dir).join("start.svg");
+        runner_start.egraph.dot().to_svg(start_filename).unwrap();
+
+        let ext_filename = Path::new(output_dir).join("ext.svg");
+        runner_ext.egraph.dot().to_svg(ext_filename).unwrap();
+    }
+    if dot {
+        let filename = Path::new(output_dir).join("start.dot");
+        write(filename, graph_dot(start, Some(&expr_shapes(&runner_start.egraph, start)))).expect("Unable to write file");
+        let filename = Path::new(output_dir).join("optimized.dot");
+        write(filename, graph_dot(best, Some(&expr_shapes(&runner_ext.egraph, best)))).expect("Unable to write file");
+    }
+    if egraph_dot_file {
+        let filename = Path::new(output_dir).join("egraph.dot");
+        write(filename, egraph_dot(egraph, cost_model)).expect("Unable to write file");
+    }
+}
+
+/// Compares the measured runtime of the optimized graph to the sum of its op
+/// runtimes, and records their ratio in the device profile, written to
+/// device_profile.json
+///
+/// # Parameters
+///
+/// - `profile`: the device profile of the run
+/// - `runtime_source`: where the op runtimes come from
+/// - `estimate`: the sum of the op runtimes of the optimized graph
+/// - `measured`: its measured runtime
+/// - `threshold`: how far the ratio may be from 1 without a warning
+pub fn runtime_drift_report(
+    profile: &mut DeviceProfile,
+    runtime_source: &str,
+    estimate: f32,
+    measured: f32,
+    threshold: f32,
+    output_dir: &str,
+) {
+    if let Some(ratio) = profile.record_runtime(runtime_source, estimate, measured) {
+        println!("Sum of op runtimes: {}, measured / estimated: {:.3}", estimate, ratio);
+        if (ratio - 1.0).abs() > threshold {
+            println!(
+                "Warning: the measured runtime is {:.3}x the sum of the {} op runtimes, the cost model misses effects of the whole graph",
+                ratio, runtime_source
+            );
+        }
+        let filename = Path::new(output_dir).join("device_profile.json");
+        write(filename, serde_json::to_string_pretty(&profile).unwrap()).expect("Unable to write file");
+    }
+}
+
+/// Attributes the speedup of the optimized graph to the regions it rewrote
+/// (see attribution.rs), to speedup_report.json
+///
+/// # Parameters
+///
+/// - `measured_saving`: the measured end-to-end saving, in milliseconds
+pub fn speedup_report(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    start: &RecExpr<Mdl>,
+    best: &RecExpr<Mdl>,
+    cost_model: &CostModel,
+    measured_saving: f32,
+    output_dir: &str,
+) {
+    let layer_names = default_layer_names(start);
+    let replaced = provenance(egraph, start, &layer_names, best);
+    let regions = speedup_regions(
+        start,
+        &expr_op_runtimes(egraph, start, cost_model),
+        &layer_names,
+        best,
+        &expr_op_runtimes(egraph, best, cost_model),
+        &replaced,
+        Some(measured_saving),
+    );
+    let patterns = pattern_speedups(&regions);
+    println!("Speedup by pattern ({} rewritten regions):", regions.len());
+    println!("{:>10} {:>10} {:>8}  pattern", "estimated", "measured", "regions");
+    for pattern in patterns.iter().take(10) {
+        println!(
+            "{:>10.4} {:>10.4} {:>8}  {}",
+            pattern.estimated_saved_ms, pattern.measured_saved_ms, pattern.num_regions, pattern.pattern
+        );
+    }
+    let filename = Path::new(output_dir).join("speedup_report.json");
+    let report = json!({"regions": regions, "patterns": patterns});
+    write(filename, serde_json::to_string(&report).unwrap()).expect("Unable to write file");
+}
+
+/// Extracts with blends of latency and energy, to pareto.json
+///
+/// # Parameters
+///
+/// - `ignore_all_weight_only`: the setting of the cost models
+/// - `watts`: the board power of the GPU
+///
+/// # Returns
+///
+/// For each weight, the latency and energy of the extracted graph, and
+/// whether it is Pareto optimal among the extracted graphs
+pub fn pareto_report(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    ignore_all_weight_only: bool,
+    watts: f32,
+    output_dir: &str,
+) -> Vec<Value> {
+    let latency_model = CostModel::with_setting(ignore_all_weight_only);
+    let energy_model = CostModel::with_setting(ignore_all_weight_only).with_objective(Objective::Energy { watts: watts });
+    let total = |expr: &RecExpr<Mdl>, cost_model: &CostModel| -> f32 {
+        expr_op_costs(egraph, expr, cost_model).iter().filter_map(|c| *c).sum()
+    };
+
+    let points: Vec<(f32, f32, f32)> = [0.0, 0.25, 0.5, 0.75, 1.0]
+        .iter()
+        .map(|w| {
+            let cost_model = CostModel::with_setting(ignore_all_weight_only)
+                .with_objective(Objective::Blend { watts: watts, energy_weight: *w });
+            let tnsr_cost = TensorCost::new(egraph, &cost_model, true);
+            let (_, best) = Extractor::new(egraph, tnsr_cost).find_best(root);
+            (*w, total(&best, &latency_model), total(&best, &energy_model))
+        })
+        .collect();
+    let report: Vec<Value> = points
+        .iter()
+        .map(|(w, latency, energy)| {
+            let dominated = points
+                .iter()
+                .any(|(_, l, e)| l <= latency && e <= energy && (l < latency || e < energy));
+            json!({
+                "energy_weight": w,
+                "latency": latency,
+                "energy": energy,
+                "pareto_optimal": !dominated,
+            })
+        })
+        .collect();
+    let filename = Path::new(output_dir).join("pareto.json");
+    write(filename, serde_json::to_string(&report).unwrap()).expect("Unable to write file");
+    report
+}
+
+/// Checks that the optimized graph of a model with a symbolic batch is
+/// valid at other batch sizes, as the rewrites only saw the shapes at the
+/// batch value
+pub fn batch_validity_report(best: &RecExpr<Mdl>, batches: &[i32]) {
+    let invalid = check_batch_sizes(best, batches);
+    for (batch, e) in invalid.iter() {
+        println!("Warning: the optimized graph is not valid at batch size {}: {}", batch, e);
+    }
+    if invalid.is_empty() {
+        println!("The optimized graph is valid at batch sizes {:?}", batches);
+    }
+}
+
+/// Measures the speedup of the optimized graph at other batch sizes, to
+/// shape_report.json
+///
+/// # Parameters
+///
+/// - `faster`: whether the optimized graph is faster at the tuned batch
+///   size, to warn where it is slower
+pub fn shape_report(start: &RecExpr<Mdl>, best: &RecExpr<Mdl>, batches: &[i32], faster: bool, output_dir: &str) {
+    let tuned = batch_size(start).unwrap_or(0);
+    let mut points = vec![];
+    for batch in batches.iter() {
+        match measure_at_batch(start, best, *batch) {
+            Ok(point) => {
+                println!("Batch size {}: speedup {:.3}", batch, point.speedup);
+                if point.speedup < 1.0 && faster {
+                    println!(
+                        "Warning: the optimized graph is slower at batch size {} than the original, the rewrites only win at the tuned batch size {}",
+                        batch, tuned
+                    );
+                }
+                points.push(point);
+            }
+            Err(e) => println!("Warning: could not measure at batch size {}: {}", batch, e),
+        }
+    }
+    let filename = Path::new(output_dir).join("shape_report.json");
+    write(filename, serde_json::to_string(&json!({"tuned_batch": tuned, "points": points})).unwrap())
+        .expect("Unable to write file");
+}
+
+/// Compares the graphs the cost model of the run and another one extract
+/// (see compare_cost_models), to cost_model_comparison.json
+///
+/// # Parameters
+///
+/// - `other_name`: the runtime source of the other model
+pub fn cost_model_comparison_report(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    cost_model: &CostModel,
+    other_model: &CostModel,
+    other_name: &str,
+    output_dir: &str,
+) {
+    let report = compare_cost_models(egraph, root, cost_model, other_model);
+    println!("{:<24} {:>14} {:>14}", "", "cost (primary)", format!("cost ({})", other_name));
+    println!("{:<24} {:>14.4} {:>14.4}", "graph of primary model", report.graph_a[0], report.graph_a[1]);
+    println!("{:<24} {:>14.4} {:>14.4}", format!("graph of {} model", other_name), report.graph_b[0], report.graph_b[1]);
+    println!("The models pick different enodes in {} eclasses", report.disagreements.len());
+    let filename = Path::new(output_dir).join("cost_model_comparison.json");
+    write(filename, serde_json::to_string(&report).unwrap()).expect("Unable to write file");
+}
+
+/// How the optimized graph is exported to ONNX by export_models
+pub struct OnnxExport<'a> {
+    /// Script converting the measured TASO model, if any. Without one, the
+    /// optimized graph is serialized directly
+    pub script: Option<&'a str>,
+    pub provider: ExecutionProvider,
+    pub opset: i64,
+}
+
+/// Exports the original and the optimized graph for other tools: as TASO
+/// models (start.model, optimized.model, optimized.measured.model), the
+/// optimized one as ONNX (optimized.onnx), its transforms of the weights
+/// (weight_transforms.txt, weight_transforms.json) and the original layers
+/// each of its ops replaces (provenance.json)
+///
+/// # Parameters
+///
+/// - `runner_start`, `runner_ext`: runners on the original and the
+///   optimized graph, as for their runtimes
+/// - `packed_attrs`: whether the graphs have packed attributes
+/// - `onnx`: how to export to ONNX
+/// - `markers`: whether to write the profiling markers of both graphs
+#[allow(clippy::too_many_arguments)]
+pub fn export_models(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    start: &RecExpr<Mdl>,
+    best: &RecExpr<Mdl>,
+    runner_start: &Runner<Mdl, TensorAnalysis, ()>,
+    runner_ext: &Runner<Mdl, TensorAnalysis, ()>,
+    packed_attrs: bool,
+    onnx: &OnnxExport,
+    markers: bool,
+    output_dir: &str,
+) {
+    let filename_start = Path::new(output_dir).join("start.model");
+    save_model(runner_start, filename_start.to_str().unwrap());
+
+    let filename_optimized = Path::new(output_dir).join("optimized.model");
+    save_model(runner_ext, filename_optimized.to_str().unwrap());
+
+    // The graph TASO measured, with the ops on weights only precomputed
+    let filename_measured = Path::new(output_dir).join("optimized.measured.model");
+    save_measured_model(runner_ext, filename_measured.to_str().unwrap());
+    if let Some(script) = onnx.script {
+        let filename_onnx = Path::new(output_dir).join("optimized.onnx");
+        let status = run_solver(Command::new("python").arg(script).arg(&filename_measured).arg(&filename_onnx))
+            .unwrap_or_else(|e| panic!("failed to execute the ONNX conversion script: {}", e));
+        if status.success() {
+            println!("Exported the measured graph to {}", filename_onnx.display());
+        } else {
+            println!("The ONNX conversion script failed: {}", status);
+        }
+    } else {
+        // Without a conversion script, the optimized graph is
+        // serialized directly, with the ops on weights kept as ops
+        let out = if packed_attrs { unpack_expr(best) } else { best.clone() };
+        let runner_out = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&out);
+        let filename_onnx = Path::new(output_dir).join("optimized.onnx");
+        match export_onnx(&out, &expr_shapes(&runner_out.egraph, &out), onnx.provider, onnx.opset) {
+            Ok(bytes) => {
+                write(&filename_onnx, bytes).expect("Unable to write file");
+                println!("Exported the optimized graph to {}", filename_onnx.display());
+            }
+            Err(e) => println!("Warning: could not export the optimized graph to ONNX: {}", e),
+        }
+    }
+
+    // The ops on weights only, for runtimes loading trained weights
+    if let Some(transforms) = weight_transforms(best) {
+        let filename_program = Path::new(output_dir).join("weight_transforms.txt");
+        write(filename_program, to_text(&transforms.program, None)).expect("Unable to write file");
+        let filename_uses = Path::new(output_dir).join("weight_transforms.json");
+        let uses = transformed_weights(best, &transforms);
+        write(filename_uses, serde_json::to_string(&uses).unwrap()).expect("Unable to write file");
+        println!("Exported {} transformed weights", uses.len());
+    }
+
+    // Which original layers each op in the optimized model replaces
+    let layer_names = default_layer_names(start);
+    let replaced = provenance(egraph, start, &layer_names, best);
+    let entries: Vec<Value> = best
+        .as_ref()
+        .iter()
+        .zip(replaced.iter())
+        .enumerate()
+        .filter(|(_, (_, names))| !names.is_empty())
+        .map(|(i, (node, names))| json!({"node": i, "op": node.to_string(), "replaces": names}))
+        .collect();
+    let filename_provenance = Path::new(output_dir).join("provenance.json");
+    write(filename_provenance, serde_json::to_string(&entries).unwrap())
+        .expect("Unable to write file");
+
+    if markers {
+        // An op of the original model only replaces itself
+        let own_names: Vec<Vec<String>> = (0..start.as_ref().len())
+            .map(|i| layer_names.get(&Id::from(i)).into_iter().cloned().collect())
+            .collect();
+        let markers_start = profiling_markers(&runner_start.egraph, start, &own_names);
+        let filename_markers = Path::new(output_dir).join("start.markers.json");
+        write(filename_markers, serde_json::to_string(&markers_start).unwrap())
+            .expect("Unable to write file");
+
+        let markers_optimized = profiling_markers(&runner_ext.egraph, best, &replaced);
+        let filename_markers = Path::new(output_dir).join("optimized.markers.json");
+        write(filename_markers, serde_json::to_string(&markers_optimized).unwrap())
+            .expect("Unable to write file");
+    }
+}
@@ -0,0 +1,158 @@
+//! Resource usage of a run (--resource_summary)
+//!
+//! The peak resident memory of the process comes from /proc/self/status,
+//! the peak GPU memory from sampling nvidia-smi in the background. The
+//! counts of tensors, and the time spent creating ops (where TASO measures
+//! them) and running whole graphs on the GPU, are kept by the analysis and
+//! the FFI helpers in process-wide counters. The TASO graphs, and their
+//! tensors, are freed when the EGraph analyzed with them is dropped (see
+//! ffi::TasoGraph for who owns them).
+
+use serde::Serialize;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Graphs created on the TASO side, and how many of them were freed
+static TASO_GRAPHS: AtomicUsize = AtomicUsize::new(0);
+static TASO_GRAPHS_FREED: AtomicUsize = AtomicUsize::new(0);
+/// Tensors returned by TASO, allocated on the C++ side, and how many of
+/// them were freed
+static TASO_TENSORS: AtomicUsize = AtomicUsize::new(0);
+static TASO_TENSORS_FREED: AtomicUsize = AtomicUsize::new(0);
+/// Tensors created on the Rust side, and how many of them were freed
+static RUST_TENSORS: AtomicUsize = AtomicUsize::new(0);
+static RUST_TENSORS_FREED: AtomicUsize = AtomicUsize::new(0);
+/// Nanoseconds spent creating ops and running graphs on the TASO side
+static MEASURE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+pub fn count_taso_graph() {
+    TASO_GRAPHS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_taso_graph_freed() {
+    TASO_GRAPHS_FREED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_taso_tensor() {
+    TASO_TENSORS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_taso_tensors_freed(n: usize) {
+    TASO_TENSORS_FREED.fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn count_rust_tensor() {
+    RUST_TENSORS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_rust_tensors_freed(n: usize) {
+    RUST_TENSORS_FREED.fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn record_measure_time(time: Duration) {
+    MEASURE_NANOS.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Resource usage of the run so far
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResourceUsage {
+    /// Peak resident memory of the process, in MB
+    pub peak_rss_mb: Option<f32>,
+    /// Peak memory used on the GPU (by all processes), in MB
+    pub peak_gpu_memory_mb: Option<f32>,
+    pub taso_graphs: usize,
+    pub taso_graphs_freed: usize,
+    pub taso_tensors: usize,
+    pub taso_tensors_freed: usize,
+    pub rust_tensors: usize,
+    pub rust_tensors_freed: usize,
+    /// Seconds spent creating (and measuring) ops and running graphs
+    pub measure_secs: f32,
+}
+
+impl ResourceUsage {
+    pub fn collect(gpu_memory: Option<&GpuMemorySampler>) -> Self {
+        let peak_rss_kb = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| status_kb(&status, "VmHWM"));
+        ResourceUsage {
+            peak_rss_mb: peak_rss_kb.map(|kb| kb as f32 / 1024.0),
+            peak_gpu_memory_mb: gpu_memory.and_then(|sampler| sampler.peak_mb()),
+            taso_graphs: TASO_GRAPHS.load(Ordering::Relaxed),
+            taso_graphs_freed: TASO_GRAPHS_FREED.load(Ordering::Relaxed),
+            taso_tensors: TASO_TENSORS.load(Ordering::Relaxed),
+            taso_tensors_freed: TASO_TENSORS_FREED.load(Ordering::Relaxed),
+            rust_tensors: RUST_TENSORS.load(Ordering::Relaxed),
+            rust_tensors_freed: RUST_TENSORS_FREED.load(Ordering::Relaxed),
+            measure_secs: MEASURE_NANOS.load(Ordering::Relaxed) as f32 / 1e9,
+        }
+    }
+}
+
+/// Gets a field in kB of /proc/<pid>/status, e.g. `VmHWM:   123456 kB`
+pub fn status_kb(status: &str, field: &str) -> Option<u64> {
+    status
+        .lines()
+        .find(|line| line.starts_with(field) && line[field.len()..].starts_with(':'))
+        .and_then(|line| line[field.len() + 1..].trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+/// Samples the memory used on a GPU in the background, keeping the peak
+pub struct GpuMemorySampler {
+    child: Child,
+    peak_mb: Arc<Mutex<Option<f32>>>,
+}
+
+impl GpuMemorySampler {
+    /// Starts sampling the memory of GPU `gpu_id` every `period_ms`
+    ///
+    /// # Returns
+    ///
+    /// The sampler, or an error if nvidia-smi is not available
+    pub fn start(gpu_id: usize, period_ms: u64) -> Result<Self, String> {
+        let mut child = Command::new("nvidia-smi")
+            .arg("--query-gpu=memory.used")
+            .arg("--format=csv,noheader,nounits")
+            .arg(format!("--id={}", gpu_id))
+            .arg(format!("--loop-ms={}", period_ms))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("could not run nvidia-smi: {}", e))?;
+        let stdout = child.stdout.take().unwrap();
+        let peak_mb = Arc::new(Mutex::new(None));
+        let peak_writer = peak_mb.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line.map(|l| l.trim().parse::<f32>()) {
+                    Ok(Ok(mb)) => {
+                        let mut peak = peak_writer.lock().unwrap();
+                        *peak = Some(peak.map_or(mb, |p: f32| p.max(mb)));
+                    }
+                    Ok(Err(_)) => continue,
+                    Err(_) => break,
+                }
+            }
+        });
+        Ok(GpuMemorySampler {
+            child: child,
+            peak_mb: peak_mb,
+        })
+    }
+
+    pub fn peak_mb(&self) -> Option<f32> {
+        *self.peak_mb.lock().unwrap()
+    }
+}
+
+impl Drop for GpuMemorySampler {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -1,7 +1,15 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 
+use crate::attrs::unpack_attrs;
+use crate::custom::parse_custom_label;
 use crate::model::*;
+use crate::capped::CappedRules;
+use crate::freeze::is_frozen_class;
+use crate::region::HotRegion;
+use crate::rulecache::{is_cacheable, subst_signature};
+use crate::schedule::{Bans, GrowthLimits, RuleGrowth};
+use crate::shape::{broadcast_shape, ElemType};
 use egg::{rewrite as rw, *};
 use itertools::Itertools;
 use root::taso::*;
@@ -116,15 +124,198 @@ pub fn rules_from_str(rs: Vec<&str>, filter_after: bool) -> Vec<Rewrite<Mdl, Ten
         let lhs: Pattern<Mdl> = eqn[0].parse().unwrap();
         let rhs: Pattern<Mdl> = eqn[1].parse().unwrap();
         let rule_name = format!("rule{}", pos);
+        let numerics = rule_numerics(&lhs, &rhs);
         rule_vec.push(rw!(rule_name; { lhs.clone() } => { CheckApply {
             pat: rhs,
             src_pat: lhs,
             filter_after: filter_after,
+            numerics: numerics,
         } }));
     }
     rule_vec
 }
 
+/// Renames the variables of a rule to ?v0, ?v1, ... in order of first
+/// appearance, reading the sides in the given order
+fn canonical_rule(first: &str, second: &str) -> (String, String) {
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut rename = |side: &str| {
+        side.replace('(', " ( ")
+            .replace(')', " ) ")
+            .split_whitespace()
+            .map(|token| {
+                if token.starts_with('?') {
+                    let num_names = names.len();
+                    names.entry(token.to_string()).or_insert_with(|| format!("?v{}", num_names)).clone()
+                } else {
+                    token.to_string()
+                }
+            })
+            .join(" ")
+    };
+    let first = rename(first);
+    let second = rename(second);
+    (first, second)
+}
+
+/// Finds the pairs of rules that are the same rule in opposite directions
+/// (up to renaming variables), with both sides using the same variables
+///
+/// # Returns
+///
+/// The pairs of indices into rs, each rule in at most one pair
+pub fn mirror_pairs(rs: &[&str]) -> Vec<(usize, usize)> {
+    let sides: Vec<(&str, &str)> = rs
+        .iter()
+        .map(|rule| rule.split("=>").next_tuple().unwrap())
+        .collect();
+    let forward: Vec<(String, String)> = sides.iter().map(|(l, r)| canonical_rule(l, r)).collect();
+    let mut by_forward: HashMap<&(String, String), Vec<usize>> = HashMap::new();
+    for (i, canonical) in forward.iter().enumerate() {
+        by_forward.entry(canonical).or_default().push(i);
+    }
+    let mut paired = vec![false; rs.len()];
+    let mut pairs = vec![];
+    for (j, (l, r)) in sides.iter().enumerate() {
+        if paired[j] {
+            continue;
+        }
+        let lhs: Pattern<Mdl> = l.parse().unwrap();
+        let rhs: Pattern<Mdl> = r.parse().unwrap();
+        let lhs_vars: HashSet<Var> = lhs.vars().into_iter().collect();
+        let rhs_vars: HashSet<Var> = rhs.vars().into_iter().collect();
+        if lhs_vars != rhs_vars {
+            continue;
+        }
+        let mirror = canonical_rule(r, l);
+        if let Some(candidates) = by_forward.get(&mirror) {
+            if let Some(&i) = candidates.iter().find(|&&i| i != j && !paired[i]) {
+                paired[i] = true;
+                paired[j] = true;
+                pairs.push((j.min(i), j.max(i)));
+            }
+        }
+    }
+    pairs
+}
+
+/// Searcher of a bidirectional rule, matching both of its sides
+pub struct BidirectionalSearcher {
+    lhs: Pattern<Mdl>,
+    rhs: Pattern<Mdl>,
+}
+
+impl Searcher<Mdl, TensorAnalysis> for BidirectionalSearcher {
+    fn search_eclass_with_limit(
+        &self,
+        egraph: &EGraph<Mdl, TensorAnalysis>,
+        eclass: Id,
+        limit: usize,
+    ) -> Option<SearchMatches<Mdl>> {
+        self.lhs
+            .search_eclass_with_limit(egraph, eclass, limit)
+            .or_else(|| self.rhs.search_eclass_with_limit(egraph, eclass, limit))
+    }
+
+    fn search_with_limit(&self, egraph: &EGraph<Mdl, TensorAnalysis>, limit: usize) -> Vec<SearchMatches<Mdl>> {
+        let mut matches = self.lhs.search_with_limit(egraph, limit);
+        let num_found: usize = matches.iter().map(|m| m.substs.len()).sum();
+        if num_found < limit {
+            matches.extend(self.rhs.search_with_limit(egraph, limit - num_found));
+        }
+        matches
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        self.lhs.vars()
+    }
+}
+
+/// Applier of a bidirectional rule, constructing the side opposite to the
+/// side that matched
+pub struct BidirectionalApply {
+    forward: CheckApply,
+    backward: CheckApply,
+}
+
+impl Applier<Mdl, TensorAnalysis> for BidirectionalApply {
+    fn apply_one(
+        &self,
+        egraph: &mut EGraph<Mdl, TensorAnalysis>,
+        matched_id: Id,
+        subst: &Subst,
+        searcher_ast: Option<&PatternAst<Mdl>>,
+        rule_name: Symbol,
+    ) -> Vec<Id> {
+        match searcher_ast {
+            Some(ast) if *ast == self.backward.src_pat.ast => {
+                self.backward.apply_one(egraph, matched_id, subst, searcher_ast, rule_name)
+            }
+            _ => self.forward.apply_one(egraph, matched_id, subst, searcher_ast, rule_name),
+        }
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        self.forward.vars()
+    }
+}
+
+/// Same as rules_from_str, but each pair of rules that are the same rule in
+/// opposite directions (see mirror_pairs) becomes one bidirectional rule
+pub fn bidirectional_rules_from_str(rs: Vec<&str>, filter_after: bool) -> Vec<Rewrite<Mdl, TensorAnalysis>> {
+    let pairs = mirror_pairs(&rs);
+    let mut in_pair: HashMap<usize, usize> = HashMap::new();
+    for (i, j) in pairs.iter() {
+        in_pair.insert(*i, *j);
+        in_pair.insert(*j, *i);
+    }
+    let mut rule_vec = Vec::new();
+    for (pos, rule) in rs.iter().enumerate() {
+        let eqn: Vec<&str> = rule.split("=>").collect();
+        let lhs: Pattern<Mdl> = eqn[0].parse().unwrap();
+        let rhs: Pattern<Mdl> = eqn[1].parse().unwrap();
+        // Classifies both directions the same
+        let numerics = rule_numerics(&lhs, &rhs);
+        match in_pair.get(&pos) {
+            // The rule using the first index of a pair stands for both
+            Some(other) if *other < pos => continue,
+            Some(other) => {
+                let rule_name = format!("rule{}-{}", pos, other);
+                let searcher = BidirectionalSearcher {
+                    lhs: lhs.clone(),
+                    rhs: rhs.clone(),
+                };
+                let applier = BidirectionalApply {
+                    forward: CheckApply {
+                        pat: rhs.clone(),
+                        src_pat: lhs.clone(),
+                        filter_after: filter_after,
+                        numerics: numerics,
+                    },
+                    backward: CheckApply {
+                        pat: lhs,
+                        src_pat: rhs,
+                        filter_after: filter_after,
+                        numerics: numerics,
+                    },
+                };
+                rule_vec.push(Rewrite::new(rule_name, searcher, applier).unwrap());
+            }
+            None => {
+                let rule_name = format!("rule{}", pos);
+                rule_vec.push(rw!(rule_name; { lhs.clone() } => { CheckApply {
+                    pat: rhs,
+                    src_pat: lhs,
+                    filter_after: filter_after,
+                    numerics: numerics,
+                } }));
+            }
+        }
+    }
+    println!("Bidirectional rules: {} pairs of {} rules collapsed", pairs.len(), rs.len());
+    rule_vec
+}
+
 /// Hand specified normal rules from TASO
 #[rustfmt::skip]
 pub static PRE_DEFINED_RULES: &[&str] = &[
@@ -132,6 +323,31 @@ pub static PRE_DEFINED_RULES: &[&str] = &[
     "(conv2d 1 1 0 2 ?input_1 ?input_2)=>(conv2d 1 1 0 2 ?input_1 (merge ?input_2 2))",
     "(conv2d 2 2 0 0 ?input_1 ?input_2)=>(conv2d 2 2 0 0 ?input_1 (merge ?input_2 2))",
     "(conv2d 2 2 0 2 ?input_1 ?input_2)=>(conv2d 2 2 0 2 ?input_1 (merge ?input_2 2))",
+    // Fully connected layers with a bias, and the activation fused into the bias add
+    "(ewadd (matmul 0 ?input_1 ?input_2) ?input_3)=>(dense 0 ?input_1 ?input_2 ?input_3)",
+    "(relu (dense 0 ?input_1 ?input_2 ?input_3))=>(dense 2 ?input_1 ?input_2 ?input_3)",
+    "(tanh (dense 0 ?input_1 ?input_2 ?input_3))=>(dense 3 ?input_1 ?input_2 ?input_3)",
+    "(sigmoid (dense 0 ?input_1 ?input_2 ?input_3))=>(dense 1 ?input_1 ?input_2 ?input_3)",
+    // Inference only, dropout does nothing
+    "(dropout ?input_1)=>?input_1",
+    // Concats and splits of any number of tensors, one op instead of a chain
+    // of binary ones
+    "(concat ?axis ?ndim ?input_1 (concat ?axis ?ndim ?input_2 ?input_3))=>(concat_n ?axis ?ndim ?input_1 ?input_2 ?input_3)",
+    "(concat ?axis ?ndim (concat ?axis ?ndim ?input_1 ?input_2) ?input_3)=>(concat_n ?axis ?ndim ?input_1 ?input_2 ?input_3)",
+    "(concat3 ?axis ?ndim ?input_1 ?input_2 ?input_3)=>(concat_n ?axis ?ndim ?input_1 ?input_2 ?input_3)",
+    "(concat4 ?axis ?ndim ?input_1 ?input_2 ?input_3 ?input_4)=>(concat_n ?axis ?ndim ?input_1 ?input_2 ?input_3 ?input_4)",
+    "(concat5 ?axis ?ndim ?input_1 ?input_2 ?input_3 ?input_4 ?input_5)=>(concat_n ?axis ?ndim ?input_1 ?input_2 ?input_3 ?input_4 ?input_5)",
+    "(split_0 (split ?axis ?input_1))=>(split_i ?axis 2 0 ?input_1)",
+    "(split_1 (split ?axis ?input_1))=>(split_i ?axis 2 1 ?input_1)",
+    "(split_i ?axis 3 0 (concat_n ?axis ?ndim ?input_1 ?input_2 ?input_3))=>?input_1",
+    "(split_i ?axis 3 1 (concat_n ?axis ?ndim ?input_1 ?input_2 ?input_3))=>?input_2",
+    "(split_i ?axis 3 2 (concat_n ?axis ?ndim ?input_1 ?input_2 ?input_3))=>?input_3",
+    // Casts (see Mdl::Cast): casting twice to the same type, and a relu on
+    // either side of a cast, since rounding keeps the sign. Other casts
+    // round, so they are never removed
+    "(cast ?elem (cast ?elem ?input_1))=>(cast ?elem ?input_1)",
+    "(relu (cast ?elem ?input_1))=>(cast ?elem (relu ?input_1))",
+    "(cast ?elem (relu ?input_1))=>(relu (cast ?elem ?input_1))",
 ];
 
 /// Hand specified multi-pattern rules from TASO
@@ -141,8 +357,153 @@ pub static PRE_DEFINED_MULTI: &[&str] = &[
     "(conv2d 1 1 0 0 ?input_1 ?input_3)=>(split_1 (split 1 (conv2d 1 1 0 0 ?input_1 (concat 0 4 (enlarge ?input_2 ?input_3) ?input_3))))",
     "(conv2d 1 1 0 2 ?input_1 ?input_2)=>(split_0 (split 1 (conv2d 1 1 0 2 ?input_1 (concat 0 4 (enlarge ?input_2 ?input_3) ?input_3))))",
     "(conv2d 1 1 0 2 ?input_1 ?input_3)=>(split_1 (split 1 (conv2d 1 1 0 2 ?input_1 (concat 0 4 (enlarge ?input_2 ?input_3) ?input_3))))",
+    // Sparse kernels, only cheaper if the weight is sparse enough (see SPARSE_OVERHEAD)
+    "(matmul ?act ?input_1 ?input_2)=>(smatmul ?act ?input_1 ?input_2)",
+    "(conv2d ?stride_h ?stride_w ?pad ?act ?input_1 ?input_2)=>(sconv2d ?stride_h ?stride_w ?pad ?act ?input_1 ?input_2)",
+];
+
+/// Rules between the storage layouts of conv2d weights (see Mdl::Repack). A
+/// blocked kernel pays for repacking its weight, which is free when the
+/// weight is known ahead of time (see CostModel::ignore_all_weight_only)
+#[rustfmt::skip]
+pub static WEIGHT_LAYOUT_RULES: &[&str] = &[
+    "(conv2d ?stride_h ?stride_w ?pad ?act ?input_1 ?input_2)=>(conv2d_b ?stride_h ?stride_w ?pad ?act ?input_1 (repack 1 ?input_2))",
+    "(conv2d_b ?stride_h ?stride_w ?pad ?act ?input_1 ?input_2)=>(conv2d ?stride_h ?stride_w ?pad ?act ?input_1 (repack 0 ?input_2))",
+    "(repack 0 (repack 1 ?input_1))=>?input_1",
+    "(repack 1 (repack 0 ?input_1))=>?input_1",
 ];
 
+/// How a rewrite rule affects the numerical results of the graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Numerics {
+    /// The rewritten graph computes every value with the same sequence of
+    /// arithmetic, only batched or laid out differently
+    Exact,
+    /// The rewritten graph may round differently, e.g. when additions are
+    /// reassociated or weights are pre-combined. Not acceptable for quantized
+    /// models, where rounding happens at every requantization
+    Changing,
+}
+
+/// How far a rewrite rule can be trusted, from the safest tier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RuleTier {
+    /// Proven with the axioms of rules() (see verify::rule_tiers): both
+    /// sides have the same shape and, in exact arithmetic, the same values
+    Proven,
+    /// Not proven, but Numerics::Exact: both sides do the same arithmetic,
+    /// only batched or laid out differently
+    Exact,
+    /// Neither, e.g. learned rules the axioms can not prove, and the rules of
+    /// approx_rules
+    Approximate,
+}
+
+/// Which tiers of rules a run enables (--safety)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Safety {
+    /// Only proven rules
+    Strict,
+    /// Proven and exact rules
+    Normal,
+    /// All rules
+    Aggressive,
+}
+
+impl Safety {
+    /// If rules of the tier are enabled
+    pub fn allows(&self, tier: RuleTier) -> bool {
+        match self {
+            Safety::Strict => tier == RuleTier::Proven,
+            Safety::Normal => tier <= RuleTier::Exact,
+            Safety::Aggressive => true,
+        }
+    }
+}
+
+/// If the op does arithmetic on its inputs (as opposed to moving data around)
+fn is_arithmetic(node: &Mdl) -> bool {
+    match node {
+        Mdl::Ewadd(_)
+        | Mdl::Ewmul(_)
+        | Mdl::Smul(_)
+        | Mdl::Matmul(_)
+        | Mdl::Conv2d(_)
+        | Mdl::Smatmul(_)
+        | Mdl::Sconv2d(_)
+        | Mdl::Conv2dBlocked(_)
+        | Mdl::Conv2dPacked(_)
+        | Mdl::Sconv2dPacked(_)
+        | Mdl::Poolavg(_)
+        | Mdl::PoolavgPacked(_)
+        | Mdl::Dense(_)
+        | Mdl::Softmax(_)
+        | Mdl::BatchNorm(_) => true,
+        _ => false,
+    }
+}
+
+/// Name of the op, with the sparse, blocked and packed variants named like
+/// their dense op since they do the same arithmetic
+fn dense_name(node: &Mdl) -> String {
+    match node {
+        Mdl::Smatmul(_) => String::from("matmul"),
+        Mdl::Sconv2d(_) | Mdl::Conv2dBlocked(_) | Mdl::Conv2dPacked(_) | Mdl::Sconv2dPacked(_) => {
+            String::from("conv2d")
+        }
+        Mdl::PoolavgPacked(_) => String::from("poolavg"),
+        _ => node.to_string(),
+    }
+}
+
+/// Gets the maximum number of arithmetic ops on a path from the root of pat to
+/// a leaf, and adds the names of the arithmetic ops in pat to kinds
+fn arithmetic_depth(pat: &[ENodeOrVar<Mdl>], kinds: &mut HashSet<String>) -> usize {
+    match pat.last().unwrap() {
+        ENodeOrVar::Var(_) => 0,
+        ENodeOrVar::ENode(e) => {
+            let child_depth = e
+                .children()
+                .iter()
+                .map(|child| arithmetic_depth(&pat[..usize::from(*child) + 1], kinds))
+                .max()
+                .unwrap_or(0);
+            if is_arithmetic(e) {
+                kinds.insert(dense_name(e));
+                child_depth + 1
+            } else {
+                child_depth
+            }
+        }
+    }
+}
+
+/// Classifies a rule by its effect on numerics
+///
+/// A rule is considered exact if both sides apply the same kinds of arithmetic
+/// ops, never to the result of another arithmetic op. So batching two convs
+/// into one conv on concatenated weights is exact, while reassociation,
+/// distributivity and fusing ops into pre-combined weights are not.
+pub fn rule_numerics(lhs: &Pattern<Mdl>, rhs: &Pattern<Mdl>) -> Numerics {
+    let mut lhs_kinds = HashSet::new();
+    let mut rhs_kinds = HashSet::new();
+    let lhs_depth = arithmetic_depth(lhs.ast.as_ref(), &mut lhs_kinds);
+    let rhs_depth = arithmetic_depth(rhs.ast.as_ref(), &mut rhs_kinds);
+    if lhs_depth <= 1 && rhs_depth <= 1 && lhs_kinds == rhs_kinds {
+        Numerics::Exact
+    } else {
+        Numerics::Changing
+    }
+}
+
+/// Classifies a rule given in the format lhs=>rhs by its effect on numerics
+pub fn rule_str_numerics(rule: &str) -> Numerics {
+    let eqn: Vec<&str> = rule.split("=>").collect();
+    let lhs: Pattern<Mdl> = eqn[0].parse().unwrap();
+    let rhs: Pattern<Mdl> = eqn[1].parse().unwrap();
+    rule_numerics(&lhs, &rhs)
+}
+
 /// Struct for passing results in the recursive function check_pat
 ///
 /// Similar as ValTnsr for TensorAnalysis, but with tnsr being the object
@@ -183,6 +544,9 @@ pub struct CheckApply {
     pub src_pat: Pattern<Mdl>,
     /// Whether we need to check if any node in matched source graph is in blacklist
     pub filter_after: bool,
+    /// How the rule affects numerics, rules rounding differently are only
+    /// applied to fp32 tensors
+    pub numerics: Numerics,
 }
 
 impl Applier<Mdl, TensorAnalysis> for CheckApply {
@@ -196,6 +560,23 @@ impl Applier<Mdl, TensorAnalysis> for CheckApply {
         searcher_ast: Option<&PatternAst<Mdl>>,
         rule_name: Symbol,
     ) -> Vec<Id> {
+        // Reduced precision types round at every op, so rounding differently
+        // changes the results more than in fp32
+        if self.numerics == Numerics::Changing && egraph[matched_id].data.elem != ElemType::Float32 {
+            return vec![];
+        }
+        // Matches on pruned enodes do not grow their eclasses again, and
+        // matches on frozen enodes would replace them
+        if !egraph.analysis.pruned_nodes.is_empty() || !egraph.analysis.frozen_nodes.is_empty() {
+            let (_, matched) = existing_nodes(self.src_pat.ast.as_ref(), egraph, subst);
+            let analysis = &egraph.analysis;
+            if matched
+                .iter()
+                .any(|enode| analysis.pruned_nodes.contains(enode) || analysis.frozen_nodes.contains(enode))
+            {
+                return vec![];
+            }
+        }
         if self.filter_after {
             // Check if any node in matched source graph is in blacklist. If so, stop applying
             let (contains, _) = contains_blacklist(self.src_pat.ast.as_ref(), egraph, subst);
@@ -203,12 +584,32 @@ impl Applier<Mdl, TensorAnalysis> for CheckApply {
                 return vec![];
             }
         }
-        let (valid, _, _, existing) = check_pat(
-            self.pat.ast.as_ref(),
-            egraph,
-            subst,
-            /*get_exist_nodes=*/ self.filter_after,
-        );
+        let signature = if egraph.analysis.condition_cache.enabled && is_cacheable(&self.pat.ast) {
+            Some(subst_signature(egraph, &self.pat.vars(), subst))
+        } else {
+            None
+        };
+        let cached = match &signature {
+            Some(signature) => egraph.analysis.condition_cache.get(&self.pat.ast, signature),
+            None => None,
+        };
+        let (valid, existing) = match cached {
+            Some(false) => (false, None),
+            Some(true) if self.filter_after => (true, Some(existing_nodes(self.pat.ast.as_ref(), egraph, subst).1)),
+            Some(true) => (true, None),
+            None => {
+                let (valid, _, _, existing) = check_pat(
+                    self.pat.ast.as_ref(),
+                    egraph,
+                    subst,
+                    /*get_exist_nodes=*/ self.filter_after,
+                );
+                if let Some(signature) = signature {
+                    egraph.analysis.condition_cache.insert(&self.pat.ast, signature, valid);
+                }
+                (valid, existing)
+            }
+        };
         if valid {
             let result = self.pat.apply_one(egraph, matched_id, subst, searcher_ast, rule_name);
 
@@ -289,6 +690,41 @@ fn contains_blacklist(
     }
 }
 
+/// Gets the nodes of a pattern that are already in the EGraph, as check_pat
+/// does with get_exist_nodes, without checking the new nodes
+///
+/// # Returns
+///
+/// The Id of the eclass of the root of the pattern if it is in the EGraph,
+/// and the existing nodes
+fn existing_nodes(
+    pat: &[ENodeOrVar<Mdl>],
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    subst: &Subst,
+) -> (Option<Id>, HashSet<Mdl>) {
+    match pat.last().unwrap() {
+        ENodeOrVar::Var(w) => (Some(subst[*w]), HashSet::new()),
+        ENodeOrVar::ENode(e) => {
+            let mut existing = HashSet::new();
+            let mut new_e = e.clone();
+            let mut all_in = true;
+            for (i, child) in e.children().iter().enumerate() {
+                let (id, nodes) = existing_nodes(&pat[..usize::from(*child) + 1], egraph, subst);
+                existing.extend(nodes);
+                match id {
+                    Some(id) => new_e.children_mut()[i] = id,
+                    None => all_in = false,
+                }
+            }
+            let looked = if all_in { egraph.lookup(new_e.clone()) } else { None };
+            if looked.is_some() {
+                existing.insert(new_e);
+            }
+            (looked, existing)
+        }
+    }
+}
+
 /// Check if all the new nodes to create in the pattern is valid.
 ///
 /// This function does the checking recursively.
@@ -322,11 +758,16 @@ fn check_pat(
         ENodeOrVar::Var(w) => {
             // The root node is a variable, then use subst to get metadata from egraph
             let cid = subst[*w];
+            if egraph[cid].data.infeasible {
+                // No tensor to build new nodes on
+                let default_data: TData = Default::default();
+                return (false, None, default_data, None);
+            }
             let t_data = if egraph[cid].data.dtype == DataKind::Tnsr {
                 TData {
                     dtype: egraph[cid].data.dtype,
                     val: egraph[cid].data.val,
-                    tnsr: unsafe { Some((*egraph[cid].data.meta).clone()) },
+                    tnsr: unsafe { Some((*egraph[cid].data.meta.ptr()).clone()) },
                     tnsr_2: None,
                 }
             } else {
@@ -390,19 +831,23 @@ fn check_pat(
                     }
                     let looked = egraph.lookup(new_e.clone());
                     if let Some(id) = looked {
+                        if egraph[id].data.infeasible {
+                            let default_data: TData = Default::default();
+                            return (false, None, default_data, None);
+                        }
                         // Get metadata from egraph
                         let t_data = match egraph[id].data.dtype {
                             DataKind::Tnsr => TData {
                                 dtype: egraph[id].data.dtype,
                                 val: egraph[id].data.val,
-                                tnsr: unsafe { Some((*egraph[id].data.meta).clone()) },
+                                tnsr: unsafe { Some((*egraph[id].data.meta.ptr()).clone()) },
                                 tnsr_2: None,
                             },
                             DataKind::TnsrTuple => TData {
                                 dtype: egraph[id].data.dtype,
                                 val: egraph[id].data.val,
-                                tnsr: unsafe { Some((*egraph[id].data.meta).clone()) },
-                                tnsr_2: unsafe { Some((*egraph[id].data.meta_2).clone()) },
+                                tnsr: unsafe { Some((*egraph[id].data.meta.ptr()).clone()) },
+                                tnsr_2: unsafe { Some((*egraph[id].data.meta_2.ptr()).clone()) },
                             },
                             _ => TData {
                                 dtype: egraph[id].data.dtype,
@@ -509,7 +954,9 @@ fn check_pat(
                         }
                     }
 
-                    Mdl::Conv2d([_stride_h, _stride_w, _pad, _act, _inpt, _wght]) => {
+                    Mdl::Conv2d([_stride_h, _stride_w, _pad, _act, _inpt, _wght])
+                    | Mdl::Sconv2d([_stride_h, _stride_w, _pad, _act, _inpt, _wght])
+                    | Mdl::Conv2dBlocked([_stride_h, _stride_w, _pad, _act, _inpt, _wght]) => {
                         // Check types
                         let _stride_h_data = &results[0].2;
                         let _stride_w_data = &results[1].2;
@@ -553,6 +1000,43 @@ fn check_pat(
                         }
                     }
 
+                    Mdl::Conv2dPacked([_attrs, _inpt, _wght]) | Mdl::Sconv2dPacked([_attrs, _inpt, _wght]) => {
+                        // Check types
+                        let _attrs_data = &results[0].2;
+                        let _inpt_data = &results[1].2;
+                        let _wght_data = &results[2].2;
+                        assert!(_attrs_data.dtype == DataKind::Scalar);
+                        assert!(_inpt_data.dtype == DataKind::Tnsr);
+                        assert!(_wght_data.dtype == DataKind::Tnsr);
+
+                        // Get arguments
+                        let t_inpt = _inpt_data.tnsr.unwrap();
+                        let t_wght = _wght_data.tnsr.unwrap();
+                        let (strides, pad, act) = unpack_attrs(_attrs_data.val, 2);
+                        let padding: PaddingMode = pad.try_into().unwrap();
+                        let activation: ActiMode = act.try_into().unwrap();
+
+                        // Try creating op
+                        unsafe {
+                            let op = (*g.model).get_or_create_conv2d(
+                                t_inpt, t_wght, strides[0], strides[1], padding, activation,
+                            );
+                            if op == Op_INVALID_OP {
+                                let default_data: TData = Default::default();
+                                (false, None, default_data)
+                            } else {
+                                let t = (*op.ptr).outputs[0].clone();
+                                let t_data = TData {
+                                    dtype: DataKind::Tnsr,
+                                    val: 0,
+                                    tnsr: Some(t),
+                                    tnsr_2: None,
+                                };
+                                (true, None, t_data)
+                            }
+                        }
+                    }
+
                     Mdl::Ewadd([_a, _b]) => {
                         // Check types
                         let _a_data = &results[0].2;
@@ -564,9 +1048,14 @@ fn check_pat(
                         let t_a = _a_data.tnsr.unwrap();
                         let t_b = _b_data.tnsr.unwrap();
 
-                        // Try creating op
+                        // Try creating op, if the inputs broadcast together
                         unsafe {
-                            let op = (*g.model).get_or_create_element(OpType_OP_EW_ADD, &t_a, &t_b);
+                            let broadcasts = broadcast_shape(&t_a.dim[..t_a.numDim as usize], &t_b.dim[..t_b.numDim as usize]).is_some();
+                            let op = if broadcasts {
+                                (*g.model).get_or_create_element(OpType_OP_EW_ADD, &t_a, &t_b)
+                            } else {
+                                Op_INVALID_OP
+                            };
                             if op == Op_INVALID_OP {
                                 let default_data: TData = Default::default();
                                 (false, None, default_data)
@@ -594,9 +1083,14 @@ fn check_pat(
                         let t_a = _a_data.tnsr.unwrap();
                         let t_b = _b_data.tnsr.unwrap();
 
-                        // Try creating op
+                        // Try creating op, if the inputs broadcast together
                         unsafe {
-                            let op = (*g.model).get_or_create_element(OpType_OP_EW_MUL, &t_a, &t_b);
+                            let broadcasts = broadcast_shape(&t_a.dim[..t_a.numDim as usize], &t_b.dim[..t_b.numDim as usize]).is_some();
+                            let op = if broadcasts {
+                                (*g.model).get_or_create_element(OpType_OP_EW_MUL, &t_a, &t_b)
+                            } else {
+                                Op_INVALID_OP
+                            };
                             if op == Op_INVALID_OP {
                                 let default_data: TData = Default::default();
                                 (false, None, default_data)
@@ -613,7 +1107,7 @@ fn check_pat(
                         }
                     }
 
-                    Mdl::Matmul([_act, _a, _b]) => {
+                    Mdl::Matmul([_act, _a, _b]) | Mdl::Smatmul([_act, _a, _b]) => {
                         // Check types
                         let _act_data = &results[0].2;
                         let _a_data = &results[1].2;
@@ -646,6 +1140,82 @@ fn check_pat(
                         }
                     }
 
+                    Mdl::Dense([_act, _a, _b, _bias]) => {
+                        // Check types
+                        let _act_data = &results[0].2;
+                        let _a_data = &results[1].2;
+                        let _b_data = &results[2].2;
+                        let _bias_data = &results[3].2;
+                        assert!(_act_data.dtype == DataKind::Scalar);
+                        assert!(_a_data.dtype == DataKind::Tnsr);
+                        assert!(_b_data.dtype == DataKind::Tnsr);
+                        assert!(_bias_data.dtype == DataKind::Tnsr);
+
+                        // Get arguments
+                        let t_a = _a_data.tnsr.unwrap();
+                        let t_b = _b_data.tnsr.unwrap();
+                        let t_bias = _bias_data.tnsr.unwrap();
+                        let valid_act = [ACTNONE, ACTSIGMOID, ACTRELU, ACTTANH].contains(&_act_data.val);
+
+                        // Try creating the matmul and the bias add, the
+                        // activation keeps the shape
+                        unsafe {
+                            let mm = (*g.model).get_or_create_matmul(t_a, t_b, ACTNONE.try_into().unwrap());
+                            let op = if mm == Op_INVALID_OP || !valid_act {
+                                Op_INVALID_OP
+                            } else {
+                                let t_mm = (*mm.ptr).outputs[0].clone();
+                                let broadcasts = broadcast_shape(&t_mm.dim[..t_mm.numDim as usize], &t_bias.dim[..t_bias.numDim as usize]).is_some();
+                                if broadcasts {
+                                    (*g.model).get_or_create_element(OpType_OP_EW_ADD, &t_mm, &t_bias)
+                                } else {
+                                    Op_INVALID_OP
+                                }
+                            };
+                            if op == Op_INVALID_OP {
+                                let default_data: TData = Default::default();
+                                (false, None, default_data)
+                            } else {
+                                let t = (*op.ptr).outputs[0].clone();
+                                let t_data = TData {
+                                    dtype: DataKind::Tnsr,
+                                    val: 0,
+                                    tnsr: Some(t),
+                                    tnsr_2: None,
+                                };
+                                (true, None, t_data)
+                            }
+                        }
+                    }
+
+                    Mdl::Softmax([_inpt, _axis]) => {
+                        // Check types
+                        let _inpt_data = &results[0].2;
+                        let _axis_data = &results[1].2;
+                        assert!(_inpt_data.dtype == DataKind::Tnsr);
+                        assert!(_axis_data.dtype == DataKind::Scalar);
+
+                        // Same tensor as its input, created like an input as
+                        // in the analysis
+                        let t_inpt = _inpt_data.tnsr.unwrap();
+                        if _axis_data.val < 0 || _axis_data.val >= t_inpt.numDim {
+                            let default_data: TData = Default::default();
+                            (false, None, default_data)
+                        } else {
+                            let mut dims = t_inpt.dim[..t_inpt.numDim as usize].to_vec();
+                            let ndim = dims.len();
+                            let ptr = dims.as_mut_ptr();
+                            let t = unsafe { (*g.new_input(ndim.try_into().unwrap(), ptr)).clone() };
+                            let t_data = TData {
+                                dtype: DataKind::Tnsr,
+                                val: 0,
+                                tnsr: Some(t),
+                                tnsr_2: None,
+                            };
+                            (true, None, t_data)
+                        }
+                    }
+
                     Mdl::Concat([_axis, _ndim, _a, _b]) => {
                         // Check types
                         let _axis_data = &results[0].2;
@@ -669,12 +1239,8 @@ fn check_pat(
                             let default_data: TData = Default::default();
                             (false, None, default_data)
                         } else {
-                            // Pass ownership to C++
                             let mut inputs = vec![t_a, t_b];
-                            inputs.shrink_to_fit();
-                            assert!(inputs.len() == inputs.capacity());
                             let ptr = inputs.as_mut_ptr();
-                            std::mem::forget(inputs);
 
                             let mut need_copy = [false, false];
                             unsafe {
@@ -702,6 +1268,55 @@ fn check_pat(
                         }
                     }
 
+                    Mdl::ConcatN(_children) => {
+                        // Check types
+                        let _axis_data = &results[0].2;
+                        let _ndim_data = &results[1].2;
+                        assert!(_axis_data.dtype == DataKind::Scalar);
+                        assert!(_ndim_data.dtype == DataKind::Scalar);
+                        assert!(results[2..].iter().all(|res| res.2.dtype == DataKind::Tnsr));
+
+                        // Get arguments
+                        let inputs: Vec<_> = results[2..].iter().map(|res| res.2.tnsr.unwrap()).collect();
+                        let axis = _axis_data.val;
+                        let ndim = _ndim_data.val;
+                        let n = inputs.len();
+
+                        // Try creating op
+                        // Check tensor ndim
+                        if inputs.iter().any(|t| t.numDim != ndim) {
+                            let default_data: TData = Default::default();
+                            (false, None, default_data)
+                        } else {
+                            let mut inputs = inputs;
+                            let ptr = inputs.as_mut_ptr();
+
+                            let mut need_copy = vec![false; n];
+                            unsafe {
+                                let op = (*g.model).get_or_create_concat(
+                                    axis,
+                                    n as i32,
+                                    ptr,
+                                    need_copy.as_mut_ptr(),
+                                );
+
+                                if op == Op_INVALID_OP {
+                                    let default_data: TData = Default::default();
+                                    (false, None, default_data)
+                                } else {
+                                    let t = (*op.ptr).outputs[0].clone();
+                                    let t_data = TData {
+                                        dtype: DataKind::Tnsr,
+                                        val: 0,
+                                        tnsr: Some(t),
+                                        tnsr_2: None,
+                                    };
+                                    (true, None, t_data)
+                                }
+                            }
+                        }
+                    }
+
                     Mdl::Merge([_weight, _count]) => {
                         // Check types
                         let _weight_data = &results[0].2;
@@ -763,6 +1378,47 @@ fn check_pat(
                         }
                     }
 
+                    Mdl::SplitI([_axis, _count, _index, _inpt]) => {
+                        // Check types
+                        let _axis_data = &results[0].2;
+                        let _count_data = &results[1].2;
+                        let _index_data = &results[2].2;
+                        let _inpt_data = &results[3].2;
+                        assert!(_axis_data.dtype == DataKind::Scalar);
+                        assert!(_count_data.dtype == DataKind::Scalar);
+                        assert!(_index_data.dtype == DataKind::Scalar);
+                        assert!(_inpt_data.dtype == DataKind::Tnsr);
+
+                        // Get arguments
+                        let t_inpt = _inpt_data.tnsr.unwrap();
+                        let axis = _axis_data.val;
+                        let count = _count_data.val;
+                        let index = _index_data.val;
+
+                        // Try creating op
+                        if count < 2 || index < 0 || index >= count {
+                            let default_data: TData = Default::default();
+                            (false, None, default_data)
+                        } else {
+                            unsafe {
+                                let op = (*g.model).get_or_create_split1(&t_inpt, axis, count);
+                                if op == Op_INVALID_OP {
+                                    let default_data: TData = Default::default();
+                                    (false, None, default_data)
+                                } else {
+                                    let t = (*op.ptr).outputs[index as usize].clone();
+                                    let t_data = TData {
+                                        dtype: DataKind::Tnsr,
+                                        val: 0,
+                                        tnsr: Some(t),
+                                        tnsr_2: None,
+                                    };
+                                    (true, None, t_data)
+                                }
+                            }
+                        }
+                    }
+
                     Mdl::Split0(_inpt) => {
                         // Check types
                         let _inpt_data = &results[0].2;
@@ -823,6 +1479,54 @@ fn check_pat(
                         }
                     }
 
+                    Mdl::Repack([_layout, _inpt]) | Mdl::Cast([_layout, _inpt]) => {
+                        // Same tensor as its input, see TensorAnalysis::make
+                        let _inpt_data = &results[1].2;
+                        assert!(results[0].2.dtype == DataKind::Scalar);
+                        assert!(_inpt_data.dtype == DataKind::Tnsr);
+                        let t_data = TData {
+                            dtype: DataKind::Tnsr,
+                            val: 0,
+                            tnsr: _inpt_data.tnsr,
+                            tnsr_2: None,
+                        };
+                        (true, None, t_data)
+                    }
+
+                    Mdl::Custom(_children) => {
+                        // The label is a name in the EGraph, the other children are tensors
+                        let label = results[0].1.map(|id| egraph[id].data.name);
+                        let inputs: Option<Vec<Vec<i32>>> = results[1..]
+                            .iter()
+                            .map(|res| res.2.tnsr.map(|t| t.dim[..t.numDim as usize].to_vec()))
+                            .collect();
+                        let dims = match (label.and_then(|l| parse_custom_label(l.as_str())), inputs) {
+                            (Some((custom, attrs)), Some(inputs)) if inputs.len() == custom.arity => {
+                                (custom.shape)(&inputs, &attrs).ok()
+                            }
+                            _ => None,
+                        };
+                        match dims {
+                            Some(mut dims) => {
+                                // Created like an input, as in the analysis
+                                let ndim = dims.len();
+                                let ptr = dims.as_mut_ptr();
+                                let t = unsafe { (*g.new_input(ndim.try_into().unwrap(), ptr)).clone() };
+                                let t_data = TData {
+                                    dtype: DataKind::Tnsr,
+                                    val: 0,
+                                    tnsr: Some(t),
+                                    tnsr_2: None,
+                                };
+                                (true, None, t_data)
+                            }
+                            None => {
+                                let default_data: TData = Default::default();
+                                (false, None, default_data)
+                            }
+                        }
+                    }
+
                     other => {
                         println!("{:?}", other);
                         todo!()
@@ -879,6 +1583,26 @@ pub struct MultiPatterns {
     descendents: Option<HashMap<Id, HashSet<Id>>>,
     /// Output directory. If non empty, will be used to save the number of multi-pattern rewrite rule applications. If empty, no effect.
     output_dir: String,
+    /// If set, rules are only applied to matches in this region
+    pub region: Option<HotRegion>,
+    /// Whether to run one round at the start of each of the first iter_limit
+    /// iterations, or all iter_limit rounds back to back before the first
+    /// iteration of single-pattern rules
+    interleave: bool,
+    /// Maximum number of nodes added in one round
+    round_node_limit: usize,
+    /// First iteration the rounds run at, so that the single-pattern rules
+    /// run alone before
+    start_iteration: usize,
+    /// If set, caps the applications of each rule in a round (see
+    /// schedule.rs), recording the growth of each rule
+    growth_limits: Option<(GrowthLimits, RuleGrowth)>,
+    bans: Bans,
+    /// Number of rounds run so far
+    num_rounds: usize,
+    /// If set, the rules stopped at their cap or the limits of a round are
+    /// recorded in it (see capped.rs)
+    capped: Option<CappedRules>,
 }
 
 impl MultiPatterns {
@@ -961,9 +1685,51 @@ impl MultiPatterns {
             num_applied: 0,
             n_sec: n_sec,
             output_dir: output_dir,
+            region: None,
+            interleave: true,
+            round_node_limit: usize::MAX,
+            start_iteration: 0,
+            growth_limits: None,
+            bans: Bans::default(),
+            num_rounds: 0,
+            capped: None,
         }
     }
 
+    /// Sets whether the rounds interleave with the iterations of single-pattern
+    /// rules (the default), or all run before the first iteration
+    pub fn with_interleave(mut self, interleave: bool) -> Self {
+        self.interleave = interleave;
+        self
+    }
+
+    /// Sets the maximum number of nodes added in one round, on top of the
+    /// total node_limit
+    pub fn with_round_node_limit(mut self, round_node_limit: usize) -> Self {
+        self.round_node_limit = round_node_limit;
+        self
+    }
+
+    /// Sets the first iteration the rounds run at, 0 by default
+    pub fn with_start_iteration(mut self, start_iteration: usize) -> Self {
+        self.start_iteration = start_iteration;
+        self
+    }
+
+    /// Caps the applications of each rule in a round, banning the rules over
+    /// their cap (see schedule.rs). The growth of each rule is added to growth
+    pub fn with_growth_limits(mut self, limits: GrowthLimits, growth: RuleGrowth) -> Self {
+        self.growth_limits = Some((limits, growth));
+        self
+    }
+
+    /// Records the rules stopped at their cap or the limits of a round in
+    /// capped
+    pub fn with_capped_rules(mut self, capped: CappedRules) -> Self {
+        self.capped = Some(capped);
+        self
+    }
+
     /// Search and apply all multi-pattern rules for one iteration
     ///
     /// This function is used as hook function to egg::Runner. It first searches for matches
@@ -979,8 +1745,17 @@ impl MultiPatterns {
             remove_cycle_by_order(runner);
         }
 
-        if runner.iterations.len() < self.iter_limit && self.node_limit > 0 && self.n_sec > 0 {
+        let num_rounds = match (self.interleave, runner.iterations.len().checked_sub(self.start_iteration)) {
+            (true, Some(n)) if n < self.iter_limit => 1,
+            (false, Some(0)) => self.iter_limit,
+            _ => 0,
+        };
+        for _ in 0..num_rounds {
+            if self.node_limit == 0 || self.n_sec == 0 {
+                break;
+            }
             // println!("Run one");
+            let node_limit = self.node_limit.min(self.round_node_limit);
             let starting_num_nodes = runner.egraph.analysis.newly_added.len();
             let start_time = Instant::now();
             let num_applied = 0;
@@ -991,6 +1766,21 @@ impl MultiPatterns {
                 .iter()
                 .map(|x| x.search(&runner.egraph))
                 .collect();
+            let matches: Vec<Vec<SearchMatches<Mdl>>> = match &mut self.region {
+                Some(region) => {
+                    region.refresh(&runner.egraph);
+                    matches
+                        .into_iter()
+                        .map(|ms| ms.into_iter().filter(|m| region.contains(&runner.egraph, m.eclass)).collect())
+                        .collect()
+                }
+                None => matches,
+            };
+            // Frozen layers are not merged with others
+            let matches: Vec<Vec<SearchMatches<Mdl>>> = matches
+                .into_iter()
+                .map(|ms| ms.into_iter().filter(|m| !is_frozen_class(&runner.egraph, m.eclass)).collect())
+                .collect();
 
             if self.filter_after {
                 // Make a pass to get descendents
@@ -1001,9 +1791,26 @@ impl MultiPatterns {
                 ));
             }
 
+            // Applications and nodes added by each rule in this round, and the
+            // rules over their cap
+            let mut round_applied: HashMap<usize, usize> = HashMap::new();
+            let mut round_nodes: HashMap<usize, usize> = HashMap::new();
+            let mut over_cap: Vec<usize> = vec![];
+            // Rule at which the round hit its node or time limit
+            let mut stopped_at: Option<usize> = None;
+
             // For each multi rule
             'outer: for (i, rule) in self.rules.iter().enumerate() {
                 let current_rule = i;
+                let rule_name = format!("multi{}", i);
+                if self.bans.is_banned(&rule_name, self.num_rounds) {
+                    continue;
+                }
+                let cap = self
+                    .growth_limits
+                    .as_ref()
+                    .map_or(usize::MAX, |(limits, _)| limits.cap(&rule_name, limits.k_multi));
+                let rule_start_nodes = runner.egraph.analysis.newly_added.len();
                 // If a rule idx is supplied, skip all rules except the specified one (used by EgraphEnv -> step())
                 match rule_idx {
                     Some(idx) => {
@@ -1020,7 +1827,7 @@ impl MultiPatterns {
                 // If the rule is fully symmetrical
                 if map_1.index == map_2.index && rule.4 {
                     let matches_both = &matches[map_1.index];
-                    for (i, match_1) in matches_both.iter().enumerate() {
+                    'symmetric: for (i, match_1) in matches_both.iter().enumerate() {
                         for match_2 in (&matches_both[(i + 1)..]).iter() {
                             if match_1.eclass == match_2.eclass {
                                 // We don't want to apply multi-pattern rules on the same eclass
@@ -1029,13 +1836,18 @@ impl MultiPatterns {
                             let n_applied = self.apply_match_pair(rule, match_1, match_2, map_1, map_2, runner);
 
                             *rules_applied.entry(current_rule).or_insert(n_applied) += n_applied;
+                            round_nodes.insert(current_rule, runner.egraph.analysis.newly_added.len() - rule_start_nodes);
+                            let applied = round_applied.entry(current_rule).or_insert(0);
+                            *applied += n_applied;
+                            if *applied > cap {
+                                over_cap.push(current_rule);
+                                break 'symmetric;
+                            }
 
                             //num_applied += n_applied;
                             let num_nodes = runner.egraph.analysis.newly_added.len();
-                            if num_nodes - starting_num_nodes > self.node_limit {
-                               break 'outer;
-                            }
-                            if start_time.elapsed().as_secs() > self.n_sec {
+                            if num_nodes - starting_num_nodes > node_limit || start_time.elapsed().as_secs() > self.n_sec {
+                                stopped_at = Some(current_rule);
                                 break 'outer;
                             }
                         }
@@ -1043,7 +1855,7 @@ impl MultiPatterns {
                 } else {
                     let matches_1 = &matches[map_1.index];
                     let matches_2 = &matches[map_2.index];
-                    for match_1 in matches_1 {
+                    'pairs: for match_1 in matches_1 {
                         for match_2 in matches_2 {
                             if match_1.eclass == match_2.eclass {
                                 // We don't want to apply multi-pattern rules on the same eclass
@@ -1052,13 +1864,18 @@ impl MultiPatterns {
                             let n_applied = self.apply_match_pair(rule, match_1, match_2, map_1, map_2, runner);
 
                             *rules_applied.entry(current_rule).or_insert(n_applied) += n_applied;
+                            round_nodes.insert(current_rule, runner.egraph.analysis.newly_added.len() - rule_start_nodes);
+                            let applied = round_applied.entry(current_rule).or_insert(0);
+                            *applied += n_applied;
+                            if *applied > cap {
+                                over_cap.push(current_rule);
+                                break 'pairs;
+                            }
 
                             //num_applied += n_applied;
                             let num_nodes = runner.egraph.analysis.newly_added.len();
-                            if num_nodes - starting_num_nodes > self.node_limit {
-                               break 'outer;
-                            }
-                            if start_time.elapsed().as_secs() > self.n_sec {
+                            if num_nodes - starting_num_nodes > node_limit || start_time.elapsed().as_secs() > self.n_sec {
+                                stopped_at = Some(current_rule);
                                 break 'outer;
                             }
                         }
@@ -1066,6 +1883,29 @@ impl MultiPatterns {
                 }
             }
 
+            if let Some((limits, growth)) = &self.growth_limits {
+                let mut growth = growth.lock().unwrap();
+                for (i, applied) in round_applied.iter() {
+                    let entry = growth.entry(format!("multi{}", i)).or_default();
+                    entry.matches += applied;
+                    entry.nodes_added += round_nodes.get(i).copied().unwrap_or(0);
+                }
+                for i in over_cap.iter() {
+                    growth.entry(format!("multi{}", i)).or_default().num_bans += 1;
+                    self.bans.ban(&format!("multi{}", i), self.num_rounds, limits.ban_length);
+                }
+            }
+            if let Some(capped) = &self.capped {
+                let mut capped = capped.lock().unwrap();
+                // The rules after the one at the limit were not tried
+                let not_tried = stopped_at.map_or(0..0, |i| i..self.rules.len());
+                let rules: HashSet<usize> = over_cap.iter().cloned().chain(not_tried).collect();
+                for i in rules {
+                    capped.entry(format!("multi{}", i)).or_default().capped_rounds += 1;
+                }
+            }
+            self.num_rounds += 1;
+
             runner.egraph.rebuild();
 
             if self.filter_after {
@@ -0,0 +1,111 @@
+//! Caching the shape checks of the rules within an iteration
+//!
+//! Before applying a rule, CheckApply checks that the nodes of its right hand
+//! side can be created (see check_pat), which calls TASO for each new node.
+//! The result only depends on the right hand side and on the metadata of the
+//! eclasses its variables bind, so matches binding eclasses of the same
+//! shapes, scalars and names get the same result. In shape-heavy models most
+//! matches of a rule repeat a few signatures, so the results are cached per
+//! (right hand side, signature), and cleared at the beginning of each
+//! iteration.
+//!
+//! Rules creating splits are not cached: a split depends on how its input was
+//! concatenated on the TASO side, which the signature does not capture.
+
+use crate::model::*;
+use crate::shape::{ElemType, ShapeId};
+use egg::*;
+use std::collections::HashMap;
+
+/// Metadata of an eclass that the shape checks depend on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClassSignature {
+    pub dtype: DataKind,
+    pub val: i32,
+    pub name: Symbol,
+    pub shape: Option<ShapeId>,
+    pub elem: ElemType,
+    pub infeasible: bool,
+}
+
+impl ClassSignature {
+    pub fn of(data: &ValTnsr) -> Self {
+        let shape = if !data.infeasible && data.dtype == DataKind::Tnsr {
+            data.shape
+        } else {
+            None
+        };
+        ClassSignature {
+            dtype: data.dtype,
+            val: data.val,
+            name: data.name,
+            shape: shape,
+            elem: data.elem,
+            infeasible: data.infeasible,
+        }
+    }
+}
+
+/// Results of the shape checks of the current iteration
+#[derive(Debug, Clone, Default)]
+pub struct ConditionCache {
+    /// Whether results are cached, off by default
+    pub enabled: bool,
+    results: HashMap<PatternAst<Mdl>, HashMap<Vec<ClassSignature>, bool>>,
+    /// Number of checks answered from the cache, and computed, over the run
+    pub num_hits: usize,
+    pub num_misses: usize,
+}
+
+impl ConditionCache {
+    pub fn new(enabled: bool) -> Self {
+        ConditionCache {
+            enabled: enabled,
+            ..Default::default()
+        }
+    }
+
+    /// Gets the cached result of checking pat for a signature, counting a
+    /// hit or a miss
+    pub fn get(&mut self, pat: &PatternAst<Mdl>, signature: &[ClassSignature]) -> Option<bool> {
+        let result = self.results.get(pat).and_then(|results| results.get(signature)).cloned();
+        match result {
+            Some(_) => self.num_hits += 1,
+            None => self.num_misses += 1,
+        }
+        result
+    }
+
+    pub fn insert(&mut self, pat: &PatternAst<Mdl>, signature: Vec<ClassSignature>, valid: bool) {
+        self.results.entry(pat.clone()).or_default().insert(signature, valid);
+    }
+
+    /// Forgets the results, at the beginning of an iteration
+    pub fn clear(&mut self) {
+        self.results.clear();
+    }
+
+    /// Fraction of the checks answered from the cache
+    pub fn hit_rate(&self) -> f32 {
+        let total = self.num_hits + self.num_misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.num_hits as f32 / total as f32
+        }
+    }
+}
+
+/// Whether the shape check of a right hand side can be cached: it creates no
+/// splits
+pub fn is_cacheable(pat: &PatternAst<Mdl>) -> bool {
+    !pat.as_ref().iter().any(|node| match node {
+        ENodeOrVar::ENode(e) => matches!(e, Mdl::Split(_) | Mdl::Split0(_) | Mdl::Split1(_) | Mdl::SplitI(_)),
+        ENodeOrVar::Var(_) => false,
+    })
+}
+
+/// Gets the signature of the eclasses bound to the variables of a pattern
+pub fn subst_signature(egraph: &EGraph<Mdl, TensorAnalysis>, vars: &[Var], subst: &Subst) -> Vec<ClassSignature> {
+    vars.iter().map(|v| ClassSignature::of(&egraph[subst[*v]].data)).collect()
+}
@@ -0,0 +1,132 @@
+//! Rewrite rules loaded from a file at runtime
+//!
+//! The rules are written in the syntax of the built-in rules (see
+//! PRE_DEFINED_RULES and PRE_DEFINED_MULTI). A text file has one rule per
+//! line, `lhs=>rhs` for a single-pattern rule and `lhs_1=>rhs_1;lhs_2=>rhs_2`
+//! for a multi-pattern rule; empty lines and lines starting with `#` are
+//! skipped. A JSON file (ending in .json) has the form
+//! `{"rules": ["lhs=>rhs", ...], "multi_rules": [["lhs_1=>rhs_1", "lhs_2=>rhs_2"], ...]}`.
+//!
+//! Each pattern is parsed as a Pattern<Mdl>, which checks the ops and their
+//! numbers of children, and every variable of a target pattern has to be
+//! bound by the source patterns of the rule.
+
+use crate::model::*;
+use egg::*;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+
+/// Rules of a rules file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RuleFile {
+    /// Single-pattern rules, in the format lhs=>rhs
+    #[serde(default)]
+    pub rules: Vec<String>,
+    /// Multi-pattern rules, each as its two lhs=>rhs rules
+    #[serde(default)]
+    pub multi_rules: Vec<[String; 2]>,
+}
+
+impl RuleFile {
+    /// The multi-pattern rules as MultiPatterns::with_rules takes them, as
+    /// adjacent pairs of non-symmetric rules
+    pub fn multi_rule_pairs(&self) -> Vec<(&str, bool)> {
+        self.multi_rules
+            .iter()
+            .flat_map(|pair| pair.iter().map(|rule| (rule.as_str(), /*symmetric=*/ false)))
+            .collect()
+    }
+}
+
+/// Parses the source and target pattern of a rule
+fn parse_rule(rule: &str) -> Result<(Pattern<Mdl>, Pattern<Mdl>), String> {
+    let eqn: Vec<&str> = rule.split("=>").collect();
+    if eqn.len() != 2 {
+        return Err(String::from("expected one => between the source and the target pattern"));
+    }
+    let lhs: Pattern<Mdl> = eqn[0].trim().parse().map_err(|e| format!("source pattern: {}", e))?;
+    let rhs: Pattern<Mdl> = eqn[1].trim().parse().map_err(|e| format!("target pattern: {}", e))?;
+    if let ENodeOrVar::Var(_) = lhs.ast.as_ref().last().unwrap() {
+        return Err(String::from("the source pattern is only a variable, it would match every eclass"));
+    }
+    Ok((lhs, rhs))
+}
+
+/// Checks a rule made of one or two lhs=>rhs rules
+///
+/// # Returns
+///
+/// The rules with surrounding whitespace removed, or what is wrong with them
+fn check_rule(rules: &[&str]) -> Result<Vec<String>, String> {
+    let mut parsed = vec![];
+    for rule in rules.iter() {
+        parsed.push(parse_rule(rule)?);
+    }
+    let bound: HashSet<Var> = parsed.iter().flat_map(|(lhs, _)| lhs.vars()).collect();
+    for (_, rhs) in parsed.iter() {
+        if let Some(var) = rhs.vars().into_iter().find(|var| !bound.contains(var)) {
+            return Err(format!("{} is not bound by the source pattern", var));
+        }
+    }
+    Ok(rules.iter().map(|rule| rule.trim().to_string()).collect())
+}
+
+/// Parses the rules of a text rules file
+pub fn parse_rule_text(s: &str) -> Result<RuleFile, Vec<String>> {
+    let mut rule_file = RuleFile::default();
+    let mut errors = vec![];
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let rules: Vec<&str> = line.split(';').collect();
+        if rules.len() > 2 {
+            errors.push(format!("line {}: a multi-pattern rule has two patterns", i + 1));
+            continue;
+        }
+        match check_rule(&rules) {
+            Ok(mut checked) if checked.len() == 2 => {
+                let second = checked.pop().unwrap();
+                rule_file.multi_rules.push([checked.pop().unwrap(), second]);
+            }
+            Ok(mut checked) => rule_file.rules.push(checked.pop().unwrap()),
+            Err(e) => errors.push(format!("line {}: {}", i + 1, e)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(rule_file)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Loads the rules of a rules file, text or JSON
+///
+/// # Returns
+///
+/// The rules, or what is wrong with each invalid rule
+pub fn load_rule_file(filename: &str) -> Result<RuleFile, Vec<String>> {
+    let s = fs::read_to_string(filename).map_err(|e| vec![format!("Could not read {}: {}", filename, e)])?;
+    if !filename.ends_with(".json") {
+        return parse_rule_text(&s);
+    }
+    let rule_file: RuleFile = serde_json::from_str(&s).map_err(|e| vec![format!("Could not parse {}: {}", filename, e)])?;
+    let mut errors = vec![];
+    for (i, rule) in rule_file.rules.iter().enumerate() {
+        if let Err(e) = check_rule(&[rule.as_str()]) {
+            errors.push(format!("rules[{}]: {}", i, e));
+        }
+    }
+    for (i, [rule_1, rule_2]) in rule_file.multi_rules.iter().enumerate() {
+        if let Err(e) = check_rule(&[rule_1.as_str(), rule_2.as_str()]) {
+            errors.push(format!("multi_rules[{}]: {}", i, e));
+        }
+    }
+    if errors.is_empty() {
+        Ok(rule_file)
+    } else {
+        Err(errors)
+    }
+}
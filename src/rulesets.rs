@@ -0,0 +1,223 @@
+//! Selecting the rules of a run
+//!
+//! RuleSelection filters the rules of a run before they are parsed, in this
+//! order: for a quantized model, the rules changing the numerics are
+//! disabled (the rules of approx_rules are added after this); with
+//! verify_trials, the rules diverging in check_rule are dropped; the tiers
+//! the safety level does not enable are dropped; with packed_attrs, the rules
+//! are migrated to the packed ops. The multi-pattern rules, pairs of adjacent
+//! lines, are kept or dropped by pairs, and are never verified.
+//!
+//! Optimizer applies the selection of its settings to its rules, so that the
+//! optimize command and the library filter them the same way.
+
+use crate::attrs::pack_rule_set;
+use crate::rewrites::*;
+use crate::verify::{check_rule, rule_tiers, RuleCheck};
+use std::collections::HashSet;
+use std::time::Instant;
+
+/// How to filter the rules of a run, see the module documentation. The
+/// default keeps all the rules
+#[derive(Debug, Clone)]
+pub struct RuleSelection {
+    /// Whether the model is quantized, so that only the rules exact w.r.t.
+    /// numerics are enabled
+    pub quantized: bool,
+    /// Approximate rules to add, only enabled with Safety::Aggressive
+    pub approx_rules: Vec<String>,
+    /// Number of trials to check each rule numerically with, if any
+    pub verify_trials: Option<usize>,
+    pub safety: Safety,
+    /// Whether to migrate the rules to the packed ops
+    pub packed_attrs: bool,
+}
+
+impl Default for RuleSelection {
+    fn default() -> Self {
+        RuleSelection {
+            quantized: false,
+            approx_rules: vec![],
+            verify_trials: None,
+            safety: Safety::Aggressive,
+            packed_attrs: false,
+        }
+    }
+}
+
+impl RuleSelection {
+    /// Filters single-pattern rules
+    ///
+    /// # Parameters
+    ///
+    /// - `rules`: the rules, one per entry. Empty entries are dropped
+    ///
+    /// # Returns
+    ///
+    /// The enabled rules, with the approximate rules if enabled, in their
+    /// order
+    pub fn select_rules(&self, rules: Vec<&str>) -> Vec<String> {
+        let mut split_rules: Vec<&str> = rules.into_iter().filter(|r| !r.trim().is_empty()).collect();
+        if self.quantized {
+            let n_rules = split_rules.len();
+            split_rules.retain(|r| rule_str_numerics(r) == Numerics::Exact);
+            println!("Quantized model: disabled {} numerics-changing rules", n_rules - split_rules.len());
+        }
+        split_rules.extend(self.approx_rules.iter().map(|r| r.as_str()).filter(|r| !r.trim().is_empty()));
+
+        // Check the rules numerically, before they are used
+        if let Some(num_trials) = self.verify_trials {
+            let start_time = Instant::now();
+            let mut num_unchecked = 0;
+            let mut diverging = vec![];
+            for (i, rule) in split_rules.iter().enumerate() {
+                match check_rule(rule, num_trials) {
+                    RuleCheck::Agrees(_) => (),
+                    RuleCheck::Diverges(error, src) => {
+                        println!("Warning: rule {} diverges (relative error {}, at {}): {}", i, error, src, rule);
+                        diverging.push(i);
+                    }
+                    RuleCheck::Unchecked(_) => num_unchecked += 1,
+                }
+            }
+            println!(
+                "Verified rules on the host interpreter in {:?}: {} diverge, {} could not be checked, of {}",
+                start_time.elapsed(),
+                diverging.len(),
+                num_unchecked,
+                split_rules.len()
+            );
+            split_rules = split_rules
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| !diverging.contains(i))
+                .map(|(_, rule)| rule)
+                .collect();
+        }
+
+        // Keep the tiers of rules the safety level enables
+        if self.safety != Safety::Aggressive && !self.approx_rules.is_empty() {
+            println!("Warning: the rules of approx_rules are only enabled with --safety aggressive");
+        }
+        if self.safety != Safety::Aggressive {
+            let approx: HashSet<&str> = self.approx_rules.iter().map(|r| r.as_str()).collect();
+            let start_time = Instant::now();
+            let tiers: Vec<RuleTier> = rule_tiers(&split_rules)
+                .into_iter()
+                .zip(split_rules.iter())
+                .map(|(tier, rule)| if approx.contains(rule) { RuleTier::Approximate } else { tier })
+                .collect();
+            let count = |tier| tiers.iter().filter(|t| **t == tier).count();
+            println!(
+                "Rule tiers ({:?}): {} proven, {} exact, {} approximate",
+                start_time.elapsed(),
+                count(RuleTier::Proven),
+                count(RuleTier::Exact),
+                count(RuleTier::Approximate)
+            );
+            split_rules = split_rules
+                .into_iter()
+                .zip(tiers)
+                .filter(|(_, tier)| self.safety.allows(*tier))
+                .map(|(rule, _)| rule)
+                .collect();
+            println!("Safety {:?}: enabled {} rules", self.safety, split_rules.len());
+        }
+
+        // Migrate the rules to the packed ops. Rules that can not be migrated
+        // are kept as they are
+        if !self.packed_attrs {
+            return split_rules.iter().map(|r| r.to_string()).collect();
+        }
+        let mut n_unpacked = 0;
+        let packed_rules: Vec<String> = split_rules
+            .iter()
+            .map(|rule| match pack_rule_set(&[rule]) {
+                Ok(mut packed) => packed.remove(0),
+                Err(_) => {
+                    n_unpacked += 1;
+                    rule.to_string()
+                }
+            })
+            .collect();
+        println!("Packed attributes: {} of {} rules could not be migrated", n_unpacked, packed_rules.len());
+        packed_rules
+    }
+
+    /// Filters multi-pattern rules, by pairs of adjacent entries
+    ///
+    /// # Parameters
+    ///
+    /// - `rules`: the rules, with whether each is symmetric
+    ///
+    /// # Returns
+    ///
+    /// The pairs enabled, in their order
+    pub fn select_multi_rules(&self, rules: Vec<(&str, bool)>) -> Vec<(String, bool)> {
+        let mut multi_rules = rules;
+        if self.quantized {
+            multi_rules = exact_multi_rules(multi_rules);
+        }
+        if self.safety != Safety::Aggressive {
+            multi_rules = safe_multi_rules(multi_rules, self.safety);
+        }
+        if self.packed_attrs {
+            return pack_multi_rules(&multi_rules);
+        }
+        multi_rules.into_iter().map(|(r, s)| (r.to_string(), s)).collect()
+    }
+}
+
+/// Migrates multi-pattern rules to the packed ops, see attrs::pack_rule_set
+///
+/// # Returns
+///
+/// The rules with each pair migrated if possible
+fn pack_multi_rules(rules: &[(&str, bool)]) -> Vec<(String, bool)> {
+    let mut n_unpacked = 0;
+    let mut packed_rules = vec![];
+    for pair in rules.chunks(2) {
+        let strs: Vec<&str> = pair.iter().map(|(r, _)| *r).collect();
+        let migrated = pack_rule_set(&strs).unwrap_or_else(|_| {
+            n_unpacked += 1;
+            strs.iter().map(|r| r.to_string()).collect()
+        });
+        packed_rules.extend(migrated.into_iter().zip(pair.iter().map(|(_, s)| *s)));
+    }
+    println!("Packed attributes: {} of {} multi-pattern rules could not be migrated", n_unpacked, rules.len() / 2);
+    packed_rules
+}
+
+/// Keeps the multi-pattern rules (pairs of consecutive lines) whose both
+/// halves are exact w.r.t. numerics
+fn exact_multi_rules(rules: Vec<(&str, bool)>) -> Vec<(&str, bool)> {
+    let n_rules = rules.len() / 2;
+    let exact: Vec<(&str, bool)> = rules
+        .chunks(2)
+        .filter(|pair| pair.iter().all(|r| rule_str_numerics(r.0) == Numerics::Exact))
+        .flat_map(|pair| pair.iter().cloned())
+        .collect();
+    println!("Quantized model: disabled {} numerics-changing multi-pattern rules", n_rules - exact.len() / 2);
+    exact
+}
+
+/// Keeps the multi-pattern rules (pairs of consecutive lines) of the tiers
+/// enabled by the safety level. They are never proven, only exact if both
+/// halves are
+fn safe_multi_rules(rules: Vec<(&str, bool)>, safety: Safety) -> Vec<(&str, bool)> {
+    let n_rules = rules.len() / 2;
+    let tier = |pair: &[(&str, bool)]| {
+        if pair.iter().all(|r| rule_str_numerics(r.0) == Numerics::Exact) {
+            RuleTier::Exact
+        } else {
+            RuleTier::Approximate
+        }
+    };
+    let safe: Vec<(&str, bool)> = rules
+        .chunks(2)
+        .filter(|pair| safety.allows(tier(pair)))
+        .flat_map(|pair| pair.iter().cloned())
+        .collect();
+    println!("Safety {:?}: disabled {} multi-pattern rules", safety, n_rules - safe.len() / 2);
+    safe
+}
@@ -0,0 +1,160 @@
+//! Limits on the growth of the EGraph by each rule
+//!
+//! With all multi-pattern rules enabled, a few rules matching almost
+//! anywhere (e.g. concatenating any two convs) fill the node limit within an
+//! iteration or two, before the other rules had a chance. GrowthLimits bans
+//! such rules for a while:
+//!
+//! - a single-pattern rule with more matches in an iteration than its cap is
+//!   banned (GrowthScheduler, like egg's BackoffScheduler with a cap per rule)
+//! - a multi-pattern rule applied more than its cap (k_multi) in a round of
+//!   MultiPatterns stops for the round and is banned for the next rounds
+//!
+//! A ban lasts ban_length iterations (or rounds), doubled at each ban of the
+//! same rule. The single-pattern rules are named rule<i> (see
+//! rules_from_str), the multi-pattern rules multi<i>, by their position. The
+//! matches, the nodes added and the bans of each rule are recorded in a
+//! RuleGrowth.
+
+use crate::model::*;
+use egg::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+/// Caps of the rules, see the module documentation
+#[derive(Debug, Clone)]
+pub struct GrowthLimits {
+    /// Maximum number of matches of a single-pattern rule in an iteration
+    pub match_limit: usize,
+    /// Maximum number of applications of a multi-pattern rule in a round
+    pub k_multi: usize,
+    /// Number of iterations (rounds) of the first ban of a rule
+    pub ban_length: usize,
+    /// Caps of some rules, instead of match_limit or k_multi
+    pub caps: HashMap<String, usize>,
+}
+
+impl Default for GrowthLimits {
+    fn default() -> Self {
+        GrowthLimits {
+            match_limit: usize::MAX,
+            k_multi: usize::MAX,
+            ban_length: 2,
+            caps: HashMap::new(),
+        }
+    }
+}
+
+impl GrowthLimits {
+    /// Loads the caps of some rules, a JSON object from rule names to caps
+    pub fn load_caps(&mut self, filename: &str) -> Result<(), String> {
+        let s = fs::read_to_string(filename).map_err(|e| format!("Could not read {}: {}", filename, e))?;
+        self.caps = serde_json::from_str(&s).map_err(|e| format!("Could not parse {}: {}", filename, e))?;
+        Ok(())
+    }
+
+    /// Gets the cap of a rule, default if it has none of its own
+    pub fn cap(&self, rule: &str, default: usize) -> usize {
+        self.caps.get(rule).copied().unwrap_or(default)
+    }
+}
+
+/// Growth of one rule over a saturation
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuleGrowthEntry {
+    /// Number of matches (substitutions) found, banned or not
+    pub matches: usize,
+    /// Number of enodes added, for the multi-pattern rules
+    pub nodes_added: usize,
+    pub num_bans: usize,
+}
+
+/// Growth of each rule, shared between the schedulers and the caller
+pub type RuleGrowth = Arc<Mutex<BTreeMap<String, RuleGrowthEntry>>>;
+
+/// Which rules are banned until when
+#[derive(Debug, Clone, Default)]
+pub struct Bans {
+    banned_until: HashMap<String, usize>,
+    num_bans: HashMap<String, usize>,
+}
+
+impl Bans {
+    pub fn is_banned(&self, rule: &str, iteration: usize) -> bool {
+        self.banned_until.get(rule).map_or(false, |until| iteration < *until)
+    }
+
+    pub fn any_banned(&self, iteration: usize) -> bool {
+        self.banned_until.values().any(|until| iteration < *until)
+    }
+
+    /// Bans a rule from the next iteration on, for ban_length iterations
+    /// doubled at each ban of the rule
+    pub fn ban(&mut self, rule: &str, iteration: usize, ban_length: usize) {
+        let num_bans = self.num_bans.entry(rule.to_string()).or_insert(0);
+        let length = ban_length << (*num_bans).min(16);
+        *num_bans += 1;
+        self.banned_until.insert(rule.to_string(), iteration + 1 + length);
+    }
+
+    pub fn unban_all(&mut self) {
+        self.banned_until.clear();
+    }
+}
+
+/// Scheduler banning the single-pattern rules with more matches than their
+/// cap, and as the inner scheduler otherwise
+pub struct GrowthScheduler {
+    inner: Box<dyn RewriteScheduler<Mdl, TensorAnalysis>>,
+    limits: GrowthLimits,
+    bans: Bans,
+    growth: RuleGrowth,
+}
+
+impl GrowthScheduler {
+    pub fn new<S: RewriteScheduler<Mdl, TensorAnalysis> + 'static>(inner: S, limits: GrowthLimits, growth: RuleGrowth) -> Self {
+        GrowthScheduler {
+            inner: Box::new(inner),
+            limits: limits,
+            bans: Bans::default(),
+            growth: growth,
+        }
+    }
+}
+
+impl RewriteScheduler<Mdl, TensorAnalysis> for GrowthScheduler {
+    fn can_stop(&mut self, iteration: usize) -> bool {
+        // As BackoffScheduler: a saturated EGraph with banned rules gives
+        // them another try
+        if self.bans.any_banned(iteration) {
+            self.bans.unban_all();
+            return false;
+        }
+        self.inner.can_stop(iteration)
+    }
+
+    fn search_rewrite<'a>(
+        &mut self,
+        iteration: usize,
+        egraph: &EGraph<Mdl, TensorAnalysis>,
+        rewrite: &'a Rewrite<Mdl, TensorAnalysis>,
+    ) -> Vec<SearchMatches<'a, Mdl>> {
+        let name = rewrite.name.as_str();
+        if self.bans.is_banned(name, iteration) {
+            return vec![];
+        }
+        let matches = self.inner.search_rewrite(iteration, egraph, rewrite);
+        let num_matches: usize = matches.iter().map(|m| m.substs.len()).sum();
+        let mut growth = self.growth.lock().unwrap();
+        let entry = growth.entry(name.to_string()).or_default();
+        entry.matches += num_matches;
+        if num_matches > self.limits.cap(name, self.limits.match_limit) {
+            entry.num_bans += 1;
+            self.bans.ban(name, iteration, self.limits.ban_length);
+            return vec![];
+        }
+        matches
+    }
+}
@@ -0,0 +1,195 @@
+//! Checking that the results of runs are stable (--self_check, --deterministic)
+//!
+//! The result of a run can depend on more than its settings: the runtimes
+//! measured on the GPU are noisy, the time limits of the saturation and the
+//! ILP stop them at a point that depends on the load of the machine, and the
+//! solver threads race. The deterministic mode removes these sources:
+//!
+//! - runtimes are estimated from the FLOPs and bytes of the ops
+//!   (--runtime_source analytical)
+//! - the saturation is only stopped by n_iter and n_nodes, and the ILP by
+//!   its gap: the time limits (n_sec, time_budget, ilp_time_sec) are dropped
+//! - the ILP solver runs on one thread
+//!
+//! It is applied by rewriting the arguments (see deterministic_args), so the
+//! rest of the pipeline is unchanged.
+//!
+//! A self check runs the whole pipeline N times as subprocesses, each in its
+//! own directory under the output directory (self_check/run_i). In the
+//! deterministic mode the optimized graphs have to be byte-identical, else
+//! the check fails. Otherwise it reports how much the final cost varies
+//! across the runs, to quantify how much one result can be trusted.
+
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Flag of the deterministic mode
+pub const DETERMINISTIC_FLAG: &str = "--deterministic";
+
+/// Flag of the self check
+pub const SELF_CHECK_FLAG: &str = "--self_check";
+
+/// Output file of the runs of a self check, if no output_file is given
+pub const SELF_CHECK_OUTPUT: &str = "optimized.txt";
+
+/// Statistics file of the runs of a self check, if no stats_out is given
+pub const SELF_CHECK_STATS: &str = "self_check_stats.json";
+
+/// Seconds of saturation replacing n_sec in the deterministic mode, so that
+/// only the node and iteration limits stop it
+const UNLIMITED_SEC: &str = "1000000000";
+
+/// Gets the value of an option in command-line arguments, given as
+/// `--name value` or `--name=value`
+pub fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let prefix = format!("{}=", name);
+    args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == name {
+            args.get(i + 1).map(|v| v.as_str())
+        } else {
+            arg.strip_prefix(prefix.as_str())
+        }
+    })
+}
+
+/// Removes an option from command-line arguments, with its value if it
+/// takes one
+pub fn strip_arg(args: &[String], name: &str, takes_value: bool) -> Vec<String> {
+    let prefix = format!("{}=", name);
+    let mut stripped = Vec::with_capacity(args.len());
+    let mut skip_value = false;
+    for arg in args {
+        if skip_value {
+            skip_value = false;
+        } else if arg == name {
+            skip_value = takes_value;
+        } else if !arg.starts_with(prefix.as_str()) {
+            stripped.push(arg.clone());
+        }
+    }
+    stripped
+}
+
+/// Sets an option in command-line arguments, replacing its value if given
+pub fn set_arg(args: &[String], name: &str, value: &str) -> Vec<String> {
+    let mut args = strip_arg(args, name, true);
+    args.push(name.to_string());
+    args.push(value.to_string());
+    args
+}
+
+/// Rewrites command-line arguments with --deterministic into the settings
+/// of the deterministic mode (see the module doc), keeping the flag.
+/// Arguments without it are returned as they are
+pub fn deterministic_args(args: &[String]) -> Vec<String> {
+    if !args.iter().any(|arg| arg == DETERMINISTIC_FLAG) {
+        return args.to_vec();
+    }
+    let mut args = strip_arg(args, "--time_budget", true);
+    args = strip_arg(&args, "--ilp_time_sec", true);
+    args = set_arg(&args, "--n_sec", UNLIMITED_SEC);
+    args = set_arg(&args, "--runtime_source", "analytical");
+    set_arg(&args, "--ilp_num_threads", "1")
+}
+
+/// Gets the arguments of one run of a self check: the same as the checking
+/// run, without --self_check, writing to its own directory
+///
+/// # Parameters
+///
+/// - `args`: arguments of the checking run, without the executable
+/// - `run_dir`: output directory of the run
+pub fn self_check_run_args(args: &[String], run_dir: &str) -> Vec<String> {
+    let mut args = strip_arg(args, SELF_CHECK_FLAG, true);
+    args = set_arg(&args, "--output_dir", run_dir);
+    if matches!(arg_value(&args, "--output_file"), None | Some("-")) {
+        args = set_arg(&args, "--output_file", SELF_CHECK_OUTPUT);
+    }
+    if arg_value(&args, "--stats_out").is_none() {
+        args = set_arg(&args, "--stats_out", SELF_CHECK_STATS);
+    }
+    args
+}
+
+/// Outcome of one run of a self check
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfCheckRun {
+    /// Contents of the optimized graph file
+    pub output: Vec<u8>,
+    /// Cost of the optimized graph, if extracted
+    pub cost: Option<f32>,
+}
+
+/// Runs the pipeline for a self check
+///
+/// # Parameters
+///
+/// - `exe`: the tensat executable
+/// - `args`: arguments of the checking run, without the executable
+/// - `output_dir`: output directory of the checking run
+/// - `num_runs`: number of runs
+///
+/// # Returns
+///
+/// The outcome of each run, or the first run that failed
+pub fn run_self_check(exe: &Path, args: &[String], output_dir: &str, num_runs: usize) -> Result<Vec<SelfCheckRun>, String> {
+    (0..num_runs)
+        .map(|i| {
+            let run_dir = Path::new(output_dir).join("self_check").join(format!("run_{}", i));
+            fs::create_dir_all(&run_dir).map_err(|e| e.to_string())?;
+            let run_args = self_check_run_args(args, run_dir.to_str().unwrap());
+            println!("Self check run {}/{}", i + 1, num_runs);
+            let out = Command::new(exe).args(&run_args).output().map_err(|e| e.to_string())?;
+            fs::write(run_dir.join("log.txt"), [&out.stdout[..], &out.stderr[..]].concat()).map_err(|e| e.to_string())?;
+            if !out.status.success() {
+                return Err(format!("run {} failed ({}), see {}", i, out.status, run_dir.join("log.txt").display()));
+            }
+            let output_file = arg_value(&run_args, "--output_file").unwrap();
+            let output = fs::read(run_dir.join(output_file)).map_err(|e| format!("run {}: {}", i, e))?;
+            let stats_file = arg_value(&run_args, "--stats_out").unwrap();
+            let cost = fs::read_to_string(run_dir.join(stats_file))
+                .ok()
+                .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+                .and_then(|stats| stats["optimized_cost"].as_f64())
+                .map(|c| c as f32);
+            Ok(SelfCheckRun {
+                output: output,
+                cost: cost,
+            })
+        })
+        .collect()
+}
+
+/// Gets the first run whose optimized graph differs from the one of the
+/// first run, if any
+pub fn first_mismatch(runs: &[SelfCheckRun]) -> Option<usize> {
+    runs.iter().position(|run| run.output != runs[0].output)
+}
+
+/// Spread of the final cost across runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostSpread {
+    pub mean: f32,
+    /// Population standard deviation
+    pub std_dev: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Computes the spread of costs, None if there are none
+pub fn cost_spread(costs: &[f32]) -> Option<CostSpread> {
+    if costs.is_empty() {
+        return None;
+    }
+    let n = costs.len() as f32;
+    let mean = costs.iter().sum::<f32>() / n;
+    let variance = costs.iter().map(|c| (c - mean) * (c - mean)).sum::<f32>() / n;
+    Some(CostSpread {
+        mean: mean,
+        std_dev: variance.sqrt(),
+        min: costs.iter().cloned().fold(f32::INFINITY, f32::min),
+        max: costs.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+    })
+}
@@ -0,0 +1,240 @@
+//! Queries for the shapes TASO inferred for the tensors in an EGraph
+
+use crate::model::*;
+use crate::provenance::expr_eclasses;
+use egg::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::RwLock;
+
+/// Element type of a tensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ElemType {
+    Float32,
+    Float16,
+    Int8,
+}
+
+impl Default for ElemType {
+    fn default() -> Self {
+        ElemType::Float32
+    }
+}
+
+impl ElemType {
+    /// Gets the element type of a code ELEM_*
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            ELEM_F32 => Some(ElemType::Float32),
+            ELEM_F16 => Some(ElemType::Float16),
+            ELEM_I8 => Some(ElemType::Int8),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        match self {
+            ElemType::Float32 => ELEM_F32,
+            ElemType::Float16 => ELEM_F16,
+            ElemType::Int8 => ELEM_I8,
+        }
+    }
+
+    /// Size of an element in bytes
+    pub fn bytes(self) -> usize {
+        match self {
+            ElemType::Float32 => 4,
+            ElemType::Float16 => 2,
+            ElemType::Int8 => 1,
+        }
+    }
+
+    /// Gets the element type of a name (see name)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "f32" => Some(ElemType::Float32),
+            "f16" => Some(ElemType::Float16),
+            "i8" => Some(ElemType::Int8),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ElemType::Float32 => "f32",
+            ElemType::Float16 => "f16",
+            ElemType::Int8 => "i8",
+        }
+    }
+}
+
+/// Shape and element type of a tensor
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct TensorShape {
+    /// Size of each dimension
+    pub dims: Vec<i32>,
+    /// Element type
+    pub dtype: ElemType,
+}
+
+impl TensorShape {
+    /// Reads the shape of a tensor on the TASO side, as fp32 (the tensors on
+    /// the TASO side have no element type). Returns None for a null handle
+    pub fn from_handle(t: TensorHandle) -> Option<Self> {
+        if t.is_null() {
+            return None;
+        }
+        Some(TensorShape {
+            dims: handle_dims(t),
+            dtype: ElemType::Float32,
+        })
+    }
+
+    /// Number of elements in the tensor
+    pub fn volume(&self) -> i64 {
+        self.dims.iter().map(|d| *d as i64).product()
+    }
+}
+
+/// Gets the shape two tensors are broadcast to by an elementwise op
+///
+/// As in NumPy, the shapes are aligned at their last dimension, and each pair
+/// of dimensions has to agree or one of them has to be 1. A missing dimension
+/// of the shorter shape counts as 1, so a scalar broadcasts to any shape.
+///
+/// # Returns
+///
+/// The broadcast shape, or None if the shapes can not be broadcast together
+pub fn broadcast_shape(a: &[i32], b: &[i32]) -> Option<Vec<i32>> {
+    let n = a.len().max(b.len());
+    let dim = |s: &[i32], d: usize| if d + s.len() < n { 1 } else { s[d + s.len() - n] };
+    (0..n)
+        .map(|d| match (dim(a, d), dim(b, d)) {
+            (x, y) if x == y => Some(x),
+            (1, y) => Some(y),
+            (x, 1) => Some(x),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Gets the dimensions of a tensor on the TASO side
+pub fn handle_dims(t: TensorHandle) -> Vec<i32> {
+    unsafe { (*t).dim[..(*t).numDim as usize].to_vec() }
+}
+
+/// A shape stored in the shape arena
+///
+/// Most tensors of an EGraph share their shape with many others (all the
+/// enodes of an eclass, and all the eclasses of a layer repeated along the
+/// model), so the analysis data only keeps the id of its shape, and the
+/// dimensions are stored once in the arena. Ids are only equal for equal
+/// shapes, so comparing shapes does not read the dimensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShapeId(NonZeroU32);
+
+/// Shapes interned so far, shared by all EGraphs of the process
+#[derive(Default)]
+struct ShapeArena {
+    /// Dimensions of all the shapes, one after the other
+    dims: Vec<i32>,
+    /// Start and end in dims of each shape, by id - 1
+    spans: Vec<(usize, usize)>,
+    ids: HashMap<Vec<i32>, ShapeId>,
+}
+
+static SHAPES: Lazy<RwLock<ShapeArena>> = Lazy::new(Default::default);
+
+/// Gets the id of a shape, storing it in the arena if it is new
+pub fn intern_shape(dims: &[i32]) -> ShapeId {
+    if let Some(id) = SHAPES.read().unwrap().ids.get(dims) {
+        return *id;
+    }
+    let mut arena = SHAPES.write().unwrap();
+    if let Some(id) = arena.ids.get(dims) {
+        return *id;
+    }
+    let start = arena.dims.len();
+    arena.dims.extend_from_slice(dims);
+    let end = arena.dims.len();
+    arena.spans.push((start, end));
+    let id = ShapeId(NonZeroU32::new(arena.spans.len() as u32).unwrap());
+    arena.ids.insert(dims.to_vec(), id);
+    id
+}
+
+/// Gets the id of the shape of a tensor on the TASO side, None for a null
+/// handle
+pub fn handle_shape(t: TensorHandle) -> Option<ShapeId> {
+    if t.is_null() {
+        return None;
+    }
+    unsafe { Some(intern_shape(&(*t).dim[..(*t).numDim as usize])) }
+}
+
+/// Calls a function on the dimensions of a shape, without copying them
+pub fn with_shape<R>(id: ShapeId, f: impl FnOnce(&[i32]) -> R) -> R {
+    let arena = SHAPES.read().unwrap();
+    let (start, end) = arena.spans[id.0.get() as usize - 1];
+    f(&arena.dims[start..end])
+}
+
+/// Gets the dimensions of a shape
+pub fn shape_dims(id: ShapeId) -> Vec<i32> {
+    with_shape(id, |dims| dims.to_vec())
+}
+
+/// Number of distinct shapes in the arena
+pub fn num_shapes() -> usize {
+    SHAPES.read().unwrap().spans.len()
+}
+
+/// Gets the shapes of the output tensors of an eclass
+///
+/// # Returns
+///
+/// One shape for a tensor, two for the output of a split, and none for
+/// names, scalars, infeasible eclasses, and the noop combining graph outputs
+pub fn eclass_shapes(egraph: &EGraph<Mdl, TensorAnalysis>, id: Id) -> Vec<TensorShape> {
+    let data = &egraph[id].data;
+    if data.infeasible {
+        return vec![];
+    }
+    match data.dtype {
+        DataKind::Tnsr | DataKind::TnsrTuple => [data.shape, data.shape_2]
+            .iter()
+            .flatten()
+            .map(|id| TensorShape {
+                dims: shape_dims(*id),
+                dtype: data.elem,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Gets the output shapes of every node in a graph
+///
+/// The graph can be the original or the optimized graph, as long as all its
+/// nodes are in the EGraph, e.g. the EGraph after saturation.
+///
+/// # Parameters
+///
+/// - `egraph`: E-graph containing the nodes of expr
+/// - `expr`: the graph to get shapes for
+///
+/// # Returns
+///
+/// For each node in expr (in the same order), its output shapes (see
+/// eclass_shapes), or None if the node is not in the EGraph
+pub fn expr_shapes(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    expr: &RecExpr<Mdl>,
+) -> Vec<Option<Vec<TensorShape>>> {
+    let ids = expr_eclasses(egraph, expr);
+    ids.iter()
+        .map(|id| id.map(|id| eclass_shapes(egraph, id)))
+        .collect()
+}
@@ -0,0 +1,142 @@
+//! Snapshots of the EGraph between iterations, for debugging blowups
+//!
+//! Before each iteration (as a runner hook) and after the last one, the
+//! EGraph is serialized, and compared with the snapshot before. The diff
+//! says how many eclasses and enodes of each op the iteration added, and
+//! how often each rule was applied in it, which points at the iteration
+//! and the rule responsible when the EGraph blows up.
+
+use crate::model::*;
+use egg::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// The enodes of each eclass, each as `op(child ids)`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EGraphSnapshot {
+    pub classes: BTreeMap<usize, Vec<String>>,
+}
+
+impl EGraphSnapshot {
+    pub fn take(egraph: &EGraph<Mdl, TensorAnalysis>) -> Self {
+        let classes = egraph
+            .classes()
+            .map(|class| {
+                let mut nodes: Vec<String> = class.iter().map(|node| enode_string(egraph, node)).collect();
+                nodes.sort();
+                (usize::from(class.id), nodes)
+            })
+            .collect();
+        EGraphSnapshot { classes: classes }
+    }
+}
+
+fn enode_string(egraph: &EGraph<Mdl, TensorAnalysis>, node: &Mdl) -> String {
+    let children: Vec<String> = node
+        .children()
+        .iter()
+        .map(|id| usize::from(egraph.find(*id)).to_string())
+        .collect();
+    format!("{}({})", node, children.join(","))
+}
+
+/// What changed in the EGraph during one iteration
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SnapshotDiff {
+    pub iteration: usize,
+    pub num_classes: usize,
+    pub num_nodes: usize,
+    /// Eclasses that are new (not containing an eclass of the snapshot before)
+    pub new_classes: usize,
+    /// Eclasses of the snapshot before merged into others
+    pub merged_classes: usize,
+    /// Number of new enodes by op
+    pub new_nodes_by_op: BTreeMap<String, usize>,
+    /// Number of applications of each single-pattern rule. Enodes added by
+    /// multi-pattern rules are in new_nodes_by_op, but not here
+    pub applied: BTreeMap<String, usize>,
+}
+
+/// Compares two snapshots
+///
+/// Eclasses are matched by Id: an eclass Id of before that is still
+/// canonical in after is the same eclass, other Ids of before were merged.
+pub fn diff_snapshots(before: &EGraphSnapshot, after: &EGraphSnapshot) -> SnapshotDiff {
+    let old_nodes: HashSet<&String> = before.classes.values().flatten().collect();
+    let mut new_nodes_by_op: BTreeMap<String, usize> = BTreeMap::new();
+    for node in after.classes.values().flatten() {
+        // An old enode with a merged child eclass counts as new, since its
+        // children changed
+        if !old_nodes.contains(node) {
+            let op = node.split('(').next().unwrap().to_string();
+            *new_nodes_by_op.entry(op).or_insert(0) += 1;
+        }
+    }
+    let merged_classes = before.classes.keys().filter(|id| !after.classes.contains_key(id)).count();
+    SnapshotDiff {
+        iteration: 0,
+        num_classes: after.classes.len(),
+        num_nodes: after.classes.values().map(|nodes| nodes.len()).sum(),
+        new_classes: after.classes.keys().filter(|id| !before.classes.contains_key(id)).count(),
+        merged_classes: merged_classes,
+        new_nodes_by_op: new_nodes_by_op,
+        applied: BTreeMap::new(),
+    }
+}
+
+/// Records a snapshot and a diff for each iteration of a runner
+///
+/// The diffs are appended to `<dir>/egraph_diffs.jsonl`, and if full is set
+/// the snapshots are written to `<dir>/egraph_snapshot_<iteration>.json`.
+pub struct SnapshotRecorder {
+    dir: PathBuf,
+    full: bool,
+    last: Option<EGraphSnapshot>,
+    pub diffs: Vec<SnapshotDiff>,
+}
+
+impl SnapshotRecorder {
+    pub fn new(dir: &str, full: bool) -> Self {
+        let dir = PathBuf::from(dir);
+        // Start a new file of diffs for this run
+        let _ = fs::remove_file(dir.join("egraph_diffs.jsonl"));
+        SnapshotRecorder {
+            dir: dir,
+            full: full,
+            last: None,
+            diffs: vec![],
+        }
+    }
+
+    /// Takes a snapshot and records the diff to the previous one. Call it
+    /// before each iteration (it is a runner hook) and after the last one
+    pub fn record(&mut self, runner: &Runner<Mdl, TensorAnalysis, ()>) -> Result<(), String> {
+        let snapshot = EGraphSnapshot::take(&runner.egraph);
+        let iteration = runner.iterations.len();
+        if self.full {
+            let filename = self.dir.join(format!("egraph_snapshot_{}.json", iteration));
+            fs::write(filename, serde_json::to_string(&snapshot).unwrap()).map_err(|e| e.to_string())?;
+        }
+        if let Some(last) = &self.last {
+            let mut diff = diff_snapshots(last, &snapshot);
+            diff.iteration = iteration - 1;
+            diff.applied = runner.iterations[iteration - 1]
+                .applied
+                .iter()
+                .map(|(rule, n)| (rule.to_string(), *n))
+                .collect();
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join("egraph_diffs.jsonl"))
+                .map_err(|e| e.to_string())?;
+            writeln!(file, "{}", serde_json::to_string(&diff).unwrap()).map_err(|e| e.to_string())?;
+            self.diffs.push(diff);
+        }
+        self.last = Some(snapshot);
+        Ok(())
+    }
+}
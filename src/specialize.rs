@@ -0,0 +1,146 @@
+//! How a graph optimized at one input shape performs at nearby shapes
+//!
+//! Ops are measured at the shapes of the input graph, so a rewrite can win
+//! only at those shapes, e.g. merging convolutions that fill the GPU only
+//! at a large batch size. The starting and the optimized graph are rebuilt
+//! at other batch sizes and measured again, and a batch size where the
+//! optimized graph is the slower one shows that the optimization is
+//! specialized to the tuned shape.
+
+use crate::model::*;
+use crate::names::{has_symbolic_batch, parse_dims, parse_tensor_name, validate_names};
+use crate::utils::get_full_graph_runtime;
+use crate::validate::validate_graph;
+use egg::*;
+use itertools::Itertools;
+use serde::Serialize;
+
+/// Runtimes of the starting and optimized graph at one batch size
+#[derive(Debug, Clone, Serialize)]
+pub struct ShapePoint {
+    pub batch: i32,
+    pub start_runtime: f32,
+    pub optimized_runtime: f32,
+    /// start_runtime / optimized_runtime
+    pub speedup: f32,
+}
+
+/// Gets the batch size of a graph, the first dimension of its first input
+pub fn batch_size(expr: &RecExpr<Mdl>) -> Option<i32> {
+    let nodes = expr.as_ref();
+    nodes.iter().find_map(|node| match node {
+        Mdl::Input([name]) => match &nodes[usize::from(*name)] {
+            Mdl::Var(s) => parse_tensor_name(s.as_str()).ok()?.dims.first().cloned(),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// If the inputs of a graph have the symbolic batch dimension (see names.rs)
+pub fn is_batch_symbolic(expr: &RecExpr<Mdl>) -> bool {
+    let nodes = expr.as_ref();
+    nodes.iter().any(|node| match node {
+        Mdl::Input([name]) => match &nodes[usize::from(*name)] {
+            Mdl::Var(s) => parse_tensor_name(s.as_str()).map_or(false, |t| t.symbolic_batch),
+            _ => false,
+        },
+        _ => false,
+    })
+}
+
+/// Replaces the first dimension of dims if it is the batch size
+fn rebatch(dims: &[i32], from: i32, to: i32) -> Vec<i32> {
+    let mut dims = dims.to_vec();
+    if dims.first() == Some(&from) {
+        dims[0] = to;
+    }
+    dims
+}
+
+/// Rebuilds a graph at another batch size
+///
+/// The first dimension of the inputs, and of the shapes of reshapes and
+/// opaque ops that start with the batch size, is set to batch. This covers
+/// graphs that keep the batch dimension in front. In a graph with a symbolic
+/// batch dimension, exactly the symbolic dimensions are set.
+///
+/// # Returns
+///
+/// The graph, or why it is not valid at that batch size
+pub fn with_batch_size(expr: &RecExpr<Mdl>, batch: i32) -> Result<RecExpr<Mdl>, String> {
+    let from = batch_size(expr).ok_or("the graph has no inputs")?;
+    let symbolic = is_batch_symbolic(expr);
+    let resize = |dims: &[i32], is_symbolic: bool| match (symbolic, is_symbolic) {
+        (true, true) => rebatch(dims, dims[0], batch),
+        (true, false) => dims.to_vec(),
+        (false, _) => rebatch(dims, from, batch),
+    };
+    let nodes = expr.as_ref();
+    // How each Var is used, so that only shapes are changed
+    let mut is_tensor_name = vec![false; nodes.len()];
+    let mut is_shape = vec![false; nodes.len()];
+    for node in nodes.iter() {
+        match node {
+            Mdl::Input([name]) | Mdl::Opaque([name, _, _]) => is_tensor_name[usize::from(*name)] = true,
+            Mdl::Reshape([_, shape]) => is_shape[usize::from(*shape)] = true,
+            _ => (),
+        }
+    }
+    let mut res = Vec::with_capacity(nodes.len());
+    for (i, node) in nodes.iter().enumerate() {
+        let node = match node {
+            Mdl::Var(s) if is_tensor_name[i] => {
+                let t = parse_tensor_name(s.as_str())?;
+                let mut name = format!("{}@{}", t.label, resize(&t.dims, t.symbolic_batch).iter().join("_"));
+                if let Some(density) = t.density {
+                    name = format!("{}@{}", name, density);
+                }
+                Mdl::Var(Symbol::from(name))
+            }
+            Mdl::Var(s) if is_shape[i] => {
+                let dims = parse_dims(s.as_str())?;
+                Mdl::Var(Symbol::from(resize(&dims, has_symbolic_batch(s.as_str())).iter().join("_")))
+            }
+            other => other.clone(),
+        };
+        res.push(node);
+    }
+    let res = RecExpr::from(res);
+    validate_names(&res).map_err(|errors| format!("{} invalid names", errors.len()))?;
+    validate_graph(&res).map_err(|errors| errors.join("; "))?;
+    Ok(res)
+}
+
+/// Measures the starting and optimized graph at another batch size
+///
+/// # Returns
+///
+/// The runtimes, or why the graphs are not valid at that batch size
+pub fn measure_at_batch(start: &RecExpr<Mdl>, optimized: &RecExpr<Mdl>, batch: i32) -> Result<ShapePoint, String> {
+    let start = with_batch_size(start, batch)?;
+    let optimized = with_batch_size(optimized, batch)?;
+    let runner_start = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&start);
+    let start_runtime = get_full_graph_runtime(&runner_start, false);
+    let runner_optimized = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&optimized);
+    let optimized_runtime = get_full_graph_runtime(&runner_optimized, true);
+    Ok(ShapePoint {
+        batch: batch,
+        start_runtime: start_runtime,
+        optimized_runtime: optimized_runtime,
+        speedup: start_runtime / optimized_runtime,
+    })
+}
+
+/// Checks that a graph with a symbolic batch dimension is valid at other
+/// batch sizes, its shapes having been computed at one batch size only
+///
+/// # Returns
+///
+/// The batch sizes the graph is not valid at, with why
+pub fn check_batch_sizes(expr: &RecExpr<Mdl>, batches: &[i32]) -> Vec<(i32, String)> {
+    batches
+        .iter()
+        .filter_map(|batch| with_batch_size(expr, *batch).err().map(|e| (*batch, e)))
+        .collect()
+}
@@ -0,0 +1,70 @@
+//! Saturation and extraction as separate stages (modes saturate and extract)
+//!
+//! Mode saturate runs the saturation of mode optimize and writes an artifact
+//! directory: the saturated EGraph (egraph.json, see checkpoint.rs), the
+//! input graph (input.txt, in the textual format) and the settings of the
+//! run (settings.json). Mode extract restores the EGraph from the artifact
+//! and runs the rest of mode optimize, so different objectives, targets and
+//! solvers can be tried on one expensive saturation. The settings that
+//! change the EGraph (EGRAPH_SETTINGS) have to be the same in both stages.
+
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Which stages of the optimization a run does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Saturation and extraction, mode optimize
+    All,
+    Saturate,
+    Extract,
+}
+
+/// Settings that change the saturated EGraph, or how it is restored
+pub const EGRAPH_SETTINGS: &[&str] = &["packed_attrs", "batch_size", "seed", "weights_file", "weight_layouts"];
+
+pub fn artifact_egraph(dir: &str) -> PathBuf {
+    Path::new(dir).join("egraph.json")
+}
+
+pub fn artifact_input(dir: &str) -> PathBuf {
+    Path::new(dir).join("input.txt")
+}
+
+pub fn artifact_settings(dir: &str) -> PathBuf {
+    Path::new(dir).join("settings.json")
+}
+
+/// Writes the input graph and the settings of the saturation to an artifact,
+/// the EGraph is saved with EGraphCheckpoint::save
+pub fn save_artifact(dir: &str, input: &str, settings: &Map<String, Value>) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Could not create {}: {}", dir, e))?;
+    let write = |file: PathBuf, contents: String| {
+        fs::write(&file, contents).map_err(|e| format!("Could not write {}: {}", file.display(), e))
+    };
+    write(artifact_input(dir), input.to_string())?;
+    write(artifact_settings(dir), serde_json::to_string(settings).unwrap())
+}
+
+/// Loads the settings of the saturation of an artifact
+pub fn load_artifact_settings(dir: &str) -> Result<Map<String, Value>, String> {
+    let file = artifact_settings(dir);
+    let s = fs::read_to_string(&file).map_err(|e| format!("Could not read {}: {}", file.display(), e))?;
+    serde_json::from_str(&s).map_err(|e| format!("Could not parse {}: {}", file.display(), e))
+}
+
+/// Compares the settings that change the EGraph between the saturation and
+/// the extraction
+///
+/// # Returns
+///
+/// A message for each setting that differs
+pub fn setting_mismatches(saturated: &Map<String, Value>, current: &Map<String, Value>) -> Vec<String> {
+    let show = |value: Option<&Value>| value.map_or(String::from("not set"), |v| v.to_string());
+    EGRAPH_SETTINGS
+        .iter()
+        .filter(|name| saturated.get(**name) != current.get(**name))
+        .map(|name| format!("{} is {} but was {} in the saturation", name, show(current.get(*name)), show(saturated.get(*name))))
+        .collect()
+}
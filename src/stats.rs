@@ -0,0 +1,124 @@
+//! Statistics of an optimize run, written as one JSON file (--stats_out)
+//!
+//! RunStats is filled in as the run goes: the saturation from the runner,
+//! then the extraction and the costs. Phases that did not run are left out
+//! of phase_secs, costs that were not computed are null.
+
+use crate::model::*;
+use crate::resources::ResourceUsage;
+use egg::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+
+/// Statistics of one iteration of the saturation
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IterationStats {
+    pub num_enodes: usize,
+    pub num_classes: usize,
+    /// Number of applications of each rule applied in the iteration
+    pub applied: BTreeMap<String, usize>,
+    pub search_secs: f64,
+    pub apply_secs: f64,
+    pub rebuild_secs: f64,
+    pub total_secs: f64,
+}
+
+/// Statistics of a run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunStats {
+    pub iterations: Vec<IterationStats>,
+    pub stop_reason: String,
+    /// If the saturation was stopped with Ctrl-C
+    pub interrupted: bool,
+    pub num_enodes: usize,
+    pub num_classes: usize,
+    /// Number of applications of each rule, over all iterations
+    pub rule_applications: BTreeMap<String, usize>,
+    /// Number of enodes blacklisted so that the extracted graph has no
+    /// cycles (see remove_cycle_by_order)
+    pub num_cycles_filtered: usize,
+    /// Number of enodes pruned from explosive eclasses (see prune.rs)
+    pub num_pruned: usize,
+    /// Seconds of each phase that ran: saturation, cycle_filter,
+    /// cost_population (see CostModel::populate_runtimes), extraction and
+    /// ilp_solve (the solver alone, for the ILP extractors)
+    pub phase_secs: BTreeMap<String, f32>,
+    pub extract_mode: String,
+    /// Cost of the original and the extracted graph in the cost model,
+    /// counting shared nodes once
+    pub original_cost: Option<f32>,
+    pub optimized_cost: Option<f32>,
+    /// Runtimes of the original and the optimized graph measured by TASO
+    pub original_runtime: Option<f32>,
+    pub optimized_runtime: Option<f32>,
+    /// Sum of the runtimes of the ops of the optimized graph, to compare with
+    /// its measured runtime
+    pub optimized_runtime_estimate: Option<f32>,
+    /// Lower bound the ILP solver proved on the cost of the extracted graph
+    pub lower_bound: Option<f32>,
+    /// Resources used by the run, with --resource_summary
+    pub resources: Option<ResourceUsage>,
+}
+
+impl RunStats {
+    /// Records the saturation of a runner
+    ///
+    /// # Parameters
+    ///
+    /// - `runner`: the runner, after saturation
+    /// - `saturation_secs`: how long the saturation took
+    pub fn record_saturation(&mut self, runner: &Runner<Mdl, TensorAnalysis, ()>, saturation_secs: f32) {
+        self.iterations = runner
+            .iterations
+            .iter()
+            .map(|it| IterationStats {
+                num_enodes: it.egraph_nodes,
+                num_classes: it.egraph_classes,
+                applied: it.applied.iter().map(|(rule, n)| (rule.to_string(), *n)).collect(),
+                search_secs: it.search_time,
+                apply_secs: it.apply_time,
+                rebuild_secs: it.rebuild_time,
+                total_secs: it.total_time,
+            })
+            .collect();
+        self.rule_applications.clear();
+        for it in self.iterations.iter() {
+            for (rule, n) in it.applied.iter() {
+                *self.rule_applications.entry(rule.clone()).or_insert(0) += n;
+            }
+        }
+        self.stop_reason = format!("{:?}", runner.stop_reason.as_ref().unwrap());
+        self.num_enodes = runner.egraph.total_size();
+        self.num_classes = runner.egraph.number_of_classes();
+        self.phase_secs.insert(String::from("saturation"), saturation_secs);
+    }
+
+    pub fn record_phase(&mut self, phase: &str, secs: f32) {
+        self.phase_secs.insert(phase.to_string(), secs);
+    }
+
+    /// Summarizes whether the run was worth it: the measured runtime of the
+    /// original graph, the estimated and the measured runtime of the
+    /// optimized graph, and the lower bound of the solver, as a table
+    pub fn runtime_summary(&self) -> String {
+        let show = |v: Option<f32>| v.map_or(String::from("-"), |v| format!("{:.4}", v));
+        let mut table = format!("{:<36} {:>12}\n", "", "runtime (ms)");
+        table += &format!("{:<36} {:>12}\n", "Original graph, measured", show(self.original_runtime));
+        table += &format!("{:<36} {:>12}\n", "Optimized graph, estimated", show(self.optimized_runtime_estimate));
+        table += &format!("{:<36} {:>12}\n", "Optimized graph, measured", show(self.optimized_runtime));
+        table += &format!("{:<36} {:>12}\n", "Lower bound of the solver (cost)", show(self.lower_bound));
+        let verdict = match (self.original_runtime, self.optimized_runtime) {
+            (Some(original), Some(optimized)) if optimized > 0.0 && original > optimized => {
+                format!("Speedup {:.3}x, {:.1}% faster", original / optimized, 100.0 * (1.0 - optimized / original))
+            }
+            (Some(_), Some(_)) => String::from("No speedup: the optimized graph is not faster than the original"),
+            _ => String::from("No speedup measured"),
+        };
+        table + &verdict
+    }
+
+    pub fn save(&self, filename: &str) {
+        fs::write(filename, serde_json::to_string_pretty(self).unwrap()).expect("Unable to write file");
+    }
+}
@@ -0,0 +1,77 @@
+//! Reading the input graph from stdin and writing the optimized graph to
+//! stdout, with `-` as the path
+//!
+//! tensat prints its progress to stdout, which would end up in the graph
+//! written there. So when the output goes to stdout, stdout is redirected to
+//! stderr before anything is printed (see redirect_stdout), and the graph is
+//! written to the original stdout. This uses Unix system calls.
+
+use once_cell::sync::Lazy;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The path standing for stdin or stdout
+pub const STDIO: &str = "-";
+
+extern "C" {
+    fn dup(fd: i32) -> i32;
+    fn dup2(old_fd: i32, new_fd: i32) -> i32;
+}
+
+/// The original stdout, once it is redirected
+static OUTPUT: Lazy<Mutex<Option<File>>> = Lazy::new(Default::default);
+
+/// Redirects stdout to stderr, keeping the original stdout for write_output.
+/// Call it before printing anything
+pub fn redirect_stdout() {
+    let mut output = OUTPUT.lock().unwrap();
+    if output.is_some() {
+        return;
+    }
+    io::stdout().flush().unwrap();
+    unsafe {
+        let fd = dup(1);
+        assert!(fd >= 0 && dup2(2, 1) >= 0, "Could not redirect stdout");
+        *output = Some(File::from_raw_fd(fd));
+    }
+}
+
+/// Reads a file, or stdin for `-`
+pub fn read_input(path: &str) -> io::Result<Vec<u8>> {
+    if path == STDIO {
+        let mut bytes = vec![];
+        io::stdin().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else {
+        fs::read(path)
+    }
+}
+
+/// Reads a text file, or stdin for `-`
+pub fn read_input_string(path: &str) -> io::Result<String> {
+    String::from_utf8(read_input(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes a file in a directory, or to stdout for `-`
+///
+/// # Parameters
+///
+/// - `path`: the file, relative to dir, or `-`
+/// - `dir`: the directory
+/// - `contents`: what to write
+pub fn write_output(path: &str, dir: &str, contents: &[u8]) -> io::Result<()> {
+    if path != STDIO {
+        return fs::write(Path::new(dir).join(path), contents);
+    }
+    match OUTPUT.lock().unwrap().as_mut() {
+        Some(file) => file.write_all(contents).and_then(|_| file.flush()),
+        None => {
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            handle.write_all(contents).and_then(|_| handle.flush())
+        }
+    }
+}
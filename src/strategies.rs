@@ -0,0 +1,68 @@
+//! Extraction strategies registered by users at runtime
+//!
+//! An extraction algorithm the crate does not have, e.g. a research
+//! prototype, can be registered under a name instead of adding a variant to
+//! ExtractionStrategy. It is then selected like the built-in ones, by
+//! ExtractionStrategy::Custom with its name, or with `--extract <name>` in a
+//! binary that registered it before parsing its arguments. A registered
+//! strategy gets the costs of the enodes from the cost model of the run.
+
+use crate::model::*;
+use crate::optimize::CostModel;
+use egg::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// An extraction algorithm
+pub trait CustomStrategy: Send + Sync {
+    /// Extracts a graph from an EGraph
+    ///
+    /// # Parameters
+    ///
+    /// - `egraph`: the EGraph to extract from
+    /// - `root`: its root
+    /// - `cost_model`: the costs of the enodes (see CostModel::get_self_cost)
+    ///
+    /// # Returns
+    ///
+    /// The extracted graph and its cost
+    fn extract(&self, egraph: &EGraph<Mdl, TensorAnalysis>, root: Id, cost_model: &CostModel) -> (RecExpr<Mdl>, f32);
+}
+
+/// Names of the extraction strategies of the crate (see the extract flag)
+pub const BUILTIN_STRATEGIES: &[&str] = &["greedy", "beam", "ilp", "cbc", "egg_ilp"];
+
+static CUSTOM_STRATEGIES: Lazy<RwLock<HashMap<String, Arc<dyn CustomStrategy>>>> = Lazy::new(Default::default);
+
+/// Registers an extraction strategy, for the whole process
+///
+/// # Returns
+///
+/// Ok, or why the strategy can not be registered
+pub fn register_strategy(name: &str, strategy: Box<dyn CustomStrategy>) -> Result<(), String> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("invalid strategy name {}", name));
+    }
+    if BUILTIN_STRATEGIES.contains(&name) {
+        return Err(format!("{} is a strategy of the crate", name));
+    }
+    let mut strategies = CUSTOM_STRATEGIES.write().unwrap();
+    if strategies.contains_key(name) {
+        return Err(format!("{} is already registered", name));
+    }
+    strategies.insert(name.to_string(), Arc::from(strategy));
+    Ok(())
+}
+
+/// Gets a registered strategy by name
+pub fn custom_strategy(name: &str) -> Option<Arc<dyn CustomStrategy>> {
+    CUSTOM_STRATEGIES.read().unwrap().get(name).cloned()
+}
+
+/// Gets the names of the registered strategies, sorted
+pub fn custom_strategy_names() -> Vec<String> {
+    let mut names: Vec<String> = CUSTOM_STRATEGIES.read().unwrap().keys().cloned().collect();
+    names.sort();
+    names
+}
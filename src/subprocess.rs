@@ -0,0 +1,180 @@
+//! Running solvers (the ILP scripts, the ONNX conversion script) as
+//! subprocesses with limits
+//!
+//! A solver gets limits on its CPU time and its address space (set with
+//! setrlimit in the child, so it is killed by the kernel when it goes over),
+//! and is killed after a wall-clock timeout. It does not outlive a cancelled
+//! run: on Ctrl-C or SIGTERM a signal handler kills the running solvers
+//! before exiting, and on any other death of tensat the kernel kills them
+//! (PR_SET_PDEATHSIG). This uses Linux system calls.
+//!
+//! While saturating, Ctrl-C does not exit but stops the run gracefully (see
+//! set_graceful_interrupt): the runner stops at the next iteration, and the
+//! EGraph so far is extracted and written out. A second Ctrl-C exits.
+
+use once_cell::sync::Lazy;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Once, RwLock};
+use std::time::{Duration, Instant};
+
+const SIGINT: i32 = 2;
+const SIGKILL: i32 = 9;
+const SIGTERM: i32 = 15;
+const RLIMIT_CPU: i32 = 0;
+const RLIMIT_AS: i32 = 9;
+const PR_SET_PDEATHSIG: i32 = 1;
+
+#[repr(C)]
+struct RLimit {
+    rlim_cur: u64,
+    rlim_max: u64,
+}
+
+extern "C" {
+    fn setrlimit(resource: i32, rlim: *const RLimit) -> i32;
+    fn prctl(option: i32, ...) -> i32;
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    fn kill(pid: i32, sig: i32) -> i32;
+    fn _exit(status: i32) -> !;
+}
+
+/// Limits of a solver, None for no limit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SolverLimits {
+    pub cpu_secs: Option<u64>,
+    pub memory_mb: Option<u64>,
+    pub wall_secs: Option<u64>,
+}
+
+static LIMITS: Lazy<RwLock<SolverLimits>> = Lazy::new(Default::default);
+
+/// Maximum number of solvers running at once that are killed on Ctrl-C
+const MAX_SOLVERS: usize = 16;
+
+/// Process ids of the running solvers, 0 for a free slot. Atomics, so that
+/// the signal handler can read them
+static SOLVERS: [AtomicI32; MAX_SOLVERS] = [
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+];
+
+static INSTALL_HANDLERS: Once = Once::new();
+
+/// If Ctrl-C only sets INTERRUPTED
+static GRACEFUL: AtomicBool = AtomicBool::new(false);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the limits of the solvers run after it
+pub fn set_solver_limits(limits: SolverLimits) {
+    *LIMITS.write().unwrap() = limits;
+}
+
+pub fn solver_limits() -> SolverLimits {
+    *LIMITS.read().unwrap()
+}
+
+/// Sets if Ctrl-C stops the run gracefully, instead of exiting. Check
+/// interrupted to stop
+pub fn set_graceful_interrupt(graceful: bool) {
+    install_handlers();
+    GRACEFUL.store(graceful, Ordering::SeqCst);
+}
+
+/// If Ctrl-C was pressed while the run could stop gracefully
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+extern "C" fn on_signal(sig: i32) {
+    for slot in SOLVERS.iter() {
+        let pid = slot.load(Ordering::SeqCst);
+        if pid > 0 {
+            unsafe {
+                kill(pid, SIGKILL);
+            }
+        }
+    }
+    if sig == SIGINT && GRACEFUL.load(Ordering::SeqCst) && !INTERRUPTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    unsafe { _exit(128 + sig) }
+}
+
+fn install_handlers() {
+    INSTALL_HANDLERS.call_once(|| unsafe {
+        signal(SIGINT, on_signal);
+        signal(SIGTERM, on_signal);
+    });
+}
+
+/// Runs a solver until it exits, within the limits set by set_solver_limits
+///
+/// The solver inherits stdout and stderr, as with Command::status.
+///
+/// # Returns
+///
+/// The exit status of the solver, or why it could not be run or was killed
+/// after the wall-clock timeout
+pub fn run_solver(command: &mut Command) -> Result<ExitStatus, String> {
+    install_handlers();
+    let limits = solver_limits();
+    unsafe {
+        command.pre_exec(move || {
+            prctl(PR_SET_PDEATHSIG, SIGKILL as u64);
+            if let Some(secs) = limits.cpu_secs {
+                setrlimit(RLIMIT_CPU, &RLimit { rlim_cur: secs, rlim_max: secs + 1 });
+            }
+            if let Some(mb) = limits.memory_mb {
+                let bytes = mb * 1024 * 1024;
+                setrlimit(RLIMIT_AS, &RLimit { rlim_cur: bytes, rlim_max: bytes });
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().map_err(|e| format!("failed to start the solver: {}", e))?;
+
+    let slot = SOLVERS
+        .iter()
+        .find(|slot| slot.compare_exchange(0, child.id() as i32, Ordering::SeqCst, Ordering::SeqCst).is_ok());
+    if slot.is_none() {
+        println!("Warning: more than {} solvers running, solver {} is not killed on Ctrl-C", MAX_SOLVERS, child.id());
+    }
+
+    let start = Instant::now();
+    let result = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) => (),
+            Err(e) => break Err(format!("failed to wait for the solver: {}", e)),
+        }
+        if let Some(secs) = limits.wall_secs {
+            if start.elapsed() > Duration::from_secs(secs) {
+                let _ = child.kill();
+                let _ = child.wait();
+                break Err(format!("the solver was killed after {}s", secs));
+            }
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    if let Some(slot) = slot {
+        slot.store(0, Ordering::SeqCst);
+    }
+    result
+}
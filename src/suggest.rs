@@ -0,0 +1,151 @@
+//! Suggesting local substitutions instead of rewriting the graph (mode suggest)
+//!
+//! A team that can not swap its serving graph for the optimized one can
+//! still apply the best rewrites by hand, in its model code. Every rule
+//! matching at a node of the graph is applied there alone (see
+//! whatif::what_if), and each rewrite lowering the cost becomes a suggestion:
+//! the layers it replaces, named as in the profiles (see
+//! provenance::default_layer_names), the ops replacing them, and the
+//! estimated gain. Suggestions making the same substitution are merged, and
+//! they are ranked by gain. Gains do not add up: two suggestions can replace
+//! the same layers.
+
+use crate::model::*;
+use crate::optimize::CostModel;
+use crate::provenance::default_layer_names;
+use crate::whatif::{matching_rules_at_nodes, what_if};
+use egg::*;
+use std::collections::{HashMap, HashSet};
+
+/// A substitution of some layers of a graph by other ops
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    /// Rule making the substitution
+    pub rule: String,
+    /// Index of the node the rule was applied at
+    pub node: usize,
+    /// Names of the layers replaced, in the order of the graph
+    pub replaced: Vec<String>,
+    /// Ops replacing them, in the order of the rewritten graph
+    pub added: Vec<String>,
+    /// Change in cost, negative
+    pub delta: f32,
+}
+
+impl Suggestion {
+    /// Describes the substitution, e.g. "replace conv2d_3, relu_4 with
+    /// conv2d, est. -0.800 ms"
+    pub fn describe(&self, unit: &str) -> String {
+        format!(
+            "replace {} with {}, est. {:.3} {} ({} at node {})",
+            self.replaced.join(", "),
+            if self.added.is_empty() { String::from("nothing") } else { self.added.join(", ") },
+            self.delta,
+            unit,
+            self.rule,
+            self.node
+        )
+    }
+}
+
+/// Numbers the distinct subgraphs of graphs, so that equal subgraphs of two
+/// graphs get the same number
+#[derive(Default)]
+struct SubgraphTable {
+    numbers: HashMap<Mdl, usize>,
+}
+
+impl SubgraphTable {
+    /// Gets the number of the subgraph rooted at each node of a graph
+    fn number(&mut self, expr: &RecExpr<Mdl>) -> Vec<usize> {
+        let mut numbers: Vec<usize> = Vec::with_capacity(expr.as_ref().len());
+        for node in expr.as_ref() {
+            let key = node.clone().map_children(|c| Id::from(numbers[usize::from(c)]));
+            let next = self.numbers.len();
+            numbers.push(*self.numbers.entry(key).or_insert(next));
+        }
+        numbers
+    }
+}
+
+/// If a node is an op, as opposed to a leaf or the noops combining the
+/// outputs
+fn is_op(node: &Mdl) -> bool {
+    !matches!(node, Mdl::Num(_) | Mdl::Var(_) | Mdl::Input(_) | Mdl::Weight(_) | Mdl::Noop(_))
+}
+
+/// Gets the ops of a graph that are not in another one
+///
+/// # Returns
+///
+/// The indices of the ops of `before` that are not in `after`, and of the
+/// ops of `after` that are not in `before`
+pub fn changed_ops(before: &RecExpr<Mdl>, after: &RecExpr<Mdl>) -> (Vec<usize>, Vec<usize>) {
+    let mut table = SubgraphTable::default();
+    let numbers_before = table.number(before);
+    let numbers_after = table.number(after);
+    let set_before: HashSet<usize> = numbers_before.iter().cloned().collect();
+    let set_after: HashSet<usize> = numbers_after.iter().cloned().collect();
+    let only = |expr: &RecExpr<Mdl>, numbers: &[usize], other: &HashSet<usize>| {
+        expr.as_ref()
+            .iter()
+            .enumerate()
+            .filter(|(i, node)| is_op(node) && !other.contains(&numbers[*i]))
+            .map(|(i, _)| i)
+            .collect::<Vec<usize>>()
+    };
+    (
+        only(before, &numbers_before, &set_after),
+        only(after, &numbers_after, &set_before),
+    )
+}
+
+/// Gets the substitutions lowering the cost of a graph
+///
+/// # Parameters
+///
+/// - `expr`: the graph
+/// - `rewrites`: the rules, see rules_from_str
+/// - `cost_model`: the cost model for the costs of the graphs
+/// - `min_gain`: smallest decrease in cost of a suggestion
+///
+/// # Returns
+///
+/// The suggestions, by decreasing gain
+pub fn suggest_substitutions(
+    expr: &RecExpr<Mdl>,
+    rewrites: &[Rewrite<Mdl, TensorAnalysis>],
+    cost_model: &CostModel,
+    min_gain: f32,
+) -> Vec<Suggestion> {
+    let names = default_layer_names(expr);
+    let name = |i: usize| names.get(&Id::from(i)).cloned().unwrap_or_else(|| expr.as_ref()[i].to_string());
+    // The best suggestion for each substitution
+    let mut best: HashMap<(Vec<String>, Vec<String>), Suggestion> = HashMap::new();
+    for (node, rules) in matching_rules_at_nodes(expr, rewrites).into_iter().enumerate() {
+        for rule in rules.iter() {
+            let result = match what_if(expr, rewrites, rule, node, cost_model) {
+                Ok(result) => result,
+                Err(_) => continue,
+            };
+            if result.delta() + min_gain >= 0.0 {
+                continue;
+            }
+            let (removed, added) = changed_ops(expr, &result.expr);
+            let suggestion = Suggestion {
+                rule: rule.clone(),
+                node: node,
+                replaced: removed.into_iter().map(name).collect(),
+                added: added.into_iter().map(|i| result.expr.as_ref()[i].to_string()).collect(),
+                delta: result.delta(),
+            };
+            let key = (suggestion.replaced.clone(), suggestion.added.clone());
+            if best.get(&key).map_or(true, |other| suggestion.delta < other.delta) {
+                best.insert(key, suggestion);
+            }
+        }
+    }
+    let mut suggestions: Vec<Suggestion> = best.into_values().collect();
+    suggestions.sort_by(|a, b| a.delta.partial_cmp(&b.delta).unwrap().then_with(|| a.node.cmp(&b.node)));
+    suggestions
+}
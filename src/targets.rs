@@ -0,0 +1,112 @@
+//! Optimizing for several targets over one saturation (--targets)
+//!
+//! Saturation does not depend on the target, only the extraction does, so
+//! one saturated EGraph serves a server GPU, an edge GPU and a CPU alike.
+//! Each target is a device profile (see device.rs), and its graph is
+//! extracted with the analytical runtimes of the profile, or with the
+//! runtimes measured on the local GPU for the target `local`. The other
+//! settings of the cost model (objective, weights) are those of the run.
+
+use crate::attrs::unpack_expr;
+use crate::cost::{AnalyticalRuntime, MeasuredRuntime, RuntimeModel};
+use crate::device::DeviceProfile;
+use crate::extract::{extract, ExtractionStrategy};
+use crate::model::*;
+use crate::optimize::CostModel;
+use egg::*;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// A target to optimize for
+pub enum Target {
+    /// The local GPU, with measured runtimes
+    Local,
+    Profile(DeviceProfile),
+}
+
+impl Target {
+    pub fn name(&self) -> String {
+        match self {
+            Target::Local => String::from("local"),
+            Target::Profile(profile) => profile.name.clone(),
+        }
+    }
+
+    fn runtime_model(&self) -> Box<dyn RuntimeModel> {
+        match self {
+            Target::Local => Box::new(MeasuredRuntime),
+            Target::Profile(profile) => Box::new(AnalyticalRuntime {
+                peak_tflops: profile.peak_tflops,
+                gb_per_sec: profile.gb_per_sec,
+            }),
+        }
+    }
+}
+
+/// The graph extracted for a target
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetResult {
+    pub target: String,
+    /// Cost of the graph with the runtimes of the target
+    pub cost: f32,
+    pub num_nodes: usize,
+    pub secs: f32,
+    /// File the graph is written to, in the output directory
+    pub file: String,
+}
+
+/// Gets the file the graph of a target is written to, its name with
+/// characters other than letters and digits replaced by _
+pub fn target_file_name(target: &str) -> String {
+    let name: String = target
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    format!("optimized_{}.txt", name)
+}
+
+/// Extracts a graph for each target from the same EGraph
+///
+/// # Parameters
+///
+/// - `egraph`, `root`: the saturated EGraph
+/// - `targets`: the targets to extract for
+/// - `cost_model`: makes the cost model of the run with the runtime model of
+///   a target
+/// - `strategy`: the extraction strategy
+/// - `packed_attrs`: whether the EGraph has packed attributes, the graphs are
+///   written unpacked
+/// - `dir`: directory to write the graphs to
+///
+/// # Returns
+///
+/// The graph extracted for each target
+pub fn extract_for_targets<F: Fn(Box<dyn RuntimeModel>) -> CostModel>(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    root: Id,
+    targets: &[Target],
+    cost_model: F,
+    strategy: &ExtractionStrategy,
+    packed_attrs: bool,
+    dir: &Path,
+) -> Vec<TargetResult> {
+    targets
+        .iter()
+        .map(|target| {
+            let mut target_model = cost_model(target.runtime_model());
+            target_model.populate_runtimes(egraph);
+            let (best, cost, secs) = extract(egraph, root, &target_model, strategy);
+            let best = if packed_attrs { unpack_expr(&best) } else { best };
+            let file = target_file_name(&target.name());
+            fs::write(dir.join(&file), best.to_string()).expect("Unable to write file");
+            TargetResult {
+                target: target.name(),
+                cost: cost,
+                num_nodes: best.as_ref().len(),
+                secs: secs,
+                file: file,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,65 @@
+// Creates and frees the C++ side of the TASO graphs of the analysis (see
+// ffi::TasoGraph)
+//
+// TASO's Graph and Model declare no destructors, their implicit ones are
+// inline, so the bindings have no symbols to call. These functions are
+// compiled by build.rs against the header the bindings are generated from.
+
+#include "../wrapper.h"
+
+using namespace taso;
+
+// Deletes the ops a cache of a Model owns
+template <typename Cache>
+static void delete_ops(Cache& cache)
+{
+  for (auto& it : cache)
+    delete it.second;
+  cache.clear();
+}
+
+extern "C" Graph* tensat_new_graph()
+{
+  return new Graph();
+}
+
+// Deletes a graph created by tensat_new_graph, with the Model it allocated
+// in its constructor and the ops the Model caches
+extern "C" void tensat_free_graph(Graph* graph)
+{
+  Model* model = graph->model;
+  delete_ops(model->activation);
+  delete_ops(model->batchnorm);
+  delete_ops(model->cast);
+  delete_ops(model->concat);
+  delete_ops(model->constant);
+  delete_ops(model->conv2d);
+  delete_ops(model->element);
+  delete_ops(model->element_unary);
+  delete_ops(model->enlarge);
+  delete_ops(model->matmul);
+  delete_ops(model->merge_gconv);
+  delete_ops(model->mul);
+  delete_ops(model->noop);
+  delete_ops(model->pad);
+  delete_ops(model->pool2d);
+  delete_ops(model->reduce);
+  delete_ops(model->reshape);
+  delete_ops(model->resize);
+  delete_ops(model->shape);
+  delete_ops(model->slice);
+  delete_ops(model->split);
+  delete_ops(model->squeeze);
+  delete_ops(model->topk);
+  delete_ops(model->transpose);
+  delete_ops(model->unsqueeze);
+  delete_ops(model->where);
+  delete model;
+  delete graph;
+}
+
+// Deletes a tensor returned by a function of Graph, which allocates it
+extern "C" void tensat_free_tensor(Tensor* tensor)
+{
+  delete tensor;
+}
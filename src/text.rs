@@ -0,0 +1,567 @@
+//! Human readable textual format for graphs
+//!
+//! The s-expression format of RecExpr is positional, e.g. a conv is
+//! `(conv2d 1 1 0 2 ?x ?w)`. This format has one op per line, with named
+//! attributes and the inferred shapes as comments:
+//!
+//! ```text
+//! # tensat graph
+//! t1 = input(name=input_0, dims=[1,64,56,56])  # [1, 64, 56, 56]
+//! t3 = weight(name=w_0, dims=[64,64,3,3])  # [64, 64, 3, 3]
+//! t9 = conv2d(t1, t3, stride_h=1, stride_w=1, padding=SAME, activation=RELU)  # [1, 64, 56, 56]
+//! return t9
+//! ```
+//!
+//! Names and scalars are written as attributes of the ops using them. The
+//! parser is strict: every attribute has to be given exactly once, tensors
+//! have to be defined before they are used, and unknown ops or attributes are
+//! errors. Ops registered at runtime (see custom.rs) are accepted too, and
+//! other custom nodes are written as `custom(<inputs>, label=<label>)`.
+
+use crate::custom::*;
+use crate::model::*;
+use crate::names::{tensor_name, validate_names, BATCH_DIM};
+use crate::shape::TensorShape;
+use egg::*;
+use std::collections::{HashMap, HashSet};
+
+/// First line of a graph in the textual format
+pub const HEADER: &str = "# tensat graph";
+
+/// Kind of an argument of an op
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Param {
+    /// A tensor input, given positionally
+    Tensor,
+    /// An integer attribute
+    Int(&'static str),
+    /// A padding mode attribute, SAME or VALID
+    Padding,
+    /// An activation mode attribute, NONE, SIGMOID, RELU or TANH
+    Activation,
+    /// A shuffle attribute for transpose, true or false
+    Shuffle,
+    /// A list of integers, stored as a name of the format dim1_dim2...
+    Dims(&'static str),
+}
+
+use Param::*;
+
+/// Gets the arguments of an op, in the order of the children of its enode
+///
+/// Input and weight are not in here, they take a name with their shape
+fn signature(op: &str) -> Option<Vec<Param>> {
+    let sig = match op {
+        "ewadd" | "ewmul" | "smul" | "enlarge" | "noop" => vec![Tensor, Tensor],
+        "transpose" => vec![Tensor, Dims("perm"), Shuffle],
+        "matmul" | "smatmul" => vec![Activation, Tensor, Tensor],
+        "dense" => vec![Activation, Tensor, Tensor, Tensor],
+        "softmax" => vec![Tensor, Int("axis")],
+        "conv2d" | "sconv2d" | "conv2d_b" => vec![
+            Int("stride_h"),
+            Int("stride_w"),
+            Padding,
+            Activation,
+            Tensor,
+            Tensor,
+        ],
+        "dropout" | "relu" | "tanh" | "sigmoid" | "split_0" | "split_1" => vec![Tensor],
+        "poolmax" | "poolavg" => vec![
+            Tensor,
+            Int("kernel_h"),
+            Int("kernel_w"),
+            Int("stride_h"),
+            Int("stride_w"),
+            Padding,
+            Activation,
+        ],
+        "conv2d_p" | "sconv2d_p" => vec![Int("attrs"), Tensor, Tensor],
+        "poolmax_p" | "poolavg_p" => vec![Tensor, Int("attrs")],
+        "concat" => vec![Int("axis"), Int("ndim"), Tensor, Tensor],
+        "concat3" => vec![Int("axis"), Int("ndim"), Tensor, Tensor, Tensor],
+        "concat4" => vec![Int("axis"), Int("ndim"), Tensor, Tensor, Tensor, Tensor],
+        "concat5" => vec![Int("axis"), Int("ndim"), Tensor, Tensor, Tensor, Tensor, Tensor],
+        "split" => vec![Int("axis"), Tensor],
+        "split_i" => vec![Int("axis"), Int("count"), Int("index"), Tensor],
+        "Cpool" | "Iconv" => vec![Int("kernel_h"), Int("kernel_w")],
+        "Imatmul" | "Iewmul" => vec![],
+        "merge" => vec![Tensor, Int("count")],
+        "repack" => vec![Int("layout"), Tensor],
+        "cast" => vec![Int("elem"), Tensor],
+        "reshape" => vec![Tensor, Dims("shape")],
+        "batchnorm" => vec![Tensor, Tensor, Tensor, Tensor, Tensor],
+        _ => return None,
+    };
+    Some(sig)
+}
+
+fn param_name(param: Param) -> &'static str {
+    match param {
+        Tensor => "",
+        Int(name) | Dims(name) => name,
+        Padding => "padding",
+        Activation => "activation",
+        Shuffle => "shuffle",
+    }
+}
+
+/// Writes a graph in the textual format
+///
+/// # Parameters
+///
+/// - `expr`: the graph to write
+/// - `shapes`: optionally, the output shapes of every node in expr (see
+///     shape::expr_shapes), written as comments
+///
+/// # Returns
+///
+/// The graph in the textual format
+pub fn to_text(expr: &RecExpr<Mdl>, shapes: Option<&[Option<Vec<TensorShape>>]>) -> String {
+    let nodes = expr.as_ref();
+    let num = |id: &Id| match &nodes[usize::from(*id)] {
+        Mdl::Num(n) => *n,
+        other => panic!("Expected a scalar, got {:?}", other),
+    };
+    let name = |id: &Id| match &nodes[usize::from(*id)] {
+        Mdl::Var(s) => s.as_str().to_string(),
+        other => panic!("Expected a name, got {:?}", other),
+    };
+
+    let mut lines = vec![String::from(HEADER)];
+    for (i, node) in nodes.iter().enumerate() {
+        let mut op_name = node.to_string();
+        let args: Vec<String> = match node {
+            Mdl::Num(_) | Mdl::Var(_) => continue,
+            Mdl::Input([n]) | Mdl::Weight([n]) => {
+                let full_name = name(n);
+                let name_vec: Vec<&str> = full_name.split("@").collect();
+                assert!(name_vec.len() == 2 || name_vec.len() == 3);
+                let mut args = vec![
+                    format!("name={}", name_vec[0]),
+                    format!("dims=[{}]", name_vec[1].replace("_", ",")),
+                ];
+                if let Some(density) = name_vec.get(2) {
+                    args.push(format!("density={}", density));
+                }
+                args
+            }
+            Mdl::Opaque([n, a, b]) => {
+                let full_name = name(n);
+                let name_vec: Vec<&str> = full_name.split("@").collect();
+                assert!(name_vec.len() == 2);
+                vec![
+                    format!("t{}", usize::from(*a)),
+                    format!("t{}", usize::from(*b)),
+                    format!("label={}", name_vec[0]),
+                    format!("dims=[{}]", name_vec[1].replace("_", ",")),
+                ]
+            }
+            // Takes any number of tensors, so it has no signature
+            Mdl::ConcatN(children) => {
+                let mut args: Vec<String> = children[2..].iter().map(|t| format!("t{}", usize::from(*t))).collect();
+                args.push(format!("axis={}", num(&children[0])));
+                args.push(format!("ndim={}", num(&children[1])));
+                args
+            }
+            Mdl::Custom(children) => {
+                let label = name(&children[0]);
+                let mut args: Vec<String> = children[1..].iter().map(|t| format!("t{}", usize::from(*t))).collect();
+                // Registered ops are written as themselves
+                match parse_custom_label(&label) {
+                    Some((custom, values)) => {
+                        for (key, value) in custom.attrs.iter().zip(values.iter()) {
+                            args.push(format!("{}={}", key, value));
+                        }
+                        op_name = custom.name.clone();
+                    }
+                    None => args.push(format!("label={}", label)),
+                }
+                args
+            }
+            _ => {
+                let op = node.to_string();
+                let sig = signature(&op).unwrap_or_else(|| panic!("No signature for op {}", op));
+                let children = node.children();
+                assert!(sig.len() == children.len());
+                let mut tensors = vec![];
+                let mut attrs = vec![];
+                for (param, child) in sig.iter().zip(children.iter()) {
+                    match param {
+                        Tensor => tensors.push(format!("t{}", usize::from(*child))),
+                        Int(key) => attrs.push(format!("{}={}", key, num(child))),
+                        Padding => attrs.push(format!("padding={}", padding_str(num(child)))),
+                        Activation => attrs.push(format!("activation={}", activation_str(num(child)))),
+                        Shuffle => attrs.push(format!("shuffle={}", num(child) == SHUFFLE)),
+                        Dims(key) => attrs.push(format!("{}=[{}]", key, name(child).replace("_", ","))),
+                    }
+                }
+                tensors.extend(attrs);
+                tensors
+            }
+        };
+        let mut line = format!("t{} = {}({})", i, op_name, args.join(", "));
+        if let Some(Some(node_shapes)) = shapes.map(|s| &s[i]) {
+            if !node_shapes.is_empty() {
+                let dims: Vec<String> = node_shapes.iter().map(|s| format!("{:?}", s.dims)).collect();
+                line = format!("{}  # {}", line, dims.join(", "));
+            }
+        }
+        lines.push(line);
+    }
+    lines.push(format!("return t{}", nodes.len() - 1));
+    lines.join("\n") + "\n"
+}
+
+fn padding_str(val: i32) -> &'static str {
+    match val {
+        PSAME => "SAME",
+        PVALID => "VALID",
+        _ => panic!("Unknown padding mode {}", val),
+    }
+}
+
+fn activation_str(val: i32) -> &'static str {
+    match val {
+        ACTNONE => "NONE",
+        ACTSIGMOID => "SIGMOID",
+        ACTRELU => "RELU",
+        ACTTANH => "TANH",
+        _ => panic!("Unknown activation mode {}", val),
+    }
+}
+
+/// Parses a graph in the textual format
+///
+/// # Returns
+///
+/// The graph, or an error naming the offending line
+pub fn from_text(s: &str) -> Result<RecExpr<Mdl>, String> {
+    let mut lines = s
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty());
+    match lines.next() {
+        Some((_, l)) if l == HEADER => (),
+        _ => return Err(format!("graph does not start with \"{}\"", HEADER)),
+    }
+
+    let mut expr = RecExpr::default();
+    let mut defined: HashMap<String, Id> = HashMap::new();
+    let mut root: Option<Id> = None;
+    for (line_no, line) in lines {
+        let err = |msg: String| format!("line {}: {}", line_no, msg);
+        // Strip the shape comment
+        let line = match line.find('#') {
+            Some(pos) => line[..pos].trim(),
+            None => line,
+        };
+        if line.is_empty() {
+            continue;
+        }
+        if root.is_some() {
+            return Err(err(String::from("nothing may follow the return statement")));
+        }
+        if let Some(name) = line.strip_prefix("return ") {
+            let id = defined
+                .get(name.trim())
+                .ok_or_else(|| err(format!("undefined tensor {}", name.trim())))?;
+            if usize::from(*id) != expr.as_ref().len() - 1 {
+                return Err(err(String::from("the returned tensor has to be defined last")));
+            }
+            root = Some(*id);
+            continue;
+        }
+
+        let (lhs, rhs) = match line.find('=') {
+            Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+            None => return Err(err(String::from("expected <name> = <op>(<args>)"))),
+        };
+        if lhs.is_empty() || !lhs.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(err(format!("invalid tensor name {}", lhs)));
+        }
+        if defined.contains_key(lhs) {
+            return Err(err(format!("tensor {} is defined twice", lhs)));
+        }
+        if !rhs.ends_with(')') {
+            return Err(err(String::from("expected <op>(<args>)")));
+        }
+        let (op, args) = match rhs.find('(') {
+            Some(pos) => (rhs[..pos].trim(), &rhs[pos + 1..rhs.len() - 1]),
+            None => return Err(err(String::from("expected <op>(<args>)"))),
+        };
+        let (tensors, attrs) = split_args(args).map_err(err)?;
+
+        let node = match op {
+            "input" | "weight" => {
+                let mut allowed = vec!["name", "dims"];
+                if op == "weight" {
+                    allowed.push("density");
+                }
+                check_attrs(&attrs, &allowed, &["name", "dims"]).map_err(err)?;
+                if !tensors.is_empty() {
+                    return Err(err(format!("{} takes no tensor inputs", op)));
+                }
+                let name = attrs["name"];
+                if name.is_empty() || name.contains('@') {
+                    return Err(err(format!("invalid name {}", name)));
+                }
+                let mut full_name = format!("{}@{}", name, parse_dims(attrs["dims"]).map_err(err)?);
+                if let Some(density) = attrs.get("density") {
+                    match density.parse::<f32>() {
+                        Ok(d) if d > 0.0 && d <= 1.0 => full_name = full_name + "@" + density,
+                        _ => return Err(err(format!("invalid density {}", density))),
+                    }
+                }
+                let name = Symbol::from(full_name);
+                tensor_name(name).map_err(err)?;
+                let name_id = expr.add(Mdl::Var(name));
+                if op == "input" {
+                    Mdl::Input([name_id])
+                } else {
+                    Mdl::Weight([name_id])
+                }
+            }
+            "opaque" => {
+                check_attrs(&attrs, &["label", "dims"], &["label", "dims"]).map_err(err)?;
+                if tensors.len() != 2 {
+                    return Err(err(format!("opaque takes 2 tensor inputs, got {}", tensors.len())));
+                }
+                let label = attrs["label"];
+                if label.is_empty() || label.contains('@') {
+                    return Err(err(format!("invalid label {}", label)));
+                }
+                let full_name = Symbol::from(format!("{}@{}", label, parse_dims(attrs["dims"]).map_err(err)?));
+                tensor_name(full_name).map_err(err)?;
+                let name_id = expr.add(Mdl::Var(full_name));
+                let mut inputs = vec![];
+                for t in tensors.iter() {
+                    inputs.push(*defined.get(*t).ok_or_else(|| err(format!("undefined tensor {}", t)))?);
+                }
+                Mdl::Opaque([name_id, inputs[0], inputs[1]])
+            }
+            "concat_n" => {
+                check_attrs(&attrs, &["axis", "ndim"], &["axis", "ndim"]).map_err(err)?;
+                if tensors.len() < 2 {
+                    return Err(err(String::from("concat_n takes at least 2 tensor inputs")));
+                }
+                let mut children = vec![];
+                for key in ["axis", "ndim"].iter() {
+                    let val = parse_attr(Int(*key), attrs[*key])
+                        .ok_or_else(|| err(format!("invalid value {} for {}", attrs[*key], key)))?;
+                    children.push(expr.add(Mdl::Num(val)));
+                }
+                for t in tensors.iter() {
+                    children.push(*defined.get(*t).ok_or_else(|| err(format!("undefined tensor {}", t)))?);
+                }
+                Mdl::ConcatN(children)
+            }
+            "custom" => {
+                check_attrs(&attrs, &["label"], &["label"]).map_err(err)?;
+                if tensors.is_empty() {
+                    return Err(err(String::from("custom takes at least 1 tensor input")));
+                }
+                let mut children = vec![expr.add(Mdl::Var(Symbol::from(attrs["label"])))];
+                for t in tensors.iter() {
+                    children.push(*defined.get(*t).ok_or_else(|| err(format!("undefined tensor {}", t)))?);
+                }
+                Mdl::Custom(children)
+            }
+            _ if signature(op).is_none() && custom_op(op).is_some() => {
+                let custom = custom_op(op).unwrap();
+                if tensors.len() != custom.arity {
+                    return Err(err(format!("{} takes {} tensor inputs, got {}", op, custom.arity, tensors.len())));
+                }
+                let keys: Vec<&str> = custom.attrs.iter().map(|k| k.as_str()).collect();
+                check_attrs(&attrs, &keys, &keys).map_err(err)?;
+                let values: Result<Vec<i32>, String> = keys
+                    .iter()
+                    .map(|k| attrs[k].parse::<i32>().map_err(|_| format!("invalid value {} for {}", attrs[k], k)))
+                    .collect();
+                let values = values.map_err(err)?;
+                let mut children = vec![expr.add(Mdl::Var(Symbol::from(custom_label(op, &values))))];
+                for t in tensors.iter() {
+                    children.push(*defined.get(*t).ok_or_else(|| err(format!("undefined tensor {}", t)))?);
+                }
+                Mdl::Custom(children)
+            }
+            _ => {
+                let sig = signature(op).ok_or_else(|| err(format!("unknown op {}", op)))?;
+                let n_tensors = sig.iter().filter(|p| **p == Tensor).count();
+                if tensors.len() != n_tensors {
+                    return Err(err(format!(
+                        "{} takes {} tensor inputs, got {}",
+                        op,
+                        n_tensors,
+                        tensors.len()
+                    )));
+                }
+                let names: Vec<&str> = sig.iter().filter(|p| **p != Tensor).map(|p| param_name(*p)).collect();
+                check_attrs(&attrs, &names, &names).map_err(err)?;
+
+                let mut tensors = tensors.iter();
+                let mut children = vec![];
+                for param in sig.iter() {
+                    let id = match param {
+                        Tensor => {
+                            let t = tensors.next().unwrap();
+                            *defined.get(*t).ok_or_else(|| err(format!("undefined tensor {}", t)))?
+                        }
+                        Dims(key) => {
+                            let dims = parse_dims(attrs[key]).map_err(err)?;
+                            expr.add(Mdl::Var(Symbol::from(dims)))
+                        }
+                        _ => {
+                            let key = param_name(*param);
+                            let val = parse_attr(*param, attrs[key])
+                                .ok_or_else(|| err(format!("invalid value {} for {}", attrs[key], key)))?;
+                            expr.add(Mdl::Num(val))
+                        }
+                    };
+                    children.push(id);
+                }
+                Mdl::from_op(op, children).map_err(|e| err(format!("{:?}", e)))?
+            }
+        };
+        let id = expr.add(node);
+        defined.insert(lhs.to_string(), id);
+    }
+
+    match root {
+        Some(_) => Ok(expr),
+        None => Err(String::from("graph has no return statement")),
+    }
+}
+
+/// Splits the arguments of an op into the positional tensors and the named
+/// attributes. Tensors have to come before attributes.
+fn split_args(args: &str) -> Result<(Vec<&str>, HashMap<&str, &str>), String> {
+    // Split at the commas outside of brackets
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 0 => return Err(String::from("unbalanced brackets")),
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if depth != 0 {
+        return Err(String::from("unbalanced brackets"));
+    }
+    parts.push(args[start..].trim());
+    if parts == vec![""] {
+        parts.clear();
+    }
+
+    let mut tensors = vec![];
+    let mut attrs = HashMap::new();
+    for part in parts {
+        match part.find('=') {
+            Some(pos) => {
+                let key = part[..pos].trim();
+                if attrs.insert(key, part[pos + 1..].trim()).is_some() {
+                    return Err(format!("attribute {} is given twice", key));
+                }
+            }
+            None if !attrs.is_empty() => {
+                return Err(format!("tensor {} given after the attributes", part));
+            }
+            None if part.is_empty() => return Err(String::from("empty argument")),
+            None => tensors.push(part),
+        }
+    }
+    Ok((tensors, attrs))
+}
+
+/// Checks that only allowed attributes are given, and all required ones
+fn check_attrs(attrs: &HashMap<&str, &str>, allowed: &[&str], required: &[&str]) -> Result<(), String> {
+    let allowed: HashSet<&str> = allowed.iter().cloned().collect();
+    for key in attrs.keys() {
+        if !allowed.contains(key) {
+            return Err(format!("unknown attribute {}", key));
+        }
+    }
+    for key in required {
+        if !attrs.contains_key(key) {
+            return Err(format!("missing attribute {}", key));
+        }
+    }
+    Ok(())
+}
+
+/// Parses a list of the format [a,b,c] into a name of the format a_b_c
+fn parse_dims(s: &str) -> Result<String, String> {
+    if !s.starts_with('[') || !s.ends_with(']') {
+        return Err(format!("expected a list, got {}", s));
+    }
+    // [] is the shape of a 0-d tensor
+    if s[1..s.len() - 1].trim().is_empty() {
+        return Ok(String::new());
+    }
+    // The leading dimension can be the symbolic batch dimension
+    let dims: Result<Vec<String>, String> = s[1..s.len() - 1]
+        .split(",")
+        .enumerate()
+        .map(|(i, d)| match d.trim() {
+            BATCH_DIM if i == 0 => Ok(BATCH_DIM.to_string()),
+            d => d.parse::<i32>().map(|d| d.to_string()).map_err(|_| format!("invalid list {}", s)),
+        })
+        .collect();
+    Ok(dims?.join("_"))
+}
+
+fn parse_attr(param: Param, s: &str) -> Option<i32> {
+    match param {
+        Int(_) => s.parse::<i32>().ok(),
+        Padding => match s {
+            "SAME" => Some(PSAME),
+            "VALID" => Some(PVALID),
+            _ => None,
+        },
+        Activation => match s {
+            "NONE" => Some(ACTNONE),
+            "SIGMOID" => Some(ACTSIGMOID),
+            "RELU" => Some(ACTRELU),
+            "TANH" => Some(ACTTANH),
+            _ => None,
+        },
+        Shuffle => match s {
+            "true" => Some(SHUFFLE),
+            "false" => Some(NOSHUFFLE),
+            _ => None,
+        },
+        Tensor | Dims(_) => None,
+    }
+}
+
+/// Reads a graph either in the textual format (if it starts with HEADER) or
+/// as an s-expression
+///
+/// The names of inputs, weights and opaque ops are validated (and
+/// registered, see names.rs) while reading, and malformed ones are rejected
+/// with the line they are on.
+pub fn read_graph(s: &str) -> Result<RecExpr<Mdl>, String> {
+    if s.trim_start().starts_with(HEADER) {
+        from_text(s)
+    } else {
+        let expr: RecExpr<Mdl> = s.parse().map_err(|e| format!("{:?}", e))?;
+        validate_names(&expr).map_err(|errors| {
+            let messages: Vec<String> = errors
+                .iter()
+                .map(|e| match s.lines().position(|line| line.contains(e.name.as_str())) {
+                    Some(line) => format!("line {}: {}", line + 1, e.error),
+                    None => format!("node {}: {}", e.node, e.error),
+                })
+                .collect();
+            messages.join("\n")
+        })?;
+        Ok(expr)
+    }
+}
@@ -0,0 +1,120 @@
+//! Keeping shared weights consistent in the optimized graph (--tied_weights)
+//!
+//! Encoder-decoder models often share a weight between sub-networks, e.g. the
+//! embedding table also projecting the decoder output onto the vocabulary.
+//! Such a weight is used by several ops, through one node of the graph or
+//! through several nodes of the same name (the readers of graphs do not merge
+//! equal nodes, the EGraph does). Rewrites on
+//! weights only (concatenating, merging or enlarging them) would give one of
+//! its uses a transformed copy, which the runtime then stores and trains
+//! apart from the original, so the tie is lost.
+//!
+//! With tied weights, the ops on weights only computed from a shared weight
+//! are infeasible in the extraction, unless the original graph has them
+//! (e.g. the transpose of a tied projection). The ops of the other tensors
+//! can still use the shared weights as they are.
+
+use crate::model::*;
+use crate::optimize::original_enodes;
+use crate::provenance::expr_eclasses;
+use egg::*;
+use std::collections::{HashMap, HashSet};
+
+/// The shared weights of a graph and the eclasses computed from them
+pub struct TiedWeights {
+    /// Names of the shared weights
+    pub names: Vec<String>,
+    /// Eclasses of the shared weights and of the tensors computed from them
+    /// and other weights only
+    derived: HashSet<Id>,
+    /// Enodes of the original graph
+    original: HashSet<Mdl>,
+}
+
+/// Gets the weights of a graph used by more than one op, counting the uses
+/// of all the weight nodes of the same name
+///
+/// # Returns
+///
+/// The indices of the weight nodes of the shared weights in the graph, all
+/// the nodes of each weight
+pub fn shared_weights(expr: &RecExpr<Mdl>) -> Vec<Id> {
+    let nodes = expr.as_ref();
+    let weight_name = |i: usize| match &nodes[i] {
+        Mdl::Weight([name]) => Some(expr[*name].to_string()),
+        _ => None,
+    };
+    let mut users: HashMap<String, HashSet<usize>> = HashMap::new();
+    for (i, node) in nodes.iter().enumerate() {
+        for child in node.children() {
+            if let Some(name) = weight_name(usize::from(*child)) {
+                users.entry(name).or_default().insert(i);
+            }
+        }
+    }
+    (0..nodes.len())
+        .filter(|i| weight_name(*i).map_or(false, |name| users.get(&name).map_or(0, |u| u.len()) > 1))
+        .map(Id::from)
+        .collect()
+}
+
+impl TiedWeights {
+    /// Finds the shared weights of the original graph in an EGraph
+    pub fn new(egraph: &EGraph<Mdl, TensorAnalysis>, expr: &RecExpr<Mdl>) -> Self {
+        let ids = expr_eclasses(egraph, expr);
+        let shared = shared_weights(expr);
+        // One name per weight, in the order of the graph
+        let mut seen = HashSet::new();
+        let names = shared
+            .iter()
+            .map(|i| match &expr[*i] {
+                Mdl::Weight([name]) => expr[*name].to_string(),
+                _ => unreachable!(),
+            })
+            .filter(|name| seen.insert(name.clone()))
+            .collect();
+        let mut derived: HashSet<Id> = shared.iter().filter_map(|i| ids[usize::from(*i)]).collect();
+        // Add the eclasses of weights only with an enode using a derived
+        // eclass, until there are no more
+        loop {
+            let added: Vec<Id> = egraph
+                .classes()
+                .filter(|class| class.data.all_weights && !derived.contains(&class.id))
+                .filter(|class| {
+                    class.iter().any(|enode| enode.children().iter().any(|c| derived.contains(&egraph.find(*c))))
+                })
+                .map(|class| class.id)
+                .collect();
+            if added.is_empty() {
+                break;
+            }
+            derived.extend(added);
+        }
+        TiedWeights {
+            names: names,
+            derived: derived,
+            original: original_enodes(egraph, expr),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// If an enode transforms a shared weight: an op on weights only, not in
+    /// the original graph, with an input computed from a shared weight
+    pub fn unties(&self, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> bool {
+        if matches!(enode, Mdl::Weight(_) | Mdl::Noop(_)) || self.original.contains(enode) {
+            return false;
+        }
+        let tensors: Vec<Id> = enode
+            .children()
+            .iter()
+            .map(|c| egraph.find(*c))
+            .filter(|c| matches!(egraph[*c].data.dtype, DataKind::Tnsr | DataKind::TnsrTuple))
+            .collect();
+        !tensors.is_empty()
+            && tensors.iter().all(|c| egraph[*c].data.all_weights)
+            && tensors.iter().any(|c| self.derived.contains(c))
+    }
+}
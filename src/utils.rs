@@ -19,6 +19,8 @@ use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::io::Error;
 use std::process::{Command, Stdio};
+use crate::subprocess::run_solver;
+use crate::resources::record_measure_time;
 use std::thread;
 use std::path::Path;
 use std::ffi::CString;
@@ -31,10 +33,21 @@ pub fn save_model(runner: &Runner<Mdl, TensorAnalysis, ()>, file_name: &str) {
     }
 }
 
+/// Saves the graph as TASO measures it in get_full_graph_runtime, after
+/// precomputing the ops that only have weights as inputs
+pub fn save_measured_model(runner: &Runner<Mdl, TensorAnalysis, ()>, file_name: &str) {
+    let mut g = runner.egraph.analysis.graph.lock().unwrap();
+    unsafe {
+        let processed_g = g.preprocess_weights();
+        (*processed_g).export_to_file_raw(CString::new(file_name).unwrap().into_raw());
+    }
+}
+
 pub fn get_full_graph_runtime(runner: &Runner<Mdl, TensorAnalysis, ()>, process: bool) -> f32 {
     // let mut g = runner.egraph.analysis.graph.borrow_mut();
     let mut g = runner.egraph.analysis.graph.lock().unwrap();
-    unsafe {
+    let start_time = Instant::now();
+    let runtime = unsafe {
         // This is calling TASO's preprocess_weights function before evaluating full graph
         // run time. It removes op that has only weights as its inputs. Since TASO only cares
         // about inference time, such ops can be pre-computed
@@ -46,7 +59,9 @@ pub fn get_full_graph_runtime(runner: &Runner<Mdl, TensorAnalysis, ()>, process:
             //(*g).export_to_file_raw(CString::new("/usr/tensat/orig.onnx").unwrap().into_raw());
             (*g).run()
         }
-    }
+    };
+    record_measure_time(start_time.elapsed());
+    runtime
 }
 
 
@@ -164,13 +179,9 @@ pub fn extract_by_ilp_rmcts(
     //     arg_vec.push(num_thread);
     // }
 
-    let child = Command::new("python")
-        .args(&arg_vec)
-        .spawn()
-        .expect("failed to execute child");
-    let output = child.wait_with_output().expect("failed to get output");
+    let status = run_solver(Command::new("python").args(&arg_vec)).unwrap_or_else(|e| panic!("Python script failed: {}", e));
 
-    if output.status.success() {
+    if status.success() {
         // Read back solved results, construct optimized graph
         let filename = Path::new(&ilp_dir).join("solved_".to_owned() + thread_name + ".json");
 
@@ -196,6 +207,9 @@ pub fn extract_by_ilp_rmcts(
             }
         }
 
+        if let Err(e) = check_selection(&node_picked, &[root], egraph) {
+            panic!("The ILP solution is not a valid graph: {}", e);
+        }
         let mut expr = RecExpr::default();
         if return_expr {
             // println!("Egg expression builder started.");
@@ -213,7 +227,7 @@ pub fn extract_by_ilp_rmcts(
 
         (expr, solved_data.cost, solved_data.time)
     } else {
-        println!("Output status: {}", output.status);
+        println!("Output status: {}", status);
         panic!("Python script failed");
     }
 }
\ No newline at end of file
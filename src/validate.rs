@@ -0,0 +1,324 @@
+//! Validation of input graphs before they are handed to TASO
+//!
+//! TASO checks most shapes with asserts on the C++ side, which abort the
+//! process without saying which node is wrong. This infers the shapes on the
+//! Rust side and reports all problems found in a graph at once.
+
+use crate::custom::parse_custom_label;
+use crate::model::*;
+use crate::names::parse_dims;
+use crate::shape::{broadcast_shape, ElemType};
+use egg::*;
+
+/// Inferred output shape of a node. None for names, scalars, and where the
+/// shape is not known (e.g. the outputs of a split, or after an error)
+type Shape = Option<Vec<i32>>;
+
+/// Checks the shapes of all nodes in a graph
+///
+/// Checks that dimensions are positive, the inputs of elementwise ops
+/// broadcast together, concat axes are smaller than the
+/// number of dimensions and the other dimensions agree, the inner dimensions
+/// of matmuls agree, and convolutions and poolings produce positive output
+/// dimensions. A node with an invalid input is not checked again, so every
+/// error is reported once, at the node causing it.
+///
+/// # Returns
+///
+/// Ok if the graph is valid, otherwise one message for each invalid node
+pub fn validate_graph(expr: &RecExpr<Mdl>) -> Result<(), Vec<String>> {
+    let nodes = expr.as_ref();
+    let mut shapes: Vec<Shape> = Vec::with_capacity(nodes.len());
+    let mut errors = vec![];
+    for (i, node) in nodes.iter().enumerate() {
+        let shape = match infer_shape(node, nodes, &shapes) {
+            Ok(shape) => shape,
+            Err(e) => {
+                errors.push(format!("node {} ({}): {}", i, node, e));
+                None
+            }
+        };
+        shapes.push(shape);
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Infers the output shape of each node of a graph, as validate_graph does
+///
+/// # Returns
+///
+/// For each node (in the same order), its dimensions, or None for names,
+/// scalars, invalid nodes and where the shape is not known
+pub fn inferred_shapes(expr: &RecExpr<Mdl>) -> Vec<Option<Vec<i32>>> {
+    let nodes = expr.as_ref();
+    let mut shapes: Vec<Shape> = Vec::with_capacity(nodes.len());
+    for node in nodes.iter() {
+        let shape = infer_shape(node, nodes, &shapes).unwrap_or(None);
+        shapes.push(shape);
+    }
+    shapes
+}
+
+fn infer_shape(node: &Mdl, nodes: &[Mdl], shapes: &[Shape]) -> Result<Shape, String> {
+    let num = |id: &Id| match &nodes[usize::from(*id)] {
+        Mdl::Num(n) => Ok(*n),
+        other => Err(format!("expected a scalar, got {}", other)),
+    };
+    let name = |id: &Id| match &nodes[usize::from(*id)] {
+        Mdl::Var(s) => Ok(s.as_str().to_string()),
+        other => Err(format!("expected a name, got {}", other)),
+    };
+    let shape = |id: &Id| shapes[usize::from(*id)].clone();
+
+    // Nodes with an input of unknown shape are not checked
+    let tensor_inputs: Vec<Id> = node
+        .children()
+        .iter()
+        .cloned()
+        .filter(|id| !matches!(nodes[usize::from(*id)], Mdl::Num(_) | Mdl::Var(_)))
+        .collect();
+    if tensor_inputs.iter().any(|id| shape(id).is_none()) {
+        return Ok(None);
+    }
+
+    let res = match node {
+        Mdl::Num(_) | Mdl::Var(_) | Mdl::Noop(_) => None,
+        Mdl::Input([n]) | Mdl::Weight([n]) | Mdl::Opaque([n, _, _]) => {
+            let full_name = name(n)?;
+            let name_vec: Vec<&str> = full_name.split("@").collect();
+            if name_vec.len() != 2 && name_vec.len() != 3 {
+                return Err(format!("name {} should be name@dim1_dim2...", full_name));
+            }
+            let dims = parse_dims(name_vec[1]).map_err(|_| format!("invalid dimensions in {}", full_name))?;
+            check_dims(&dims)?;
+            Some(dims)
+        }
+        Mdl::Ewadd([a, b]) | Mdl::Ewmul([a, b]) => {
+            let (a, b) = (shape(a).unwrap(), shape(b).unwrap());
+            Some(broadcast_shape(&a, &b).ok_or_else(|| format!("shapes {:?} and {:?} do not broadcast", a, b))?)
+        }
+        Mdl::Smul([a, _]) => shape(a),
+        Mdl::Cast([elem, a]) => {
+            let elem = num(elem)?;
+            if ElemType::from_code(elem).is_none() {
+                return Err(format!("unknown element type {}", elem));
+            }
+            shape(a)
+        }
+        Mdl::Repack([layout, a]) => {
+            let layout = num(layout)?;
+            if layout != LAYOUT_DEFAULT && layout != LAYOUT_BLOCKED {
+                return Err(format!("unknown weight layout {}", layout));
+            }
+            shape(a)
+        }
+        Mdl::Dropout(a) | Mdl::Relu(a) | Mdl::Tanh(a) | Mdl::Sigmoid(a) => shape(a),
+        Mdl::BatchNorm([inpt, ..]) => shape(inpt),
+        Mdl::Softmax([inpt, axis]) => {
+            let inpt = shape(inpt).unwrap();
+            let axis = num(axis)?;
+            if axis < 0 || axis as usize >= inpt.len() {
+                return Err(format!("softmax along axis {} of shape {:?}", axis, inpt));
+            }
+            Some(inpt)
+        }
+        Mdl::Matmul([_, a, b]) | Mdl::Smatmul([_, a, b]) | Mdl::Dense([_, a, b, _]) => {
+            let (a, b) = (shape(a).unwrap(), shape(b).unwrap());
+            let n = a.len();
+            if n < 2 || b.len() != n {
+                return Err(format!(
+                    "matmul of shapes {:?} and {:?} needs the same number (at least 2) of dimensions",
+                    a, b
+                ));
+            }
+            if a[n - 1] != b[n - 2] {
+                return Err(format!("inner dimensions of {:?} and {:?} do not agree", a, b));
+            }
+            if a[..n - 2] != b[..n - 2] {
+                return Err(format!("batch dimensions of {:?} and {:?} do not agree", a, b));
+            }
+            let mut out = a.clone();
+            out[n - 1] = b[n - 1];
+            if let Mdl::Dense([_, _, _, bias]) = node {
+                let bias = shape(bias).unwrap();
+                if broadcast_shape(&out, &bias).as_ref() != Some(&out) {
+                    return Err(format!("bias {:?} does not broadcast to {:?}", bias, out));
+                }
+            }
+            Some(out)
+        }
+        Mdl::Conv2d([stride_h, stride_w, pad, _, inpt, wght])
+        | Mdl::Sconv2d([stride_h, stride_w, pad, _, inpt, wght])
+        | Mdl::Conv2dBlocked([stride_h, stride_w, pad, _, inpt, wght]) => {
+            let (inpt, wght) = (shape(inpt).unwrap(), shape(wght).unwrap());
+            if inpt.len() != 4 || wght.len() != 4 {
+                return Err(format!("conv2d of shapes {:?} and {:?} needs 4 dimensions", inpt, wght));
+            }
+            if inpt[1] % wght[1] != 0 {
+                return Err(format!(
+                    "input channels {} are not a multiple of the weight's {}",
+                    inpt[1], wght[1]
+                ));
+            }
+            let (h, w) = window_output(
+                [inpt[2], inpt[3]],
+                [wght[2], wght[3]],
+                [num(stride_h)?, num(stride_w)?],
+                num(pad)?,
+            )?;
+            Some(vec![inpt[0], wght[0], h, w])
+        }
+        Mdl::Poolmax([inpt, kernel_h, kernel_w, stride_h, stride_w, pad, _])
+        | Mdl::Poolavg([inpt, kernel_h, kernel_w, stride_h, stride_w, pad, _]) => {
+            let inpt = shape(inpt).unwrap();
+            if inpt.len() != 4 {
+                return Err(format!("pooling of shape {:?} needs 4 dimensions", inpt));
+            }
+            let (h, w) = window_output(
+                [inpt[2], inpt[3]],
+                [num(kernel_h)?, num(kernel_w)?],
+                [num(stride_h)?, num(stride_w)?],
+                num(pad)?,
+            )?;
+            Some(vec![inpt[0], inpt[1], h, w])
+        }
+        Mdl::Concat(_) | Mdl::Concat3(_) | Mdl::Concat4(_) | Mdl::Concat5(_) | Mdl::ConcatN(_) => {
+            let inputs: Vec<Vec<i32>> = tensor_inputs.iter().map(|id| shape(id).unwrap()).collect();
+            let (axis, ndim) = (num(&node.children()[0])?, num(&node.children()[1])?);
+            let n = inputs[0].len();
+            if ndim as usize != n {
+                return Err(format!("ndim is {}, but the inputs have {} dimensions", ndim, n));
+            }
+            if axis < 0 || axis as usize >= n {
+                return Err(format!("axis {} out of bounds for {} dimensions", axis, n));
+            }
+            let axis = axis as usize;
+            let mut out = inputs[0].clone();
+            for other in &inputs[1..] {
+                let agree = other.len() == n && (0..n).all(|d| d == axis || other[d] == out[d]);
+                if !agree {
+                    return Err(format!(
+                        "shapes {:?} and {:?} can not be concatenated along axis {}",
+                        inputs[0], other, axis
+                    ));
+                }
+                out[axis] += other[axis];
+            }
+            Some(out)
+        }
+        Mdl::Split([axis, inpt]) => {
+            let (axis, n) = (num(axis)?, shape(inpt).unwrap().len());
+            if axis < 0 || axis as usize >= n {
+                return Err(format!("axis {} out of bounds for {} dimensions", axis, n));
+            }
+            // The split sizes are only known on the TASO side
+            None
+        }
+        Mdl::SplitI([axis, count, index, inpt]) => {
+            let (axis, n) = (num(axis)?, shape(inpt).unwrap().len());
+            if axis < 0 || axis as usize >= n {
+                return Err(format!("axis {} out of bounds for {} dimensions", axis, n));
+            }
+            let (count, index) = (num(count)?, num(index)?);
+            if count < 2 || index < 0 || index >= count {
+                return Err(format!("output {} of a split into {}", index, count));
+            }
+            None
+        }
+        Mdl::Split0(_) | Mdl::Split1(_) => None,
+        Mdl::Enlarge([a, b]) => {
+            let (a, b) = (shape(a).unwrap(), shape(b).unwrap());
+            if a.len() != 4 || b.len() != 4 || a[2] > b[2] || a[3] > b[3] {
+                return Err(format!("kernel {:?} can not be enlarged to {:?}", a, b));
+            }
+            Some(vec![a[0], a[1], b[2], b[3]])
+        }
+        Mdl::Merge([wght, count]) => {
+            let wght = shape(wght).unwrap();
+            if wght.len() != 4 {
+                return Err(format!("merge of shape {:?} needs 4 dimensions", wght));
+            }
+            Some(vec![wght[0], wght[1] * num(count)?, wght[2], wght[3]])
+        }
+        Mdl::Reshape([inpt, shape_name]) => {
+            let inpt = shape(inpt).unwrap();
+            let dims = parse_dims(&name(shape_name)?).map_err(|_| String::from("invalid shape"))?;
+            check_dims(&dims)?;
+            let volume = |s: &[i32]| s.iter().map(|d| *d as i64).product::<i64>();
+            if volume(&inpt) != volume(&dims) {
+                return Err(format!("can not reshape {:?} to {:?}", inpt, dims));
+            }
+            Some(dims)
+        }
+        Mdl::Transpose([inpt, perm_name, _]) => {
+            let inpt = shape(inpt).unwrap();
+            let perm = parse_dims(&name(perm_name)?).map_err(|_| String::from("invalid permutation"))?;
+            if perm.iter().any(|p| *p < 0) {
+                return Err(format!("{:?} is not a permutation of the {} dimensions", perm, inpt.len()));
+            }
+            let perm: Vec<usize> = perm.iter().map(|p| *p as usize).collect();
+            let mut sorted = perm.clone();
+            sorted.sort();
+            if sorted != (0..inpt.len()).collect::<Vec<usize>>() {
+                return Err(format!("{:?} is not a permutation of the {} dimensions", perm, inpt.len()));
+            }
+            Some(perm.iter().map(|p| inpt[*p]).collect())
+        }
+        Mdl::Custom(children) => {
+            let label = name(&children[0])?;
+            let (custom, attrs) =
+                parse_custom_label(&label).ok_or_else(|| format!("custom op {} is not registered", label))?;
+            if children.len() - 1 != custom.arity {
+                return Err(format!("{} takes {} inputs, got {}", custom.name, custom.arity, children.len() - 1));
+            }
+            let inputs: Vec<Vec<i32>> = children[1..].iter().map(|t| shape(t).unwrap()).collect();
+            let dims = (custom.shape)(&inputs, &attrs)?;
+            check_dims(&dims)?;
+            Some(dims)
+        }
+        Mdl::Cpool(_) | Mdl::Iconv(_) | Mdl::Imatmul | Mdl::Iewmul => None,
+        // Packed ops are only created from validated graphs, see attrs::pack_expr
+        Mdl::Conv2dPacked(_) | Mdl::Sconv2dPacked(_) | Mdl::PoolmaxPacked(_) | Mdl::PoolavgPacked(_) => None,
+    };
+    Ok(res)
+}
+
+fn check_dims(dims: &[i32]) -> Result<(), String> {
+    if dims.len() > 8 || dims.iter().any(|d| *d <= 0) {
+        Err(format!("dimensions {:?} should be at most 8 positive sizes", dims))
+    } else {
+        Ok(())
+    }
+}
+
+/// Gets the output height and width of a convolution or pooling, see
+/// GraphConverter::get_conv_shape
+fn window_output(input: [i32; 2], kernel: [i32; 2], stride: [i32; 2], padding: i32) -> Result<(i32, i32), String> {
+    if stride.iter().any(|s| *s <= 0) || kernel.iter().any(|k| *k <= 0) {
+        return Err(format!("kernel {:?} and stride {:?} should be positive", kernel, stride));
+    }
+    let out: Vec<i32> = (0..2)
+        .map(|d| match padding {
+            PSAME => (input[d] + stride[d] - 1) / stride[d],
+            _ => (input[d] - kernel[d]) / stride[d] + 1,
+        })
+        .collect();
+    if padding != PSAME && padding != PVALID {
+        return Err(format!("unknown padding mode {}", padding));
+    }
+    if out.iter().any(|o| *o <= 0) || (padding == PVALID && (input[0] < kernel[0] || input[1] < kernel[1])) {
+        return Err(format!(
+            "output size {:?} is not positive (input {:?}, kernel {:?}, stride {:?}, padding {})",
+            out,
+            input,
+            kernel,
+            stride,
+            if padding == PSAME { "SAME" } else { "VALID" }
+        ));
+    }
+    Ok((out[0], out[1]))
+}
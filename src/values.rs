@@ -0,0 +1,248 @@
+//! Values of the weights created on the TASO side
+//!
+//! TASO creates a weight (new_weight) with its values, and kernels whose
+//! speed depends on the data (e.g. of pruned weights) are measured on them.
+//! By default the values are pseudo-random, determined by the name of the
+//! weight and a seed (--seed), so that two runs measure the same graphs on
+//! the same values. The trained values of the weights can be loaded instead
+//! (--weights_file), from an .npz archive of arrays named as the weights,
+//! or from the initializers of an ONNX model.
+//!
+//! The values are shared by all EGraphs of the process, so that the input
+//! and the optimized graph are measured on the same weights.
+
+use crate::input::sanitize_name;
+use crate::model::*;
+use crate::names::parse_tensor_name;
+use crate::onnx::onnx_weight_values;
+use egg::*;
+use once_cell::sync::Lazy;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, Default)]
+pub struct WeightValues {
+    /// Seed of the pseudo-random values
+    pub seed: u64,
+    /// Loaded values of weights, by the label of the weight (the part of its
+    /// name before the dimensions), in row-major order
+    pub loaded: HashMap<String, Vec<f32>>,
+}
+
+static WEIGHT_VALUES: Lazy<RwLock<Arc<WeightValues>>> = Lazy::new(Default::default);
+
+/// Sets the values of the weights created from now on
+pub fn set_weight_values(values: WeightValues) {
+    *WEIGHT_VALUES.write().unwrap() = Arc::new(values);
+}
+
+pub fn weight_values() -> Arc<WeightValues> {
+    WEIGHT_VALUES.read().unwrap().clone()
+}
+
+impl WeightValues {
+    /// Gets the values of a weight
+    ///
+    /// # Parameters
+    ///
+    /// - `label`: the label of the weight
+    /// - `num_entries`: the number of entries of the weight
+    /// - `density`: the target density of a pruned weight, the pruned entries
+    ///   of random values are zero
+    ///
+    /// # Returns
+    ///
+    /// The loaded values, or random values in [0, 1). Loaded values of the
+    /// wrong size are an error
+    pub fn values(&self, label: &str, num_entries: usize, density: f32) -> Result<Vec<f32>, String> {
+        if let Some(values) = self.loaded.get(label) {
+            if values.len() != num_entries {
+                return Err(format!(
+                    "weight {} has {} loaded values, its shape has {} entries",
+                    label,
+                    values.len(),
+                    num_entries
+                ));
+            }
+            return Ok(values.clone());
+        }
+        let mut hasher = DefaultHasher::new();
+        label.hash(&mut hasher);
+        let mut rng = StdRng::seed_from_u64(self.seed ^ hasher.finish());
+        Ok((0..num_entries)
+            .map(|_| {
+                if density == 1.0 || rng.gen::<f32>() < density {
+                    rng.gen()
+                } else {
+                    0.0
+                }
+            })
+            .collect())
+    }
+
+    /// Checks that the loaded values fit the weights of a graph
+    ///
+    /// # Returns
+    ///
+    /// What is wrong with each weight whose loaded values do not fit, and the
+    /// number of weights of the graph that got loaded values
+    pub fn check(&self, expr: &RecExpr<Mdl>) -> (Vec<String>, usize) {
+        let nodes = expr.as_ref();
+        let mut errors = vec![];
+        let mut num_loaded = 0;
+        for node in nodes.iter() {
+            if let Mdl::Weight([name]) = node {
+                let name = nodes[usize::from(*name)].to_string();
+                let parsed = match parse_tensor_name(&name) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        errors.push(e);
+                        continue;
+                    }
+                };
+                let values = match self.loaded.get(&parsed.label) {
+                    Some(values) => values,
+                    None => continue,
+                };
+                let num_entries: i64 = parsed.dims.iter().map(|d| *d as i64).product();
+                if values.len() as i64 != num_entries {
+                    errors.push(format!(
+                        "weight {} has {} loaded values, its shape {:?} has {} entries",
+                        parsed.label,
+                        values.len(),
+                        parsed.dims,
+                        num_entries
+                    ));
+                } else {
+                    num_loaded += 1;
+                }
+            }
+        }
+        (errors, num_loaded)
+    }
+}
+
+/// Loads the values of weights from an .npz archive or an ONNX model, by the
+/// extension of the file
+///
+/// # Returns
+///
+/// The values by the name of the weight, with the characters that can not be
+/// in a tensor name replaced as on import (see sanitize_name)
+pub fn load_weight_file(filename: &str) -> Result<HashMap<String, Vec<f32>>, String> {
+    let bytes = fs::read(filename).map_err(|e| format!("Could not read {}: {}", filename, e))?;
+    let values = if filename.ends_with(".onnx") {
+        onnx_weight_values(&bytes)
+    } else {
+        read_npz(&bytes)
+    };
+    values.map_err(|e| format!("Could not read the weights of {}: {}", filename, e))
+}
+
+fn u16_at(bytes: &[u8], pos: usize) -> Result<u16, String> {
+    let b = bytes.get(pos..pos + 2).ok_or("truncated file")?;
+    Ok(u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> Result<u32, String> {
+    let b = bytes.get(pos..pos + 4).ok_or("truncated file")?;
+    Ok(u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn u64_at(bytes: &[u8], pos: usize) -> Result<u64, String> {
+    let b = bytes.get(pos..pos + 8).ok_or("truncated file")?;
+    Ok(u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// Reads the arrays of an .npz archive, as written by np.savez. Compressed
+/// archives (np.savez_compressed) are not supported
+pub fn read_npz(bytes: &[u8]) -> Result<HashMap<String, Vec<f32>>, String> {
+    // The end of central directory record is at the end, before a comment
+    let eocd = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|pos| bytes[*pos..].starts_with(&[0x50, 0x4b, 0x05, 0x06]))
+        .ok_or("not a zip archive")?;
+    let num_entries = u16_at(bytes, eocd + 10)? as usize;
+    let mut pos = u32_at(bytes, eocd + 16)? as usize;
+
+    let mut arrays = HashMap::new();
+    for _ in 0..num_entries {
+        if u32_at(bytes, pos)? != 0x0201_4b50 {
+            return Err("invalid central directory".to_string());
+        }
+        let method = u16_at(bytes, pos + 10)?;
+        let mut size = u32_at(bytes, pos + 24)? as u64;
+        let name_len = u16_at(bytes, pos + 28)? as usize;
+        let extra_len = u16_at(bytes, pos + 30)? as usize;
+        let comment_len = u16_at(bytes, pos + 32)? as usize;
+        let mut offset = u32_at(bytes, pos + 42)? as u64;
+        let name = bytes.get(pos + 46..pos + 46 + name_len).ok_or("truncated file")?;
+        let name = String::from_utf8_lossy(name).to_string();
+
+        // np.savez writes zip64 entries, with the sizes and the offset that
+        // do not fit 32 bits in an extra field
+        let extra_start = pos + 46 + name_len;
+        let mut extra = extra_start;
+        while extra + 4 <= extra_start + extra_len {
+            let id = u16_at(bytes, extra)?;
+            let len = u16_at(bytes, extra + 2)? as usize;
+            if id == 1 {
+                let mut field = extra + 4;
+                if size == 0xffff_ffff {
+                    // The uncompressed size comes before the compressed one
+                    size = u64_at(bytes, field)?;
+                    field += 16;
+                }
+                if offset == 0xffff_ffff {
+                    offset = u64_at(bytes, field)?;
+                }
+            }
+            extra += 4 + len;
+        }
+        pos = extra_start + extra_len + comment_len;
+
+        if method != 0 {
+            return Err(format!("{} is compressed, save the weights with np.savez", name));
+        }
+        let offset = offset as usize;
+        if u32_at(bytes, offset)? != 0x0403_4b50 {
+            return Err(format!("invalid header of {}", name));
+        }
+        let data_start = offset + 30 + u16_at(bytes, offset + 26)? as usize + u16_at(bytes, offset + 28)? as usize;
+        let data = bytes.get(data_start..data_start + size as usize).ok_or("truncated file")?;
+        let values = read_npy(data).map_err(|e| format!("{}: {}", name, e))?;
+        let name = name.strip_suffix(".npy").unwrap_or(&name);
+        arrays.insert(sanitize_name(name), values);
+    }
+    Ok(arrays)
+}
+
+/// Reads an array in the .npy format, of float32 or float64 values
+pub fn read_npy(bytes: &[u8]) -> Result<Vec<f32>, String> {
+    if !bytes.starts_with(b"\x93NUMPY") || bytes.len() < 10 {
+        return Err("not an .npy array".to_string());
+    }
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16_at(bytes, 8)? as usize, 10),
+        _ => (u32_at(bytes, 8)? as usize, 12),
+    };
+    let header = bytes.get(header_start..header_start + header_len).ok_or("truncated file")?;
+    let header = String::from_utf8_lossy(header);
+    if header.contains("'fortran_order': True") {
+        return Err("arrays in Fortran order are not supported".to_string());
+    }
+    let data = &bytes[header_start + header_len..];
+    if header.contains("'descr': '<f4'") {
+        Ok(data.chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap())).collect())
+    } else if header.contains("'descr': '<f8'") {
+        Ok(data.chunks_exact(8).map(|c| f64::from_le_bytes(c.try_into().unwrap()) as f32).collect())
+    } else {
+        Err(format!("unsupported array type in {}", header.trim()))
+    }
+}
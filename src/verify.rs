@@ -0,0 +1,212 @@
+//! Verifying rewrite rules
+//!
+//! verify tries to prove rules equal with the axioms of rules(). check_rule
+//! tests a rule numerically instead: both sides are instantiated with random
+//! shapes and attributes, evaluated with the same random inputs by the host
+//! interpreter (TASO only measures runtimes, it does not compute values), and
+//! their outputs compared. A trial where the source side can not be
+//! evaluated (the shapes do not fit its ops) is skipped, so a rule is only
+//! reported as diverging when both sides were computed and differ.
+
+use crate::execute::GraphExecutor;
+use crate::interp::max_relative_error;
+use crate::model::*;
+use crate::rewrites::*;
+use egg::*;
+use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+/// Largest relative error between the sides of a rule, above which it
+/// diverges. Reassociating fp32 ops stays well below it
+pub const VERIFY_TOLERANCE: f32 = 1e-3;
+
+use crate::model::*;
+use crate::rewrites::*;
+use egg::*;
+
+type ExprPair = (RecExpr<Mdl>, RecExpr<Mdl>);
+// returns failed pairs
+pub fn verify(pairs: &[ExprPair]) -> Vec<ExprPair> {
+    let mut runner = Runner::<Mdl, (), ()>::default();
+    for (l, r) in pairs {
+        runner = runner.with_expr(l).with_expr(r);
+    }
+
+    println!("Running...");
+    let runner = runner.run(&rules());
+    println!("Runner complete!");
+    println!("  Nodes: {}", runner.egraph.total_size());
+    println!("  Classes: {}", runner.egraph.number_of_classes());
+    println!("  Stopped: {:?}", runner.stop_reason.unwrap());
+
+    let mut failed = vec![];
+    for (i, roots) in runner.roots.chunks(2).enumerate() {
+        let eg = &runner.egraph;
+        if eg.find(roots[0]) != eg.find(roots[1]) {
+            failed.push(pairs[i].clone());
+        }
+    }
+
+    failed
+}
+
+/// Proves rules given in the format lhs=>rhs with the axioms of rules()
+///
+/// As in mode verify, the rules not proven are tried again on their own, as
+/// long as that proves more of them.
+///
+/// # Returns
+///
+/// For each rule, if it was proven. Rules that can not be parsed are not
+pub fn prove_rules(rules: &[&str]) -> Vec<bool> {
+    let pairs: Vec<Option<ExprPair>> = rules
+        .iter()
+        .map(|rule| {
+            let eqn: Vec<&str> = rule.split("=>").collect();
+            if eqn.len() != 2 {
+                return None;
+            }
+            match (eqn[0].parse(), eqn[1].parse()) {
+                (Ok(lhs), Ok(rhs)) => Some((lhs, rhs)),
+                _ => None,
+            }
+        })
+        .collect();
+    let mut to_prove: Vec<ExprPair> = pairs.iter().flatten().cloned().collect();
+    while !to_prove.is_empty() {
+        let failed = verify(&to_prove);
+        if failed.len() == to_prove.len() {
+            break;
+        }
+        to_prove = failed;
+    }
+    pairs
+        .iter()
+        .map(|pair| pair.as_ref().map_or(false, |pair| !to_prove.contains(pair)))
+        .collect()
+}
+
+/// Classifies rules given in the format lhs=>rhs into tiers (see RuleTier)
+pub fn rule_tiers(rules: &[&str]) -> Vec<RuleTier> {
+    let proven = prove_rules(rules);
+    rules
+        .iter()
+        .zip(proven)
+        .map(|(rule, proven)| {
+            if proven {
+                RuleTier::Proven
+            } else if rule_str_numerics(rule) == Numerics::Exact {
+                RuleTier::Exact
+            } else {
+                RuleTier::Approximate
+            }
+        })
+        .collect()
+}
+
+/// Result of checking a rule numerically
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleCheck {
+    /// The sides agreed in this many trials
+    Agrees(usize),
+    /// The sides diverged, with the relative error (infinite if the outputs
+    /// differ in shape) and the instantiated source side
+    Diverges(f32, String),
+    /// No trial could be evaluated, with why the last one could not
+    Unchecked(String),
+}
+
+/// Instantiates a pattern, with an input of the given dims for each tensor
+/// variable and the given value for each attribute variable
+fn instantiate(pat: &Pattern<Mdl>, tensors: &HashMap<Var, Vec<i32>>, attrs: &HashMap<Var, i32>) -> RecExpr<Mdl> {
+    let mut expr = RecExpr::default();
+    let mut ids: Vec<Id> = vec![];
+    for node in pat.ast.as_ref().iter() {
+        let id = match node {
+            ENodeOrVar::ENode(n) => expr.add(n.clone().map_children(|child| ids[usize::from(child)])),
+            ENodeOrVar::Var(v) => match tensors.get(v) {
+                Some(dims) => {
+                    let name = format!("{}@{}", v.to_string().trim_start_matches('?'), dims.iter().join("_"));
+                    let name_id = expr.add(Mdl::Var(Symbol::from(name)));
+                    expr.add(Mdl::Input([name_id]))
+                }
+                None => expr.add(Mdl::Num(attrs[v])),
+            },
+        };
+        ids.push(id);
+    }
+    expr
+}
+
+/// Checks a rule numerically
+///
+/// Trial i uses tensors of rank 2 to 4 and dims of size 2 to 4: the same
+/// dims for every tensor in the first half of the trials (which fits
+/// matmuls and convs), dims of one or two times the size in the second.
+/// Tensor variables are the ones named ?input_*, the others (e.g. ?axis) get
+/// a random value from 0 to the rank.
+///
+/// # Parameters
+///
+/// - `rule`: the rule, in the format lhs=>rhs
+/// - `num_trials`: the number of instantiations to try
+pub fn check_rule(rule: &str, num_trials: usize) -> RuleCheck {
+    let eqn: Vec<&str> = rule.split("=>").collect();
+    if eqn.len() != 2 {
+        return RuleCheck::Unchecked(format!("{} is not in the format lhs=>rhs", rule));
+    }
+    let (lhs, rhs): (Pattern<Mdl>, Pattern<Mdl>) = match (eqn[0].parse(), eqn[1].parse()) {
+        (Ok(lhs), Ok(rhs)) => (lhs, rhs),
+        _ => return RuleCheck::Unchecked(format!("could not parse {}", rule)),
+    };
+    let vars: Vec<Var> = lhs.vars().into_iter().chain(rhs.vars()).unique().collect();
+
+    let mut num_agreed = 0;
+    let mut last_error = String::from("no trials");
+    for trial in 0..num_trials {
+        let mut rng = StdRng::seed_from_u64(trial as u64);
+        let rank = 2 + trial % 3;
+        let size = rng.gen_range(2, 5);
+        let mut tensors = HashMap::new();
+        let mut attrs = HashMap::new();
+        for v in vars.iter() {
+            if v.to_string().starts_with("?input") {
+                let dims = (0..rank)
+                    .map(|_| if trial < num_trials / 2 { size } else { size * rng.gen_range(1, 3) })
+                    .collect();
+                tensors.insert(*v, dims);
+            } else {
+                attrs.insert(*v, rng.gen_range(0, rank as i32 + 1));
+            }
+        }
+        let src = instantiate(&lhs, &tensors, &attrs);
+        let dst = instantiate(&rhs, &tensors, &attrs);
+        let src_out = match GraphExecutor::new().with_seed(trial as u64).run(&src) {
+            Ok(execution) => execution.outputs,
+            Err(e) => {
+                last_error = e;
+                continue;
+            }
+        };
+        // The rewrite is not applied if the target side is infeasible
+        let dst_out = match GraphExecutor::new().with_seed(trial as u64).run(&dst) {
+            Ok(execution) => execution.outputs,
+            Err(e) => {
+                last_error = e;
+                continue;
+            }
+        };
+        let error = max_relative_error(&src_out, &dst_out).unwrap_or(f32::INFINITY);
+        if error > VERIFY_TOLERANCE {
+            return RuleCheck::Diverges(error, src.to_string());
+        }
+        num_agreed += 1;
+    }
+    if num_agreed > 0 {
+        RuleCheck::Agrees(num_agreed)
+    } else {
+        RuleCheck::Unchecked(last_error)
+    }
+}
@@ -0,0 +1,130 @@
+//! Timeouts for single TASO measurements (--measure_timeout)
+//!
+//! A pathological op configuration can hang TASO while it creates and
+//! measures the op, e.g. in a cuDNN algorithm search, which stalls the whole
+//! saturation. A TASO call can not be interrupted, and TASO's graph can only
+//! be used by one thread (its model holds the CUDA handles), so the
+//! measurement can not just be abandoned in the background either. Instead,
+//! a watchdog thread checks the measurement in flight; when it runs over the
+//! timeout, its op is appended to a file of hung ops and the process
+//! restarts itself with the same arguments, which also frees the GPU. On
+//! start, the analysis marks the ops of the file infeasible without
+//! measuring them (see TensorAnalysis::make), so the restarted run gets past
+//! them. Ops are identified by their key in a runtime table (see
+//! cost::op_key). A run restarts at most MAX_RESTARTS times.
+
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Maximum number of restarts of a run after hung measurements
+pub const MAX_RESTARTS: usize = 10;
+
+/// Environment variable counting the restarts of a run
+const RESTARTS_VAR: &str = "TENSAT_MEASURE_RESTARTS";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static HUNG_OPS: Lazy<RwLock<HashSet<String>>> = Lazy::new(Default::default);
+/// Key and start of the measurement in flight
+static CURRENT: Lazy<Mutex<Option<(String, Instant)>>> = Lazy::new(Default::default);
+
+/// Reads a file of hung ops, one key per line
+pub fn parse_hung_ops(s: &str) -> HashSet<String> {
+    s.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).map(String::from).collect()
+}
+
+/// Starts the watchdog, for the rest of the process
+///
+/// # Parameters
+///
+/// - `timeout`: longest a measurement can take
+/// - `hung_file`: file of the hung ops, read now if it exists and appended
+///   to on a timeout
+///
+/// # Returns
+///
+/// The number of hung ops read from the file
+pub fn start_watchdog(timeout: Duration, hung_file: PathBuf) -> Result<usize, String> {
+    let hung = match fs::read_to_string(&hung_file) {
+        Ok(s) => parse_hung_ops(&s),
+        Err(_) => HashSet::new(),
+    };
+    let num_hung = hung.len();
+    *HUNG_OPS.write().unwrap() = hung;
+    let poll = (timeout / 4).min(Duration::from_secs(1));
+    thread::Builder::new()
+        .name(String::from("measure-watchdog"))
+        .spawn(move || loop {
+            thread::sleep(poll);
+            let hung = match &*CURRENT.lock().unwrap() {
+                Some((key, start)) if start.elapsed() > timeout => Some(key.clone()),
+                _ => None,
+            };
+            if let Some(key) = hung {
+                restart_after_hang(&key, timeout, &hung_file);
+            }
+        })
+        .map_err(|e| format!("Could not start the measurement watchdog: {}", e))?;
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(num_hung)
+}
+
+/// If the watchdog is running
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// If the measurement of an op hung in a previous run
+pub fn is_hung(key: &str) -> bool {
+    HUNG_OPS.read().unwrap().contains(key)
+}
+
+/// Marks the start of the measurement of an op
+pub fn begin_measurement(key: String) {
+    *CURRENT.lock().unwrap() = Some((key, Instant::now()));
+}
+
+/// Marks the end of the measurement in flight
+pub fn end_measurement() {
+    *CURRENT.lock().unwrap() = None;
+}
+
+/// Records a hung op and restarts the process, or exits if it restarted too
+/// often
+fn restart_after_hang(key: &str, timeout: Duration, hung_file: &Path) -> ! {
+    println!("Measuring {} took more than {:?}, marking it infeasible", key, timeout);
+    let recorded = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(hung_file)
+        .and_then(|mut f| writeln!(f, "{}", key));
+    if let Err(e) = recorded {
+        eprintln!("Could not record the hung op in {}: {}", hung_file.display(), e);
+        std::process::exit(1);
+    }
+    let restarts = std::env::var(RESTARTS_VAR)
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(0);
+    if restarts >= MAX_RESTARTS {
+        eprintln!("Giving up after {} restarts for hung measurements, see {}", restarts, hung_file.display());
+        std::process::exit(1);
+    }
+    println!("Restarting ({} of at most {})", restarts + 1, MAX_RESTARTS);
+    let args: Vec<String> = std::env::args().collect();
+    let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from(&args[0]));
+    let e = Command::new(exe)
+        .args(&args[1..])
+        .env(RESTARTS_VAR, (restarts + 1).to_string())
+        .exec();
+    eprintln!("Could not restart: {}", e);
+    std::process::exit(1);
+}
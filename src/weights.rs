@@ -0,0 +1,209 @@
+//! Transformations of the weights in an optimized graph
+//!
+//! Rewrites like merging grouped convolutions (merge), enlarging kernels
+//! (enlarge) or concatenating the weights of parallel ops create ops whose
+//! inputs are all weights. TASO precomputes them before measuring, but an
+//! inference runtime loading the optimized graph has to apply them to its
+//! trained weights. They are exported as a graph of their own, whose inputs
+//! are the original weights and whose outputs are the transformed weights
+//! the optimized graph uses.
+//!
+//! The rewrites on weights are applied speculatively, so the same
+//! transformation of the same weights ends up in many candidate graphs the
+//! interpreter evaluates (rule verification, equivalence checks, executions).
+//! Its values are computed once: the interpreter keeps the transformed
+//! weights in a cache (see TransformCache), by the transformation with its
+//! source weights and the seed of their values.
+
+use crate::interp::HostTensor;
+use crate::model::*;
+use egg::*;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Default size of the cache of transformed weights, in bytes
+pub const DEFAULT_TRANSFORM_CACHE_BYTES: usize = 256 << 20;
+
+/// Values of transformed weights, by the subexpression computing them from
+/// the source weights (e.g. `(enlarge (weight w@64_64_1_1) (weight r@64_64_3_3))`)
+/// and the seed of the values of the source weights
+#[derive(Debug, Clone, Default)]
+pub struct TransformCache {
+    values: HashMap<(String, u64), HostTensor>,
+    /// Most bytes of values kept, the cache is emptied when it is full
+    pub max_bytes: usize,
+    bytes: usize,
+    pub num_hits: usize,
+    pub num_misses: usize,
+}
+
+impl TransformCache {
+    pub fn new(max_bytes: usize) -> Self {
+        TransformCache {
+            max_bytes: max_bytes,
+            ..Default::default()
+        }
+    }
+
+    pub fn get(&mut self, transformation: &str, seed: u64) -> Option<HostTensor> {
+        let value = self.values.get(&(transformation.to_string(), seed)).cloned();
+        if value.is_some() {
+            self.num_hits += 1;
+        } else {
+            self.num_misses += 1;
+        }
+        value
+    }
+
+    /// Caches the value of a transformation, unless it is larger than the
+    /// cache
+    pub fn insert(&mut self, transformation: &str, seed: u64, value: &HostTensor) {
+        let bytes = value.data.len() * std::mem::size_of::<f32>();
+        if bytes > self.max_bytes {
+            return;
+        }
+        if self.bytes + bytes > self.max_bytes {
+            self.values.clear();
+            self.bytes = 0;
+        }
+        if self.values.insert((transformation.to_string(), seed), value.clone()).is_none() {
+            self.bytes += bytes;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// Cache of the interpreter, shared by all evaluations of the process
+pub static TRANSFORM_CACHE: Lazy<Mutex<TransformCache>> =
+    Lazy::new(|| Mutex::new(TransformCache::new(DEFAULT_TRANSFORM_CACHE_BYTES)));
+
+/// Sets the size of the cache of transformed weights, 0 to disable it
+pub fn set_transform_cache_size(max_bytes: usize) {
+    *TRANSFORM_CACHE.lock().unwrap() = TransformCache::new(max_bytes);
+}
+
+/// The ops on weights only of a graph
+#[derive(Debug, Clone)]
+pub struct WeightTransforms {
+    /// The transformations, with their outputs combined with noops (the
+    /// first two in an inner noop)
+    pub program: RecExpr<Mdl>,
+    /// Index into the original graph of each output of the program
+    pub outputs: Vec<usize>,
+}
+
+/// Where an output of the weight transformations is used
+#[derive(Debug, Clone, Serialize)]
+pub struct TransformedWeight {
+    /// Position of the output in the program
+    pub output: usize,
+    /// Index of the transformed weight in the graph
+    pub node: usize,
+    /// Op computing the transformed weight
+    pub op: String,
+    /// Indices and ops of the nodes using the transformed weight
+    pub used_by: Vec<(usize, String)>,
+}
+
+/// For each node of a graph, if it is a tensor computed from weights only
+pub fn weights_only(nodes: &[Mdl]) -> Vec<bool> {
+    let mut res: Vec<bool> = Vec::with_capacity(nodes.len());
+    for node in nodes.iter() {
+        let weight_only = match node {
+            Mdl::Weight(_) => true,
+            Mdl::Num(_) | Mdl::Var(_) | Mdl::Input(_) | Mdl::Opaque(_) | Mdl::Noop(_) => false,
+            _ => {
+                let tensors: Vec<usize> = node
+                    .children()
+                    .iter()
+                    .map(|id| usize::from(*id))
+                    .filter(|c| !matches!(nodes[*c], Mdl::Num(_) | Mdl::Var(_)))
+                    .collect();
+                !tensors.is_empty() && tensors.iter().all(|c| res[*c])
+            }
+        };
+        res.push(weight_only);
+    }
+    res
+}
+
+/// Gets the transformations of the weights of a graph
+///
+/// # Returns
+///
+/// The transformations, or None if the graph uses the weights as they are
+pub fn weight_transforms(expr: &RecExpr<Mdl>) -> Option<WeightTransforms> {
+    let nodes = expr.as_ref();
+    let weight_only = weights_only(nodes);
+    let transformed = |i: usize| weight_only[i] && !matches!(nodes[i], Mdl::Weight(_));
+
+    // Transformed weights used by ops on other tensors, or returned
+    let mut is_output = vec![false; nodes.len()];
+    is_output[nodes.len() - 1] = transformed(nodes.len() - 1);
+    for (i, node) in nodes.iter().enumerate() {
+        if weight_only[i] {
+            continue;
+        }
+        for child in node.children() {
+            is_output[usize::from(*child)] |= transformed(usize::from(*child));
+        }
+    }
+    let outputs: Vec<usize> = (0..nodes.len()).filter(|i| is_output[*i]).collect();
+    if outputs.is_empty() {
+        return None;
+    }
+
+    // Copy the transformations with their inputs, in post order
+    let mut program = RecExpr::default();
+    let mut ids: HashMap<usize, Id> = HashMap::new();
+    fn add(nodes: &[Mdl], i: usize, program: &mut RecExpr<Mdl>, ids: &mut HashMap<usize, Id>) -> Id {
+        if let Some(id) = ids.get(&i) {
+            return *id;
+        }
+        let node = nodes[i]
+            .clone()
+            .map_children(|child| add(nodes, usize::from(child), program, ids));
+        let id = program.add(node);
+        ids.insert(i, id);
+        id
+    }
+    let mut out = add(nodes, outputs[0], &mut program, &mut ids);
+    for o in outputs[1..].iter() {
+        let id = add(nodes, *o, &mut program, &mut ids);
+        out = program.add(Mdl::Noop([out, id]));
+    }
+    Some(WeightTransforms {
+        program: program,
+        outputs: outputs,
+    })
+}
+
+/// Describes where each output of the weight transformations is used
+pub fn transformed_weights(expr: &RecExpr<Mdl>, transforms: &WeightTransforms) -> Vec<TransformedWeight> {
+    let nodes = expr.as_ref();
+    transforms
+        .outputs
+        .iter()
+        .enumerate()
+        .map(|(k, i)| TransformedWeight {
+            output: k,
+            node: *i,
+            op: nodes[*i].to_string(),
+            used_by: nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, node)| node.children().iter().any(|c| usize::from(*c) == *i))
+                .map(|(j, node)| (j, node.to_string()))
+                .collect(),
+        })
+        .collect()
+}
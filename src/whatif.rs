@@ -0,0 +1,186 @@
+//! Applying one rule at one node of a graph, to see what it changes
+//!
+//! Instead of saturating, the graph is added to an EGraph, a single rule is
+//! applied to the matches rooted at one eclass, and the graph is read back
+//! with the same ops everywhere except at that eclass, which takes the enode
+//! the rule added. The cost of the graph before and after tells whether the
+//! rewrite pays off on its own (it may still pay off with other rewrites).
+
+use crate::ensemble::graph_cost;
+use crate::model::*;
+use crate::optimize::CostModel;
+use egg::*;
+use std::collections::{HashMap, HashSet};
+
+/// The result of applying a rule at a node
+#[derive(Debug, Clone)]
+pub struct WhatIf {
+    pub rule: String,
+    /// Index of the node in the graph the rule was applied at
+    pub node: usize,
+    /// Number of matches of the rule at the node
+    pub num_matches: usize,
+    pub cost_before: f32,
+    pub cost_after: f32,
+    /// The rewritten graph
+    pub expr: RecExpr<Mdl>,
+}
+
+impl WhatIf {
+    /// Change in cost, negative if the rewrite makes the graph cheaper
+    pub fn delta(&self) -> f32 {
+        self.cost_after - self.cost_before
+    }
+}
+
+/// Adds a graph to a new EGraph
+///
+/// # Returns
+///
+/// The EGraph and the eclass of each node of the graph
+fn graph_egraph(expr: &RecExpr<Mdl>) -> (EGraph<Mdl, TensorAnalysis>, Vec<Id>) {
+    let mut egraph = EGraph::new(TensorAnalysis::default());
+    let mut ids: Vec<Id> = vec![];
+    for node in expr.as_ref().iter() {
+        let node = node.clone().map_children(|child| ids[usize::from(child)]);
+        ids.push(egraph.add(node));
+    }
+    egraph.rebuild();
+    (egraph, ids)
+}
+
+/// Gets the names of the rules matching at a node of a graph
+pub fn matching_rules(expr: &RecExpr<Mdl>, rewrites: &[Rewrite<Mdl, TensorAnalysis>], node: usize) -> Vec<String> {
+    let (egraph, ids) = graph_egraph(expr);
+    let target = egraph.find(ids[node]);
+    rewrites
+        .iter()
+        .filter(|rw| rw.search(&egraph).iter().any(|m| m.eclass == target))
+        .map(|rw| rw.name.to_string())
+        .collect()
+}
+
+/// Gets the names of the rules matching at each node of a graph, searching
+/// each rule once
+pub fn matching_rules_at_nodes(expr: &RecExpr<Mdl>, rewrites: &[Rewrite<Mdl, TensorAnalysis>]) -> Vec<Vec<String>> {
+    let (egraph, ids) = graph_egraph(expr);
+    let mut rules: HashMap<Id, Vec<String>> = HashMap::new();
+    for rw in rewrites.iter() {
+        for m in rw.search(&egraph) {
+            rules.entry(m.eclass).or_default().push(rw.name.to_string());
+        }
+    }
+    ids.iter()
+        .map(|id| rules.get(&egraph.find(*id)).cloned().unwrap_or_default())
+        .collect()
+}
+
+/// Reads a graph back from an EGraph, taking the enode in picks for each
+/// eclass in it and the first enode for the others
+fn build_expr(
+    egraph: &EGraph<Mdl, TensorAnalysis>,
+    picks: &HashMap<Id, Mdl>,
+    class: Id,
+    expr: &mut RecExpr<Mdl>,
+    built: &mut HashMap<Id, Id>,
+    visiting: &mut HashSet<Id>,
+) -> Result<Id, String> {
+    let class = egraph.find(class);
+    if let Some(id) = built.get(&class) {
+        return Ok(*id);
+    }
+    if !visiting.insert(class) {
+        return Err(format!("the rewritten graph has a cycle through eclass {}", class));
+    }
+    let node = picks.get(&class).cloned().unwrap_or_else(|| egraph[class].nodes[0].clone());
+    let mut children = vec![];
+    for child in node.children() {
+        children.push(build_expr(egraph, picks, *child, expr, built, visiting)?);
+    }
+    let mut children = children.into_iter();
+    let id = expr.add(node.map_children(|_| children.next().unwrap()));
+    visiting.remove(&class);
+    built.insert(class, id);
+    Ok(id)
+}
+
+/// Applies a rule at a node of a graph
+///
+/// # Parameters
+///
+/// - `expr`: the graph
+/// - `rewrites`: the rules, see rules_from_str
+/// - `rule`: name of the rule to apply (e.g. rule12)
+/// - `node`: index of the node of the graph to apply it at
+/// - `cost_model`: the cost model for the costs of the graphs
+///
+/// # Returns
+///
+/// The rewritten graph and its cost, or why the rule could not be applied
+pub fn what_if(
+    expr: &RecExpr<Mdl>,
+    rewrites: &[Rewrite<Mdl, TensorAnalysis>],
+    rule: &str,
+    node: usize,
+    cost_model: &CostModel,
+) -> Result<WhatIf, String> {
+    if node >= expr.as_ref().len() {
+        return Err(format!("the graph has no node {}", node));
+    }
+    let rewrite = rewrites
+        .iter()
+        .find(|rw| rw.name.as_str() == rule)
+        .ok_or_else(|| format!("there is no rule {}", rule))?;
+
+    let (mut egraph, ids) = graph_egraph(expr);
+    let cost_before = graph_cost(&egraph, expr, cost_model);
+    let target = egraph.find(ids[node]);
+    let matches: Vec<SearchMatches<Mdl>> = rewrite.search(&egraph).into_iter().filter(|m| m.eclass == target).collect();
+    if matches.is_empty() {
+        return Err(format!("{} does not match at node {}", rule, node));
+    }
+    let num_matches = matches.iter().map(|m| m.substs.len()).sum();
+    rewrite.apply(&mut egraph, &matches);
+    egraph.rebuild();
+
+    // The enodes of the graph, in their eclasses after the rewrite
+    let mut picks: HashMap<Id, Mdl> = HashMap::new();
+    let mut original: HashSet<Mdl> = HashSet::new();
+    for (enode, id) in expr.as_ref().iter().zip(ids.iter()) {
+        let enode = enode.clone().map_children(|child| egraph.find(ids[usize::from(child)]));
+        original.insert(enode.clone());
+        picks.entry(egraph.find(*id)).or_insert(enode);
+    }
+    let target = egraph.find(target);
+    // Prefer an enode the rule added, over one of another eclass of the
+    // graph merged into the target
+    let replaced = &picks[&target];
+    let new_node = egraph[target]
+        .iter()
+        .find(|n| !original.contains(n))
+        .or_else(|| egraph[target].iter().find(|n| *n != replaced))
+        .cloned()
+        .ok_or_else(|| format!("{} did not change the graph at node {} (e.g. the result is infeasible)", rule, node))?;
+    picks.insert(target, new_node);
+
+    let mut rewritten = RecExpr::default();
+    let roots: Vec<usize> = {
+        let used: HashSet<Id> = expr.as_ref().iter().flat_map(|n| n.children().to_vec()).collect();
+        (0..ids.len()).filter(|i| !used.contains(&Id::from(*i))).collect()
+    };
+    let mut built = HashMap::new();
+    let mut visiting = HashSet::new();
+    for root in roots.iter() {
+        build_expr(&egraph, &picks, ids[*root], &mut rewritten, &mut built, &mut visiting)?;
+    }
+    let cost_after = graph_cost(&egraph, &rewritten, cost_model);
+
+    Ok(WhatIf {
+        rule: rule.to_string(),
+        node: node,
+        num_matches: num_matches,
+        cost_before: cost_before,
+        cost_after: cost_after,
+        expr: rewritten,
+    })
+}
@@ -0,0 +1,45 @@
+use egg::*;
+use tensat::model::*;
+
+// An op made while its input was infeasible becomes feasible once the input
+// eclass gets a feasible enode
+#[test]
+fn revive_infeasible_inputs() {
+    let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+    // The shapes do not broadcast, so the ewadd and the relu using it are infeasible
+    let relu: RecExpr<Mdl> = "(relu (ewadd (input a@2_3) (input b@4_5)))".parse().unwrap();
+    let relu_id = egraph.add_expr(&relu);
+    let ewadd_id = egraph[relu_id].nodes[0].children()[0];
+    assert!(egraph[ewadd_id].data.infeasible && egraph[relu_id].data.infeasible);
+
+    let feasible: RecExpr<Mdl> = "(input c@2_3)".parse().unwrap();
+    let feasible_id = egraph.add_expr(&feasible);
+    egraph.union(ewadd_id, feasible_id);
+    egraph.rebuild();
+    let relu_id = egraph.find(relu_id);
+    assert!(!egraph[relu_id].data.infeasible);
+    assert!(!egraph[relu_id].data.meta.is_null());
+    assert!(egraph.analysis.waiting_nodes.lock().unwrap().is_empty());
+}
+
+// Tensors of the same shape share one shape in the arena, and the analysis
+// reads its dimensions from there
+#[test]
+fn shapes_in_arena() {
+    use tensat::shape::{eclass_shapes, intern_shape, shape_dims};
+    let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+    let expr: RecExpr<Mdl> = "(ewadd (relu (input x@4_8)) (input y@4_8))".parse().unwrap();
+    let add_id = egraph.add_expr(&expr);
+    let relu_id = egraph[add_id].nodes[0].children()[0];
+    let y_id = egraph[add_id].nodes[0].children()[1];
+
+    let shape = egraph[add_id].data.shape.unwrap();
+    assert_eq!(egraph[relu_id].data.shape, Some(shape));
+    assert_eq!(egraph[y_id].data.shape, Some(shape));
+    assert_eq!(intern_shape(&[4, 8]), shape);
+    assert_ne!(intern_shape(&[8, 4]), shape);
+    assert_eq!(shape_dims(shape), vec![4, 8]);
+    assert_eq!(egraph[add_id].data.shape_2, None);
+    assert_eq!(eclass_shapes(&egraph, add_id)[0].dims, vec![4, 8]);
+    assert_eq!(egraph[add_id].data.bytes, 4 * 8 * 4);
+}
@@ -0,0 +1,30 @@
+use egg::*;
+use tensat::attrs::*;
+use tensat::model::*;
+
+// Packing the attributes of a graph and unpacking them again has to give the
+// same graph
+#[test]
+fn packed_attrs_round_trip() {
+    let expr: RecExpr<Mdl> =
+        "(poolmax (conv2d 2 2 0 2 (input input_0@1_64_56_56) (weight w_0@64_64_3_3)) 3 3 2 2 1 0)"
+            .parse()
+            .unwrap();
+    let packed = pack_expr(&expr);
+    assert!(packed.to_string().starts_with("(poolmax_p (conv2d_p"));
+    assert_eq!(unpack_expr(&packed).to_string(), expr.to_string());
+}
+
+// Rules with constant or grouped attributes are migrated, rules using single
+// attribute variables are not
+#[test]
+fn packed_attrs_rules() {
+    let migrated = pack_rule_set(&["(conv2d ?sx ?sy ?p ?c ?x ?w)=>(sconv2d ?sx ?sy ?p ?c ?x ?w)"]).unwrap();
+    assert_eq!(migrated[0], "(conv2d_p ?attrs_sx_sy_p_c ?x ?w)=>(sconv2d_p ?attrs_sx_sy_p_c ?x ?w)");
+
+    let constant = pack_rule_set(&["(conv2d 1 1 0 2 ?x ?w)=>(conv2d 1 1 0 2 ?x (merge ?w 2))"]).unwrap();
+    let attrs = pack_attrs(&[1, 1], 0, 2);
+    assert_eq!(constant[0], format!("(conv2d_p {} ?x ?w)=>(conv2d_p {} ?x (merge ?w 2))", attrs, attrs));
+
+    assert!(pack_rule_set(&["(conv2d ?sx ?sy ?p 2 ?x ?w)=>(relu (conv2d ?sx ?sy ?p 0 ?x ?w))"]).is_err());
+}
@@ -0,0 +1,228 @@
+use egg::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tensat::attribution::{pattern_speedups, speedup_regions};
+use tensat::cost::*;
+use tensat::costcheck::{key_expr, CostDrift, DriftSummary};
+use tensat::device::builtin_profile;
+use tensat::microbench::LatencySummary;
+use tensat::model::*;
+use tensat::optimize::*;
+use tensat::provenance::default_layer_names;
+use tensat::stats::RunStats;
+use tensat::text::read_graph;
+
+/// Runtime of a relu growing with the square root of its input, so that the
+/// analytical runtimes (linear in the bytes) do not scale exactly as it.
+/// Counts the relus it is asked for
+struct SqrtRuntime {
+    calls: Arc<AtomicUsize>,
+}
+
+impl RuntimeModel for SqrtRuntime {
+    fn runtime(&self, _cost_model: &CostModel, egraph: &EGraph<Mdl, TensorAnalysis>, enode: &Mdl) -> f32 {
+        match enode {
+            Mdl::Relu(a) => {
+                self.calls.fetch_add(1, Ordering::Relaxed);
+                (egraph[*a].data.bytes as f32).sqrt()
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn name(&self) -> String {
+        "sqrt".to_string()
+    }
+}
+
+/// Builds relus on inputs of the given shapes, with the ids of the relus
+fn relus(dims: &[&str]) -> (EGraph<Mdl, TensorAnalysis>, Vec<Id>) {
+    let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+    let ids = dims
+        .iter()
+        .enumerate()
+        .map(|(i, d)| egraph.add_expr(&format!("(relu (input x{}@{}))", i, d).parse().unwrap()))
+        .collect();
+    egraph.rebuild();
+    (egraph, ids)
+}
+
+/// Populates the runtimes of an EGraph with sampling
+///
+/// # Returns
+///
+/// The cost model, what was sampled, and how many relus the runtime model
+/// was asked for
+fn populate(egraph: &EGraph<Mdl, TensorAnalysis>, granularity: f32, fraction: f32) -> (CostModel, SamplingReport, usize) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut cost_model = CostModel::with_setting(false).with_runtime_model(Box::new(SqrtRuntime { calls: calls.clone() }));
+    let estimate = AnalyticalRuntime {
+        peak_tflops: 1.0,
+        gb_per_sec: 1.0,
+    };
+    let sampling = ShapeSampling {
+        granularity: granularity,
+        fraction: fraction,
+    };
+    let report = cost_model.populate_sampled_runtimes(egraph, &sampling, &estimate);
+    let calls = calls.load(Ordering::Relaxed);
+    (cost_model, report, calls)
+}
+
+fn runtime(cost_model: &CostModel, egraph: &EGraph<Mdl, TensorAnalysis>, id: Id) -> f32 {
+    cost_model.runtime_model().runtime(cost_model, egraph, &egraph[id].nodes[0])
+}
+
+// Ops of the same shape are asked for once, and ops of shapes in the same
+// bucket get the runtime of its representative, scaled
+#[test]
+fn shape_buckets() {
+    let (egraph, ids) = relus(&["4_8", "4_8", "4_12", "4_16"]);
+    let (cost_model, report, calls) = populate(&egraph, 1.0, 1.0);
+    assert_eq!((report.num_buckets, calls), (3, 3));
+    assert_eq!(runtime(&cost_model, &egraph, ids[0]), runtime(&cost_model, &egraph, ids[1]));
+
+    // 12 and 16 are both in the bucket up to 2^4
+    let (cost_model, report, calls) = populate(&egraph, 2.0, 1.0);
+    assert_eq!((report.num_buckets, calls), (2, 2));
+    assert_eq!(bucket_key(&egraph, &egraph[ids[2]].nodes[0], 2.0), "relu ~2x~4");
+    let (r_12, r_16) = (runtime(&cost_model, &egraph, ids[2]), runtime(&cost_model, &egraph, ids[3]));
+    assert!((r_16 / r_12 - 16.0 / 12.0).abs() < 1e-3);
+}
+
+// Buckets that are not asked for are interpolated between the ones around
+// them, with a confidence below 1 when the analytical runtimes do not scale
+// as the measured ones
+#[test]
+fn interpolated_shapes() {
+    let (egraph, ids) = relus(&["4_8", "4_64", "4_512"]);
+    let (cost_model, report, calls) = populate(&egraph, 1.0, 0.5);
+    assert_eq!(calls, 2);
+    assert_eq!(report.measured_keys, vec!["relu 4x8", "relu 4x512"]);
+    assert_eq!(report.interpolated.len(), 1);
+    assert_eq!(report.interpolated[0].key, "relu 4x64");
+    let confidence = report.mean_confidence().unwrap();
+    assert!(confidence > 0.0 && confidence < 1.0);
+
+    // Between the runtimes the two neighbours' ratios would give it
+    let measured = (egraph[egraph[ids[1]].nodes[0].children()[0]].data.bytes as f32).sqrt();
+    let interpolated = runtime(&cost_model, &egraph, ids[1]);
+    assert!(interpolated > measured);
+    assert!(interpolated < runtime(&cost_model, &egraph, ids[2]));
+}
+
+// GPUs are matched to their profile by the model in their name
+#[test]
+fn device_profiles() {
+    assert_eq!(builtin_profile("Tesla V100-SXM2-16GB").unwrap().name, "V100");
+    assert_eq!(builtin_profile("NVIDIA GeForce RTX 4090").unwrap().name, "4090");
+    assert_eq!(builtin_profile("t4").unwrap().name, "T4");
+    assert!(builtin_profile("NVIDIA GeForce GTX 1080 Ti").is_none());
+}
+
+// Latency percentiles are nearest-rank
+#[test]
+fn latency_summary() {
+    let samples: Vec<f32> = (1..=10).rev().map(|i| i as f32).collect();
+    let summary = LatencySummary::from_samples(&samples).unwrap();
+    assert_eq!((summary.min, summary.p50, summary.p90, summary.p99, summary.max), (1.0, 5.0, 9.0, 10.0, 10.0));
+    assert_eq!(summary.mean, 5.5);
+    assert!(LatencySummary::from_samples(&[]).is_none());
+}
+
+// The ratios of measured to estimated runtimes are averaged per source
+#[test]
+fn runtime_ratios() {
+    let mut profile = builtin_profile("V100").unwrap();
+    assert_eq!(profile.record_runtime("measured", 2.0, 3.0), Some(1.5));
+    assert_eq!(profile.record_runtime("measured", 2.0, 2.0), Some(1.0));
+    assert_eq!(profile.record_runtime("analytical", 0.0, 2.0), None);
+    assert_eq!(profile.runtime_ratios["measured"].mean, 1.25);
+    assert_eq!(profile.runtime_ratios["measured"].num_samples, 2);
+    assert!(!profile.runtime_ratios.contains_key("analytical"));
+}
+
+// Cached runtimes are re-measured on the op rebuilt from their key
+#[test]
+fn cost_drift() {
+    let expr = key_expr("conv2d 1 1 0 2 1x64x56x56 64x64x3x3").unwrap();
+    assert_eq!(expr.to_string(), "(conv2d 1 1 0 2 (input t4@1_64_56_56) (input t5@64_64_3_3))");
+    let expr = key_expr("relu 1x64x56x56:f16").unwrap();
+    assert_eq!(expr.to_string(), "(relu (cast 1 (input t0@1_64_56_56)))");
+    assert!(key_expr("relu ?").is_err());
+
+    let drift = |cached, measured| CostDrift {
+        key: String::from("relu 1x4"),
+        cached: cached,
+        measured: measured,
+    };
+    let summary = DriftSummary::from_drifts(&[drift(1.0, 1.5), drift(2.0, 1.0)], 0.25).unwrap();
+    assert_eq!((summary.mean_drift, summary.mean_abs_drift, summary.max_abs_drift), (0.0, 0.5, 0.5));
+    assert_eq!(summary.num_over_threshold, 2);
+    assert!(summary.is_stale(0.25) && !summary.is_stale(0.5));
+    assert!(DriftSummary::from_drifts(&[], 0.25).is_none());
+}
+
+// The summary says whether the optimization gained anything
+#[test]
+fn runtime_summary() {
+    let mut stats = RunStats::default();
+    stats.original_runtime = Some(2.0);
+    stats.optimized_runtime = Some(1.6);
+    let summary = stats.runtime_summary();
+    assert!(summary.contains("Optimized graph, estimated") && summary.contains("-"));
+    assert!(summary.ends_with("Speedup 1.250x, 20.0% faster"));
+    stats.optimized_runtime = Some(2.5);
+    assert!(stats.runtime_summary().ends_with("No speedup: the optimized graph is not faster than the original"));
+}
+
+// The saving of a rewritten region is scaled to the measured one
+#[test]
+fn speedup_attribution() {
+    let original = read_graph("(relu (ewadd (input x@1_4) (input y@1_4)))").unwrap();
+    let optimized = read_graph("(ewadd (input x@1_4) (input y@1_4))").unwrap();
+    let names = default_layer_names(&original);
+    let mut replaced = vec![vec![]; 5];
+    replaced[1] = vec![String::from("input_1")];
+    replaced[4] = vec![String::from("ewadd_4"), String::from("relu_5")];
+    let regions = speedup_regions(
+        &original,
+        &[0.0, 0.0, 0.0, 0.0, 1.0, 0.5],
+        &names,
+        &optimized,
+        &[0.0, 0.0, 0.0, 0.0, 1.0],
+        &replaced,
+        Some(0.25),
+    );
+    assert_eq!(regions.len(), 1);
+    assert_eq!(regions[0].pattern, "ewadd+relu -> ewadd");
+    assert_eq!((regions[0].estimated_saved_ms(), regions[0].measured_saved_ms), (0.5, 0.25));
+    let patterns = pattern_speedups(&regions);
+    assert_eq!((patterns[0].num_regions, patterns[0].measured_saved_ms), (1, 0.25));
+}
+
+// Concurrent runs sharing a cost cache keep each other's runtimes
+#[test]
+fn shared_cost_cache() {
+    let file = std::env::temp_dir().join(format!("tensat_cost_cache_{}.json", std::process::id()));
+    let filename = file.to_str().unwrap().to_string();
+    let load = |entries: &str| {
+        std::fs::write(&file, entries).unwrap();
+        RuntimeCache::load(&filename).unwrap()
+    };
+    let first = load(r#"{"relu 1x4": 1.0}"#);
+    let second = load(r#"{"tanh 1x4": 2.0}"#);
+    std::fs::remove_file(&file).unwrap();
+    let writers: Vec<_> = vec![first, second]
+        .into_iter()
+        .map(|cache| {
+            let filename = filename.clone();
+            std::thread::spawn(move || cache.save(&filename).unwrap())
+        })
+        .collect();
+    writers.into_iter().for_each(|writer| writer.join().unwrap());
+    let saved = RuntimeCache::load(&filename).unwrap().entries();
+    assert_eq!((saved["relu 1x4"], saved["tanh 1x4"]), (1.0, 2.0));
+    std::fs::remove_file(&file).unwrap();
+    std::fs::remove_file(format!("{}.lock", filename)).unwrap();
+}
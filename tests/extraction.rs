@@ -0,0 +1,108 @@
+use egg::*;
+use std::collections::BTreeMap;
+use std::fs;
+use tensat::corpus::*;
+use tensat::device::DeviceProfile;
+use tensat::extract::{extract, ExtractionStrategy};
+use tensat::model::*;
+use tensat::optimize::CostModel;
+use tensat::strategies::{custom_strategy_names, register_strategy, CustomStrategy};
+use tensat::targets::{extract_for_targets, Target};
+
+// Greedy extraction has to give a valid graph for every case of the corpus,
+// and can not be cheaper than the optimum
+#[test]
+fn greedy_on_corpus() {
+    let cases = load_corpus("extractor/corpus");
+    assert!(!cases.is_empty());
+    for (name, case) in cases.iter() {
+        let result = check_solution(name, case, "greedy", Ok(greedy_solution(case)), false);
+        assert!(result.passed(), "{}: {:?}", name, result.error);
+    }
+}
+
+// A graph is only dominated by one at least as good on every metric
+#[test]
+fn pareto_dominance() {
+    use tensat::extract::GraphMetrics;
+    let fast = GraphMetrics { cost: 1.0, num_ops: 10, memory: 400 };
+    let small = GraphMetrics { cost: 2.0, num_ops: 10, memory: 100 };
+    let worse = GraphMetrics { cost: 2.0, num_ops: 12, memory: 400 };
+    assert!(!fast.dominates(&small) && !small.dominates(&fast));
+    assert!(fast.dominates(&worse));
+    assert!(!fast.dominates(&fast));
+}
+
+// Each target gets its graph written to its own file, with a cost from the
+// speed of its device
+#[test]
+fn target_extraction() {
+    let expr: RecExpr<Mdl> = "(relu (matmul 0 (input x@64_256) (weight w@256_64)))".parse().unwrap();
+    let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+    let root = egraph.add_expr(&expr);
+    egraph.rebuild();
+    let profile = |name: &str, peak_tflops, gb_per_sec| {
+        Target::Profile(DeviceProfile {
+            name: name.to_string(),
+            peak_tflops: peak_tflops,
+            gb_per_sec: gb_per_sec,
+            runtime_ratios: BTreeMap::new(),
+        })
+    };
+    // The edge device is 100 times slower on compute and memory alike
+    let targets = vec![profile("Server GPU", 100.0, 1000.0), profile("Jetson AGX/Orin", 1.0, 10.0)];
+    let dir = std::env::temp_dir().join(format!("tensat_targets_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let results = extract_for_targets(
+        &egraph,
+        root,
+        &targets,
+        |runtime_model| CostModel::with_setting(false).with_runtime_model(runtime_model),
+        &ExtractionStrategy::Greedy,
+        false,
+        &dir,
+    );
+    let files: Vec<&str> = results.iter().map(|r| r.file.as_str()).collect();
+    assert_eq!(files, vec!["optimized_server_gpu.txt", "optimized_jetson_agx_orin.txt"]);
+    for result in results.iter() {
+        assert_eq!(fs::read_to_string(dir.join(&result.file)).unwrap(), expr.to_string());
+    }
+    assert!((results[1].cost / results[0].cost - 100.0).abs() < 1.0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Extracts the first enode of each eclass, with the number of nodes as its
+/// cost
+struct FirstEnode;
+
+impl CustomStrategy for FirstEnode {
+    fn extract(&self, egraph: &EGraph<Mdl, TensorAnalysis>, root: Id, _cost_model: &CostModel) -> (RecExpr<Mdl>, f32) {
+        fn add(egraph: &EGraph<Mdl, TensorAnalysis>, id: Id, expr: &mut RecExpr<Mdl>) -> Id {
+            let node = egraph[id].nodes[0].clone().map_children(|child| add(egraph, child, expr));
+            expr.add(node)
+        }
+        let mut expr = RecExpr::default();
+        add(egraph, root, &mut expr);
+        let cost = expr.as_ref().len() as f32;
+        (expr, cost)
+    }
+}
+
+// A registered strategy is selected by its name like the built-in ones, and
+// the crate's names and the registered ones can not be taken again
+#[test]
+fn registered_strategy() {
+    assert!(register_strategy("greedy", Box::new(FirstEnode)).is_err());
+    register_strategy("first_enode", Box::new(FirstEnode)).unwrap();
+    assert!(register_strategy("first_enode", Box::new(FirstEnode)).is_err());
+    assert!(custom_strategy_names().contains(&String::from("first_enode")));
+
+    let expr: RecExpr<Mdl> = "(relu (ewadd (input x@1_4) (input y@1_4)))".parse().unwrap();
+    let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+    let root = egraph.add_expr(&expr);
+    let strategy = ExtractionStrategy::Custom(String::from("first_enode"));
+    assert_eq!(strategy.name(), "first_enode");
+    let (best, cost, _) = extract(&egraph, root, &CostModel::with_setting(false), &strategy);
+    assert_eq!(best.to_string(), expr.to_string());
+    assert_eq!(cost, expr.as_ref().len() as f32);
+}
@@ -0,0 +1,27 @@
+// The counters of TASO graphs and tensors are global to the process, so the
+// ownership of the TASO side gets a test binary of its own
+
+use egg::*;
+use tensat::model::*;
+use tensat::resources::ResourceUsage;
+
+// The TASO graph of an EGraph is freed with it, but only once the tensors
+// taken out of its eclasses are dropped too
+#[test]
+fn taso_graph_freed() {
+    let expr: RecExpr<Mdl> = "(relu (input x@1_64_56_56))".parse().unwrap();
+    let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+    let relu_id = egraph.add_expr(&expr);
+    let kept = egraph[relu_id].data.meta.clone();
+    let usage = ResourceUsage::collect(None);
+    assert_eq!((usage.taso_graphs, usage.taso_graphs_freed), (1, 0));
+    assert_eq!((usage.taso_tensors, usage.taso_tensors_freed), (2, 0));
+
+    drop(egraph);
+    assert_eq!(ResourceUsage::collect(None).taso_graphs_freed, 0);
+    assert_eq!(unsafe { (*kept.ptr()).dim[..4].to_vec() }, vec![1, 64, 56, 56]);
+
+    drop(kept);
+    let usage = ResourceUsage::collect(None);
+    assert_eq!((usage.taso_graphs_freed, usage.taso_tensors_freed), (1, 2));
+}
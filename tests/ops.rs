@@ -0,0 +1,189 @@
+use egg::*;
+use std::collections::HashMap;
+use tensat::assertions::{tensor_names, ShapeAssertions};
+use tensat::builder::{Activation, GraphBuilder, Padding, Stride};
+use tensat::custom::{decompose, register_op, CustomOp};
+use tensat::model::*;
+use tensat::shape::{broadcast_shape, TensorShape};
+use tensat::specialize::{batch_size, is_batch_symbolic, with_batch_size};
+use tensat::text::*;
+use tensat::validate::validate_graph;
+
+// 0-d and 1-d tensors are written and read back like any other
+#[test]
+fn low_rank_tensors() {
+    let expr: RecExpr<Mdl> = "(ewadd (input bias@64) (relu (weight s@)))".parse().unwrap();
+    let text = to_text(&expr, None);
+    assert!(text.contains("dims=[]"));
+    assert_eq!(from_text(&text).unwrap().to_string(), expr.to_string());
+    assert!(validate_graph(&expr).is_ok());
+}
+
+// Elementwise ops broadcast their inputs as in NumPy
+#[test]
+fn broadcasting() {
+    assert_eq!(broadcast_shape(&[1, 64, 56, 56], &[64, 1, 1]), Some(vec![1, 64, 56, 56]));
+    assert_eq!(broadcast_shape(&[2, 3], &[]), Some(vec![2, 3]));
+    assert_eq!(broadcast_shape(&[2, 3], &[4]), None);
+    let expr: RecExpr<Mdl> = "(ewmul (input a@2_3) (input b@4))".parse().unwrap();
+    assert!(validate_graph(&expr).is_err());
+}
+
+fn repeat_shape(inputs: &[Vec<i32>], attrs: &[i32]) -> Result<Vec<i32>, String> {
+    let mut dims = inputs[0].clone();
+    dims[1] *= attrs[0];
+    Ok(dims)
+}
+
+// Ops registered at runtime are read as custom nodes and written back as
+// themselves, under names the language does not use
+#[test]
+fn custom_ops() {
+    let repeat = CustomOp {
+        name: String::from("repeat_channels"),
+        arity: 1,
+        attrs: vec![String::from("times")],
+        shape: repeat_shape,
+        cost: None,
+        decomposition: None,
+        rules: vec![],
+    };
+    register_op(repeat.clone()).unwrap();
+    let text = "# tensat graph\nt1 = input(name=x, dims=[1,8,4,4])\nt2 = repeat_channels(t1, times=3)\nreturn t2";
+    let expr = from_text(text).unwrap();
+    assert_eq!(expr.to_string(), "(custom repeat_channels:3 (input x@1_8_4_4))");
+    assert!(to_text(&expr, None).contains("repeat_channels(t1, times=3)"));
+    assert!(validate_graph(&expr).is_ok());
+    assert!(register_op(CustomOp {
+        name: String::from("relu"),
+        ..repeat
+    })
+    .is_err());
+}
+
+// Rebuilding a graph at another batch size changes the inputs and the reshapes
+// keeping the batch in front
+#[test]
+fn batch_sizes() {
+    let expr: RecExpr<Mdl> = "(reshape (relu (input x@4_8_2_2)) 4_32)".parse().unwrap();
+    assert_eq!(batch_size(&expr), Some(4));
+    let rebatched = with_batch_size(&expr, 16).unwrap();
+    assert_eq!(rebatched.to_string(), "(reshape (relu (input x@16_8_2_2)) 16_32)");
+}
+
+// Repacked weights keep the shape of the weight, in the known layouts only
+#[test]
+fn weight_layouts() {
+    let expr: RecExpr<Mdl> = "(conv2d_b 1 1 0 2 (input input_0@1_64_56_56) (repack 1 (weight w_0@64_64_3_3)))"
+        .parse()
+        .unwrap();
+    let text = to_text(&expr, None);
+    assert!(text.contains("layout=1"));
+    assert_eq!(from_text(&text).unwrap().to_string(), expr.to_string());
+    assert!(validate_graph(&expr).is_ok());
+    let unknown: RecExpr<Mdl> = "(repack 2 (weight w_0@64_64_3_3))".parse().unwrap();
+    assert!(validate_graph(&unknown).is_err());
+}
+
+// concat_n takes any number of inputs, written as positional tensors
+#[test]
+fn n_way_concat() {
+    let expr: RecExpr<Mdl> =
+        "(split_i 1 3 2 (concat_n 1 4 (input a@1_8_4_4) (input b@1_8_4_4) (input c@1_16_4_4)))".parse().unwrap();
+    let text = to_text(&expr, None);
+    assert!(text.contains("axis=1, ndim=4"));
+    assert_eq!(from_text(&text).unwrap().to_string(), expr.to_string());
+    assert!(validate_graph(&expr).is_ok());
+    let out_of_range: RecExpr<Mdl> = "(split_i 1 3 3 (input a@1_8_4_4))".parse().unwrap();
+    assert!(validate_graph(&out_of_range).is_err());
+}
+
+// A symbolic batch dimension is kept when writing the graph, and is the only
+// dimension set when rebuilding it at a batch size
+#[test]
+fn symbolic_batch() {
+    let expr: RecExpr<Mdl> = "(reshape (ewadd (input x@N_8) (weight b@1_8)) N_2_4)".parse().unwrap();
+    assert!(is_batch_symbolic(&expr));
+    let text = to_text(&expr, None);
+    assert!(text.contains("dims=[N,8]"));
+    assert_eq!(from_text(&text).unwrap().to_string(), expr.to_string());
+    let rebatched = with_batch_size(&expr, 16).unwrap();
+    assert_eq!(rebatched.to_string(), "(reshape (ewadd (input x@16_8) (weight b@1_8)) 16_2_4)");
+    assert!(read_graph("(relu (input x@8_N))").is_err());
+}
+
+// Casts keep the shape of their input, to the known element types only
+#[test]
+fn casts() {
+    let expr: RecExpr<Mdl> = "(relu (cast 1 (input x@1_8)))".parse().unwrap();
+    let text = to_text(&expr, None);
+    assert!(text.contains("elem=1"));
+    assert_eq!(from_text(&text).unwrap().to_string(), expr.to_string());
+    assert!(validate_graph(&expr).is_ok());
+    let unknown: RecExpr<Mdl> = "(cast 3 (input x@1_8))".parse().unwrap();
+    assert!(validate_graph(&unknown).is_err());
+}
+
+// The typed builder gives the same graph as the s-expression, without the
+// nodes the output does not use
+#[test]
+fn graph_builder() {
+    let mut g = GraphBuilder::new();
+    let x = g.input(&[1, 64, 56, 56]);
+    let w = g.weight(&[64, 64, 3, 3]);
+    let _unused = g.weight(&[8, 8]);
+    let y = g.conv2d(x, w, Stride(1, 1), Padding::Same, Activation::Relu);
+    assert_eq!(y.dims(), &[1, 64, 56, 56]);
+    let expr = g.build(&[y]);
+    assert_eq!(expr.to_string(), "(conv2d 1 1 0 2 (input input_0@1_64_56_56) (weight w_0@64_64_3_3))");
+}
+
+// Shape assertions name tensors by input name or layer name, and the ones
+// naming tensors the graph no longer has are only errors when strict
+#[test]
+fn shape_assertions() {
+    let expr: RecExpr<Mdl> = "(relu (conv2d 1 1 0 2 (input x@1_8_4_4) (weight w@8_8_3_3)))".parse().unwrap();
+    let tensors = tensor_names(&expr, &HashMap::new());
+    assert_eq!(tensors["x"], 5);
+    assert_eq!(tensors["conv2d_8"], 8);
+    let mut shapes = vec![None; expr.as_ref().len()];
+    shapes[8] = Some(vec![TensorShape {
+        dims: vec![1, 8, 4, 4],
+        dtype: Default::default(),
+    }]);
+    let mut assertions = ShapeAssertions::default();
+    assertions.shapes.insert("conv2d_8".to_string(), vec![1, 8, 4, 4]);
+    assert!(assertions.check(&tensors, &shapes, true).is_empty());
+    assertions.shapes.insert("conv2d_8:1".to_string(), vec![1, 8, 4, 4]);
+    assertions.shapes.insert("y".to_string(), vec![1]);
+    assert_eq!(assertions.check(&tensors, &shapes, true).len(), 2);
+    assert_eq!(assertions.check(&tensors, &shapes, false).len(), 1);
+}
+
+fn same_shape(inputs: &[Vec<i32>], _attrs: &[i32]) -> Result<Vec<i32>, String> {
+    Ok(inputs[0].clone())
+}
+
+// A registered op without a cost function is built from its decomposition
+// at the shapes of its inputs
+#[test]
+fn custom_op_decomposition() {
+    let op = CustomOp {
+        name: String::from("gated_relu"),
+        arity: 2,
+        attrs: vec![String::from("axis")],
+        shape: same_shape,
+        cost: None,
+        decomposition: Some(String::from("(softmax (ewmul (relu ?x0) ?x1) ?a0)")),
+        rules: vec![],
+    };
+    let expr = decompose(&op, &[vec![1, 8], vec![1, 8]], &[1]).unwrap();
+    assert_eq!(expr.to_string(), "(softmax (ewmul (relu (input x0@1_8)) (input x1@1_8)) 1)");
+    assert!(decompose(&op, &[vec![1, 8]], &[1]).is_err());
+    assert!(register_op(CustomOp {
+        name: String::from("bad_decomposition"),
+        decomposition: Some(String::from("(relu")),
+        ..op
+    })
+    .is_err());
+}
@@ -1,4 +1,9 @@
+use egg::*;
+use tensat::flatbuf::*;
+use tensat::model::*;
+use tensat::onnx::*;
 use tensat::parse::*;
+use tensat::shape::*;
 
 // parse_model takes a string which is the serialized model from taso
 // here the string is the content of bts.model.
@@ -599,3 +604,156 @@ fn model_parser() {
 64,1024",
     );
 }
+
+// A matmul of mismatched shapes fails the import, unless it is permissive
+#[test]
+fn import_modes() {
+    let model = "1
+0
+0:0
+64,1024
+2
+1
+0:0
+512,1024
+3
+18
+1:0,2:0
+0";
+    let (_, report) = parse_model_with_mode(model, ImportMode::Strict);
+    assert_eq!(report.num_failed(), 1);
+    let (_, report) = parse_model_with_mode(model, ImportMode::Permissive);
+    assert_eq!((report.num_failed(), report.num_opaque()), (0, 1));
+}
+
+// Encodes a protobuf field, for building small ONNX models
+fn varint(mut v: u64, out: &mut Vec<u8>) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn field(num: u64, bytes: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    varint(num << 3 | 2, &mut out);
+    varint(bytes.len() as u64, &mut out);
+    out.extend_from_slice(bytes);
+    out
+}
+
+fn onnx_model(op_type: &str) -> Vec<u8> {
+    let dim = |d: u64| {
+        let mut v = vec![];
+        varint(1 << 3, &mut v);
+        varint(d, &mut v);
+        field(1, &v)
+    };
+    // Graph input x of shape [1, 4]
+    let shape = [dim(1), dim(4)].concat();
+    let x = [field(1, b"x"), field(2, &field(1, &field(2, &shape)))].concat();
+    // Initializer w of shape [4, 8]
+    let mut w = vec![];
+    for d in [4, 8].iter() {
+        varint(1 << 3, &mut w);
+        varint(*d, &mut w);
+    }
+    w.extend(field(8, b"w"));
+    let node = |op: &str, inputs: &[&str], output: &str| {
+        let mut n = vec![];
+        for i in inputs.iter() {
+            n.extend(field(1, i.as_bytes()));
+        }
+        [n, field(2, output.as_bytes()), field(3, output.as_bytes()), field(4, op.as_bytes())].concat()
+    };
+    let graph = [
+        field(1, &node("MatMul", &["x", "w"], "y")),
+        field(1, &node(op_type, &["y"], "z")),
+        field(5, &w),
+        field(11, &x),
+        field(12, &field(1, b"z")),
+    ]
+    .concat();
+    field(7, &graph)
+}
+
+// Inputs and initializers keep their names, unsupported ops fail the import
+#[test]
+fn onnx_import() {
+    let expr = import_onnx(&onnx_model("Relu")).unwrap();
+    assert_eq!(expr.to_string(), "(relu (matmul 0 (input x@1_4) (weight w@4_8)))");
+    let expr = import_onnx(&onnx_model("Softmax")).unwrap();
+    assert_eq!(expr.to_string(), "(softmax (matmul 0 (input x@1_4) (weight w@4_8)) 1)");
+    let err = import_onnx(&onnx_model("LRN")).unwrap_err();
+    assert!(err.contains("unsupported op LRN"));
+}
+
+// An exported graph imports back to the same graph, with the same names
+#[test]
+fn onnx_export() {
+    let expr = import_onnx(&onnx_model("Relu")).unwrap();
+    let shape = |dims: &[i32]| {
+        Some(vec![TensorShape {
+            dims: dims.to_vec(),
+            dtype: ElemType::Float32,
+        }])
+    };
+    // x@1_4, input, w@4_8, weight, 0, matmul, relu
+    let shapes = vec![Some(vec![]), shape(&[1, 4]), Some(vec![]), shape(&[4, 8]), Some(vec![]), shape(&[1, 8]), shape(&[1, 8])];
+    let exported = export_onnx(&expr, &shapes, ExecutionProvider::Standard, DEFAULT_ONNX_OPSET).unwrap();
+    assert_eq!(import_onnx(&exported).unwrap().to_string(), expr.to_string());
+
+    // Fused activations are written as ONNX Runtime's fused ops
+    let fused: RecExpr<Mdl> = "(matmul 2 (input x@1_4) (weight w@4_8))".parse().unwrap();
+    let exported = export_onnx(&fused, &shapes[..6], ExecutionProvider::OnnxRuntime, DEFAULT_ONNX_OPSET).unwrap();
+    let contains = |s: &str| exported.windows(s.len()).any(|w| w == s.as_bytes());
+    assert!(contains("FusedGemm") && contains("com.microsoft") && !contains("MatMul"));
+}
+
+// Ops whose definition changed between opsets are written as the target
+// opset expects them
+#[test]
+fn onnx_export_opsets() {
+    let shape = |dims: &[i32]| TensorShape {
+        dims: dims.to_vec(),
+        dtype: ElemType::Float32,
+    };
+    let split: RecExpr<Mdl> = "(split_0 (split 1 (input x@2_8)))".parse().unwrap();
+    let shapes = vec![
+        Some(vec![]),
+        Some(vec![]),
+        Some(vec![shape(&[2, 8])]),
+        Some(vec![shape(&[2, 4]), shape(&[2, 4])]),
+        Some(vec![shape(&[2, 4])]),
+    ];
+    let contains = |bytes: &[u8], s: &str| bytes.windows(s.len()).any(|w| w == s.as_bytes());
+    // From 13 the sizes of the outputs are a constant input, before an attribute
+    let exported = export_onnx(&split, &shapes, ExecutionProvider::Standard, 13).unwrap();
+    assert!(contains(&exported, "t3_split"));
+    let exported = export_onnx(&split, &shapes, ExecutionProvider::Standard, 11).unwrap();
+    assert!(!contains(&exported, "t3_split") && contains(&exported, "split"));
+
+    // Before 13, Softmax flattens the dimensions from its axis
+    let softmax = |axis: i32| -> RecExpr<Mdl> { format!("(softmax (input x@2_8) {})", axis).parse().unwrap() };
+    let shapes = vec![Some(vec![]), Some(vec![shape(&[2, 8])]), Some(vec![]), Some(vec![shape(&[2, 8])])];
+    assert!(export_onnx(&softmax(0), &shapes, ExecutionProvider::Standard, 13).is_ok());
+    assert!(export_onnx(&softmax(1), &shapes, ExecutionProvider::Standard, 11).is_ok());
+    let err = export_onnx(&softmax(0), &shapes, ExecutionProvider::Standard, 11).unwrap_err();
+    assert!(err.contains("needs opset 13"));
+
+    for opset in &[MIN_ONNX_OPSET - 1, MAX_ONNX_OPSET + 1] {
+        assert!(export_onnx(&softmax(1), &shapes, ExecutionProvider::Standard, *opset).is_err());
+    }
+}
+
+// The FlatBuffer has the identifier, is aligned and lists the tensor nodes
+#[test]
+fn flatbuffer_export() {
+    let expr: RecExpr<Mdl> = "(noop (relu (input x@1_64)) (matmul 0 (input y@1_64) (weight w@64_64)))".parse().unwrap();
+    let buf = export_flatbuffer(&expr).unwrap();
+    assert_eq!(&buf[4..8], FLATBUFFER_IDENTIFIER);
+    assert_eq!(buf.len() % 4, 0);
+    assert_eq!(flatbuffer_ops(&buf).unwrap(), vec!["input", "relu", "input", "weight", "matmul"]);
+    assert!(flatbuffer_ops(&buf[8..]).is_err());
+}
@@ -0,0 +1,135 @@
+use egg::*;
+use tensat::blocks::{builtin_blocks, union_blocks, BUILTIN_BLOCKS};
+use tensat::model::*;
+use tensat::rewrites::{RuleTier, Safety};
+use tensat::rulecache::{is_cacheable, ClassSignature, ConditionCache};
+use tensat::rulefile::parse_rule_text;
+use tensat::rulesets::RuleSelection;
+use tensat::shape::{intern_shape, ElemType};
+use tensat::suggest::{changed_ops, Suggestion};
+use tensat::verify::{check_rule, rule_tiers, RuleCheck};
+
+// A rule is checked by evaluating both sides on random inputs
+#[test]
+fn rule_verification() {
+    assert!(matches!(
+        check_rule("(ewadd ?input_1 ?input_2)=>(ewadd ?input_2 ?input_1)", 4),
+        RuleCheck::Agrees(n) if n > 0
+    ));
+    assert!(matches!(
+        check_rule("(ewadd ?input_1 ?input_2)=>(ewmul ?input_1 ?input_2)", 4),
+        RuleCheck::Diverges(_, _)
+    ));
+    assert!(matches!(check_rule("(ewadd ?input_1", 4), RuleCheck::Unchecked(_)));
+}
+
+// Rules files are checked against the language (ops, arities, variables)
+#[test]
+fn rule_files() {
+    let rules = parse_rule_text(
+        "# comment\n(relu (relu ?input_1))=>(relu ?input_1)\n\n(relu ?input_1)=>(relu ?input_1);(relu ?input_2)=>(relu ?input_2)\n",
+    )
+    .unwrap();
+    assert_eq!(rules.rules, vec!["(relu (relu ?input_1))=>(relu ?input_1)"]);
+    assert_eq!(rules.multi_rules.len(), 1);
+    assert_eq!(rules.multi_rule_pairs().len(), 2);
+    let errors = parse_rule_text("(relu ?input_1 ?input_2)=>?input_1\n(relu ?input_1)=>?input_2\n?input_1=>(relu ?input_1)").unwrap_err();
+    assert_eq!(errors.len(), 3);
+    assert!(errors[1].contains("?input_2 is not bound"));
+}
+
+// The built-in blocks are valid rules, and a match of a block in the
+// starting EGraph gets its optimized form in its eclass
+#[test]
+fn blocks() {
+    for (name, rule) in BUILTIN_BLOCKS.iter() {
+        assert!(parse_rule_text(rule).is_ok(), "{}", name);
+    }
+    let expr: RecExpr<Mdl> = "(relu (conv2d 1 1 0 0 (input x@1_8_4_4) (weight w@8_8_3_3)))".parse().unwrap();
+    let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+    let root = egraph.add_expr(&expr);
+    let counts = union_blocks(&mut egraph, &builtin_blocks(), false);
+    let matched: Vec<&(String, usize)> = counts.iter().filter(|(_, n)| *n > 0).collect();
+    assert_eq!(matched, vec![&(String::from("conv_relu"), 1)]);
+    let fused = egraph.add_expr(&"(conv2d 1 1 0 2 (input x@1_8_4_4) (weight w@8_8_3_3))".parse().unwrap());
+    assert_eq!(egraph.find(fused), egraph.find(root));
+}
+
+// Rules are proven with the axioms, or else exact or approximate
+#[test]
+fn rule_safety_tiers() {
+    let tiers = rule_tiers(&[
+        "(ewadd ?input_1 ?input_2)=>(ewadd ?input_2 ?input_1)",
+        "(relu ?input_1)=>(relu (relu ?input_1))",
+        "(ewadd ?input_1 ?input_2)=>(ewmul ?input_1 ?input_2)",
+    ]);
+    assert_eq!(tiers, vec![RuleTier::Proven, RuleTier::Exact, RuleTier::Approximate]);
+    assert!(Safety::Strict.allows(RuleTier::Proven) && !Safety::Strict.allows(RuleTier::Exact));
+    assert!(Safety::Normal.allows(RuleTier::Exact) && !Safety::Normal.allows(RuleTier::Approximate));
+    assert!(Safety::Aggressive.allows(RuleTier::Approximate));
+}
+
+// Shape checks are cached per right hand side and signature until cleared
+#[test]
+fn condition_cache() {
+    let relu: Pattern<Mdl> = "(relu ?x)".parse().unwrap();
+    let split: Pattern<Mdl> = "(split_0 (split 1 ?x))".parse().unwrap();
+    assert!(is_cacheable(&relu.ast));
+    assert!(!is_cacheable(&split.ast));
+    let signature = vec![ClassSignature {
+        dtype: DataKind::Tnsr,
+        val: 0,
+        name: Symbol::from(""),
+        shape: Some(intern_shape(&[1, 64])),
+        elem: ElemType::Float32,
+        infeasible: false,
+    }];
+    let mut cache = ConditionCache::new(true);
+    assert_eq!(cache.get(&relu.ast, &signature), None);
+    cache.insert(&relu.ast, signature.clone(), true);
+    assert_eq!(cache.get(&relu.ast, &signature), Some(true));
+    assert_eq!(cache.hit_rate(), 0.5);
+    cache.clear();
+    assert_eq!(cache.get(&relu.ast, &signature), None);
+}
+
+// A substitution is told by the ops of the graph it removes and adds, the
+// shared subgraphs are left out
+#[test]
+fn suggested_substitution_ops() {
+    let before: RecExpr<Mdl> = "(relu (ewadd (relu (input x@1_8)) (relu (input x@1_8))))".parse().unwrap();
+    let after: RecExpr<Mdl> = "(relu (smul (relu (input x@1_8)) 2))".parse().unwrap();
+    let (removed, added) = changed_ops(&before, &after);
+    let ops = |expr: &RecExpr<Mdl>, ids: &[usize]| ids.iter().map(|i| expr.as_ref()[*i].to_string()).collect::<Vec<_>>();
+    assert_eq!(ops(&before, &removed), vec!["ewadd", "relu"]);
+    assert_eq!(ops(&after, &added), vec!["smul", "relu"]);
+    let suggestion = Suggestion {
+        rule: String::from("rule3"),
+        node: 4,
+        replaced: vec![String::from("ewadd_3"), String::from("relu_4")],
+        added: vec![String::from("smul"), String::from("relu")],
+        delta: -0.8,
+    };
+    assert_eq!(suggestion.describe("ms"), "replace ewadd_3, relu_4 with smul, relu, est. -0.800 ms (rule3 at node 4)");
+}
+
+// A quantized model only keeps the exact rules, the multi-pattern rules by
+// pairs, and the empty lines of a rule file are dropped
+#[test]
+fn quantized_rule_selection() {
+    let exact = "(ewadd ?input_1 ?input_2)=>(ewadd ?input_2 ?input_1)";
+    let changing = "(ewadd ?input_1 (ewadd ?input_2 ?input_3))=>(ewadd (ewadd ?input_1 ?input_2) ?input_3)";
+    let selection = RuleSelection::default();
+    assert_eq!(selection.select_rules(vec![exact, "", changing]), vec![exact, changing]);
+
+    let selection = RuleSelection {
+        quantized: true,
+        ..RuleSelection::default()
+    };
+    assert_eq!(selection.select_rules(vec![exact, "", changing]), vec![exact]);
+    let multi_rules = vec![(exact, true), (exact, true), (exact, false), (changing, false)];
+    assert_eq!(
+        selection.select_multi_rules(multi_rules),
+        vec![(exact.to_string(), true), (exact.to_string(), true)]
+    );
+}
@@ -0,0 +1,127 @@
+use egg::*;
+use serde_json::{Map, Value};
+use std::fs;
+use std::process::Command;
+use tensat::checkpoint::EGraphCheckpoint;
+use tensat::extract::{extract, ExtractionStrategy};
+use tensat::model::*;
+use tensat::optimize::CostModel;
+use tensat::replay::ReplayRecord;
+use tensat::resources::status_kb;
+use tensat::rewrites::rules_from_str;
+use tensat::selfcheck::*;
+use tensat::stages::*;
+
+// Peak memory is read from the kB fields of /proc/self/status
+#[test]
+fn resource_status() {
+    let status = "Name:\ttensat\nVmPeak:\t  900000 kB\nVmHWM:\t  123456 kB\nVmHWMx:\t 1 kB\n";
+    assert_eq!(status_kb(status, "VmHWM"), Some(123456));
+    assert_eq!(status_kb(status, "VmPeak"), Some(900000));
+    assert_eq!(status_kb(status, "VmRSS"), None);
+}
+
+fn settings(pairs: &[(&str, &str)]) -> Map<String, Value> {
+    pairs.iter().map(|(k, v)| (k.to_string(), Value::String(v.to_string()))).collect()
+}
+
+// An artifact of the saturate stage has the input, the settings and the
+// EGraph the extract stage restores, with the analysis of the saturation, and
+// the extraction is refused with other settings that change the EGraph
+#[test]
+fn stage_artifact() {
+    let dir = std::env::temp_dir().join(format!("tensat_stages_{}", std::process::id()));
+    let dir = dir.to_str().unwrap();
+    let input = "(ewadd (relu (input x@1_64)) (input y@1_64))";
+    let expr: RecExpr<Mdl> = input.parse().unwrap();
+    // Not the defaults of TensorAnalysis
+    let analysis = AnalysisSettings {
+        condition_cache: true,
+        measure_power: false,
+        recomputed_ops: vec![String::from("relu")].into_iter().collect(),
+    };
+    let runner = Runner::<Mdl, TensorAnalysis, ()>::new(TensorAnalysis::with_settings(&analysis).unwrap())
+        .with_iter_limit(2)
+        .with_expr(&expr)
+        .run(&rules_from_str(vec!["(ewadd ?input_1 ?input_2)=>(ewadd ?input_2 ?input_1)"], false));
+    let saturated = settings(&[("batch_size", "1"), ("packed_attrs", "true"), ("objective", "latency")]);
+    save_artifact(dir, input, &saturated).unwrap();
+    EGraphCheckpoint::take(&runner, 0.5).save(artifact_egraph(dir).to_str().unwrap());
+
+    assert_eq!(fs::read_to_string(artifact_input(dir)).unwrap(), input);
+    let loaded = load_artifact_settings(dir).unwrap();
+    assert_eq!(loaded, saturated);
+    // The objective only changes the extraction
+    let same = settings(&[("batch_size", "1"), ("packed_attrs", "true"), ("objective", "energy")]);
+    assert!(setting_mismatches(&loaded, &same).is_empty());
+    assert_eq!(
+        setting_mismatches(&loaded, &settings(&[("batch_size", "8")])),
+        vec![
+            "packed_attrs is not set but was \"true\" in the saturation",
+            "batch_size is \"8\" but was \"1\" in the saturation",
+        ]
+    );
+
+    let checkpoint = EGraphCheckpoint::load(artifact_egraph(dir).to_str().unwrap()).unwrap();
+    assert_eq!(checkpoint.num_nodes(), runner.egraph.total_number_of_nodes());
+    assert_eq!(checkpoint.analysis, analysis);
+    let (egraph, roots) = checkpoint.restore().unwrap();
+    assert_eq!(egraph.analysis.settings(), analysis);
+    assert!(egraph.analysis.condition_cache.enabled);
+    assert_eq!(egraph.number_of_classes(), runner.egraph.number_of_classes());
+    let cost_model = CostModel::with_setting(false);
+    let (_, cost, _) = extract(&egraph, roots[0], &cost_model, &ExtractionStrategy::Greedy);
+    let (_, saturated_cost, _) = extract(&runner.egraph, runner.roots[0], &cost_model, &ExtractionStrategy::Greedy);
+    assert_eq!(cost, saturated_cost);
+
+    fs::remove_dir_all(dir).unwrap();
+    assert!(load_artifact_settings(dir).is_err());
+}
+
+// The deterministic mode drops the time limits, and the runs of a self check
+// write to their own directory
+#[test]
+fn self_check_arguments() {
+    let args: Vec<String> = ["-m", "optimize", "--n_sec=30", "--ilp_time_sec", "60", "--deterministic", "--self_check", "3", "--output_dir", "out"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let det = deterministic_args(&args);
+    assert_eq!(arg_value(&det, "--ilp_time_sec"), None);
+    assert_eq!(arg_value(&det, "--runtime_source"), Some("analytical"));
+    assert_eq!(deterministic_args(&det), det);
+    let run = self_check_run_args(&det, "out/self_check/run_0");
+    assert_eq!(arg_value(&run, "--self_check"), None);
+    assert_eq!(arg_value(&run, "--output_dir"), Some("out/self_check/run_0"));
+    assert_eq!(arg_value(&run, "--output_file"), Some(SELF_CHECK_OUTPUT));
+    let spread = cost_spread(&[1.0, 3.0]).unwrap();
+    assert_eq!((spread.mean, spread.std_dev, spread.min, spread.max), (2.0, 1.0, 1.0, 3.0));
+}
+
+// With --output_file -, the replayed graph is all that is written to stdout,
+// and no file named - is written to the output directory
+#[test]
+fn replay_to_stdout() {
+    let dir = std::env::temp_dir().join(format!("tensat_replay_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let record = ReplayRecord {
+        start: String::from("(relu (ewadd (input a@1_4) (input b@1_4)))"),
+        optimized: String::from("(relu (ewadd (input b@1_4) (input a@1_4)))"),
+    };
+    let (model_file, replay_file) = (dir.join("model.txt"), dir.join("replay.json"));
+    record.save(replay_file.to_str().unwrap());
+    fs::write(&model_file, "(relu (ewadd (input x@1_4) (input y@1_4)))").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_tensat"))
+        .arg("--model_file")
+        .arg(&model_file)
+        .arg("--replay")
+        .arg(&replay_file)
+        .args(&["--rules", "converted.txt", "--output_file", "-", "--output_dir"])
+        .arg(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "(relu (ewadd (input y@1_4) (input x@1_4)))");
+    assert!(!dir.join("-").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,104 @@
+use egg::*;
+use std::collections::{BTreeMap, HashSet};
+use tensat::capped::{capped_warnings, CappedRule};
+use tensat::freeze::{frozen_enodes, frozen_layers, layer_index};
+use tensat::model::*;
+use tensat::partition::suggest_cuts;
+use tensat::provenance::expr_eclasses;
+use tensat::prune::enodes_to_prune;
+use tensat::reduce::reduce;
+use tensat::rewrites::rules_from_str;
+use tensat::schedule::Bans;
+use tensat::shape::expr_shapes;
+use tensat::text::read_graph;
+
+// The suggested cut crosses the smallest tensor between the halves
+#[test]
+fn min_cut_partition() {
+    let expr = read_graph("(relu (matmul 0 (matmul 0 (relu (input x@1_1024)) (weight w@1024_4)) (weight v@4_256)))").unwrap();
+    let runner = Runner::<Mdl, TensorAnalysis, ()>::default().with_expr(&expr);
+    let (cuts, part_of) = suggest_cuts(&expr, &expr_shapes(&runner.egraph, &expr), 2);
+    assert_eq!(cuts.len(), 1);
+    // The 1x4 output of the first matmul
+    assert_eq!(cuts[0].bytes, 16);
+    assert_eq!(part_of[expr.as_ref().len() - 1], Some(1));
+    assert_eq!(part_of[cuts[0].tensors[0]], Some(0));
+}
+
+// A ban lasts ban_length iterations after the one it is found in, doubled
+// at each ban of the rule
+#[test]
+fn rule_bans() {
+    let mut bans = Bans::default();
+    bans.ban("rule0", 1, 2);
+    assert!(bans.is_banned("rule0", 3));
+    assert!(!bans.is_banned("rule0", 4));
+    bans.ban("rule0", 4, 2);
+    assert!(bans.is_banned("rule0", 8));
+    assert!(!bans.is_banned("rule0", 9));
+    assert!(!bans.is_banned("rule1", 2));
+}
+
+// The reducer drops the last layers and removes the ones in the middle
+#[test]
+fn reduce_failing_graph() {
+    let expr = read_graph("(relu (tanh (sigmoid (ewadd (input x@1_4) (input y@1_4)))))").unwrap();
+    // Fails while an ewadd is under a relu at the root
+    let reduction = reduce(&expr, |graph| {
+        let s = graph.to_string();
+        s.starts_with("(relu") && s.contains("ewadd")
+    });
+    assert_eq!(reduction.graph.to_string(), "(relu (ewadd (input x@1_4) (input y@1_4)))");
+    assert!(reduction.num_tries > 0);
+}
+
+// The cheapest enodes and the original ones are kept
+#[test]
+fn prune_explosive_eclass() {
+    let costs = vec![(Mdl::Num(1), 3.0), (Mdl::Num(2), 1.0), (Mdl::Num(3), 5.0), (Mdl::Num(4), 2.0)];
+    let original: HashSet<Mdl> = vec![Mdl::Num(3)].into_iter().collect();
+    assert_eq!(enodes_to_prune(&costs, 2, &original), vec![Mdl::Num(1)]);
+    assert!(enodes_to_prune(&costs, 4, &original).is_empty());
+}
+
+// A frozen prefix is a layer with its dependencies, a suffix with its users,
+// and the saturation does not rewrite their enodes
+#[test]
+fn frozen_layers_not_rewritten() {
+    let expr = read_graph("(tanh (sigmoid (relu (input x@1_64))))").unwrap();
+    let relu = layer_index(&expr, "relu_2").unwrap();
+    let sigmoid = layer_index(&expr, "sigmoid_3").unwrap();
+    assert!(layer_index(&expr, "relu_3").is_err());
+    // Inputs and names are not frozen
+    assert_eq!(frozen_layers(&expr, &[relu], &[]), vec![false, false, true, false, false]);
+    assert_eq!(frozen_layers(&expr, &[], &[sigmoid]), vec![false, false, false, true, true]);
+
+    let expr = read_graph("(ewadd (ewadd (input x@1_64) (input y@1_64)) (input z@1_64))").unwrap();
+    let inner = layer_index(&expr, "ewadd_4").unwrap();
+    let outer = layer_index(&expr, "ewadd_7").unwrap();
+    let mut runner = Runner::<Mdl, TensorAnalysis, ()>::default().with_iter_limit(1).with_expr(&expr);
+    runner.egraph.analysis.frozen_nodes = frozen_enodes(&runner.egraph, &expr, &frozen_layers(&expr, &[inner], &[]));
+    let ids = expr_eclasses(&runner.egraph, &expr);
+    let runner = runner.run(&rules_from_str(vec!["(ewadd ?input_1 ?input_2)=>(ewadd ?input_2 ?input_1)"], false));
+    let num_enodes = |i: usize| runner.egraph[ids[i].unwrap()].len();
+    assert_eq!((num_enodes(inner), num_enodes(outer)), (1, 2));
+}
+
+// Each capped rule gets one warning with its reasons, the others none
+#[test]
+fn capped_rule_warnings() {
+    let mut capped = BTreeMap::new();
+    capped.insert(
+        String::from("rule3"),
+        CappedRule {
+            skipped_iterations: 2,
+            capped_rounds: 0,
+            applied_at_stop: true,
+        },
+    );
+    capped.insert(String::from("multi0"), CappedRule::default());
+    let warnings = capped_warnings(&capped);
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].starts_with("rule3 did not apply all its matches: skipped by the scheduler with matches in 2 iterations"));
+    assert!(warnings[0].ends_with("still applying when a limit stopped the saturation"));
+}
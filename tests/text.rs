@@ -0,0 +1,44 @@
+use egg::*;
+use tensat::model::*;
+use tensat::text::*;
+
+// Writing a graph in the textual format and parsing it back has to give the
+// same s-expression
+#[test]
+fn text_round_trip() {
+    let expr: RecExpr<Mdl> = "(relu (conv2d 1 1 0 2 (input input_0@1_64_56_56) (weight w_0@64_64_3_3@0.5)))"
+        .parse()
+        .unwrap();
+    let text = to_text(&expr, None);
+    assert!(text.contains("padding=SAME, activation=RELU"));
+    let parsed = from_text(&text).unwrap();
+    assert_eq!(parsed.to_string(), expr.to_string());
+
+    assert!(from_text(&text.replace("stride_w=1, ", "")).is_err());
+}
+
+// Malformed names are rejected when the graph is read, each with its line,
+// and the names of a graph read are parsed once for the process
+#[test]
+fn malformed_names() {
+    let e = read_graph("(ewadd\n  (input input_0@1_x_56)\n  (weight w_0))").unwrap_err();
+    let errors: Vec<&str> = e.lines().collect();
+    assert_eq!(errors.len(), 2, "{}", e);
+    assert_eq!(errors[0], "line 2: invalid dimensions in input_0@1_x_56");
+    assert_eq!(errors[1], "line 3: name w_0 should be name@dim1_dim2...");
+
+    read_graph("(relu (input input_1@1_64_56_56@0.5))").unwrap();
+    let name = tensat::names::tensor_name(Symbol::from("input_1@1_64_56_56@0.5")).unwrap();
+    assert_eq!((name.dims.clone(), name.density), (vec![1, 64, 56, 56], Some(0.5)));
+    let cached = tensat::names::tensor_name(Symbol::from("input_1@1_64_56_56@0.5")).unwrap();
+    assert!(std::sync::Arc::ptr_eq(&name, &cached));
+}
+
+// A DOT graph has one node per op, with the scalars in its label
+#[test]
+fn dot_graph() {
+    let expr: RecExpr<Mdl> = "(relu (conv2d 1 1 0 2 (input x@1_8_4_4) (weight w@8_8_3_3)))".parse().unwrap();
+    let dot = tensat::dot::graph_dot(&expr, None);
+    assert!(dot.contains("label=\"conv2d 1 1 0 2\""));
+    assert_eq!(dot.matches("->").count(), 3);
+}
@@ -0,0 +1,26 @@
+// The watchdog is global to the process, so it gets a test binary of its own
+
+use egg::*;
+use std::fs;
+use std::time::Duration;
+use tensat::model::*;
+use tensat::watchdog::{is_enabled, is_hung, start_watchdog};
+
+// Ops of the file of hung ops, one key per line, are infeasible without being
+// measured, the other ops are measured as usual
+#[test]
+fn hung_ops_infeasible() {
+    let file = std::env::temp_dir().join(format!("tensat_hung_{}.txt", std::process::id()));
+    fs::write(&file, "relu 1x64\n\n  relu 1x64:f16  \n").unwrap();
+    assert_eq!(start_watchdog(Duration::from_secs(600), file.clone()), Ok(2));
+    assert!(is_enabled());
+    assert!(is_hung("relu 1x64:f16"));
+    assert!(!is_hung("relu 1x32"));
+
+    let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+    let hung = egraph.add_expr(&"(relu (input x@1_64))".parse().unwrap());
+    let measured = egraph.add_expr(&"(relu (input x@1_32))".parse().unwrap());
+    assert!(egraph[hung].data.infeasible);
+    assert!(!egraph[measured].data.infeasible);
+    fs::remove_file(&file).unwrap();
+}
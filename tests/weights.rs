@@ -0,0 +1,115 @@
+use egg::*;
+use tensat::execute::GraphExecutor;
+use tensat::extract::*;
+use tensat::interp::HostTensor;
+use tensat::model::*;
+use tensat::optimize::*;
+use tensat::text::read_graph;
+use tensat::tied::*;
+use tensat::values::{read_npy, WeightValues};
+use tensat::weights::TransformCache;
+
+// The encoder input and the decoder output share the embedding
+const TIED_GRAPH: &str = "(noop (matmul 0 (input x@2_4) (weight emb@4_4)) (matmul 0 (matmul 0 (input y@2_4) (weight out@4_4)) (weight emb@4_4)))";
+
+// A weight is shared when several ops use it, through one node or through
+// separate nodes of the same name as the readers give
+#[test]
+fn shared_weights_by_name() {
+    let expr: RecExpr<Mdl> = TIED_GRAPH.parse().unwrap();
+    let shared = shared_weights(&expr);
+    assert_eq!(shared.len(), 2);
+    assert!(shared.iter().all(|i| expr[*i].to_string() == "weight" && expr[expr[*i].children()[0]].to_string() == "emb@4_4"));
+
+    let once: RecExpr<Mdl> = "(matmul 0 (matmul 0 (input y@2_4) (weight out@4_4)) (weight emb@4_4))".parse().unwrap();
+    assert!(shared_weights(&once).is_empty());
+}
+
+// With tied weights, the extraction can not give one use of the embedding a
+// transformed copy and the other the original
+#[test]
+fn tied_weights_in_extraction() {
+    let expr: RecExpr<Mdl> = TIED_GRAPH.parse().unwrap();
+    let mut egraph = EGraph::<Mdl, TensorAnalysis>::new(TensorAnalysis::default());
+    let root = egraph.add_expr(&expr);
+    // A rewrite gives the encoder a copy of the embedding, transposed twice
+    let encoder: RecExpr<Mdl> = "(matmul 0 (input x@2_4) (weight emb@4_4))".parse().unwrap();
+    let copy: RecExpr<Mdl> = "(matmul 0 (input x@2_4) (transpose (transpose (weight emb@4_4) 1_0 1) 1_0 1))".parse().unwrap();
+    let encoder_id = egraph.add_expr(&encoder);
+    let copy_id = egraph.add_expr(&copy);
+    egraph.union(encoder_id, copy_id);
+    egraph.rebuild();
+
+    let tied = TiedWeights::new(&egraph, &expr);
+    assert_eq!(tied.names, vec![String::from("emb@4_4")]);
+    let transpose: RecExpr<Mdl> = "(transpose (weight emb@4_4) 1_0 1)".parse().unwrap();
+    let transpose_id = egraph.add_expr(&transpose);
+    let transpose_enode = egraph[transpose_id].iter().find(|n| matches!(n, Mdl::Transpose(_))).unwrap().clone();
+
+    let untied_model = CostModel::with_setting(false);
+    assert!(untied_model.get_self_cost(&egraph, &transpose_enode) < INFEASIBLE_COST);
+    let cost_model = CostModel::with_setting(false).with_tied_weights(tied);
+    assert!(cost_model.get_self_cost(&egraph, &transpose_enode) >= INFEASIBLE_COST);
+
+    let (best, cost, _) = extract(&egraph, root, &cost_model, &ExtractionStrategy::Greedy);
+    assert!(cost < INFEASIBLE_COST);
+    assert!(!best.to_string().contains("transpose"));
+}
+
+// Random weight values are the same for the same name and seed, and loaded
+// values have to fit the shape of their weight
+#[test]
+fn weight_values() {
+    let mut values = WeightValues::default();
+    values.seed = 7;
+    let a = values.values("w_0", 16, 1.0).unwrap();
+    assert_eq!(a, values.values("w_0", 16, 1.0).unwrap());
+    assert_ne!(a, values.values("w_1", 16, 1.0).unwrap());
+
+    let mut npy = b"\x93NUMPY\x01\x00".to_vec();
+    let header = "{'descr': '<f4', 'fortran_order': False, 'shape': (2,), }\n";
+    npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    npy.extend_from_slice(header.as_bytes());
+    npy.extend_from_slice(&1.5f32.to_le_bytes());
+    npy.extend_from_slice(&(-2.0f32).to_le_bytes());
+    assert_eq!(read_npy(&npy).unwrap(), vec![1.5, -2.0]);
+
+    values.loaded.insert("w_0".to_string(), vec![1.5, -2.0]);
+    assert_eq!(values.values("w_0", 2, 1.0).unwrap(), vec![1.5, -2.0]);
+    let expr: RecExpr<Mdl> = "(relu (weight w_0@2_2))".parse().unwrap();
+    assert_eq!(values.check(&expr).0.len(), 1);
+    let expr: RecExpr<Mdl> = "(relu (weight w_0@1_2))".parse().unwrap();
+    assert_eq!(values.check(&expr), (vec![], 1));
+}
+
+// Fed inputs replace the pseudo-random values, of the same dimensions only
+#[test]
+fn execute_with_fed_inputs() {
+    let expr = read_graph("(relu (input x@1_3))").unwrap();
+    let execution = GraphExecutor::new()
+        .with_input("x", HostTensor::new(vec![1, 3], vec![-1.0, 0.5, 2.0]))
+        .run(&expr)
+        .unwrap();
+    assert_eq!(execution.outputs[0].data, vec![0.0, 0.5, 2.0]);
+    assert_eq!(execution.runtime_ms, None);
+    assert!(GraphExecutor::new().with_input("x", HostTensor::zeros(vec![3])).run(&expr).is_err());
+    assert!(GraphExecutor::new().with_input("y", HostTensor::zeros(vec![1, 3])).run(&expr).is_err());
+}
+
+// Transformed weights are cached by transformation and seed, within the size
+#[test]
+fn transform_cache() {
+    let mut cache = TransformCache::new(32);
+    let t = HostTensor::new(vec![2, 2], vec![1.0, 2.0, 3.0, 4.0]);
+    cache.insert("(transpose (weight w@2_2) 1_0 1)", 0, &t);
+    assert_eq!(cache.get("(transpose (weight w@2_2) 1_0 1)", 0), Some(t.clone()));
+    assert_eq!(cache.get("(transpose (weight w@2_2) 1_0 1)", 1), None);
+    assert_eq!((cache.num_hits, cache.num_misses), (1, 1));
+    cache.insert("(enlarge (weight w@2_2) (weight r@2_2))", 0, &t);
+    assert_eq!(cache.len(), 2);
+    // Full, emptied before caching the next value
+    cache.insert("(merge (weight w@2_2) 2)", 0, &t);
+    assert_eq!(cache.len(), 1);
+    cache.insert("(weight big@3_3)", 0, &HostTensor::zeros(vec![3, 3]));
+    assert_eq!(cache.len(), 1);
+}
@@ -0,0 +1,76 @@
+"""
+This script compares the outputs of two ONNX models with ONNX Runtime.
+
+The models are the original and the optimized graph written by tensat (see
+export_onnx in src/onnx.rs). Both get the same pseudo-random inputs and
+weights, determined by their names and the seed, and the largest absolute and
+relative errors of the outputs of the second model are written as JSON, null
+for outputs with NaNs.
+
+Example:
+    $ python ort_equivalence.py start.onnx optimized.onnx errors.json --seed 0
+"""
+
+from __future__ import print_function
+import argparse
+import json
+import zlib
+
+import numpy as np
+import onnx
+import onnxruntime as ort
+from onnx import numpy_helper
+
+
+def random_values(name, shape, seed):
+    rng = np.random.RandomState((seed ^ zlib.crc32(name.encode())) & 0xffffffff)
+    return rng.uniform(-1.0, 1.0, size=shape).astype(np.float32)
+
+
+def run(path, seed):
+    # The weights are external data written next to the model, they get
+    # random values instead
+    model = onnx.load(path, load_external_data=False)
+    for init in model.graph.initializer:
+        if init.data_location == onnx.TensorProto.EXTERNAL:
+            values = random_values(init.name, list(init.dims), seed)
+            init.CopyFrom(numpy_helper.from_array(values, init.name))
+    session = ort.InferenceSession(model.SerializeToString(), providers=['CPUExecutionProvider'])
+    feeds = {}
+    for i in session.get_inputs():
+        shape = [d if isinstance(d, int) else 1 for d in i.shape]
+        feeds[i.name] = random_values(i.name, shape, seed)
+    return session.run(None, feeds)
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument('original')
+    parser.add_argument('optimized')
+    parser.add_argument('out_file')
+    parser.add_argument('--seed', type=int, default=0)
+    args = parser.parse_args()
+
+    out_a = run(args.original, args.seed)
+    out_b = run(args.optimized, args.seed)
+    if len(out_a) != len(out_b):
+        raise SystemExit('{} outputs compared to {}'.format(len(out_b), len(out_a)))
+    abs_err = 0.0
+    rel_err = 0.0
+    for a, b in zip(out_a, out_b):
+        if a.shape != b.shape:
+            raise SystemExit('output of shape {} compared to {}'.format(b.shape, a.shape))
+        diff = np.abs(a.astype(np.float64) - b.astype(np.float64))
+        if np.isnan(diff).any():
+            abs_err = rel_err = None
+            break
+        scale = max(np.abs(a).max() if a.size else 0.0, 1e-12)
+        abs_err = max(abs_err, float(diff.max()) if diff.size else 0.0)
+        rel_err = max(rel_err, float(diff.max() / scale) if diff.size else 0.0)
+
+    with open(args.out_file, 'w') as f:
+        json.dump({'abs_err': abs_err, 'rel_err': rel_err}, f)
+
+
+if __name__ == '__main__':
+    main()